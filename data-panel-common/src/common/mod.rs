@@ -1,14 +1,91 @@
-/// RDBC Error
-#[derive(Debug)]
+//! The errors of the mesh, by what failed.
+//!
+//! Each kind answers the client with an ERR packet of its own code and SQLSTATE, a backend
+//! error passing on the ones of the backend, and ends the process with an exit code of its own
+//! when it is fatal.
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
 pub enum Error {
-    General(String),
+    /// The statement does not parse, or can not be written back as SQL.
+    #[error("{0}")]
+    Parse(String),
+    /// The client asked for what the mesh does not support.
+    #[error("{0}")]
+    Protocol(String),
+    /// A backend failed the statement or could not be reached.
+    #[error("{message}")]
+    Backend { code: u32, state: String, message: String },
+    /// No backend can run the statement as it is.
+    #[error("{0}")]
+    Routing(String),
+    #[error("{0}")]
+    Config(String),
+    /// The user may not run the statement.
+    #[error("{0}")]
+    Auth(String),
 }
 
-/// RDBC Result type
+impl Error {
+    /// The exit code of the process ending on this error, after sysexits.h.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            // EX_DATAERR
+            Error::Parse(_) => 65,
+            // EX_UNAVAILABLE
+            Error::Backend { .. } => 69,
+            // EX_SOFTWARE
+            Error::Routing(_) => 70,
+            // EX_PROTOCOL
+            Error::Protocol(_) => 76,
+            // EX_NOPERM
+            Error::Auth(_) => 77,
+            // EX_CONFIG
+            Error::Config(_) => 78,
+        }
+    }
+}
+
+/// The mesh's own Result type.
 pub type Result<T> = std::result::Result<T, Error>;
 
 impl From<std::fmt::Error> for Error {
     fn from(e: std::fmt::Error) -> Self {
-        Error::General(e.to_string())
+        Error::Parse(e.to_string())
+    }
+}
+
+impl From<mysql::Error> for Error {
+    fn from(e: mysql::Error) -> Self {
+        let code = match e {
+            mysql::Error::MySqlError(e) => return Error::Backend { code: e.code as u32, state: e.state, message: e.message },
+            mysql::Error::IoError(_) => 10000,
+            mysql::Error::DriverError(_) => 20000,
+            mysql::Error::UrlError(_) => 40000,
+            mysql::Error::TlsError(_) => 50000,
+            mysql::Error::TlsHandshakeError(_) => 60000,
+            _ => 70000,
+        };
+        Error::Backend { code, state: "HY000".to_string(), message: e.to_string() }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use mysql::MySqlError;
+
+    use super::Error;
+
+    #[test]
+    fn test_error_kinds() {
+        let e = Error::from(mysql::Error::MySqlError(MySqlError { state: "40001".to_string(), message: "Deadlock found".to_string(), code: 1213 }));
+        match e {
+            Error::Backend { code, ref state, ref message } => assert_eq!((code, state.as_str(), message.as_str()), (1213, "40001", "Deadlock found")),
+            _ => panic!("not a backend error: {:?}", e),
+        }
+        assert_eq!(e.exit_code(), 69);
+        assert_eq!(Error::Config("port 0".to_string()).exit_code(), 78);
+        assert_eq!(Error::from(std::fmt::Error).to_string(), "an error occurred when formatting an argument");
+    }
+}
@@ -3,41 +3,50 @@ use std::io::ErrorKind;
 use bytes::Bytes;
 use futures::io::Error;
 use futures::SinkExt;
-use tokio::net::tcp::ReadHalf;
-use tokio::net::tcp::WriteHalf;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
 use tokio_util::codec::{FramedRead, FramedWrite};
 use tokio_util::codec::LengthDelimitedCodec;
 
 use crate::service::ServiceCodec;
 
-pub struct Channel<'a> {
-    // socket: &'a TcpStream,
-    pub stream: FramedRead<ReadHalf<'a>, LengthDelimitedCodec>,
-    pub sink: FramedWrite<WriteHalf<'a>, LengthDelimitedCodec>,
+pub struct Channel<S> {
+    pub stream: FramedRead<ReadHalf<S>, LengthDelimitedCodec>,
+    pub sink: FramedWrite<WriteHalf<S>, LengthDelimitedCodec>,
 }
 
-impl<'a> Channel<'a> {
-    pub fn new<CODEC: ServiceCodec>(socket: &'a mut TcpStream, codec: CODEC) -> Self {
-        let (r, w) = socket.split();
+impl<S: AsyncRead + AsyncWrite> Channel<S> {
+    pub fn new<CODEC: ServiceCodec>(socket: S, codec: CODEC) -> Self {
+        let (r, w) = tokio::io::split(socket);
         let stream = codec.read_frame(r);
         let sink = codec.write_frame(w);
         Channel {
-            // socket: socket,
             stream: stream,
             sink: sink,
         }
     }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Channel<S> {
+    /// Gives the socket back, e.g. to upgrade it to TLS. Fails if the client already sent
+    /// data that was read into the frame buffer.
+    pub fn into_inner(self) -> Result<S, Error> {
+        if !self.stream.read_buffer().is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "unexpected data before the upgrade"));
+        }
+        Ok(self.stream.into_inner().unsplit(self.sink.into_inner()))
+    }
 
+    /// Writes the payloads as one batch: they are framed into the write buffer, which goes out
+    /// whenever it grows past the backpressure boundary of the codec, and once more at the end,
+    /// rather than in a write of its own for every packet.
     pub async fn send(&mut self, payloads: Option<Vec<Bytes>>) -> Result<(), Error> {
         match payloads {
             Some(bytes) => {
                 if bytes.len() > 0 {
                     for payload in bytes {
-                        if let Err(e) = self.sink.send(payload).await {
-                            return Err(e);
-                        }
+                        self.sink.feed(payload).await?;
                     }
+                    self.sink.flush().await?;
                 }
                 Ok(())
             }
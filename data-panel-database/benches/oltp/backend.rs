@@ -0,0 +1,174 @@
+//! An in-memory MySQL server answering the load with canned results, so the numbers measure
+//! the mesh rather than a database and repeat from run to run.
+//!
+//! It speaks the text protocol with the packets of the mesh itself: any password logs in,
+//! SELECTs answer one row, or `RANGE_SIZE` rows for a `BETWEEN`, and writes answer OK with one
+//! affected row. Prepared statements are refused.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+
+use bytes::Bytes;
+
+use data_panel_database::handler::database::mysql::{deprecate_eof_payloads, err_payloads, nullable_text_result_payloads, text_result_payloads};
+use data_panel_database::protocol::database::{DatabasePacket, PacketPayload};
+use data_panel_database::protocol::database::mysql::constant::{MySQLCapabilityFlag, MySQLCommandPacketType, MySQLServerErrorCode};
+use data_panel_database::protocol::database::mysql::packet::{MySQLHandshakePacket, MySQLOKPacket, MySQLPacketPayload};
+
+/// The rows of a range SELECT, the `range_size` of sysbench.
+pub const RANGE_SIZE: usize = 100;
+
+const MAX_ALLOWED_PACKET: &str = "16777216";
+
+pub struct MockBackend {
+    addr: SocketAddr,
+    queries: Arc<AtomicU64>,
+}
+
+impl MockBackend {
+    /// Listens on a free port of the loopback, every connection served on its own thread.
+    pub fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let queries = Arc::new(AtomicU64::new(0));
+        let served = queries.clone();
+        thread::spawn(move || {
+            for (connection_id, stream) in listener.incoming().enumerate() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        println!("error accepting on the mock backend; error = {:?}", e);
+                        continue;
+                    }
+                };
+                let queries = served.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(stream, connection_id as u32 + 1, &queries) {
+                        println!("error on serving a mock backend connection; error = {:?}", e);
+                    }
+                });
+            }
+        });
+        Ok(MockBackend { addr, queries })
+    }
+
+    pub fn url(&self) -> String {
+        format!("mysql://sbtest:sbtest@{}/sbtest", self.addr)
+    }
+
+    /// The COM_QUERY statements answered so far.
+    pub fn get_queries(&self) -> u64 {
+        self.queries.load(Ordering::Relaxed)
+    }
+}
+
+/// The sequence id and body of the next packet, None once the client went away.
+fn read_packet(stream: &mut TcpStream) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut header = [0u8; 4];
+    match stream.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_le_bytes([header[0], header[1], header[2], 0]) as usize;
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body)?;
+    Ok(Some((header[3], body)))
+}
+
+/// Writes payloads of the mesh, their sequence id first, as packets.
+fn write_payloads(stream: &mut TcpStream, payloads: Vec<Bytes>) -> io::Result<()> {
+    let mut packets = Vec::with_capacity(payloads.iter().map(|payload| payload.len() + 3).sum());
+    for payload in payloads {
+        packets.extend_from_slice(&(payload.len() as u32 - 1).to_le_bytes()[..3]);
+        packets.extend_from_slice(&payload);
+    }
+    stream.write_all(&packets)
+}
+
+fn ok_payloads(sequence_id: u32, affected_rows: u64, last_insert_id: u64) -> Vec<Bytes> {
+    let mut ok_packet = MySQLOKPacket::new(sequence_id, affected_rows, last_insert_id);
+    let mut ok_payload = MySQLPacketPayload::new();
+    vec![DatabasePacket::encode(&mut ok_packet, &mut ok_payload).get_payload()]
+}
+
+/// The `c` column of a row of `sbtest1`, 120 characters like sysbench's.
+fn c_value(id: usize) -> String {
+    format!("{:0>11}-{:0>108}", id, id * 7)
+}
+
+/// The answer to `SELECT @@variable, ...`, NULL for the variables it does not know.
+fn variables_payloads(sql: &str) -> Option<Vec<Bytes>> {
+    let items: Vec<&str> = sql["SELECT".len()..].split(',').map(|item| item.trim()).collect();
+    let row = items.iter()
+        .map(|item| match item.trim_start_matches("@@").to_lowercase().as_str() {
+            "max_allowed_packet" => Some(MAX_ALLOWED_PACKET.to_string()),
+            _ => None,
+        })
+        .collect();
+    nullable_text_result_payloads(items, vec![row])
+}
+
+fn answer(sql: &str, last_insert_id: &mut u64) -> Vec<Bytes> {
+    let sql = sql.trim();
+    let upper = sql.to_uppercase();
+    let payloads = if upper.starts_with("SELECT @@") {
+        variables_payloads(sql)
+    } else if upper.starts_with("SELECT") && upper.contains("SUM(") {
+        text_result_payloads(vec!["SUM(k)"], vec![vec![(RANGE_SIZE * 7).to_string()]])
+    } else if upper.starts_with("SELECT") && upper.contains("BETWEEN") {
+        text_result_payloads(vec!["c"], (0..RANGE_SIZE).map(|id| vec![c_value(id)]).collect())
+    } else if upper.starts_with("SELECT") {
+        text_result_payloads(vec!["c"], vec![vec![c_value(0)]])
+    } else if upper.starts_with("INSERT") || upper.starts_with("REPLACE") {
+        *last_insert_id += 1;
+        Some(ok_payloads(1, 1, *last_insert_id))
+    } else if upper.starts_with("UPDATE") || upper.starts_with("DELETE") {
+        Some(ok_payloads(1, 1, 0))
+    } else {
+        Some(ok_payloads(1, 0, 0))
+    };
+    payloads.unwrap_or_default()
+}
+
+fn serve(mut stream: TcpStream, connection_id: u32, queries: &AtomicU64) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut handshake_packet = MySQLHandshakePacket::new(connection_id, b"m0ckseed".to_vec(), b"m0ckseed2345".to_vec());
+    let mut handshake_payload = MySQLPacketPayload::new();
+    write_payloads(&mut stream, vec![DatabasePacket::encode(&mut handshake_packet, &mut handshake_payload).get_payload()])?;
+    let response = match read_packet(&mut stream)? {
+        Some((_, response)) if response.len() >= 4 => response,
+        _ => return Ok(()),
+    };
+    let capability_flags = MySQLCapabilityFlag::from_bits_truncate(u32::from_le_bytes([response[0], response[1], response[2], response[3]]));
+    let deprecate_eof = capability_flags.contains(MySQLCapabilityFlag::CLIENT_DEPRECATE_EOF);
+    write_payloads(&mut stream, ok_payloads(2, 0, 0))?;
+
+    let mut last_insert_id = 0;
+    while let Some((_, body)) = read_packet(&mut stream)? {
+        let command = match body.first() {
+            Some(command) => *command,
+            None => continue,
+        };
+        let payloads = match command {
+            command if command == MySQLCommandPacketType::ComQuit as u8 => return Ok(()),
+            command if command == MySQLCommandPacketType::ComQuery as u8 => {
+                queries.fetch_add(1, Ordering::Relaxed);
+                answer(&String::from_utf8_lossy(&body[1..]), &mut last_insert_id)
+            }
+            command if command == MySQLCommandPacketType::ComPing as u8
+                || command == MySQLCommandPacketType::ComInitDb as u8
+                || command == MySQLCommandPacketType::ComResetConnection as u8 => ok_payloads(1, 0, 0),
+            _ => {
+                let message = format!("command {:#04x} is not supported by the mock backend", command);
+                err_payloads(1, MySQLServerErrorCode::ErNotSupportedYet, message).unwrap_or_default()
+            }
+        };
+        let payloads = if deprecate_eof { deprecate_eof_payloads(command, payloads) } else { payloads };
+        write_payloads(&mut stream, payloads)?;
+    }
+    Ok(())
+}
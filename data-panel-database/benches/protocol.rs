@@ -0,0 +1,87 @@
+use bytes::{BufMut, BytesMut};
+use criterion::{black_box, Criterion, criterion_group, criterion_main};
+use tokio_util::codec::Decoder;
+
+use data_panel_common::service::ServiceCodec;
+use data_panel_database::handler::database::mysql::{deprecate_eof_payloads, text_result_payloads};
+use data_panel_database::protocol::database::DatabasePacket;
+use data_panel_database::protocol::database::mysql::codec::MySQLCodec;
+use data_panel_database::protocol::database::mysql::constant::MySQLCommandPacketType;
+use data_panel_database::protocol::database::mysql::packet::{MySQLOKPacket, MySQLPacketHeader, MySQLPacketPayload};
+use data_panel_database::protocol::database::mysql::packet::text::MySQLComQueryPacket;
+use data_panel_database::protocol::database::PacketPayload;
+use data_panel_database::session::mysql::SessionContext;
+
+const SQL: &str = "SELECT c FROM sbtest1 WHERE id BETWEEN 4021 AND 4120 ORDER BY c";
+
+/// 16 COM_QUERY frames as a client sends them back to back.
+fn query_frames() -> BytesMut {
+    let mut frames = BytesMut::new();
+    for _ in 0..16 {
+        frames.put_uint_le(SQL.len() as u64 + 1, 3);
+        frames.put_u8(0);
+        frames.put_u8(MySQLCommandPacketType::ComQuery as u8);
+        frames.put_slice(SQL.as_bytes());
+    }
+    frames
+}
+
+fn bench_frames(c: &mut Criterion) {
+    let frames = query_frames();
+    let mut framed = MySQLCodec {}.read_frame(tokio::io::empty());
+    c.bench_function("decode 16 query frames", |b| b.iter(|| {
+        let mut frames = frames.clone();
+        let codec = framed.decoder_mut();
+        let mut decoded = 0;
+        while let Some(frame) = codec.decode(black_box(&mut frames)).unwrap() {
+            decoded += frame.len();
+        }
+        decoded
+    }));
+}
+
+fn bench_packets(c: &mut Criterion) {
+    let mut session_ctx = SessionContext::new(1);
+    c.bench_function("decode COM_QUERY", |b| b.iter(|| {
+        let header = MySQLPacketHeader::new(SQL.len() as u64 + 1, 0, MySQLCommandPacketType::ComQuery as u8, 1);
+        let mut payload = MySQLPacketPayload::new_with_payload(BytesMut::from(black_box(SQL).as_bytes()));
+        let mut query_packet = MySQLComQueryPacket::new(MySQLCommandPacketType::ComQuery as u8);
+        DatabasePacket::decode(&mut query_packet, &header, &mut payload, &mut session_ctx).get_sql().len()
+    }));
+    // A row of lenenc strings, the values of a COM_STMT_EXECUTE, read as slices of the packet
+    // and as the copies the packets held before.
+    let mut strings = MySQLPacketPayload::new();
+    for _ in 0..16 {
+        strings.put_string_lenenc(SQL.as_bytes());
+    }
+    let strings = BytesMut::from(&strings.get_payload()[..]);
+    c.bench_function("read 16 strings sliced", |b| b.iter(|| {
+        let mut payload = MySQLPacketPayload::new_with_payload(black_box(strings.clone()));
+        (0..16).map(|_| payload.get_string_lenenc().len()).sum::<usize>()
+    }));
+    c.bench_function("read 16 strings copied", |b| b.iter(|| {
+        let mut payload = MySQLPacketPayload::new_with_payload(black_box(strings.clone()));
+        (0..16).map(|_| payload.get_string_lenenc().to_vec().len()).sum::<usize>()
+    }));
+    c.bench_function("encode OK", |b| b.iter(|| {
+        let mut ok_packet = MySQLOKPacket::new(1, black_box(1), black_box(4021));
+        let mut ok_payload = MySQLPacketPayload::new();
+        DatabasePacket::encode(&mut ok_packet, &mut ok_payload).get_payload()
+    }));
+}
+
+fn bench_result_sets(c: &mut Criterion) {
+    let rows: Vec<Vec<String>> = (0..100)
+        .map(|id| vec![id.to_string(), (id * 7).to_string(), format!("{:0>119}", id), format!("{:0>59}", id)])
+        .collect();
+    c.bench_function("encode 100 row result set", |b| b.iter(|| {
+        text_result_payloads(vec!["id", "k", "c", "pad"], black_box(rows.clone())).unwrap().len()
+    }));
+    let payloads = text_result_payloads(vec!["id", "k", "c", "pad"], rows).unwrap();
+    c.bench_function("deprecate EOF of 100 row result set", |b| b.iter(|| {
+        deprecate_eof_payloads(MySQLCommandPacketType::ComQuery as u8, black_box(payloads.clone())).len()
+    }));
+}
+
+criterion_group!(benches, bench_frames, bench_packets, bench_result_sets);
+criterion_main!(benches);
@@ -0,0 +1,49 @@
+use criterion::{black_box, Criterion, criterion_group, criterion_main};
+
+use data_panel_common::config::config::RouteCacheConfig;
+use data_panel_database::discovery::database::rules::load_rules_file;
+use data_panel_database::handler::database::parser;
+use data_panel_database::handler::database::parser::sql::{analyse_statement_context, fingerprint_statement};
+use data_panel_database::handler::database::parser::sql::hint::SQLHints;
+use data_panel_database::handler::database::route_cache::{configure_route_cache, route_plan};
+
+/// The rules of the end-to-end suite, `t_order` distributed on `user_id` over two segments.
+const RULES_FILE: &str = "./tests/e2e/dbmesh.yaml";
+
+/// The statements of an OLTP read/write transaction on a distributed table.
+const STATEMENTS: [&str; 5] = [
+    "SELECT id, status, amount FROM t_order WHERE user_id = 10",
+    "SELECT status, SUM(amount) FROM t_order WHERE user_id IN (10, 20, 30) GROUP BY status ORDER BY status",
+    "UPDATE t_order SET status = 'PAID' WHERE user_id = 10 AND id = 4021",
+    "INSERT INTO t_order (id, user_id, status, amount) VALUES (4022, 10, 'NEW', 12.50)",
+    "DELETE FROM t_order WHERE user_id = 10 AND id = 4020",
+];
+
+fn bench_parser(c: &mut Criterion) {
+    c.bench_function("parse", |b| b.iter(|| {
+        STATEMENTS.iter().map(|sql| parser::sql::mysql::parser(black_box(sql).to_string()).unwrap().len()).sum::<usize>()
+    }));
+    let statements: Vec<_> = STATEMENTS.iter().map(|sql| parser::sql::mysql::parser(sql.to_string()).unwrap().pop().unwrap()).collect();
+    c.bench_function("analyse", |b| b.iter(|| {
+        statements.iter().filter(|statement| analyse_statement_context(black_box(statement)).is_some()).count()
+    }));
+    c.bench_function("fingerprint", |b| b.iter(|| {
+        statements.iter().filter_map(|statement| fingerprint_statement(black_box(statement))).count()
+    }));
+}
+
+fn bench_route_plans(c: &mut Criterion) {
+    load_rules_file(RULES_FILE).unwrap();
+    let statements: Vec<_> = STATEMENTS.iter()
+        .map(|sql| (*sql, parser::sql::mysql::parser(sql.to_string()).unwrap().pop().unwrap(), SQLHints::parse(sql)))
+        .collect();
+    let plan_all = || statements.iter().filter(|(sql, statement, hints)| route_plan(black_box(sql), statement, hints).is_some()).count();
+
+    configure_route_cache(&RouteCacheConfig::default());
+    c.bench_function("route plan", |b| b.iter(plan_all));
+    configure_route_cache(&RouteCacheConfig::new(128));
+    c.bench_function("route plan cached", |b| b.iter(plan_all));
+}
+
+criterion_group!(benches, bench_parser, bench_route_plans);
+criterion_main!(benches);
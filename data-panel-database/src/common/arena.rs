@@ -0,0 +1,100 @@
+//! Per-query scratch arena.
+//!
+//! Parse, route and rewrite scratch data only lives as long as the command being handled,
+//! so it is bump-allocated and released in one go once the response payloads are built:
+//! the SQL text and its rewrites, the backend url, and the statements replaying the session
+//! variables that `ExplainPlanContext` borrows. The arena is kept per worker thread and reused,
+//! so steady traffic does not go back to the global allocator for scratch data.
+//! Route plans are not scratch data, they outlive the query in the route cache.
+//! Nothing needing its `Drop` to run may be put in the arena, it is reset without dropping.
+
+use std::cell::RefCell;
+
+use bumpalo::Bump;
+
+/// Arenas that grew beyond this after a query are shrunk back instead of being kept.
+const RETAINED_CAPACITY: usize = 64 * 1024;
+
+thread_local! {
+    static QUERY_ARENA: RefCell<Bump> = RefCell::new(Bump::with_capacity(RETAINED_CAPACITY));
+}
+
+pub struct QueryArena<'a> {
+    bump: &'a Bump,
+}
+
+impl<'a> QueryArena<'a> {
+    pub fn alloc<T>(&self, value: T) -> &'a mut T {
+        self.bump.alloc(value)
+    }
+
+    pub fn alloc_str(&self, value: &str) -> &'a str {
+        self.bump.alloc_str(value)
+    }
+
+    pub fn new_string(&self) -> bumpalo::collections::String<'a> {
+        bumpalo::collections::String::new_in(self.bump)
+    }
+
+    pub fn new_vec<T>(&self) -> bumpalo::collections::Vec<'a, T> {
+        bumpalo::collections::Vec::new_in(self.bump)
+    }
+
+    /// Copies `values` into the arena.
+    pub fn alloc_strs<S: AsRef<str>>(&self, values: &[S]) -> &'a [&'a str] {
+        let mut strs = bumpalo::collections::Vec::with_capacity_in(values.len(), self.bump);
+        for value in values {
+            strs.push(&*self.bump.alloc_str(value.as_ref()));
+        }
+        strs.into_bump_slice()
+    }
+
+    pub fn allocated_bytes(&self) -> usize {
+        self.bump.allocated_bytes()
+    }
+}
+
+/// Runs `f` with the current thread's query arena, resetting it afterwards.
+/// Nested calls get a temporary arena of their own.
+pub fn with_query_arena<R>(f: impl FnOnce(&QueryArena<'_>) -> R) -> R {
+    QUERY_ARENA.with(|cell| match cell.try_borrow_mut() {
+        Ok(mut bump) => {
+            let result = f(&QueryArena { bump: &*bump });
+            if bump.allocated_bytes() > RETAINED_CAPACITY {
+                *bump = Bump::with_capacity(RETAINED_CAPACITY);
+            } else {
+                bump.reset();
+            }
+            result
+        }
+        Err(_) => {
+            let bump = Bump::new();
+            f(&QueryArena { bump: &bump })
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Write;
+
+    use crate::common::arena::with_query_arena;
+
+    #[test]
+    fn test_arena_is_reset_between_queries() {
+        let len = with_query_arena(|arena| {
+            let sql = arena.alloc_str("SELECT * FROM t_order");
+            let mut scratch = arena.new_string();
+            write!(scratch, "{} WHERE user_id = {}", sql, 1).unwrap();
+            assert!(arena.allocated_bytes() > 0);
+            scratch.len()
+        });
+        assert_eq!(len, "SELECT * FROM t_order WHERE user_id = 1".len());
+
+        let strs = with_query_arena(|arena| arena.alloc_strs(&["SET NAMES utf8mb4".to_string(), "SET @tenant = 7".to_string()]).concat());
+        assert_eq!(strs, "SET NAMES utf8mb4SET @tenant = 7");
+
+        let nested = with_query_arena(|_| with_query_arena(|arena| arena.alloc_str("nested").len()));
+        assert_eq!(nested, 6);
+    }
+}
@@ -0,0 +1,170 @@
+//! Durable metadata of the mesh, see `MetadataStoreConfig`.
+//!
+//! Values are bytes under a key in a namespace, one per subsystem: the XA log, the key
+//! generator and the cdc position. A write returns once the value is on disk, so the
+//! subsystems keep their state as they did with their own files, the store only replacing
+//! the files by one database. Another store plugs in by implementing `MetadataStore`.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+use rusqlite::{Connection, OptionalExtension, params};
+
+use data_panel_common::config::config::MetadataStoreConfig;
+
+pub trait MetadataStore: Send + Sync {
+    fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>, String>;
+
+    fn put(&self, namespace: &str, key: &str, value: &[u8]) -> Result<(), String>;
+
+    fn delete(&self, namespace: &str, key: &str) -> Result<(), String>;
+
+    /// The keys and values of `namespace`, in key order.
+    fn scan(&self, namespace: &str) -> Result<Vec<(String, Vec<u8>)>, String>;
+}
+
+/// A SQLite database in WAL mode, synced on every write.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let conn = Connection::open(path).map_err(|e| e.to_string())?;
+        conn.execute_batch("PRAGMA journal_mode = WAL;
+                            PRAGMA synchronous = FULL;
+                            CREATE TABLE IF NOT EXISTS metadata (
+                                namespace TEXT NOT NULL,
+                                key TEXT NOT NULL,
+                                value BLOB NOT NULL,
+                                PRIMARY KEY (namespace, key)
+                            );")
+            .map_err(|e| e.to_string())?;
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+impl MetadataStore for SqliteStore {
+    fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>, String> {
+        self.conn.lock().unwrap()
+            .query_row("SELECT value FROM metadata WHERE namespace = ?1 AND key = ?2", params![namespace, key], |row| row.get(0))
+            .optional()
+            .map_err(|e| e.to_string())
+    }
+
+    fn put(&self, namespace: &str, key: &str, value: &[u8]) -> Result<(), String> {
+        self.conn.lock().unwrap()
+            .execute("INSERT OR REPLACE INTO metadata (namespace, key, value) VALUES (?1, ?2, ?3)", params![namespace, key, value])
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn delete(&self, namespace: &str, key: &str) -> Result<(), String> {
+        self.conn.lock().unwrap()
+            .execute("DELETE FROM metadata WHERE namespace = ?1 AND key = ?2", params![namespace, key])
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn scan(&self, namespace: &str) -> Result<Vec<(String, Vec<u8>)>, String> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT key, value FROM metadata WHERE namespace = ?1 ORDER BY key").map_err(|e| e.to_string())?;
+        let rows = statement.query_map(params![namespace], |row| Ok((row.get(0)?, row.get(1)?))).map_err(|e| e.to_string())?;
+        rows.collect::<Result<Vec<(String, Vec<u8>)>, _>>().map_err(|e| e.to_string())
+    }
+}
+
+/// Kept in memory only, lost on restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    values: Mutex<BTreeMap<(String, String), Vec<u8>>>,
+}
+
+impl MetadataStore for MemoryStore {
+    fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.values.lock().unwrap().get(&(namespace.to_string(), key.to_string())).cloned())
+    }
+
+    fn put(&self, namespace: &str, key: &str, value: &[u8]) -> Result<(), String> {
+        self.values.lock().unwrap().insert((namespace.to_string(), key.to_string()), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, namespace: &str, key: &str) -> Result<(), String> {
+        self.values.lock().unwrap().remove(&(namespace.to_string(), key.to_string()));
+        Ok(())
+    }
+
+    fn scan(&self, namespace: &str) -> Result<Vec<(String, Vec<u8>)>, String> {
+        Ok(self.values.lock().unwrap().iter()
+            .filter(|((value_namespace, _), _)| value_namespace == namespace)
+            .map(|((_, key), value)| (key.clone(), value.clone()))
+            .collect())
+    }
+}
+
+fn new_store(config: &MetadataStoreConfig) -> Result<Arc<dyn MetadataStore>, String> {
+    match config.get_backend().as_str() {
+        "sqlite" => Ok(Arc::new(SqliteStore::open(&config.get_path())?)),
+        "memory" => Ok(Arc::new(MemoryStore::default())),
+        backend => Err(format!("unknown metadata store backend {}, expected sqlite or memory", backend)),
+    }
+}
+
+lazy_static! {
+    static ref METADATA_STORE: RwLock<Option<Arc<dyn MetadataStore>>> = RwLock::new(None);
+    /// The backend and path of the store open.
+    static ref OPENED: Mutex<Option<(String, String)>> = Mutex::new(None);
+}
+
+/// Keeps the store open across reloads unless its backend or path changed.
+pub fn configure_metadata_store(config: &MetadataStoreConfig) -> Result<(), String> {
+    let mut opened = OPENED.lock().unwrap();
+    let wanted = if config.is_enabled() { Some((config.get_backend(), config.get_path())) } else { None };
+    if *opened == wanted {
+        return Ok(());
+    }
+    let store = if config.is_enabled() { Some(new_store(config)?) } else { None };
+    *METADATA_STORE.write().unwrap() = store;
+    *opened = wanted;
+    Ok(())
+}
+
+pub fn metadata_store() -> Option<Arc<dyn MetadataStore>> {
+    METADATA_STORE.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{MemoryStore, MetadataStore, SqliteStore};
+
+    fn check_store(store: &dyn MetadataStore) {
+        store.put("xa", "x-2", b"prepare x-2").unwrap();
+        store.put("xa", "x-1", b"prepare x-1").unwrap();
+        store.put("cdc", "position", b"binlog.000001:4").unwrap();
+        store.put("xa", "x-1", b"prepare x-1\ncommit x-1").unwrap();
+        assert_eq!(store.get("xa", "x-1").unwrap(), Some(b"prepare x-1\ncommit x-1".to_vec()));
+        assert_eq!(store.get("xa", "position").unwrap(), None);
+        store.delete("xa", "x-2").unwrap();
+        assert_eq!(store.scan("xa").unwrap(), vec![("x-1".to_string(), b"prepare x-1\ncommit x-1".to_vec())]);
+    }
+
+    #[test]
+    fn test_metadata_stores() {
+        check_store(&MemoryStore::default());
+
+        let path = std::env::temp_dir().join(format!("martlet-meta-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        check_store(&SqliteStore::open(&path).unwrap());
+        let reopened = SqliteStore::open(&path).unwrap();
+        assert_eq!(reopened.get("cdc", "position").unwrap(), Some(b"binlog.000001:4".to_vec()));
+        drop(reopened);
+        for file in [path.clone(), format!("{}-wal", path), format!("{}-shm", path)].iter() {
+            let _ = fs::remove_file(file);
+        }
+    }
+}
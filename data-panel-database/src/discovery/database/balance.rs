@@ -0,0 +1,199 @@
+//! Spreading sessions over the mirrors of a segment.
+//!
+//! A backend segment named like `data-100/mirrors` stands for whichever routable mirror of
+//! `data-100` the load balancer of the cluster picks, or its primary while no mirror is
+//! healthy. The cluster document selects the balancer with `load_balancer`: `round_robin`, the
+//! default, `least_connections`, `weighted` by the `weight` of each mirror, or `latency` by the
+//! last health probe of each mirror.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::discovery::database::Cluster;
+use crate::discovery::database::health;
+use crate::handler::database::lifecycle;
+
+/// A healthy mirror a session may be routed to.
+#[derive(Debug, Clone)]
+pub struct MirrorCandidate {
+    segment: String,
+    weight: u32,
+    connections: u64,
+    latency: Option<Duration>,
+}
+
+impl MirrorCandidate {
+    pub fn new(segment: &str, weight: u32, connections: u64, latency: Option<Duration>) -> Self {
+        MirrorCandidate {
+            segment: segment.to_string(),
+            weight,
+            connections,
+            latency,
+        }
+    }
+
+    pub fn get_segment(&self) -> &String {
+        &self.segment
+    }
+
+    pub fn get_weight(&self) -> u32 {
+        self.weight
+    }
+
+    /// Backend connections held on the mirror right now.
+    pub fn get_connections(&self) -> u64 {
+        self.connections
+    }
+
+    pub fn get_latency(&self) -> Option<Duration> {
+        self.latency
+    }
+}
+
+pub trait LoadBalancer: Send + Sync {
+    /// The index of the mirror to route to among `candidates`, which is never empty.
+    fn choose(&self, candidates: &[MirrorCandidate]) -> usize;
+}
+
+#[derive(Default)]
+pub struct RoundRobinBalancer {
+    next: AtomicUsize,
+}
+
+impl LoadBalancer for RoundRobinBalancer {
+    fn choose(&self, candidates: &[MirrorCandidate]) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()
+    }
+}
+
+#[derive(Default)]
+pub struct LeastConnectionsBalancer {}
+
+impl LoadBalancer for LeastConnectionsBalancer {
+    fn choose(&self, candidates: &[MirrorCandidate]) -> usize {
+        (0..candidates.len()).min_by_key(|index| candidates[*index].connections).unwrap_or(0)
+    }
+}
+
+/// Smooth weighted round-robin: over the sum of the weights, every mirror is picked as often
+/// as its weight says, spread out rather than in runs.
+#[derive(Default)]
+pub struct WeightedBalancer {
+    current: Mutex<HashMap<String, i64>>,
+}
+
+impl LoadBalancer for WeightedBalancer {
+    fn choose(&self, candidates: &[MirrorCandidate]) -> usize {
+        let mut current = self.current.lock().unwrap();
+        let total: i64 = candidates.iter().map(|candidate| candidate.weight as i64).sum();
+        let mut chosen = 0;
+        let mut chosen_weight = i64::MIN;
+        for (index, candidate) in candidates.iter().enumerate() {
+            let weight = current.entry(candidate.segment.clone()).or_insert(0);
+            *weight += candidate.weight as i64;
+            if *weight > chosen_weight {
+                chosen = index;
+                chosen_weight = *weight;
+            }
+        }
+        if let Some(weight) = current.get_mut(&candidates[chosen].segment) {
+            *weight -= total;
+        }
+        chosen
+    }
+}
+
+/// Picks the mirror that answered its last health probe fastest, mirrors not probed yet last.
+#[derive(Default)]
+pub struct LatencyAwareBalancer {}
+
+impl LoadBalancer for LatencyAwareBalancer {
+    fn choose(&self, candidates: &[MirrorCandidate]) -> usize {
+        (0..candidates.len()).min_by_key(|index| (candidates[*index].latency.is_none(), candidates[*index].latency)).unwrap_or(0)
+    }
+}
+
+pub(crate) fn new_load_balancer(name: &str) -> Result<Arc<dyn LoadBalancer>, String> {
+    match name.to_lowercase().as_str() {
+        "" | "round_robin" => Ok(Arc::new(RoundRobinBalancer::default())),
+        "least_connections" => Ok(Arc::new(LeastConnectionsBalancer::default())),
+        "weighted" => Ok(Arc::new(WeightedBalancer::default())),
+        "latency" => Ok(Arc::new(LatencyAwareBalancer::default())),
+        _ => Err(format!("unknown load balancer {}", name)),
+    }
+}
+
+lazy_static! {
+    /// Balancers by cluster name, with the strategy they were made for.
+    static ref LOAD_BALANCERS: RwLock<HashMap<String, (String, Arc<dyn LoadBalancer>)>> = RwLock::new(HashMap::new());
+}
+
+/// The balancer of `cluster`, kept across sessions for as long as the cluster asks for it.
+pub fn load_balancer(cluster: &Cluster) -> Arc<dyn LoadBalancer> {
+    let name = cluster.get_load_balancer();
+    if let Some((strategy, balancer)) = LOAD_BALANCERS.read().unwrap().get(cluster.get_name()) {
+        if strategy == name {
+            return balancer.clone();
+        }
+    }
+    let balancer = new_load_balancer(name).unwrap_or_else(|e| {
+        println!("error on load balancer of cluster {}, balancing round robin; error = {:?}", cluster.get_name(), e);
+        Arc::new(RoundRobinBalancer::default())
+    });
+    LOAD_BALANCERS.write().unwrap().insert(cluster.get_name().clone(), (name.clone(), balancer.clone()));
+    balancer
+}
+
+/// The group of a segment standing for all mirrors, e.g. `data-100` of `data-100/mirrors`.
+pub fn mirror_group(segment: &str) -> Option<&str> {
+    segment.strip_suffix("/mirrors")
+}
+
+/// The mirrors of `group` that are healthy and not stale, as candidates.
+fn mirror_candidates(cluster: &Cluster, group: &str) -> Vec<MirrorCandidate> {
+    cluster.mirrors(group).into_iter()
+        .filter(|(name, segment)| health::is_routable(name) && segment.get_weight() > 0)
+        .map(|(name, segment)| {
+            let connections = lifecycle::active_connections(&segment.to_mysql_url());
+            MirrorCandidate::new(&name, segment.get_weight(), connections, health::probe_latency(&name))
+        })
+        .collect()
+}
+
+/// The mirror of `group` a new session goes to, or the primary while no mirror is healthy.
+pub fn choose_mirror(cluster: &Cluster, group: &str) -> String {
+    let candidates = mirror_candidates(cluster, group);
+    if candidates.is_empty() {
+        return format!("{}/primary", group);
+    }
+    let chosen = load_balancer(cluster).choose(&candidates);
+    candidates[chosen.min(candidates.len() - 1)].segment.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{LatencyAwareBalancer, LeastConnectionsBalancer, LoadBalancer, MirrorCandidate, RoundRobinBalancer, WeightedBalancer};
+
+    #[test]
+    fn test_load_balancers() {
+        let candidates = vec![
+            MirrorCandidate::new("data-100/mirror-0", 1, 4, Some(Duration::from_millis(9))),
+            MirrorCandidate::new("data-100/mirror-1", 3, 1, None),
+            MirrorCandidate::new("data-100/mirror-2", 1, 2, Some(Duration::from_millis(2))),
+        ];
+        let round_robin = RoundRobinBalancer::default();
+        let chosen: Vec<usize> = (0..4).map(|_| round_robin.choose(&candidates)).collect();
+        assert_eq!(chosen, vec![0, 1, 2, 0]);
+
+        assert_eq!(LeastConnectionsBalancer::default().choose(&candidates), 1);
+        assert_eq!(LatencyAwareBalancer::default().choose(&candidates), 2);
+
+        let weighted = WeightedBalancer::default();
+        let chosen: Vec<usize> = (0..5).map(|_| weighted.choose(&candidates)).collect();
+        assert_eq!(chosen, vec![1, 0, 1, 2, 1]);
+    }
+}
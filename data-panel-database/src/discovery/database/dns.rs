@@ -0,0 +1,104 @@
+//! Backend addresses discovered from DNS, see `DnsDiscoveryConfig`.
+//!
+//! Every name configured is resolved with the resolver of the system, its addresses becoming
+//! the ones of the segment it stands for, see `endpoints`. A name is resolved again when its
+//! records expire, or after the `ttl` configured, the delay jittered so that meshes started
+//! together do not query together. A name that fails to resolve keeps its last addresses.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::TokioAsyncResolver;
+
+use data_panel_common::config::config::{DnsDiscoveryConfig, DnsName};
+
+use crate::discovery::database::endpoints;
+
+/// The shortest delay between two resolutions of a name, for records of a TTL of 0.
+const MIN_REFRESH_DELAY: Duration = Duration::from_secs(1);
+
+/// `ttl` give or take `jitter` of it, `random` being within [0, 1).
+fn refresh_delay(ttl: Duration, jitter: f64, random: f64) -> Duration {
+    let factor = 1.0 + jitter * (2.0 * random - 1.0);
+    ttl.mul_f64(factor).max(MIN_REFRESH_DELAY)
+}
+
+/// The addresses of `name`, sorted, and when they expire.
+async fn resolve(resolver: &TokioAsyncResolver, name: &DnsName) -> Result<(Vec<String>, Instant), ResolveError> {
+    let mut addresses = vec![];
+    let valid_until = if name.is_srv() {
+        let records = resolver.srv_lookup(name.get_name()).await?;
+        let mut valid_until = records.as_lookup().valid_until();
+        for record in records.iter() {
+            let ips = resolver.lookup_ip(record.target().to_utf8()).await?;
+            valid_until = valid_until.min(ips.valid_until());
+            addresses.extend(ips.iter().map(|ip| SocketAddr::new(ip, record.port()).to_string()));
+        }
+        valid_until
+    } else {
+        let ips = resolver.lookup_ip(name.get_name()).await?;
+        addresses.extend(ips.iter().map(|ip| SocketAddr::new(ip, name.get_port()).to_string()));
+        ips.valid_until()
+    };
+    addresses.sort();
+    addresses.dedup();
+    Ok((addresses, valid_until))
+}
+
+/// Resolves `name` for as long as the mesh runs.
+async fn resolve_name(resolver: TokioAsyncResolver, name: DnsName, config: DnsDiscoveryConfig) {
+    let mut resolved: Option<Vec<String>> = None;
+    loop {
+        let delay = match resolve(&resolver, &name).await {
+            Ok((addresses, valid_until)) => {
+                if resolved.as_ref() != Some(&addresses) {
+                    endpoints::discovered(&format!("name {}", name.get_name()), &name.get_segment(), addresses.clone());
+                    resolved = Some(addresses);
+                }
+                let ttl = match config.get_ttl() {
+                    0 => valid_until.saturating_duration_since(Instant::now()),
+                    ttl => Duration::from_millis(ttl as u64),
+                };
+                refresh_delay(ttl, config.get_jitter(), rand::random())
+            }
+            Err(e) => {
+                println!("error on resolving name {}; error = {}", name.get_name(), e);
+                Duration::from_millis(config.get_retry_interval() as u64)
+            }
+        };
+        tokio::time::sleep(delay).await;
+    }
+}
+
+pub fn spawn_dns_discovery(config: DnsDiscoveryConfig) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let resolver = match TokioAsyncResolver::tokio_from_system_conf() {
+            Ok(resolver) => resolver,
+            Err(e) => {
+                println!("error on reading the resolver config of the system; error = {}", e);
+                return;
+            }
+        };
+        for name in config.get_names().iter() {
+            tokio::spawn(resolve_name(resolver.clone(), name.clone(), config.clone()));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{MIN_REFRESH_DELAY, refresh_delay};
+
+    #[test]
+    fn test_refresh_delay() {
+        let ttl = Duration::from_secs(30);
+        assert_eq!(refresh_delay(ttl, 0.0, 0.9), ttl);
+        assert_eq!(refresh_delay(ttl, 0.1, 0.0), Duration::from_secs(27));
+        assert_eq!(refresh_delay(ttl, 0.1, 0.5), ttl);
+        assert!(refresh_delay(ttl, 0.1, 0.999) < Duration::from_secs(33));
+        assert_eq!(refresh_delay(Duration::from_secs(0), 0.1, 0.5), MIN_REFRESH_DELAY);
+    }
+}
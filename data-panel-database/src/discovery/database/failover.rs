@@ -0,0 +1,238 @@
+//! Failover of unhealthy primaries to one of their mirrors, see `HealthCheckConfig`.
+//!
+//! Once the health checks find the primary of a segment group unhealthy, with failover on, a
+//! mirror of the group is promoted to take its writes: the one the control plane at
+//! `promotion_url` names, or else the configured `promote` mirror while it is routable, or else
+//! the first routable one. A promotion holds, even once the old primary is healthy again, until
+//! it is demoted through the admin API, so writes never go back to a primary the mirror may have
+//! diverged from.
+//!
+//! Every promotion and demotion fences the segment writes are taken off. Sessions holding a
+//! transaction or a pinned connection on it have them rolled back and their next command fails
+//! with a deadlock error, which clients retry, before they move to the new write target.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::{Body, Client, Method, Request};
+use serde_json::json;
+
+use data_panel_common::config::config::HealthCheckConfig;
+
+use crate::discovery::database::Cluster;
+use crate::discovery::database::health;
+use crate::handler::database::lifecycle::redact_url;
+use crate::service::shutdown::service_counters;
+
+/// The most failover events kept for the admin API.
+const FAILOVER_EVENTS_KEPT: usize = 100;
+
+#[derive(Debug, Clone)]
+pub struct FailoverEvent {
+    group: String,
+    from: String,
+    to: String,
+    /// Who chose the new write target: `control_plane`, `config` or `admin`.
+    source: String,
+    at: u64,
+    epoch: u64,
+    fenced_url: String,
+}
+
+impl FailoverEvent {
+    pub fn get_group(&self) -> String {
+        self.group.clone()
+    }
+
+    pub fn get_from(&self) -> String {
+        self.from.clone()
+    }
+
+    pub fn get_to(&self) -> String {
+        self.to.clone()
+    }
+
+    pub fn get_source(&self) -> String {
+        self.source.clone()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "group": self.group,
+            "from": self.from,
+            "to": self.to,
+            "source": self.source,
+            "at": self.at,
+            "epoch": self.epoch,
+            "fenced": redact_url(&self.fenced_url),
+        })
+    }
+}
+
+lazy_static! {
+    /// The promotion in force by segment group.
+    static ref PROMOTIONS: RwLock<HashMap<String, FailoverEvent>> = RwLock::new(HashMap::new());
+    static ref FAILOVER_EVENTS: Mutex<VecDeque<FailoverEvent>> = Mutex::new(VecDeque::new());
+}
+
+/// Counts the promotions and demotions, sessions compare it with the one they last saw.
+static FENCING_EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// The mirror taking the writes of the primary of `group`, e.g. `data-100/mirror-1`.
+pub fn promoted(group: &str) -> Option<String> {
+    PROMOTIONS.read().unwrap().get(group).map(|event| event.to.clone())
+}
+
+pub fn fencing_epoch() -> u64 {
+    FENCING_EPOCH.load(Ordering::Acquire)
+}
+
+/// Whether writes were taken off `database_url` after fencing epoch `epoch`.
+pub fn is_fenced_since(epoch: u64, database_url: &str) -> bool {
+    FAILOVER_EVENTS.lock().unwrap().iter()
+        .any(|event| event.epoch > epoch && event.fenced_url == database_url)
+}
+
+/// The promotions in force.
+pub fn promotions() -> Vec<FailoverEvent> {
+    PROMOTIONS.read().unwrap().values().cloned().collect()
+}
+
+/// The recent promotions and demotions, oldest first.
+pub fn failover_events() -> Vec<FailoverEvent> {
+    FAILOVER_EVENTS.lock().unwrap().iter().cloned().collect()
+}
+
+fn record(group: &str, from: &str, to: &str, source: &str, fenced_url: String) -> FailoverEvent {
+    let event = FailoverEvent {
+        group: group.to_string(),
+        from: from.to_string(),
+        to: to.to_string(),
+        source: source.to_string(),
+        at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        epoch: FENCING_EPOCH.fetch_add(1, Ordering::AcqRel) + 1,
+        fenced_url,
+    };
+    let mut events = FAILOVER_EVENTS.lock().unwrap();
+    events.push_back(event.clone());
+    while events.len() > FAILOVER_EVENTS_KEPT {
+        events.pop_front();
+    }
+    println!("Writes of segment group {} moved from {} to {}, chosen by {}", group, from, to, source);
+    event
+}
+
+fn promote(group: &str, to: &str, source: &str, fenced_url: String) {
+    let event = record(group, &format!("{}/primary", group), to, source, fenced_url);
+    PROMOTIONS.write().unwrap().insert(group.to_string(), event);
+    service_counters().failed_over();
+}
+
+/// Gives the writes of `group` back to its primary, fencing the promoted mirror.
+pub fn demote(cluster: &Cluster, group: &str) -> Option<FailoverEvent> {
+    let promotion = PROMOTIONS.write().unwrap().remove(group)?;
+    let fenced_url = cluster.segment(&promotion.to).map(|segment| segment.to_mysql_url()).unwrap_or_default();
+    Some(record(group, &promotion.to, &promotion.from, "admin", fenced_url))
+}
+
+/// The mirror of `group` to promote without the control plane: `promote`, e.g. `mirror-1`,
+/// while it is routable, or else the first routable mirror.
+fn choose_promotion(cluster: &Cluster, group: &str, promote: &str) -> Option<String> {
+    let routable: Vec<String> = cluster.mirrors(group).into_iter()
+        .map(|(name, _)| name)
+        .filter(|name| health::is_routable(name))
+        .collect();
+    let configured = format!("{}/{}", group, promote);
+    if !promote.is_empty() && routable.contains(&configured) {
+        return Some(configured);
+    }
+    routable.into_iter().next()
+}
+
+/// Asks the control plane which mirror of `group` to promote. It answers a JSON object whose
+/// `segment` names a mirror of the group, like `data-100/mirror-1`.
+async fn ask_control_plane(cluster: &Cluster, group: &str, config: &HealthCheckConfig) -> Result<String, String> {
+    let mirrors: Vec<serde_json::Value> = cluster.mirrors(group).into_iter()
+        .map(|(name, _)| json!({ "segment": name, "healthy": health::is_healthy(&name), "stale": health::is_stale(&name) }))
+        .collect();
+    let body = json!({ "cluster": cluster.get_name(), "group": group, "mirrors": mirrors }).to_string();
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(config.get_promotion_url().as_str())
+        .header("content-type", "application/json")
+        .body(Body::from(body))
+        .map_err(|e| e.to_string())?;
+    let timeout = Duration::from_millis(config.get_timeout() as u64);
+    let response = match tokio::time::timeout(timeout, Client::new().request(request)).await {
+        Ok(Ok(response)) if response.status().is_success() => response,
+        Ok(Ok(response)) => return Err(format!("status {}", response.status())),
+        Ok(Err(e)) => return Err(e.to_string()),
+        Err(_) => return Err(format!("no answer within {} ms", timeout.as_millis())),
+    };
+    let bytes = hyper::body::to_bytes(response.into_body()).await.map_err(|e| e.to_string())?;
+    let answer: serde_json::Value = serde_json::from_slice(&bytes).map_err(|e| e.to_string())?;
+    match answer["segment"].as_str() {
+        Some(segment) if cluster.mirrors(group).iter().any(|(name, _)| name == segment) => Ok(segment.to_string()),
+        Some(segment) => Err(format!("{} is no mirror of {}", segment, group)),
+        None => Err("no segment in the answer".to_string()),
+    }
+}
+
+/// Promotes a mirror of `group` in place of its unhealthy primary, unless one already is.
+pub async fn fail_over(cluster: &Cluster, group: &str, config: &HealthCheckConfig) {
+    if promoted(group).is_some() {
+        return;
+    }
+    let fenced_url = match cluster.segment(&format!("{}/primary", group)) {
+        Some(primary) => primary.to_mysql_url(),
+        None => return,
+    };
+    if !config.get_promotion_url().is_empty() {
+        match ask_control_plane(cluster, group, config).await {
+            Ok(segment) => return promote(group, &segment, "control_plane", fenced_url),
+            Err(e) => println!("error on asking the control plane to fail over {}, choosing locally; error = {:?}", group, e),
+        }
+    }
+    match choose_promotion(cluster, group, &config.get_promote()) {
+        Some(segment) => promote(group, &segment, "config", fenced_url),
+        None => println!("error on failing over {}; error = \"no routable mirror\"", group),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discovery::database::{Cluster, Segment};
+
+    use super::{choose_promotion, demote, fencing_epoch, is_fenced_since, promote, promoted};
+
+    #[test]
+    fn test_promotion() {
+        let cluster = Cluster::builder("failover")
+            .meta_segment(Segment::new(0, "jdbc:mysql://meta:3306/martlet", "root", "root"), vec![])
+            .data_segment(800, Segment::new(0, "jdbc:mysql://primary:3306/martlet", "root", "root"),
+                          vec![Segment::new(1, "jdbc:mysql://mirror-0:3306/martlet", "root", "root"),
+                               Segment::new(2, "jdbc:mysql://mirror-1:3306/martlet", "root", "root")])
+            .build()
+            .unwrap();
+        assert_eq!(choose_promotion(&cluster, "data-800", "mirror-1"), Some("data-800/mirror-1".to_string()));
+        assert_eq!(choose_promotion(&cluster, "data-800", "mirror-7"), Some("data-800/mirror-0".to_string()));
+        assert_eq!(choose_promotion(&cluster, "data-900", ""), None);
+
+        let epoch = fencing_epoch();
+        let primary_url = cluster.segment("data-800/primary").unwrap().to_mysql_url();
+        let mirror_url = cluster.segment("data-800/mirror-1").unwrap().to_mysql_url();
+        promote("data-800", "data-800/mirror-1", "config", primary_url.clone());
+        assert_eq!(promoted("data-800"), Some("data-800/mirror-1".to_string()));
+        assert!(is_fenced_since(epoch, &primary_url));
+        assert!(!is_fenced_since(epoch, &mirror_url));
+        assert!(!is_fenced_since(fencing_epoch(), &primary_url));
+
+        let demoted = demote(&cluster, "data-800").unwrap();
+        assert_eq!(demoted.get_to(), "data-800/primary");
+        assert_eq!(promoted("data-800"), None);
+        assert!(is_fenced_since(epoch, &mirror_url));
+        assert!(demote(&cluster, "data-800").is_none());
+    }
+}
@@ -0,0 +1,113 @@
+//! Backend addresses discovered from Kubernetes, see `KubernetesDiscoveryConfig`.
+//!
+//! The Endpoints of every service configured are watched, their ready addresses becoming the
+//! ones of the segment the service stands for, see `endpoints`.
+
+use std::fs;
+use std::time::Duration;
+
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Endpoints;
+use kube::{Api, Client};
+use kube::api::ListParams;
+use kube_runtime::watcher;
+use kube_runtime::watcher::Event;
+use serde_json::Value;
+
+use data_panel_common::config::config::{KubernetesDiscoveryConfig, KubernetesService};
+
+use crate::discovery::database::endpoints;
+
+/// The namespace of the pod, mounted with its service account token.
+const POD_NAMESPACE_FILE: &str = "/var/run/secrets/kubernetes.io/serviceaccount/namespace";
+
+/// The ready `host:port` addresses of `endpoints` on the port named `port`, or the first one.
+fn endpoint_addresses(endpoints: &Endpoints, port: &str) -> Vec<String> {
+    let endpoints = serde_json::to_value(endpoints).unwrap_or(Value::Null);
+    let mut addresses = vec![];
+    for subset in endpoints["subsets"].as_array().into_iter().flatten() {
+        let ports = subset["ports"].as_array().map_or(&[][..], |ports| ports.as_slice());
+        let number = match ports.iter().find(|candidate| port.is_empty() || candidate["name"].as_str() == Some(port)) {
+            Some(found) => found["port"].as_i64().unwrap_or(0),
+            None => continue,
+        };
+        for address in subset["addresses"].as_array().into_iter().flatten() {
+            if let Some(ip) = address["ip"].as_str() {
+                addresses.push(format!("{}:{}", ip, number));
+            }
+        }
+    }
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
+
+fn discovered(service: &KubernetesService, addresses: Vec<String>) {
+    endpoints::discovered(&format!("service {}", service.get_service()), &service.get_segment(), addresses);
+}
+
+/// Watches the Endpoints of `service` for as long as the mesh runs.
+async fn watch_service(client: Client, namespace: String, service: KubernetesService, retry_interval: Duration) {
+    let api: Api<Endpoints> = Api::namespaced(client, &namespace);
+    let list_params = ListParams::default().fields(&format!("metadata.name={}", service.get_service()));
+    let mut events = Box::pin(watcher(api, list_params));
+    while let Some(event) = events.next().await {
+        match event {
+            Ok(Event::Applied(found)) => discovered(&service, endpoint_addresses(&found, &service.get_port())),
+            Ok(Event::Deleted(_)) => discovered(&service, vec![]),
+            Ok(Event::Restarted(found)) => {
+                let addresses = found.first().map_or(vec![], |found| endpoint_addresses(found, &service.get_port()));
+                discovered(&service, addresses);
+            }
+            Err(e) => {
+                println!("error on watching the endpoints of service {}/{}; error = {}", namespace, service.get_service(), e);
+                // The watcher lists the endpoints again on the next poll.
+                tokio::time::sleep(retry_interval).await;
+            }
+        }
+    }
+}
+
+pub fn spawn_kubernetes_discovery(config: KubernetesDiscoveryConfig) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let client = match Client::try_default().await {
+            Ok(client) => client,
+            Err(e) => {
+                println!("error on connecting to the Kubernetes API; error = {}", e);
+                return;
+            }
+        };
+        let namespace = match config.get_namespace() {
+            namespace if !namespace.is_empty() => namespace,
+            _ => fs::read_to_string(POD_NAMESPACE_FILE).map(|namespace| namespace.trim().to_string()).unwrap_or_else(|_| "default".to_string()),
+        };
+        let retry_interval = Duration::from_millis(config.get_retry_interval() as u64);
+        for service in config.get_services().iter() {
+            tokio::spawn(watch_service(client.clone(), namespace.clone(), service.clone(), retry_interval));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::core::v1::Endpoints;
+    use serde_json::json;
+
+    use super::endpoint_addresses;
+
+    #[test]
+    fn test_kubernetes_endpoints() {
+        let endpoints: Endpoints = serde_json::from_value(json!({
+            "apiVersion": "v1",
+            "kind": "Endpoints",
+            "metadata": { "name": "mysql-100-replicas" },
+            "subsets": [{
+                "addresses": [{ "ip": "10.0.0.9" }, { "ip": "10.0.0.8" }],
+                "notReadyAddresses": [{ "ip": "10.0.0.10" }],
+                "ports": [{ "name": "metrics", "port": 9104 }, { "name": "mysql", "port": 3306 }],
+            }],
+        })).unwrap();
+        assert_eq!(endpoint_addresses(&endpoints, "mysql"), vec!["10.0.0.8:3306", "10.0.0.9:3306"]);
+        assert_eq!(endpoint_addresses(&endpoints, ""), vec!["10.0.0.8:9104", "10.0.0.9:9104"]);
+    }
+}
@@ -0,0 +1,181 @@
+//! Routing rules streamed from the control plane.
+//!
+//! The mesh calls `StreamRules` on pilot, a server streaming gRPC method using the JSON codec
+//! (`application/grpc+json`), telling it the version of the rules it has. Pilot answers with a
+//! snapshot of the cluster document, the one dbmesh.yaml holds, then with JSON merge patches
+//! (RFC 7386) of it as the rules change. Every update is activated like the file based rules
+//! and gets its own version. On a patch that cannot be applied, or a lost stream, the mesh
+//! reconnects and gets a new snapshot.
+
+use std::time::Duration;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use hyper::{Body, Client, Request};
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use data_panel_common::config::config::ControlConfig;
+
+use crate::discovery::database::endpoints;
+use crate::discovery::database::rules::{current_rules, current_rules_version, load_rules_json};
+
+const STREAM_RULES_PATH: &str = "/martlet.discovery.v1.RulesDiscovery/StreamRules";
+
+/// Length of the gRPC message prefix: the compressed flag and the message length.
+const MESSAGE_PREFIX_LEN: usize = 5;
+
+#[derive(Serialize)]
+struct RulesRequest {
+    node: String,
+    version: String,
+}
+
+#[derive(Deserialize)]
+struct RulesUpdate {
+    #[serde(default)]
+    snapshot: Option<Value>,
+    #[serde(default)]
+    patch: Option<Value>,
+}
+
+/// Applies the JSON merge patch `patch` to `target`.
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let patch = match patch {
+        Value::Object(patch) => patch,
+        patch => {
+            *target = patch.clone();
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let target = target.as_object_mut().unwrap();
+    for (key, value) in patch.iter() {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+/// Activates the rules of `update`, returns their version.
+fn apply(update: RulesUpdate) -> Result<String, String> {
+    let mut document = match (update.snapshot, update.patch) {
+        (Some(snapshot), _) => snapshot,
+        (None, Some(patch)) => {
+            let rules = current_rules().ok_or_else(|| "a patch arrived before any rules".to_string())?;
+            let mut document = serde_json::to_value(&*rules.get_cluster()).map_err(|e| e.to_string())?;
+            merge_patch(&mut document, &patch);
+            document
+        }
+        (None, None) => return Err("an update without snapshot or patch".to_string()),
+    };
+    endpoints::with_discovered(&mut document)?;
+    load_rules_json(&document.to_string())
+}
+
+pub(crate) fn grpc_message(message: &[u8]) -> Bytes {
+    let mut frame = BytesMut::with_capacity(MESSAGE_PREFIX_LEN + message.len());
+    frame.put_u8(0);
+    frame.put_u32(message.len() as u32);
+    frame.put_slice(message);
+    frame.freeze()
+}
+
+/// Splits the next complete message off `buffer`, None until it arrived whole.
+pub(crate) fn next_message(buffer: &mut BytesMut) -> Result<Option<Bytes>, String> {
+    if buffer.len() < MESSAGE_PREFIX_LEN {
+        return Ok(None);
+    }
+    if buffer[0] != 0 {
+        return Err("compressed messages are not supported".to_string());
+    }
+    let len = u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]) as usize;
+    if buffer.len() < MESSAGE_PREFIX_LEN + len {
+        return Ok(None);
+    }
+    buffer.advance(MESSAGE_PREFIX_LEN);
+    Ok(Some(buffer.split_to(len).freeze()))
+}
+
+/// The gRPC status of a call, from the trailers or from the headers of a trailers-only answer.
+pub(crate) fn grpc_status(headers: Option<&hyper::HeaderMap>) -> String {
+    headers.and_then(|headers| headers.get("grpc-status"))
+        .and_then(|status| status.to_str().ok())
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+/// Streams the rules until the stream ends, which is always an error for the caller.
+async fn stream_rules(client: &Client<HttpConnector>, config: &ControlConfig) -> Result<(), String> {
+    let request = RulesRequest {
+        node: config.get_node(),
+        version: current_rules_version(),
+    };
+    let message = serde_json::to_vec(&request).map_err(|e| e.to_string())?;
+    let request = Request::post(format!("http://{}{}", config.get_pilot(), STREAM_RULES_PATH))
+        .header(CONTENT_TYPE, "application/grpc+json")
+        .header("te", "trailers")
+        .body(Body::from(grpc_message(&message)))
+        .map_err(|e| e.to_string())?;
+    let mut response = client.request(request).await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("pilot answered {}", response.status()));
+    }
+    if response.headers().contains_key("grpc-status") {
+        return Err(format!("pilot answered grpc-status {}", grpc_status(Some(response.headers()))));
+    }
+    let mut buffer = BytesMut::new();
+    while let Some(chunk) = response.body_mut().data().await {
+        buffer.extend_from_slice(&chunk.map_err(|e| e.to_string())?);
+        while let Some(message) = next_message(&mut buffer)? {
+            let update: RulesUpdate = serde_json::from_slice(&message).map_err(|e| e.to_string())?;
+            let version = apply(update)?;
+            println!("Loaded rules version {} from pilot", version);
+        }
+    }
+    let trailers = response.body_mut().trailers().await.ok().flatten();
+    Err(format!("stream ended with grpc-status {}", grpc_status(trailers.as_ref())))
+}
+
+pub fn spawn_rules_discovery(config: ControlConfig) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let client = Client::builder().http2_only(true).build_http();
+        let retry_interval = Duration::from_millis(config.get_retry_interval() as u64);
+        loop {
+            if let Err(e) = stream_rules(&client, &config).await {
+                println!("error on streaming rules from pilot {}; error = {}", config.get_pilot(), e);
+            }
+            tokio::time::sleep(retry_interval).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use serde_json::json;
+
+    use super::{grpc_message, merge_patch, next_message};
+
+    #[test]
+    fn test_rules_patch_stream() {
+        let mut document = json!({"name": "martlet", "dis_rules": {"replicated_tables": ["t_dept"], "distributed_tables": {}}});
+        merge_patch(&mut document, &json!({"dis_rules": {"replicated_tables": ["t_dept", "t_area"]}, "name": null}));
+        assert_eq!(document, json!({"dis_rules": {"replicated_tables": ["t_dept", "t_area"], "distributed_tables": {}}}));
+
+        let mut buffer = BytesMut::new();
+        buffer.extend_from_slice(&grpc_message(b"{\"patch\":{}}"));
+        let second = grpc_message(b"{}");
+        buffer.extend_from_slice(&second[..3]);
+        assert_eq!(&next_message(&mut buffer).unwrap().unwrap()[..], b"{\"patch\":{}}");
+        assert_eq!(next_message(&mut buffer).unwrap(), None);
+        buffer.extend_from_slice(&second[3..]);
+        assert_eq!(&next_message(&mut buffer).unwrap().unwrap()[..], b"{}");
+    }
+}
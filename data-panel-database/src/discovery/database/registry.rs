@@ -0,0 +1,284 @@
+//! The mesh registered in, and backend addresses discovered from, a Consul or etcd service
+//! registry, see `RegistryConfig`.
+//!
+//! In Consul, the mesh registers itself with the local agent under a TTL check that it passes
+//! while it accepts sessions and fails once it drains. The healthy instances of every service
+//! come from blocking queries of `/v1/health/service/<service>?passing`, answered as soon as
+//! they change. In etcd, through its JSON gateway, the mesh keeps its key under a lease it
+//! renews, the value telling whether it is healthy, and lists the keys of every service every
+//! third of the TTL, the instances that are not healthy left out. Either way, the instances
+//! become the addresses of the segment of the service, see `endpoints`.
+
+use std::time::Duration;
+
+use hyper::{Body, Client, Method, Request};
+use hyper::header::CONTENT_TYPE;
+use serde_json::{json, Value};
+
+use data_panel_common::config::config::{MeshConfig, RegistryConfig, RegistryService};
+
+use crate::discovery::database::endpoints;
+use crate::service::shutdown;
+
+/// How long a Consul blocking query waits for a change.
+const CONSUL_WAIT: &str = "5m";
+
+/// Calls the registry, answers the headers and the JSON body of its answer.
+async fn call(method: Method, url: &str, token: &str, body: Option<Value>) -> Result<(hyper::HeaderMap, Value), String> {
+    let mut request = Request::builder().method(method).uri(url);
+    if !token.is_empty() {
+        request = request.header("X-Consul-Token", token);
+    }
+    let body = match body {
+        Some(body) => {
+            request = request.header(CONTENT_TYPE, "application/json");
+            Body::from(body.to_string())
+        }
+        None => Body::empty(),
+    };
+    let response = Client::new().request(request.body(body).map_err(|e| e.to_string())?).await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("status {}", response.status()));
+    }
+    let (parts, body) = response.into_parts();
+    let bytes = hyper::body::to_bytes(body).await.map_err(|e| e.to_string())?;
+    let value = if bytes.is_empty() { Value::Null } else { serde_json::from_slice(&bytes).map_err(|e| e.to_string())? };
+    Ok((parts.headers, value))
+}
+
+/// The `host:port` the mesh registers.
+fn advertise(config: &RegistryConfig) -> String {
+    match config.get_advertise() {
+        advertise if !advertise.is_empty() => advertise,
+        _ => format!("{}:{}", MeshConfig::get_host(), MeshConfig::get_port()),
+    }
+}
+
+/// The addresses of the instances of a Consul health answer, the service's or else the node's.
+fn consul_addresses(entries: &Value) -> Vec<String> {
+    let mut addresses: Vec<String> = entries.as_array().into_iter().flatten()
+        .filter_map(|entry| {
+            let host = match entry["Service"]["Address"].as_str() {
+                Some(host) if !host.is_empty() => host,
+                _ => entry["Node"]["Address"].as_str()?,
+            };
+            Some(format!("{}:{}", host, entry["Service"]["Port"].as_u64()?))
+        })
+        .collect();
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
+
+/// The end of the etcd range of the keys starting with `key`.
+fn prefix_end(key: &str) -> Vec<u8> {
+    let mut end = key.as_bytes().to_vec();
+    if let Some(last) = end.last_mut() {
+        *last += 1;
+    }
+    end
+}
+
+/// The addresses of the healthy instances of an etcd range answer.
+fn etcd_addresses(range: &Value) -> Vec<String> {
+    let mut addresses: Vec<String> = range["kvs"].as_array().into_iter().flatten()
+        .filter_map(|kv| base64::decode(kv["value"].as_str()?).ok())
+        .filter_map(|value| serde_json::from_slice::<Value>(&value).ok())
+        .filter(|instance| instance["healthy"].as_bool().unwrap_or(true))
+        .filter_map(|instance| instance["address"].as_str().map(|address| address.to_string()))
+        .collect();
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
+
+fn discovered(service: &RegistryService, found: &mut Option<Vec<String>>, addresses: Vec<String>) {
+    if found.as_ref() != Some(&addresses) {
+        endpoints::discovered(&format!("service {}", service.get_service()), &service.get_segment(), addresses.clone());
+        *found = Some(addresses);
+    }
+}
+
+/// Registers the mesh with the Consul agent and passes its check for as long as it runs.
+async fn consul_register(config: RegistryConfig) {
+    let advertise = advertise(&config);
+    let id = format!("{}-{}", config.get_service_name(), advertise.replace(':', "-"));
+    let (host, port) = match advertise.rsplitn(2, ':').collect::<Vec<&str>>().as_slice() {
+        [port, host] => (host.to_string(), port.parse::<u16>().unwrap_or(0)),
+        _ => (advertise.clone(), 0),
+    };
+    let ttl = Duration::from_millis(config.get_ttl() as u64);
+    let registration = json!({
+        "ID": id,
+        "Name": config.get_service_name(),
+        "Address": host,
+        "Port": port,
+        "Check": { "CheckID": format!("service:{}", id), "TTL": format!("{}ms", ttl.as_millis()), "DeregisterCriticalServiceAfter": "1m" },
+    });
+    let mut registered = false;
+    loop {
+        let result = if registered {
+            let status = if shutdown::is_shutting_down() { "fail" } else { "pass" };
+            let url = format!("http://{}/v1/agent/check/{}/service:{}", config.get_address(), status, id);
+            call(Method::PUT, &url, &config.get_token(), None).await
+        } else {
+            let url = format!("http://{}/v1/agent/service/register", config.get_address());
+            call(Method::PUT, &url, &config.get_token(), Some(registration.clone())).await
+        };
+        match result {
+            Ok(_) if !registered => {
+                println!("Registered {} in consul {}", id, config.get_address());
+                registered = true;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                println!("error on registering {} in consul {}; error = {}", id, config.get_address(), e);
+                registered = false;
+            }
+        }
+        tokio::time::sleep(ttl / 3).await;
+    }
+}
+
+/// Follows the healthy instances of `service` in Consul for as long as the mesh runs.
+async fn consul_watch(config: RegistryConfig, service: RegistryService) {
+    let mut index = 0u64;
+    let mut found = None;
+    loop {
+        let url = format!("http://{}/v1/health/service/{}?passing=true&index={}&wait={}", config.get_address(), service.get_service(), index, CONSUL_WAIT);
+        match call(Method::GET, &url, &config.get_token(), None).await {
+            Ok((headers, entries)) => {
+                let next = headers.get("x-consul-index").and_then(|index| index.to_str().ok()).and_then(|index| index.parse().ok()).unwrap_or(0);
+                // An index going backwards, e.g. of a restarted agent, starts over.
+                index = if next < index { 0 } else { next };
+                discovered(&service, &mut found, consul_addresses(&entries));
+            }
+            Err(e) => {
+                println!("error on watching service {} in consul {}; error = {}", service.get_service(), config.get_address(), e);
+                tokio::time::sleep(Duration::from_millis(config.get_retry_interval() as u64)).await;
+            }
+        }
+    }
+}
+
+/// Grants a lease and puts the key of the mesh under it, answers the lease.
+async fn etcd_put(config: &RegistryConfig, key: &str, value: &Value, lease: Option<&str>) -> Result<String, String> {
+    let lease = match lease {
+        Some(lease) => lease.to_string(),
+        None => {
+            let url = format!("http://{}/v3/lease/grant", config.get_address());
+            let ttl = (config.get_ttl() / 1000).to_string();
+            let (_, granted) = call(Method::POST, &url, "", Some(json!({ "TTL": ttl }))).await?;
+            granted["ID"].as_str().ok_or_else(|| "no lease granted".to_string())?.to_string()
+        }
+    };
+    let url = format!("http://{}/v3/kv/put", config.get_address());
+    let put = json!({ "key": base64::encode(key), "value": base64::encode(value.to_string()), "lease": lease });
+    call(Method::POST, &url, "", Some(put)).await?;
+    Ok(lease)
+}
+
+/// Keeps the key of the mesh in etcd for as long as it runs.
+async fn etcd_register(config: RegistryConfig) {
+    let advertise = advertise(&config);
+    let key = format!("{}/services/{}/{}", config.get_prefix(), config.get_service_name(), advertise);
+    let ttl = Duration::from_millis(config.get_ttl() as u64);
+    let mut lease: Option<String> = None;
+    let mut healthy = true;
+    loop {
+        let now_healthy = !shutdown::is_shutting_down();
+        let result = match lease.as_deref() {
+            Some(id) if now_healthy == healthy => {
+                let url = format!("http://{}/v3/lease/keepalive", config.get_address());
+                match call(Method::POST, &url, "", Some(json!({ "ID": id }))).await {
+                    // An expired lease is kept alive with no TTL left.
+                    Ok((_, kept)) if kept["result"]["TTL"].as_str().map_or(false, |ttl| ttl != "0") => Ok(id.to_string()),
+                    Ok(_) => Err("the lease expired".to_string()),
+                    Err(e) => Err(e),
+                }
+            }
+            id => etcd_put(&config, &key, &json!({ "address": advertise, "healthy": now_healthy }), id).await,
+        };
+        match result {
+            Ok(id) => {
+                if lease.is_none() {
+                    println!("Registered {} in etcd {}", key, config.get_address());
+                }
+                lease = Some(id);
+                healthy = now_healthy;
+            }
+            Err(e) => {
+                println!("error on registering {} in etcd {}; error = {}", key, config.get_address(), e);
+                lease = None;
+            }
+        }
+        tokio::time::sleep(ttl / 3).await;
+    }
+}
+
+/// Follows the healthy instances of `service` in etcd for as long as the mesh runs.
+async fn etcd_watch(config: RegistryConfig, service: RegistryService) {
+    let key = format!("{}/services/{}/", config.get_prefix(), service.get_service());
+    let range = json!({ "key": base64::encode(&key), "range_end": base64::encode(prefix_end(&key)) });
+    let url = format!("http://{}/v3/kv/range", config.get_address());
+    let mut found = None;
+    loop {
+        let delay = match call(Method::POST, &url, "", Some(range.clone())).await {
+            Ok((_, answer)) => {
+                discovered(&service, &mut found, etcd_addresses(&answer));
+                config.get_ttl() / 3
+            }
+            Err(e) => {
+                println!("error on listing service {} in etcd {}; error = {}", service.get_service(), config.get_address(), e);
+                config.get_retry_interval()
+            }
+        };
+        tokio::time::sleep(Duration::from_millis(delay as u64)).await;
+    }
+}
+
+pub fn spawn_registry(config: RegistryConfig) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let consul = config.get_provider() == "consul";
+        if config.is_register() {
+            if consul {
+                tokio::spawn(consul_register(config.clone()));
+            } else {
+                tokio::spawn(etcd_register(config.clone()));
+            }
+        }
+        for service in config.get_services().iter() {
+            if consul {
+                tokio::spawn(consul_watch(config.clone(), service.clone()));
+            } else {
+                tokio::spawn(etcd_watch(config.clone(), service.clone()));
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{consul_addresses, etcd_addresses, prefix_end};
+
+    #[test]
+    fn test_registry_instances() {
+        let entries = json!([
+            { "Node": { "Address": "10.0.1.2" }, "Service": { "Address": "10.0.0.9", "Port": 3306 } },
+            { "Node": { "Address": "10.0.1.1" }, "Service": { "Address": "", "Port": 3307 } },
+        ]);
+        assert_eq!(consul_addresses(&entries), vec!["10.0.0.9:3306", "10.0.1.1:3307"]);
+
+        assert_eq!(prefix_end("/martlet/services/mysql-100/"), b"/martlet/services/mysql-1000".to_vec());
+        let instance = |value: serde_json::Value| json!({ "key": "", "value": base64::encode(value.to_string()) });
+        let range = json!({ "kvs": [
+            instance(json!({ "address": "10.0.0.9:3306" })),
+            instance(json!({ "address": "10.0.0.8:3306", "healthy": true })),
+            instance(json!({ "address": "10.0.0.7:3306", "healthy": false })),
+        ] });
+        assert_eq!(etcd_addresses(&range), vec!["10.0.0.8:3306", "10.0.0.9:3306"]);
+        assert!(etcd_addresses(&json!({ "count": "0" })).is_empty());
+    }
+}
@@ -0,0 +1,279 @@
+//! Versioned routing rules.
+//!
+//! Every loaded cluster rules document is tagged with a version, the hash of its YAML, which
+//! audit and metric records carry so a routing decision can be traced back to the rules that
+//! produced it. The last few versions stay in memory for EXPLAIN ROUTE to compare.
+
+use std::collections::{HashSet, VecDeque};
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use openssl::sha::sha256;
+
+use crate::discovery::database::{Cluster, DisType};
+
+/// How many rule versions are kept when no history size is configured.
+const DEFAULT_RULES_HISTORY: usize = 8;
+
+/// Reported while no rules have been loaded yet.
+pub const UNVERSIONED: &str = "unversioned";
+
+/// Where the rows of a table are, under one rules version.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableRoute {
+    Meta,
+    Replicated,
+    /// The data segments that may hold the rows, every one of them unless the shard keys are bound.
+    Distributed(Vec<u32>),
+}
+
+#[derive(Debug)]
+pub struct RulesVersion {
+    version: String,
+    loaded_at: u64,
+    cluster: Arc<Cluster>,
+}
+
+impl RulesVersion {
+    pub fn new(version: String, cluster: Cluster) -> Self {
+        RulesVersion {
+            version,
+            loaded_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            cluster: Arc::new(cluster),
+        }
+    }
+
+    pub fn get_version(&self) -> String {
+        self.version.clone()
+    }
+
+    /// Unix seconds when this version became active.
+    pub fn get_loaded_at(&self) -> u64 {
+        self.loaded_at
+    }
+
+    pub fn get_cluster(&self) -> Arc<Cluster> {
+        self.cluster.clone()
+    }
+
+    /// How `table` is routed under this version.
+    pub fn route_of(&self, table: &str) -> String {
+        let dis_rules = &self.cluster.dis_rules;
+        if let Some(dis_table) = dis_rules.distributed_tables.get(table) {
+            format!("distributed by ({}) {:?} {}",
+                    dis_table.dis_keys.join(", "),
+                    dis_table.dis_algorithm.dis_type,
+                    dis_table.dis_algorithm.dis_expression)
+        } else if dis_rules.replicated_tables.iter().any(|replicated| replicated == table) {
+            "replicated".to_string()
+        } else {
+            "meta segment".to_string()
+        }
+    }
+
+    /// The distributed and the replicated tables, by name.
+    pub fn tables(&self) -> Vec<String> {
+        let dis_rules = &self.cluster.dis_rules;
+        let mut tables: Vec<String> = dis_rules.distributed_tables.keys().chain(dis_rules.replicated_tables.iter()).cloned().collect();
+        tables.sort();
+        tables.dedup();
+        tables
+    }
+
+    /// The shard keys of every distributed table, lowercased.
+    pub fn shard_keys(&self) -> HashSet<String> {
+        self.cluster.dis_rules.distributed_tables.values()
+            .flat_map(|dis_table| dis_table.dis_keys.iter().map(|key| key.to_lowercase()))
+            .collect()
+    }
+
+    /// The shard keys of `table`, empty unless it is distributed.
+    pub fn dis_keys(&self, table: &str) -> Vec<String> {
+        self.cluster.dis_rules.distributed_tables.get(table).map_or(vec![], |dis_table| dis_table.dis_keys.clone())
+    }
+
+    /// Whether the distributed tables `a` and `b` are binding tables, one listing the other in
+    /// its `dis_relatives`: rows sharing a shard key value are on the same data segment.
+    pub fn are_relatives(&self, a: &str, b: &str) -> bool {
+        let distributed_tables = &self.cluster.dis_rules.distributed_tables;
+        match (distributed_tables.get(a), distributed_tables.get(b)) {
+            (Some(dis_a), Some(dis_b)) => dis_a.dis_relatives.iter().any(|relative| relative == b)
+                || dis_b.dis_relatives.iter().any(|relative| relative == a),
+            _ => false,
+        }
+    }
+
+    /// The name `table` has on data segment `segment`.
+    pub fn actual_table(&self, table: &str, segment: u32) -> String {
+        match self.cluster.dis_rules.distributed_tables.get(table) {
+            Some(dis_table) if !dis_table.actual_table.is_empty() => dis_table.actual_table.replace("{segment}", &segment.to_string()),
+            _ => table.to_string(),
+        }
+    }
+
+    /// The distributed table `actual` is the name of on one of the data segments, if any.
+    pub fn logical_table(&self, actual: &str) -> Option<String> {
+        let data_segments = &self.cluster.segments.data_segments;
+        self.cluster.dis_rules.distributed_tables.iter()
+            .find(|(_, dis_table)| {
+                let mut parts = dis_table.actual_table.splitn(2, "{segment}");
+                let (prefix, suffix) = match (parts.next(), parts.next()) {
+                    (Some(prefix), Some(suffix)) => (prefix, suffix),
+                    _ => return false,
+                };
+                actual.len() > prefix.len() + suffix.len()
+                    && actual.starts_with(prefix)
+                    && actual.ends_with(suffix)
+                    && actual[prefix.len()..actual.len() - suffix.len()].parse::<u32>().map_or(false, |segment| data_segments.contains_key(&segment))
+            })
+            .map(|(table, _)| table.clone())
+    }
+
+    /// Whether any distributed table has other names on the data segments than its own.
+    pub fn has_actual_tables(&self) -> bool {
+        self.cluster.dis_rules.distributed_tables.values().any(|dis_table| !dis_table.actual_table.is_empty())
+    }
+
+    /// The key column the mesh generates for `table`, if any.
+    pub fn generated_key(&self, table: &str) -> Option<String> {
+        self.cluster.dis_rules.distributed_tables.get(table)
+            .filter(|dis_table| !dis_table.generated_key.is_empty())
+            .map(|dis_table| dis_table.generated_key.clone())
+    }
+
+    /// Routes `table` given the values its statement binds to shard keys, as `(key, value)`.
+    /// Hash distributed tables with every shard key bound to exactly one value go to a single
+    /// data segment, anything else is scattered to all of them.
+    pub fn route(&self, table: &str, bindings: &[(String, String)]) -> TableRoute {
+        let dis_rules = &self.cluster.dis_rules;
+        let dis_table = match dis_rules.distributed_tables.get(table) {
+            Some(dis_table) => dis_table,
+            None if dis_rules.replicated_tables.iter().any(|replicated| replicated == table) => return TableRoute::Replicated,
+            None => return TableRoute::Meta,
+        };
+        let mut segments: Vec<u32> = self.cluster.segments.data_segments.keys().cloned().collect();
+        segments.sort_unstable();
+        if dis_table.dis_algorithm.dis_type != DisType::HASH || dis_table.dis_keys.is_empty() || segments.is_empty() {
+            return TableRoute::Distributed(segments);
+        }
+        let mut values = vec![];
+        for key in dis_table.dis_keys.iter() {
+            let mut bound = bindings.iter().filter(|(bound_key, _)| bound_key.eq_ignore_ascii_case(key));
+            match (bound.next(), bound.next()) {
+                (Some((_, value)), None) => values.push(value.as_str()),
+                _ => return TableRoute::Distributed(segments),
+            }
+        }
+        let digest = sha256(values.join("\u{0}").as_bytes());
+        let mut hash = [0u8; 8];
+        hash.copy_from_slice(&digest[..8]);
+        let index = u64::from_be_bytes(hash) % segments.len() as u64;
+        TableRoute::Distributed(vec![segments[index as usize]])
+    }
+}
+
+/// The hash tag identifying a rules document.
+pub fn rules_version_of(yaml: &str) -> String {
+    sha256(yaml.as_bytes()).iter().take(6).map(|b| format!("{:02x}", b)).collect()
+}
+
+struct RulesHistory {
+    versions: VecDeque<Arc<RulesVersion>>,
+    capacity: usize,
+}
+
+lazy_static! {
+    static ref RULES_HISTORY: RwLock<RulesHistory> = RwLock::new(RulesHistory {
+        versions: VecDeque::new(),
+        capacity: DEFAULT_RULES_HISTORY,
+    });
+}
+
+/// Parses, validates and activates a rules document, returns its version.
+pub fn load_rules(yaml: &str) -> Result<String, String> {
+    let cluster: Cluster = serde_yaml::from_str(yaml).map_err(|e| format!("invalid rules; error = {}", e))?;
+    cluster.validate().map_err(|e| format!("invalid rules; error = {}", e))?;
+    activate(cluster, rules_version_of(yaml))
+}
+
+/// Parses, validates and activates a rules document in JSON, as the control plane sends them.
+pub fn load_rules_json(json: &str) -> Result<String, String> {
+    let cluster: Cluster = serde_json::from_str(json).map_err(|e| format!("invalid rules; error = {}", e))?;
+    cluster.validate().map_err(|e| format!("invalid rules; error = {}", e))?;
+    activate(cluster, rules_version_of(json))
+}
+
+fn activate(cluster: Cluster, version: String) -> Result<String, String> {
+    let mut history = RULES_HISTORY.write().unwrap();
+    if history.versions.back().map_or(false, |current| current.version == version) {
+        return Ok(version);
+    }
+    history.versions.push_back(Arc::new(RulesVersion::new(version.clone(), cluster)));
+    while history.versions.len() > history.capacity {
+        history.versions.pop_front();
+    }
+    Ok(version)
+}
+
+pub fn load_rules_file(path: &str) -> Result<String, String> {
+    let mut file = File::open(path).map_err(|e| format!("error on opening {}; error = {}", path, e))?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).map_err(|e| format!("error on reading {}; error = {}", path, e))?;
+    load_rules(&contents).map_err(|e| format!("{} in {}", e, path))
+}
+
+pub fn set_rules_history(capacity: usize) {
+    let mut history = RULES_HISTORY.write().unwrap();
+    history.capacity = capacity.max(1);
+    while history.versions.len() > history.capacity {
+        history.versions.pop_front();
+    }
+}
+
+pub fn current_rules() -> Option<Arc<RulesVersion>> {
+    RULES_HISTORY.read().unwrap().versions.back().cloned()
+}
+
+/// The tag to put on audit and metric records.
+pub fn current_rules_version() -> String {
+    current_rules().map_or(UNVERSIONED.to_string(), |rules| rules.get_version())
+}
+
+/// Kept versions, newest first.
+pub fn rules_history() -> Vec<Arc<RulesVersion>> {
+    RULES_HISTORY.read().unwrap().versions.iter().rev().cloned().collect()
+}
+
+pub fn rules_version(version: &str) -> Option<Arc<RulesVersion>> {
+    RULES_HISTORY.read().unwrap().versions.iter().find(|rules| rules.version == version).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Read;
+
+    use crate::discovery::database::rules::{current_rules_version, load_rules, rules_history, rules_version, rules_version_of};
+
+    #[test]
+    fn test_rules_versions() {
+        let mut file = File::open("./etc/dbmesh.yaml").expect("Unable to open file");
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).expect("Unable to read file");
+
+        let first = load_rules(&contents).unwrap();
+        assert_eq!(first, rules_version_of(&contents));
+        assert_eq!(load_rules(&contents).unwrap(), first);
+
+        let changed = contents.replace("- t_dept", "- t_dept_v2");
+        let second = load_rules(&changed).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(current_rules_version(), second);
+        assert_eq!(rules_history()[1].get_version(), first);
+
+        assert_eq!(rules_version(&first).unwrap().route_of("t_dept"), "replicated");
+        assert_eq!(rules_version(&second).unwrap().route_of("t_dept"), "meta segment");
+    }
+}
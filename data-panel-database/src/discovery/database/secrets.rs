@@ -0,0 +1,218 @@
+//! Backend credentials kept out of the rules, see `SecretsConfig`.
+//!
+//! A segment `username` or `password` like `${<provider>:<reference>}` is the secret the
+//! provider fetches for the reference. Fetched secrets are cached for the ttl, then fetched
+//! again, so a rotated password reaches the connections opened after it. A backend refusing
+//! the credentials drops the cache at once. While a provider fails, the last secret it
+//! fetched keeps being used.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use openssl::ssl::{SslConnector, SslMethod};
+
+use data_panel_common::config::config::{MeshConfig, SecretsConfig};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    /// The secrets fetched, by reference, with when they were.
+    static ref SECRETS: DashMap<String, (String, Instant)> = DashMap::new();
+}
+
+pub trait SecretProvider {
+    /// The secret `reference` names to the provider.
+    fn fetch(&self, reference: &str) -> Result<String, String>;
+}
+
+/// `env:NAME`, the environment variable NAME.
+pub struct EnvSecretProvider;
+
+impl SecretProvider for EnvSecretProvider {
+    fn fetch(&self, reference: &str) -> Result<String, String> {
+        std::env::var(reference).map_err(|e| format!("{} {}", reference, e))
+    }
+}
+
+/// `file:/run/secrets/db`, the content of a mounted file, without its trailing newline.
+pub struct FileSecretProvider;
+
+impl SecretProvider for FileSecretProvider {
+    fn fetch(&self, reference: &str) -> Result<String, String> {
+        std::fs::read_to_string(reference)
+            .map(|secret| secret.trim_end_matches(&['\n', '\r'][..]).to_string())
+            .map_err(|e| format!("unable to read {}; error = {}", reference, e))
+    }
+}
+
+/// `vault:secret/data/db#password`, the field `password` of the secret at `secret/data/db`
+/// of a KV engine, version 1 or 2, on the Vault server.
+pub struct VaultSecretProvider {
+    addr: String,
+    token: String,
+}
+
+impl VaultSecretProvider {
+    pub fn new(config: &SecretsConfig) -> Self {
+        let token = if !config.get_vault_token().is_empty() {
+            config.get_vault_token()
+        } else if !config.get_vault_token_file().is_empty() {
+            FileSecretProvider.fetch(&config.get_vault_token_file()).unwrap_or_else(|e| {
+                println!("error on reading the vault token; error = {:?}", e);
+                String::new()
+            })
+        } else {
+            std::env::var("VAULT_TOKEN").unwrap_or_default()
+        };
+        VaultSecretProvider {
+            addr: config.get_vault_addr(),
+            token,
+        }
+    }
+
+    /// The body of the answer to a GET of `path`, an HTTP/1.0 request so it is not chunked.
+    fn get(&self, path: &str) -> Result<String, String> {
+        let (tls, address) = match (self.addr.strip_prefix("https://"), self.addr.strip_prefix("http://")) {
+            (Some(address), _) => (true, address),
+            (None, Some(address)) => (false, address),
+            _ => return Err(format!("{} is not an http:// or https:// url", self.addr)),
+        };
+        let host = address.split(':').next().unwrap_or(address);
+        let address = if address.contains(':') { address.to_string() } else { format!("{}:{}", address, if tls { 443 } else { 80 }) };
+        let socket_addr = address.to_socket_addrs().map_err(|e| e.to_string())?.next().ok_or_else(|| format!("unable to resolve {}", address))?;
+        let stream = TcpStream::connect_timeout(&socket_addr, TIMEOUT).map_err(|e| e.to_string())?;
+        stream.set_read_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+        stream.set_write_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+
+        let request = format!("GET /v1/{} HTTP/1.0\r\nHost: {}\r\nX-Vault-Token: {}\r\nAccept: application/json\r\n\r\n", path, host, self.token);
+        let response = if tls {
+            let connector = SslConnector::builder(SslMethod::tls()).map_err(|e| e.to_string())?.build();
+            let mut stream = connector.connect(host, stream).map_err(|e| e.to_string())?;
+            exchange(&mut stream, &request)
+        } else {
+            exchange(&mut &stream, &request)
+        }.map_err(|e| e.to_string())?;
+
+        let end = response.find("\r\n\r\n").ok_or_else(|| "truncated answer from vault".to_string())?;
+        let status = response.split(' ').nth(1).unwrap_or_default();
+        let body = &response[end + 4..];
+        if status != "200" {
+            return Err(format!("vault answered {} for {}: {}", status, path, body.trim()));
+        }
+        Ok(body.to_string())
+    }
+}
+
+fn exchange<S: Read + Write>(stream: &mut S, request: &str) -> io::Result<String> {
+    stream.write_all(request.as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+/// The `field` of the secret `body` Vault answered with, KV version 2 nesting it in `data`.
+fn vault_field(body: &str, field: &str) -> Option<String> {
+    let body: serde_json::Value = serde_json::from_str(body).ok()?;
+    let data = &body["data"];
+    let value = if data["data"].is_object() { &data["data"][field] } else { &data[field] };
+    match value {
+        serde_json::Value::String(value) => Some(value.clone()),
+        serde_json::Value::Null => None,
+        value => Some(value.to_string()),
+    }
+}
+
+impl SecretProvider for VaultSecretProvider {
+    fn fetch(&self, reference: &str) -> Result<String, String> {
+        let hash = reference.rfind('#').ok_or_else(|| format!("vault secret {} has no #field", reference))?;
+        let (path, field) = (reference[..hash].trim_start_matches('/'), &reference[hash + 1..]);
+        let body = self.get(path)?;
+        vault_field(&body, field).ok_or_else(|| format!("vault secret {} has no field {}", path, field))
+    }
+}
+
+/// The provider and reference of `value` when it is a `${<provider>:<reference>}` secret.
+fn secret_reference(value: &str) -> Option<(&str, &str)> {
+    let reference = value.strip_prefix("${")?.strip_suffix('}')?;
+    let colon = reference.find(':')?;
+    Some((&reference[..colon], &reference[colon + 1..]))
+}
+
+fn provider(name: &str) -> Result<Box<dyn SecretProvider>, String> {
+    match name {
+        "env" => Ok(Box::new(EnvSecretProvider)),
+        "file" => Ok(Box::new(FileSecretProvider)),
+        "vault" => Ok(Box::new(VaultSecretProvider::new(&MeshConfig::get_secrets_config()))),
+        name => Err(format!("unknown secret provider {}, expected env, file or vault", name)),
+    }
+}
+
+/// Whether `value` is a secret reference rather than the credential itself.
+pub fn is_secret(value: &str) -> bool {
+    secret_reference(value).is_some()
+}
+
+/// `value`, or the secret it references, fetched again once the cached one is older than the
+/// ttl.
+pub fn resolve(value: &str) -> Result<String, String> {
+    let (name, reference) = match secret_reference(value) {
+        Some(secret) => secret,
+        None => return Ok(value.to_string()),
+    };
+    let ttl = Duration::from_secs(MeshConfig::get_secrets_config().get_ttl());
+    let cached = SECRETS.get(value).map(|entry| entry.value().clone());
+    if let Some((secret, fetched)) = &cached {
+        if fetched.elapsed() < ttl {
+            return Ok(secret.clone());
+        }
+    }
+    match provider(name).and_then(|provider| provider.fetch(reference)) {
+        Ok(secret) => {
+            if matches!(&cached, Some((previous, _)) if *previous != secret) {
+                println!("secret {} rotated", value);
+            }
+            SECRETS.insert(value.to_string(), (secret.clone(), Instant::now()));
+            Ok(secret)
+        }
+        Err(e) => match cached {
+            Some((secret, _)) => {
+                println!("error on fetching secret {}, the cached one is used; error = {:?}", value, e);
+                Ok(secret)
+            }
+            None => Err(e),
+        },
+    }
+}
+
+/// Drops the cached secrets, e.g. once a backend refused credentials that may have rotated.
+pub fn invalidate() {
+    SECRETS.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_secret, resolve, secret_reference, vault_field};
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(secret_reference("${vault:secret/data/db#password}"), Some(("vault", "secret/data/db#password")));
+        assert!(!is_secret("root"));
+        assert!(!is_secret("${root"));
+        assert_eq!(resolve("root"), Ok("root".to_string()));
+
+        std::env::set_var("MARTLET_TEST_SECRET", "s3cr@t");
+        assert_eq!(resolve("${env:MARTLET_TEST_SECRET}"), Ok("s3cr@t".to_string()));
+        let path = std::env::temp_dir().join("martlet_test_secret");
+        std::fs::write(&path, "from-file\n").unwrap();
+        assert_eq!(resolve(&format!("${{file:{}}}", path.to_str().unwrap())), Ok("from-file".to_string()));
+        std::fs::remove_file(&path).unwrap();
+        assert!(resolve("${env:MARTLET_TEST_MISSING}").is_err());
+        assert!(resolve("${ssm:db}").is_err());
+
+        assert_eq!(vault_field(r#"{"data":{"data":{"password":"kv2"},"metadata":{}}}"#, "password"), Some("kv2".to_string()));
+        assert_eq!(vault_field(r#"{"data":{"password":"kv1"}}"#, "password"), Some("kv1".to_string()));
+        assert_eq!(vault_field(r#"{"data":{"password":"kv1"}}"#, "username"), None);
+    }
+}
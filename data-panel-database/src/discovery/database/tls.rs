@@ -0,0 +1,411 @@
+//! Backend certificate pinning.
+//!
+//! Segments may pin the certificate (`sha256:<hex>` of the DER) or its public key
+//! (`spki-sha256:<hex>` of the SubjectPublicKeyInfo) they are expected to present. The
+//! monitor upgrades a probe connection to TLS like a client would, compares what the backend
+//! presents against the pins and raises an alert event when it changes unexpectedly. Until a
+//! segment presents a pinned certificate again, the mesh refuses to connect to it, see
+//! `TlsPinMonitor::check_trusted`.
+//!
+//! Segments with TLS options are also connected to over TLS, see `SegmentTls::ssl_opts`.
+
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use mysql::SslOpts;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::sha::sha256;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::X509;
+
+use crate::discovery::database::{Cluster, Segment, SegmentTls};
+use crate::discovery::database::rules::{current_rules, current_rules_version};
+use crate::protocol::database::mysql::constant::MySQLCapabilityFlag;
+
+/// Only the latest events are kept for the admin API.
+const MAX_TLS_EVENTS: usize = 256;
+
+/// How often the monitor started with the mesh probes the pinned segments.
+pub const TLS_PIN_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TlsPinEventKind {
+    /// The certificate changed and still matches a pin, e.g. a planned rotation.
+    Rotated,
+    /// The presented certificate matches none of the segment's pins.
+    PinMismatch,
+    /// The backend could not be probed, or refused to upgrade to TLS.
+    ProbeFailed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsPinEvent {
+    segment: String,
+    kind: TlsPinEventKind,
+    fingerprint: String,
+    timestamp: u64,
+    rules_version: String,
+}
+
+impl TlsPinEvent {
+    pub fn get_segment(&self) -> String {
+        self.segment.clone()
+    }
+
+    pub fn get_kind(&self) -> TlsPinEventKind {
+        self.kind.clone()
+    }
+
+    pub fn get_fingerprint(&self) -> String {
+        self.fingerprint.clone()
+    }
+
+    pub fn get_rules_version(&self) -> String {
+        self.rules_version.clone()
+    }
+
+    pub fn is_alert(&self) -> bool {
+        self.kind != TlsPinEventKind::Rotated
+    }
+}
+
+/// Fingerprints of a presented certificate, in the format used by the pins.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertificateFingerprint {
+    certificate: String,
+    spki: String,
+}
+
+impl CertificateFingerprint {
+    pub fn from_der(der: &[u8]) -> Result<Self, String> {
+        let certificate = X509::from_der(der).map_err(|e| e.to_string())?;
+        let spki = certificate.public_key()
+            .and_then(|key| key.public_key_to_der())
+            .map_err(|e| e.to_string())?;
+        Ok(CertificateFingerprint {
+            certificate: format!("sha256:{}", to_hex(&sha256(der))),
+            spki: format!("spki-sha256:{}", to_hex(&sha256(&spki))),
+        })
+    }
+
+    pub fn matches(&self, pins: &[String]) -> bool {
+        pins.iter().any(|pin| pin.eq_ignore_ascii_case(&self.certificate) || pin.eq_ignore_ascii_case(&self.spki))
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// `host:port` of a segment url such as `jdbc:mysql://user@localhost:3306/martlet`.
+fn segment_addr(url: &str) -> Option<String> {
+    let rest = &url[url.find("://")? + 3..];
+    let authority = rest.split('/').next()?;
+    let host_port = authority.rsplit('@').next()?;
+    if host_port.contains(':') {
+        Some(host_port.to_string())
+    } else {
+        Some(format!("{}:3306", host_port))
+    }
+}
+
+fn read_packet(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header)?;
+    let len = header[0] as usize | (header[1] as usize) << 8 | (header[2] as usize) << 16;
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// Reads the lower capability flags out of the initial handshake packet.
+fn handshake_capabilities(handshake: &[u8]) -> Option<u32> {
+    let version_end = handshake.iter().skip(1).position(|b| *b == 0)? + 1;
+    // thread id (4), auth-plugin-data-part-1 (8), filler (1)
+    let offset = version_end + 1 + 4 + 8 + 1;
+    let lower = handshake.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([lower[0], lower[1]]) as u32)
+}
+
+/// Connects like a client, asks the backend to switch to TLS and returns its certificate.
+pub fn fetch_peer_certificate(addr: &str, timeout: Duration) -> Result<Vec<u8>, String> {
+    let mut stream = TcpStream::connect(addr).map_err(|e| e.to_string())?;
+    stream.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+
+    let handshake = read_packet(&mut stream).map_err(|e| e.to_string())?;
+    let capabilities = handshake_capabilities(&handshake).ok_or("malformed handshake packet")?;
+    if capabilities & MySQLCapabilityFlag::CLIENT_SSL.bits() == 0 {
+        return Err("backend does not support TLS".to_string());
+    }
+
+    // SSLRequest: capabilities, max packet size, character set, 23 reserved bytes
+    let flags = MySQLCapabilityFlag::CLIENT_SSL | MySQLCapabilityFlag::CLIENT_PROTOCOL_41 | MySQLCapabilityFlag::CLIENT_SECURE_CONNECTION;
+    let mut ssl_request = vec![32u8, 0, 0, 1];
+    ssl_request.extend_from_slice(&flags.bits().to_le_bytes());
+    ssl_request.extend_from_slice(&(16 * 1024 * 1024u32).to_le_bytes());
+    ssl_request.push(33);
+    ssl_request.extend_from_slice(&[0u8; 23]);
+    stream.write_all(&ssl_request).map_err(|e| e.to_string())?;
+
+    // The chain is judged by the pins, not by a CA.
+    let mut connector = SslConnector::builder(SslMethod::tls()).map_err(|e| e.to_string())?;
+    connector.set_verify(SslVerifyMode::NONE);
+    let host = addr.rsplitn(2, ':').last().unwrap_or(addr);
+    let tls = connector.build().connect(host, stream).map_err(|e| e.to_string())?;
+    let certificate = tls.ssl().peer_certificate().ok_or("backend presented no certificate")?;
+    certificate.to_der().map_err(|e| e.to_string())
+}
+
+/// Remembers the last fingerprint seen per segment and the recent events.
+pub struct TlsPinMonitor {
+    last_seen: Mutex<HashMap<String, CertificateFingerprint>>,
+    events: Mutex<Vec<TlsPinEvent>>,
+}
+
+impl TlsPinMonitor {
+    pub fn new() -> Self {
+        TlsPinMonitor {
+            last_seen: Mutex::new(HashMap::new()),
+            events: Mutex::new(vec![]),
+        }
+    }
+
+    fn raise(&self, segment: &str, kind: TlsPinEventKind, fingerprint: String) {
+        let event = TlsPinEvent {
+            segment: segment.to_string(),
+            kind,
+            fingerprint,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            rules_version: current_rules_version(),
+        };
+        if event.is_alert() {
+            println!("alert on backend certificate of segment {}; event = {:?}", segment, event);
+        }
+        let mut events = self.events.lock().unwrap();
+        if events.len() >= MAX_TLS_EVENTS {
+            events.remove(0);
+        }
+        events.push(event);
+    }
+
+    /// Compares what `segment` presents against its pins and the last certificate seen.
+    pub fn observe(&self, name: &str, segment: &Segment, der: Result<Vec<u8>, String>) {
+        let fingerprint = match der.and_then(|der| CertificateFingerprint::from_der(&der)) {
+            Ok(fingerprint) => fingerprint,
+            Err(e) => return self.raise(name, TlsPinEventKind::ProbeFailed(e), "".to_string()),
+        };
+        let changed = self.last_seen.lock().unwrap()
+            .insert(name.to_string(), fingerprint.clone())
+            .map_or(false, |last| last != fingerprint);
+        if !segment.tls_pins.is_empty() && !fingerprint.matches(&segment.tls_pins) {
+            self.raise(name, TlsPinEventKind::PinMismatch, fingerprint.certificate);
+        } else if changed {
+            self.raise(name, TlsPinEventKind::Rotated, fingerprint.certificate);
+        }
+    }
+
+    /// Probes every pinned segment of the cluster once.
+    pub fn check(&self, cluster: &Cluster, timeout: Duration) {
+        for (name, segment) in cluster.all_segments() {
+            if segment.tls_pins.is_empty() {
+                continue;
+            }
+            let der = segment_addr(&segment.url)
+                .ok_or(format!("unable to parse segment url {}", segment.url))
+                .and_then(|addr| fetch_peer_certificate(&addr, timeout));
+            self.observe(&name, segment, der);
+        }
+    }
+
+    /// False once a segment presented a certificate outside of its pins, until it matches again.
+    pub fn is_trusted(&self, name: &str) -> bool {
+        self.events.lock().unwrap().iter().rev()
+            .find(|event| event.segment == name)
+            .map_or(true, |event| event.kind != TlsPinEventKind::PinMismatch)
+    }
+
+    /// Refuses connections to segment `name` while it is not trusted, see `is_trusted`.
+    pub fn check_trusted(&self, name: &str) -> Result<(), String> {
+        if self.is_trusted(name) {
+            Ok(())
+        } else {
+            Err(format!("backend certificate of segment {} matches none of its pins", name))
+        }
+    }
+
+    pub fn events(&self) -> Vec<TlsPinEvent> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+lazy_static! {
+    static ref TLS_PIN_MONITOR: TlsPinMonitor = TlsPinMonitor::new();
+}
+
+pub fn tls_pin_monitor() -> &'static TlsPinMonitor {
+    &TLS_PIN_MONITOR
+}
+
+/// Re-checks the pinned segments of the current rules every `interval` on the blocking pool.
+pub fn spawn_tls_pin_monitor(interval: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let cluster: Arc<Cluster> = match current_rules() {
+                Some(rules) => rules.get_cluster(),
+                None => continue,
+            };
+            if let Err(e) = tokio::task::spawn_blocking(move || tls_pin_monitor().check(&cluster, interval)).await {
+                println!("error on checking backend certificates; error = {:?}", e);
+            }
+        }
+    })
+}
+
+lazy_static! {
+    /// PKCS#12 bundles built from the segments' PEM client identities, by cert and key path.
+    static ref CLIENT_IDENTITIES: Mutex<HashMap<(String, String), (PathBuf, String)>> = Mutex::new(HashMap::new());
+}
+
+/// Bundles a PEM certificate and key into a PKCS#12 file only the mesh can read, the form
+/// the backend driver takes a client identity in.
+fn client_identity(cert: &str, key: &str) -> Result<(PathBuf, String), String> {
+    let mut identities = CLIENT_IDENTITIES.lock().unwrap();
+    if let Some(identity) = identities.get(&(cert.to_string(), key.to_string())) {
+        return Ok(identity.clone());
+    }
+    let cert_pem = std::fs::read(cert).map_err(|e| format!("unable to read {}; error = {:?}", cert, e))?;
+    let key_pem = std::fs::read(key).map_err(|e| format!("unable to read {}; error = {:?}", key, e))?;
+    let x509 = X509::from_pem(&cert_pem).map_err(|e| format!("invalid certificate {}; error = {:?}", cert, e))?;
+    let pkey = PKey::private_key_from_pem(&key_pem).map_err(|e| format!("invalid private key {}; error = {:?}", key, e))?;
+    let password = to_hex(&rand::random::<[u8; 16]>());
+    let der = Pkcs12::builder().build(&password, "martlet", &pkey, &x509)
+        .and_then(|pkcs12| pkcs12.to_der())
+        .map_err(|e| format!("unable to bundle the client identity; error = {:?}", e))?;
+
+    let path = std::env::temp_dir().join(format!("martlet-{}-{}.p12", std::process::id(), identities.len()));
+    OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&path)
+        .and_then(|mut file| file.write_all(&der))
+        .map_err(|e| format!("unable to write {}; error = {:?}", path.display(), e))?;
+    identities.insert((cert.to_string(), key.to_string()), (path.clone(), password.clone()));
+    Ok((path, password))
+}
+
+impl SegmentTls {
+    /// The driver options to connect to the segment with.
+    pub fn ssl_opts(&self) -> Result<SslOpts, String> {
+        let mut ssl_opts = SslOpts::default()
+            .with_danger_skip_domain_validation(!self.is_verify_hostname());
+        if !self.get_ca_cert().is_empty() {
+            ssl_opts = ssl_opts.with_root_cert_path(Some(PathBuf::from(self.get_ca_cert())));
+        }
+        if !self.get_client_cert().is_empty() {
+            let (path, password) = client_identity(&self.get_client_cert(), &self.get_client_key())?;
+            ssl_opts = ssl_opts.with_pkcs12_path(Some(path)).with_password(Some(password));
+        }
+        Ok(ssl_opts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkcs12::Pkcs12;
+    use openssl::pkey::{PKey, Private};
+    use openssl::sha::sha256;
+    use openssl::x509::X509;
+
+    use crate::discovery::database::{Segment, SegmentTls};
+
+    use super::{CertificateFingerprint, TlsPinEventKind, TlsPinMonitor, to_hex};
+
+    fn key() -> PKey<Private> {
+        PKey::from_ec_key(EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap()).unwrap()
+    }
+
+    /// A self-signed certificate of `key`, told apart by `serial`.
+    fn certificate(key: &PKey<Private>, serial: u32) -> Vec<u8> {
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(&openssl::bn::BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap()).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        builder.sign(key, MessageDigest::sha256()).unwrap();
+        builder.build().to_der().unwrap()
+    }
+
+    #[test]
+    fn test_tls_pins() {
+        let url = "jdbc:mysql://localhost:3306/martlet";
+        let key = key();
+        let pinned = certificate(&key, 1);
+        let pin = format!("sha256:{}", to_hex(&sha256(&pinned)));
+        assert!(CertificateFingerprint::from_der(&pinned).unwrap().matches(&[pin.clone()]));
+
+        // The pinned certificate, then another one.
+        let monitor = TlsPinMonitor::new();
+        let segment = Segment::new(100, url, "root", "root").tls_pin(&pin);
+        monitor.observe("data-100/primary", &segment, Ok(pinned.clone()));
+        assert!(monitor.events().is_empty());
+        assert!(monitor.check_trusted("data-100/primary").is_ok());
+        monitor.observe("data-100/primary", &segment, Ok(certificate(&self::key(), 2)));
+        assert_eq!(monitor.events().last().unwrap().get_kind(), TlsPinEventKind::PinMismatch);
+        assert!(monitor.check_trusted("data-100/primary").is_err());
+        assert!(monitor.check_trusted("data-200/primary").is_ok());
+        monitor.observe("data-100/primary", &segment, Ok(pinned));
+        assert!(monitor.check_trusted("data-100/primary").is_ok());
+
+        // A certificate rotated on the pinned public key.
+        let spki = CertificateFingerprint::from_der(&certificate(&key, 3)).unwrap().spki;
+        let segment = Segment::new(200, url, "root", "root").tls_pin(&spki);
+        monitor.observe("data-200/primary", &segment, Ok(certificate(&key, 3)));
+        monitor.observe("data-200/primary", &segment, Ok(certificate(&key, 4)));
+        let event = monitor.events().pop().unwrap();
+        assert_eq!((event.get_segment().as_str(), event.get_kind()), ("data-200/primary", TlsPinEventKind::Rotated));
+        assert!(!event.is_alert());
+        assert!(monitor.check_trusted("data-200/primary").is_ok());
+    }
+
+    #[test]
+    fn test_ssl_opts() {
+        let key = key();
+        let cert_path = std::env::temp_dir().join("martlet_test_segment.crt");
+        let key_path = std::env::temp_dir().join("martlet_test_segment.key");
+        std::fs::write(&cert_path, X509::from_der(&certificate(&key, 1)).unwrap().to_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let (cert, key) = (cert_path.to_str().unwrap(), key_path.to_str().unwrap());
+
+        let ssl_opts = SegmentTls::new(cert).ssl_opts().unwrap();
+        assert_eq!(ssl_opts.root_cert_path(), Some(cert_path.as_path()));
+        assert!(!ssl_opts.skip_domain_validation());
+        assert!(ssl_opts.pkcs12_path().is_none());
+
+        // The client identity is bundled once, into a file only the mesh can read.
+        let tls = SegmentTls::new("").client_identity(cert, key).verify_hostname(false);
+        let ssl_opts = tls.ssl_opts().unwrap();
+        assert!(ssl_opts.root_cert_path().is_none());
+        assert!(ssl_opts.skip_domain_validation());
+        let pkcs12_path = ssl_opts.pkcs12_path().unwrap();
+        assert_eq!(std::fs::metadata(pkcs12_path).unwrap().permissions().mode() & 0o777, 0o600);
+        let pkcs12 = Pkcs12::from_der(&std::fs::read(pkcs12_path).unwrap()).unwrap();
+        assert!(pkcs12.parse(ssl_opts.password().unwrap()).is_ok());
+        assert_eq!(tls.ssl_opts().unwrap().pkcs12_path(), Some(pkcs12_path));
+
+        assert!(SegmentTls::new("").client_identity(key, key).ssl_opts().is_err());
+    }
+}
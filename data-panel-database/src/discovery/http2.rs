@@ -0,0 +1,77 @@
+//! Upstreams of the HTTP/2 proxy, selected per route from the `http2_proxy` config.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use hyper::Uri;
+
+use data_panel_common::config::config::Http2ProxyConfig;
+
+struct RouteEntry {
+    prefix: String,
+    upstreams: Vec<Uri>,
+    next: AtomicUsize,
+}
+
+/// The routes of the proxy, longest prefix first.
+pub struct Http2Routes {
+    routes: Vec<RouteEntry>,
+}
+
+impl Http2Routes {
+    /// Fails on an upstream that is not an absolute `http://` uri, or a route without one.
+    pub fn from_config(config: &Http2ProxyConfig) -> Result<Self, String> {
+        let mut routes = Vec::new();
+        for route in config.get_routes() {
+            let mut upstreams = Vec::new();
+            for upstream in route.get_upstreams() {
+                let uri = upstream.parse::<Uri>().map_err(|e| format!("invalid upstream {}: {}", upstream, e))?;
+                if uri.scheme_str() != Some("http") || uri.authority().is_none() {
+                    return Err(format!("upstream {} is not an http://host:port uri", upstream));
+                }
+                upstreams.push(uri);
+            }
+            if upstreams.is_empty() {
+                return Err(format!("route {} has no upstream", route.get_prefix()));
+            }
+            routes.push(RouteEntry {
+                prefix: route.get_prefix(),
+                upstreams,
+                next: AtomicUsize::new(0),
+            });
+        }
+        routes.sort_by(|a, b| b.prefix.len().cmp(&a.prefix.len()));
+        Ok(Http2Routes {
+            routes,
+        })
+    }
+
+    /// The upstream the request for `path` goes to, the route's upstreams taking turns.
+    pub fn upstream(&self, path: &str) -> Option<Uri> {
+        let route = self.routes.iter().find(|route| path.starts_with(route.prefix.as_str()))?;
+        let next = route.next.fetch_add(1, Ordering::Relaxed);
+        Some(route.upstreams[next % route.upstreams.len()].clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data_panel_common::config::config::{Http2ProxyConfig, Http2Route};
+
+    use super::Http2Routes;
+
+    #[test]
+    fn test_http2_routes() {
+        let config = Http2ProxyConfig::new(0, vec![
+            Http2Route::new("/", vec!["http://default:8080"]),
+            Http2Route::new("/helloworld.Greeter/", vec!["http://greeter-a:50051", "http://greeter-b:50051"]),
+        ]);
+        let routes = Http2Routes::from_config(&config).unwrap();
+        let authority = |path: &str| routes.upstream(path).unwrap().authority().unwrap().to_string();
+        assert_eq!(authority("/helloworld.Greeter/SayHello"), "greeter-a:50051");
+        assert_eq!(authority("/helloworld.Greeter/SayHello"), "greeter-b:50051");
+        assert_eq!(authority("/index.html"), "default:8080");
+
+        let config = Http2ProxyConfig::new(0, vec![Http2Route::new("/", vec!["greeter:50051"])]);
+        assert!(Http2Routes::from_config(&config).is_err());
+    }
+}
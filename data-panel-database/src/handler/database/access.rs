@@ -0,0 +1,120 @@
+//! Connection access control, see `ConnectionAclConfig`.
+//!
+//! Checked when a client authenticates, on the address it connects from, the user it logs
+//! in as and the database it names in the handshake. A refused client gets the error MySQL
+//! itself sends for what the denying rule matched.
+
+use std::net::IpAddr;
+
+use data_panel_common::config::config::{ConnectionAclConfig, ConnectionAclRule};
+
+use crate::protocol::database::mysql::constant::MySQLServerErrorCode;
+
+/// An IPv4 client seen through an IPv6 socket as `::ffff:a.b.c.d` is matched as IPv4.
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => {
+            let octets = v6.octets();
+            if octets[..10].iter().all(|octet| *octet == 0) && octets[10] == 0xff && octets[11] == 0xff {
+                IpAddr::from([octets[12], octets[13], octets[14], octets[15]])
+            } else {
+                ip
+            }
+        }
+        ip => ip,
+    }
+}
+
+/// Whether `ip` is `source`, an address or a CIDR range.
+fn source_contains(source: &str, ip: IpAddr) -> bool {
+    let mut parts = source.splitn(2, '/');
+    let network = match parts.next().and_then(|network| network.parse::<IpAddr>().ok()) {
+        Some(network) => canonical_ip(network),
+        None => return false,
+    };
+    let prefix = parts.next().and_then(|prefix| prefix.parse::<u32>().ok());
+    match (network, canonical_ip(ip)) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let prefix = prefix.unwrap_or(32).min(32);
+            let mask = if prefix == 0 { 0 } else { u32::MAX << (32 - prefix) };
+            u32::from(network) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let prefix = prefix.unwrap_or(128).min(128);
+            let mask = if prefix == 0 { 0 } else { u128::MAX << (128 - prefix) };
+            u128::from(network) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+fn rule_matches(rule: &ConnectionAclRule, ip: IpAddr, user: &str, database: &str) -> bool {
+    (rule.get_source().is_empty() || source_contains(&rule.get_source(), ip))
+        && (rule.get_user().is_empty() || rule.get_user() == user)
+        && (rule.get_database().is_empty() || rule.get_database().eq_ignore_ascii_case(database))
+}
+
+/// The error refusing a client, by what the denying rule matched.
+fn denial(rule: Option<&ConnectionAclRule>, ip: IpAddr, user: &str, database: &str) -> (MySQLServerErrorCode, String) {
+    match rule {
+        Some(rule) if !rule.get_database().is_empty() => {
+            (MySQLServerErrorCode::ErDbaccessDeniedError, format!("Access denied for user '{}'@'{}' to database '{}'", user, ip, database))
+        }
+        Some(rule) if !rule.get_source().is_empty() && rule.get_user().is_empty() => {
+            (MySQLServerErrorCode::ErHostNotPrivileged, format!("Host '{}' is not allowed to connect to this MySQL server", ip))
+        }
+        _ => (MySQLServerErrorCode::ErAccessDeniedError, format!("Access denied for user '{}'@'{}'", user, ip)),
+    }
+}
+
+/// Whether the client may connect, the error to refuse it with otherwise.
+pub fn check(config: &ConnectionAclConfig, ip: IpAddr, user: &str, database: &str) -> Result<(), (MySQLServerErrorCode, String)> {
+    if !config.is_enabled() {
+        return Ok(());
+    }
+    let ip = canonical_ip(ip);
+    match config.get_rules().iter().find(|rule| rule_matches(rule, ip, user, database)) {
+        Some(rule) if rule.is_allow() => Ok(()),
+        Some(rule) => {
+            println!("connection of {}@{} to {} denied by acl rule {}", user, ip, database, rule.get_name());
+            Err(denial(Some(rule), ip, user, database))
+        }
+        None if config.is_default_allow() => Ok(()),
+        None => Err(denial(None, ip, user, database)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use data_panel_common::config::config::{ConnectionAclConfig, ConnectionAclRule};
+
+    use crate::protocol::database::mysql::constant::MySQLServerErrorCode;
+
+    use super::{check, source_contains};
+
+    #[test]
+    fn test_connection_acl() {
+        let pod: IpAddr = "127.0.0.1".parse().unwrap();
+        let dmz: IpAddr = "10.20.3.4".parse().unwrap();
+        assert!(source_contains("10.20.0.0/16", dmz));
+        assert!(source_contains("10.20.0.0/16", "::ffff:10.20.9.9".parse().unwrap()));
+        assert!(!source_contains("10.21.0.0/16", dmz));
+        assert!(source_contains("0.0.0.0/0", dmz));
+        assert!(source_contains("fd00::/8", "fd12::1".parse().unwrap()));
+
+        let config = ConnectionAclConfig::new(vec![
+            ConnectionAclRule::allow("admin-from-pod").user("admin").source("127.0.0.1"),
+            ConnectionAclRule::deny("no-remote-admin").user("admin"),
+            ConnectionAclRule::deny("no-billing-from-dmz").source("10.20.0.0/16").database("billing"),
+            ConnectionAclRule::deny("no-lab").source("192.168.0.0/16"),
+        ]);
+        assert!(check(&config, pod, "admin", "").is_ok());
+        assert_eq!(check(&config, dmz, "admin", "").unwrap_err().0, MySQLServerErrorCode::ErAccessDeniedError);
+        assert_eq!(check(&config, dmz, "app", "BILLING").unwrap_err().0, MySQLServerErrorCode::ErDbaccessDeniedError);
+        assert_eq!(check(&config, "192.168.1.1".parse().unwrap(), "app", "").unwrap_err().0, MySQLServerErrorCode::ErHostNotPrivileged);
+        assert!(check(&config, dmz, "app", "orders").is_ok());
+        assert!(check(&config.default_action("deny"), dmz, "app", "orders").is_err());
+    }
+}
@@ -0,0 +1,275 @@
+//! Approval workflow for dangerous statements.
+//!
+//! Statements matching a dangerous rule (mass DELETE/UPDATE without WHERE, DDL on
+//! configured large tables) are parked until an external approver decides, either by
+//! answering the webhook notification directly or by calling back into `decide`.
+
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Client, Method, Request};
+use sqlparser::ast::{ObjectName, Statement};
+
+use data_panel_common::config::config::{ApprovalConfig, MeshConfig};
+
+use crate::discovery::database::rules::current_rules_version;
+use crate::session::mysql::SessionContext;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ApprovalDecision {
+    Pending,
+    Approved,
+    Denied(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ApprovalRequest {
+    id: u64,
+    session_id: u64,
+    user_name: String,
+    sql: String,
+    reason: String,
+    decision: ApprovalDecision,
+}
+
+impl ApprovalRequest {
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn get_session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    pub fn get_user_name(&self) -> String {
+        self.user_name.clone()
+    }
+
+    pub fn get_sql(&self) -> String {
+        self.sql.clone()
+    }
+
+    pub fn get_reason(&self) -> String {
+        self.reason.clone()
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::json!({
+            "id": self.id,
+            "session_id": self.session_id,
+            "user": self.user_name,
+            "sql": self.sql,
+            "reason": self.reason,
+            "rules_version": current_rules_version(),
+        }).to_string()
+    }
+}
+
+pub struct ApprovalRegistry {
+    requests: Mutex<HashMap<u64, ApprovalRequest>>,
+    decided: Condvar,
+}
+
+impl ApprovalRegistry {
+    fn new() -> Self {
+        ApprovalRegistry {
+            requests: Mutex::new(HashMap::new()),
+            decided: Condvar::new(),
+        }
+    }
+
+    fn park(&self, request: ApprovalRequest) {
+        self.requests.lock().unwrap().insert(request.id, request);
+    }
+
+    /// Records the decision for a parked statement, returns false if it is unknown or already decided.
+    pub fn decide(&self, id: u64, decision: ApprovalDecision) -> bool {
+        let mut requests = self.requests.lock().unwrap();
+        match requests.get_mut(&id) {
+            Some(request) if request.decision == ApprovalDecision::Pending => {
+                request.decision = decision;
+                self.decided.notify_all();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn pending(&self) -> Vec<ApprovalRequest> {
+        self.requests.lock().unwrap().values()
+            .filter(|request| request.decision == ApprovalDecision::Pending)
+            .cloned()
+            .collect()
+    }
+
+    /// Blocks until the request is decided or the timeout elapses, the request is discarded either way.
+    fn wait(&self, id: u64, timeout: Duration) -> ApprovalDecision {
+        let deadline = Instant::now() + timeout;
+        let mut requests = self.requests.lock().unwrap();
+        loop {
+            let decision = match requests.get(&id) {
+                Some(request) => request.decision.clone(),
+                None => ApprovalDecision::Denied("approval request was discarded".to_string()),
+            };
+            let now = Instant::now();
+            if decision != ApprovalDecision::Pending || now >= deadline {
+                requests.remove(&id);
+                return decision;
+            }
+            let (guard, _) = self.decided.wait_timeout(requests, deadline - now).unwrap();
+            requests = guard;
+        }
+    }
+}
+
+lazy_static! {
+    static ref APPROVAL_REGISTRY: ApprovalRegistry = ApprovalRegistry::new();
+    static ref APPROVAL_ID_GENERATOR: AtomicU64 = AtomicU64::new(1);
+}
+
+pub fn approval_registry() -> &'static ApprovalRegistry {
+    &APPROVAL_REGISTRY
+}
+
+fn is_large_table(config: &ApprovalConfig, name: &ObjectName) -> bool {
+    let full_name = name.to_string();
+    let table_name = name.0.last().map(|ident| ident.value.clone()).unwrap_or_default();
+    config.get_large_tables().iter()
+        .any(|large| large.eq_ignore_ascii_case(&full_name) || large.eq_ignore_ascii_case(&table_name))
+}
+
+/// Returns why `statement` needs approval, or None if it can run right away.
+pub fn dangerous_reason(statement: &Statement, config: &ApprovalConfig) -> Option<String> {
+    match statement {
+        Statement::Delete { table_name, selection: None, .. } => {
+            Some(format!("DELETE FROM {} without WHERE", table_name))
+        }
+        Statement::Update { table_name, selection: None, .. } => {
+            Some(format!("UPDATE {} without WHERE", table_name))
+        }
+        Statement::Truncate { table_name, .. } if is_large_table(config, table_name) => {
+            Some(format!("TRUNCATE on large table {}", table_name))
+        }
+        Statement::AlterTable { name, .. } if is_large_table(config, name) => {
+            Some(format!("ALTER on large table {}", name))
+        }
+        Statement::CreateIndex { table_name, .. } if is_large_table(config, table_name) => {
+            Some(format!("CREATE INDEX on large table {}", table_name))
+        }
+        Statement::Drop { names, .. } => {
+            names.iter()
+                .find(|name| is_large_table(config, name))
+                .map(|name| format!("DROP on large table {}", name))
+        }
+        _ => None,
+    }
+}
+
+/// Posts the parked request to the webhook. An approver may answer right away with
+/// `{"approved": bool, "reason": "..."}`, otherwise it has to call back later.
+fn notify_webhook(webhook: String, request: &ApprovalRequest) {
+    if webhook.is_empty() {
+        return;
+    }
+    let handle = match tokio::runtime::Handle::try_current() {
+        Ok(handle) => handle,
+        Err(e) => {
+            println!("error on notifying approval webhook {}; error = {:?}", webhook, e);
+            return;
+        }
+    };
+    let id = request.get_id();
+    let body = request.to_json();
+    handle.spawn(async move {
+        let request = match Request::builder()
+            .method(Method::POST)
+            .uri(webhook.as_str())
+            .header("content-type", "application/json")
+            .body(Body::from(body)) {
+            Ok(request) => request,
+            Err(e) => {
+                println!("error on building approval webhook request; error = {:?}", e);
+                return;
+            }
+        };
+        let response = match Client::new().request(request).await {
+            Ok(response) => response,
+            Err(e) => {
+                println!("error on notifying approval webhook {}; error = {:?}", webhook, e);
+                return;
+            }
+        };
+        let bytes = match hyper::body::to_bytes(response.into_body()).await {
+            Ok(bytes) => bytes,
+            Err(_) => return,
+        };
+        if let Ok(answer) = serde_json::from_slice::<serde_json::Value>(&bytes) {
+            let reason = answer["reason"].as_str().unwrap_or("denied by approver").to_string();
+            match answer["approved"].as_bool() {
+                Some(true) => { APPROVAL_REGISTRY.decide(id, ApprovalDecision::Approved); }
+                Some(false) => { APPROVAL_REGISTRY.decide(id, ApprovalDecision::Denied(reason)); }
+                None => {}
+            }
+        }
+    });
+}
+
+/// Parks `statement` until it is approved when it matches a dangerous rule.
+/// On rejection returns the message to send back to the client.
+pub fn check(statement: &Statement, sql: &str, session_ctx: &SessionContext) -> Result<(), String> {
+    let config = MeshConfig::get_approval_config();
+    if !config.is_enabled() {
+        return Ok(());
+    }
+    let reason = match dangerous_reason(statement, &config) {
+        Some(reason) => reason,
+        None => return Ok(()),
+    };
+
+    let request = ApprovalRequest {
+        id: APPROVAL_ID_GENERATOR.fetch_add(1, Ordering::SeqCst),
+        session_id: session_ctx.get_thread_id(),
+        user_name: session_ctx.get_user_name(),
+        sql: sql.to_string(),
+        reason: reason.clone(),
+        decision: ApprovalDecision::Pending,
+    };
+    let id = request.get_id();
+    APPROVAL_REGISTRY.park(request.clone());
+    notify_webhook(config.get_webhook(), &request);
+
+    let timeout = Duration::from_millis(config.get_timeout() as u64);
+    match tokio::task::block_in_place(|| APPROVAL_REGISTRY.wait(id, timeout)) {
+        ApprovalDecision::Approved => Ok(()),
+        ApprovalDecision::Denied(why) => {
+            Err(format!("Statement rejected by approval workflow ({}): {}", reason, why))
+        }
+        ApprovalDecision::Pending => {
+            Err(format!("Statement requires approval ({}), not granted within {} ms", reason, config.get_timeout()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data_panel_common::config::config::ApprovalConfig;
+
+    use crate::handler::database::approval::dangerous_reason;
+    use crate::handler::database::parser::sql::mysql::parser;
+
+    #[test]
+    fn test_dangerous_reason() {
+        let config = ApprovalConfig::default();
+
+        let statement = parser("DELETE FROM t_order".to_string()).unwrap().pop().unwrap();
+        assert!(dangerous_reason(&statement, &config).is_some());
+
+        let statement = parser("DELETE FROM t_order WHERE user_id = 1".to_string()).unwrap().pop().unwrap();
+        assert!(dangerous_reason(&statement, &config).is_none());
+
+        let statement = parser("UPDATE t_order SET status = 1".to_string()).unwrap().pop().unwrap();
+        assert!(dangerous_reason(&statement, &config).is_some());
+    }
+}
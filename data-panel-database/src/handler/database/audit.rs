@@ -0,0 +1,310 @@
+//! Audit log of the statements clients run.
+//!
+//! Every statement leaves a record: who ran it from where, on which database, its
+//! fingerprint, and how it ended. Records are queued and written by a background thread to the
+//! configured sink, a full queue drops records instead of holding statements up.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use hyper::{Body, Client, Method, Request};
+
+use data_panel_common::config::config::AuditConfig;
+
+/// Records written to the sink at once.
+const BATCH_SIZE: usize = 256;
+/// Syslog facility local0.
+const SYSLOG_FACILITY: u8 = 16;
+
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    timestamp: String,
+    session_id: u64,
+    user: String,
+    client_addr: SocketAddr,
+    database: String,
+    fingerprint: String,
+    affected_rows: Option<u64>,
+    error: Option<(u16, String)>,
+}
+
+impl AuditRecord {
+    pub fn new(session_id: u64, user: String, client_addr: SocketAddr, database: String, fingerprint: String) -> Self {
+        AuditRecord {
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            session_id,
+            user,
+            client_addr,
+            database,
+            fingerprint,
+            affected_rows: None,
+            error: None,
+        }
+    }
+
+    /// Rows changed by a statement answered with an OK packet.
+    pub fn affected_rows(mut self, affected_rows: Option<u64>) -> Self {
+        self.affected_rows = affected_rows;
+        self
+    }
+
+    pub fn error(mut self, error: Option<(u16, String)>) -> Self {
+        self.error = error;
+        self
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.error.is_none()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "timestamp": self.timestamp,
+            "session_id": self.session_id,
+            "user": self.user,
+            "client_addr": self.client_addr.to_string(),
+            "database": self.database,
+            "fingerprint": self.fingerprint,
+            "affected_rows": self.affected_rows,
+            "success": self.is_success(),
+            "error_code": self.error.as_ref().map(|(code, _)| code),
+            "error_message": self.error.as_ref().map(|(_, message)| message),
+        })
+    }
+}
+
+/// Where the records end up.
+pub trait AuditSink: Send {
+    fn write(&mut self, records: &[AuditRecord]) -> Result<(), String>;
+}
+
+/// JSON lines in a file, rotated to `path.1`, `path.2`... once it grows past `max_size` bytes.
+pub struct FileSink {
+    path: String,
+    max_size: u64,
+    max_files: u32,
+    file: Option<File>,
+    size: u64,
+}
+
+impl FileSink {
+    pub fn new(path: &str, max_size: u64, max_files: u32) -> Self {
+        FileSink {
+            path: path.to_string(),
+            max_size,
+            max_files,
+            file: None,
+            size: 0,
+        }
+    }
+
+    fn rotate(&mut self) -> Result<(), String> {
+        self.file = None;
+        for i in (1..self.max_files).rev() {
+            let from = format!("{}.{}", self.path, i);
+            if fs::metadata(&from).is_ok() {
+                fs::rename(&from, format!("{}.{}", self.path, i + 1)).map_err(|e| e.to_string())?;
+            }
+        }
+        fs::rename(&self.path, format!("{}.1", self.path)).map_err(|e| e.to_string())
+    }
+
+    fn file(&mut self) -> Result<&mut File, String> {
+        if self.file.is_none() {
+            let file = OpenOptions::new().create(true).append(true).open(&self.path).map_err(|e| e.to_string())?;
+            self.size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+}
+
+impl AuditSink for FileSink {
+    fn write(&mut self, records: &[AuditRecord]) -> Result<(), String> {
+        for record in records {
+            let line = format!("{}\n", record.to_json());
+            self.file()?.write_all(line.as_bytes()).map_err(|e| e.to_string())?;
+            self.size += line.len() as u64;
+            if self.size >= self.max_size {
+                self.rotate()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// RFC 5424 messages over UDP, failed statements with the warning severity.
+pub struct SyslogSink {
+    socket: UdpSocket,
+    addr: String,
+}
+
+impl SyslogSink {
+    pub fn new(addr: &str) -> Result<Self, String> {
+        let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| e.to_string())?;
+        Ok(SyslogSink {
+            socket,
+            addr: addr.to_string(),
+        })
+    }
+}
+
+impl AuditSink for SyslogSink {
+    fn write(&mut self, records: &[AuditRecord]) -> Result<(), String> {
+        for record in records {
+            let severity = if record.is_success() { 6 } else { 4 };
+            let message = format!("<{}>1 {} - martlet {} audit - {}",
+                                  SYSLOG_FACILITY * 8 + severity, record.timestamp, std::process::id(), record.to_json());
+            self.socket.send_to(message.as_bytes(), self.addr.as_str()).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// Batches posted to a topic through the Kafka REST proxy.
+pub struct KafkaSink {
+    url: String,
+    runtime: tokio::runtime::Runtime,
+}
+
+impl KafkaSink {
+    pub fn new(url: &str, topic: &str) -> Result<Self, String> {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().map_err(|e| e.to_string())?;
+        Ok(KafkaSink {
+            url: format!("{}/topics/{}", url.trim_end_matches('/'), topic),
+            runtime,
+        })
+    }
+}
+
+impl AuditSink for KafkaSink {
+    fn write(&mut self, records: &[AuditRecord]) -> Result<(), String> {
+        let body = serde_json::json!({
+            "records": records.iter().map(|record| serde_json::json!({ "value": record.to_json() })).collect::<Vec<_>>(),
+        });
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.as_str())
+            .header("content-type", "application/vnd.kafka.json.v2+json")
+            .body(Body::from(body.to_string()))
+            .map_err(|e| e.to_string())?;
+        let response = self.runtime.block_on(async {
+            tokio::time::timeout(Duration::from_secs(5), Client::new().request(request)).await
+        });
+        match response {
+            Ok(Ok(response)) if response.status().is_success() => Ok(()),
+            Ok(Ok(response)) => Err(format!("the Kafka REST proxy answered {}", response.status())),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err("no answer from the Kafka REST proxy within 5s".to_string()),
+        }
+    }
+}
+
+fn new_sink(config: &AuditConfig) -> Result<Box<dyn AuditSink>, String> {
+    match config.get_sink().as_str() {
+        "file" => Ok(Box::new(FileSink::new(&config.get_path(), config.get_max_size() as u64 * 1024 * 1024, config.get_max_files()))),
+        "syslog" => Ok(Box::new(SyslogSink::new(&config.get_syslog_addr())?)),
+        "kafka" if !config.get_kafka_url().is_empty() => Ok(Box::new(KafkaSink::new(&config.get_kafka_url(), &config.get_kafka_topic())?)),
+        "kafka" => Err("the kafka sink needs kafka_url".to_string()),
+        sink => Err(format!("unknown audit sink {}, expected file, syslog or kafka", sink)),
+    }
+}
+
+fn write_records(mut sink: Box<dyn AuditSink>, records: Receiver<AuditRecord>) {
+    // Ends once the log is replaced and its last record written.
+    while let Ok(record) = records.recv() {
+        let mut batch = vec![record];
+        while batch.len() < BATCH_SIZE {
+            match records.try_recv() {
+                Ok(record) => batch.push(record),
+                Err(_) => break,
+            }
+        }
+        if let Err(e) = sink.write(&batch) {
+            println!("error on writing {} audit records; error = {}", batch.len(), e);
+        }
+    }
+}
+
+pub struct AuditLog {
+    records: SyncSender<AuditRecord>,
+    dropped: AtomicU64,
+}
+
+impl AuditLog {
+    pub fn new(sink: Box<dyn AuditSink>, queue_size: usize) -> Self {
+        let (records, receiver) = mpsc::sync_channel(queue_size);
+        thread::spawn(move || write_records(sink, receiver));
+        AuditLog {
+            records,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, record: AuditRecord) {
+        match self.records.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                if self.dropped.fetch_add(1, Ordering::Relaxed) % 10000 == 0 {
+                    println!("Audit queue is full, records are dropped");
+                }
+            }
+        }
+    }
+
+    /// Records dropped on a full queue.
+    pub fn get_dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+lazy_static! {
+    static ref AUDIT_LOG: RwLock<Option<Arc<AuditLog>>> = RwLock::new(None);
+}
+
+pub fn configure_audit(config: &AuditConfig) -> Result<(), String> {
+    let audit_log = if config.is_enabled() {
+        Some(Arc::new(AuditLog::new(new_sink(config)?, config.get_queue_size())))
+    } else {
+        None
+    };
+    *AUDIT_LOG.write().unwrap() = audit_log;
+    Ok(())
+}
+
+pub fn audit_log() -> Option<Arc<AuditLog>> {
+    AUDIT_LOG.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{AuditRecord, AuditSink, FileSink};
+
+    #[test]
+    fn test_file_sink_rotation() {
+        let path = std::env::temp_dir().join(format!("martlet-audit-{}.log", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let record = AuditRecord::new(1, "root".to_string(), "127.0.0.1:40000".parse().unwrap(), "martlet".to_string(), "SELECT * FROM t_user WHERE id = ?".to_string())
+            .error(Some((1146, "Table 'martlet.t_user' doesn't exist".to_string())));
+        let line_len = format!("{}\n", record.to_json()).len() as u64;
+
+        let mut sink = FileSink::new(&path, line_len * 2, 2);
+        sink.write(&vec![record.clone(); 5]).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), line_len);
+        assert_eq!(fs::metadata(format!("{}.1", path)).unwrap().len(), line_len * 2);
+        assert_eq!(fs::metadata(format!("{}.2", path)).unwrap().len(), line_len * 2);
+        assert!(fs::metadata(format!("{}.3", path)).is_err());
+
+        for file in [path.clone(), format!("{}.1", path), format!("{}.2", path)].iter() {
+            fs::remove_file(file).unwrap();
+        }
+    }
+}
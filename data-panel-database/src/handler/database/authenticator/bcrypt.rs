@@ -0,0 +1,239 @@
+//! Verifies passwords against bcrypt hashes, `$2a$`, `$2b$` and `$2y$` with their cost and salt.
+//!
+//! The initial Blowfish state is the fractional part of pi, computed once with Machin's formula
+//! instead of tabled.
+
+use std::sync::Arc;
+
+const ALPHABET: &[u8] = b"./ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+/// The 18 words of P, then the 4 S-boxes of 256 words.
+const STATE_WORDS: usize = 18 + 4 * 256;
+
+lazy_static! {
+    static ref INITIAL_STATE: Arc<Vec<u32>> = Arc::new(pi_fraction(STATE_WORDS));
+}
+
+/// `a` / `d`, `a` being a fixed point number with its integer part in the first word.
+fn div_small(a: &mut [u32], d: u32) {
+    let mut remainder = 0u64;
+    for word in a.iter_mut() {
+        let value = (remainder << 32) | *word as u64;
+        *word = (value / d as u64) as u32;
+        remainder = value % d as u64;
+    }
+}
+
+fn add(a: &mut [u32], b: &[u32]) {
+    let mut carry = 0u64;
+    for (x, y) in a.iter_mut().zip(b.iter()).rev() {
+        let sum = *x as u64 + *y as u64 + carry;
+        *x = sum as u32;
+        carry = sum >> 32;
+    }
+}
+
+fn sub(a: &mut [u32], b: &[u32]) {
+    let mut borrow = 0i64;
+    for (x, y) in a.iter_mut().zip(b.iter()).rev() {
+        let difference = *x as i64 - *y as i64 - borrow;
+        *x = difference as u32;
+        borrow = if difference < 0 { 1 } else { 0 };
+    }
+}
+
+/// `factor` * atan(1 / `x`) over `len` words.
+fn arctan_inverse(x: u32, factor: u32, len: usize) -> Vec<u32> {
+    let mut power = vec![0u32; len];
+    power[0] = factor;
+    div_small(&mut power, x);
+    let mut sum = power.clone();
+    let mut n = 1u32;
+    while power.iter().any(|word| *word != 0) {
+        div_small(&mut power, x * x);
+        let mut term = power.clone();
+        div_small(&mut term, 2 * n + 1);
+        if n % 2 == 1 {
+            sub(&mut sum, &term);
+        } else {
+            add(&mut sum, &term);
+        }
+        n += 1;
+    }
+    sum
+}
+
+/// The first `words` 32 bit words of the fractional part of pi.
+fn pi_fraction(words: usize) -> Vec<u32> {
+    // An integer word, and guard words for the truncated divisions.
+    let len = words + 5;
+    let mut pi = arctan_inverse(5, 16, len);
+    sub(&mut pi, &arctan_inverse(239, 4, len));
+    pi[1..=words].to_vec()
+}
+
+fn decode_base64(text: &str, len: usize) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in text.bytes() {
+        let value = ALPHABET.iter().position(|a| *a == c)? as u32;
+        bits = (bits << 6) | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    if bytes.len() < len {
+        return None;
+    }
+    bytes.truncate(len);
+    Some(bytes)
+}
+
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    let mut bits = 0u32;
+    let mut count = 0;
+    for byte in bytes {
+        bits = (bits << 8) | *byte as u32;
+        count += 8;
+        while count >= 6 {
+            count -= 6;
+            text.push(ALPHABET[(bits >> count) as usize & 0x3f] as char);
+        }
+    }
+    if count > 0 {
+        text.push(ALPHABET[(bits << (6 - count)) as usize & 0x3f] as char);
+    }
+    text
+}
+
+struct Blowfish {
+    p: Vec<u32>,
+    s: Vec<u32>,
+}
+
+/// The next big endian word of `data`, cycling over it.
+fn stream_word(data: &[u8], position: &mut usize) -> u32 {
+    let mut word = 0u32;
+    for _ in 0..4 {
+        word = (word << 8) | data[*position] as u32;
+        *position = (*position + 1) % data.len();
+    }
+    word
+}
+
+impl Blowfish {
+    fn new() -> Self {
+        let state = INITIAL_STATE.clone();
+        Blowfish {
+            p: state[..18].to_vec(),
+            s: state[18..].to_vec(),
+        }
+    }
+
+    fn f(&self, x: u32) -> u32 {
+        let a = self.s[(x >> 24) as usize];
+        let b = self.s[256 + ((x >> 16) & 0xff) as usize];
+        let c = self.s[512 + ((x >> 8) & 0xff) as usize];
+        let d = self.s[768 + (x & 0xff) as usize];
+        (a.wrapping_add(b) ^ c).wrapping_add(d)
+    }
+
+    fn encrypt(&self, mut left: u32, mut right: u32) -> (u32, u32) {
+        for p in self.p[..16].iter() {
+            left ^= p;
+            right ^= self.f(left);
+            std::mem::swap(&mut left, &mut right);
+        }
+        std::mem::swap(&mut left, &mut right);
+        (left ^ self.p[17], right ^ self.p[16])
+    }
+
+    /// The key schedule of EksBlowfish, with no salt mixed in when `salt` is empty.
+    fn expand(&mut self, salt: &[u8], key: &[u8]) {
+        let mut position = 0;
+        for word in self.p.iter_mut() {
+            *word ^= stream_word(key, &mut position);
+        }
+        let mut salt_position = 0;
+        let (mut left, mut right) = (0u32, 0u32);
+        for i in (0..STATE_WORDS).step_by(2) {
+            if !salt.is_empty() {
+                left ^= stream_word(salt, &mut salt_position);
+                right ^= stream_word(salt, &mut salt_position);
+            }
+            let (l, r) = self.encrypt(left, right);
+            left = l;
+            right = r;
+            if i < 18 {
+                self.p[i] = left;
+                self.p[i + 1] = right;
+            } else {
+                self.s[i - 18] = left;
+                self.s[i - 17] = right;
+            }
+        }
+    }
+}
+
+/// The 23 byte bcrypt digest of `password` with `cost` and the 16 byte `salt`.
+fn digest(password: &str, cost: u32, salt: &[u8]) -> Vec<u8> {
+    let mut key = password.as_bytes().to_vec();
+    key.push(0);
+    key.truncate(72);
+    let mut blowfish = Blowfish::new();
+    blowfish.expand(salt, &key);
+    for _ in 0..(1u64 << cost) {
+        blowfish.expand(&[], &key);
+        blowfish.expand(&[], salt);
+    }
+    let mut text: Vec<u32> = b"OrpheanBeholderScryDoubt".chunks(4)
+        .map(|chunk| u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect();
+    for _ in 0..64 {
+        for pair in text.chunks_mut(2) {
+            let (left, right) = blowfish.encrypt(pair[0], pair[1]);
+            pair[0] = left;
+            pair[1] = right;
+        }
+    }
+    let mut bytes: Vec<u8> = text.iter().flat_map(|word| word.to_be_bytes().to_vec()).collect();
+    bytes.truncate(23);
+    bytes
+}
+
+/// Whether `password` matches `hash`, false for a hash that is not bcrypt.
+pub fn verify(password: &str, hash: &str) -> bool {
+    let parts: Vec<&str> = hash.split('$').collect();
+    if parts.len() != 4 || !parts[0].is_empty() || !matches!(parts[1], "2a" | "2b" | "2y") || parts[3].len() != 53 {
+        return false;
+    }
+    let cost = match parts[2].parse::<u32>() {
+        Ok(cost) if (4..=31).contains(&cost) => cost,
+        _ => return false,
+    };
+    let salt = match decode_base64(&parts[3][..22], 16) {
+        Some(salt) => salt,
+        None => return false,
+    };
+    let expected = encode_base64(&digest(password, cost, &salt));
+    // Compared in constant time.
+    expected.len() == 31 && expected.bytes().zip(parts[3][22..].bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::verify;
+
+    #[test]
+    fn test_verify() {
+        assert!(verify("U*U", "$2b$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW"));
+        assert!(verify("U*U", "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW"));
+        assert!(!verify("U*V", "$2b$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW"));
+        assert!(!verify("U*U", "{SHA}E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW"));
+    }
+}
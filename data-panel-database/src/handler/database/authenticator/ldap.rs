@@ -0,0 +1,181 @@
+//! LDAP simple bind, the only LDAP operation the mesh needs to check a password.
+//!
+//! The BindRequest is BER encoded by hand, the BindResponse decoded just enough to read its
+//! resultCode. `ldaps://` servers are reached over TLS, their certificate verified.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use openssl::ssl::{SslConnector, SslMethod};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// BER tags of the messages of a bind.
+const SEQUENCE: u8 = 0x30;
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const ENUMERATED: u8 = 0x0a;
+const BIND_REQUEST: u8 = 0x60;
+const BIND_RESPONSE: u8 = 0x61;
+const SIMPLE_AUTHENTICATION: u8 = 0x80;
+
+/// resultCode of a bind with a wrong DN or password.
+const INVALID_CREDENTIALS: u8 = 49;
+
+fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    let len = value.len();
+    if len < 0x80 {
+        encoded.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len.to_be_bytes().iter().skip_while(|byte| **byte == 0).cloned().collect();
+        encoded.push(0x80 | bytes.len() as u8);
+        encoded.extend_from_slice(&bytes);
+    }
+    encoded.extend_from_slice(value);
+    encoded
+}
+
+/// The LDAPMessage of a version 3 simple bind as `dn`.
+fn bind_request(dn: &str, password: &str) -> Vec<u8> {
+    let mut bind = tlv(INTEGER, &[3]);
+    bind.extend(tlv(OCTET_STRING, dn.as_bytes()));
+    bind.extend(tlv(SIMPLE_AUTHENTICATION, password.as_bytes()));
+    let mut message = tlv(INTEGER, &[1]);
+    message.extend(tlv(BIND_REQUEST, &bind));
+    tlv(SEQUENCE, &message)
+}
+
+/// The tag and value of the element `bytes` starts with, and the bytes after it.
+fn next_tlv(bytes: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *bytes.first()?;
+    let first = *bytes.get(1)? as usize;
+    let (len, start) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let count = first & 0x7f;
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let len = bytes.get(2..2 + count)?.iter().fold(0usize, |len, byte| (len << 8) | *byte as usize);
+        (len, 2 + count)
+    };
+    let value = bytes.get(start..start + len)?;
+    Some((tag, value, &bytes[start + len..]))
+}
+
+/// The resultCode of the BindResponse `message`.
+fn bind_result(message: &[u8]) -> Option<u8> {
+    let (tag, message, _) = next_tlv(message)?;
+    if tag != SEQUENCE {
+        return None;
+    }
+    let (_, _, rest) = next_tlv(message)?;
+    let (tag, response, _) = next_tlv(rest)?;
+    if tag != BIND_RESPONSE {
+        return None;
+    }
+    match next_tlv(response)? {
+        (ENUMERATED, code, _) if code.len() == 1 => Some(code[0]),
+        _ => None,
+    }
+}
+
+/// `value` escaped to be an attribute value of a DN, RFC 4514.
+fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::new();
+    for (i, c) in value.chars().enumerate() {
+        let special = matches!(c, '\\' | ',' | '+' | '"' | '<' | '>' | ';' | '=')
+            || (i == 0 && (c == '#' || c == ' '))
+            || (i == value.chars().count() - 1 && c == ' ');
+        if special {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Reads the LDAPMessage the server answers with.
+fn read_message<S: Read>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut message = vec![0u8; 2];
+    stream.read_exact(&mut message)?;
+    let len = if message[1] < 0x80 {
+        message[1] as usize
+    } else {
+        let count = (message[1] & 0x7f) as usize;
+        if count == 0 || count > 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported BER length"));
+        }
+        let mut len = vec![0u8; count];
+        stream.read_exact(&mut len)?;
+        message.extend_from_slice(&len);
+        len.iter().fold(0usize, |len, byte| (len << 8) | *byte as usize)
+    };
+    let start = message.len();
+    message.resize(start + len, 0);
+    stream.read_exact(&mut message[start..])?;
+    Ok(message)
+}
+
+fn exchange<S: Read + Write>(stream: &mut S, request: &[u8]) -> io::Result<Vec<u8>> {
+    stream.write_all(request)?;
+    read_message(stream)
+}
+
+/// Binds as `bind_dn`, its `{user}` replaced by `user`, with `password` on the server of `url`.
+pub fn bind(url: &str, bind_dn: &str, user: &str, password: &str) -> Result<(), String> {
+    // An empty password would be an unauthenticated bind, which servers accept.
+    if password.is_empty() {
+        return Err("empty password".to_string());
+    }
+    let (tls, address) = match (url.strip_prefix("ldaps://"), url.strip_prefix("ldap://")) {
+        (Some(address), _) => (true, address),
+        (None, Some(address)) => (false, address),
+        _ => return Err(format!("{} is not an ldap:// or ldaps:// url", url)),
+    };
+    let address = address.trim_end_matches('/');
+    let host = address.split(':').next().unwrap_or(address);
+    let address = if address.contains(':') { address.to_string() } else { format!("{}:{}", address, if tls { 636 } else { 389 }) };
+    let socket_addr = address.to_socket_addrs().map_err(|e| e.to_string())?.next().ok_or_else(|| format!("unable to resolve {}", address))?;
+    let stream = TcpStream::connect_timeout(&socket_addr, TIMEOUT).map_err(|e| e.to_string())?;
+    stream.set_read_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+
+    let request = bind_request(&bind_dn.replace("{user}", &escape_dn_value(user)), password);
+    let response = if tls {
+        let connector = SslConnector::builder(SslMethod::tls()).map_err(|e| e.to_string())?.build();
+        let mut stream = connector.connect(host, stream).map_err(|e| e.to_string())?;
+        exchange(&mut stream, &request)
+    } else {
+        exchange(&mut &stream, &request)
+    };
+    match response.map(|response| bind_result(&response)) {
+        Ok(Some(0)) => Ok(()),
+        Ok(Some(INVALID_CREDENTIALS)) => Err("invalid credentials".to_string()),
+        Ok(Some(code)) => Err(format!("the bind failed with resultCode {}", code)),
+        Ok(None) => Err("the server did not answer with a BindResponse".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bind_request, bind_result, escape_dn_value};
+
+    #[test]
+    fn test_bind_messages() {
+        let request = bind_request("uid=app,dc=example", "secret");
+        assert_eq!(request[..9], [0x30, 0x24, 0x02, 0x01, 0x01, 0x60, 0x1f, 0x02, 0x01]);
+        assert_eq!(request.len(), 0x26);
+        assert_eq!(escape_dn_value("a,b=c"), "a\\,b\\=c");
+        assert_eq!(escape_dn_value(" #x "), "\\ #x\\ ");
+
+        let success = [0x30, 0x0c, 0x02, 0x01, 0x01, 0x61, 0x07, 0x0a, 0x01, 0x00, 0x04, 0x00, 0x04, 0x00];
+        assert_eq!(bind_result(&success), Some(0));
+        let invalid = [0x30, 0x84, 0x00, 0x00, 0x00, 0x0c, 0x02, 0x01, 0x01, 0x61, 0x07, 0x0a, 0x01, 0x31, 0x04, 0x00, 0x04, 0x00];
+        assert_eq!(bind_result(&invalid), Some(49));
+        assert_eq!(bind_result(&success[..6]), None);
+    }
+}
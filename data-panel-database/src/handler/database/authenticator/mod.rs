@@ -0,0 +1,223 @@
+//! Password checks of client logins, see `AuthConfig`.
+//!
+//! The protocol handlers turn what the client answered into a `Credential` and ask the
+//! `Authenticator` of the configured backend. The backends other than `config` do not know
+//! the plain passwords, so they check them as typed, the MySQL handler asking the client for
+//! it with mysql_clear_password.
+
+pub mod bcrypt;
+pub mod ldap;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use hyper::{Body, Client, Request};
+use hyper::body::HttpBody;
+use hyper::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+
+use data_panel_common::config::config::{AuthConfig, MeshConfig};
+
+use crate::discovery::database::pilot::{grpc_message, grpc_status, next_message};
+use crate::handler::database::mysql::auth::{scramble_caching_sha2_password, scramble_native_password};
+use crate::protocol::database::mysql::constant::MySQLAuthenticationMethod;
+
+const CHECK_TOKEN_PATH: &str = "/martlet.auth.v1.Authenticator/CheckToken";
+
+/// What a client answered the authentication with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credential {
+    /// The answer computed by the client with the MySQL auth `plugin` for `scramble`.
+    Scramble { plugin: String, scramble: Vec<u8>, response: Vec<u8> },
+    /// The password as typed.
+    Cleartext(String),
+}
+
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Ok when `user` proved who it is with `credential`, why it did not otherwise.
+    async fn authenticate(&self, user: &str, credential: Credential) -> Result<(), String>;
+}
+
+/// Every login, when authentication is not enabled.
+pub struct AcceptAuthenticator;
+
+#[async_trait]
+impl Authenticator for AcceptAuthenticator {
+    async fn authenticate(&self, _user: &str, _credential: Credential) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// The plain passwords of the users of the config, compared with the scramble answered.
+pub struct ConfigAuthenticator {
+    config: AuthConfig,
+}
+
+#[async_trait]
+impl Authenticator for ConfigAuthenticator {
+    async fn authenticate(&self, user: &str, credential: Credential) -> Result<(), String> {
+        let password = self.config.password(user).ok_or_else(|| "unknown user".to_string())?;
+        let matches = match credential {
+            Credential::Scramble { plugin, scramble, response } => {
+                let expected = if plugin == MySQLAuthenticationMethod::CachingSha2Password.value() {
+                    scramble_caching_sha2_password(&password, &scramble)
+                } else {
+                    scramble_native_password(&password, &scramble)
+                };
+                expected == response
+            }
+            Credential::Cleartext(typed) => typed == password,
+        };
+        if matches { Ok(()) } else { Err("wrong password".to_string()) }
+    }
+}
+
+/// The bcrypt hashes of the `user:hash` lines of a file, read on every login so edits apply
+/// at once.
+pub struct FileAuthenticator {
+    file: String,
+}
+
+#[async_trait]
+impl Authenticator for FileAuthenticator {
+    async fn authenticate(&self, user: &str, credential: Credential) -> Result<(), String> {
+        let password = match credential {
+            Credential::Cleartext(password) => password,
+            Credential::Scramble { .. } => return Err("the password was not sent as typed".to_string()),
+        };
+        let file = self.file.clone();
+        let user = user.to_string();
+        // bcrypt is slow on purpose, it does not run on the workers.
+        tokio::task::spawn_blocking(move || {
+            let users = std::fs::read_to_string(&file).map_err(|e| format!("unable to read {}; error = {}", file, e))?;
+            let hash = users.lines()
+                .map(|line| line.trim())
+                .filter(|line| !line.starts_with('#'))
+                .filter_map(|line| line.find(':').map(|colon| (&line[..colon], &line[colon + 1..])))
+                .find(|(name, _)| *name == user)
+                .map(|(_, hash)| hash.to_string())
+                .ok_or_else(|| "unknown user".to_string())?;
+            if bcrypt::verify(&password, &hash) { Ok(()) } else { Err("wrong password".to_string()) }
+        }).await.map_err(|e| e.to_string())?
+    }
+}
+
+/// A simple bind on an LDAP server.
+pub struct LdapAuthenticator {
+    url: String,
+    bind_dn: String,
+}
+
+#[async_trait]
+impl Authenticator for LdapAuthenticator {
+    async fn authenticate(&self, user: &str, credential: Credential) -> Result<(), String> {
+        let password = match credential {
+            Credential::Cleartext(password) => password,
+            Credential::Scramble { .. } => return Err("the password was not sent as typed".to_string()),
+        };
+        let (url, bind_dn, user) = (self.url.clone(), self.bind_dn.clone(), user.to_string());
+        tokio::task::spawn_blocking(move || ldap::bind(&url, &bind_dn, &user, &password)).await.map_err(|e| e.to_string())?
+    }
+}
+
+#[derive(Serialize)]
+struct TokenRequest {
+    user: String,
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct TokenReview {
+    #[serde(default)]
+    allowed: bool,
+    #[serde(default)]
+    reason: String,
+}
+
+/// The tokens, or passwords, citadel accepts, checked with a `CheckToken` call using the JSON
+/// codec like the rules discovery.
+pub struct ControlPlaneAuthenticator {
+    citadel: String,
+}
+
+impl ControlPlaneAuthenticator {
+    async fn check_token(&self, user: &str, token: String) -> Result<(), String> {
+        let message = serde_json::to_vec(&TokenRequest { user: user.to_string(), token }).map_err(|e| e.to_string())?;
+        let request = Request::post(format!("http://{}{}", self.citadel, CHECK_TOKEN_PATH))
+            .header(CONTENT_TYPE, "application/grpc+json")
+            .header("te", "trailers")
+            .body(Body::from(grpc_message(&message)))
+            .map_err(|e| e.to_string())?;
+        let client = Client::builder().http2_only(true).build_http::<Body>();
+        let mut response = client.request(request).await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() || response.headers().contains_key("grpc-status") {
+            return Err(format!("citadel answered {} grpc-status {}", response.status(), grpc_status(Some(response.headers()))));
+        }
+        let mut buffer = BytesMut::new();
+        while let Some(chunk) = response.body_mut().data().await {
+            buffer.extend_from_slice(&chunk.map_err(|e| e.to_string())?);
+            if let Some(message) = next_message(&mut buffer)? {
+                let review: TokenReview = serde_json::from_slice(&message).map_err(|e| e.to_string())?;
+                return if review.allowed { Ok(()) } else { Err(format!("citadel refused the token: {}", review.reason)) };
+            }
+        }
+        let trailers = response.body_mut().trailers().await.ok().flatten();
+        Err(format!("no review from citadel, grpc-status {}", grpc_status(trailers.as_ref())))
+    }
+}
+
+#[async_trait]
+impl Authenticator for ControlPlaneAuthenticator {
+    async fn authenticate(&self, user: &str, credential: Credential) -> Result<(), String> {
+        let token = match credential {
+            Credential::Cleartext(token) => token,
+            Credential::Scramble { .. } => return Err("the token was not sent as typed".to_string()),
+        };
+        match tokio::time::timeout(Duration::from_secs(5), self.check_token(user, token)).await {
+            Ok(result) => result,
+            Err(_) => Err("no answer from citadel within 5s".to_string()),
+        }
+    }
+}
+
+/// The authenticator of the backend of `config`.
+pub fn authenticator(config: &AuthConfig) -> Arc<dyn Authenticator> {
+    if !config.is_enabled() {
+        return Arc::new(AcceptAuthenticator);
+    }
+    match config.get_backend().as_str() {
+        "file" => Arc::new(FileAuthenticator { file: config.get_file() }),
+        "ldap" => Arc::new(LdapAuthenticator { url: config.get_ldap_url(), bind_dn: config.get_ldap_bind_dn() }),
+        "control_plane" => Arc::new(ControlPlaneAuthenticator { citadel: MeshConfig::get_control_config().get_citadel() }),
+        _ => Arc::new(ConfigAuthenticator { config: config.clone() }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use data_panel_common::config::config::{AuthConfig, AuthUser};
+
+    use super::{authenticator, Credential};
+
+    #[tokio::test]
+    async fn test_authenticators() {
+        let config = authenticator(&AuthConfig::new(vec![AuthUser::new("app", "secret")]));
+        assert!(config.authenticate("app", Credential::Cleartext("secret".to_string())).await.is_ok());
+        assert!(config.authenticate("app", Credential::Cleartext("guess".to_string())).await.is_err());
+        assert!(config.authenticate("other", Credential::Cleartext("secret".to_string())).await.is_err());
+
+        let path = std::env::temp_dir().join("martlet_auth_users");
+        let mut file = std::fs::File::create(&path).unwrap();
+        writeln!(file, "# htpasswd -B\nU:$2b$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW").unwrap();
+        let file = authenticator(&AuthConfig::file(path.to_str().unwrap()));
+        assert!(file.authenticate("U", Credential::Cleartext("U*U".to_string())).await.is_ok());
+        assert!(file.authenticate("U", Credential::Cleartext("U*V".to_string())).await.is_err());
+        assert!(file.authenticate("U", Credential::Scramble { plugin: "mysql_native_password".to_string(), scramble: vec![], response: vec![] }).await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
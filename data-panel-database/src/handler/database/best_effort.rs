@@ -0,0 +1,391 @@
+//! Best-effort delivery of writes over several data segments, see `DistributedTransactionConfig`.
+//!
+//! Every segment a transaction writes to runs a local transaction of its own, committed one
+//! after the other. Once one of them committed, a segment failing to commit no longer rolls the
+//! others back: its statements are queued to run again in a new transaction, the queue kept in
+//! a file so the branches outlive a restart. A branch failing `retries` times is stuck until
+//! the admin API retries it or abandons it, abandoned branches going to the compensation hooks
+//! so the changes of the other segments can be undone.
+//!
+//! A branch whose commit got no answer may have committed, running it again could apply it
+//! twice: writes meant for this mode should be idempotent.
+
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::{Body, Client, Method, Request};
+use mysql::{MySqlError, TxOpts};
+use mysql::prelude::Queryable;
+use serde::{Deserialize, Serialize};
+
+use data_panel_common::config::config::{DistributedTransactionConfig, MeshConfig};
+
+use crate::discovery;
+use crate::handler::database::lifecycle::{self, BackendConn};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StuckBranch {
+    id: String,
+    xid: String,
+    segment: String,
+    statements: Vec<String>,
+    attempts: u32,
+    error: String,
+    queued_at: u64,
+    /// `queued` while waiting, then `delivered` or `abandoned`.
+    state: String,
+}
+
+impl StuckBranch {
+    pub fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    pub fn get_xid(&self) -> String {
+        self.xid.clone()
+    }
+
+    pub fn get_segment(&self) -> String {
+        self.segment.clone()
+    }
+
+    pub fn get_statements(&self) -> &Vec<String> {
+        &self.statements
+    }
+
+    pub fn get_attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// The error of the last attempt.
+    pub fn get_error(&self) -> String {
+        self.error.clone()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or(serde_json::Value::Null)
+    }
+}
+
+/// Told about the branches given up on, to undo what the other segments of their transaction
+/// committed.
+pub trait CompensationHook: Send + Sync {
+    fn on_abandon(&self, branch: &StuckBranch);
+}
+
+/// Posts the abandoned branches as JSON to `compensation_url`, without waiting for the answer.
+pub struct CompensationWebhook {
+    url: String,
+}
+
+impl CompensationWebhook {
+    pub fn new(url: &str) -> Self {
+        CompensationWebhook {
+            url: url.to_string(),
+        }
+    }
+}
+
+impl CompensationHook for CompensationWebhook {
+    fn on_abandon(&self, branch: &StuckBranch) {
+        let handle = match tokio::runtime::Handle::try_current() {
+            Ok(handle) => handle,
+            Err(_) => return,
+        };
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.as_str())
+            .header("content-type", "application/json")
+            .body(Body::from(branch.to_json().to_string()));
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => return println!("error on building compensation request; error = {:?}", e),
+        };
+        let id = branch.get_id();
+        handle.spawn(async move {
+            if let Err(e) = Client::new().request(request).await {
+                println!("error on posting abandoned branch {} for compensation; error = {:?}", id, e);
+            }
+        });
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BestEffortStats {
+    queued: u64,
+    delivered: u64,
+    abandoned: u64,
+    pending: usize,
+}
+
+impl BestEffortStats {
+    /// Branches queued since the start.
+    pub fn get_queued(&self) -> u64 {
+        self.queued
+    }
+
+    /// Queued branches that committed on a retry.
+    pub fn get_delivered(&self) -> u64 {
+        self.delivered
+    }
+
+    pub fn get_abandoned(&self) -> u64 {
+        self.abandoned
+    }
+
+    /// Branches in the queue right now.
+    pub fn get_pending(&self) -> usize {
+        self.pending
+    }
+}
+
+lazy_static! {
+    static ref BRANCHES: Mutex<BTreeMap<String, StuckBranch>> = Mutex::new(BTreeMap::new());
+    static ref COMPENSATION_HOOKS: RwLock<Vec<Arc<dyn CompensationHook>>> = RwLock::new(vec![]);
+    /// One retry at a time, so a branch is never run twice at once.
+    static ref RETRYING: Mutex<()> = Mutex::new(());
+    /// Keeps the records on lines of their own.
+    static ref QUEUE_FILE: Mutex<()> = Mutex::new(());
+}
+
+static QUEUED: AtomicU64 = AtomicU64::new(0);
+static DELIVERED: AtomicU64 = AtomicU64::new(0);
+static ABANDONED: AtomicU64 = AtomicU64::new(0);
+
+pub fn register_compensation_hook(hook: Arc<dyn CompensationHook>) {
+    COMPENSATION_HOOKS.write().unwrap().push(hook);
+}
+
+pub fn best_effort_stats() -> BestEffortStats {
+    BestEffortStats {
+        queued: QUEUED.load(Ordering::Relaxed),
+        delivered: DELIVERED.load(Ordering::Relaxed),
+        abandoned: ABANDONED.load(Ordering::Relaxed),
+        pending: BRANCHES.lock().unwrap().len(),
+    }
+}
+
+/// The branches waiting in the queue, by id.
+pub fn stuck_branches() -> Vec<StuckBranch> {
+    BRANCHES.lock().unwrap().values().cloned().collect()
+}
+
+/// Appends the state of `branch` to the queue file and waits until it is on disk.
+fn record(branch: &StuckBranch) -> io::Result<()> {
+    let line = serde_json::to_string(branch).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let _guard = QUEUE_FILE.lock().unwrap();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(MeshConfig::get_distributed_transaction_config().get_queue())?;
+    file.write_all(format!("{}\n", line).as_bytes())?;
+    file.sync_data()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn enqueue(xid: &str, segment: &str, statements: Vec<String>, error: String) {
+    let branch = StuckBranch {
+        id: format!("{}.{}", xid, segment.split('/').next().unwrap_or(segment)),
+        xid: xid.to_string(),
+        segment: segment.to_string(),
+        statements,
+        attempts: 0,
+        error,
+        queued_at: now(),
+        state: "queued".to_string(),
+    };
+    if let Err(e) = record(&branch) {
+        println!("error on queueing branch {}, kept in memory only; error = {:?}", branch.id, e);
+    }
+    println!("Queued branch {} of transaction {} to commit again; error = {}", branch.id, xid, branch.error);
+    QUEUED.fetch_add(1, Ordering::Relaxed);
+    BRANCHES.lock().unwrap().insert(branch.id.clone(), branch);
+}
+
+/// Commits the local transactions of `branches`, `(segment, connection, statements)` in
+/// order. Until one commits a failure rolls back the others, unless the transaction is already
+/// `decided`, after which the failed ones are queued.
+pub fn commit(xid: &str, branches: Vec<(String, BackendConn, Vec<String>)>, decided: bool) -> mysql::Result<()> {
+    let mut decided = decided;
+    let mut branches = branches.into_iter();
+    while let Some((segment, mut conn, statements)) = branches.next() {
+        match conn.query_drop("COMMIT") {
+            Ok(()) => decided = true,
+            Err(e) if !decided => {
+                conn.discard();
+                for (segment, mut conn, _) in branches {
+                    if let Err(e) = conn.query_drop("ROLLBACK") {
+                        println!("error on rolling back branch of {} on {}; error = {:?}", xid, segment, e);
+                        conn.discard();
+                    }
+                }
+                return Err(e);
+            }
+            Err(e) => {
+                conn.discard();
+                enqueue(xid, &segment, statements, e.to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The branches `queue` leaves queued, the last record of a branch telling its state. A torn
+/// last record is skipped.
+fn queued_branches(queue: &str) -> Vec<StuckBranch> {
+    let mut branches: BTreeMap<String, StuckBranch> = BTreeMap::new();
+    for branch in queue.lines().filter_map(|line| serde_json::from_str::<StuckBranch>(line).ok()) {
+        if branch.state == "queued" {
+            branches.insert(branch.id.clone(), branch);
+        } else {
+            branches.remove(&branch.id);
+        }
+    }
+    branches.into_iter().map(|(_, branch)| branch).collect()
+}
+
+/// Runs the statements of `branch` again in a transaction of their own.
+fn deliver(branch: &StuckBranch) -> mysql::Result<()> {
+    let database_url = discovery::database::segment_url(&branch.segment).ok_or_else(|| mysql::Error::MySqlError(MySqlError {
+        state: "HY000".to_string(),
+        message: format!("no such segment {}", branch.segment),
+        code: 1105,
+    }))?;
+    let mut conn = lifecycle::connect(&database_url)?;
+    let mut tx = conn.start_transaction(TxOpts::default())?;
+    for statement in branch.statements.iter() {
+        tx.query_drop(statement)?;
+    }
+    tx.commit()
+}
+
+/// Retries the queued branch `id` now, None when there is none.
+pub fn retry(id: &str) -> Option<Result<(), String>> {
+    let _guard = RETRYING.lock().unwrap();
+    let mut branch = BRANCHES.lock().unwrap().get(id).cloned()?;
+    branch.attempts += 1;
+    let delivered = deliver(&branch);
+    match &delivered {
+        Ok(()) => {
+            branch.state = "delivered".to_string();
+            BRANCHES.lock().unwrap().remove(id);
+            DELIVERED.fetch_add(1, Ordering::Relaxed);
+            println!("Delivered branch {} after {} retries", id, branch.attempts);
+        }
+        Err(e) => {
+            branch.error = e.to_string();
+            BRANCHES.lock().unwrap().insert(id.to_string(), branch.clone());
+        }
+    }
+    if let Err(e) = record(&branch) {
+        println!("error on recording branch {}; error = {:?}", id, e);
+    }
+    Some(delivered.map_err(|e| e.to_string()))
+}
+
+/// Gives up on the queued branch `id`, handing it to the compensation hooks.
+pub fn abandon(id: &str) -> Option<StuckBranch> {
+    let _guard = RETRYING.lock().unwrap();
+    let mut branch = BRANCHES.lock().unwrap().remove(id)?;
+    branch.state = "abandoned".to_string();
+    if let Err(e) = record(&branch) {
+        println!("error on recording branch {}; error = {:?}", id, e);
+    }
+    ABANDONED.fetch_add(1, Ordering::Relaxed);
+    println!("Abandoned branch {} of transaction {} on {}", id, branch.xid, branch.segment);
+    let hooks: Vec<Arc<dyn CompensationHook>> = COMPENSATION_HOOKS.read().unwrap().clone();
+    for hook in hooks {
+        hook.on_abandon(&branch);
+    }
+    Some(branch)
+}
+
+/// Retries the queued branches that have retries left.
+fn retry_due(retries: u32) {
+    let due: Vec<String> = BRANCHES.lock().unwrap().values()
+        .filter(|branch| branch.attempts < retries)
+        .map(|branch| branch.id.clone())
+        .collect();
+    for id in due {
+        if let Some(Err(e)) = retry(&id) {
+            println!("error on retrying branch {}; error = {:?}", id, e);
+        }
+    }
+}
+
+/// Loads the queue a previous run left, keeping only its queued branches in the file, registers
+/// the configured compensation webhook and retries the queue every `retry_interval`.
+pub fn start(config: &DistributedTransactionConfig) -> tokio::task::JoinHandle<()> {
+    let path = config.get_queue();
+    match fs::read_to_string(&path) {
+        Ok(queue) => {
+            let branches = queued_branches(&queue);
+            let compacted: String = branches.iter()
+                .filter_map(|branch| serde_json::to_string(branch).ok())
+                .map(|line| format!("{}\n", line))
+                .collect();
+            if let Err(e) = fs::write(&path, compacted) {
+                println!("error on compacting best-effort queue {}; error = {:?}", path, e);
+            }
+            let mut queued = BRANCHES.lock().unwrap();
+            for branch in branches {
+                queued.insert(branch.id.clone(), branch);
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => println!("error on reading best-effort queue {}; error = {:?}", path, e),
+    }
+    if !config.get_compensation_url().is_empty() {
+        register_compensation_hook(Arc::new(CompensationWebhook::new(&config.get_compensation_url())));
+    }
+    let retries = config.get_retries();
+    let interval = Duration::from_millis(config.get_retry_interval() as u64);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = tokio::task::spawn_blocking(move || retry_due(retries)).await {
+                println!("error on retrying best-effort branches; error = {:?}", e);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{queued_branches, StuckBranch};
+
+    fn branch(id: &str, state: &str, attempts: u32) -> StuckBranch {
+        StuckBranch {
+            id: id.to_string(),
+            xid: "x-1".to_string(),
+            segment: "data-100/primary".to_string(),
+            statements: vec!["UPDATE t_order_100 SET status = 'PAID' WHERE id = 1".to_string()],
+            attempts,
+            error: "connection closed".to_string(),
+            queued_at: 0,
+            state: state.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_queued_branches() {
+        let records: Vec<String> = vec![
+            branch("x-1.data-100", "queued", 0),
+            branch("x-2.data-100", "queued", 0),
+            branch("x-1.data-100", "queued", 1),
+            branch("x-2.data-100", "delivered", 1),
+            branch("x-3.data-100", "queued", 0),
+            branch("x-3.data-100", "abandoned", 0),
+        ].iter().map(|branch| serde_json::to_string(branch).unwrap()).collect();
+        let queue = format!("{}\n{{\"id\":\"x-4", records.join("\n"));
+        assert_eq!(queued_branches(&queue), vec![branch("x-1.data-100", "queued", 1)]);
+    }
+}
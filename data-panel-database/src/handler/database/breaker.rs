@@ -0,0 +1,250 @@
+//! Circuit breakers ("fuse-outs") of the backend segments.
+//!
+//! Each backend gets a breaker, closed while its statements mostly succeed. Once too many of
+//! them fail, or run slower than the latency threshold, it opens and statements for the backend
+//! fail fast without touching it. After the open timeout a single probe is let through: its
+//! success closes the breaker again, its failure opens it for another timeout. Statements that
+//! the backend rejects, e.g. on a syntax error, show it is up and count as successes.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use mysql::MySqlError;
+
+use data_panel_common::config::config::CircuitBreakerConfig;
+
+use crate::handler::database::lifecycle::redact_url;
+
+/// Server errors meaning the backend itself is in trouble: too many connections, shutting
+/// down, connection killed, gone away and lost.
+const BACKEND_FAILURE_CODES: [u16; 5] = [1040, 1053, 1927, 2006, 2013];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+struct Breaker {
+    state: BreakerState,
+    window_start: Instant,
+    requests: u32,
+    failures: u32,
+    opened_at: Instant,
+    /// When the probe of a half-open breaker was let through.
+    probing: Option<Instant>,
+}
+
+impl Breaker {
+    fn new() -> Self {
+        Breaker {
+            state: BreakerState::Closed,
+            window_start: Instant::now(),
+            requests: 0,
+            failures: 0,
+            opened_at: Instant::now(),
+            probing: None,
+        }
+    }
+
+    fn open(&mut self) {
+        self.state = BreakerState::Open;
+        self.opened_at = Instant::now();
+        self.probing = None;
+    }
+
+    fn close(&mut self) {
+        self.state = BreakerState::Closed;
+        self.window_start = Instant::now();
+        self.requests = 0;
+        self.failures = 0;
+        self.probing = None;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BreakerStats {
+    backend: String,
+    state: BreakerState,
+    requests: u32,
+    failures: u32,
+}
+
+impl BreakerStats {
+    /// `host:port/db` of the backend.
+    pub fn get_backend(&self) -> String {
+        self.backend.clone()
+    }
+
+    pub fn get_state(&self) -> BreakerState {
+        self.state
+    }
+
+    /// Statements of the current window.
+    pub fn get_requests(&self) -> u32 {
+        self.requests
+    }
+
+    pub fn get_failures(&self) -> u32 {
+        self.failures
+    }
+}
+
+pub struct CircuitBreakers {
+    config: CircuitBreakerConfig,
+    breakers: Mutex<HashMap<String, Breaker>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        CircuitBreakers {
+            config,
+            breakers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn open_timeout(&self) -> Duration {
+        Duration::from_millis(self.config.get_open_timeout() as u64)
+    }
+
+    /// Refuses a statement for `backend` while its breaker is open.
+    pub fn admit(&self, backend: &str) -> Result<(), String> {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(backend.to_string()).or_insert_with(Breaker::new);
+        if breaker.state == BreakerState::Open && breaker.opened_at.elapsed() >= self.open_timeout() {
+            breaker.state = BreakerState::HalfOpen;
+        }
+        match breaker.state {
+            BreakerState::Closed => Ok(()),
+            // A probe that never reported back does not hold the breaker half-open for good.
+            BreakerState::HalfOpen if breaker.probing.map_or(true, |since| since.elapsed() >= self.open_timeout()) => {
+                breaker.probing = Some(Instant::now());
+                Ok(())
+            }
+            _ => {
+                let retry = self.open_timeout().checked_sub(breaker.opened_at.elapsed()).unwrap_or_default();
+                Err(format!("circuit open for backend {}, retry in {} ms", backend, retry.as_millis()))
+            }
+        }
+    }
+
+    /// Counts a statement of `backend` that `failed` or not.
+    pub fn record(&self, backend: &str, failed: bool) {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(backend.to_string()).or_insert_with(Breaker::new);
+        match breaker.state {
+            BreakerState::HalfOpen if failed => {
+                println!("Circuit of backend {} opened again, its probe failed", backend);
+                breaker.open();
+            }
+            BreakerState::HalfOpen => {
+                println!("Circuit of backend {} closed", backend);
+                breaker.close();
+            }
+            BreakerState::Open => {}
+            BreakerState::Closed => {
+                if breaker.window_start.elapsed() >= Duration::from_millis(self.config.get_window() as u64) {
+                    breaker.close();
+                }
+                breaker.requests += 1;
+                if failed {
+                    breaker.failures += 1;
+                }
+                if breaker.requests >= self.config.get_min_requests()
+                    && breaker.failures as f64 >= breaker.requests as f64 * self.config.get_error_rate() {
+                    println!("Circuit of backend {} opened, {} of {} statements failed", backend, breaker.failures, breaker.requests);
+                    breaker.open();
+                }
+            }
+        }
+    }
+
+    pub fn stats(&self) -> Vec<BreakerStats> {
+        self.breakers.lock().unwrap().iter()
+            .map(|(backend, breaker)| BreakerStats {
+                backend: backend.clone(),
+                state: breaker.state,
+                requests: breaker.requests,
+                failures: breaker.failures,
+            })
+            .collect()
+    }
+
+    fn is_failure<T>(&self, result: &mysql::Result<T>, elapsed: Duration) -> bool {
+        let failed = match result {
+            Ok(_) => false,
+            Err(mysql::Error::MySqlError(e)) => BACKEND_FAILURE_CODES.contains(&e.code),
+            Err(mysql::Error::UrlError(_)) => false,
+            Err(_) => true,
+        };
+        failed || (self.config.get_latency() > 0 && elapsed > Duration::from_millis(self.config.get_latency() as u64))
+    }
+}
+
+lazy_static! {
+    static ref CIRCUIT_BREAKERS: RwLock<Option<Arc<CircuitBreakers>>> = RwLock::new(None);
+}
+
+pub fn configure_circuit_breakers(config: &CircuitBreakerConfig) {
+    let breakers = if config.is_enabled() {
+        Some(Arc::new(CircuitBreakers::new(config.clone())))
+    } else {
+        None
+    };
+    *CIRCUIT_BREAKERS.write().unwrap() = breakers;
+}
+
+pub fn circuit_breakers() -> Option<Arc<CircuitBreakers>> {
+    CIRCUIT_BREAKERS.read().unwrap().clone()
+}
+
+/// Fails fast while the breaker of `database_url` is open.
+pub fn admit(database_url: &str) -> mysql::Result<()> {
+    match circuit_breakers() {
+        Some(breakers) => breakers.admit(&redact_url(database_url)).map_err(|message| mysql::Error::MySqlError(MySqlError {
+            state: "08004".to_string(),
+            message,
+            code: 1040,
+        })),
+        None => Ok(()),
+    }
+}
+
+/// Counts the outcome of a statement, or a connection attempt, on `database_url`.
+pub fn record<T>(database_url: &str, result: &mysql::Result<T>, elapsed: Duration) {
+    if let Some(breakers) = circuit_breakers() {
+        breakers.record(&redact_url(database_url), breakers.is_failure(result, elapsed));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use data_panel_common::config::config::CircuitBreakerConfig;
+
+    use super::{BreakerState, CircuitBreakers};
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breakers = CircuitBreakers::new(CircuitBreakerConfig::new(0.5, 4).open_timeout(50));
+        let backend = "localhost:3306/martlet";
+        for failed in [false, true, false, true].iter() {
+            assert!(breakers.admit(backend).is_ok());
+            breakers.record(backend, *failed);
+        }
+        assert_eq!(breakers.stats()[0].get_state(), BreakerState::Open);
+        assert!(breakers.admit(backend).is_err());
+
+        thread::sleep(Duration::from_millis(60));
+        assert!(breakers.admit(backend).is_ok());
+        // Only the probe gets through while half-open.
+        assert!(breakers.admit(backend).is_err());
+        breakers.record(backend, false);
+        assert_eq!(breakers.stats()[0].get_state(), BreakerState::Closed);
+        assert!(breakers.admit(backend).is_ok());
+    }
+}
@@ -0,0 +1,250 @@
+//! A replication client reading the binlog of a MySQL primary, as a replica would.
+//!
+//! It logs in with mysql_native_password or the fast path of caching_sha2_password, asks for
+//! the checksums the primary writes and dumps the binlog from a position on. The primary then
+//! sends event after event for as long as the connection lives.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::handler::database::mysql::auth::{scramble_caching_sha2_password, scramble_native_password};
+use crate::protocol::database::mysql::constant::{MySQLAuthenticationMethod, MySQLCapabilityFlag, MySQLCommandPacketType};
+
+const MAX_PAYLOAD_LEN: usize = 0xff_ffff;
+const EVENT_HEADER_LEN: usize = 19;
+const CHECKSUM_LEN: usize = 4;
+/// caching_sha2_password status asking for the password over TLS or RSA.
+const PERFORM_FULL_AUTHENTICATION: u8 = 0x04;
+
+pub const ROTATE_EVENT: u8 = 4;
+pub const QUERY_EVENT: u8 = 2;
+pub const FORMAT_DESCRIPTION_EVENT: u8 = 15;
+pub const XID_EVENT: u8 = 16;
+pub const TABLE_MAP_EVENT: u8 = 19;
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The message of an ERR packet.
+fn err_message(payload: &[u8]) -> String {
+    let code = payload.get(1..3).map_or(0, |code| u16::from_le_bytes([code[0], code[1]]));
+    // Skips the `#` marker and the SQL state.
+    let message = payload.get(9..).unwrap_or_default();
+    format!("{} ({})", String::from_utf8_lossy(message), code)
+}
+
+/// The NUL terminated string at the start of `bytes`, and the bytes after it.
+fn null_terminated(bytes: &[u8]) -> (&[u8], &[u8]) {
+    match bytes.iter().position(|byte| *byte == 0) {
+        Some(end) => (&bytes[..end], &bytes[end + 1..]),
+        None => (bytes, &[]),
+    }
+}
+
+/// The binlog a ROTATE event goes on in.
+pub fn rotated_file(body: &[u8]) -> Option<String> {
+    // The position in the new binlog comes first.
+    body.get(8..).map(|file| String::from_utf8_lossy(file).to_string())
+}
+
+/// The statement of a QUERY event, like `BEGIN`, `COMMIT` or a DDL statement.
+pub fn query_text(body: &[u8]) -> Option<String> {
+    // Thread id, execution time, schema length, error code, status variables length.
+    let schema_len = *body.get(8)? as usize;
+    let status_len = u16::from_le_bytes([*body.get(11)?, *body.get(12)?]) as usize;
+    body.get(13 + status_len + schema_len + 1..).map(|query| String::from_utf8_lossy(query).to_string())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventHeader {
+    pub timestamp: u32,
+    pub event_type: u8,
+    pub server_id: u32,
+    /// The position of the next event in the binlog.
+    pub log_pos: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BinlogEvent {
+    pub header: EventHeader,
+    /// The event after its header, without its checksum.
+    pub body: Vec<u8>,
+}
+
+pub struct BinlogClient {
+    stream: TcpStream,
+    sequence_id: u8,
+    /// Whether the primary writes checksums at all, asked for before the dump.
+    checksums: bool,
+    checksum: bool,
+}
+
+impl BinlogClient {
+    /// Logs in to `addr` as `user`.
+    pub fn connect(addr: &str, user: &str, password: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let mut client = BinlogClient {
+            stream,
+            sequence_id: 0,
+            checksums: false,
+            checksum: false,
+        };
+        client.login(user, password)?;
+        Ok(client)
+    }
+
+    fn read_packet(&mut self) -> io::Result<Vec<u8>> {
+        let mut payload = vec![];
+        loop {
+            let mut header = [0u8; 4];
+            self.stream.read_exact(&mut header)?;
+            let len = u32::from_le_bytes([header[0], header[1], header[2], 0]) as usize;
+            self.sequence_id = header[3].wrapping_add(1);
+            let start = payload.len();
+            payload.resize(start + len, 0);
+            self.stream.read_exact(&mut payload[start..])?;
+            // A payload of 2^24 - 1 bytes goes on in the next packet.
+            if len < MAX_PAYLOAD_LEN {
+                return Ok(payload);
+            }
+        }
+    }
+
+    fn write_packet(&mut self, payload: &[u8]) -> io::Result<()> {
+        let len = (payload.len() as u32).to_le_bytes();
+        let mut packet = vec![len[0], len[1], len[2], self.sequence_id];
+        packet.extend_from_slice(payload);
+        self.sequence_id = self.sequence_id.wrapping_add(1);
+        self.stream.write_all(&packet)
+    }
+
+    fn write_command(&mut self, command: MySQLCommandPacketType, payload: &[u8]) -> io::Result<()> {
+        self.sequence_id = 0;
+        let mut packet = vec![command as u8];
+        packet.extend_from_slice(payload);
+        self.write_packet(&packet)
+    }
+
+    fn scramble(method: &[u8], password: &str, scramble: &[u8]) -> Vec<u8> {
+        if method == MySQLAuthenticationMethod::CachingSha2Password.value().as_bytes() {
+            scramble_caching_sha2_password(password, scramble)
+        } else {
+            scramble_native_password(password, scramble)
+        }
+    }
+
+    fn login(&mut self, user: &str, password: &str) -> io::Result<()> {
+        let handshake = self.read_packet()?;
+        if handshake.first() == Some(&0xff) {
+            return Err(invalid_data(err_message(&handshake)));
+        }
+        // Protocol version, server version, connection id.
+        let (_, rest) = null_terminated(handshake.get(1..).unwrap_or_default());
+        let rest = rest.get(4..).ok_or_else(|| invalid_data("short handshake".to_string()))?;
+        let mut scramble = rest.get(..8).unwrap_or_default().to_vec();
+        // Filler, capabilities, charset, status, capabilities, auth data length, reserved.
+        let rest = rest.get(8 + 1 + 2 + 1 + 2 + 2 + 1 + 10..).unwrap_or_default();
+        let (scramble_tail, rest) = rest.split_at(rest.len().min(12));
+        scramble.extend_from_slice(scramble_tail);
+        let rest = rest.get(1..).unwrap_or_default();
+        let (method, _) = null_terminated(rest);
+        let method = if method.is_empty() { MySQLAuthenticationMethod::SecurePasswordAuthentication.value().as_bytes().to_vec() } else { method.to_vec() };
+
+        let capabilities = MySQLCapabilityFlag::CLIENT_LONG_PASSWORD
+            | MySQLCapabilityFlag::CLIENT_PROTOCOL_41
+            | MySQLCapabilityFlag::CLIENT_SECURE_CONNECTION
+            | MySQLCapabilityFlag::CLIENT_PLUGIN_AUTH;
+        let auth_response = Self::scramble(&method, password, &scramble);
+        let mut response = capabilities.bits().to_le_bytes().to_vec();
+        response.extend_from_slice(&(MAX_PAYLOAD_LEN as u32).to_le_bytes());
+        // utf8_general_ci
+        response.push(33);
+        response.extend_from_slice(&[0u8; 23]);
+        response.extend_from_slice(user.as_bytes());
+        response.push(0);
+        response.push(auth_response.len() as u8);
+        response.extend_from_slice(&auth_response);
+        response.extend_from_slice(&method);
+        response.push(0);
+        self.write_packet(&response)?;
+
+        loop {
+            let packet = self.read_packet()?;
+            match packet.first() {
+                Some(0x00) => return Ok(()),
+                Some(0xff) => return Err(invalid_data(err_message(&packet))),
+                // Auth switch request.
+                Some(0xfe) => {
+                    let (method, scramble) = null_terminated(&packet[1..]);
+                    let (scramble, _) = null_terminated(scramble);
+                    let auth_response = Self::scramble(method, password, scramble);
+                    self.write_packet(&auth_response)?;
+                }
+                Some(0x01) if packet.get(1) == Some(&PERFORM_FULL_AUTHENTICATION) => {
+                    return Err(invalid_data(format!("{} needs a full authentication, log in once through a client to cache it", user)));
+                }
+                // Fast auth success, the OK follows.
+                Some(0x01) => {}
+                _ => return Err(invalid_data("unexpected packet during login".to_string())),
+            }
+        }
+    }
+
+    /// Runs a statement answered with an OK packet.
+    fn execute(&mut self, sql: &str) -> io::Result<()> {
+        self.write_command(MySQLCommandPacketType::ComQuery, sql.as_bytes())?;
+        let packet = self.read_packet()?;
+        match packet.first() {
+            Some(0xff) => Err(invalid_data(err_message(&packet))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Asks the primary, as replica `server_id`, for the events of binlog `file` from `position` on.
+    pub fn dump(&mut self, server_id: u32, file: &str, position: u32) -> io::Result<()> {
+        // Primaries before 5.6 have no checksums, and events without one.
+        self.checksums = self.execute("SET @master_binlog_checksum = @@global.binlog_checksum").is_ok();
+        // Heartbeats every 30s, in nanoseconds, tell a quiet primary from a gone one.
+        self.execute("SET @master_heartbeat_period = 30000000000")?;
+        let mut payload = position.to_le_bytes().to_vec();
+        payload.extend_from_slice(&0u16.to_le_bytes());
+        payload.extend_from_slice(&server_id.to_le_bytes());
+        payload.extend_from_slice(file.as_bytes());
+        self.write_command(MySQLCommandPacketType::ComBinlogDump, &payload)?;
+        self.stream.set_read_timeout(Some(Duration::from_secs(90)))
+    }
+
+    /// The next event of the binlog, blocking until the primary writes one.
+    pub fn next_event(&mut self) -> io::Result<BinlogEvent> {
+        let packet = self.read_packet()?;
+        match packet.first() {
+            Some(0x00) if packet.len() > EVENT_HEADER_LEN => {}
+            Some(0xff) => return Err(invalid_data(err_message(&packet))),
+            Some(0xfe) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the primary ended the binlog dump")),
+            _ => return Err(invalid_data("unexpected binlog packet".to_string())),
+        }
+        let header = &packet[1..1 + EVENT_HEADER_LEN];
+        let u32_at = |offset: usize| u32::from_le_bytes([header[offset], header[offset + 1], header[offset + 2], header[offset + 3]]);
+        let header = EventHeader {
+            timestamp: u32_at(0),
+            event_type: header[4],
+            server_id: u32_at(5),
+            log_pos: u32_at(13),
+        };
+        let mut body = packet[1 + EVENT_HEADER_LEN..].to_vec();
+        if header.event_type == FORMAT_DESCRIPTION_EVENT {
+            // The checksum algorithm precedes the checksum of the event, 0 for none.
+            self.checksum = self.checksums && body.len() > CHECKSUM_LEN && body[body.len() - CHECKSUM_LEN - 1] != 0;
+        }
+        if self.checksum {
+            body.truncate(body.len().saturating_sub(CHECKSUM_LEN));
+        }
+        Ok(BinlogEvent {
+            header,
+            body,
+        })
+    }
+}
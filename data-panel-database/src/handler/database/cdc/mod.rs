@@ -0,0 +1,330 @@
+//! Change data capture: the row changes of the primary published as events, see `CdcConfig`.
+//!
+//! A background thread reads the binlog of the primary as a replica would, from the position
+//! kept in the position file or else from the current end of the binlog. The rows events of the
+//! captured tables become change events, published to Kafka or NATS a transaction at a time,
+//! then the position after the transaction is kept. The binlog must be in ROW format.
+//!
+//! Events are published at least once: the transactions after the last kept position are
+//! published again after a restart or a lost connection. Binlog events do not name columns,
+//! they are named after the columns the table has once its events are read, `@1`, `@2`, ...
+//! when their number differs.
+
+pub mod binlog;
+pub mod rows;
+pub mod sink;
+
+use std::collections::HashMap;
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mysql::{Conn, Opts, Row};
+use mysql::prelude::Queryable;
+use serde_json::{json, Value};
+
+use data_panel_common::config::config::{CdcConfig, MeshConfig};
+
+use crate::common::store;
+use crate::discovery;
+use crate::handler::database::cdc::binlog::{BinlogClient, QUERY_EVENT, ROTATE_EVENT, TABLE_MAP_EVENT, XID_EVENT};
+use crate::handler::database::cdc::rows::{DELETE_ROWS_EVENT, DELETE_ROWS_EVENT_V1, RowChange, TableMap, UPDATE_ROWS_EVENT, UPDATE_ROWS_EVENT_V1, WRITE_ROWS_EVENT, WRITE_ROWS_EVENT_V1};
+use crate::handler::database::cdc::sink::ChangeSink;
+use crate::handler::database::lifecycle::redact_url;
+use crate::handler::database::traffic;
+
+/// The wait before reading the binlog again after an error, or publishing a batch again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the position is kept while no captured table changes.
+const POSITION_INTERVAL: Duration = Duration::from_secs(1);
+/// The namespace of the positions in the metadata store, keyed by their position file.
+const STORE_NAMESPACE: &str = "cdc";
+
+const COLUMNS_SQL: &str = "SELECT COLUMN_NAME, COLUMN_TYPE FROM information_schema.COLUMNS \
+    WHERE TABLE_SCHEMA = ? AND TABLE_NAME = ? ORDER BY ORDINAL_POSITION";
+
+/// A row written, updated or deleted in a captured table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeEvent {
+    op: &'static str,
+    schema: String,
+    table: String,
+    columns: Vec<String>,
+    before: Option<Vec<Value>>,
+    after: Option<Vec<Value>>,
+    binlog_file: String,
+    binlog_pos: u32,
+    timestamp: u32,
+}
+
+impl ChangeEvent {
+    pub fn get_schema(&self) -> String {
+        self.schema.clone()
+    }
+
+    pub fn get_table(&self) -> String {
+        self.table.clone()
+    }
+
+    /// `<schema>.<table>`, the key of its Kafka records.
+    pub fn get_key(&self) -> String {
+        format!("{}.{}", self.schema, self.table)
+    }
+
+    fn row_json(&self, values: &Option<Vec<Value>>) -> Value {
+        match values {
+            Some(values) => Value::Object(self.columns.iter().cloned().zip(values.iter().cloned()).collect()),
+            None => Value::Null,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "op": self.op,
+            "schema": self.schema,
+            "table": self.table,
+            "before": self.row_json(&self.before),
+            "after": self.row_json(&self.after),
+            "binlog": format!("{}:{}", self.binlog_file, self.binlog_pos),
+            "timestamp": self.timestamp,
+        })
+    }
+
+    /// The name of the Avro record of the rows of the table, letters, digits and `_` only.
+    fn avro_row_name(&self) -> String {
+        avro_name(&format!("{}_{}_row", self.schema, self.table))
+    }
+
+    /// The Avro schema of the events of the table, its columns as nullable strings.
+    pub fn avro_schema(&self) -> Value {
+        let fields: Vec<Value> = self.columns.iter()
+            .map(|column| json!({ "name": avro_name(column), "type": ["null", "string"], "default": null }))
+            .collect();
+        json!({
+            "type": "record",
+            "name": avro_name(&format!("{}_{}", self.schema, self.table)),
+            "namespace": "martlet.cdc",
+            "fields": [
+                { "name": "op", "type": "string" },
+                { "name": "schema", "type": "string" },
+                { "name": "table", "type": "string" },
+                { "name": "before", "type": ["null", { "type": "record", "name": self.avro_row_name(), "fields": fields }], "default": null },
+                { "name": "after", "type": ["null", format!("martlet.cdc.{}", self.avro_row_name())], "default": null },
+                { "name": "binlog", "type": "string" },
+                { "name": "timestamp", "type": "long" },
+            ],
+        })
+    }
+
+    /// The event in the JSON encoding of Avro, which names the branch a union value takes.
+    fn avro_row_json(&self, values: &Option<Vec<Value>>) -> Value {
+        let values = match values {
+            Some(values) => values,
+            None => return Value::Null,
+        };
+        let row: serde_json::Map<String, Value> = self.columns.iter().zip(values.iter()).map(|(column, value)| {
+            let value = match value {
+                Value::Null => Value::Null,
+                Value::String(text) => json!({ "string": text }),
+                value => json!({ "string": value.to_string() }),
+            };
+            (avro_name(column), value)
+        }).collect();
+        let mut union = serde_json::Map::new();
+        union.insert(format!("martlet.cdc.{}", self.avro_row_name()), Value::Object(row));
+        Value::Object(union)
+    }
+
+    pub fn to_avro_json(&self) -> Value {
+        json!({
+            "op": self.op,
+            "schema": self.schema,
+            "table": self.table,
+            "before": self.avro_row_json(&self.before),
+            "after": self.avro_row_json(&self.after),
+            "binlog": format!("{}:{}", self.binlog_file, self.binlog_pos),
+            "timestamp": self.timestamp as i64,
+        })
+    }
+}
+
+fn avro_name(name: &str) -> String {
+    let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) { format!("_{}", name) } else { name }
+}
+
+/// The names of the columns of a table, and which of them are unsigned.
+struct TableColumns {
+    names: Vec<String>,
+    unsigned: Vec<bool>,
+}
+
+fn table_columns(conn: &mut Conn, schema: &str, table: &str) -> mysql::Result<TableColumns> {
+    let columns: Vec<(String, String)> = conn.exec(COLUMNS_SQL, (schema, table))?;
+    Ok(TableColumns {
+        names: columns.iter().map(|(name, _)| name.clone()).collect(),
+        unsigned: columns.iter().map(|(_, column_type)| column_type.to_lowercase().contains("unsigned")).collect(),
+    })
+}
+
+/// The binlog and position kept in `path`, or in the metadata store, `<file>:<position>`.
+fn read_position(path: &str) -> Option<(String, u32)> {
+    let position = match store::metadata_store() {
+        Some(store) => String::from_utf8(store.get(STORE_NAMESPACE, path).ok()??).ok()?,
+        None => fs::read_to_string(path).ok()?,
+    };
+    let position = position.trim();
+    let colon = position.rfind(':')?;
+    Some((position[..colon].to_string(), position[colon + 1..].parse().ok()?))
+}
+
+fn write_position(path: &str, file: &str, position: u32) {
+    let position = format!("{}:{}\n", file, position);
+    let kept = match store::metadata_store() {
+        Some(store) => store.put(STORE_NAMESPACE, path, position.as_bytes()),
+        None => fs::write(path, &position).map_err(|e| e.to_string()),
+    };
+    if let Err(e) = kept {
+        println!("error on keeping the cdc position of {}; error = {}", path, e);
+    }
+}
+
+/// The current end of the binlog.
+fn master_status(conn: &mut Conn) -> Result<(String, u32), String> {
+    let row: Row = conn.query_first("SHOW MASTER STATUS").map_err(|e| e.to_string())?
+        .ok_or_else(|| "binary logging is not enabled on the primary".to_string())?;
+    match (row.get::<String, _>(0), row.get::<u32, _>(1)) {
+        (Some(file), Some(position)) => Ok((file, position)),
+        _ => Err("unexpected SHOW MASTER STATUS".to_string()),
+    }
+}
+
+/// The primary whose binlog is read: that of the configured segment, or of the backend segment.
+fn source_url(config: &CdcConfig) -> String {
+    let segment = if config.get_segment().is_empty() { MeshConfig::get_backend_config().get_segment() } else { config.get_segment() };
+    discovery::database::segment_url(&traffic::primary_of(&segment)).unwrap_or_else(|| MeshConfig::get_backend_config().get_url())
+}
+
+/// Publishes `events`, again and again until the sink takes them.
+fn publish(sink: &mut dyn ChangeSink, events: &[ChangeEvent]) {
+    while let Err(e) = sink.publish(events) {
+        println!("error on publishing {} change events; error = {}", events.len(), e);
+        thread::sleep(RETRY_INTERVAL);
+    }
+}
+
+fn change_events(event_type: u8, changes: Vec<RowChange>, table_map: &TableMap, columns: &TableColumns, binlog_file: &str, binlog_pos: u32, timestamp: u32) -> Vec<ChangeEvent> {
+    let op = match event_type {
+        WRITE_ROWS_EVENT | WRITE_ROWS_EVENT_V1 => "insert",
+        UPDATE_ROWS_EVENT | UPDATE_ROWS_EVENT_V1 => "update",
+        _ => "delete",
+    };
+    let named = columns.names.len() == table_map.column_types.len();
+    let names: Vec<String> = if named { columns.names.clone() } else { (1..=table_map.column_types.len()).map(|index| format!("@{}", index)).collect() };
+    let unsigned = |values: Option<Vec<Value>>| values.map(|values| {
+        values.into_iter().enumerate().map(|(index, value)| {
+            if named && columns.unsigned[index] { rows::unsigned_value(value, table_map.column_types[index]) } else { value }
+        }).collect()
+    });
+    changes.into_iter().map(|change| ChangeEvent {
+        op,
+        schema: table_map.schema.clone(),
+        table: table_map.table.clone(),
+        columns: names.clone(),
+        before: unsigned(change.before),
+        after: unsigned(change.after),
+        binlog_file: binlog_file.to_string(),
+        binlog_pos,
+        timestamp,
+    }).collect()
+}
+
+/// Reads the binlog and publishes the changes of the captured tables until the connection fails.
+fn capture(config: &CdcConfig, sink: &mut dyn ChangeSink) -> Result<(), String> {
+    let url = source_url(config);
+    let opts = Opts::from_url(&url).map_err(|e| e.to_string())?;
+    let mut conn = Conn::new(opts.clone()).map_err(|e| e.to_string())?;
+    let position_file = config.get_position_file();
+    let (mut binlog_file, position) = match read_position(&position_file) {
+        Some(position) => position,
+        None => master_status(&mut conn)?,
+    };
+    let addr = format!("{}:{}", opts.get_ip_or_hostname().unwrap_or("127.0.0.1"), opts.get_tcp_port());
+    let mut client = BinlogClient::connect(&addr, opts.get_user().unwrap_or_default(), opts.get_pass().unwrap_or_default()).map_err(|e| e.to_string())?;
+    client.dump(config.get_server_id(), &binlog_file, position).map_err(|e| e.to_string())?;
+    println!("Capturing changes of {} from {}:{}", redact_url(&url), binlog_file, position);
+
+    let mut tables: HashMap<u64, TableMap> = HashMap::new();
+    let mut columns: HashMap<(String, String), TableColumns> = HashMap::new();
+    let mut pending: Vec<ChangeEvent> = vec![];
+    let mut kept_at = Instant::now();
+    loop {
+        let event = client.next_event().map_err(|e| e.to_string())?;
+        let header = &event.header;
+        match header.event_type {
+            ROTATE_EVENT => {
+                if let Some(file) = binlog::rotated_file(&event.body) {
+                    binlog_file = file;
+                }
+            }
+            TABLE_MAP_EVENT => {
+                if let Some(table_map) = TableMap::decode(&event.body) {
+                    if config.is_captured(&table_map.schema, &table_map.table) {
+                        tables.insert(table_map.table_id, table_map);
+                    }
+                }
+            }
+            WRITE_ROWS_EVENT | UPDATE_ROWS_EVENT | DELETE_ROWS_EVENT | WRITE_ROWS_EVENT_V1 | UPDATE_ROWS_EVENT_V1 | DELETE_ROWS_EVENT_V1 => {
+                let table_map = match rows::rows_table_id(&event.body).and_then(|table_id| tables.get(&table_id)) {
+                    Some(table_map) => table_map,
+                    None => continue,
+                };
+                let changes = rows::decode_rows(header.event_type, &event.body, table_map)
+                    .ok_or_else(|| format!("undecodable rows event of {}.{} at {}:{}", table_map.schema, table_map.table, binlog_file, header.log_pos))?;
+                let key = (table_map.schema.clone(), table_map.table.clone());
+                // Looked up again once the table has other columns.
+                if columns.get(&key).map_or(true, |columns| columns.names.len() != table_map.column_types.len()) {
+                    let table_columns = table_columns(&mut conn, &key.0, &key.1).map_err(|e| e.to_string())?;
+                    columns.insert(key.clone(), table_columns);
+                }
+                pending.extend(change_events(header.event_type, changes, table_map, &columns[&key], &binlog_file, header.log_pos, header.timestamp));
+            }
+            // The end of a transaction, or a statement outside of one.
+            XID_EVENT | QUERY_EVENT => {
+                if header.event_type == QUERY_EVENT && binlog::query_text(&event.body).map_or(false, |query| query.eq_ignore_ascii_case("BEGIN")) {
+                    continue;
+                }
+                if !pending.is_empty() {
+                    publish(sink, &pending);
+                    pending.clear();
+                } else if kept_at.elapsed() < POSITION_INTERVAL {
+                    continue;
+                }
+                write_position(&position_file, &binlog_file, header.log_pos);
+                kept_at = Instant::now();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Publishes the changes of the captured tables from a background thread, reading the binlog
+/// again from the last kept position whenever it fails.
+pub fn start(config: &CdcConfig) -> thread::JoinHandle<()> {
+    let config = config.clone();
+    thread::spawn(move || {
+        let mut sink = match sink::new_sink(&config) {
+            Ok(sink) => sink,
+            Err(e) => {
+                println!("error on starting change data capture; error = {}", e);
+                return;
+            }
+        };
+        loop {
+            if let Err(e) = capture(&config, sink.as_mut()) {
+                println!("error on capturing changes; error = {}", e);
+            }
+            thread::sleep(RETRY_INTERVAL);
+        }
+    })
+}
@@ -0,0 +1,524 @@
+//! Decoding the TABLE_MAP and rows events of a ROW format binlog.
+//!
+//! A TABLE_MAP event describes the columns of a table, by type and metadata but not by name,
+//! under a table id the rows events following it refer to. The values of a row are decoded to
+//! JSON: integers as numbers, DECIMAL, date and time types as strings like MySQL prints them,
+//! JSON columns as JSON and everything else as a lossy UTF-8 string.
+
+use serde_json::Value;
+
+const MYSQL_TYPE_TINY: u8 = 1;
+const MYSQL_TYPE_SHORT: u8 = 2;
+const MYSQL_TYPE_LONG: u8 = 3;
+const MYSQL_TYPE_FLOAT: u8 = 4;
+const MYSQL_TYPE_DOUBLE: u8 = 5;
+const MYSQL_TYPE_NULL: u8 = 6;
+const MYSQL_TYPE_TIMESTAMP: u8 = 7;
+const MYSQL_TYPE_LONGLONG: u8 = 8;
+const MYSQL_TYPE_INT24: u8 = 9;
+const MYSQL_TYPE_DATE: u8 = 10;
+const MYSQL_TYPE_TIME: u8 = 11;
+const MYSQL_TYPE_DATETIME: u8 = 12;
+const MYSQL_TYPE_YEAR: u8 = 13;
+const MYSQL_TYPE_VARCHAR: u8 = 15;
+const MYSQL_TYPE_BIT: u8 = 16;
+const MYSQL_TYPE_TIMESTAMP2: u8 = 17;
+const MYSQL_TYPE_DATETIME2: u8 = 18;
+const MYSQL_TYPE_TIME2: u8 = 19;
+const MYSQL_TYPE_JSON: u8 = 245;
+const MYSQL_TYPE_NEWDECIMAL: u8 = 246;
+const MYSQL_TYPE_ENUM: u8 = 247;
+const MYSQL_TYPE_SET: u8 = 248;
+const MYSQL_TYPE_TINY_BLOB: u8 = 249;
+const MYSQL_TYPE_MEDIUM_BLOB: u8 = 250;
+const MYSQL_TYPE_LONG_BLOB: u8 = 251;
+const MYSQL_TYPE_BLOB: u8 = 252;
+const MYSQL_TYPE_VAR_STRING: u8 = 253;
+const MYSQL_TYPE_STRING: u8 = 254;
+const MYSQL_TYPE_GEOMETRY: u8 = 255;
+
+pub const WRITE_ROWS_EVENT_V1: u8 = 23;
+pub const UPDATE_ROWS_EVENT_V1: u8 = 24;
+pub const DELETE_ROWS_EVENT_V1: u8 = 25;
+pub const WRITE_ROWS_EVENT: u8 = 30;
+pub const UPDATE_ROWS_EVENT: u8 = 31;
+pub const DELETE_ROWS_EVENT: u8 = 32;
+
+/// The digits of a DECIMAL stored in each of 0 to 9 leftover digits.
+const DIG2BYTES: [usize; 10] = [0, 1, 1, 2, 2, 3, 3, 4, 4, 4];
+const DIGITS_PER_INT: usize = 9;
+
+/// Reads the little-endian fields of an event.
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Cursor {
+            bytes,
+            offset: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.offset >= self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(self.offset..self.offset + len)?;
+        self.offset += len;
+        Some(bytes)
+    }
+
+    fn uint(&mut self, len: usize) -> Option<u64> {
+        Some(self.take(len)?.iter().rev().fold(0u64, |value, byte| value << 8 | *byte as u64))
+    }
+
+    fn uint_be(&mut self, len: usize) -> Option<u64> {
+        Some(self.take(len)?.iter().fold(0u64, |value, byte| value << 8 | *byte as u64))
+    }
+
+    fn lenenc(&mut self) -> Option<u64> {
+        match self.uint(1)? {
+            0xfc => self.uint(2),
+            0xfd => self.uint(3),
+            0xfe => self.uint(8),
+            len => Some(len),
+        }
+    }
+}
+
+fn is_set(bitmap: &[u8], index: usize) -> bool {
+    bitmap.get(index / 8).map_or(false, |byte| byte & (1 << (index % 8)) != 0)
+}
+
+/// The columns of a table, as a TABLE_MAP event describes them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableMap {
+    pub table_id: u64,
+    pub schema: String,
+    pub table: String,
+    pub column_types: Vec<u8>,
+    pub column_metadata: Vec<u16>,
+}
+
+impl TableMap {
+    pub fn decode(body: &[u8]) -> Option<TableMap> {
+        let mut cursor = Cursor::new(body);
+        let table_id = cursor.uint(6)?;
+        // Flags.
+        cursor.take(2)?;
+        let schema_len = cursor.uint(1)? as usize;
+        let schema = String::from_utf8_lossy(cursor.take(schema_len)?).to_string();
+        cursor.take(1)?;
+        let table_len = cursor.uint(1)? as usize;
+        let table = String::from_utf8_lossy(cursor.take(table_len)?).to_string();
+        cursor.take(1)?;
+        let column_count = cursor.lenenc()? as usize;
+        let column_types = cursor.take(column_count)?.to_vec();
+        cursor.lenenc()?;
+        let mut column_metadata = Vec::with_capacity(column_count);
+        for column_type in column_types.iter() {
+            let metadata = match *column_type {
+                MYSQL_TYPE_FLOAT | MYSQL_TYPE_DOUBLE | MYSQL_TYPE_BLOB | MYSQL_TYPE_GEOMETRY | MYSQL_TYPE_JSON
+                | MYSQL_TYPE_TIMESTAMP2 | MYSQL_TYPE_DATETIME2 | MYSQL_TYPE_TIME2 => cursor.uint(1)?,
+                MYSQL_TYPE_VARCHAR | MYSQL_TYPE_VAR_STRING | MYSQL_TYPE_BIT => cursor.uint(2)?,
+                // The real type and the length, the other way round.
+                MYSQL_TYPE_NEWDECIMAL | MYSQL_TYPE_STRING | MYSQL_TYPE_ENUM | MYSQL_TYPE_SET => cursor.uint_be(2)?,
+                _ => 0,
+            };
+            column_metadata.push(metadata as u16);
+        }
+        Some(TableMap {
+            table_id,
+            schema,
+            table,
+            column_types,
+            column_metadata,
+        })
+    }
+}
+
+/// A row written, updated or deleted: the values of its columns before and after the change.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowChange {
+    pub before: Option<Vec<Value>>,
+    pub after: Option<Vec<Value>>,
+}
+
+/// The table id a rows event refers to.
+pub fn rows_table_id(body: &[u8]) -> Option<u64> {
+    Cursor::new(body).uint(6)
+}
+
+/// The rows changed by a rows event of `event_type` on the table `table_map` describes.
+/// Columns the event leaves out, with a minimal binlog_row_image, are null.
+pub fn decode_rows(event_type: u8, body: &[u8], table_map: &TableMap) -> Option<Vec<RowChange>> {
+    let mut cursor = Cursor::new(body);
+    cursor.take(6 + 2)?;
+    if matches!(event_type, WRITE_ROWS_EVENT | UPDATE_ROWS_EVENT | DELETE_ROWS_EVENT) {
+        // The extra data, counting its own length.
+        let extra_len = cursor.uint(2)? as usize;
+        cursor.take(extra_len.checked_sub(2)?)?;
+    }
+    let column_count = cursor.lenenc()? as usize;
+    let bitmap_len = (column_count + 7) / 8;
+    let before_columns = cursor.take(bitmap_len)?;
+    let after_columns = match event_type {
+        UPDATE_ROWS_EVENT | UPDATE_ROWS_EVENT_V1 => cursor.take(bitmap_len)?,
+        _ => before_columns,
+    };
+    let mut changes = vec![];
+    while !cursor.is_empty() {
+        let change = match event_type {
+            WRITE_ROWS_EVENT | WRITE_ROWS_EVENT_V1 => RowChange {
+                before: None,
+                after: Some(decode_row(&mut cursor, before_columns, table_map)?),
+            },
+            DELETE_ROWS_EVENT | DELETE_ROWS_EVENT_V1 => RowChange {
+                before: Some(decode_row(&mut cursor, before_columns, table_map)?),
+                after: None,
+            },
+            UPDATE_ROWS_EVENT | UPDATE_ROWS_EVENT_V1 => RowChange {
+                before: Some(decode_row(&mut cursor, before_columns, table_map)?),
+                after: Some(decode_row(&mut cursor, after_columns, table_map)?),
+            },
+            _ => return None,
+        };
+        changes.push(change);
+    }
+    Some(changes)
+}
+
+fn decode_row(cursor: &mut Cursor, present: &[u8], table_map: &TableMap) -> Option<Vec<Value>> {
+    let column_count = table_map.column_types.len();
+    let present_count = (0..column_count).filter(|index| is_set(present, *index)).count();
+    let nulls = cursor.take((present_count + 7) / 8)?;
+    let mut values = Vec::with_capacity(column_count);
+    let mut present_index = 0;
+    let columns = table_map.column_types.iter().zip(table_map.column_metadata.iter());
+    for (index, (column_type, metadata)) in columns.enumerate() {
+        if !is_set(present, index) {
+            values.push(Value::Null);
+            continue;
+        }
+        let is_null = is_set(nulls, present_index);
+        present_index += 1;
+        if is_null {
+            values.push(Value::Null);
+        } else {
+            values.push(decode_value(cursor, *column_type, *metadata)?);
+        }
+    }
+    Some(values)
+}
+
+fn string_value(bytes: &[u8]) -> Value {
+    Value::String(String::from_utf8_lossy(bytes).to_string())
+}
+
+/// Integer values are signed, the schema knowing which columns are unsigned.
+fn decode_value(cursor: &mut Cursor, column_type: u8, metadata: u16) -> Option<Value> {
+    let value = match column_type {
+        MYSQL_TYPE_TINY => Value::from(cursor.uint(1)? as i8),
+        MYSQL_TYPE_SHORT => Value::from(cursor.uint(2)? as i16),
+        MYSQL_TYPE_INT24 => Value::from(((cursor.uint(3)? as i32) << 8) >> 8),
+        MYSQL_TYPE_LONG => Value::from(cursor.uint(4)? as i32),
+        MYSQL_TYPE_LONGLONG => Value::from(cursor.uint(8)? as i64),
+        MYSQL_TYPE_FLOAT => Value::from(f32::from_bits(cursor.uint(4)? as u32) as f64),
+        MYSQL_TYPE_DOUBLE => Value::from(f64::from_bits(cursor.uint(8)?)),
+        MYSQL_TYPE_NULL => Value::Null,
+        MYSQL_TYPE_YEAR => match cursor.uint(1)? {
+            0 => Value::from(0),
+            year => Value::from(1900 + year),
+        },
+        MYSQL_TYPE_NEWDECIMAL => Value::String(decode_decimal(cursor, (metadata >> 8) as usize, (metadata & 0xff) as usize)?),
+        MYSQL_TYPE_DATE => {
+            let date = cursor.uint(3)?;
+            Value::String(format!("{:04}-{:02}-{:02}", date >> 9, (date >> 5) & 0x0f, date & 0x1f))
+        }
+        MYSQL_TYPE_TIME => {
+            let time = cursor.uint(3)?;
+            Value::String(format!("{:02}:{:02}:{:02}", time / 10000, time / 100 % 100, time % 100))
+        }
+        MYSQL_TYPE_DATETIME => {
+            let datetime = cursor.uint(8)?;
+            let (date, time) = (datetime / 1_000_000, datetime % 1_000_000);
+            Value::String(format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", date / 10000, date / 100 % 100, date % 100, time / 10000, time / 100 % 100, time % 100))
+        }
+        MYSQL_TYPE_TIMESTAMP => Value::String(timestamp(cursor.uint(4)? as i64, "")),
+        MYSQL_TYPE_TIMESTAMP2 => {
+            let seconds = cursor.uint_be(4)? as i64;
+            Value::String(timestamp(seconds, &fraction(cursor, metadata)?))
+        }
+        MYSQL_TYPE_DATETIME2 => {
+            let packed = cursor.uint_be(5)?.wrapping_sub(0x80_0000_0000);
+            let (ymd, hms) = (packed >> 17, packed % (1 << 17));
+            let (year_month, day) = (ymd >> 5, ymd % (1 << 5));
+            Value::String(format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}{}",
+                                  year_month / 13, year_month % 13, day, hms >> 12, (hms >> 6) % (1 << 6), hms % (1 << 6), fraction(cursor, metadata)?))
+        }
+        MYSQL_TYPE_TIME2 => {
+            let packed = cursor.uint_be(3)? as i64 - 0x80_0000;
+            let (sign, hms) = if packed < 0 { ("-", -packed) } else { ("", packed) };
+            Value::String(format!("{}{:02}:{:02}:{:02}{}", sign, (hms >> 12) % (1 << 10), (hms >> 6) % (1 << 6), hms % (1 << 6), fraction(cursor, metadata)?))
+        }
+        MYSQL_TYPE_BIT => {
+            let bits = (metadata >> 8) as usize * 8 + (metadata & 0xff) as usize;
+            Value::from(cursor.uint_be((bits + 7) / 8)?)
+        }
+        MYSQL_TYPE_VARCHAR | MYSQL_TYPE_VAR_STRING => {
+            let len = cursor.uint(if metadata < 256 { 1 } else { 2 })? as usize;
+            string_value(cursor.take(len)?)
+        }
+        MYSQL_TYPE_STRING => {
+            let (real_type, len) = ((metadata >> 8) as u8, metadata & 0xff);
+            match real_type {
+                MYSQL_TYPE_ENUM | MYSQL_TYPE_SET => Value::from(cursor.uint(len as usize)?),
+                // CHAR longer than 255 bytes keeps the high bits of its length in the real type.
+                _ => {
+                    let max_len = ((((metadata >> 4) & 0x300) ^ 0x300) + len) as usize;
+                    let len = cursor.uint(if max_len < 256 { 1 } else { 2 })? as usize;
+                    string_value(cursor.take(len)?)
+                }
+            }
+        }
+        MYSQL_TYPE_ENUM | MYSQL_TYPE_SET => Value::from(cursor.uint((metadata & 0xff) as usize)?),
+        MYSQL_TYPE_BLOB | MYSQL_TYPE_TINY_BLOB | MYSQL_TYPE_MEDIUM_BLOB | MYSQL_TYPE_LONG_BLOB | MYSQL_TYPE_GEOMETRY => {
+            let len = cursor.uint(metadata as usize)? as usize;
+            string_value(cursor.take(len)?)
+        }
+        MYSQL_TYPE_JSON => {
+            let len = cursor.uint(metadata as usize)? as usize;
+            let bytes = cursor.take(len)?;
+            match bytes.split_first() {
+                Some((value_type, value)) => decode_json(*value_type, value, 0)?,
+                None => Value::Null,
+            }
+        }
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// The integer `value` of a column of `column_type` read as unsigned.
+pub fn unsigned_value(value: Value, column_type: u8) -> Value {
+    let signed = match value.as_i64() {
+        Some(signed) if signed < 0 => signed,
+        _ => return value,
+    };
+    match column_type {
+        MYSQL_TYPE_TINY => Value::from(signed as u8),
+        MYSQL_TYPE_SHORT => Value::from(signed as u16),
+        MYSQL_TYPE_INT24 => Value::from(signed as u32 & 0xff_ffff),
+        MYSQL_TYPE_LONG => Value::from(signed as u32),
+        MYSQL_TYPE_LONGLONG => Value::from(signed as u64),
+        _ => value,
+    }
+}
+
+/// The fractional seconds of a temporal type of `fsp` digits, `.` and 6 digits unless 0.
+fn fraction(cursor: &mut Cursor, fsp: u16) -> Option<String> {
+    let micros = match fsp {
+        1 | 2 => cursor.uint_be(1)? * 10000,
+        3 | 4 => cursor.uint_be(2)? * 100,
+        5 | 6 => cursor.uint_be(3)?,
+        _ => return Some(String::new()),
+    };
+    Some(format!(".{:06}", micros))
+}
+
+fn timestamp(seconds: i64, fraction: &str) -> String {
+    format!("{}{}", chrono::NaiveDateTime::from_timestamp(seconds, 0).format("%Y-%m-%d %H:%M:%S"), fraction)
+}
+
+/// A DECIMAL(`precision`, `scale`) in the binary format, groups of 9 digits in 4 big-endian
+/// bytes with the sign in the first bit, negative numbers having every bit inverted.
+fn decode_decimal(cursor: &mut Cursor, precision: usize, scale: usize) -> Option<String> {
+    let integral = precision.checked_sub(scale)?;
+    let group_lens = |digits: usize| {
+        let mut lens = vec![DIG2BYTES[digits % DIGITS_PER_INT]];
+        lens.extend(std::iter::repeat(4).take(digits / DIGITS_PER_INT));
+        lens
+    };
+    let integral_lens = group_lens(integral);
+    let mut fraction_lens = group_lens(scale);
+    // The leftover digits of the fraction come last.
+    fraction_lens.rotate_left(1);
+    let len = integral_lens.iter().sum::<usize>() + fraction_lens.iter().sum::<usize>();
+    let mut bytes = cursor.take(len)?.to_vec();
+    let negative = bytes.first().map_or(false, |byte| byte & 0x80 == 0);
+    bytes[0] ^= 0x80;
+    if negative {
+        bytes.iter_mut().for_each(|byte| *byte = !*byte);
+    }
+    let mut groups = Cursor::new(&bytes);
+    let mut integral_digits = String::new();
+    for group_len in integral_lens {
+        if group_len > 0 {
+            let group = groups.uint_be(group_len)?;
+            if integral_digits.is_empty() {
+                if group > 0 {
+                    integral_digits = group.to_string();
+                }
+            } else {
+                integral_digits.push_str(&format!("{:09}", group));
+            }
+        }
+    }
+    let mut fraction_digits = String::new();
+    let mut remaining = scale;
+    for group_len in fraction_lens {
+        if group_len > 0 {
+            let digits = remaining.min(DIGITS_PER_INT);
+            fraction_digits.push_str(&format!("{:0width$}", groups.uint_be(group_len)?, width = digits));
+            remaining -= digits;
+        }
+    }
+    let mut decimal = if negative { "-".to_string() } else { String::new() };
+    decimal.push_str(if integral_digits.is_empty() { "0" } else { &integral_digits });
+    if scale > 0 {
+        decimal.push('.');
+        decimal.push_str(&fraction_digits);
+    }
+    Some(decimal)
+}
+
+/// A value of the binary JSON format of MySQL.
+fn decode_json(value_type: u8, bytes: &[u8], depth: usize) -> Option<Value> {
+    // The nesting MySQL allows.
+    if depth > 100 {
+        return None;
+    }
+    let mut cursor = Cursor::new(bytes);
+    let value = match value_type {
+        0x00 => decode_json_container(bytes, true, false, depth)?,
+        0x01 => decode_json_container(bytes, true, true, depth)?,
+        0x02 => decode_json_container(bytes, false, false, depth)?,
+        0x03 => decode_json_container(bytes, false, true, depth)?,
+        0x04 => match cursor.uint(1)? {
+            0x00 => Value::Null,
+            0x01 => Value::Bool(true),
+            _ => Value::Bool(false),
+        },
+        0x05 => Value::from(cursor.uint(2)? as i16),
+        0x06 => Value::from(cursor.uint(2)? as u16),
+        0x07 => Value::from(cursor.uint(4)? as i32),
+        0x08 => Value::from(cursor.uint(4)? as u32),
+        0x09 => Value::from(cursor.uint(8)? as i64),
+        0x0a => Value::from(cursor.uint(8)?),
+        0x0b => Value::from(f64::from_bits(cursor.uint(8)?)),
+        0x0c => {
+            let len = json_data_len(&mut cursor)?;
+            string_value(cursor.take(len)?)
+        }
+        // Opaque values, like DECIMAL or DATETIME, keep their MySQL type first.
+        0x0f => {
+            cursor.take(1)?;
+            let len = json_data_len(&mut cursor)?;
+            string_value(cursor.take(len)?)
+        }
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// The length of a string in a JSON value, 7 bits per byte.
+fn json_data_len(cursor: &mut Cursor) -> Option<usize> {
+    let mut len = 0usize;
+    for shift in 0..5 {
+        let byte = cursor.uint(1)?;
+        len |= ((byte & 0x7f) as usize) << (7 * shift);
+        if byte & 0x80 == 0 {
+            return Some(len);
+        }
+    }
+    None
+}
+
+fn decode_json_container(bytes: &[u8], object: bool, large: bool, depth: usize) -> Option<Value> {
+    let offset_len = if large { 4 } else { 2 };
+    let mut cursor = Cursor::new(bytes);
+    let count = cursor.uint(offset_len)? as usize;
+    cursor.uint(offset_len)?;
+    let mut keys = Vec::with_capacity(count);
+    if object {
+        for _ in 0..count {
+            let key_offset = cursor.uint(offset_len)? as usize;
+            let key_len = cursor.uint(2)? as usize;
+            keys.push(String::from_utf8_lossy(bytes.get(key_offset..key_offset + key_len)?).to_string());
+        }
+    }
+    let mut values = Vec::with_capacity(count);
+    for _ in 0..count {
+        let value_type = cursor.uint(1)? as u8;
+        // Literals and small integers are inlined in the entry, the other values at an offset.
+        let value = match value_type {
+            0x04 | 0x05 | 0x06 => decode_json(value_type, cursor.take(offset_len)?, depth + 1)?,
+            0x07 | 0x08 if large => decode_json(value_type, cursor.take(offset_len)?, depth + 1)?,
+            _ => {
+                let value_offset = cursor.uint(offset_len)? as usize;
+                decode_json(value_type, bytes.get(value_offset..)?, depth + 1)?
+            }
+        };
+        values.push(value);
+    }
+    if object {
+        Some(Value::Object(keys.into_iter().zip(values.into_iter()).collect()))
+    } else {
+        Some(Value::Array(values))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{decode_rows, rows_table_id, TableMap, unsigned_value, UPDATE_ROWS_EVENT, WRITE_ROWS_EVENT};
+
+    #[test]
+    fn test_decode_rows() {
+        // shop.t_order (id BIGINT, status VARCHAR(32), amount DECIMAL(10, 2), created DATETIME(0))
+        let mut table_map = vec![0x2a, 0, 0, 0, 0, 0, 0x01, 0x00];
+        table_map.extend_from_slice(b"\x04shop\x00\x07t_order\x00");
+        table_map.extend_from_slice(&[4, 8, 15, 246, 18]);
+        table_map.extend_from_slice(&[5, 32, 0, 10, 2, 0]);
+        table_map.extend_from_slice(&[0x0e]);
+        let table_map = TableMap::decode(&table_map).unwrap();
+        assert_eq!((table_map.table_id, table_map.schema.as_str(), table_map.table.as_str()), (42, "shop", "t_order"));
+        assert_eq!(table_map.column_metadata, vec![0, 32, 10 << 8 | 2, 0]);
+
+        // INSERT (7, 'PAID', 12.50, '2021-03-04 05:06:07')
+        let mut row = vec![0x00];
+        row.extend_from_slice(&7i64.to_le_bytes());
+        row.extend_from_slice(b"\x04PAID");
+        row.extend_from_slice(&[0x80, 0, 0, 12, 50]);
+        let ymd: u64 = (2021 * 13 + 3) << 5 | 4;
+        let packed = (ymd << 17 | 5 << 12 | 6 << 6 | 7) + 0x80_0000_0000;
+        row.extend_from_slice(&packed.to_be_bytes()[3..]);
+        let mut write = vec![0x2a, 0, 0, 0, 0, 0, 0, 0, 2, 0, 4, 0x0f];
+        write.extend_from_slice(&row);
+        assert_eq!(rows_table_id(&write), Some(42));
+        let changes = decode_rows(WRITE_ROWS_EVENT, &write, &table_map).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].before, None);
+        assert_eq!(changes[0].after, Some(vec![json!(7), json!("PAID"), json!("12.50"), json!("2021-03-04 05:06:07")]));
+
+        // UPDATE ... SET status = NULL, amount = -3.05
+        let mut update = vec![0x2a, 0, 0, 0, 0, 0, 0, 0, 2, 0, 4, 0x0f, 0x0f];
+        update.extend_from_slice(&row);
+        let mut after = vec![0x02];
+        after.extend_from_slice(&7i64.to_le_bytes());
+        after.extend_from_slice(&[!0x80u8, 0xff, 0xff, !3, !5]);
+        after.extend_from_slice(&packed.to_be_bytes()[3..]);
+        update.extend_from_slice(&after);
+        let changes = decode_rows(UPDATE_ROWS_EVENT, &update, &table_map).unwrap();
+        assert_eq!(changes[0].before.as_ref().unwrap()[1], json!("PAID"));
+        assert_eq!(changes[0].after.as_ref().unwrap()[1..3].to_vec(), vec![json!(null), json!("-3.05")]);
+
+        assert_eq!(unsigned_value(json!(-1), 1), json!(255));
+        assert_eq!(unsigned_value(json!(-2), 8), json!(u64::MAX - 1));
+    }
+}
@@ -0,0 +1,151 @@
+//! Where change events are published: a Kafka topic through the Kafka REST proxy, or NATS.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use hyper::{Body, Client, Method, Request};
+
+use data_panel_common::config::config::CdcConfig;
+
+use crate::handler::database::cdc::ChangeEvent;
+
+pub trait ChangeSink: Send {
+    /// Publishes `events`, all of them or, on an error, possibly only some.
+    fn publish(&mut self, events: &[ChangeEvent]) -> Result<(), String>;
+}
+
+/// Batches posted to a topic through the Kafka REST proxy, keyed by table so the changes of a
+/// table keep their order. Avro batches are posted per table, with the schema of the table.
+pub struct KafkaChangeSink {
+    url: String,
+    avro: bool,
+    runtime: tokio::runtime::Runtime,
+}
+
+impl KafkaChangeSink {
+    pub fn new(url: &str, topic: &str, avro: bool) -> Result<Self, String> {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().map_err(|e| e.to_string())?;
+        Ok(KafkaChangeSink {
+            url: format!("{}/topics/{}", url.trim_end_matches('/'), topic),
+            avro,
+            runtime,
+        })
+    }
+
+    fn post(&self, content_type: &str, body: serde_json::Value) -> Result<(), String> {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.as_str())
+            .header("content-type", content_type)
+            .body(Body::from(body.to_string()))
+            .map_err(|e| e.to_string())?;
+        let response = self.runtime.block_on(async {
+            tokio::time::timeout(Duration::from_secs(5), Client::new().request(request)).await
+        });
+        match response {
+            Ok(Ok(response)) if response.status().is_success() => Ok(()),
+            Ok(Ok(response)) => Err(format!("the Kafka REST proxy answered {}", response.status())),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err("no answer from the Kafka REST proxy within 5s".to_string()),
+        }
+    }
+}
+
+impl ChangeSink for KafkaChangeSink {
+    fn publish(&mut self, events: &[ChangeEvent]) -> Result<(), String> {
+        if !self.avro {
+            let body = serde_json::json!({
+                "records": events.iter().map(|event| serde_json::json!({ "key": event.get_key(), "value": event.to_json() })).collect::<Vec<_>>(),
+            });
+            return self.post("application/vnd.kafka.json.v2+json", body);
+        }
+        let mut start = 0;
+        while start < events.len() {
+            let key = events[start].get_key();
+            let end = events[start..].iter().position(|event| event.get_key() != key).map_or(events.len(), |len| start + len);
+            let body = serde_json::json!({
+                "key_schema": "\"string\"",
+                "value_schema": events[start].avro_schema().to_string(),
+                "records": events[start..end].iter().map(|event| serde_json::json!({ "key": key, "value": event.to_avro_json() })).collect::<Vec<_>>(),
+            });
+            self.post("application/vnd.kafka.avro.v2+json", body)?;
+            start = end;
+        }
+        Ok(())
+    }
+}
+
+/// Events published as JSON on `<subject>.<schema>.<table>` over the text protocol of NATS,
+/// each batch confirmed by a PING answered once the server processed it.
+pub struct NatsSink {
+    addr: String,
+    subject: String,
+    conn: Option<(TcpStream, BufReader<TcpStream>)>,
+}
+
+impl NatsSink {
+    pub fn new(addr: &str, subject: &str) -> Self {
+        NatsSink {
+            addr: addr.to_string(),
+            subject: subject.to_string(),
+            conn: None,
+        }
+    }
+
+    fn connect(&self) -> io::Result<(TcpStream, BufReader<TcpStream>)> {
+        let mut stream = TcpStream::connect(self.addr.as_str())?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        // The server greets with its INFO.
+        let mut info = String::new();
+        reader.read_line(&mut info)?;
+        stream.write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"martlet-cdc\"}\r\n")?;
+        Ok((stream, reader))
+    }
+
+    fn publish_on(stream: &mut TcpStream, reader: &mut BufReader<TcpStream>, subject: &str, events: &[ChangeEvent]) -> io::Result<()> {
+        let mut batch = vec![];
+        for event in events {
+            let payload = event.to_json().to_string();
+            batch.extend_from_slice(format!("PUB {}.{}.{} {}\r\n", subject, event.get_schema(), event.get_table(), payload.len()).as_bytes());
+            batch.extend_from_slice(payload.as_bytes());
+            batch.extend_from_slice(b"\r\n");
+        }
+        batch.extend_from_slice(b"PING\r\n");
+        stream.write_all(&batch)?;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the NATS server closed the connection"));
+            }
+            match line.trim_end() {
+                "PONG" => return Ok(()),
+                "PING" => stream.write_all(b"PONG\r\n")?,
+                line if line.starts_with("-ERR") => return Err(io::Error::new(io::ErrorKind::Other, line.to_string())),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl ChangeSink for NatsSink {
+    fn publish(&mut self, events: &[ChangeEvent]) -> Result<(), String> {
+        let (mut stream, mut reader) = match self.conn.take() {
+            Some(conn) => conn,
+            None => self.connect().map_err(|e| e.to_string())?,
+        };
+        Self::publish_on(&mut stream, &mut reader, &self.subject, events).map_err(|e| e.to_string())?;
+        // A connection that failed is dropped, the next batch connects again.
+        self.conn = Some((stream, reader));
+        Ok(())
+    }
+}
+
+pub fn new_sink(config: &CdcConfig) -> Result<Box<dyn ChangeSink>, String> {
+    match config.get_sink().as_str() {
+        "kafka" => Ok(Box::new(KafkaChangeSink::new(&config.get_kafka_url(), &config.get_kafka_topic(), config.get_format() == "avro")?)),
+        "nats" => Ok(Box::new(NatsSink::new(&config.get_nats_addr(), &config.get_nats_subject()))),
+        sink => Err(format!("unknown cdc sink {}, expected kafka or nats", sink)),
+    }
+}
@@ -0,0 +1,195 @@
+//! Concurrent statement limits of the backend segments.
+//!
+//! A statement takes a permit of its segment before it runs on the backend and gives it back
+//! once its results were read, at most the segment's limit of permits being out at a time.
+//! Statements over the limit wait in arrival order for a permit, up to the queue timeout. Once
+//! the queue of a segment is full, statements for it fail at once instead of piling up behind a
+//! backend that cannot keep up, e.g. with the sub-queries of a fan-out.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use mysql::MySqlError;
+
+use data_panel_common::config::config::SegmentConcurrencyConfig;
+
+use crate::discovery::database::segment_name;
+use crate::handler::database::lifecycle::redact_url;
+
+#[derive(Default)]
+struct SegmentQueue {
+    running: usize,
+    /// The tickets of the waiting statements, in arrival order.
+    waiting: VecDeque<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SegmentConcurrencyStats {
+    segment: String,
+    running: usize,
+    queued: usize,
+}
+
+impl SegmentConcurrencyStats {
+    pub fn get_segment(&self) -> String {
+        self.segment.clone()
+    }
+
+    pub fn get_running(&self) -> usize {
+        self.running
+    }
+
+    pub fn get_queued(&self) -> usize {
+        self.queued
+    }
+}
+
+pub struct SegmentLimiter {
+    config: SegmentConcurrencyConfig,
+    queues: Mutex<HashMap<String, SegmentQueue>>,
+    tickets: AtomicU64,
+    released: Condvar,
+}
+
+impl SegmentLimiter {
+    pub fn new(config: SegmentConcurrencyConfig) -> Self {
+        SegmentLimiter {
+            config,
+            queues: Mutex::new(HashMap::new()),
+            tickets: AtomicU64::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    fn refused(message: String) -> mysql::Error {
+        mysql::Error::MySqlError(MySqlError {
+            state: "08004".to_string(),
+            message,
+            code: 1040,
+        })
+    }
+
+    /// Waits up to the queue timeout for a permit to run a statement on `segment`, failing at
+    /// once when its queue is full.
+    pub fn acquire(limiter: &Arc<SegmentLimiter>, segment: &str) -> mysql::Result<SegmentPermit> {
+        let max_concurrent = limiter.config.max_concurrent(segment);
+        let ticket = limiter.tickets.fetch_add(1, Ordering::Relaxed);
+        let deadline = Instant::now() + Duration::from_millis(limiter.config.get_queue_timeout() as u64);
+        let mut queues = limiter.queues.lock().unwrap();
+        let queue = queues.entry(segment.to_string()).or_default();
+        if queue.running >= max_concurrent && queue.waiting.len() >= limiter.config.get_max_queued() {
+            return Err(SegmentLimiter::refused(format!("too many statements waiting for segment {}, {} running and {} queued", segment, queue.running, queue.waiting.len())));
+        }
+        queue.waiting.push_back(ticket);
+        loop {
+            let queue = queues.get_mut(segment).unwrap();
+            if queue.waiting.front() == Some(&ticket) && queue.running < max_concurrent {
+                queue.waiting.pop_front();
+                queue.running += 1;
+                // The statement queued behind may fit in as well.
+                limiter.released.notify_all();
+                return Ok(SegmentPermit {
+                    limiter: limiter.clone(),
+                    segment: segment.to_string(),
+                });
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                queue.waiting.retain(|waiting| *waiting != ticket);
+                limiter.released.notify_all();
+                return Err(SegmentLimiter::refused(format!("no capacity on segment {} within {} ms", segment, limiter.config.get_queue_timeout())));
+            }
+            queues = limiter.released.wait_timeout(queues, deadline - now).unwrap().0;
+        }
+    }
+
+    fn release(&self, segment: &str) {
+        let mut queues = self.queues.lock().unwrap();
+        if let Some(queue) = queues.get_mut(segment) {
+            queue.running -= 1;
+        }
+        self.released.notify_all();
+    }
+
+    pub fn stats(&self) -> Vec<SegmentConcurrencyStats> {
+        let queues = self.queues.lock().unwrap();
+        let mut stats: Vec<SegmentConcurrencyStats> = queues.iter()
+            .map(|(segment, queue)| SegmentConcurrencyStats {
+                segment: segment.clone(),
+                running: queue.running,
+                queued: queue.waiting.len(),
+            })
+            .collect();
+        stats.sort_by(|a, b| a.segment.cmp(&b.segment));
+        stats
+    }
+}
+
+/// A permit to run a statement on a segment, given back on drop.
+pub struct SegmentPermit {
+    limiter: Arc<SegmentLimiter>,
+    segment: String,
+}
+
+impl Drop for SegmentPermit {
+    fn drop(&mut self) {
+        self.limiter.release(&self.segment);
+    }
+}
+
+lazy_static! {
+    static ref SEGMENT_LIMITER: RwLock<Option<Arc<SegmentLimiter>>> = RwLock::new(None);
+}
+
+/// Installs the limiter described by `config`, or removes it when disabled.
+pub fn configure_segment_limiter(config: &SegmentConcurrencyConfig) {
+    let limiter = if config.is_enabled() {
+        Some(Arc::new(SegmentLimiter::new(config.clone())))
+    } else {
+        None
+    };
+    *SEGMENT_LIMITER.write().unwrap() = limiter;
+}
+
+pub fn segment_limiter() -> Option<Arc<SegmentLimiter>> {
+    SEGMENT_LIMITER.read().unwrap().clone()
+}
+
+/// A permit for a statement on `database_url`, limited as its segment under the current
+/// rules, or as its backend without rules. None when no limiter is installed.
+pub fn permit(database_url: &str) -> mysql::Result<Option<SegmentPermit>> {
+    match segment_limiter() {
+        Some(limiter) => {
+            let segment = segment_name(database_url).unwrap_or_else(|| redact_url(database_url));
+            SegmentLimiter::acquire(&limiter, &segment).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use data_panel_common::config::config::{SegmentConcurrency, SegmentConcurrencyConfig};
+
+    use super::SegmentLimiter;
+
+    #[test]
+    fn test_segment_limits() {
+        let config = SegmentConcurrencyConfig::new(2, vec![SegmentConcurrency::new("data-100/primary", 1)])
+            .max_queued(1)
+            .queue_timeout(10);
+        let limiter = Arc::new(SegmentLimiter::new(config));
+        let permit = SegmentLimiter::acquire(&limiter, "data-100/primary").unwrap();
+        assert!(SegmentLimiter::acquire(&limiter, "data-100/primary").is_err());
+        let others = (SegmentLimiter::acquire(&limiter, "data-200/primary").unwrap(), SegmentLimiter::acquire(&limiter, "data-200/primary").unwrap());
+        assert_eq!(limiter.stats().iter().map(|stats| stats.get_running()).collect::<Vec<_>>(), vec![1, 2]);
+        drop(permit);
+        assert!(SegmentLimiter::acquire(&limiter, "data-100/primary").is_ok());
+        drop(others);
+        assert_eq!(limiter.stats().iter().map(|stats| stats.get_queued() + stats.get_running()).sum::<usize>(), 0);
+    }
+}
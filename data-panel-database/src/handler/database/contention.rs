@@ -0,0 +1,215 @@
+//! Deadlocks and lock wait timeouts, with the statements that likely took part in them.
+//!
+//! When a backend fails a statement on a deadlock (1213) or a lock wait timeout (1205), the
+//! mesh records the fingerprints of the statement and of the open transaction of its session,
+//! and of the other sessions on the same backend that run a statement or hold a transaction on
+//! one of its tables: the likely holders of the locks it waited for. The events are logged and
+//! the recent ones kept for `GET /contention` of the admin API. Fingerprints hold no values.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+
+use crate::handler::database::parser::sql::{mysql, statement_tables};
+use crate::handler::database::statement_stats::statement_fingerprint;
+use crate::session::activity::{session_activities, session_activity, SessionActivitySnapshot};
+
+pub const ER_LOCK_WAIT_TIMEOUT: u16 = 1205;
+pub const ER_LOCK_DEADLOCK: u16 = 1213;
+
+const CONTENTION_EVENTS_KEPT: usize = 64;
+
+/// Whether a backend error `code` is a lock conflict.
+pub fn is_lock_conflict(code: u16) -> bool {
+    code == ER_LOCK_DEADLOCK || code == ER_LOCK_WAIT_TIMEOUT
+}
+
+/// A session taking part in a lock conflict, by the fingerprints of its statements.
+#[derive(Debug, Clone)]
+pub struct ContentionSession {
+    session_id: u64,
+    user: String,
+    /// The statement it was running.
+    statement: Option<String>,
+    /// The statements of its open transaction, oldest first.
+    transaction: Vec<String>,
+}
+
+impl ContentionSession {
+    fn new(activity: &SessionActivitySnapshot) -> Self {
+        ContentionSession {
+            session_id: activity.get_session_id(),
+            user: activity.get_details().get_user(),
+            statement: activity.get_query().map(|sql| statement_fingerprint(sql)),
+            transaction: activity.get_transaction_statements().iter().map(|sql| statement_fingerprint(sql)).collect(),
+        }
+    }
+
+    pub fn get_session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    pub fn get_statement(&self) -> Option<&String> {
+        self.statement.as_ref()
+    }
+
+    pub fn get_transaction(&self) -> &Vec<String> {
+        &self.transaction
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "session_id": self.session_id,
+            "user": self.user,
+            "statement": self.statement,
+            "transaction": self.transaction,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ContentionEvent {
+    code: u16,
+    /// Seconds since the epoch.
+    at: u64,
+    backend: String,
+    session: ContentionSession,
+    /// The other sessions that likely hold the locks.
+    conflicting: Vec<ContentionSession>,
+}
+
+impl ContentionEvent {
+    pub fn get_code(&self) -> u16 {
+        self.code
+    }
+
+    pub fn get_session(&self) -> &ContentionSession {
+        &self.session
+    }
+
+    pub fn get_conflicting(&self) -> &Vec<ContentionSession> {
+        &self.conflicting
+    }
+
+    fn kind(&self) -> &'static str {
+        if self.code == ER_LOCK_DEADLOCK { "deadlock" } else { "lock wait timeout" }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "code": self.code,
+            "kind": self.kind(),
+            "at": self.at,
+            "backend": self.backend,
+            "session": self.session.to_json(),
+            "conflicting": self.conflicting.iter().map(|session| session.to_json()).collect::<Vec<serde_json::Value>>(),
+        })
+    }
+}
+
+/// The tables `statements` read or write, the ones that do not parse left out.
+fn tables<'a>(statements: impl Iterator<Item = &'a String>) -> HashSet<String> {
+    statements
+        .filter_map(|sql| mysql::parser(sql.to_string()).ok())
+        .flatten()
+        .flat_map(|statement| statement_tables(&statement))
+        .collect()
+}
+
+fn statements(activity: &SessionActivitySnapshot) -> impl Iterator<Item = &String> {
+    activity.get_query().into_iter().chain(activity.get_transaction_statements().iter())
+}
+
+/// Whether `other` may hold the locks `victim` waited for: on the same backend, running a
+/// statement or in a transaction, on one of `victim_tables` when they are known.
+fn is_conflicting(victim: &SessionActivitySnapshot, victim_tables: &HashSet<String>, other: &SessionActivitySnapshot) -> bool {
+    if other.get_session_id() == victim.get_session_id() || other.get_details().get_backend() != victim.get_details().get_backend() {
+        return false;
+    }
+    if statements(other).next().is_none() {
+        return false;
+    }
+    victim_tables.is_empty() || !tables(statements(other)).is_disjoint(victim_tables)
+}
+
+/// The lock conflict `code` of `victim`, among the open `sessions`.
+pub fn contention_event(code: u16, victim: &SessionActivitySnapshot, sessions: &[SessionActivitySnapshot]) -> ContentionEvent {
+    let victim_tables = tables(statements(victim));
+    let mut conflicting: Vec<ContentionSession> = sessions.iter()
+        .filter(|other| is_conflicting(victim, &victim_tables, other))
+        .map(ContentionSession::new)
+        .collect();
+    conflicting.sort_by_key(|session| session.session_id);
+    ContentionEvent {
+        code,
+        at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        backend: victim.get_details().get_backend(),
+        session: ContentionSession::new(victim),
+        conflicting,
+    }
+}
+
+lazy_static! {
+    static ref CONTENTION_EVENTS: Mutex<VecDeque<ContentionEvent>> = Mutex::new(VecDeque::new());
+}
+
+/// Records the lock conflict `code` the running statement of session `session_id` failed on.
+pub fn record(session_id: u64, code: u16) {
+    let victim = match session_activity(session_id) {
+        Some(victim) => victim,
+        None => return,
+    };
+    let event = contention_event(code, &victim, &session_activities());
+    let conflicting: Vec<u64> = event.conflicting.iter().map(|session| session.session_id).collect();
+    println!("{} on {} of session {} running {}; transaction = {:?}, conflicting sessions = {:?}",
+             event.kind(), event.backend, session_id, event.session.statement.as_deref().unwrap_or(""), event.session.transaction, conflicting);
+    let mut events = CONTENTION_EVENTS.lock().unwrap();
+    events.push_back(event);
+    while events.len() > CONTENTION_EVENTS_KEPT {
+        events.pop_front();
+    }
+}
+
+/// The recent lock conflicts, oldest first.
+pub fn contention_events() -> Vec<ContentionEvent> {
+    CONTENTION_EVENTS.lock().unwrap().iter().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handler::database::statement_stats::statement_fingerprint;
+    use crate::session::activity::{register_session_activity, session_activities, session_activity, SessionDetails};
+
+    use super::{contention_event, ER_LOCK_DEADLOCK, is_lock_conflict};
+
+    #[test]
+    fn test_contention_event() {
+        assert!(is_lock_conflict(1205) && is_lock_conflict(1213) && !is_lock_conflict(1062));
+        let session = |id: u64, user: &str, backend: &str, transaction: &[&str], running: Option<&str>| {
+            let guard = register_session_activity(id, "10.0.0.7:40000".parse().unwrap());
+            guard.describe(SessionDetails::new(user.to_string(), "sales".to_string(), backend.to_string(), vec![]));
+            for sql in transaction {
+                guard.run(Some(sql.to_string()));
+                guard.finish(true);
+            }
+            guard.run(running.map(|sql| sql.to_string()));
+            guard
+        };
+        let _victim = session(9101, "alice", "10.0.0.9:3306/sales", &["UPDATE t_order SET status = 1 WHERE id = 7"], Some("UPDATE t_user SET k = 2 WHERE id = 3"));
+        let holder = session(9102, "bob", "10.0.0.9:3306/sales", &["UPDATE t_user SET k = 1 WHERE id = 3"], Some("UPDATE t_order SET status = 2 WHERE id = 7"));
+        let _other_table = session(9103, "carol", "10.0.0.9:3306/sales", &["UPDATE t_item SET price = 1 WHERE id = 1"], None);
+        let _other_backend = session(9104, "dave", "10.0.0.8:3306/sales", &["UPDATE t_user SET k = 3 WHERE id = 3"], None);
+
+        let event = contention_event(ER_LOCK_DEADLOCK, &session_activity(9101).unwrap(), &session_activities());
+        assert_eq!(event.get_session().get_statement(), Some(&statement_fingerprint("UPDATE t_user SET k = 2 WHERE id = 3")));
+        assert_eq!(event.get_session().get_transaction(), &vec![statement_fingerprint("UPDATE t_order SET status = 1 WHERE id = 7")]);
+        let conflicting: Vec<u64> = event.get_conflicting().iter().map(|session| session.get_session_id()).collect();
+        assert_eq!(conflicting, vec![9102]);
+        assert_eq!(event.to_json()["kind"], "deadlock");
+
+        holder.finish(false);
+        assert!(session_activity(9102).unwrap().get_transaction_statements().is_empty());
+    }
+}
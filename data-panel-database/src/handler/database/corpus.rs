@@ -0,0 +1,153 @@
+//! Sampled query corpus.
+//!
+//! A fraction of the incoming statements is fingerprinted (literals replaced by `?`, whitespace
+//! and comments collapsed) and counted per time window. Every finished window is appended to
+//! the corpus file as one JSON line, the running window can be exported through the admin API.
+
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sqlparser::tokenizer::{Token, Tokenizer};
+
+use data_panel_common::config::config::{CorpusConfig, MeshConfig};
+
+use crate::discovery::database::rules::current_rules_version;
+use crate::handler::database::parser::sql::fingerprint;
+use crate::handler::database::parser::sql::mysql::MySQLDialect;
+
+/// `sql` with every literal replaced by `?`, or None if it does not tokenize.
+pub fn normalize(sql: &str) -> Option<String> {
+    let dialect = MySQLDialect {};
+    let tokens = Tokenizer::new(&dialect, sql).tokenize().ok()?;
+    Some(normalize_tokens(&tokens))
+}
+
+/// Like `normalize`, for a statement already tokenized.
+pub fn normalize_tokens(tokens: &[Token]) -> String {
+    let mut normalized = String::new();
+    for token in tokens {
+        match token {
+            Token::Number(_, _)
+            | Token::SingleQuotedString(_)
+            | Token::NationalStringLiteral(_)
+            | Token::HexStringLiteral(_) => normalized.push('?'),
+            Token::Whitespace(_) => {
+                if !normalized.is_empty() && !normalized.ends_with(' ') {
+                    normalized.push(' ');
+                }
+            }
+            token => normalized.push_str(&token.to_string()),
+        }
+    }
+    normalized.trim_end().to_string()
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+struct CorpusWindow {
+    started: u64,
+    counts: HashMap<String, u64>,
+}
+
+impl CorpusWindow {
+    fn new() -> Self {
+        CorpusWindow {
+            started: now_secs(),
+            counts: HashMap::new(),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut statements: Vec<(&String, &u64)> = self.counts.iter().collect();
+        statements.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        serde_json::json!({
+            "window_start": self.started,
+            "window_end": now_secs(),
+            "rules_version": current_rules_version(),
+            "statements": statements.iter()
+                .map(|(statement, count)| serde_json::json!({ "statement": statement, "count": count }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+pub struct QueryCorpus {
+    window: Mutex<CorpusWindow>,
+}
+
+impl QueryCorpus {
+    fn new() -> Self {
+        QueryCorpus {
+            window: Mutex::new(CorpusWindow::new()),
+        }
+    }
+
+    /// Counts `sql` if it is picked by the sample rate.
+    pub fn sample(&self, sql: &str, config: &CorpusConfig) {
+        if !config.is_enabled() || rand::random::<f64>() >= config.get_sample_rate() {
+            return;
+        }
+        let statement = match fingerprint(sql).or_else(|| normalize(sql)) {
+            Some(statement) => statement,
+            None => return,
+        };
+        let mut window = self.window.lock().unwrap();
+        if now_secs().saturating_sub(window.started) >= config.get_window() as u64 {
+            let finished = std::mem::replace(&mut *window, CorpusWindow::new());
+            append_window(config.get_path(), &finished);
+        }
+        *window.counts.entry(statement).or_insert(0) += 1;
+    }
+
+    /// The running window, as written to the corpus file.
+    pub fn export(&self) -> serde_json::Value {
+        self.window.lock().unwrap().to_json()
+    }
+
+    /// Writes the running window out early and starts a new one.
+    pub fn flush(&self, config: &CorpusConfig) {
+        let finished = std::mem::replace(&mut *self.window.lock().unwrap(), CorpusWindow::new());
+        append_window(config.get_path(), &finished);
+    }
+}
+
+fn append_window(path: String, window: &CorpusWindow) {
+    if window.counts.is_empty() {
+        return;
+    }
+    let written = OpenOptions::new().create(true).append(true).open(&path)
+        .and_then(|mut file| writeln!(file, "{}", window.to_json()));
+    if let Err(e) = written {
+        println!("error on writing query corpus to {}; error = {:?}", path, e);
+    }
+}
+
+lazy_static! {
+    static ref QUERY_CORPUS: QueryCorpus = QueryCorpus::new();
+}
+
+pub fn query_corpus() -> &'static QueryCorpus {
+    &QUERY_CORPUS
+}
+
+pub fn sample(sql: &str) {
+    QUERY_CORPUS.sample(sql, &MeshConfig::get_corpus_config());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handler::database::corpus::normalize;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("SELECT * FROM t_order  WHERE user_id = 10 AND status = 'PAID'").unwrap(),
+                   "SELECT * FROM t_order WHERE user_id = ? AND status = ?");
+        assert_eq!(normalize("INSERT INTO t_order (id, name) VALUES (1, 'a')").unwrap(),
+                   normalize("INSERT INTO t_order (id, name) VALUES (2, 'b')").unwrap());
+    }
+}
@@ -0,0 +1,301 @@
+//! CREATE, ALTER and DROP TABLE of distributed tables, run on every data segment.
+//!
+//! The statement goes to the primary of every data segment of the table, one after the other,
+//! under the name the table has there. DDL commits on its own, so a segment failing does not
+//! undo the segments that applied it: the client is told which segments applied the statement
+//! and which failed and why, to run it again on those. Once the statement is applied
+//! everywhere, the columns of the table are read back from every segment, a segment that does
+//! not agree with the first one is reported as a warning, and the columns of the first one go
+//! to the schema cache, see `table_columns`.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use bytes::Bytes;
+use mysql::prelude::Queryable;
+use sqlparser::ast::{ObjectType, Statement};
+
+use data_panel_common::common::Error;
+
+use crate::discovery;
+use crate::discovery::database::rules::{current_rules, RulesVersion, TableRoute};
+use crate::handler::database::{information_schema, variables};
+use crate::handler::database::lifecycle::BackendConn;
+use crate::handler::database::mysql::rdbc::err_payload;
+use crate::handler::database::parser::sql::{rewrite_statement, unquoted_table};
+use crate::protocol::database::DatabasePacket;
+use crate::protocol::database::mysql::packet::{MySQLOKPacket, MySQLPacketPayload};
+use crate::session::mysql::SessionContext;
+
+/// The columns of a table, as `(name, type)` in their order.
+pub type TableColumns = Vec<(String, String)>;
+
+lazy_static! {
+    static ref TABLE_COLUMNS: RwLock<HashMap<String, TableColumns>> = RwLock::new(HashMap::new());
+}
+
+/// The columns of distributed `table` as of the last DDL the mesh ran on it.
+pub fn table_columns(table: &str) -> Option<TableColumns> {
+    TABLE_COLUMNS.read().unwrap().get(table).cloned()
+}
+
+/// Every table of the schema cache, by name.
+pub fn cached_tables() -> HashMap<String, TableColumns> {
+    TABLE_COLUMNS.read().unwrap().clone()
+}
+
+/// The tables `statement` creates, alters or drops, None for other statements.
+fn ddl_tables(statement: &Statement) -> Option<Vec<String>> {
+    match statement {
+        Statement::CreateTable { name, .. } | Statement::AlterTable { name, .. } => Some(vec![unquoted_table(&name.to_string())]),
+        Statement::Drop { object_type: ObjectType::Table, names, .. } => Some(names.iter().map(|name| unquoted_table(&name.to_string())).collect()),
+        _ => None,
+    }
+}
+
+/// A table DDL over the data segments.
+#[derive(Debug)]
+pub struct DdlPlan {
+    /// The distributed tables of the statement.
+    tables: Vec<String>,
+    drop: bool,
+    /// The statement for every data segment, as `(segment, sql)`.
+    statements: Vec<(u32, String)>,
+}
+
+impl DdlPlan {
+    pub fn get_tables(&self) -> &Vec<String> {
+        &self.tables
+    }
+
+    pub fn get_statements(&self) -> &Vec<(u32, String)> {
+        &self.statements
+    }
+
+    /// Runs the statement on `segment` and reads back the columns its tables have there.
+    fn execute_on(&self, segment: u32, sql: &str, rules: &RulesVersion, session_ctx: &SessionContext) -> Result<Vec<TableColumns>, Error> {
+        let database_url = discovery::database::segment_url(&format!("data-{}/primary", segment))
+            .ok_or_else(|| Error::Routing(format!("no such segment data-{}/primary", segment)))?;
+        let mut conn = variables::connect_to(session_ctx, &database_url)?;
+        conn.query_drop(sql)?;
+        if self.drop {
+            return Ok(vec![]);
+        }
+        let mut columns = vec![];
+        for table in self.tables.iter() {
+            columns.push(segment_columns(&mut conn, &rules.actual_table(table, segment))?);
+        }
+        Ok(columns)
+    }
+
+    /// Runs the statement on every data segment. Fails with the segments that applied it and
+    /// the ones that did not when any fails, or else gives the columns of the tables on every
+    /// segment, none for a DROP.
+    pub fn execute(&self, rules: &RulesVersion, session_ctx: &SessionContext) -> Result<Vec<(u32, Vec<TableColumns>)>, Error> {
+        let mut applied = vec![];
+        let mut failed = vec![];
+        for (segment, sql) in self.statements.iter() {
+            match self.execute_on(*segment, sql, rules, session_ctx) {
+                Ok(columns) => applied.push((*segment, columns)),
+                Err(e) => {
+                    println!("error on running DDL on data segment {}; error = {}", segment, e);
+                    failed.push((*segment, e));
+                }
+            }
+        }
+        if failed.is_empty() {
+            return Ok(applied);
+        }
+        if applied.is_empty() && failed.len() == 1 {
+            return Err(failed.pop().unwrap().1);
+        }
+        let segments: Vec<u32> = applied.iter().map(|(segment, _)| *segment).collect();
+        let failures: Vec<String> = failed.iter().map(|(segment, e)| format!("data segment {}: {}", segment, e)).collect();
+        let message = format!("DDL applied on data segments {:?} and failed on {}; run it again on the failed segments", segments, failures.join(", "));
+        Err(match failed.swap_remove(0).1 {
+            Error::Backend { code, state, .. } => Error::Backend { code, state, message },
+            _ => Error::Backend { code: 1105, state: "HY000".to_string(), message },
+        })
+    }
+}
+
+/// The DDL of `statement` for every data segment of its distributed tables, None unless it
+/// creates, alters or drops one. The tables take their actual names on every segment, on top
+/// of the renames of `rewrite_ctx`.
+pub fn segment_ddl(statement: &Statement, rules: &RulesVersion, rewrite_ctx: &HashMap<String, String>) -> Result<Option<DdlPlan>, String> {
+    let tables = match ddl_tables(statement) {
+        Some(tables) => tables,
+        None => return Ok(None),
+    };
+    let mut distributed = vec![];
+    let mut others = vec![];
+    let mut segments = vec![];
+    for table in tables {
+        match rules.route(&table, &[]) {
+            TableRoute::Distributed(table_segments) => {
+                segments.extend(table_segments);
+                distributed.push(table);
+            }
+            _ => others.push(table),
+        }
+    }
+    if distributed.is_empty() {
+        return Ok(None);
+    }
+    if !others.is_empty() {
+        return Err(format!("the DDL of distributed tables {:?} can't name tables that are not distributed {:?}", distributed, others));
+    }
+    segments.sort_unstable();
+    segments.dedup();
+    let mut statements = vec![];
+    for segment in segments {
+        let mut ctx = rewrite_ctx.clone();
+        for table in distributed.iter() {
+            let actual = rules.actual_table(table, segment);
+            if actual != *table {
+                ctx.insert(table.clone(), actual);
+            }
+        }
+        let sql = if ctx.is_empty() {
+            statement.to_string()
+        } else {
+            rewrite_statement(statement, &ctx).ok_or_else(|| format!("the DDL can't be rewritten for data segment {}", segment))?
+        };
+        statements.push((segment, sql));
+    }
+    Ok(Some(DdlPlan {
+        tables: distributed,
+        drop: matches!(statement, Statement::Drop { .. }),
+        statements,
+    }))
+}
+
+fn segment_columns(conn: &mut BackendConn, actual_table: &str) -> mysql::Result<TableColumns> {
+    let sql = format!(
+        "SELECT COLUMN_NAME, COLUMN_TYPE FROM information_schema.COLUMNS WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = '{}' ORDER BY ORDINAL_POSITION",
+        actual_table.replace('\'', "''"));
+    let (_, rows) = information_schema::query_rows(conn, &sql)?;
+    Ok(rows.into_iter()
+        .map(|mut row| {
+            let column_type = row.pop().flatten().unwrap_or_default();
+            (row.pop().flatten().unwrap_or_default(), column_type)
+        })
+        .collect())
+}
+
+fn describe(columns: &TableColumns) -> String {
+    columns.iter().map(|(name, column_type)| format!("{} {}", name, column_type)).collect::<Vec<String>>().join(", ")
+}
+
+/// The tables whose columns on a segment are not the ones on the first segment, as warnings.
+pub fn inconsistencies(tables: &[String], columns: &[(u32, Vec<TableColumns>)]) -> Vec<String> {
+    let mut warnings = vec![];
+    let (first, expected) = match columns.first() {
+        Some(first) => first,
+        None => return warnings,
+    };
+    for (segment, segment_columns) in columns.iter().skip(1) {
+        for (index, table) in tables.iter().enumerate() {
+            if segment_columns[index] != expected[index] {
+                warnings.push(format!("table {} has columns ({}) on data segment {} but ({}) on data segment {}",
+                                      table, describe(&segment_columns[index]), segment, describe(&expected[index]), first));
+            }
+        }
+    }
+    warnings
+}
+
+fn refresh_columns(plan: &DdlPlan, columns: &[(u32, Vec<TableColumns>)]) {
+    let mut cache = TABLE_COLUMNS.write().unwrap();
+    for (index, table) in plan.tables.iter().enumerate() {
+        match columns.first() {
+            Some((_, first)) if !plan.drop => cache.insert(table.clone(), first[index].clone()),
+            _ => cache.remove(table),
+        };
+    }
+}
+
+/// Answers a CREATE, ALTER or DROP TABLE of a distributed table, run on every data segment.
+/// None for other statements.
+pub fn intercept(statement: &Statement, rewrite_ctx: &HashMap<String, String>, session_ctx: &mut SessionContext) -> Option<Vec<Bytes>> {
+    let rules = current_rules()?;
+    let plan = match segment_ddl(statement, &rules, rewrite_ctx) {
+        Ok(Some(plan)) => plan,
+        Ok(None) => return None,
+        Err(message) => return Some(vec![err_payload(Error::Protocol(message))]),
+    };
+    if session_ctx.is_in_transaction() {
+        return Some(vec![err_payload(Error::Protocol("DDL of distributed tables can't run inside a transaction".to_string()))]);
+    }
+    let columns = match plan.execute(&rules, session_ctx) {
+        Ok(columns) => columns,
+        Err(e) => {
+            // The segments no longer agree on the tables, until the DDL runs again.
+            refresh_columns(&plan, &[]);
+            return Some(vec![err_payload(e)]);
+        }
+    };
+    let warnings = inconsistencies(&plan.tables, &columns);
+    for warning in warnings.iter() {
+        println!("DDL left data segments inconsistent; {}", warning);
+        session_ctx.push_warning(warning.clone());
+    }
+    refresh_columns(&plan, &columns);
+    let mut ok_packet = MySQLOKPacket::new(1, 0, 0);
+    ok_packet.set_status_flags(session_ctx.get_status_flags());
+    ok_packet.set_warnings(warnings.len() as u16);
+    let mut ok_payload = MySQLPacketPayload::new();
+    let ok_payload = DatabasePacket::encode(&mut ok_packet, &mut ok_payload);
+    Some(vec![ok_payload.get_payload()])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::discovery::database::{Cluster, DisAlgorithm, DisRules, DisTable, DisType, Segment};
+    use crate::discovery::database::rules::RulesVersion;
+    use crate::handler::database::parser::sql::mysql::parser;
+
+    use super::{inconsistencies, segment_ddl};
+
+    #[test]
+    fn test_segment_ddl() {
+        let url = "jdbc:mysql://localhost:3306/martlet";
+        let cluster = Cluster::builder("martlet")
+            .meta_segment(Segment::new(0, url, "root", "root"), vec![])
+            .data_segment(100, Segment::new(0, url, "root", "root"), vec![])
+            .data_segment(200, Segment::new(0, url, "root", "root"), vec![])
+            .dis_rules(DisRules::builder()
+                .distributed_table("t_order", DisTable::new(vec!["user_id"], DisAlgorithm::new(DisType::HASH, ""), vec![])
+                    .actual_table("t_order_{segment}"))
+                .replicated_table("t_dept")
+                .build())
+            .build()
+            .unwrap();
+        let rules = RulesVersion::new("v1".to_string(), cluster);
+        let ddl = |sql: &str| segment_ddl(&parser(sql.to_string()).unwrap().pop().unwrap(), &rules, &HashMap::new()).unwrap();
+
+        let plan = ddl("ALTER TABLE t_order ADD COLUMN note VARCHAR(32)").unwrap();
+        assert_eq!(plan.get_tables(), &vec!["t_order".to_string()]);
+        assert_eq!(plan.get_statements(), &vec![
+            (100, "ALTER TABLE t_order_100 ADD COLUMN note VARCHAR(32)".to_string()),
+            (200, "ALTER TABLE t_order_200 ADD COLUMN note VARCHAR(32)".to_string()),
+        ]);
+        let plan = ddl("DROP TABLE IF EXISTS t_order").unwrap();
+        assert_eq!(plan.get_statements()[1].1, "DROP TABLE IF EXISTS t_order_200");
+        assert!(ddl("CREATE TABLE t_dept (id INT)").is_none());
+        assert!(ddl("SELECT * FROM t_order").is_none());
+        let statement = parser("DROP TABLE t_order, t_dept".to_string()).unwrap().pop().unwrap();
+        assert!(segment_ddl(&statement, &rules, &HashMap::new()).is_err());
+
+        let column = |name: &str, column_type: &str| (name.to_string(), column_type.to_string());
+        let columns = vec![
+            (100, vec![vec![column("id", "bigint"), column("note", "varchar(32)")]]),
+            (200, vec![vec![column("id", "bigint")]]),
+        ];
+        assert_eq!(inconsistencies(&["t_order".to_string()], &columns),
+                   vec!["table t_order has columns (id bigint) on data segment 200 but (id bigint, note varchar(32)) on data segment 100".to_string()]);
+        assert!(inconsistencies(&["t_order".to_string()], &columns[..1]).is_empty());
+    }
+}
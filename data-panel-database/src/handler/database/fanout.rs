@@ -0,0 +1,569 @@
+//! Scatter-gather execution of per-segment sub-queries.
+//!
+//! Every sub-query runs on its own backend connection on the blocking pool, at most
+//! `parallelism` of a fan-out at once, see `FanoutConfig`. A sub-query past the branch timeout
+//! is killed on its own and fails. When the client cancels or the statement deadline passes,
+//! the merge aborts and every sub-query still in flight is killed through a separate control
+//! connection, and the fan-out only returns once all of them have stopped, so nothing keeps
+//! running on the backends behind the client. The sub-queries that failed make up one error,
+//! telling every failed segment and why.
+//!
+//! Reads that opted into partial results (per query hint or per table) still return when a
+//! minority of the segments fail, together with the list of the missing segments.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use futures::future::join_all;
+use mysql::{Column, Row};
+use mysql::prelude::Queryable;
+use sqlparser::ast::Statement;
+use tokio::sync::{Notify, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use data_panel_common::common::Error;
+use data_panel_common::config::config::{FanoutConfig, PartialResultsConfig};
+
+use crate::handler::database::concurrency::{self, SegmentPermit};
+use crate::handler::database::lifecycle::{self, BackendConn};
+use crate::handler::database::merge::MergeRow;
+use crate::handler::database::parser::sql::statement_tables;
+
+/// How often sub-queries that survived a kill are killed again.
+const KILL_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+const PARTIAL_RESULTS_HINT: &str = "/*+ PARTIAL_RESULTS */";
+
+/// The backend side of a fan-out, abstracted so the cancellation can be exercised in tests.
+pub trait FanoutBackend: Send + Sync + 'static {
+    type Conn: Send;
+    type Output: Send;
+
+    fn connect(&self, segment: &str) -> Result<Self::Conn, String>;
+
+    fn connection_id(&self, conn: &Self::Conn) -> u64;
+
+    fn query(&self, conn: &mut Self::Conn, sql: &str) -> Result<Self::Output, String>;
+
+    /// Stops the statement running on `connection_id`, from another connection.
+    fn kill(&self, segment: &str, connection_id: u64);
+}
+
+#[derive(Debug, Clone)]
+pub struct SubQuery {
+    segment: String,
+    sql: String,
+}
+
+impl SubQuery {
+    pub fn new(segment: &str, sql: &str) -> Self {
+        SubQuery {
+            segment: segment.to_string(),
+            sql: sql.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FanoutError {
+    Cancelled,
+    DeadlineExceeded,
+    SubQuery(String, String),
+    /// The sub-query of a segment ran past the branch timeout, in milliseconds.
+    BranchTimeout(String, u32),
+    /// Several sub-queries of a fan-out of that many failed.
+    Segments(usize, Vec<FanoutError>),
+}
+
+impl fmt::Display for FanoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FanoutError::Cancelled => write!(f, "Query execution was interrupted"),
+            FanoutError::DeadlineExceeded => write!(f, "Query execution was interrupted, maximum statement execution time exceeded"),
+            FanoutError::SubQuery(segment, message) => write!(f, "{}: {}", segment, message),
+            FanoutError::BranchTimeout(segment, timeout) => write!(f, "{}: no answer within {} ms", segment, timeout),
+            FanoutError::Segments(total, errors) => {
+                let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "{} of {} segments failed: {}", errors.len(), total, errors.join("; "))
+            }
+        }
+    }
+}
+
+impl From<FanoutError> for Error {
+    fn from(e: FanoutError) -> Self {
+        let (code, state) = match e {
+            FanoutError::Cancelled => (1317, "70100"),
+            FanoutError::DeadlineExceeded => (3024, "HY000"),
+            _ => (1105, "HY000"),
+        };
+        Error::Backend { code, state: state.to_string(), message: e.to_string() }
+    }
+}
+
+/// The errors of the failed sub-queries of a fan-out of `total` as one, a lone one as it is.
+fn aggregate(total: usize, mut errors: Vec<FanoutError>) -> FanoutError {
+    if errors.len() == 1 {
+        return errors.pop().unwrap();
+    }
+    FanoutError::Segments(total, errors)
+}
+
+/// The outputs of the segments that answered, and the segments that did not.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialResult<O> {
+    outputs: Vec<O>,
+    missing: Vec<String>,
+}
+
+impl<O> PartialResult<O> {
+    pub fn get_outputs(&self) -> &Vec<O> {
+        &self.outputs
+    }
+
+    pub fn into_outputs(self) -> Vec<O> {
+        self.outputs
+    }
+
+    pub fn get_missing(&self) -> Vec<String> {
+        self.missing.clone()
+    }
+
+    pub fn is_partial(&self) -> bool {
+        !self.missing.is_empty()
+    }
+
+    /// The warning to send with a partial response, None when every segment answered.
+    pub fn warning(&self) -> Option<String> {
+        if !self.is_partial() {
+            return None;
+        }
+        Some(format!("Partial result, {} of {} segments missing: {}",
+                     self.missing.len(), self.missing.len() + self.outputs.len(), self.missing.join(", ")))
+    }
+}
+
+/// Whether a failed minority of segments may be left out of the result of `statement`.
+pub fn partial_results_allowed(sql: &str, statement: &Statement, config: &PartialResultsConfig) -> bool {
+    if !matches!(statement, Statement::Query(_)) {
+        return false;
+    }
+    sql.to_ascii_uppercase().contains(PARTIAL_RESULTS_HINT)
+        || statement_tables(statement).iter().any(|table| config.allows(table))
+}
+
+/// Cancels a running fan-out, e.g. when the client disconnects.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    async fn cancelled(&self) {
+        while !self.is_cancelled() {
+            let notified = self.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+lazy_static! {
+    static ref IN_FLIGHT_SUB_QUERIES: AtomicU64 = AtomicU64::new(0);
+}
+
+/// Sub-queries currently holding a backend connection, across all fan-outs.
+pub fn in_flight_sub_queries() -> u64 {
+    IN_FLIGHT_SUB_QUERIES.load(Ordering::SeqCst)
+}
+
+/// Connections of the sub-queries still running, by sub-query index.
+type Running = Arc<Mutex<HashMap<usize, (String, u64)>>>;
+
+fn run_sub_query<B: FanoutBackend>(backend: &B, index: usize, query: &SubQuery,
+                                   running: &Running, stopped: impl Fn() -> bool) -> Result<B::Output, FanoutError> {
+    let sub_query_error = |e: String| FanoutError::SubQuery(query.segment.clone(), e);
+    let mut conn = backend.connect(&query.segment).map_err(sub_query_error)?;
+    running.lock().unwrap().insert(index, (query.segment.clone(), backend.connection_id(&conn)));
+    // Registered first, so a fan-out stopped from here on sees and kills this connection.
+    let result = if stopped() {
+        Err(FanoutError::Cancelled)
+    } else {
+        backend.query(&mut conn, &query.sql).map_err(sub_query_error)
+    };
+    running.lock().unwrap().remove(&index);
+    result
+}
+
+/// Kills the sub-queries still running, only the one of index `only` if given, until `done`.
+async fn kill_until<B: FanoutBackend, F: Future + Unpin>(backend: &Arc<B>, running: &Running, only: Option<usize>, done: &mut F) {
+    loop {
+        let victims: Vec<(String, u64)> = running.lock().unwrap().iter()
+            .filter(|(index, _)| only.map_or(true, |only| only == **index))
+            .map(|(_, victim)| victim.clone())
+            .collect();
+        for (segment, connection_id) in victims {
+            let backend = backend.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || backend.kill(&segment, connection_id)).await {
+                println!("error on killing sub-query; error = {:?}", e);
+            }
+        }
+        if tokio::time::timeout(KILL_RETRY_INTERVAL, &mut *done).await.is_ok() {
+            return;
+        }
+    }
+}
+
+/// Runs sub-query `index` once the fan-out has a free slot, killing it past `branch_timeout`.
+async fn run_branch<B: FanoutBackend>(backend: Arc<B>, index: usize, query: SubQuery, slots: Arc<Semaphore>,
+                                      branch_timeout: Option<Duration>, running: Running, stop: Arc<AtomicBool>) -> Result<B::Output, FanoutError> {
+    let _slot = slots.acquire_owned().await.map_err(|_| FanoutError::Cancelled)?;
+    if stop.load(Ordering::SeqCst) {
+        return Err(FanoutError::Cancelled);
+    }
+    let segment = query.segment.clone();
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let mut sub_query = {
+        let (backend, running, timed_out) = (backend.clone(), running.clone(), timed_out.clone());
+        IN_FLIGHT_SUB_QUERIES.fetch_add(1, Ordering::SeqCst);
+        tokio::task::spawn_blocking(move || {
+            let stopped = || stop.load(Ordering::SeqCst) || timed_out.load(Ordering::SeqCst);
+            let result = run_sub_query(backend.as_ref(), index, &query, &running, stopped);
+            IN_FLIGHT_SUB_QUERIES.fetch_sub(1, Ordering::SeqCst);
+            result
+        })
+    };
+    let joined = match branch_timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, &mut sub_query).await {
+            Ok(joined) => joined,
+            Err(_) => {
+                timed_out.store(true, Ordering::SeqCst);
+                kill_until(&backend, &running, Some(index), &mut sub_query).await;
+                return Err(FanoutError::BranchTimeout(segment, timeout.as_millis() as u32));
+            }
+        },
+        None => sub_query.await,
+    };
+    joined.unwrap_or_else(|e| Err(FanoutError::SubQuery(segment, e.to_string())))
+}
+
+/// Runs `queries` concurrently and returns their outputs in order, or the errors of the ones
+/// that failed.
+pub async fn scatter_gather<B: FanoutBackend>(backend: Arc<B>, queries: Vec<SubQuery>, deadline: Instant,
+                                              cancel: CancelToken, config: &FanoutConfig) -> Result<Vec<B::Output>, FanoutError> {
+    let results = fan_out(backend, queries, deadline, cancel, config).await?;
+    let total = results.len();
+    let (mut outputs, mut errors) = (vec![], vec![]);
+    for result in results {
+        match result {
+            Ok(output) => outputs.push(output),
+            Err(e) => errors.push(e),
+        }
+    }
+    if errors.is_empty() {
+        Ok(outputs)
+    } else {
+        Err(aggregate(total, errors))
+    }
+}
+
+/// Like `scatter_gather`, but leaves out the segments that failed as long as they are a
+/// minority. Cancellation and the deadline still abort the whole fan-out.
+pub async fn scatter_gather_partial<B: FanoutBackend>(backend: Arc<B>, queries: Vec<SubQuery>, deadline: Instant,
+                                                      cancel: CancelToken, config: &FanoutConfig) -> Result<PartialResult<B::Output>, FanoutError> {
+    let segments: Vec<String> = queries.iter().map(|query| query.segment.clone()).collect();
+    let results = fan_out(backend, queries, deadline, cancel, config).await?;
+    let failed = results.iter().filter(|result| result.is_err()).count();
+    if failed * 2 >= results.len() && failed > 0 {
+        let total = results.len();
+        return Err(aggregate(total, results.into_iter().filter_map(Result::err).collect()));
+    }
+    let mut partial = PartialResult {
+        outputs: vec![],
+        missing: vec![],
+    };
+    for (segment, result) in segments.into_iter().zip(results) {
+        match result {
+            Ok(output) => partial.outputs.push(output),
+            Err(e) => {
+                println!("error on segment {} of partial fan-out; error = {:?}", segment, e);
+                partial.missing.push(segment);
+            }
+        }
+    }
+    Ok(partial)
+}
+
+/// Runs `queries` concurrently and returns every sub-query's result in order, or why the
+/// fan-out was aborted.
+async fn fan_out<B: FanoutBackend>(backend: Arc<B>, queries: Vec<SubQuery>, deadline: Instant,
+                                   cancel: CancelToken, config: &FanoutConfig) -> Result<Vec<Result<B::Output, FanoutError>>, FanoutError> {
+    let running: Running = Arc::new(Mutex::new(HashMap::new()));
+    let stop = Arc::new(AtomicBool::new(false));
+    let slots = Arc::new(Semaphore::new(config.get_parallelism()));
+    let branch_timeout = match config.get_branch_timeout() {
+        0 => None,
+        timeout => Some(Duration::from_millis(timeout as u64)),
+    };
+    let mut handles: Vec<JoinHandle<Result<B::Output, FanoutError>>> = vec![];
+    for (index, query) in queries.into_iter().enumerate() {
+        let branch = run_branch(backend.clone(), index, query, slots.clone(), branch_timeout, running.clone(), stop.clone());
+        handles.push(tokio::spawn(branch));
+    }
+
+    let all_done = join_all(handles.iter_mut());
+    tokio::pin!(all_done);
+    let aborted = tokio::select! {
+        results = &mut all_done => {
+            return Ok(results.into_iter()
+                .map(|result| result.unwrap_or_else(|e| Err(FanoutError::SubQuery("".to_string(), e.to_string()))))
+                .collect());
+        }
+        _ = tokio::time::sleep_until(deadline) => FanoutError::DeadlineExceeded,
+        _ = cancel.cancelled() => FanoutError::Cancelled,
+    };
+
+    stop.store(true, Ordering::SeqCst);
+    // The sub-queries waiting for a slot give up.
+    slots.close();
+    kill_until(&backend, &running, None, &mut all_done).await;
+    Err(aborted)
+}
+
+/// The result set a segment answered a sub-query with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentResult {
+    columns: Vec<Column>,
+    rows: Vec<MergeRow>,
+}
+
+impl SegmentResult {
+    pub fn new(columns: Vec<Column>, rows: Vec<MergeRow>) -> Self {
+        SegmentResult {
+            columns,
+            rows,
+        }
+    }
+
+    pub fn get_columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    pub fn into_rows(self) -> Vec<MergeRow> {
+        self.rows
+    }
+}
+
+/// Fans out to real segments, `urls` maps the segment name to its mysql url.
+pub struct MySQLFanoutBackend {
+    urls: HashMap<String, String>,
+    binary: bool,
+}
+
+impl MySQLFanoutBackend {
+    pub fn new(urls: HashMap<String, String>) -> Self {
+        MySQLFanoutBackend {
+            urls,
+            binary: false,
+        }
+    }
+
+    /// Runs the sub-queries as prepared statements, so their values come typed as the binary
+    /// protocol sends them rather than as text.
+    pub fn binary(mut self, binary: bool) -> Self {
+        self.binary = binary;
+        self
+    }
+
+    fn url(&self, segment: &str) -> Result<&String, String> {
+        self.urls.get(segment).ok_or(format!("unknown segment {}", segment))
+    }
+}
+
+impl FanoutBackend for MySQLFanoutBackend {
+    /// The connection of a sub-query, with the permit of its segment, see `concurrency`.
+    type Conn = (BackendConn, Option<SegmentPermit>);
+    type Output = SegmentResult;
+
+    fn connect(&self, segment: &str) -> Result<Self::Conn, String> {
+        let url = self.url(segment)?;
+        let permit = concurrency::permit(url).map_err(|e| e.to_string())?;
+        let conn = lifecycle::connect(url).map_err(|e| e.to_string())?;
+        Ok((conn, permit))
+    }
+
+    fn connection_id(&self, conn: &Self::Conn) -> u64 {
+        conn.0.connection_id() as u64
+    }
+
+    fn query(&self, conn: &mut Self::Conn, sql: &str) -> Result<SegmentResult, String> {
+        let (columns, rows) = if self.binary {
+            let result = conn.0.exec_iter(sql, ()).map_err(|e| e.to_string())?;
+            let columns = result.columns().as_ref().to_vec();
+            (columns, result.map(|row| row.map(Row::unwrap)).collect::<mysql::Result<Vec<MergeRow>>>())
+        } else {
+            let result = conn.0.query_iter(sql).map_err(|e| e.to_string())?;
+            let columns = result.columns().as_ref().to_vec();
+            (columns, result.map(|row| row.map(Row::unwrap)).collect::<mysql::Result<Vec<MergeRow>>>())
+        };
+        Ok(SegmentResult::new(columns, rows.map_err(|e| e.to_string())?))
+    }
+
+    fn kill(&self, segment: &str, connection_id: u64) {
+        // The control connection does not wait for a permit behind the sub-queries it stops.
+        let killed = self.url(segment)
+            .and_then(|url| lifecycle::connect(url).map_err(|e| e.to_string()))
+            .and_then(|mut control| control.query_drop(format!("KILL QUERY {}", connection_id)).map_err(|e| e.to_string()));
+        if let Err(e) = killed {
+            println!("error on killing connection {} on segment {}; error = {:?}", connection_id, segment, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use data_panel_common::common::Error;
+    use data_panel_common::config::config::{FanoutConfig, PartialResultsConfig};
+
+    use crate::handler::database::fanout::{CancelToken, FanoutBackend, FanoutError, in_flight_sub_queries, partial_results_allowed, scatter_gather, scatter_gather_partial, SubQuery};
+    use crate::handler::database::parser::sql::mysql::parser;
+
+    /// Sub-queries on `slow` segments block until they are killed, `down` segments refuse.
+    #[derive(Default)]
+    struct BlockingBackend {
+        next_id: AtomicU64,
+        killed: Mutex<HashSet<u64>>,
+        kill_signal: Condvar,
+    }
+
+    impl FanoutBackend for BlockingBackend {
+        type Conn = (String, u64);
+        type Output = String;
+
+        fn connect(&self, segment: &str) -> Result<Self::Conn, String> {
+            if segment == "down" {
+                return Err("Connection refused".to_string());
+            }
+            Ok((segment.to_string(), self.next_id.fetch_add(1, Ordering::SeqCst)))
+        }
+
+        fn connection_id(&self, conn: &Self::Conn) -> u64 {
+            conn.1
+        }
+
+        fn query(&self, conn: &mut Self::Conn, sql: &str) -> Result<String, String> {
+            if conn.0 != "slow" {
+                return Ok(sql.to_string());
+            }
+            let mut killed = self.killed.lock().unwrap();
+            while !killed.contains(&conn.1) {
+                killed = self.kill_signal.wait(killed).unwrap();
+            }
+            Err("Query execution was interrupted".to_string())
+        }
+
+        fn kill(&self, _segment: &str, connection_id: u64) {
+            self.killed.lock().unwrap().insert(connection_id);
+            self.kill_signal.notify_all();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancel_kills_in_flight_sub_queries() {
+        let backend = Arc::new(BlockingBackend::default());
+        let queries = vec![SubQuery::new("fast", "a"), SubQuery::new("slow", "b"), SubQuery::new("slow", "c")];
+
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let fast = vec![SubQuery::new("fast", "a"), SubQuery::new("fast", "b")];
+        let result = scatter_gather(backend.clone(), fast, deadline, CancelToken::new(), &FanoutConfig::default()).await;
+        assert_eq!(result, Ok(vec!["a".to_string(), "b".to_string()]));
+
+        let cancel = CancelToken::new();
+        let client = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client.cancel();
+        });
+        let result = scatter_gather(backend.clone(), queries.clone(), deadline, cancel, &FanoutConfig::default()).await;
+        assert_eq!(result, Err(FanoutError::Cancelled));
+        assert_eq!(backend.killed.lock().unwrap().len(), 2);
+        assert_eq!(in_flight_sub_queries(), 0);
+
+        let result = scatter_gather(backend.clone(), queries, Instant::now() + Duration::from_millis(50), CancelToken::new(), &FanoutConfig::default()).await;
+        assert_eq!(result, Err(FanoutError::DeadlineExceeded));
+        assert_eq!(backend.killed.lock().unwrap().len(), 4);
+        assert_eq!(in_flight_sub_queries(), 0);
+
+        // A single slot: the other branches wait for the slow one to time out.
+        let queries = vec![SubQuery::new("slow", "a"), SubQuery::new("fast", "b"), SubQuery::new("down", "c")];
+        let result = scatter_gather(backend.clone(), queries, deadline, CancelToken::new(), &FanoutConfig::new(1, 50)).await;
+        let expected = FanoutError::Segments(3, vec![
+            FanoutError::BranchTimeout("slow".to_string(), 50),
+            FanoutError::SubQuery("down".to_string(), "Connection refused".to_string()),
+        ]);
+        assert_eq!(result, Err(expected.clone()));
+        assert_eq!(backend.killed.lock().unwrap().len(), 5);
+        assert_eq!(in_flight_sub_queries(), 0);
+        match Error::from(expected) {
+            Error::Backend { code, message, .. } => {
+                assert_eq!(code, 1105);
+                assert_eq!(message, "2 of 3 segments failed: slow: no answer within 50 ms; down: Connection refused");
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_partial_results() {
+        let backend = Arc::new(BlockingBackend::default());
+        let deadline = Instant::now() + Duration::from_secs(60);
+
+        let queries = vec![SubQuery::new("fast", "a"), SubQuery::new("down", "b"), SubQuery::new("fast", "c")];
+        let result = scatter_gather_partial(backend.clone(), queries.clone(), deadline, CancelToken::new(), &FanoutConfig::default()).await.unwrap();
+        assert_eq!(result.get_outputs(), &vec!["a".to_string(), "c".to_string()]);
+        assert_eq!(result.get_missing(), vec!["down".to_string()]);
+        assert_eq!(result.warning().unwrap(), "Partial result, 1 of 3 segments missing: down");
+        assert!(scatter_gather(backend.clone(), queries, deadline, CancelToken::new(), &FanoutConfig::default()).await.is_err());
+
+        let queries = vec![SubQuery::new("fast", "a"), SubQuery::new("down", "b")];
+        let result = scatter_gather_partial(backend.clone(), queries, deadline, CancelToken::new(), &FanoutConfig::default()).await;
+        assert_eq!(result, Err(FanoutError::SubQuery("down".to_string(), "Connection refused".to_string())));
+
+        let config = PartialResultsConfig::new(vec!["t_metrics"]);
+        let statement = parser("SELECT * FROM t_metrics".to_string()).unwrap().pop().unwrap();
+        assert!(partial_results_allowed("SELECT * FROM t_metrics", &statement, &config));
+        let sql = "SELECT /*+ PARTIAL_RESULTS */ * FROM t_order";
+        let statement = parser(sql.to_string()).unwrap().pop().unwrap();
+        assert!(partial_results_allowed(sql, &statement, &config));
+        let statement = parser("DELETE FROM t_metrics".to_string()).unwrap().pop().unwrap();
+        assert!(!partial_results_allowed("DELETE FROM t_metrics", &statement, &config));
+    }
+}
@@ -0,0 +1,256 @@
+//! Fault injection for resilience testing.
+//!
+//! Latency is injected towards a percentile target rather than as a fixed delay: a fraction of
+//! the statements is delayed to around the target, and that fraction is continuously
+//! calibrated against the observed latencies until the configured percentile lands on it.
+//!
+//! Fault rules delay, or fail with a synthetic error, a share of the statements matching their
+//! table, statement type, user or session, without the backend being involved.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use mysql::MySqlError;
+use sqlparser::ast::Statement;
+
+use data_panel_common::config::config::{FaultInjectionConfig, FaultRule};
+
+use crate::handler::database::parser::sql::statement_tables;
+use crate::session::mysql::SessionContext;
+
+/// Latencies kept to compute the percentiles from.
+const LATENCY_WINDOW: usize = 1024;
+/// Statements between two calibrations.
+const CALIBRATE_EVERY: usize = 64;
+const MIN_SAMPLES: usize = 128;
+/// How strongly the delayed fraction follows the distance to the target.
+const GAIN: f64 = 0.5;
+/// Lets a fraction that dropped to zero grow back.
+const MIN_STEP: f64 = 0.0005;
+
+/// The `p`th percentile of `samples`, in microseconds.
+fn percentile(samples: &VecDeque<u64>, p: f64) -> u64 {
+    if samples.is_empty() {
+        return 0;
+    }
+    let mut sorted: Vec<u64> = samples.iter().cloned().collect();
+    sorted.sort_unstable();
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.max(1).min(sorted.len()) - 1]
+}
+
+fn push_sample(samples: &mut VecDeque<u64>, sample: u64) {
+    if samples.len() >= LATENCY_WINDOW {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+struct InjectorState {
+    /// Latencies without the injected delay.
+    natural: VecDeque<u64>,
+    /// Latencies as the clients see them.
+    observed: VecDeque<u64>,
+    /// Fraction of the statements that get delayed.
+    rate: f64,
+    since_calibration: usize,
+}
+
+pub struct LatencyInjector {
+    percentile: f64,
+    target: u64,
+    state: Mutex<InjectorState>,
+}
+
+impl LatencyInjector {
+    pub fn new(percentile: f64, target: Duration) -> Self {
+        LatencyInjector {
+            percentile,
+            target: target.as_micros() as u64,
+            state: Mutex::new(InjectorState {
+                natural: VecDeque::with_capacity(LATENCY_WINDOW),
+                observed: VecDeque::with_capacity(LATENCY_WINDOW),
+                rate: 1.0 - percentile / 100.0,
+                since_calibration: 0,
+            }),
+        }
+    }
+
+    /// The delay to add to the next statement, zero for most of them.
+    pub fn next_delay(&self) -> Duration {
+        let state = self.state.lock().unwrap();
+        if rand::random::<f64>() >= state.rate {
+            return Duration::from_micros(0);
+        }
+        // Spread around the target, so the percentile moves smoothly with the rate.
+        let base = self.target.saturating_sub(percentile(&state.natural, 50.0)) as f64;
+        Duration::from_micros((base * (0.5 + rand::random::<f64>())) as u64)
+    }
+
+    /// Records a statement that took `natural` plus the `injected` delay.
+    pub fn observe(&self, natural: Duration, injected: Duration) {
+        let natural = natural.as_micros() as u64;
+        let mut state = self.state.lock().unwrap();
+        push_sample(&mut state.natural, natural);
+        push_sample(&mut state.observed, natural + injected.as_micros() as u64);
+        state.since_calibration += 1;
+        if state.since_calibration >= CALIBRATE_EVERY && state.observed.len() >= MIN_SAMPLES {
+            state.since_calibration = 0;
+            let current = percentile(&state.observed, self.percentile) as f64;
+            let error = (self.target as f64 - current) / self.target.max(1) as f64;
+            state.rate = if error > 0.0 {
+                (state.rate * (1.0 + GAIN * error)).max(state.rate + MIN_STEP).min(1.0)
+            } else {
+                (state.rate * (1.0 + GAIN * error)).max(0.0)
+            };
+        }
+    }
+
+    /// The configured percentile of the latencies clients currently see.
+    pub fn observed_percentile(&self) -> Duration {
+        Duration::from_micros(percentile(&self.state.lock().unwrap().observed, self.percentile))
+    }
+
+    pub fn get_rate(&self) -> f64 {
+        self.state.lock().unwrap().rate
+    }
+}
+
+lazy_static! {
+    static ref LATENCY_INJECTOR: RwLock<Option<Arc<LatencyInjector>>> = RwLock::new(None);
+    static ref FAULT_RULES: RwLock<Arc<Vec<FaultRule>>> = RwLock::new(Arc::new(vec![]));
+}
+
+/// Installs the injector and the fault rules described by `config`, or removes them when
+/// disabled.
+pub fn configure_fault_injection(config: &FaultInjectionConfig) {
+    let injector = if config.is_enabled() && config.get_latency_target() > 0 {
+        Some(Arc::new(LatencyInjector::new(config.get_latency_percentile(),
+                                           Duration::from_millis(config.get_latency_target() as u64))))
+    } else {
+        None
+    };
+    *LATENCY_INJECTOR.write().unwrap() = injector;
+    let rules = if config.is_enabled() { config.get_rules() } else { vec![] };
+    *FAULT_RULES.write().unwrap() = Arc::new(rules);
+}
+
+pub fn latency_injector() -> Option<Arc<LatencyInjector>> {
+    LATENCY_INJECTOR.read().unwrap().clone()
+}
+
+/// Whether latency or fault rules are being injected.
+pub fn is_injecting() -> bool {
+    latency_injector().is_some() || !FAULT_RULES.read().unwrap().is_empty()
+}
+
+/// Runs `execute`, delayed by the latency injector when one is installed.
+pub fn with_injected_latency<T>(execute: impl FnOnce() -> T) -> T {
+    let injector = match latency_injector() {
+        Some(injector) => injector,
+        None => return execute(),
+    };
+    let delay = injector.next_delay();
+    let started = Instant::now();
+    let result = execute();
+    let natural = started.elapsed();
+    if delay > Duration::from_micros(0) {
+        std::thread::sleep(delay);
+    }
+    injector.observe(natural, delay);
+    result
+}
+
+/// The type of `sql`, its first keyword upper cased.
+fn statement_type(sql: &str) -> String {
+    sql.trim_start().split(|c: char| c.is_whitespace() || c == ';' || c == '(').next().unwrap_or("").to_uppercase()
+}
+
+fn rule_matches(rule: &FaultRule, statement_type: &str, tables: &[String], user: &str, session_id: u64) -> bool {
+    (rule.get_statement().is_empty() || rule.get_statement().eq_ignore_ascii_case(statement_type))
+        && (rule.get_user().is_empty() || rule.get_user() == user)
+        && (rule.get_session() == 0 || rule.get_session() == session_id)
+        && (rule.get_table().is_empty() || tables.iter().any(|table| table.eq_ignore_ascii_case(&rule.get_table())))
+}
+
+/// The delay, and the error, the matching rules inject into one statement. Each rule draws
+/// for itself: the delays add up and the first error wins.
+fn draw_faults(rules: &[FaultRule], statement_type: &str, tables: &[String], user: &str, session_id: u64) -> (Duration, Option<(u16, String)>) {
+    let mut delay = Duration::from_millis(0);
+    let mut error = None;
+    for rule in rules.iter().filter(|rule| rule_matches(rule, statement_type, tables, user, session_id)) {
+        if rand::random::<f64>() * 100.0 >= rule.get_percentage() {
+            continue;
+        }
+        delay += Duration::from_millis(rule.get_delay() as u64);
+        if error.is_none() && rule.get_error_code() > 0 {
+            error = Some((rule.get_error_code(), rule.get_error_message()));
+        }
+    }
+    (delay, error)
+}
+
+/// Applies the fault rules to `statement`, sleeping through their delay and failing it with
+/// their synthetic error.
+pub fn inject(statement: &Statement, sql: &str, session_ctx: &SessionContext) -> mysql::Result<()> {
+    let rules = FAULT_RULES.read().unwrap().clone();
+    if rules.is_empty() {
+        return Ok(());
+    }
+    let tables = if rules.iter().any(|rule| !rule.get_table().is_empty()) {
+        statement_tables(statement)
+    } else {
+        vec![]
+    };
+    let (delay, error) = draw_faults(&rules, &statement_type(sql), &tables, &session_ctx.get_user_name(), session_ctx.get_thread_id());
+    if delay > Duration::from_millis(0) {
+        std::thread::sleep(delay);
+    }
+    match error {
+        Some((code, message)) => Err(mysql::Error::MySqlError(MySqlError {
+            state: "HY000".to_string(),
+            message,
+            code,
+        })),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use data_panel_common::config::config::FaultRule;
+
+    use crate::handler::database::fault::{draw_faults, LatencyInjector};
+
+    #[test]
+    fn test_latency_injector_calibrates_to_target() {
+        let injector = LatencyInjector::new(99.0, Duration::from_millis(200));
+        for _ in 0..20000 {
+            let delay = injector.next_delay();
+            injector.observe(Duration::from_millis(10), delay);
+        }
+        let p99 = injector.observed_percentile();
+        assert!(p99 > Duration::from_millis(120) && p99 < Duration::from_millis(280), "p99 = {:?}", p99);
+        assert!(injector.get_rate() > 0.0 && injector.get_rate() < 0.1);
+    }
+
+    #[test]
+    fn test_draw_faults() {
+        let rules = vec![
+            FaultRule::new().table("t_order").statement("update").delay(20),
+            FaultRule::new().user("batch").error(1205, "Lock wait timeout exceeded"),
+            FaultRule::new().session(7).delay(30),
+        ];
+        let tables = vec!["t_order".to_string()];
+        let (delay, error) = draw_faults(&rules, "UPDATE", &tables, "app", 1);
+        assert_eq!(delay, Duration::from_millis(20));
+        assert!(error.is_none());
+
+        let (delay, error) = draw_faults(&rules, "SELECT", &tables, "batch", 7);
+        assert_eq!(delay, Duration::from_millis(30));
+        assert_eq!(error, Some((1205, "Lock wait timeout exceeded".to_string())));
+    }
+}
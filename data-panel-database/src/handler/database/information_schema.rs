@@ -0,0 +1,166 @@
+//! `information_schema` under the logical table names of the rules.
+//!
+//! A distributed table with other names on the data segments, like `t_order_{segment}`, shows
+//! up in `information_schema` of a backend once per data segment it holds. Queries reading
+//! `information_schema` are run by the mesh, which puts the logical name in place of each of
+//! those names and keeps a single row per object, the one of the first segment listed. Filters
+//! on table names still match the names the backend has.
+
+use std::collections::HashSet;
+
+use bytes::Bytes;
+use mysql::Value;
+use mysql::prelude::Queryable;
+use sqlparser::ast::{SetExpr, Statement, TableFactor};
+
+use crate::discovery::database::rules::{current_rules, RulesVersion};
+use crate::handler::database::lifecycle::BackendConn;
+use crate::handler::database::mysql::nullable_text_result_payloads;
+use crate::handler::database::mysql::rdbc::err_payload;
+use crate::handler::database::variables;
+use crate::session::mysql::SessionContext;
+
+/// Columns holding the name of a table.
+const TABLE_NAME_COLUMNS: [&str; 2] = ["TABLE_NAME", "REFERENCED_TABLE_NAME"];
+
+/// Columns naming the object a row describes, the ones rows of the same object share.
+const IDENTITY_COLUMNS: [&str; 9] = [
+    "TABLE_CATALOG", "TABLE_SCHEMA", "TABLE_NAME", "COLUMN_NAME", "INDEX_NAME", "SEQ_IN_INDEX",
+    "CONSTRAINT_NAME", "ORDINAL_POSITION", "REFERENCED_TABLE_NAME",
+];
+
+/// Whether the query reads a table of `information_schema`.
+fn reads_information_schema(statement: &Statement) -> bool {
+    let select = match statement {
+        Statement::Query(query) => match &query.body {
+            SetExpr::Select(select) => select,
+            _ => return false,
+        },
+        _ => return false,
+    };
+    select.from.iter()
+        .flat_map(|table| std::iter::once(&table.relation).chain(table.joins.iter().map(|join| &join.relation)))
+        .any(|relation| match relation {
+            TableFactor::Table { name, .. } => name.0.len() == 2 && name.0[0].value.eq_ignore_ascii_case("information_schema"),
+            _ => false,
+        })
+}
+
+fn column_indexes(columns: &[String], names: &[&str]) -> Vec<usize> {
+    columns.iter().enumerate()
+        .filter(|(_, column)| names.iter().any(|name| column.eq_ignore_ascii_case(name)))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// `rows` with logical table names, one row per object.
+fn logical_rows(rules: &RulesVersion, columns: &[String], rows: Vec<Vec<Option<String>>>) -> Vec<Vec<Option<String>>> {
+    let table_columns = column_indexes(columns, &TABLE_NAME_COLUMNS);
+    let identity_columns = column_indexes(columns, &IDENTITY_COLUMNS);
+    let mut seen = HashSet::new();
+    let mut logical = Vec::with_capacity(rows.len());
+    for mut row in rows {
+        let mut renamed = false;
+        for index in table_columns.iter() {
+            if let Some(table) = row[*index].as_ref().and_then(|actual| rules.logical_table(actual)) {
+                row[*index] = Some(table);
+                renamed = true;
+            }
+        }
+        let identity: Vec<Option<String>> = if identity_columns.is_empty() {
+            row.clone()
+        } else {
+            identity_columns.iter().map(|index| row[*index].clone()).collect()
+        };
+        // Rows of objects that were not renamed are all kept, as the backend listed them.
+        if !renamed || seen.insert(identity) {
+            logical.push(row);
+        }
+    }
+    logical
+}
+
+/// The column names and the rows of the first result set of `sql`.
+pub fn query_rows(conn: &mut BackendConn, sql: &str) -> mysql::Result<(Vec<String>, Vec<Vec<Option<String>>>)> {
+    let mut result = conn.query_iter(sql)?;
+    let result_set = match result.next_set() {
+        Some(result_set) => result_set?,
+        None => return Ok((vec![], vec![])),
+    };
+    let columns: Vec<String> = result_set.columns().as_ref().iter().map(|column| column.name_str().to_string()).collect();
+    let mut rows = vec![];
+    for row in result_set {
+        let row = row?;
+        rows.push((0..columns.len())
+            .map(|index| match row.as_ref(index) {
+                Some(Value::Bytes(bytes)) => Some(String::from_utf8_lossy(bytes).to_string()),
+                Some(Value::NULL) | None => None,
+                Some(value) => Some(value.as_sql(true)),
+            })
+            .collect());
+    }
+    Ok((columns, rows))
+}
+
+/// Answers queries reading `information_schema` while the rules rename distributed tables.
+pub fn intercept(statement: &Statement, sql: &str, session_ctx: &mut SessionContext) -> Option<Vec<Bytes>> {
+    if !reads_information_schema(statement) {
+        return None;
+    }
+    let rules = current_rules().filter(|rules| rules.has_actual_tables())?;
+    let pinned = session_ctx.has_pinned_conn();
+    let mut conn = match session_ctx.take_pinned_conn() {
+        Some(conn) => conn,
+        None => match variables::connect(session_ctx) {
+            Ok(conn) => conn,
+            Err(e) => return Some(vec![err_payload(e)]),
+        },
+    };
+    let queried = query_rows(&mut conn, sql);
+    if pinned {
+        session_ctx.pin_conn(conn);
+    }
+    match queried {
+        Ok((columns, rows)) => {
+            let rows = logical_rows(&rules, &columns, rows);
+            nullable_text_result_payloads(columns.iter().map(|column| column.as_str()).collect(), rows)
+        }
+        Err(e) => Some(vec![err_payload(e)]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discovery::database::{Cluster, DisAlgorithm, DisRules, DisTable, DisType, Segment};
+    use crate::discovery::database::rules::RulesVersion;
+    use crate::handler::database::parser::sql::mysql::parser;
+
+    use super::{logical_rows, reads_information_schema};
+
+    #[test]
+    fn test_logical_rows() {
+        let url = "jdbc:mysql://localhost:3306/martlet";
+        let cluster = Cluster::builder("martlet")
+            .meta_segment(Segment::new(0, url, "root", "root"), vec![])
+            .data_segment(100, Segment::new(0, url, "root", "root"), vec![])
+            .data_segment(200, Segment::new(0, url, "root", "root"), vec![])
+            .dis_rules(DisRules::builder()
+                .distributed_table("t_order", DisTable::new(vec!["user_id"], DisAlgorithm::new(DisType::HASH, ""), vec![])
+                    .actual_table("t_order_{segment}"))
+                .build())
+            .build()
+            .unwrap();
+        let rules = RulesVersion::new("v1".to_string(), cluster);
+        assert_eq!(rules.logical_table("t_order_200"), Some("t_order".to_string()));
+        assert_eq!(rules.logical_table("t_order_300"), None);
+
+        let statement = parser("SELECT table_name, column_name FROM information_schema.columns WHERE table_schema = 'martlet'".to_string()).unwrap().pop().unwrap();
+        assert!(reads_information_schema(&statement));
+        assert!(!reads_information_schema(&parser("SELECT * FROM t_order".to_string()).unwrap().pop().unwrap()));
+
+        let columns = vec!["TABLE_NAME".to_string(), "COLUMN_NAME".to_string(), "COLUMN_DEFAULT".to_string()];
+        let row = |table: &str, column: &str| vec![Some(table.to_string()), Some(column.to_string()), None];
+        let rows = vec![row("t_order_100", "id"), row("t_order_100", "user_id"), row("t_order_200", "id"), row("t_order_200", "user_id"), row("t_user", "id")];
+        assert_eq!(logical_rows(&rules, &columns, rows), vec![row("t_order", "id"), row("t_order", "user_id"), row("t_user", "id")]);
+    }
+}
@@ -0,0 +1,228 @@
+//! Write-ahead intent log giving retried writes at-most-once semantics.
+//!
+//! A write runs in one transaction together with an insert of its unique statement id into
+//! the intent table. When the connection drops before the outcome is known, the proxy
+//! reconnects and looks the id up: if it is there the write committed and is not replayed.
+//!
+//! The intent table is created on every backend the first time a write goes there, and the
+//! intents older than the retention of `IntentLogConfig` are purged from it, at most once a
+//! minute per backend, after a write.
+
+use std::collections::{HashMap, HashSet};
+use std::process;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use mysql::{Conn, TxOpts};
+use mysql::prelude::Queryable;
+use sqlparser::ast::Statement;
+
+use data_panel_common::config::config::IntentLogConfig;
+
+use crate::handler::database::lifecycle;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteOutcome {
+    affected_rows: u64,
+    last_insert_id: u64,
+    /// The original attempt had already committed, the write was not run again.
+    recovered: bool,
+}
+
+impl WriteOutcome {
+    pub fn get_affected_rows(&self) -> u64 {
+        self.affected_rows
+    }
+
+    pub fn get_last_insert_id(&self) -> u64 {
+        self.last_insert_id
+    }
+
+    pub fn is_recovered(&self) -> bool {
+        self.recovered
+    }
+}
+
+lazy_static! {
+    static ref INTENT_ID_GENERATOR: AtomicU64 = AtomicU64::new(1);
+    static ref INTENT_ID_PREFIX: String = {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+        format!("{}-{}", process::id(), started)
+    };
+    static ref INTENT_TABLES: Mutex<IntentTables> = Mutex::new(IntentTables::default());
+}
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// At most so many intents are deleted by a purge, not to hold locks on the table for long.
+const PURGE_BATCH: u32 = 10000;
+
+/// The backends the intent table is known to exist on, and when it was last purged there.
+#[derive(Debug, Default)]
+struct IntentTables {
+    ready: HashSet<String>,
+    purged: HashMap<String, Instant>,
+}
+
+impl IntentTables {
+    fn is_ready(&self, database_url: &str) -> bool {
+        self.ready.contains(database_url)
+    }
+
+    fn set_ready(&mut self, database_url: &str) {
+        self.ready.insert(database_url.to_string());
+    }
+
+    /// Whether the intents of `database_url` are to be purged at `now`, then taken as purged.
+    fn purge_due(&mut self, database_url: &str, now: Instant) -> bool {
+        match self.purged.get(database_url) {
+            Some(purged) if now.duration_since(*purged) < PURGE_INTERVAL => false,
+            _ => {
+                self.purged.insert(database_url.to_string(), now);
+                true
+            }
+        }
+    }
+}
+
+fn purge_sql(table: &str, retention: u64) -> String {
+    format!("DELETE FROM {} WHERE created_at < NOW() - INTERVAL {} SECOND LIMIT {}", table, retention, PURGE_BATCH)
+}
+
+/// Unique across proxy restarts and instances sharing the backend.
+fn intent_id() -> String {
+    format!("{}-{}", *INTENT_ID_PREFIX, INTENT_ID_GENERATOR.fetch_add(1, Ordering::SeqCst))
+}
+
+/// Statements whose blind replay could apply the same change twice.
+pub fn is_write(statement: &Statement) -> bool {
+    match statement {
+        Statement::Insert { .. } | Statement::Update { .. } | Statement::Delete { .. } => true,
+        _ => false,
+    }
+}
+
+fn is_connection_error(e: &mysql::Error) -> bool {
+    match e {
+        mysql::Error::IoError(_) => true,
+        mysql::Error::DriverError(mysql::DriverError::ConnectionClosed) => true,
+        _ => false,
+    }
+}
+
+fn ensure_intent_table(conn: &mut Conn, database_url: &str, table: &str) -> mysql::Result<()> {
+    if INTENT_TABLES.lock().unwrap().is_ready(database_url) {
+        return Ok(());
+    }
+    conn.query_drop(format!(
+        "CREATE TABLE IF NOT EXISTS {} (\
+            id VARCHAR(64) NOT NULL PRIMARY KEY, \
+            affected_rows BIGINT UNSIGNED NOT NULL, \
+            last_insert_id BIGINT UNSIGNED NOT NULL, \
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP, \
+            KEY (created_at))", table))?;
+    INTENT_TABLES.lock().unwrap().set_ready(database_url);
+    Ok(())
+}
+
+/// Deletes the intents of `database_url` past their retention, when it is time to.
+fn purge_expired(conn: &mut Conn, database_url: &str, config: &IntentLogConfig) {
+    if !INTENT_TABLES.lock().unwrap().purge_due(database_url, Instant::now()) {
+        return;
+    }
+    if let Err(e) = conn.query_drop(purge_sql(&config.get_table(), config.get_retention())) {
+        println!("error on purging intent log of {}; error = {:?}", lifecycle::redact_url(database_url), e);
+    }
+}
+
+fn try_write(database_url: &str, sql: &str, id: &str, config: &IntentLogConfig) -> mysql::Result<WriteOutcome> {
+    let table = config.get_table();
+    let mut conn = lifecycle::connect(database_url)?;
+    ensure_intent_table(&mut conn, database_url, &table)?;
+    let mut tx = conn.start_transaction(TxOpts::default())?;
+    let (affected_rows, last_insert_id) = {
+        let result = tx.query_iter(sql)?;
+        (result.affected_rows(), result.last_insert_id().unwrap_or(0))
+    };
+    // The primary key makes a replay racing a still open original attempt conflict instead of
+    // both committing.
+    tx.exec_drop(format!("INSERT INTO {} (id, affected_rows, last_insert_id) VALUES (?, ?, ?)", table),
+                 (id, affected_rows, last_insert_id))?;
+    tx.commit()?;
+    purge_expired(&mut conn, database_url, config);
+    Ok(WriteOutcome {
+        affected_rows,
+        last_insert_id,
+        recovered: false,
+    })
+}
+
+/// Looks up whether the attempt recorded under `id` committed.
+fn applied_outcome(database_url: &str, id: &str, table: &str) -> mysql::Result<Option<WriteOutcome>> {
+    let mut conn = lifecycle::connect(database_url)?;
+    let row: Option<(u64, u64)> = conn.exec_first(
+        format!("SELECT affected_rows, last_insert_id FROM {} WHERE id = ?", table), (id, ))?;
+    Ok(row.map(|(affected_rows, last_insert_id)| WriteOutcome {
+        affected_rows,
+        last_insert_id,
+        recovered: true,
+    }))
+}
+
+/// Runs `sql` at most once, retrying on connection loss only when the intent log proves
+/// the previous attempt did not commit.
+pub fn execute_write(database_url: &str, sql: &str, config: &IntentLogConfig) -> mysql::Result<WriteOutcome> {
+    let id = intent_id();
+    let table = config.get_table();
+    let mut retries = 0;
+    loop {
+        let e = match try_write(database_url, sql, &id, config) {
+            Ok(outcome) => return Ok(outcome),
+            Err(e) => e,
+        };
+        if !is_connection_error(&e) || retries >= config.get_retries() {
+            return Err(e);
+        }
+        retries += 1;
+        match applied_outcome(database_url, &id, &table) {
+            Ok(Some(outcome)) => return Ok(outcome),
+            Ok(None) => println!("retrying write {} after connection loss; error = {:?}", id, e),
+            Err(check) => {
+                // Unknown outcome, replaying could apply the write twice.
+                println!("error on checking intent log for {}; error = {:?}", id, check);
+                return Err(e);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{intent_id, IntentTables, is_write, purge_sql, PURGE_INTERVAL};
+    use crate::handler::database::parser::sql::mysql::parser;
+
+    #[test]
+    fn test_intent_tables() {
+        let mut tables = IntentTables::default();
+        tables.set_ready("mysql://10.0.0.1:3306/martlet");
+        assert!(tables.is_ready("mysql://10.0.0.1:3306/martlet"));
+        // Another backend needs the table created on it too.
+        assert!(!tables.is_ready("mysql://10.0.0.2:3306/martlet"));
+
+        let now = Instant::now();
+        assert!(tables.purge_due("mysql://10.0.0.1:3306/martlet", now));
+        assert!(!tables.purge_due("mysql://10.0.0.1:3306/martlet", now + Duration::from_secs(1)));
+        assert!(tables.purge_due("mysql://10.0.0.2:3306/martlet", now + Duration::from_secs(1)));
+        assert!(tables.purge_due("mysql://10.0.0.1:3306/martlet", now + PURGE_INTERVAL));
+        assert_eq!(purge_sql("martlet_intent_log", 86400),
+                   "DELETE FROM martlet_intent_log WHERE created_at < NOW() - INTERVAL 86400 SECOND LIMIT 10000");
+
+        assert_ne!(intent_id(), intent_id());
+        assert!(is_write(&parser("UPDATE t_order SET status = 1".to_string()).unwrap().pop().unwrap()));
+        assert!(!is_write(&parser("SELECT 1".to_string()).unwrap().pop().unwrap()));
+    }
+}
@@ -0,0 +1,140 @@
+//! Distributed primary keys, see `KeyGeneratorConfig`.
+//!
+//! An id is 41 bits of milliseconds since the epoch, 10 bits of worker id and 12 bits of
+//! sequence, so ids grow with time and meshes with distinct worker ids never collide. When the
+//! clock goes back or the sequence of a millisecond runs out, ids go on from the last
+//! millisecond handed out rather than repeat one. With the metadata store, ids are reserved a
+//! second ahead in it, so that after a restart ids go on from the last reserved millisecond.
+
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use data_panel_common::config::config::KeyGeneratorConfig;
+
+use crate::common::store::{self, MetadataStore};
+
+const WORKER_ID_BITS: u64 = 10;
+const SEQUENCE_BITS: u64 = 12;
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+/// The namespace of the reserved milliseconds in the metadata store, keyed by worker id.
+const STORE_NAMESPACE: &str = "keygen";
+/// Milliseconds reserved at once, so the store is written about once a second.
+const RESERVED_MILLIS: u64 = 1000;
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+pub struct KeyGenerator {
+    worker_id: u64,
+    epoch: u64,
+    /// The millisecond and the sequence of the last id.
+    last: Mutex<(u64, u64)>,
+    store: Option<Arc<dyn MetadataStore>>,
+    /// The millisecond the store holds ids reserved up to.
+    reserved: AtomicU64,
+}
+
+impl KeyGenerator {
+    pub fn new(worker_id: u32, epoch: u64) -> Self {
+        KeyGenerator {
+            worker_id: worker_id as u64 & ((1 << WORKER_ID_BITS) - 1),
+            epoch,
+            last: Mutex::new((0, 0)),
+            store: None,
+            reserved: AtomicU64::new(0),
+        }
+    }
+
+    /// Reserves ids in `store`, going on after the ones reserved by a previous run.
+    pub fn stored(mut self, store: Arc<dyn MetadataStore>) -> Self {
+        let reserved = store.get(STORE_NAMESPACE, &self.worker_id.to_string()).ok().flatten()
+            .and_then(|reserved| String::from_utf8(reserved).ok())
+            .and_then(|reserved| reserved.parse().ok())
+            .unwrap_or(0);
+        self.last = Mutex::new((reserved, MAX_SEQUENCE));
+        self.reserved = AtomicU64::new(reserved);
+        self.store = Some(store);
+        self
+    }
+
+    fn next_key_at(&self, now: u64) -> u64 {
+        let mut last = self.last.lock().unwrap();
+        let (millis, sequence) = match *last {
+            (last_millis, _) if now > last_millis => (now, 0),
+            (last_millis, last_sequence) if last_sequence < MAX_SEQUENCE => (last_millis, last_sequence + 1),
+            (last_millis, _) => (last_millis + 1, 0),
+        };
+        *last = (millis, sequence);
+        if let Some(store) = self.store.as_ref() {
+            if millis >= self.reserved.load(Ordering::Relaxed) {
+                let reserved = millis + RESERVED_MILLIS;
+                match store.put(STORE_NAMESPACE, &self.worker_id.to_string(), reserved.to_string().as_bytes()) {
+                    Ok(()) => self.reserved.store(reserved, Ordering::Relaxed),
+                    Err(e) => println!("error on reserving the ids of worker {}; error = {}", self.worker_id, e),
+                }
+            }
+        }
+        (millis.saturating_sub(self.epoch) << (WORKER_ID_BITS + SEQUENCE_BITS)) | (self.worker_id << SEQUENCE_BITS) | sequence
+    }
+
+    pub fn next_key(&self) -> u64 {
+        self.next_key_at(now_millis())
+    }
+
+    pub fn next_keys(&self, count: usize) -> Vec<u64> {
+        (0..count).map(|_| self.next_key()).collect()
+    }
+}
+
+lazy_static! {
+    static ref KEY_GENERATOR: RwLock<Option<Arc<KeyGenerator>>> = RwLock::new(None);
+}
+
+pub fn configure_key_generator(config: &KeyGeneratorConfig) {
+    let key_generator = if config.is_enabled() {
+        let key_generator = KeyGenerator::new(config.get_worker_id(), config.get_epoch());
+        Some(Arc::new(match store::metadata_store() {
+            Some(store) => key_generator.stored(store),
+            None => key_generator,
+        }))
+    } else {
+        None
+    };
+    *KEY_GENERATOR.write().unwrap() = key_generator;
+}
+
+pub fn key_generator() -> Option<Arc<KeyGenerator>> {
+    KEY_GENERATOR.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::common::store::MemoryStore;
+
+    use super::{KeyGenerator, MAX_SEQUENCE};
+
+    #[test]
+    fn test_next_key() {
+        let generator = KeyGenerator::new(5, 1_000);
+        let first = generator.next_key_at(2_000);
+        assert_eq!(first, (1_000 << 22) | (5 << 12));
+        assert_eq!(generator.next_key_at(2_000), first + 1);
+        // The clock went back, ids still grow.
+        assert_eq!(generator.next_key_at(1_500), first + 2);
+
+        let keys: Vec<u64> = (0..MAX_SEQUENCE + 1).map(|_| generator.next_key_at(2_000)).collect();
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(keys.last().unwrap() >> 22, 1_001);
+
+        let store = Arc::new(MemoryStore::default());
+        let generator = KeyGenerator::new(5, 1_000).stored(store.clone());
+        generator.next_key_at(2_000);
+        // Restarted with the clock back, ids go on after the reserved second.
+        let restarted = KeyGenerator::new(5, 1_000).stored(store);
+        assert_eq!(restarted.next_key_at(1_500) >> 22, 2_001);
+    }
+}
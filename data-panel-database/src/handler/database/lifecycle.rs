@@ -0,0 +1,367 @@
+//! Backend connection lifecycle hooks.
+//!
+//! Hooks are told whenever a backend connection is acquired for a statement and released
+//! again, so external brokers (orchestrators, credential vault leases) can follow them. An
+//! acquire hook may veto the connection, release hooks are only informed.
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use hyper::{Body, Client, Method, Request};
+use mysql::{Conn, MySqlError, Opts, OptsBuilder};
+
+use data_panel_common::config::config::{BrokerConfig, MeshConfig};
+
+use crate::discovery::database::{backend_tls, secrets, segment_name};
+use crate::discovery::database::tls::tls_pin_monitor;
+use crate::handler::database::breaker;
+use crate::handler::database::pool::{self, ConnectionPool};
+
+#[derive(Debug, Clone)]
+pub struct BackendConnectionEvent {
+    backend: String,
+    connection_id: u64,
+    held: Option<Duration>,
+}
+
+impl BackendConnectionEvent {
+    /// `host:port/db` of the backend, credentials are never passed to hooks.
+    pub fn get_backend(&self) -> String {
+        self.backend.clone()
+    }
+
+    pub fn get_connection_id(&self) -> u64 {
+        self.connection_id
+    }
+
+    /// How long the connection was held, only set on release.
+    pub fn get_held(&self) -> Option<Duration> {
+        self.held
+    }
+
+    fn to_json(&self, event: &str) -> String {
+        serde_json::json!({
+            "event": event,
+            "backend": self.backend,
+            "connection_id": self.connection_id,
+            "held_ms": self.held.map(|held| held.as_millis() as u64),
+        }).to_string()
+    }
+}
+
+pub trait ConnectionLifecycleHook: Send + Sync {
+    /// Returning an error refuses the connection, the message is sent to the client.
+    fn on_backend_acquire(&self, event: &BackendConnectionEvent) -> Result<(), String>;
+
+    fn on_backend_release(&self, event: &BackendConnectionEvent);
+}
+
+lazy_static! {
+    static ref LIFECYCLE_HOOKS: RwLock<Vec<Arc<dyn ConnectionLifecycleHook>>> = RwLock::new(vec![]);
+    /// Connections held right now, by redacted backend url.
+    static ref ACTIVE_CONNECTIONS: DashMap<String, u64> = DashMap::new();
+}
+
+/// How many connections to `database_url` are held right now.
+pub fn active_connections(database_url: &str) -> u64 {
+    ACTIVE_CONNECTIONS.get(&redact_url(database_url)).map_or(0, |active| *active)
+}
+
+pub fn register_lifecycle_hook(hook: Arc<dyn ConnectionLifecycleHook>) {
+    LIFECYCLE_HOOKS.write().unwrap().push(hook);
+}
+
+/// Registers the hooks enabled in the mesh config.
+pub fn register_configured_hooks() {
+    let config = MeshConfig::get_broker_config();
+    if config.is_enabled() {
+        register_lifecycle_hook(Arc::new(BrokerHook::new(config)));
+    }
+}
+
+fn hooks() -> Vec<Arc<dyn ConnectionLifecycleHook>> {
+    LIFECYCLE_HOOKS.read().unwrap().clone()
+}
+
+/// Strips the scheme and credentials off a mysql url.
+pub(crate) fn redact_url(database_url: &str) -> String {
+    let rest = database_url.splitn(2, "://").last().unwrap_or(database_url);
+    rest.rsplitn(2, '@').next().unwrap_or(rest).to_string()
+}
+
+/// A backend connection that tells the hooks when it is released, and goes back to its pool
+/// if it came from one.
+pub struct BackendConn {
+    conn: Option<Conn>,
+    pool: Option<Arc<ConnectionPool>>,
+    event: BackendConnectionEvent,
+    acquired: Instant,
+    reset_on_release: bool,
+}
+
+impl BackendConn {
+    /// Resets the connection before it goes back to its pool, as session state was set on it.
+    pub fn reset_on_release(&mut self) {
+        self.reset_on_release = true;
+    }
+
+    /// Releases a broken connection without handing it back to its pool.
+    pub fn discard(mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.discard();
+        }
+    }
+}
+
+impl fmt::Debug for BackendConn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BackendConn")
+            .field("backend", &self.event.backend)
+            .field("connection_id", &self.event.connection_id)
+            .finish()
+    }
+}
+
+impl Deref for BackendConn {
+    type Target = Conn;
+
+    fn deref(&self) -> &Conn {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for BackendConn {
+    fn deref_mut(&mut self) -> &mut Conn {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for BackendConn {
+    fn drop(&mut self) {
+        let mut event = self.event.clone();
+        event.held = Some(self.acquired.elapsed());
+        if let Some(mut active) = ACTIVE_CONNECTIONS.get_mut(&event.backend) {
+            *active = active.saturating_sub(1);
+        }
+        for hook in hooks() {
+            hook.on_backend_release(&event);
+        }
+        if let (Some(pool), Some(mut conn)) = (self.pool.take(), self.conn.take()) {
+            if self.reset_on_release {
+                if let Err(e) = conn.reset() {
+                    println!("error on resetting backend connection {}; error = {:?}", self.event.connection_id, e);
+                    pool.discard();
+                    return;
+                }
+            }
+            pool.check_in(conn);
+        }
+    }
+}
+
+fn wrap(database_url: &str, conn: Conn, pool: Option<Arc<ConnectionPool>>) -> mysql::Result<BackendConn> {
+    let event = BackendConnectionEvent {
+        backend: redact_url(database_url),
+        connection_id: conn.connection_id() as u64,
+        held: None,
+    };
+    for hook in hooks() {
+        if let Err(message) = hook.on_backend_acquire(&event) {
+            if let Some(pool) = pool {
+                pool.check_in(conn);
+            }
+            return Err(mysql::Error::MySqlError(MySqlError {
+                state: "28000".to_string(),
+                message,
+                code: 1045,
+            }));
+        }
+    }
+    *ACTIVE_CONNECTIONS.entry(event.backend.clone()).or_insert(0) += 1;
+    Ok(BackendConn {
+        conn: Some(conn),
+        pool,
+        event,
+        acquired: Instant::now(),
+        reset_on_release: false,
+    })
+}
+
+/// Wraps `conn` once every hook agreed to its use.
+pub fn acquire(database_url: &str, conn: Conn) -> mysql::Result<BackendConn> {
+    wrap(database_url, conn, None)
+}
+
+/// Like `acquire` for a connection checked out of `pool`, which gets it back on release.
+pub fn acquire_pooled(database_url: &str, conn: Conn, pool: Arc<ConnectionPool>) -> mysql::Result<BackendConn> {
+    wrap(database_url, conn, Some(pool))
+}
+
+/// Opens a connection to `database_url`, over TLS when its segment has TLS options. Refused
+/// credentials drop the cached secrets, so the next connection fetches rotated ones.
+pub fn open(database_url: &str) -> mysql::Result<Conn> {
+    let opts = Opts::from_url(database_url)?;
+    let conn = match backend_tls(database_url) {
+        Some(tls) => {
+            let ssl_opts = tls.ssl_opts().map_err(|message| mysql::Error::MySqlError(MySqlError {
+                state: "08001".to_string(),
+                message,
+                code: 2026,
+            }))?;
+            Conn::new(OptsBuilder::from_opts(opts).ssl_opts(ssl_opts))
+        }
+        None => Conn::new(opts),
+    };
+    if let Err(mysql::Error::MySqlError(e)) = &conn {
+        if e.code == 1045 {
+            secrets::invalidate();
+        }
+    }
+    conn
+}
+
+/// A backend connection through the hooks, from the pool when pooling is enabled. Refused
+/// right away while the backend's circuit breaker is open, or while its segment presents a
+/// certificate outside of its pins.
+pub fn connect(database_url: &str) -> mysql::Result<BackendConn> {
+    if let Some(segment) = segment_name(database_url) {
+        tls_pin_monitor().check_trusted(&segment).map_err(|message| mysql::Error::MySqlError(MySqlError {
+            state: "08004".to_string(),
+            message,
+            code: 2026,
+        }))?;
+    }
+    breaker::admit(database_url)?;
+    let started = Instant::now();
+    let conn = if MeshConfig::get_pool_config().is_enabled() {
+        pool::checkout(database_url)
+    } else {
+        open(database_url).and_then(|conn| acquire(database_url, conn))
+    };
+    // Successes are counted once the statement ran.
+    if conn.is_err() {
+        breaker::record(database_url, &conn, started.elapsed());
+    }
+    conn
+}
+
+/// Posts lifecycle events to an external broker. The broker refuses an acquire by answering
+/// with a non-2xx status, releases are sent without waiting for the answer.
+pub struct BrokerHook {
+    config: BrokerConfig,
+}
+
+impl BrokerHook {
+    pub fn new(config: BrokerConfig) -> Self {
+        BrokerHook {
+            config,
+        }
+    }
+
+    fn request(&self, body: String) -> Result<Request<Body>, String> {
+        Request::builder()
+            .method(Method::POST)
+            .uri(self.config.get_url().as_str())
+            .header("content-type", "application/json")
+            .body(Body::from(body))
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl ConnectionLifecycleHook for BrokerHook {
+    fn on_backend_acquire(&self, event: &BackendConnectionEvent) -> Result<(), String> {
+        let handle = tokio::runtime::Handle::try_current().map_err(|e| e.to_string())?;
+        let request = self.request(event.to_json("on_backend_acquire"))?;
+        let timeout = Duration::from_millis(self.config.get_timeout() as u64);
+        let response = tokio::task::block_in_place(|| {
+            handle.block_on(tokio::time::timeout(timeout, Client::new().request(request)))
+        });
+        match response {
+            Ok(Ok(response)) if response.status().is_success() => Ok(()),
+            Ok(Ok(response)) => Err(format!("backend connection refused by broker: {}", response.status())),
+            Ok(Err(e)) => Err(format!("backend connection broker unavailable: {}", e)),
+            Err(_) => Err(format!("backend connection broker did not answer within {} ms", self.config.get_timeout())),
+        }
+    }
+
+    fn on_backend_release(&self, event: &BackendConnectionEvent) {
+        let handle = match tokio::runtime::Handle::try_current() {
+            Ok(handle) => handle,
+            Err(_) => return,
+        };
+        let request = match self.request(event.to_json("on_backend_release")) {
+            Ok(request) => request,
+            Err(e) => {
+                println!("error on building broker request; error = {:?}", e);
+                return;
+            }
+        };
+        handle.spawn(async move {
+            if let Err(e) = Client::new().request(request).await {
+                println!("error on notifying broker of backend release; error = {:?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use hyper::service::{make_service_fn, service_fn};
+
+    use data_panel_common::config::config::BrokerConfig;
+
+    use super::{BackendConnectionEvent, BrokerHook, ConnectionLifecycleHook, redact_url};
+
+    fn event(connection_id: u64) -> BackendConnectionEvent {
+        BackendConnectionEvent {
+            backend: redact_url("mysql://root:p@ss@10.0.0.1:3306/martlet"),
+            connection_id,
+            held: None,
+        }
+    }
+
+    #[test]
+    fn test_event() {
+        assert_eq!(redact_url("mysql://root:p@ss@10.0.0.1:3306/martlet"), "10.0.0.1:3306/martlet");
+        assert_eq!(redact_url("10.0.0.1:3306/martlet"), "10.0.0.1:3306/martlet");
+
+        let json: serde_json::Value = serde_json::from_str(&event(7).to_json("on_backend_acquire")).unwrap();
+        assert_eq!(json["event"], "on_backend_acquire");
+        assert_eq!(json["backend"], "10.0.0.1:3306/martlet");
+        assert_eq!(json["connection_id"], 7);
+        assert!(json["held_ms"].is_null());
+    }
+
+    /// The broker approves connections with even ids and refuses the others.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_broker_hook() {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                let event: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                let status = if event["connection_id"].as_u64().unwrap() % 2 == 0 {
+                    StatusCode::OK
+                } else {
+                    StatusCode::FORBIDDEN
+                };
+                Ok::<_, Infallible>(Response::builder().status(status).body(Body::empty()).unwrap())
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}/connections", server.local_addr());
+        tokio::spawn(server);
+
+        let hook = BrokerHook::new(BrokerConfig::new(&url, 1000));
+        assert_eq!(hook.on_backend_acquire(&event(2)), Ok(()));
+        assert_eq!(hook.on_backend_acquire(&event(3)), Err("backend connection refused by broker: 403 Forbidden".to_string()));
+
+        let hook = BrokerHook::new(BrokerConfig::new("http://127.0.0.1:1/connections", 1000));
+        assert!(hook.on_backend_acquire(&event(2)).unwrap_err().starts_with("backend connection broker unavailable"));
+    }
+}
@@ -0,0 +1,960 @@
+//! Merging of the per-segment result sets of a fanned out query.
+//!
+//! Ordered results are merged with a streaming k-way merge, only the head row of every segment
+//! is held. Aggregates are folded per group: streaming when the segments return their rows
+//! ordered by the group columns, otherwise in a hash table, and the groups are then sorted.
+//! SELECT DISTINCT groups on every column. When a query reads one distributed table and groups
+//! on all its shard keys, every group is on a single segment and the segment results are
+//! merged as they are, see `MergePlan::group_on_segments`.
+//! The hash table and the sort hold at most `max_groups` groups and `memory_budget` bytes of
+//! rows, beyond them the rows are spilled in sorted runs to temporary files, see `spill`, and
+//! merged back from disk.
+//!
+//! LIMIT n OFFSET m applies to the merged rows: every segment is sent the query with LIMIT n+m
+//! and no OFFSET, see `MergePlan::segment_query`, since any of its first n+m rows may be among
+//! the rows returned, and the merge skips the first m. A deep page then costs the segments
+//! n+m rows each for n rows returned, the merges count the rows they read from the segments and
+//! the rows they return, see `pagination_stats`. Grouped queries send no LIMIT at all, every
+//! group has to be folded before the page is known.
+//!
+//! Values are compared as numbers when both sides are numeric and byte-wise otherwise, the
+//! collation of the column is not taken into account.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+
+use mysql::{Row, Value};
+use sqlparser::ast::{Expr, FunctionArg, Query, Select, SelectItem, SetExpr, TableFactor, Value as SQLValue};
+
+use data_panel_common::common::Error;
+use data_panel_common::config::config::MergeConfig;
+
+use crate::discovery::database::rules::RulesVersion;
+use crate::handler::database::spill::{row_size, SpillRun};
+
+pub type MergeRow = Vec<Value>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MergeError {
+    /// The query needs a merge this module cannot do, e.g. AVG across segments.
+    Unsupported(String),
+    /// Rows could not be spilled to disk or read back.
+    Spill(String),
+}
+
+impl From<MergeError> for Error {
+    fn from(e: MergeError) -> Self {
+        match e {
+            MergeError::Unsupported(message) => Error::Protocol(message),
+            MergeError::Spill(message) => Error::Backend { code: 1105, state: "HY000".to_string(), message },
+        }
+    }
+}
+
+fn spill_error(e: io::Error) -> MergeError {
+    MergeError::Spill(e.to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SortKey {
+    column: usize,
+    asc: bool,
+}
+
+impl SortKey {
+    pub fn new(column: usize, asc: bool) -> Self {
+        SortKey {
+            column,
+            asc,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    Count,
+    Sum,
+    Min,
+    Max,
+}
+
+/// How the segment results of one query are merged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergePlan {
+    order_by: Vec<SortKey>,
+    group_by: Vec<usize>,
+    aggregates: Vec<(usize, Aggregate)>,
+    /// The segments return whole groups, no row of a group is on another segment.
+    segment_groups: bool,
+    /// The query filters its groups with HAVING.
+    having: bool,
+    offset: u64,
+    limit: Option<u64>,
+}
+
+impl MergePlan {
+    pub fn new() -> Self {
+        MergePlan {
+            order_by: vec![],
+            group_by: vec![],
+            aggregates: vec![],
+            segment_groups: false,
+            having: false,
+            offset: 0,
+            limit: None,
+        }
+    }
+
+    pub fn order_by(mut self, order_by: Vec<SortKey>) -> Self {
+        self.order_by = order_by;
+        self
+    }
+
+    pub fn group_by(mut self, group_by: Vec<usize>) -> Self {
+        self.group_by = group_by;
+        self
+    }
+
+    pub fn aggregate(mut self, column: usize, aggregate: Aggregate) -> Self {
+        self.aggregates.push((column, aggregate));
+        self
+    }
+
+    pub fn limit(mut self, offset: u64, limit: Option<u64>) -> Self {
+        self.offset = offset;
+        self.limit = limit;
+        self
+    }
+
+    pub fn segment_groups(mut self, segment_groups: bool) -> Self {
+        self.segment_groups = segment_groups;
+        self
+    }
+
+    pub fn having(mut self, having: bool) -> Self {
+        self.having = having;
+        self
+    }
+
+    /// The plan of `query`, `columns` being the column names of the segment results.
+    pub fn from_query(query: &Query, columns: &[String]) -> Result<Self, MergeError> {
+        let select = match &query.body {
+            SetExpr::Select(select) => select,
+            _ => return Err(MergeError::Unsupported("only plain SELECT results can be merged".to_string())),
+        };
+        let mut plan = MergePlan::new();
+        let has_wildcard = select.projection.iter()
+            .any(|item| matches!(item, SelectItem::Wildcard | SelectItem::QualifiedWildcard(_)));
+        for (column, item) in select.projection.iter().enumerate() {
+            let expr = match item {
+                SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => expr,
+                _ => continue,
+            };
+            if let Some(aggregate) = aggregate_of(expr)? {
+                if has_wildcard {
+                    return Err(MergeError::Unsupported("aggregates next to * cannot be merged".to_string()));
+                }
+                plan.aggregates.push((column, aggregate));
+            }
+        }
+        for expr in select.group_by.iter() {
+            plan.group_by.push(resolve_column(expr, &select.projection, columns)?);
+        }
+        plan.having = select.having.is_some();
+        if select.distinct && !plan.is_grouping() {
+            let width = if has_wildcard { columns.len() } else { select.projection.len() };
+            if width == 0 {
+                return Err(MergeError::Unsupported("DISTINCT * needs the result columns to be merged".to_string()));
+            }
+            plan.group_by = (0..width).collect();
+        }
+        for order_by in query.order_by.iter() {
+            let column = resolve_column(&order_by.expr, &select.projection, columns)?;
+            plan.order_by.push(SortKey::new(column, order_by.asc.unwrap_or(true)));
+        }
+        if let Some(limit) = &query.limit {
+            plan.limit = Some(number_of(limit)?);
+        }
+        if let Some(offset) = &query.offset {
+            plan.offset = number_of(&offset.value)?;
+        }
+        Ok(plan)
+    }
+
+    pub fn get_order_by(&self) -> &[SortKey] {
+        &self.order_by
+    }
+
+    pub fn get_group_by(&self) -> &[usize] {
+        &self.group_by
+    }
+
+    pub fn get_aggregates(&self) -> &[(usize, Aggregate)] {
+        &self.aggregates
+    }
+
+    pub fn is_grouping(&self) -> bool {
+        !self.group_by.is_empty() || !self.aggregates.is_empty()
+    }
+
+    /// Whether the merge folds the groups of the segments together.
+    pub fn is_regrouping(&self) -> bool {
+        self.is_grouping() && !self.segment_groups
+    }
+
+    /// Leaves the grouping to the segments when `query` reads a single distributed table of
+    /// `rules` and groups, or selects DISTINCT, on all its shard keys: no group then spans two
+    /// segments, and the groups are merged, and limited, like rows.
+    pub fn group_on_segments(mut self, query: &Query, rules: &RulesVersion) -> Self {
+        if self.group_by.is_empty() {
+            return self;
+        }
+        let select = match &query.body {
+            SetExpr::Select(select) => select,
+            _ => return self,
+        };
+        if let Some(dis_keys) = single_table(select).map(|table| rules.dis_keys(&table)) {
+            self.segment_groups = groups_on_keys(select, &dis_keys);
+        }
+        self
+    }
+
+    /// Refuses HAVING on groups the merge folds: every segment would filter its partial
+    /// groups, dropping the ones whose total passes.
+    pub fn check_having(&self) -> Result<(), MergeError> {
+        if self.having && self.is_regrouping() {
+            return Err(MergeError::Unsupported("HAVING cannot be applied to groups spanning data segments; \
+                                                group on the shard keys or filter in WHERE".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Whether the merge skips or stops at some rows.
+    pub fn is_paginated(&self) -> bool {
+        self.offset > 0 || self.limit.is_some()
+    }
+
+    /// The LIMIT to send to every segment, none when each segment must return every row.
+    pub fn segment_limit(&self) -> Option<u64> {
+        if self.is_regrouping() {
+            return None;
+        }
+        self.limit.map(|limit| self.offset + limit)
+    }
+
+    /// `query` as sent to every segment: LIMIT `segment_limit` and no OFFSET, the merge applies
+    /// the OFFSET of `query`.
+    pub fn segment_query(&self, query: &Query) -> Query {
+        let mut query = query.clone();
+        query.limit = self.segment_limit().map(|limit| Expr::Value(SQLValue::Number(limit.to_string(), false)));
+        query.offset = None;
+        query
+    }
+
+    /// Whether the segments return the groups contiguously, so grouping can stream.
+    fn groups_are_ordered(&self) -> bool {
+        !self.group_by.is_empty()
+            && self.order_by.len() == self.group_by.len()
+            && self.order_by.iter().all(|key| self.group_by.contains(&key.column))
+    }
+}
+
+/// The table `select` reads, when it reads only one.
+fn single_table(select: &Select) -> Option<String> {
+    match select.from.as_slice() {
+        [from] if from.joins.is_empty() => match &from.relation {
+            TableFactor::Table { name, .. } => name.0.last().map(|ident| ident.value.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Whether `select` groups on every one of `dis_keys`, by GROUP BY or else by DISTINCT.
+fn groups_on_keys(select: &Select, dis_keys: &[String]) -> bool {
+    let group_exprs: Vec<&Expr> = if !select.group_by.is_empty() {
+        select.group_by.iter().collect()
+    } else if select.distinct {
+        select.projection.iter()
+            .filter_map(|item| match item {
+                SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => Some(expr),
+                _ => None,
+            })
+            .collect()
+    } else {
+        return false;
+    };
+    let grouped: Vec<String> = group_exprs.into_iter().filter_map(column_name).collect();
+    !dis_keys.is_empty() && dis_keys.iter().all(|key| grouped.contains(&key.to_lowercase()))
+}
+
+fn aggregate_of(expr: &Expr) -> Result<Option<Aggregate>, MergeError> {
+    let function = match expr {
+        Expr::Function(function) => function,
+        _ => return Ok(None),
+    };
+    let name = function.name.to_string().to_uppercase();
+    let aggregate = match name.as_str() {
+        "COUNT" => Aggregate::Count,
+        "SUM" => Aggregate::Sum,
+        "MIN" => Aggregate::Min,
+        "MAX" => Aggregate::Max,
+        "AVG" | "GROUP_CONCAT" | "STD" | "STDDEV" | "VARIANCE" => {
+            return Err(MergeError::Unsupported(format!("{} cannot be merged across segments", name)));
+        }
+        _ => return Ok(None),
+    };
+    if function.distinct && aggregate != Aggregate::Min && aggregate != Aggregate::Max {
+        return Err(MergeError::Unsupported(format!("{}(DISTINCT) cannot be merged across segments", name)));
+    }
+    if function.args.iter().any(|arg| matches!(arg, FunctionArg::Named { .. })) {
+        return Err(MergeError::Unsupported(format!("{} with named arguments", name)));
+    }
+    Ok(Some(aggregate))
+}
+
+fn column_name(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Identifier(ident) => Some(ident.value.to_lowercase()),
+        Expr::CompoundIdentifier(idents) => idents.last().map(|ident| ident.value.to_lowercase()),
+        _ => None,
+    }
+}
+
+/// The result column `expr` refers to: an ordinal, an alias, a selected expression or the
+/// name of a result column.
+fn resolve_column(expr: &Expr, projection: &[SelectItem], columns: &[String]) -> Result<usize, MergeError> {
+    if let Expr::Value(SQLValue::Number(ordinal, _)) = expr {
+        return match ordinal.parse::<usize>() {
+            Ok(ordinal) if ordinal >= 1 && ordinal <= columns.len().max(projection.len()) => Ok(ordinal - 1),
+            _ => Err(MergeError::Unsupported(format!("unknown column {}", ordinal))),
+        };
+    }
+    let text = expr.to_string();
+    // Behind a wildcard the projection no longer tells the result column.
+    let expanded = projection.iter().position(|item| matches!(item, SelectItem::Wildcard | SelectItem::QualifiedWildcard(_)));
+    for (column, item) in projection.iter().enumerate().take(expanded.unwrap_or(projection.len())) {
+        let matched = match item {
+            SelectItem::ExprWithAlias { expr: selected, alias } => {
+                alias.value.eq_ignore_ascii_case(&text) || selected.to_string() == text
+            }
+            SelectItem::UnnamedExpr(selected) => selected.to_string() == text,
+            _ => false,
+        };
+        if matched {
+            return Ok(column);
+        }
+    }
+    if let Some(name) = column_name(expr) {
+        if let Some(column) = columns.iter().position(|column| column.to_lowercase() == name) {
+            return Ok(column);
+        }
+    }
+    Err(MergeError::Unsupported(format!("{} must be selected to be merged", text)))
+}
+
+fn number_of(expr: &Expr) -> Result<u64, MergeError> {
+    match expr {
+        Expr::Value(SQLValue::Number(number, _)) => number.parse::<u64>()
+            .map_err(|_| MergeError::Unsupported(format!("invalid row count {}", number))),
+        _ => Err(MergeError::Unsupported(format!("row count {} is not a number", expr))),
+    }
+}
+
+/// A value as far as comparing and summing goes.
+#[derive(Debug, Clone, PartialEq)]
+enum Scalar {
+    Null,
+    Int(i128),
+    Float(f64),
+    Bytes(Vec<u8>),
+}
+
+fn scalar(value: &Value) -> Scalar {
+    let text = match value {
+        Value::NULL => return Scalar::Null,
+        Value::Int(i) => return Scalar::Int(*i as i128),
+        Value::UInt(u) => return Scalar::Int(*u as i128),
+        Value::Double(d) => return Scalar::Float(*d),
+        Value::Bytes(bytes) => match std::str::from_utf8(bytes) {
+            Ok(text) => text.to_string(),
+            Err(_) => return Scalar::Bytes(bytes.clone()),
+        },
+        value => value.as_sql(false).trim_matches('\'').to_string(),
+    };
+    if let Ok(i) = text.parse::<i128>() {
+        Scalar::Int(i)
+    } else if let Ok(f) = text.parse::<f64>().map_err(|_| ()).and_then(|f| if f.is_finite() { Ok(f) } else { Err(()) }) {
+        Scalar::Float(f)
+    } else {
+        Scalar::Bytes(text.into_bytes())
+    }
+}
+
+/// MySQL order: NULL first, then numbers by value, then the rest byte-wise.
+pub fn compare_values(a: &Value, b: &Value) -> Ordering {
+    match (scalar(a), scalar(b)) {
+        (Scalar::Null, Scalar::Null) => Ordering::Equal,
+        (Scalar::Null, _) => Ordering::Less,
+        (_, Scalar::Null) => Ordering::Greater,
+        (Scalar::Int(a), Scalar::Int(b)) => a.cmp(&b),
+        (Scalar::Int(a), Scalar::Float(b)) => (a as f64).partial_cmp(&b).unwrap_or(Ordering::Equal),
+        (Scalar::Float(a), Scalar::Int(b)) => a.partial_cmp(&(b as f64)).unwrap_or(Ordering::Equal),
+        (Scalar::Float(a), Scalar::Float(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        (Scalar::Bytes(a), Scalar::Bytes(b)) => a.cmp(&b),
+        (Scalar::Bytes(_), _) => Ordering::Greater,
+        (_, Scalar::Bytes(_)) => Ordering::Less,
+    }
+}
+
+fn compare_rows(a: &MergeRow, b: &MergeRow, keys: &[SortKey]) -> Ordering {
+    for key in keys {
+        let ordering = compare_values(&a[key.column], &b[key.column]);
+        let ordering = if key.asc { ordering } else { ordering.reverse() };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+struct HeapEntry {
+    row: MergeRow,
+    source: usize,
+    keys: Rc<Vec<SortKey>>,
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry {}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HeapEntry {
+    // Reversed, `BinaryHeap` pops the greatest and the merge wants the smallest row. Equal rows
+    // come out in segment order.
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_rows(&other.row, &self.row, &self.keys).then_with(|| other.source.cmp(&self.source))
+    }
+}
+
+/// Merges segment results that are each ordered by `keys`.
+pub struct OrderedMerge<I: Iterator<Item = MergeRow>> {
+    sources: Vec<I>,
+    heap: BinaryHeap<HeapEntry>,
+    keys: Rc<Vec<SortKey>>,
+}
+
+impl<I: Iterator<Item = MergeRow>> OrderedMerge<I> {
+    pub fn new(mut sources: Vec<I>, keys: Vec<SortKey>) -> Self {
+        let keys = Rc::new(keys);
+        let mut heap = BinaryHeap::with_capacity(sources.len());
+        for (source, rows) in sources.iter_mut().enumerate() {
+            if let Some(row) = rows.next() {
+                heap.push(HeapEntry { row, source, keys: keys.clone() });
+            }
+        }
+        OrderedMerge {
+            sources,
+            heap,
+            keys,
+        }
+    }
+}
+
+impl<I: Iterator<Item = MergeRow>> Iterator for OrderedMerge<I> {
+    type Item = MergeRow;
+
+    fn next(&mut self) -> Option<MergeRow> {
+        let HeapEntry { row, source, .. } = self.heap.pop()?;
+        if let Some(next) = self.sources[source].next() {
+            self.heap.push(HeapEntry { row: next, source, keys: self.keys.clone() });
+        }
+        Some(row)
+    }
+}
+
+fn fold(aggregate: Aggregate, merged: &Value, partial: &Value) -> Value {
+    match aggregate {
+        Aggregate::Min | Aggregate::Max => {
+            // MIN and MAX skip NULL, which only stays when every segment had none.
+            let ordering = match (merged, partial) {
+                (Value::NULL, _) => return partial.clone(),
+                (_, Value::NULL) => return merged.clone(),
+                _ => compare_values(partial, merged),
+            };
+            let replace = if aggregate == Aggregate::Min { ordering == Ordering::Less } else { ordering == Ordering::Greater };
+            if replace { partial.clone() } else { merged.clone() }
+        }
+        Aggregate::Count | Aggregate::Sum => match (scalar(merged), scalar(partial)) {
+            (Scalar::Null, _) => partial.clone(),
+            (_, Scalar::Null) => merged.clone(),
+            (Scalar::Int(a), Scalar::Int(b)) => {
+                let sum = a + b;
+                if sum >= i64::MIN as i128 && sum <= i64::MAX as i128 {
+                    Value::Int(sum as i64)
+                } else {
+                    Value::Bytes(sum.to_string().into_bytes())
+                }
+            }
+            (Scalar::Int(a), Scalar::Float(b)) => Value::Double(a as f64 + b),
+            (Scalar::Float(a), Scalar::Int(b)) => Value::Double(a + b as f64),
+            (Scalar::Float(a), Scalar::Float(b)) => Value::Double(a + b),
+            _ => merged.clone(),
+        },
+    }
+}
+
+fn fold_row(merged: &mut MergeRow, partial: &MergeRow, aggregates: &[(usize, Aggregate)]) {
+    for (column, aggregate) in aggregates {
+        merged[*column] = fold(*aggregate, &merged[*column], &partial[*column]);
+    }
+}
+
+fn same_group(a: &MergeRow, b: &MergeRow, group_by: &[usize]) -> bool {
+    group_by.iter().all(|column| compare_values(&a[*column], &b[*column]) == Ordering::Equal)
+}
+
+/// Folds the partial aggregates of adjacent rows of the same group, for input ordered by the
+/// group columns. Holds one group at a time.
+pub struct StreamingGroups<I: Iterator<Item = MergeRow>> {
+    rows: I,
+    group_by: Vec<usize>,
+    aggregates: Vec<(usize, Aggregate)>,
+    pending: Option<MergeRow>,
+}
+
+impl<I: Iterator<Item = MergeRow>> StreamingGroups<I> {
+    pub fn new(rows: I, group_by: Vec<usize>, aggregates: Vec<(usize, Aggregate)>) -> Self {
+        StreamingGroups {
+            rows,
+            group_by,
+            aggregates,
+            pending: None,
+        }
+    }
+}
+
+impl<I: Iterator<Item = MergeRow>> Iterator for StreamingGroups<I> {
+    type Item = MergeRow;
+
+    fn next(&mut self) -> Option<MergeRow> {
+        let mut group = match self.pending.take() {
+            Some(group) => group,
+            None => self.rows.next()?,
+        };
+        for row in &mut self.rows {
+            if same_group(&group, &row, &self.group_by) {
+                fold_row(&mut group, &row, &self.aggregates);
+            } else {
+                self.pending = Some(row);
+                break;
+            }
+        }
+        Some(group)
+    }
+}
+
+fn group_key(row: &MergeRow, group_by: &[usize]) -> Vec<u8> {
+    let mut key = vec![];
+    for column in group_by {
+        match scalar(&row[*column]) {
+            Scalar::Null => key.push(0),
+            Scalar::Int(i) => key.extend(format!("\u{1}{}", i).into_bytes()),
+            Scalar::Float(f) => key.extend(format!("\u{1}{}", f).into_bytes()),
+            Scalar::Bytes(bytes) => {
+                key.push(2);
+                key.extend(bytes);
+            }
+        }
+        key.push(0xff);
+    }
+    key
+}
+
+/// Sorts `rows` by `keys` and spills them to a run of `dir`, leaving `rows` empty.
+fn spill_sorted(rows: &mut Vec<MergeRow>, keys: &[SortKey], dir: &Path) -> Result<SpillRun, MergeError> {
+    rows.sort_by(|a, b| compare_rows(a, b, keys));
+    let run = SpillRun::write(dir, rows.iter()).map_err(spill_error)?;
+    rows.clear();
+    Ok(run)
+}
+
+/// The rows of the sorted `runs` and of `rows`, merged by `keys`.
+fn merge_runs(runs: Vec<SpillRun>, mut rows: Vec<MergeRow>, keys: &[SortKey]) -> Result<OrderedMerge<Box<dyn Iterator<Item = MergeRow>>>, MergeError> {
+    rows.sort_by(|a, b| compare_rows(a, b, keys));
+    let mut sources: Vec<Box<dyn Iterator<Item = MergeRow>>> = vec![];
+    for run in runs {
+        sources.push(Box::new(run.into_rows().map_err(spill_error)?));
+    }
+    // Last, so equal rows keep the order they were pushed in.
+    sources.push(Box::new(rows.into_iter()));
+    Ok(OrderedMerge::new(sources, keys.to_vec()))
+}
+
+/// Sorts rows by `keys` within the memory budget of a merge, the rows beyond it sorted in runs
+/// spilled to disk.
+pub struct ExternalSort {
+    keys: Vec<SortKey>,
+    memory_budget: usize,
+    spill_dir: PathBuf,
+    rows: Vec<MergeRow>,
+    size: usize,
+    runs: Vec<SpillRun>,
+}
+
+impl ExternalSort {
+    pub fn new(keys: Vec<SortKey>, config: &MergeConfig) -> Self {
+        ExternalSort {
+            keys,
+            memory_budget: config.get_memory_budget(),
+            spill_dir: PathBuf::from(config.get_spill_dir()),
+            rows: vec![],
+            size: 0,
+            runs: vec![],
+        }
+    }
+
+    pub fn push(&mut self, row: MergeRow) -> Result<(), MergeError> {
+        self.size += row_size(&row);
+        self.rows.push(row);
+        if self.size > self.memory_budget {
+            self.runs.push(spill_sorted(&mut self.rows, &self.keys, &self.spill_dir)?);
+            self.size = 0;
+        }
+        Ok(())
+    }
+
+    /// The runs spilled so far.
+    pub fn get_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// The rows pushed, sorted, equal ones in the order they were pushed.
+    pub fn finish(self) -> Result<Box<dyn Iterator<Item = MergeRow>>, MergeError> {
+        if self.runs.is_empty() {
+            let (mut rows, keys) = (self.rows, self.keys);
+            rows.sort_by(|a, b| compare_rows(a, b, &keys));
+            return Ok(Box::new(rows.into_iter()));
+        }
+        Ok(Box::new(merge_runs(self.runs, self.rows, &self.keys)?))
+    }
+}
+
+/// Folds the partial aggregates of every group in a hash table. Beyond the `max_groups` and the
+/// memory budget of `config`, the groups held are spilled in a run ordered by the group
+/// columns, and the runs are merged back and folded again as they stream.
+pub fn hash_groups<I: Iterator<Item = MergeRow>>(rows: I, group_by: &[usize], aggregates: &[(usize, Aggregate)],
+                                                 config: &MergeConfig) -> Result<Box<dyn Iterator<Item = MergeRow>>, MergeError> {
+    let keys: Vec<SortKey> = group_by.iter().map(|column| SortKey::new(*column, true)).collect();
+    let (max_groups, memory_budget) = (config.get_max_groups(), config.get_memory_budget());
+    let spill_dir = PathBuf::from(config.get_spill_dir());
+    let mut index: HashMap<Vec<u8>, usize> = HashMap::new();
+    let mut groups: Vec<MergeRow> = vec![];
+    let mut size = 0;
+    let mut runs = vec![];
+    for row in rows {
+        let key = group_key(&row, group_by);
+        match index.get(&key) {
+            Some(group) => fold_row(&mut groups[*group], &row, aggregates),
+            None => {
+                if groups.len() >= max_groups || size >= memory_budget {
+                    runs.push(spill_sorted(&mut groups, &keys, &spill_dir)?);
+                    index.clear();
+                    size = 0;
+                }
+                size += row_size(&row) + key.len();
+                index.insert(key, groups.len());
+                groups.push(row);
+            }
+        }
+    }
+    if runs.is_empty() {
+        return Ok(Box::new(groups.into_iter()));
+    }
+    let merged = merge_runs(runs, groups, &keys)?;
+    Ok(Box::new(StreamingGroups::new(merged, group_by.to_vec(), aggregates.to_vec())))
+}
+
+static PAGINATED_MERGES: AtomicU64 = AtomicU64::new(0);
+static SEGMENT_ROWS: AtomicU64 = AtomicU64::new(0);
+static RETURNED_ROWS: AtomicU64 = AtomicU64::new(0);
+
+/// The cost of the paginated merges since the start.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaginationStats {
+    merges: u64,
+    segment_rows: u64,
+    returned_rows: u64,
+}
+
+impl PaginationStats {
+    pub fn get_merges(&self) -> u64 {
+        self.merges
+    }
+
+    /// Rows the merges read from the segments.
+    pub fn get_segment_rows(&self) -> u64 {
+        self.segment_rows
+    }
+
+    /// Rows the merges returned to the clients.
+    pub fn get_returned_rows(&self) -> u64 {
+        self.returned_rows
+    }
+}
+
+pub fn pagination_stats() -> PaginationStats {
+    PaginationStats {
+        merges: PAGINATED_MERGES.load(AtomicOrdering::Relaxed),
+        segment_rows: SEGMENT_ROWS.load(AtomicOrdering::Relaxed),
+        returned_rows: RETURNED_ROWS.load(AtomicOrdering::Relaxed),
+    }
+}
+
+fn count_segment_row(_: &MergeRow) {
+    SEGMENT_ROWS.fetch_add(1, AtomicOrdering::Relaxed);
+}
+
+fn count_returned_row(_: &MergeRow) {
+    RETURNED_ROWS.fetch_add(1, AtomicOrdering::Relaxed);
+}
+
+/// Merges the segment results per `plan`. Ordered and streaming grouped merges yield rows as
+/// the segments do, hashed grouping reads every row first. Paginated merges are metered, see
+/// `pagination_stats`.
+pub fn merge<I>(plan: &MergePlan, sources: Vec<I>, config: &MergeConfig) -> Result<Box<dyn Iterator<Item = MergeRow>>, MergeError>
+    where I: Iterator<Item = MergeRow> + 'static {
+    plan.check_having()?;
+    if !plan.is_paginated() {
+        return merge_rows(plan, sources, config);
+    }
+    PAGINATED_MERGES.fetch_add(1, AtomicOrdering::Relaxed);
+    let sources = sources.into_iter().map(|source| source.inspect(count_segment_row as fn(&MergeRow))).collect();
+    Ok(Box::new(merge_rows(plan, sources, config)?.inspect(count_returned_row)))
+}
+
+fn merge_rows<I>(plan: &MergePlan, sources: Vec<I>, config: &MergeConfig) -> Result<Box<dyn Iterator<Item = MergeRow>>, MergeError>
+    where I: Iterator<Item = MergeRow> + 'static {
+    let offset = plan.offset as usize;
+    let limit = plan.limit.map_or(usize::MAX, |limit| limit as usize);
+    if !plan.is_regrouping() {
+        let merged: Box<dyn Iterator<Item = MergeRow>> = if plan.order_by.is_empty() {
+            Box::new(sources.into_iter().flatten())
+        } else {
+            Box::new(OrderedMerge::new(sources, plan.order_by.clone()))
+        };
+        return Ok(Box::new(merged.skip(offset).take(limit)));
+    }
+    if plan.groups_are_ordered() {
+        let merged = OrderedMerge::new(sources, plan.order_by.clone());
+        let groups = StreamingGroups::new(merged, plan.group_by.clone(), plan.aggregates.clone());
+        return Ok(Box::new(groups.skip(offset).take(limit)));
+    }
+    let groups = hash_groups(sources.into_iter().flatten(), &plan.group_by, &plan.aggregates, config)?;
+    if plan.order_by.is_empty() {
+        return Ok(Box::new(groups.skip(offset).take(limit)));
+    }
+    let mut sorted = ExternalSort::new(plan.order_by.clone(), config);
+    for group in groups {
+        sorted.push(group)?;
+    }
+    Ok(Box::new(sorted.finish()?.skip(offset).take(limit)))
+}
+
+/// The rows of a fan-out, one source per segment.
+pub fn sources(outputs: Vec<Vec<Row>>) -> Vec<std::vec::IntoIter<MergeRow>> {
+    outputs.into_iter()
+        .map(|rows| rows.into_iter().map(Row::unwrap).collect::<Vec<MergeRow>>().into_iter())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use mysql::Value;
+    use sqlparser::ast::Statement;
+
+    use data_panel_common::config::config::MergeConfig;
+
+    use crate::discovery::database::{Cluster, DisAlgorithm, DisRules, DisTable, DisType, Segment};
+    use crate::discovery::database::rules::RulesVersion;
+    use crate::handler::database::merge::{Aggregate, ExternalSort, merge, MergeError, MergePlan, MergeRow, pagination_stats, SortKey};
+    use crate::handler::database::parser::sql::mysql::parser;
+
+    fn row(values: &[&str]) -> MergeRow {
+        values.iter().map(|value| if *value == "NULL" { Value::NULL } else { Value::Bytes(value.as_bytes().to_vec()) }).collect()
+    }
+
+    fn rows(values: &[&[&str]]) -> std::vec::IntoIter<MergeRow> {
+        values.iter().map(|values| row(values)).collect::<Vec<_>>().into_iter()
+    }
+
+    #[test]
+    fn test_ordered_merge_with_limit() {
+        let plan = MergePlan::new().order_by(vec![SortKey::new(0, false)]).limit(1, Some(3));
+        assert_eq!(plan.segment_limit(), Some(4));
+        let sources = vec![rows(&[&["9"], &["5"], &["1"]]), rows(&[&["10"], &["6"]]), rows(&[&["7"], &["NULL"]])];
+        let before = pagination_stats();
+        let merged: Vec<MergeRow> = merge(&plan, sources, &MergeConfig::new(16, 0, "")).unwrap().collect();
+        assert_eq!(merged, vec![row(&["9"]), row(&["7"]), row(&["6"])]);
+        let after = pagination_stats();
+        assert!(after.get_merges() > before.get_merges());
+        assert!(after.get_segment_rows() >= before.get_segment_rows() + 5);
+        assert!(after.get_returned_rows() >= before.get_returned_rows() + 3);
+    }
+
+    #[test]
+    fn test_segment_query() {
+        let sql = "SELECT id FROM t_order WHERE id IN (SELECT order_id FROM t_item LIMIT 3) ORDER BY id LIMIT 10 OFFSET 20";
+        let query = match parser(sql.to_string()).unwrap().pop().unwrap() {
+            Statement::Query(query) => query,
+            _ => unreachable!(),
+        };
+        let plan = MergePlan::from_query(&query, &[]).unwrap();
+        assert_eq!(plan.segment_query(&query).to_string(),
+                   "SELECT id FROM t_order WHERE id IN (SELECT order_id FROM t_item LIMIT 3) ORDER BY id LIMIT 30");
+
+        let query = match parser("SELECT status, COUNT(*) FROM t_order GROUP BY status LIMIT 10 OFFSET 20".to_string()).unwrap().pop().unwrap() {
+            Statement::Query(query) => query,
+            _ => unreachable!(),
+        };
+        let plan = MergePlan::from_query(&query, &[]).unwrap();
+        assert_eq!(plan.segment_query(&query).to_string(), "SELECT status, COUNT(*) FROM t_order GROUP BY status");
+    }
+
+    #[test]
+    fn test_grouped_merge() {
+        // Ordered by the group column: streamed.
+        let plan = MergePlan::new().order_by(vec![SortKey::new(0, true)]).group_by(vec![0])
+            .aggregate(1, Aggregate::Count).aggregate(2, Aggregate::Max);
+        let sources = vec![rows(&[&["a", "2", "5"], &["b", "1", "NULL"]]), rows(&[&["a", "3", "7"], &["c", "1", "1"]])];
+        let merged: Vec<MergeRow> = merge(&plan, sources, &MergeConfig::new(1, 0, "")).unwrap().collect();
+        assert_eq!(merged, vec![
+            vec![Value::Bytes(b"a".to_vec()), Value::Int(5), Value::Bytes(b"7".to_vec())],
+            row(&["b", "1", "NULL"]),
+            row(&["c", "1", "1"]),
+        ]);
+
+        // Ordered by the aggregate: hashed, within the group bound.
+        let plan = MergePlan::new().order_by(vec![SortKey::new(1, false)]).group_by(vec![0]).aggregate(1, Aggregate::Sum);
+        let sources = vec![rows(&[&["a", "1"], &["b", "4"]]), rows(&[&["a", "5"]])];
+        let merged: Vec<MergeRow> = merge(&plan, sources, &MergeConfig::new(2, 0, "")).unwrap().collect();
+        assert_eq!(merged, vec![vec![Value::Bytes(b"a".to_vec()), Value::Int(6)], row(&["b", "4"])]);
+
+        // Beyond the group bound: spilled and folded again from disk.
+        let sources = vec![rows(&[&["a", "1"], &["b", "4"], &["c", "2"]]), rows(&[&["c", "5"], &["a", "2"]])];
+        let merged: Vec<MergeRow> = merge(&plan, sources, &MergeConfig::new(2, 0, "")).unwrap().collect();
+        assert_eq!(merged, vec![
+            vec![Value::Bytes(b"c".to_vec()), Value::Int(7)],
+            row(&["b", "4"]),
+            vec![Value::Bytes(b"a".to_vec()), Value::Int(3)],
+        ]);
+    }
+
+    #[test]
+    fn test_distinct_and_segment_groups() {
+        let rules = {
+            let url = "jdbc:mysql://localhost:3306/martlet";
+            let cluster = Cluster::builder("martlet")
+                .meta_segment(Segment::new(0, url, "root", "root"), vec![])
+                .data_segment(100, Segment::new(0, url, "root", "root"), vec![])
+                .data_segment(200, Segment::new(0, url, "root", "root"), vec![])
+                .dis_rules(DisRules::builder()
+                    .distributed_table("t_order", DisTable::new(vec!["user_id"], DisAlgorithm::new(DisType::HASH, ""), vec![]))
+                    .build())
+                .build()
+                .unwrap();
+            RulesVersion::new("v1".to_string(), cluster)
+        };
+        let plan_of = |sql: &str| match parser(sql.to_string()).unwrap().pop().unwrap() {
+            Statement::Query(query) => MergePlan::from_query(&query, &[]).unwrap().group_on_segments(&query, &rules),
+            _ => unreachable!(),
+        };
+
+        // DISTINCT on other columns than the shard key: folded in the merge.
+        let plan = plan_of("SELECT DISTINCT status, city FROM t_order LIMIT 10");
+        assert_eq!(plan.get_group_by(), &[0, 1]);
+        assert!(plan.is_regrouping());
+        assert_eq!(plan.segment_limit(), None);
+        let sources = vec![rows(&[&["PAID", "x"], &["SENT", "x"]]), rows(&[&["PAID", "x"], &["PAID", "y"]])];
+        let merged: Vec<MergeRow> = merge(&plan, sources, &MergeConfig::new(16, 0, "")).unwrap().collect();
+        assert_eq!(merged, vec![row(&["PAID", "x"]), row(&["SENT", "x"]), row(&["PAID", "y"])]);
+
+        // Grouped on the shard key: the segments return whole groups.
+        let plan = plan_of("SELECT user_id, COUNT(*) FROM t_order GROUP BY user_id ORDER BY 2 DESC LIMIT 2");
+        assert!(plan.is_grouping() && !plan.is_regrouping());
+        assert_eq!(plan.segment_limit(), Some(2));
+        let sources = vec![rows(&[&["1", "9"], &["3", "2"]]), rows(&[&["2", "5"], &["4", "1"]])];
+        let merged: Vec<MergeRow> = merge(&plan, sources, &MergeConfig::new(16, 0, "")).unwrap().collect();
+        assert_eq!(merged, vec![row(&["1", "9"]), row(&["2", "5"])]);
+        assert!(!plan_of("SELECT DISTINCT user_id FROM t_order").is_regrouping());
+
+        // Grouped on the shard key of one table of a join: regrouped.
+        assert!(plan_of("SELECT o.user_id, COUNT(*) FROM t_order o JOIN t_user u ON o.user_id = u.id GROUP BY o.user_id").is_regrouping());
+    }
+
+    #[test]
+    fn test_external_sort() {
+        // A budget of a byte spills every row.
+        let mut sorted = ExternalSort::new(vec![SortKey::new(0, true)], &MergeConfig::new(0, 1, ""));
+        for (key, value) in [("3", "a"), ("1", "b"), ("2", "c"), ("1", "d")].iter() {
+            sorted.push(row(&[key, value])).unwrap();
+        }
+        assert_eq!(sorted.get_runs(), 4);
+        let merged: Vec<MergeRow> = sorted.finish().unwrap().collect();
+        assert_eq!(merged, vec![row(&["1", "b"]), row(&["1", "d"]), row(&["2", "c"]), row(&["3", "a"])]);
+    }
+
+    #[test]
+    fn test_plan_from_query() {
+        let query = match parser("SELECT status, COUNT(*) AS orders, MAX(amount) FROM t_order GROUP BY status ORDER BY orders DESC LIMIT 10 OFFSET 5".to_string()).unwrap().pop().unwrap() {
+            Statement::Query(query) => query,
+            _ => unreachable!(),
+        };
+        let plan = MergePlan::from_query(&query, &[]).unwrap();
+        assert_eq!(plan, MergePlan::new().order_by(vec![SortKey::new(1, false)]).group_by(vec![0])
+            .aggregate(1, Aggregate::Count).aggregate(2, Aggregate::Max).limit(5, Some(10)));
+        assert_eq!(plan.segment_limit(), None);
+
+        let query = match parser("SELECT AVG(amount) FROM t_order".to_string()).unwrap().pop().unwrap() {
+            Statement::Query(query) => query,
+            _ => unreachable!(),
+        };
+        assert!(MergePlan::from_query(&query, &[]).is_err());
+    }
+
+    #[test]
+    fn test_having() {
+        let query = match parser("SELECT status, COUNT(*) FROM t_order GROUP BY status HAVING COUNT(*) > 5".to_string()).unwrap().pop().unwrap() {
+            Statement::Query(query) => query,
+            _ => unreachable!(),
+        };
+        let plan = MergePlan::from_query(&query, &[]).unwrap();
+        assert_eq!(plan, MergePlan::new().group_by(vec![0]).aggregate(1, Aggregate::Count).having(true));
+        assert!(plan.check_having().is_err());
+        // Each segment has 3 PAID orders, 6 in all: no segment group passes HAVING on its own.
+        let sources = vec![rows(&[&["PAID", "3"]]), rows(&[&["PAID", "3"]])];
+        match merge(&plan, sources, &MergeConfig::new(16, 0, "")) {
+            Err(MergeError::Unsupported(message)) => assert!(message.starts_with("HAVING cannot be applied")),
+            _ => panic!("expected HAVING refused on regrouped groups"),
+        }
+
+        // Whole groups on every segment: each segment filters them right.
+        let plan = plan.segment_groups(true);
+        assert!(plan.check_having().is_ok());
+        let sources = vec![rows(&[&["SENT", "7"]]), rows(&[&["PAID", "6"]])];
+        assert_eq!(merge(&plan, sources, &MergeConfig::new(16, 0, "")).unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,491 @@
+//! The `mesh_admin` schema, answered by the mesh, see `MeshAdminConfig`.
+//!
+//! Its tables hold no rows on any backend, the mesh builds them from its own state when they are
+//! read: `sessions`, the open sessions, `backends`, the segments of the rules with their health
+//! and circuit breaker, `pools`, the backend connection pools, `rules`, how the tables of the
+//! rules are routed, and `stats`, the statistics of the statements by fingerprint. A SELECT of
+//! one of them may filter with comparisons, `LIKE`, `IN`, `BETWEEN`, `IS NULL`, `AND`, `OR` and
+//! `NOT`, sort, limit and count its rows, anything else is refused.
+
+use std::cmp::Ordering;
+
+use bytes::Bytes;
+use mysql::Value;
+use sqlparser::ast::{BinaryOperator, Expr, Query, SelectItem, SetExpr, Statement, TableFactor, UnaryOperator};
+use sqlparser::ast::Value as SQLValue;
+
+use data_panel_common::config::config::MeshConfig;
+
+use crate::discovery::database::health::segment_health;
+use crate::discovery::database::rules::current_rules;
+use crate::handler::database::{breaker, pool, statement_stats};
+use crate::handler::database::lifecycle::redact_url;
+use crate::handler::database::merge::compare_values;
+use crate::handler::database::mysql::{err_payloads, nullable_text_result_payloads};
+use crate::protocol::database::mysql::constant::MySQLServerErrorCode;
+use crate::session::activity::session_activities;
+use crate::session::mysql::SessionContext;
+
+pub const MESH_ADMIN: &str = "mesh_admin";
+
+type AdminResult<T> = Result<T, (MySQLServerErrorCode, String)>;
+
+/// The rows of a table of the schema, NULL being None.
+struct AdminTable {
+    columns: Vec<&'static str>,
+    rows: Vec<Vec<Option<String>>>,
+}
+
+fn sessions() -> AdminTable {
+    let mut activities = session_activities();
+    activities.sort_by_key(|activity| activity.get_session_id());
+    AdminTable {
+        columns: vec!["Id", "User", "Host", "Db", "Backend", "Phase", "Time_ms", "Stall", "Query"],
+        rows: activities.iter()
+            .map(|activity| vec![
+                Some(activity.get_session_id().to_string()),
+                Some(activity.get_details().get_user()),
+                Some(activity.get_client_addr().to_string()),
+                Some(activity.get_details().get_database()),
+                Some(activity.get_details().get_backend()),
+                Some(format!("{:?}", activity.get_phase())),
+                Some(activity.get_elapsed().to_string()),
+                activity.get_stall().map(|stall| stall.value().to_string()),
+                activity.get_query().cloned(),
+            ])
+            .collect(),
+    }
+}
+
+fn backends() -> AdminTable {
+    let health = segment_health();
+    let breakers = breaker::circuit_breakers().map(|breakers| breakers.stats()).unwrap_or_default();
+    let rows = match current_rules() {
+        Some(rules) => {
+            let cluster = rules.get_cluster();
+            cluster.all_segments().into_iter()
+                .map(|(name, segment)| {
+                    let backend = redact_url(&segment.to_mysql_url());
+                    let health = health.iter().find(|health| health.get_segment() == name);
+                    let breaker = breakers.iter().find(|breaker| breaker.get_backend() == backend);
+                    vec![
+                        Some(name),
+                        Some(backend.clone()),
+                        Some(if health.map_or(true, |health| health.is_healthy()) { "1" } else { "0" }.to_string()),
+                        Some(health.map_or(0, |health| health.get_failures()).to_string()),
+                        health.and_then(|health| health.get_latency()).map(|latency| format!("{:.3}", latency.as_secs_f64() * 1000.0)),
+                        health.and_then(|health| health.get_lag()).map(|lag| lag.to_string()),
+                        health.and_then(|health| health.get_last_error()),
+                        breaker.map(|breaker| format!("{:?}", breaker.get_state())),
+                        breaker.map(|breaker| breaker.get_failures().to_string()),
+                    ]
+                })
+                .collect()
+        }
+        None => vec![],
+    };
+    AdminTable {
+        columns: vec!["Segment", "Backend", "Healthy", "Failures", "Latency_ms", "Lag", "Last_error", "Breaker", "Breaker_failures"],
+        rows,
+    }
+}
+
+fn pools() -> AdminTable {
+    AdminTable {
+        columns: vec!["Backend", "Shard", "Size", "Idle", "In_use", "Waiting"],
+        rows: pool::pool_stats().iter()
+            .map(|stats| vec![
+                Some(stats.get_backend()),
+                stats.get_shard().map(|shard| shard.to_string()),
+                Some(stats.get_size().to_string()),
+                Some(stats.get_idle().to_string()),
+                Some(stats.get_in_use().to_string()),
+                Some(stats.get_waiting().to_string()),
+            ])
+            .collect(),
+    }
+}
+
+fn rules() -> AdminTable {
+    let rows = match current_rules() {
+        Some(rules) => rules.tables().iter()
+            .map(|table| vec![
+                Some(table.clone()),
+                Some(rules.route_of(table)),
+                rules.generated_key(table),
+                Some(rules.get_version()),
+                Some(rules.get_loaded_at().to_string()),
+            ])
+            .collect(),
+        None => vec![],
+    };
+    AdminTable {
+        columns: vec!["Table_name", "Route", "Generated_key", "Version", "Loaded_at"],
+        rows,
+    }
+}
+
+fn stats() -> AdminTable {
+    AdminTable {
+        columns: statement_stats::STATS_COLUMNS.to_vec(),
+        rows: statement_stats::statement_stats()
+            .map(|stats| stats.snapshots().iter().map(|snapshot| snapshot.to_row().into_iter().map(Some).collect()).collect())
+            .unwrap_or_default(),
+    }
+}
+
+fn admin_table(name: &str) -> AdminResult<AdminTable> {
+    match name.to_lowercase().as_str() {
+        "sessions" => Ok(sessions()),
+        "backends" => Ok(backends()),
+        "pools" => Ok(pools()),
+        "rules" => Ok(rules()),
+        "stats" => Ok(stats()),
+        _ => Err((MySQLServerErrorCode::ErNoSuchTable, format!("Table '{}.{}' doesn't exist", MESH_ADMIN, name))),
+    }
+}
+
+/// Whether the query reads a table of `mesh_admin`.
+fn reads_mesh_admin(statement: &Statement) -> bool {
+    let select = match statement {
+        Statement::Query(query) => match &query.body {
+            SetExpr::Select(select) => select,
+            _ => return false,
+        },
+        _ => return false,
+    };
+    select.from.iter()
+        .flat_map(|table| std::iter::once(&table.relation).chain(table.joins.iter().map(|join| &join.relation)))
+        .any(|relation| match relation {
+            TableFactor::Table { name, .. } => name.0.len() == 2 && name.0[0].value.eq_ignore_ascii_case(MESH_ADMIN),
+            _ => false,
+        })
+}
+
+fn not_supported<T>(what: String) -> AdminResult<T> {
+    Err((MySQLServerErrorCode::ErNotSupportedYet, format!("{} is not supported on {}", what, MESH_ADMIN)))
+}
+
+impl AdminTable {
+    fn column(&self, name: &str) -> AdminResult<usize> {
+        self.columns.iter().position(|column| column.eq_ignore_ascii_case(name))
+            .ok_or_else(|| (MySQLServerErrorCode::ErBadFieldError, format!("Unknown column '{}' in 'field list'", name)))
+    }
+
+    /// The value of `expr`, a column or a literal, on `row`.
+    fn value(&self, expr: &Expr, row: &[Option<String>]) -> AdminResult<Value> {
+        let cell = match expr {
+            Expr::Identifier(column) => row[self.column(&column.value)?].clone(),
+            Expr::CompoundIdentifier(idents) => row[self.column(&idents.last().map_or("", |ident| ident.value.as_str()))?].clone(),
+            Expr::Nested(expr) => return self.value(expr, row),
+            Expr::Value(SQLValue::Null) => None,
+            Expr::Value(SQLValue::Boolean(value)) => Some(if *value { "1" } else { "0" }.to_string()),
+            Expr::Value(SQLValue::SingleQuotedString(value)) | Expr::Value(SQLValue::NationalStringLiteral(value)) => Some(value.clone()),
+            Expr::Value(SQLValue::Number(number, _)) => Some(number.clone()),
+            Expr::UnaryOp { op: UnaryOperator::Minus, expr } => match self.value(expr, row)? {
+                Value::Bytes(number) => Some(format!("-{}", String::from_utf8_lossy(&number))),
+                _ => None,
+            },
+            _ => return not_supported(format!("'{}'", expr)),
+        };
+        Ok(cell.map_or(Value::NULL, |cell| Value::Bytes(cell.into_bytes())))
+    }
+
+    /// Whether `row` matches `expr`, None when unknown as SQL has it, e.g. comparing to NULL.
+    fn matches(&self, expr: &Expr, row: &[Option<String>]) -> AdminResult<Option<bool>> {
+        match expr {
+            Expr::Nested(expr) => self.matches(expr, row),
+            Expr::UnaryOp { op: UnaryOperator::Not, expr } => Ok(self.matches(expr, row)?.map(|matched| !matched)),
+            Expr::BinaryOp { left, op: BinaryOperator::And, right } => Ok(match (self.matches(left, row)?, self.matches(right, row)?) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            }),
+            Expr::BinaryOp { left, op: BinaryOperator::Or, right } => Ok(match (self.matches(left, row)?, self.matches(right, row)?) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            }),
+            Expr::BinaryOp { left, op, right } => {
+                let (left, right) = (self.value(left, row)?, self.value(right, row)?);
+                if left == Value::NULL || right == Value::NULL {
+                    return Ok(None);
+                }
+                let ordering = compare_values(&left, &right);
+                Ok(Some(match op {
+                    BinaryOperator::Eq => ordering == Ordering::Equal,
+                    BinaryOperator::NotEq => ordering != Ordering::Equal,
+                    BinaryOperator::Lt => ordering == Ordering::Less,
+                    BinaryOperator::LtEq => ordering != Ordering::Greater,
+                    BinaryOperator::Gt => ordering == Ordering::Greater,
+                    BinaryOperator::GtEq => ordering != Ordering::Less,
+                    BinaryOperator::Like => like(&text(&left), &text(&right)),
+                    BinaryOperator::NotLike => !like(&text(&left), &text(&right)),
+                    _ => return not_supported(format!("'{}'", op)),
+                }))
+            }
+            Expr::IsNull(expr) => Ok(Some(self.value(expr, row)? == Value::NULL)),
+            Expr::IsNotNull(expr) => Ok(Some(self.value(expr, row)? != Value::NULL)),
+            Expr::InList { expr, list, negated } => {
+                let value = self.value(expr, row)?;
+                if value == Value::NULL {
+                    return Ok(None);
+                }
+                let mut found = Some(false);
+                for item in list.iter() {
+                    match self.value(item, row)? {
+                        Value::NULL => found = None,
+                        item if compare_values(&value, &item) == Ordering::Equal => return Ok(Some(!negated)),
+                        _ => {}
+                    }
+                }
+                Ok(found.map(|found| found != *negated))
+            }
+            Expr::Between { expr, negated, low, high } => {
+                let (value, low, high) = (self.value(expr, row)?, self.value(low, row)?, self.value(high, row)?);
+                if value == Value::NULL || low == Value::NULL || high == Value::NULL {
+                    return Ok(None);
+                }
+                let between = compare_values(&value, &low) != Ordering::Less && compare_values(&value, &high) != Ordering::Greater;
+                Ok(Some(between != *negated))
+            }
+            _ => not_supported(format!("'{}'", expr)),
+        }
+    }
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::Bytes(bytes) => String::from_utf8_lossy(bytes).to_string(),
+        value => value.as_sql(true),
+    }
+}
+
+fn cell(value: Value) -> Option<String> {
+    match value {
+        Value::NULL => None,
+        value => Some(text(&value)),
+    }
+}
+
+/// Whether `text` matches the LIKE `pattern`, ignoring case as the default collations do.
+fn like(text: &str, pattern: &str) -> bool {
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    // matched[i]: whether the pattern so far matches the first i characters of the text.
+    let mut matched = vec![false; text.len() + 1];
+    matched[0] = true;
+    let mut p = 0;
+    while p < pattern.len() {
+        let (literal, any) = match pattern[p] {
+            '\\' if p + 1 < pattern.len() => {
+                p += 1;
+                (Some(pattern[p]), false)
+            }
+            '%' => (None, true),
+            '_' => (None, false),
+            c => (Some(c), false),
+        };
+        if any {
+            for i in 1..=text.len() {
+                matched[i] = matched[i] || matched[i - 1];
+            }
+        } else {
+            for i in (1..=text.len()).rev() {
+                matched[i] = matched[i - 1] && literal.map_or(true, |c| c == text[i - 1]);
+            }
+            matched[0] = false;
+        }
+        p += 1;
+    }
+    matched[text.len()]
+}
+
+fn limit(expr: &Expr) -> AdminResult<usize> {
+    match expr {
+        Expr::Value(SQLValue::Number(number, _)) => number.parse().or_else(|_| not_supported(format!("LIMIT {}", number))),
+        _ => not_supported(format!("LIMIT {}", expr)),
+    }
+}
+
+
+/// A column of the result, one of the table or an expression on its rows.
+enum Projected<'a> {
+    Column(usize),
+    Expr(&'a Expr),
+}
+
+impl AdminTable {
+    /// The columns and the rows `query`, reading this table alone, returns.
+    fn select(&self, query: &Query) -> AdminResult<(Vec<String>, Vec<Vec<Option<String>>>)> {
+        let select = match &query.body {
+            SetExpr::Select(select) => select,
+            body => return not_supported(format!("'{}'", body)),
+        };
+        if query.with.is_some() || select.distinct || !select.group_by.is_empty() || select.having.is_some() {
+            return not_supported("A query other than a plain SELECT".to_string());
+        }
+
+        let mut rows = vec![];
+        for row in self.rows.iter() {
+            let matched = match &select.selection {
+                Some(selection) => self.matches(selection, row)? == Some(true),
+                None => true,
+            };
+            if matched {
+                rows.push(row);
+            }
+        }
+        if !query.order_by.is_empty() {
+            let mut keyed = Vec::with_capacity(rows.len());
+            for row in rows {
+                let key: AdminResult<Vec<Value>> = query.order_by.iter().map(|order_by| self.value(&order_by.expr, row)).collect();
+                keyed.push((key?, row));
+            }
+            keyed.sort_by(|(a, _), (b, _)| {
+                a.iter().zip(b.iter()).zip(query.order_by.iter())
+                    .map(|((a, b), order_by)| if order_by.asc.unwrap_or(true) { compare_values(a, b) } else { compare_values(b, a) })
+                    .find(|ordering| *ordering != Ordering::Equal)
+                    .unwrap_or(Ordering::Equal)
+            });
+            rows = keyed.into_iter().map(|(_, row)| row).collect();
+        }
+
+        let counted = select.projection.iter().all(|item| match item {
+            SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => expr.to_string().eq_ignore_ascii_case("COUNT(*)"),
+            _ => false,
+        });
+        let mut columns = vec![];
+        let mut projection = vec![];
+        for item in select.projection.iter() {
+            match item {
+                SelectItem::Wildcard | SelectItem::QualifiedWildcard(_) => {
+                    columns.extend(self.columns.iter().map(|column| column.to_string()));
+                    projection.extend((0..self.columns.len()).map(Projected::Column));
+                }
+                SelectItem::UnnamedExpr(expr) => {
+                    columns.push(match expr {
+                        Expr::Identifier(column) => column.value.clone(),
+                        Expr::CompoundIdentifier(idents) => idents.last().map_or("".to_string(), |ident| ident.value.clone()),
+                        expr => expr.to_string(),
+                    });
+                    projection.push(Projected::Expr(expr));
+                }
+                SelectItem::ExprWithAlias { expr, alias } => {
+                    columns.push(alias.value.clone());
+                    projection.push(Projected::Expr(expr));
+                }
+            }
+        }
+        if counted {
+            return Ok((columns, vec![vec![Some(rows.len().to_string()); projection.len()]]));
+        }
+
+        let offset = match &query.offset {
+            Some(offset) => limit(&offset.value)?,
+            None => 0,
+        };
+        let count = match &query.limit {
+            Some(count) => limit(count)?,
+            None => usize::MAX,
+        };
+        let mut projected = vec![];
+        for row in rows.into_iter().skip(offset).take(count) {
+            let mut values = Vec::with_capacity(projection.len());
+            for column in projection.iter() {
+                values.push(match column {
+                    Projected::Column(index) => row[*index].clone(),
+                    Projected::Expr(expr) => cell(self.value(expr, row)?),
+                });
+            }
+            projected.push(values);
+        }
+        Ok((columns, projected))
+    }
+}
+
+/// Answers the queries reading `mesh_admin` while it is enabled, for the users it lists.
+pub fn intercept(statement: &Statement, session_ctx: &SessionContext) -> Option<Vec<Bytes>> {
+    let config = MeshConfig::get_mesh_admin_config();
+    if !config.is_enabled() || !reads_mesh_admin(statement) {
+        return None;
+    }
+    let user = session_ctx.get_user_name();
+    if !config.is_admin(&user) {
+        return err_payloads(1, MySQLServerErrorCode::ErDbaccessDeniedError, format!("Access denied for user '{}' to database '{}'", user, MESH_ADMIN));
+    }
+    let query = match statement {
+        Statement::Query(query) => query,
+        _ => return None,
+    };
+    let selected = match query.body {
+        SetExpr::Select(ref select) => match select.from.as_slice() {
+            [from] if from.joins.is_empty() => match &from.relation {
+                TableFactor::Table { name, .. } if name.0.len() == 2 => admin_table(&name.0[1].value).and_then(|table| table.select(query)),
+                _ => not_supported("Reading a table of another schema".to_string()),
+            },
+            _ => not_supported("Reading more than one table".to_string()),
+        },
+        _ => not_supported("A query other than a plain SELECT".to_string()),
+    };
+    match selected {
+        Ok((columns, rows)) => nullable_text_result_payloads(columns.iter().map(|column| column.as_str()).collect(), rows),
+        Err((code, message)) => err_payloads(1, code, message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlparser::ast::Statement;
+
+    use crate::handler::database::parser::sql::mysql::parser;
+
+    use super::{AdminTable, like, reads_mesh_admin};
+
+    fn selected(table: &AdminTable, sql: &str) -> Vec<Vec<Option<String>>> {
+        match parser(sql.to_string()).unwrap().pop().unwrap() {
+            Statement::Query(query) => table.select(&query).unwrap().1,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_admin_table() {
+        assert!(like("data-100/primary", "DATA-%/primary"));
+        assert!(like("data-100/primary", "data-1_0%"));
+        assert!(!like("data-100/primary", "data-2%"));
+        assert!(like("50%", "50\\%"));
+        assert!(!like("500", "50\\%"));
+
+        assert!(reads_mesh_admin(&parser("SELECT * FROM mesh_admin.sessions".to_string()).unwrap().pop().unwrap()));
+        assert!(!reads_mesh_admin(&parser("SELECT * FROM sessions".to_string()).unwrap().pop().unwrap()));
+
+        let cell = |value: &str| Some(value.to_string());
+        let table = AdminTable {
+            columns: vec!["Segment", "Healthy", "Lag"],
+            rows: vec![
+                vec![cell("data-100/primary"), cell("1"), None],
+                vec![cell("data-100/mirror-0"), cell("0"), cell("12")],
+                vec![cell("data-200/mirror-0"), cell("1"), cell("3")],
+            ],
+        };
+        let segments = |condition: &str| -> Vec<Option<String>> {
+            selected(&table, &format!("SELECT segment FROM mesh_admin.backends WHERE {}", condition)).into_iter().map(|mut row| row.remove(0)).collect()
+        };
+        assert_eq!(segments("healthy = 0"), vec![cell("data-100/mirror-0")]);
+        assert_eq!(segments("lag > 5 OR segment LIKE '%primary'"), vec![cell("data-100/primary"), cell("data-100/mirror-0")]);
+        assert_eq!(segments("lag IS NULL"), vec![cell("data-100/primary")]);
+        assert!(segments("NOT lag IN (3, 12)").is_empty());
+        assert_eq!(segments("lag BETWEEN 1 AND 10 AND healthy = 1"), vec![cell("data-200/mirror-0")]);
+
+        assert_eq!(selected(&table, "SELECT lag, segment FROM mesh_admin.backends ORDER BY lag DESC LIMIT 2"), vec![
+            vec![cell("12"), cell("data-100/mirror-0")],
+            vec![cell("3"), cell("data-200/mirror-0")],
+        ]);
+        assert_eq!(selected(&table, "SELECT COUNT(*) FROM mesh_admin.backends WHERE healthy = 1"), vec![vec![cell("2")]]);
+        match parser("SELECT * FROM mesh_admin.backends WHERE nope = 1".to_string()).unwrap().pop().unwrap() {
+            Statement::Query(query) => assert!(table.select(&query).is_err()),
+            _ => unreachable!(),
+        }
+    }
+}
@@ -1,2 +1,41 @@
 pub mod parser;
 pub mod mysql;
+pub mod access;
+pub mod approval;
+pub mod audit;
+pub mod authenticator;
+pub mod best_effort;
+pub mod breaker;
+pub mod cancel;
+pub mod cdc;
+pub mod concurrency;
+pub mod contention;
+pub mod ddl;
+pub mod information_schema;
+pub mod intent;
+pub mod keygen;
+pub mod corpus;
+pub mod explain;
+pub mod fanout;
+pub mod merge;
+pub mod mesh_admin;
+pub mod passthrough;
+pub mod lifecycle;
+pub mod pool;
+pub mod postgresql;
+pub mod procedure;
+pub mod processlist;
+pub mod fault;
+pub mod ratelimit;
+pub mod scheduler;
+pub mod route;
+pub mod route_cache;
+pub mod scatter;
+pub mod sharded_insert;
+pub mod spill;
+pub mod statement_stats;
+pub mod telemetry;
+pub mod traffic;
+pub mod transaction;
+pub mod variables;
+pub mod xa;
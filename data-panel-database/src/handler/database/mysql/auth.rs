@@ -0,0 +1,118 @@
+//! Verifies client logins with the `Authenticator` of `AuthConfig`.
+//!
+//! For the users of the config the mesh knows the plain passwords, so it computes the scramble
+//! the client should have answered with for its authentication method and compares.
+//! caching_sha2_password logins therefore always take the fast path, no full authentication
+//! over TLS or RSA is needed. The other backends get the password the client sent with
+//! mysql_clear_password.
+
+use bytes::Bytes;
+use openssl::sha::{sha1, sha256};
+
+use crate::handler::database::authenticator::{Authenticator, Credential};
+use crate::handler::database::mysql::err_payloads;
+use crate::protocol::database::{DatabasePacket, PacketPayload};
+use crate::protocol::database::mysql::constant::{MySQLAuthenticationMethod, MySQLServerErrorCode};
+use crate::protocol::database::mysql::packet::{MySQLOKPacket, MySQLPacketPayload};
+use crate::session::mysql::SessionContext;
+
+/// caching_sha2_password status telling the client its scramble was accepted.
+const FAST_AUTH_SUCCESS: u8 = 0x03;
+
+fn xor(left: &[u8], right: &[u8]) -> Vec<u8> {
+    left.iter().zip(right.iter()).map(|(l, r)| l ^ r).collect()
+}
+
+/// SHA1(password) XOR SHA1(scramble + SHA1(SHA1(password))).
+pub fn scramble_native_password(password: &str, scramble: &[u8]) -> Vec<u8> {
+    if password.is_empty() {
+        return vec![];
+    }
+    let stage1 = sha1(password.as_bytes());
+    let stage2 = sha1(&stage1);
+    let mut salted = scramble.to_vec();
+    salted.extend_from_slice(&stage2);
+    xor(&stage1, &sha1(&salted))
+}
+
+/// SHA256(password) XOR SHA256(SHA256(SHA256(password)) + scramble).
+pub fn scramble_caching_sha2_password(password: &str, scramble: &[u8]) -> Vec<u8> {
+    if password.is_empty() {
+        return vec![];
+    }
+    let stage1 = sha256(password.as_bytes());
+    let mut salted = sha256(&stage1).to_vec();
+    salted.extend_from_slice(scramble);
+    xor(&stage1, &sha256(&salted))
+}
+
+/// What the client answered the authentication of the session with.
+pub fn credential(session_ctx: &SessionContext) -> Credential {
+    let response = session_ctx.get_auth_response();
+    if session_ctx.get_auth_plugin_name() == MySQLAuthenticationMethod::ClearTextAuthentication.value() {
+        // The password comes NUL terminated.
+        let password = response.split(|byte| *byte == 0).next().unwrap_or(&[]);
+        return Credential::Cleartext(String::from_utf8_lossy(password).to_string());
+    }
+    let mut scramble = session_ctx.get_auth_plugin_data1();
+    scramble.extend(session_ctx.get_auth_plugin_data2());
+    Credential::Scramble {
+        plugin: session_ctx.get_auth_plugin_name(),
+        scramble,
+        response,
+    }
+}
+
+/// Checks the `credential` of `user` with `authenticator`, the message refusing the login
+/// otherwise.
+pub async fn authenticate(user: &str, credential: Credential, authenticator: &dyn Authenticator) -> Result<(), String> {
+    match authenticator.authenticate(user, credential).await {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            println!("login of user {} refused; error = {}", user, e);
+            Err(format!("Access denied for user '{}'", user))
+        }
+    }
+}
+
+/// The packets that end the authentication with `result`, starting at `sequence_id`: OK (after
+/// the fast auth status for caching_sha2_password), or the ERR packet when the login is refused.
+pub fn auth_result_payloads(sequence_id: u32, session_ctx: &SessionContext, result: Result<(), String>) -> Result<Vec<Bytes>, Vec<Bytes>> {
+    if let Err(message) = result {
+        return Err(err_payloads(sequence_id, MySQLServerErrorCode::ErAccessDeniedError, message).unwrap());
+    }
+    let mut payloads = vec![];
+    let mut sequence_id = sequence_id;
+    if session_ctx.get_auth_plugin_name() == MySQLAuthenticationMethod::CachingSha2Password.value() {
+        let mut fast_auth_payload = MySQLPacketPayload::new();
+        fast_auth_payload.put_u8(sequence_id as u8);
+        fast_auth_payload.put_u8(0x01);
+        fast_auth_payload.put_u8(FAST_AUTH_SUCCESS);
+        payloads.push(fast_auth_payload.get_payload());
+        sequence_id += 1;
+    }
+    let mut ok_packet = MySQLOKPacket::new(sequence_id, 0, 0);
+    let mut ok_payload = MySQLPacketPayload::new();
+    let ok_payload = DatabasePacket::encode(&mut ok_packet, &mut ok_payload);
+    payloads.push(ok_payload.get_payload());
+    Ok(payloads)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handler::database::mysql::auth::{scramble_caching_sha2_password, scramble_native_password};
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_scramble() {
+        let scramble: Vec<u8> = (1..21).collect();
+        assert_eq!(to_hex(&scramble_native_password("secret", &scramble)),
+                   "b32bb3a583e1340c0a1108d58b1be49781ad8c2f");
+        assert_eq!(to_hex(&scramble_caching_sha2_password("secret", &scramble)),
+                   "746ebe205d56a0707acb3e796e834e0dd7b1d61743b26bd5202c7a623230c7c9");
+        assert!(scramble_native_password("", &scramble).is_empty());
+    }
+}
@@ -1,7 +1,16 @@
+use std::cell::{RefCell, RefMut};
+use std::sync::Arc;
+
 use bytes::Bytes;
 use sqlparser::ast::Statement;
 
+use crate::handler::database::cancel::RunningSlot;
+use crate::handler::database::lifecycle::BackendConn;
+use crate::handler::database::parser::sql::hint::SQLHints;
+use crate::handler::database::route_cache::RoutePlan;
+use crate::handler::database::mysql::{buffered, PayloadSink};
 use crate::handler::database::mysql::rdbc::{bin_query, text_query};
+use crate::protocol::database::mysql::constant::MySQLStatusFlag;
 
 pub enum TBProtocol {
     Text,
@@ -12,19 +21,69 @@ pub struct ExplainPlanContext<'a> {
     sql: &'a str,
     statement: &'a Statement,
     protocol: TBProtocol,
+    backend_url: &'a str,
+    route_plan: Option<Arc<RoutePlan>>,
+    hints: SQLHints,
+    pinned_conn: Option<RefCell<BackendConn>>,
+    session_variables: &'a [&'a str],
+    status_flags: u16,
+    running_slot: Option<Arc<RunningSlot>>,
 }
 
 impl<'a> ExplainPlanContext<'a> {
     pub fn new(sql: &'a str,
                statement: &'a Statement,
-               protocol: TBProtocol) -> Self {
+               protocol: TBProtocol,
+               backend_url: &'a str) -> Self {
         ExplainPlanContext {
             sql,
             statement,
             protocol,
+            backend_url,
+            route_plan: None,
+            hints: SQLHints::default(),
+            pinned_conn: None,
+            session_variables: &[],
+            status_flags: MySQLStatusFlag::ServerStatusAutocommit as u16,
+            running_slot: None,
         }
     }
 
+    pub fn route_plan(mut self, route_plan: Option<Arc<RoutePlan>>) -> Self {
+        self.route_plan = route_plan;
+        self
+    }
+
+    /// The hints of the statement text, see `hint`.
+    pub fn hints(mut self, hints: SQLHints) -> Self {
+        self.hints = hints;
+        self
+    }
+
+    /// Runs the statement on the connection of the session's open transaction.
+    pub fn pinned_conn(mut self, pinned_conn: Option<BackendConn>) -> Self {
+        self.pinned_conn = pinned_conn.map(RefCell::new);
+        self
+    }
+
+    /// Replayed on a connection acquired for the statement, see `variables::replay`. Borrowed
+    /// from the query arena, see `variables::replay_statements_in`.
+    pub fn session_variables(mut self, session_variables: &'a [&'a str]) -> Self {
+        self.session_variables = session_variables;
+        self
+    }
+
+    pub fn status_flags(mut self, status_flags: u16) -> Self {
+        self.status_flags = status_flags;
+        self
+    }
+
+    /// The slot of the session the statement is tracked in, see `cancel::track`.
+    pub fn running_slot(mut self, running_slot: Option<Arc<RunningSlot>>) -> Self {
+        self.running_slot = running_slot;
+        self
+    }
+
     pub fn get_sql(&self) -> &'a str {
         self.sql
     }
@@ -32,10 +91,47 @@ impl<'a> ExplainPlanContext<'a> {
     pub fn get_statement(&self) -> &'a Statement {
         self.statement
     }
+
+    pub fn get_backend_url(&self) -> &'a str {
+        self.backend_url
+    }
+
+    pub fn get_route_plan(&self) -> Option<&RoutePlan> {
+        self.route_plan.as_deref()
+    }
+
+    pub fn get_hints(&self) -> &SQLHints {
+        &self.hints
+    }
+
+    pub fn get_pinned_conn(&self) -> Option<RefMut<'_, BackendConn>> {
+        self.pinned_conn.as_ref().map(|conn| conn.borrow_mut())
+    }
+
+    /// Gives the pinned connection back once the statement ran.
+    pub fn take_pinned_conn(self) -> Option<BackendConn> {
+        self.pinned_conn.map(RefCell::into_inner)
+    }
+
+    pub fn get_session_variables(&self) -> &'a [&'a str] {
+        self.session_variables
+    }
+
+    /// Server status flags of the OK and EOF packets answering the statement.
+    pub fn get_status_flags(&self) -> u16 {
+        self.status_flags
+    }
+
+    pub fn get_running_slot(&self) -> Option<&Arc<RunningSlot>> {
+        self.running_slot.as_ref()
+    }
 }
 
 pub trait Executor {
     fn execute(&self) -> Option<Vec<Bytes>>;
+
+    /// Like `execute`, with rows written to `sink` as they are read from the backend.
+    fn execute_streaming(&self, sink: &mut dyn PayloadSink) -> Option<Vec<Bytes>>;
 }
 
 pub struct PlanTask {}
@@ -62,8 +158,12 @@ impl<'a> ExplainPlan<'a> {
 
 impl<'a> Executor for ExplainPlan<'a> {
     fn execute(&self) -> Option<Vec<Bytes>> {
+        buffered(|sink| self.execute_streaming(sink))
+    }
+
+    fn execute_streaming(&self, sink: &mut dyn PayloadSink) -> Option<Vec<Bytes>> {
         match self.ctx.protocol {
-            TBProtocol::Text => { text_query(&self) }
+            TBProtocol::Text => { text_query(&self, sink) }
             TBProtocol::Binary => { bin_query(&self) }
         }
     }
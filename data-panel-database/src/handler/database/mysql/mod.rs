@@ -1,37 +1,469 @@
 use bytes::Bytes;
+use sqlparser::ast::Statement;
 
+use data_panel_common::common::Error;
+use data_panel_common::config::config::MeshConfig;
+
+use crate::handler::database::{cancel, processlist};
 use crate::handler::database::mysql::binary::{ComStmtCloseHandler, ComStmtExecuteHandler, ComStmtPrepareHandler, ComStmtResetHandler};
 use crate::handler::database::mysql::text::ComQueryHandler;
+use crate::handler::database::parser::sql::dialect::SQLDialect;
+use crate::handler::database::parser::sql::syntax::SyntaxError;
 use crate::protocol::database::{CommandPacketType, DatabasePacket, PacketPayload};
-use crate::protocol::database::mysql::constant::{MySQLAuthenticationMethod, MySQLCapabilityFlag, MySQLCommandPacketType, MySQLConnectionPhase};
-use crate::protocol::database::mysql::packet::{MySQLAuthSwitchRequestPacket, MySQLAuthSwitchResponsePacket, MySQLHandshakePacket, MySQLHandshakeResponse41Packet, MySQLOKPacket, MySQLPacket, MySQLPacketHeader, MySQLPacketPayload};
+use crate::protocol::database::mysql::charset::{collation_by_id, collation_by_name, default_collation, MySQLCollation};
+use crate::protocol::database::mysql::constant::{CHARSET, MySQLAuthenticationMethod, MySQLCapabilityFlag, MySQLColumnType, MySQLCommandPacketType, MySQLConnectionPhase, MySQLServerErrorCode, MySQLStatusFlag};
+use crate::protocol::database::mysql::packet::{MySQLAuthSwitchRequestPacket, MySQLAuthSwitchResponsePacket, MySQLColumnDefinition41Packet, MySQLEOFPacket, MySQLErrPacket, MySQLFieldCountPacket, MySQLHandshakePacket, MySQLHandshakeResponse41Packet, MySQLOKPacket, MySQLPacket, MySQLPacketHeader, MySQLPacketPayload};
+use crate::protocol::database::mysql::packet::text::MySQLTextResultSetRowPacket;
+use crate::service::tls;
 use crate::session::mysql::SessionContext;
 
+pub mod auth;
 pub mod text;
 pub mod binary;
 pub mod explainplan;
 pub mod rdbc;
 
+/// Takes the payloads of a response while a handler still produces it, so rows reach the
+/// client as they are read from the backend instead of all at once.
+pub trait PayloadSink {
+    fn write(&mut self, payload: Bytes) -> std::io::Result<()>;
+}
+
+impl PayloadSink for Vec<Bytes> {
+    fn write(&mut self, payload: Bytes) -> std::io::Result<()> {
+        self.push(payload);
+        Ok(())
+    }
+}
+
+/// Moves `payloads` to `sink`, false when the client can no longer be written to.
+pub fn drain_into(payloads: &mut Vec<Bytes>, sink: &mut dyn PayloadSink) -> bool {
+    for payload in payloads.drain(..) {
+        if let Err(e) = sink.write(payload) {
+            println!("error on streaming response; error = {:?}", e);
+            return false;
+        }
+    }
+    true
+}
+
+/// The whole response of `handle_streaming`, written and returned payloads together.
+pub fn buffered(handle_streaming: impl FnOnce(&mut dyn PayloadSink) -> Option<Vec<Bytes>>) -> Option<Vec<Bytes>> {
+    let mut payloads = Vec::new();
+    match handle_streaming(&mut payloads) {
+        Some(rest) => payloads.extend(rest),
+        None if payloads.is_empty() => return None,
+        None => {}
+    }
+    Some(payloads)
+}
+
 pub trait CommandHandler<P, Session> {
     fn handle(command_packet_header: Option<MySQLPacketHeader>, command_packet: Option<P>, session_ctx: &mut Session) -> Option<Vec<Bytes>>;
+
+    /// Like `handle`, with the start of the response possibly written to `sink` already; the
+    /// payloads returned follow what was written.
+    fn handle_streaming(command_packet_header: Option<MySQLPacketHeader>, command_packet: Option<P>, session_ctx: &mut Session, _sink: &mut dyn PayloadSink) -> Option<Vec<Bytes>> {
+        Self::handle(command_packet_header, command_packet, session_ctx)
+    }
+}
+
+/// The collation of the charset config, `CHARSET` when it names none MySQL knows.
+pub fn server_collation() -> &'static MySQLCollation {
+    let config = MeshConfig::get_charset_config();
+    let collation = if config.get_collation().is_empty() {
+        default_collation(&config.get_charset())
+    } else {
+        collation_by_name(&config.get_collation())
+    };
+    collation.or_else(|| collation_by_id(CHARSET)).unwrap()
+}
+
+/// The variables that bring a backend connection to the charset the client negotiated.
+fn negotiated_charset_variables(collation: &MySQLCollation) -> Vec<(String, String)> {
+    let mut variables: Vec<(String, String)> = vec!["character_set_client", "character_set_connection", "character_set_results"].into_iter()
+        .map(|name| (name.to_string(), collation.get_charset().to_string()))
+        .collect();
+    variables.push(("collation_connection".to_string(), collation.get_name().to_string()));
+    variables
+}
+
+/// Encodes the ERR packet returned when the mesh itself rejects a command.
+pub fn err_payloads(sequence_id: u32, error_code: MySQLServerErrorCode, error_message: String) -> Option<Vec<Bytes>> {
+    let mut err_packet = MySQLErrPacket::new(sequence_id, error_code.code(), error_code.sql_state().to_string(), error_message);
+    let mut err_payload = MySQLPacketPayload::new();
+    let err_payload = DatabasePacket::encode(&mut err_packet, &mut err_payload);
+    Some(vec![err_payload.get_payload()])
+}
+
+/// The code and SQLSTATE `e` answers the client with, the ones of the backend for its errors.
+fn error_code(e: &Error) -> (u32, String) {
+    let error_code = match e {
+        Error::Backend { code, state, .. } => return (*code, state.clone()),
+        Error::Parse(_) => MySQLServerErrorCode::ErParseError,
+        Error::Protocol(_) => MySQLServerErrorCode::ErNotSupportedYet,
+        Error::Routing(_) | Error::Config(_) => MySQLServerErrorCode::ErUnknownError,
+        Error::Auth(_) => MySQLServerErrorCode::ErSpecificAccessDeniedError,
+    };
+    (error_code.code(), error_code.sql_state().to_string())
+}
+
+/// Encodes the ERR packet of `e` at `sequence_id`.
+pub fn error_payload(sequence_id: u32, e: &Error) -> Bytes {
+    let (code, state) = error_code(e);
+    let mut err_packet = MySQLErrPacket::new(sequence_id, code, state, e.to_string());
+    let mut err_payload = MySQLPacketPayload::new();
+    DatabasePacket::encode(&mut err_packet, &mut err_payload).get_payload()
+}
+
+/// The statement of `sql`, the last one if it holds several, or the ERR payloads telling
+/// where it does not parse.
+pub fn parse_statement(sql: &str, dialect: SQLDialect) -> Result<Statement, Option<Vec<Bytes>>> {
+    match dialect.parser(sql.to_string()) {
+        Ok(mut statements) => statements.pop()
+            .ok_or_else(|| err_payloads(1, MySQLServerErrorCode::ErEmptyQuery, "Query was empty".to_string())),
+        Err(e) => Err(err_payloads(1, MySQLServerErrorCode::ErParseError, SyntaxError::new(sql, dialect, &e).to_mysql_message())),
+    }
+}
+
+/// The packet ending a result set of a response that may hold several, as a CALL answers,
+/// written once it is known whether another one follows.
+pub enum ResultSetEnd {
+    /// The EOF packet after the rows.
+    Eof { sequence_id: u32, status_flags: u16 },
+    /// The OK packet of a result without columns.
+    Ok { sequence_id: u32, affected_rows: u64, last_insert_id: u64, status_flags: u16 },
+}
+
+impl ResultSetEnd {
+    /// The packet, flagged SERVER_MORE_RESULTS_EXISTS when `more_results` follow.
+    pub fn payload(&self, more_results: bool) -> Bytes {
+        let more_results = if more_results { MySQLStatusFlag::ServerMoreResultsExists as u16 } else { 0 };
+        match *self {
+            ResultSetEnd::Eof { sequence_id, status_flags } => {
+                let mut eof_packet = MySQLEOFPacket::new(sequence_id);
+                eof_packet.set_status_flags(status_flags | more_results);
+                let mut eof_payload = MySQLPacketPayload::new();
+                DatabasePacket::encode(&mut eof_packet, &mut eof_payload).get_payload()
+            }
+            ResultSetEnd::Ok { sequence_id, affected_rows, last_insert_id, status_flags } => {
+                let mut ok_packet = MySQLOKPacket::new(sequence_id, affected_rows, last_insert_id);
+                ok_packet.set_status_flags(status_flags | more_results);
+                let mut ok_payload = MySQLPacketPayload::new();
+                DatabasePacket::encode(&mut ok_packet, &mut ok_payload).get_payload()
+            }
+        }
+    }
+}
+
+/// Whether a response starts with an ERR packet.
+pub fn is_err_payloads(payloads: &Option<Vec<Bytes>>) -> bool {
+    // Payloads start with the sequence id, the packet type follows.
+    payloads.as_ref()
+        .and_then(|payloads| payloads.first())
+        .map_or(false, |payload| payload.len() > 1 && payload[1] == 0xff)
+}
+
+/// The length encoded integer of `payload` at `at`, and the position after it.
+fn lenenc_int(payload: &Bytes, at: usize) -> Option<(u64, usize)> {
+    let len = match *payload.get(at)? {
+        0xfc => 2,
+        0xfd => 3,
+        0xfe => 8,
+        value => return Some((value as u64, at + 1)),
+    };
+    let bytes = payload.get(at + 1..at + 1 + len)?;
+    Some((bytes.iter().rev().fold(0u64, |value, byte| (value << 8) | *byte as u64), at + 1 + len))
+}
+
+/// The affected rows and the last insert id of a response made of an OK packet.
+fn ok_counts(payloads: &Option<Vec<Bytes>>) -> Option<(u64, u64)> {
+    let payload = payloads.as_ref()?.first()?;
+    if payload.len() < 3 || payload[1] != 0x00 {
+        return None;
+    }
+    // The length encoded integers follow the packet type.
+    let (affected_rows, next) = lenenc_int(payload, 2)?;
+    let (last_insert_id, _) = lenenc_int(payload, next)?;
+    Some((affected_rows, last_insert_id))
+}
+
+/// The affected rows of a response made of an OK packet.
+pub fn ok_affected_rows(payloads: &Option<Vec<Bytes>>) -> Option<u64> {
+    ok_counts(payloads).map(|(affected_rows, _)| affected_rows)
+}
+
+/// The last insert id of a response made of an OK packet.
+pub fn ok_last_insert_id(payloads: &Option<Vec<Bytes>>) -> Option<u64> {
+    ok_counts(payloads).map(|(_, last_insert_id)| last_insert_id)
+}
+
+/// The error code and message of a response starting with an ERR packet.
+pub fn err_code_message(payloads: &Option<Vec<Bytes>>) -> Option<(u16, String)> {
+    if !is_err_payloads(payloads) {
+        return None;
+    }
+    let payload = payloads.as_ref()?.first()?;
+    let code = u16::from_le_bytes([*payload.get(2)?, *payload.get(3)?]);
+    // The SQL state marker and the SQL state come before the message.
+    let message = payload.get(10..).map_or("".to_string(), |message| String::from_utf8_lossy(message).to_string());
+    Some((code, message))
+}
+
+/// Whether a payload is an EOF packet, which is shorter than any row starting with 0xfe.
+fn is_eof_payload(payload: &Bytes) -> bool {
+    payload.len() > 1 && payload[1] == 0xfe && payload.len() - 1 < 9
+}
+
+/// The payload with its sequence id moved back over the packets left out before it.
+fn renumbered(payload: Bytes, left_out: u8) -> Bytes {
+    if left_out == 0 || payload.is_empty() {
+        return payload;
+    }
+    let mut renumbered = payload.to_vec();
+    renumbered[0] = renumbered[0].wrapping_sub(left_out);
+    Bytes::from(renumbered)
+}
+
+/// The length encoded integer at the start of `bytes`.
+fn lenenc_int(bytes: &[u8]) -> usize {
+    let (len, first) = match bytes.first() {
+        Some(0xfc) => (2, 1),
+        Some(0xfd) => (3, 1),
+        Some(0xfe) => (8, 1),
+        Some(value) => return *value as usize,
+        None => return 0,
+    };
+    bytes.get(first..first + len)
+        .map_or(0, |bytes| bytes.iter().rev().fold(0usize, |value, byte| (value << 8) | *byte as usize))
+}
+
+/// What follows a group of definitions and the EOF packet ending it.
+#[derive(Clone, Copy)]
+enum EofNext {
+    /// The column definitions of a prepared statement.
+    Columns(usize),
+    Rows,
+    Start,
+}
+
+#[derive(Clone, Copy)]
+enum EofPhase {
+    /// At the first packet of a response: OK, ERR, COM_STMT_PREPARE_OK or a column count.
+    Start,
+    Definitions { remaining: usize, next: EofNext },
+    Rows,
+}
+
+/// Rewrites a response for a client that negotiated CLIENT_DEPRECATE_EOF one payload at a
+/// time, as it is streamed: the EOF packets ending column or parameter definitions are left
+/// out and the ones ending rows become OK packets.
+///
+/// @see <a href="https://dev.mysql.com/doc/internals/en/capability-flags.html#flag-CLIENT_DEPRECATE_EOF">CLIENT_DEPRECATE_EOF</a>
+pub struct EofDeprecator {
+    is_prepare: bool,
+    left_out: u8,
+    phase: EofPhase,
+}
+
+impl EofDeprecator {
+    pub fn new(command_packet_type: u8) -> Self {
+        EofDeprecator {
+            is_prepare: command_packet_type == MySQLCommandPacketType::ComStmtPrepare as u8,
+            left_out: 0,
+            phase: EofPhase::Start,
+        }
+    }
+
+    /// The payload to send in place of `payload`, None when it is left out.
+    pub fn deprecate(&mut self, payload: Bytes) -> Option<Bytes> {
+        match self.phase {
+            EofPhase::Start => {
+                match payload.get(1) {
+                    // COM_STMT_PREPARE_OK, then the parameter and the column definitions.
+                    Some(0x00) if self.is_prepare && payload.len() >= 10 => {
+                        let columns = u16::from_le_bytes([payload[6], payload[7]]) as usize;
+                        let parameters = u16::from_le_bytes([payload[8], payload[9]]) as usize;
+                        self.enter(parameters, EofNext::Columns(columns));
+                    }
+                    // OK or ERR.
+                    Some(0x00) | Some(0xff) | None => {}
+                    // A result set: the column count, the column definitions, EOF, the rows, EOF.
+                    Some(_) => self.enter(lenenc_int(&payload[1..]), EofNext::Rows),
+                }
+                Some(renumbered(payload, self.left_out))
+            }
+            EofPhase::Definitions { remaining: 0, next } => {
+                self.proceed(next);
+                if is_eof_payload(&payload) {
+                    self.left_out = self.left_out.wrapping_add(1);
+                    return None;
+                }
+                self.deprecate(payload)
+            }
+            EofPhase::Definitions { remaining, next } => {
+                self.phase = EofPhase::Definitions { remaining: remaining - 1, next };
+                Some(renumbered(payload, self.left_out))
+            }
+            EofPhase::Rows => {
+                if !is_eof_payload(&payload) {
+                    return Some(renumbered(payload, self.left_out));
+                }
+                self.phase = EofPhase::Start;
+                let sequence_id = payload[0].wrapping_sub(self.left_out) as u32;
+                let warnings = payload.get(2..4).map_or(0, |bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+                let status_flags = payload.get(4..6).map_or(0, |bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+                let mut ok_packet = MySQLOKPacket::new_eof(sequence_id, warnings, status_flags);
+                let mut ok_payload = MySQLPacketPayload::new();
+                Some(DatabasePacket::encode(&mut ok_packet, &mut ok_payload).get_payload())
+            }
+        }
+    }
+
+    fn enter(&mut self, definitions: usize, next: EofNext) {
+        if definitions > 0 {
+            self.phase = EofPhase::Definitions { remaining: definitions, next };
+        } else {
+            self.proceed(next);
+        }
+    }
+
+    fn proceed(&mut self, next: EofNext) {
+        match next {
+            EofNext::Columns(columns) => self.enter(columns, EofNext::Start),
+            EofNext::Rows => self.phase = EofPhase::Rows,
+            EofNext::Start => self.phase = EofPhase::Start,
+        }
+    }
+}
+
+/// A whole response for a client that negotiated CLIENT_DEPRECATE_EOF, see `EofDeprecator`.
+pub fn deprecate_eof_payloads(command_packet_type: u8, payloads: Vec<Bytes>) -> Vec<Bytes> {
+    let mut deprecator = EofDeprecator::new(command_packet_type);
+    payloads.into_iter().filter_map(|payload| deprecator.deprecate(payload)).collect()
+}
+
+/// Counts the rows of a response one payload at a time, as it is streamed: the payloads of a
+/// result set between the EOF ending its column definitions and the one ending its rows.
+#[derive(Default)]
+pub struct RowCounter {
+    eofs: u64,
+    rows: u64,
+}
+
+impl RowCounter {
+    pub fn count(&mut self, payload: &Bytes) {
+        if is_eof_payload(payload) {
+            self.eofs += 1;
+        } else if self.eofs % 2 == 1 && payload.get(1) != Some(&0xff) {
+            self.rows += 1;
+        }
+    }
+
+    pub fn get_rows(&self) -> u64 {
+        self.rows
+    }
+}
+
+/// Encodes a text result set of string columns produced by the mesh itself.
+pub fn text_result_payloads(columns: Vec<&str>, rows: Vec<Vec<String>>) -> Option<Vec<Bytes>> {
+    warned_text_result_payloads(columns, rows, 0)
+}
+
+/// SHOW WARNINGS for the warnings the mesh raised itself, e.g. on partial fan-out results.
+pub fn warnings_payloads(warnings: Vec<String>) -> Option<Vec<Bytes>> {
+    let rows = warnings.into_iter()
+        .map(|message| vec!["Warning".to_string(), "1105".to_string(), message])
+        .collect();
+    text_result_payloads(vec!["Level", "Code", "Message"], rows)
+}
+
+/// Like `text_result_payloads`, with None values sent as NULL.
+pub fn nullable_text_result_payloads(columns: Vec<&str>, rows: Vec<Vec<Option<String>>>) -> Option<Vec<Bytes>> {
+    result_payloads(columns, rows, 0)
+}
+
+/// Like `text_result_payloads`, with the warning count of the final EOF packet set.
+pub fn warned_text_result_payloads(columns: Vec<&str>, rows: Vec<Vec<String>>, warnings: u16) -> Option<Vec<Bytes>> {
+    let rows = rows.into_iter().map(|row| row.into_iter().map(Some).collect()).collect();
+    result_payloads(columns, rows, warnings)
+}
+
+fn result_payloads(columns: Vec<&str>, rows: Vec<Vec<Option<String>>>, warnings: u16) -> Option<Vec<Bytes>> {
+    let mut payloads = Vec::new();
+    let mut global_sequence_id: u32 = 1;
+
+    let mut field_count_packet = MySQLFieldCountPacket::new(global_sequence_id, columns.len() as u32);
+    let mut field_count_payload = MySQLPacketPayload::new();
+    payloads.push(DatabasePacket::encode(&mut field_count_packet, &mut field_count_payload).get_payload());
+
+    for name in columns {
+        global_sequence_id = global_sequence_id + 1;
+        let mut column_definition41_packet =
+            MySQLColumnDefinition41Packet::new(
+                global_sequence_id,
+                server_collation().get_id() as u16,
+                0,
+                "".to_string(),
+                "".to_string(),
+                "".to_string(),
+                name.to_string(),
+                name.to_string(),
+                1024,
+                MySQLColumnType::MysqlTypeVarString as u8,
+                0,
+            );
+        let mut column_definition41_payload = MySQLPacketPayload::new();
+        payloads.push(DatabasePacket::encode(&mut column_definition41_packet, &mut column_definition41_payload).get_payload());
+    }
+
+    global_sequence_id = global_sequence_id + 1;
+    let mut eof_packet = MySQLEOFPacket::new(global_sequence_id);
+    let mut eof_payload = MySQLPacketPayload::new();
+    payloads.push(DatabasePacket::encode(&mut eof_packet, &mut eof_payload).get_payload());
+
+    for row in rows {
+        global_sequence_id = global_sequence_id + 1;
+        let datas = row.into_iter()
+            .map(|value| match value {
+                Some(value) => (true, value.into_bytes()),
+                None => (false, Vec::new()),
+            })
+            .collect();
+        let mut text_result_set_row_packet = MySQLTextResultSetRowPacket::new(global_sequence_id, datas);
+        let mut text_result_set_row_payload = MySQLPacketPayload::new();
+        payloads.push(DatabasePacket::encode(&mut text_result_set_row_packet, &mut text_result_set_row_payload).get_payload());
+    }
+
+    global_sequence_id = global_sequence_id + 1;
+    let mut eof_packet = MySQLEOFPacket::new(global_sequence_id);
+    eof_packet.set_warnings(warnings);
+    let mut eof_payload = MySQLPacketPayload::new();
+    payloads.push(DatabasePacket::encode(&mut eof_packet, &mut eof_payload).get_payload());
+
+    Some(payloads)
 }
 
 pub struct CommandRootHandler {}
 
 impl CommandHandler<MySQLPacketPayload, SessionContext> for CommandRootHandler {
     fn handle(command_packet_header: Option<MySQLPacketHeader>, command_packet: Option<MySQLPacketPayload>, session_ctx: &mut SessionContext) -> Option<Vec<Bytes>> {
+        buffered(|sink| Self::handle_streaming(command_packet_header, command_packet, session_ctx, sink))
+    }
+
+    fn handle_streaming(command_packet_header: Option<MySQLPacketHeader>, command_packet: Option<MySQLPacketPayload>, session_ctx: &mut SessionContext, sink: &mut dyn PayloadSink) -> Option<Vec<Bytes>> {
         let command_packet_header = command_packet_header.unwrap();
         let command_packet = command_packet.unwrap();
         let command_packet_type = command_packet_header.get_command_packet_type();
         match MySQLCommandPacketType::value_of(command_packet_type) {
             MySQLCommandPacketType::ComQuery => {
-                ComQueryHandler::handle(Some(command_packet_header), Some(command_packet), session_ctx)
+                ComQueryHandler::handle_streaming(Some(command_packet_header), Some(command_packet), session_ctx, sink)
             }
             MySQLCommandPacketType::ComStmtPrepare => {
                 ComStmtPrepareHandler::handle(Some(command_packet_header), Some(command_packet), session_ctx)
             }
             MySQLCommandPacketType::ComStmtExecute => {
-                ComStmtExecuteHandler::handle(Some(command_packet_header), Some(command_packet), session_ctx)
+                ComStmtExecuteHandler::handle_streaming(Some(command_packet_header), Some(command_packet), session_ctx, sink)
             }
             MySQLCommandPacketType::ComStmtClose => {
                 ComStmtCloseHandler::handle(Some(command_packet_header), Some(command_packet), session_ctx)
@@ -45,6 +477,15 @@ impl CommandHandler<MySQLPacketPayload, SessionContext> for CommandRootHandler {
             MySQLCommandPacketType::ComPing => {
                 ComPingHandler::handle(Some(command_packet_header), None, session_ctx)
             }
+            MySQLCommandPacketType::ComStatistics => {
+                processlist::statistics_payloads()
+            }
+            MySQLCommandPacketType::ComProcessInfo => {
+                processlist::processlist_payloads(&session_ctx.get_user_name(), false)
+            }
+            MySQLCommandPacketType::ComProcessKill => {
+                ComProcessKillHandler::handle(Some(command_packet_header), Some(command_packet), session_ctx)
+            }
             _ => {
                 None
             }
@@ -57,6 +498,10 @@ pub struct HandshakeHandler {}
 impl CommandHandler<MySQLPacketPayload, SessionContext> for HandshakeHandler {
     fn handle(command_packet_header: Option<MySQLPacketHeader>, command_packet: Option<MySQLPacketPayload>, session_ctx: &mut SessionContext) -> Option<Vec<Bytes>> {
         let mut handshake_packet = MySQLHandshakePacket::new(session_ctx.get_thread_id() as u32, session_ctx.get_auth_plugin_data1(), session_ctx.get_auth_plugin_data2());
+        handshake_packet.set_character_set(server_collation().get_id());
+        if session_ctx.is_tls_offered() && tls::tls_acceptor().is_some() {
+            handshake_packet.enable_ssl();
+        }
         let mut handshake_payload = MySQLPacketPayload::new();
         let handshake_payload = DatabasePacket::encode(&mut handshake_packet, &mut handshake_payload);
         Some(vec![handshake_payload.get_payload()])
@@ -80,8 +525,28 @@ impl CommandHandler<MySQLPacketPayload, SessionContext> for AuthPhaseFastPathHan
             // TODO MySQLErrPacket
         }
 
-        if handshake_response41_packet.get_capability_flags().contains(MySQLCapabilityFlag::CLIENT_PLUGIN_AUTH)
-            && MySQLAuthenticationMethod::SecurePasswordAuthentication.value().to_string().eq(handshake_response41_packet.get_auth_plugin_name().as_str()) {
+        // caching_sha2_password responses are verified as they are, every other method is
+        // switched to mysql_native_password. Backends checking the password as typed get it
+        // with mysql_clear_password instead.
+        let auth_plugin_name = handshake_response41_packet.get_auth_plugin_name();
+        let plugin_auth = handshake_response41_packet.get_capability_flags().contains(MySQLCapabilityFlag::CLIENT_PLUGIN_AUTH);
+        if session_ctx.get_cert_user().is_some() {
+            // The client certificate authenticates the client, whatever it answered.
+            session_ctx.set_auth_plugin_name(auth_plugin_name);
+        } else if MeshConfig::get_auth_config().is_cleartext() {
+            let clear_text = MySQLAuthenticationMethod::ClearTextAuthentication.value().to_string();
+            if !plugin_auth || auth_plugin_name != clear_text {
+                session_ctx.set_connection_phase(MySQLConnectionPhase::AuthenticationMethodMismatch);
+                let mut auth_switch_request_packet = MySQLAuthSwitchRequestPacket::clear_text(handshake_response41_packet.get_sequence_id() + 1);
+                let mut auth_switch_request_payload = MySQLPacketPayload::new();
+                let auth_switch_request_payload = DatabasePacket::encode(&mut auth_switch_request_packet, &mut auth_switch_request_payload);
+                payloads.push(auth_switch_request_payload.get_payload());
+            }
+            session_ctx.set_auth_plugin_name(clear_text);
+        } else if plugin_auth && MySQLAuthenticationMethod::CachingSha2Password.value() == auth_plugin_name {
+            session_ctx.set_auth_plugin_name(auth_plugin_name);
+        } else {
+            session_ctx.set_auth_plugin_name(MySQLAuthenticationMethod::SecurePasswordAuthentication.value().to_string());
             session_ctx.set_connection_phase(MySQLConnectionPhase::AuthenticationMethodMismatch);
 
             let mut ok_auth_switch_request_packet = MySQLAuthSwitchRequestPacket::new(handshake_response41_packet.get_sequence_id() + 1, session_ctx.get_auth_plugin_data1(), session_ctx.get_auth_plugin_data2());
@@ -91,7 +556,23 @@ impl CommandHandler<MySQLPacketPayload, SessionContext> for AuthPhaseFastPathHan
             payloads.push(auth_switch_request_payload.get_payload());
         }
 
-        session_ctx.set_user_name(handshake_response41_packet.get_user_name());
+        session_ctx.set_deprecate_eof(handshake_response41_packet.get_capability_flags().contains(MySQLCapabilityFlag::CLIENT_DEPRECATE_EOF));
+        session_ctx.set_connect_attrs(handshake_response41_packet.get_connect_attrs());
+        // A charset other than the server's is set on every backend connection of the session.
+        let collation = collation_by_id(handshake_response41_packet.get_character_set()).unwrap_or_else(server_collation);
+        session_ctx.set_character_set(collation.get_id());
+        if collation.get_id() != server_collation().get_id() {
+            for (name, value) in negotiated_charset_variables(collation) {
+                session_ctx.set_variable(name, value);
+            }
+        }
+
+        // A client whose certificate maps to a user may leave the user out.
+        let user_name = match session_ctx.get_cert_user() {
+            Some(cert_user) if handshake_response41_packet.get_user_name().is_empty() => cert_user,
+            _ => handshake_response41_packet.get_user_name(),
+        };
+        session_ctx.set_user_name(user_name);
         session_ctx.set_auth_response(handshake_response41_packet.get_auth_response());
         session_ctx.set_database(handshake_response41_packet.get_database());
 
@@ -130,18 +611,30 @@ pub struct ComPingHandler {}
 impl CommandHandler<MySQLPacketPayload, SessionContext> for ComPingHandler {
     fn handle(command_packet_header: Option<MySQLPacketHeader>, command_packet: Option<MySQLPacketPayload>, session_ctx: &mut SessionContext) -> Option<Vec<Bytes>> {
         let mut ok_packet = MySQLOKPacket::new(1, 0, 0);
+        ok_packet.set_status_flags(session_ctx.get_status_flags());
         let mut ok_payload = MySQLPacketPayload::new();
         let ok_payload = DatabasePacket::encode(&mut ok_packet, &mut ok_payload);
         Some(vec![ok_payload.get_payload()])
     }
 }
 
+/// Kills the session whose id follows the command, like `KILL CONNECTION`.
+pub struct ComProcessKillHandler {}
+
+impl CommandHandler<MySQLPacketPayload, SessionContext> for ComProcessKillHandler {
+    fn handle(command_packet_header: Option<MySQLPacketHeader>, command_packet: Option<MySQLPacketPayload>, session_ctx: &mut SessionContext) -> Option<Vec<Bytes>> {
+        let session_id = command_packet.unwrap().get_uint_le(4);
+        cancel::kill_payloads(session_ctx, session_id, false)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
     use std::fs::File;
     use std::io::Read;
 
+    use bytes::Bytes;
     use mysql::Conn;
     use mysql::prelude::Queryable;
     use sqlparser::parser::Parser;
@@ -151,6 +644,9 @@ mod tests {
     use crate::handler::database::parser::sql::mysql::parser;
     use crate::handler::database::parser::sql::rewrite::SQLReWrite;
     use crate::handler::database::parser::sql::SQLStatementContext;
+    use crate::protocol::database::mysql::constant::MySQLCommandPacketType;
+
+    use super::{buffered, deprecate_eof_payloads, drain_into, EofDeprecator, PayloadSink, RowCounter, text_result_payloads};
 
     #[test]
     fn test_route() {
@@ -159,7 +655,7 @@ mod tests {
            WHERE user_id = 1 and a > b AND b < 100 \
            ORDER BY a DESC, b";
         //let sql = "insert into test (a, b, c) values (1, 1, ?)";
-        let mut sql_ast = parser(sql.to_string());
+        let mut sql_ast = parser(sql.to_string()).unwrap();
         let sql_stmt = sql_ast.pop().unwrap();
 
         let mut file = File::open("./etc/dbmesh.yaml").expect("Unable to open file");
@@ -176,4 +672,73 @@ mod tests {
 
         assert_eq!(sql.to_uppercase(), rewrite_sql.to_uppercase());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_deprecate_eof_payloads() {
+        let payloads = text_result_payloads(vec!["a"], vec![vec!["1".to_string()], vec!["2".to_string()]]).unwrap();
+        assert_eq!(payloads.len(), 6);
+        let deprecated = deprecate_eof_payloads(MySQLCommandPacketType::ComQuery as u8, payloads.clone());
+        // The column count, the column definition, the rows and the OK packet ending them.
+        assert_eq!(deprecated.len(), 5);
+        assert_eq!(deprecated[..2], payloads[..2]);
+        assert_eq!(deprecated[2], Bytes::from(vec![3u8, 0x01, b'1']));
+        assert_eq!(deprecated[3], Bytes::from(vec![4u8, 0x01, b'2']));
+        assert_eq!(deprecated[4], Bytes::from(vec![5u8, 0xfe, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00]));
+
+        let ok = Bytes::from(vec![1u8, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00]);
+        assert_eq!(deprecate_eof_payloads(MySQLCommandPacketType::ComQuery as u8, vec![ok.clone()]), vec![ok]);
+    }
+
+    /// A client that goes away after `accepted` payloads.
+    struct ClosingSink {
+        accepted: usize,
+        written: Vec<Bytes>,
+    }
+
+    impl PayloadSink for ClosingSink {
+        fn write(&mut self, payload: Bytes) -> std::io::Result<()> {
+            if self.written.len() == self.accepted {
+                return Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "closed"));
+            }
+            self.written.push(payload);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_streamed_payloads() {
+        let payloads = text_result_payloads(vec!["a"], vec![vec!["1".to_string()], vec!["2".to_string()]]).unwrap();
+
+        // Deprecating one payload at a time, as they are streamed, gives the whole response.
+        let mut deprecator = EofDeprecator::new(MySQLCommandPacketType::ComQuery as u8);
+        let streamed: Vec<Bytes> = payloads.iter().cloned().filter_map(|payload| deprecator.deprecate(payload)).collect();
+        assert_eq!(streamed, deprecate_eof_payloads(MySQLCommandPacketType::ComQuery as u8, payloads.clone()));
+
+        // What was written comes before what is returned.
+        let response = buffered(|sink| {
+            let mut head = payloads[..3].to_vec();
+            assert!(drain_into(&mut head, sink));
+            assert!(head.is_empty());
+            Some(payloads[3..].to_vec())
+        });
+        assert_eq!(response, Some(payloads.clone()));
+        assert_eq!(buffered(|_| None), None);
+
+        let mut sink = ClosingSink { accepted: 2, written: vec![] };
+        assert!(!drain_into(&mut payloads.clone(), &mut sink));
+        assert_eq!(sink.written, payloads[..2].to_vec());
+    }
+
+    #[test]
+    fn test_row_counter() {
+        let mut counter = RowCounter::default();
+        let payloads = text_result_payloads(vec!["a"], vec![vec!["1".to_string()], vec!["2".to_string()]]).unwrap();
+        // Two result sets, as a CALL answers.
+        payloads.iter().chain(payloads.iter()).for_each(|payload| counter.count(payload));
+        assert_eq!(counter.get_rows(), 4);
+
+        let mut counter = RowCounter::default();
+        counter.count(&Bytes::from(vec![1u8, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00]));
+        assert_eq!(counter.get_rows(), 0);
+    }
+}
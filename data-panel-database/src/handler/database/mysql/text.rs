@@ -1,9 +1,24 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
 use bytes::Bytes;
 
-use crate::handler::database::mysql::CommandHandler;
+use data_panel_common::common::Error;
+use data_panel_common::config::config::MeshConfig;
+
+use crate::common::arena::with_query_arena;
+use crate::handler::database::{approval, cancel, corpus, ddl, explain, fault, information_schema, mesh_admin, passthrough, procedure, processlist, route, route_cache, scatter, scheduler, sharded_insert, statement_stats, telemetry, traffic, transaction, variables, xa};
+use crate::handler::database::mysql::{buffered, CommandHandler, err_payloads, is_err_payloads, parse_statement, PayloadSink, warnings_payloads};
 use crate::handler::database::mysql::explainplan::{Executor, ExplainPlan, ExplainPlanContext, TBProtocol};
+use crate::handler::database::mysql::rdbc::err_payload;
 use crate::handler::database::parser;
+use crate::handler::database::parser::sql::{alias, column_acl, firewall, rewriter};
+use crate::handler::database::parser::sql::dialect::SQLDialect;
+use crate::handler::database::parser::sql::hint::SQLHints;
+use crate::handler::database::parser::sql::rewrite::DIALECT_KEY;
+use crate::handler::database::telemetry::Phase;
 use crate::protocol::database::DatabasePacket;
+use crate::protocol::database::mysql::constant::MySQLServerErrorCode;
 use crate::protocol::database::mysql::packet::{MySQLPacketHeader, MySQLPacketPayload};
 use crate::protocol::database::mysql::packet::text::MySQLComQueryPacket;
 use crate::session::mysql::SessionContext;
@@ -12,6 +27,10 @@ pub struct ComQueryHandler {}
 
 impl CommandHandler<MySQLPacketPayload, SessionContext> for ComQueryHandler {
     fn handle(command_packet_header: Option<MySQLPacketHeader>, command_packet_payload: Option<MySQLPacketPayload>, session_ctx: &mut SessionContext) -> Option<Vec<Bytes>> {
+        buffered(|sink| Self::handle_streaming(command_packet_header, command_packet_payload, session_ctx, sink))
+    }
+
+    fn handle_streaming(command_packet_header: Option<MySQLPacketHeader>, command_packet_payload: Option<MySQLPacketPayload>, session_ctx: &mut SessionContext, sink: &mut dyn PayloadSink) -> Option<Vec<Bytes>> {
         // 1 解析報文
         // 2 解析SQL
         // 3 服務發現
@@ -27,17 +46,165 @@ impl CommandHandler<MySQLPacketPayload, SessionContext> for ComQueryHandler {
         let command_packet = DatabasePacket::decode(&mut query_packet, &command_packet_header, &mut command_payload, session_ctx);
 
         let command_sql = command_packet.get_sql();
-        let cow_sql = String::from_utf8_lossy(command_sql.as_slice());
-        let sql = cow_sql.to_string();
-        println!("SQL = {}", sql);
-        let mut statement = parser::sql::mysql::parser(sql);
-        let statement = statement.pop().unwrap();
+        with_query_arena(|arena| {
+            // Read in place from the received packet, only SQL that is not UTF-8 is copied.
+            let sql: &str = match String::from_utf8_lossy(&command_sql) {
+                Cow::Borrowed(sql) => sql,
+                Cow::Owned(sql) => arena.alloc_str(&sql),
+            };
+            println!("SQL = {}", sql);
+            let warnings = session_ctx.get_warnings();
+            if !warnings.is_empty() && sql.trim().eq_ignore_ascii_case("SHOW WARNINGS") {
+                return warnings_payloads(warnings);
+            }
+            session_ctx.clear_warnings();
+            if let Some(explained) = route::explained_statement(sql) {
+                return route::explain_route(explained);
+            }
+            if let Some((session_id, query_only)) = cancel::kill_statement(sql) {
+                return cancel::kill_payloads(session_ctx, session_id, query_only);
+            }
+            if let Some(full) = processlist::processlist_statement(sql) {
+                return processlist::processlist_payloads(&session_ctx.get_user_name(), full);
+            }
+            if statement_stats::stats_statement(sql) {
+                return statement_stats::stats_payloads();
+            }
+            if procedure::is_call(sql) {
+                return passthrough::run_as_sent(sql, session_ctx, sink);
+            }
+            // EXPLAIN MESH goes through the checks and rewrites of the statement it explains.
+            let (sql, explain_mesh) = match explain::explained_statement(sql) {
+                Some((explained, backend)) => (explained, Some(backend)),
+                None => (sql, None),
+            };
+            corpus::sample(sql);
+            telemetry::enter(session_ctx, Phase::Parse);
+            // Hints are comments, which the parsed statement no longer has.
+            let hints = SQLHints::parse(sql);
+            let dialect = SQLDialect::configured();
+            let statement = match parse_statement(sql, dialect) {
+                Ok(statement) => statement,
+                Err(payloads) if explain_mesh.is_some() => return payloads,
+                Err(payloads) => {
+                    let user = session_ctx.get_user_name();
+                    return match passthrough::passes_through(sql, &user, &MeshConfig::get_passthrough_config(), &MeshConfig::get_tenant_config(), &MeshConfig::get_row_filter_config()) {
+                        Ok(true) => passthrough::execute(sql, session_ctx, sink),
+                        Ok(false) => payloads,
+                        Err(e) => Some(vec![err_payload(e)]),
+                    };
+                }
+            };
+            // Answered by the mesh from its own state, before the checks of the backend statements.
+            if explain_mesh.is_none() {
+                if let Some(payloads) = mesh_admin::intercept(&statement, session_ctx) {
+                    return Some(payloads);
+                }
+            }
+
+            if let Err(message) = approval::check(&statement, sql, session_ctx) {
+                return Some(vec![err_payload(Error::Auth(message))]);
+            }
+            let (statement, sql) = match firewall::check(&statement, &session_ctx.get_user_name(), &session_ctx.get_database(), &MeshConfig::get_firewall_config()) {
+                Ok(Some(rewritten)) => {
+                    let rewritten_sql: &str = arena.alloc_str(&rewritten.to_string());
+                    (rewritten, rewritten_sql)
+                }
+                Ok(None) => (statement, sql),
+                Err(message) => return Some(vec![err_payload(Error::Auth(message))]),
+            };
+            let (statement, sql) = match rewriter::rewrite(&statement, &hints, dialect, (&session_ctx.get_user_name(), &session_ctx.get_database(), &session_ctx.get_variables())) {
+                Ok(Some(rewritten)) => {
+                    let rewritten_sql: &str = arena.alloc_str(&rewritten.to_string());
+                    (rewritten, rewritten_sql)
+                }
+                Ok(None) => (statement, sql),
+                Err(e) => return Some(vec![err_payload(e)]),
+            };
+            if let Err(e) = fault::inject(&statement, sql, session_ctx) {
+                return Some(vec![err_payload(e)]);
+            }
+            if explain_mesh.is_none() {
+                if let Some(payloads) = transaction::intercept(&statement, session_ctx) {
+                    return Some(payloads);
+                }
+                if let Some(payloads) = variables::intercept(&statement, session_ctx) {
+                    return Some(payloads);
+                }
+                if let Some(payloads) = information_schema::intercept(&statement, sql, session_ctx) {
+                    return Some(payloads);
+                }
+            }
+
+            telemetry::enter(session_ctx, Phase::Route);
+            // Planned on the statement as sent, the one `statement` was parsed from.
+            let route_plan = route_cache::route_plan(sql, &statement, &hints);
+            if let Some(Err(message)) = route_plan.as_deref().map(route_cache::RoutePlan::check_join) {
+                return Some(vec![err_payload(Error::Routing(message))]);
+            }
+            let alias_config = MeshConfig::get_table_alias_config();
+            let mut rewrite_ctx = if alias_config.is_empty() {
+                HashMap::new()
+            } else {
+                alias::table_alias_context(&statement, &alias_config)
+            };
+            match column_acl::column_acl_context(&statement, &session_ctx.get_user_name(), &MeshConfig::get_column_acl_config(), &rewrite_ctx) {
+                Ok(masked) => rewrite_ctx.extend(masked),
+                Err(message) => return err_payloads(1, MySQLServerErrorCode::ErColumnaccessDeniedError, message),
+            }
+            if dialect != SQLDialect::MySQL {
+                rewrite_ctx.insert(DIALECT_KEY.to_string(), dialect.get_name().to_string());
+            }
+            let sql = if rewrite_ctx.is_empty() {
+                sql
+            } else {
+                match parser::sql::rewrite_statement(&statement, &rewrite_ctx) {
+                    Some(rewrite_sql) => arena.alloc_str(&rewrite_sql),
+                    None => sql,
+                }
+            };
+
+            if let Some(backend) = explain_mesh {
+                return explain::explain_mesh(&statement, sql, &hints, route_plan.as_deref(), &rewrite_ctx, backend, session_ctx);
+            }
 
-        let x_query_context = ExplainPlanContext::new(cow_sql.as_ref(),
-                                                      &statement, TBProtocol::Text);
-        let plan = ExplainPlan::new(&x_query_context);
+            let _share = match scheduler::fair_share(&session_ctx.get_user_name()) {
+                Ok(share) => share,
+                Err(e) => return Some(vec![err_payload(e)]),
+            };
+            if let Some(payloads) = ddl::intercept(&statement, &rewrite_ctx, session_ctx) {
+                return Some(payloads);
+            }
+            if let Err(e) = transaction::pin(&statement, session_ctx) {
+                return Some(vec![err_payload(e)]);
+            }
+            if let Some(payloads) = xa::intercept(&statement, &[], route_plan.as_deref(), &rewrite_ctx, session_ctx) {
+                return Some(payloads);
+            }
+            if let Some(payloads) = sharded_insert::intercept(&statement, &[], session_ctx) {
+                return Some(payloads);
+            }
+            if let Some(payloads) = scatter::intercept(&statement, sql, &[], &hints, route_plan.as_deref(), &rewrite_ctx, false, session_ctx, sink) {
+                return Some(payloads);
+            }
 
-        plan.execute()
+            let backend_url = traffic::route(&statement, &hints, session_ctx).unwrap_or_else(|| session_ctx.get_backend_url());
+            let backend_url = arena.alloc_str(&backend_url);
+            let x_query_context = ExplainPlanContext::new(sql, &statement, TBProtocol::Text, backend_url)
+                .route_plan(route_plan)
+                .hints(hints)
+                .pinned_conn(session_ctx.take_pinned_conn())
+                .session_variables(variables::replay_statements_in(session_ctx, arena))
+                .status_flags(session_ctx.get_status_flags())
+                .running_slot(Some(session_ctx.get_running_slot()));
+            telemetry::enter(session_ctx, Phase::Execute);
+            let payloads = {
+                let plan = ExplainPlan::new(&x_query_context);
+                fault::with_injected_latency(|| plan.execute_streaming(sink))
+            };
+            transaction::settle(&statement, x_query_context.take_pinned_conn(), is_err_payloads(&payloads), session_ctx);
+            payloads
+        })
     }
 }
 
@@ -0,0 +1,73 @@
+//! Table name aliasing for legacy schemas, see `TableAliasConfig`.
+
+use std::collections::HashMap;
+
+use sqlparser::ast::Statement;
+
+use data_panel_common::config::config::TableAliasConfig;
+
+use crate::handler::database::parser::sql::{rewrite_statement, statement_tables};
+
+/// The rewrite context mapping every aliased table of `statement` to its current name.
+pub fn table_alias_context(statement: &Statement, config: &TableAliasConfig) -> HashMap<String, String> {
+    statement_tables(statement).into_iter()
+        .filter_map(|table| config.resolve(&table).map(|alias| (table, alias)))
+        .collect()
+}
+
+/// `sql` with its legacy table names replaced, or None when no alias applies.
+pub fn rewrite_table_alias(statement: &Statement, config: &TableAliasConfig) -> Option<String> {
+    if config.is_empty() {
+        return None;
+    }
+    let ctx = table_alias_context(statement, config);
+    if ctx.is_empty() {
+        return None;
+    }
+    rewrite_statement(statement, &ctx)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use data_panel_common::config::config::{TableAliasConfig, TableAliasRule};
+
+    use crate::handler::database::parser::sql::alias::rewrite_table_alias;
+    use crate::handler::database::parser::sql::mysql::parser;
+    use crate::handler::database::parser::sql::rewrite_statement;
+
+    #[test]
+    fn test_rewrite_table_alias() {
+        let config = TableAliasConfig::new(vec![
+            TableAliasRule::exact("orders", "t_order"),
+            TableAliasRule::like("legacy_%_item", "t_%_item"),
+        ]);
+        assert!(config.validate().is_ok());
+
+        let statement = parser("SELECT * FROM orders o JOIN legacy_order_item i ON o.id = i.order_id".to_string()).unwrap().pop().unwrap();
+        assert_eq!(rewrite_table_alias(&statement, &config).unwrap(),
+                   "SELECT * FROM t_order AS o JOIN t_order_item AS i ON o.id = i.order_id");
+
+        let statement = parser("DELETE FROM orders WHERE id = 1".to_string()).unwrap().pop().unwrap();
+        assert_eq!(rewrite_table_alias(&statement, &config).unwrap(), "DELETE FROM t_order WHERE id = 1");
+
+        let statement = parser("SELECT * FROM t_user".to_string()).unwrap().pop().unwrap();
+        assert!(rewrite_table_alias(&statement, &config).is_none());
+
+        // As prepared, the placeholders kept.
+        let statement = parser("SELECT * FROM ORDERS WHERE id = ?".to_string()).unwrap().pop().unwrap();
+        assert_eq!(rewrite_table_alias(&statement, &config).unwrap(), "SELECT * FROM t_order WHERE id = ?");
+
+        let mut ctx = HashMap::new();
+        ctx.insert("orders".to_string(), "t_order".to_string());
+        let statement = parser("SELECT * FROM Orders o JOIN ORDERS p ON o.id = p.parent_id".to_string()).unwrap().pop().unwrap();
+        assert_eq!(rewrite_statement(&statement, &ctx).unwrap(), "SELECT * FROM t_order AS o JOIN t_order AS p ON o.id = p.parent_id");
+
+        let conflicting = TableAliasConfig::new(vec![
+            TableAliasRule::exact("orders", "t_order"),
+            TableAliasRule::exact("t_order", "t_order_v2"),
+        ]);
+        assert!(conflicting.validate().is_err());
+    }
+}
@@ -12,11 +12,11 @@
 
 //! SQL Abstract Syntax Tree (AST) types
 
-use sqlparser::ast::{AddDropSync, Assignment, Expr, FileFormat, Function, FunctionArg, HiveDistributionStyle, HiveFormat, HiveIOFormat, HiveRowFormat, Ident, ListAgg, ListAggOnOverflow, ObjectName, ObjectType, SetVariableValue, ShowStatementFilter, SqliteOnConflict, SqlOption, Statement, TransactionAccessMode, TransactionIsolationLevel, TransactionMode, UnaryOperator, WindowFrameBound, WindowFrameUnits, WindowSpec};
+use sqlparser::ast::{AddDropSync, Assignment, BinaryOperator, Expr, FileFormat, Function, FunctionArg, HiveDistributionStyle, HiveFormat, HiveIOFormat, HiveRowFormat, Ident, ListAgg, ListAggOnOverflow, ObjectName, ObjectType, Query, SetExpr, SetVariableValue, ShowStatementFilter, SqliteOnConflict, SqlOption, Statement, TransactionAccessMode, TransactionIsolationLevel, TransactionMode, UnaryOperator, Value, WindowFrameBound, WindowFrameUnits, WindowSpec};
 use sqlparser::tokenizer::{Token, Whitespace, Word};
 
 // use std::fmt::Write;
-use crate::handler::database::parser::sql::SQLStatementContext;
+use crate::handler::database::parser::sql::{Condition, ConditionOperator, OrderByItem, SQLStatementContext};
 
 mod data_type;
 mod ddl;
@@ -103,6 +103,7 @@ impl SQLAnalyse for Expr {
         match self {
             Expr::Identifier(s) => {
                 s.analyse(ctx)?;
+                ctx.add_column(s.value.clone());
             }
             Expr::MapAccess { column, key } => {
                 column.analyse(ctx)?;
@@ -110,13 +111,18 @@ impl SQLAnalyse for Expr {
             }
             Expr::Wildcard => {
                 // f.write_str("*")?;
+                ctx.add_wildcard("".to_string());
             }
             Expr::QualifiedWildcard(q) => {
                 display_separated(q, ".").analyse(ctx)?;
                 // write!(f, ".*")?;
+                ctx.add_wildcard(q.last().map_or("".to_string(), |ident| ident.value.clone()));
             }
             Expr::CompoundIdentifier(s) => {
                 display_separated(s, ".").analyse(ctx)?;
+                if let Some(column) = s.last() {
+                    ctx.add_column(column.value.clone());
+                }
             }
             Expr::IsNull(ast) => {
                 ast.analyse(ctx)?;
@@ -402,6 +408,7 @@ impl SQLAnalyse for Statement {
             }
             Statement::Query(s) => {
                 s.analyse(ctx)?;
+                query_clauses(s, ctx);
             }
             Statement::Directory {
                 overwrite,
@@ -508,6 +515,7 @@ impl SQLAnalyse for Statement {
                     display_comma_separated(columns).analyse(ctx)?;
                     // write!(f, ") ")?;
                 }
+                ctx.add_table(table_name.to_string(), "".to_string());
                 if let Some(ref parts) = partitioned {
                     if !parts.is_empty() {
                         // write!(f, "PARTITION (")?;
@@ -521,6 +529,7 @@ impl SQLAnalyse for Statement {
                     // write!(f, ") ")?;
                 }
                 source.analyse(ctx)?;
+                insert_rows(columns, source, ctx);
             }
             Statement::Copy {
                 table_name,
@@ -558,6 +567,7 @@ impl SQLAnalyse for Statement {
             } => {
                 // write!(f, "UPDATE ")?;
                 table_name.analyse(ctx)?;
+                ctx.add_table(table_name.to_string(), "".to_string());
                 if !assignments.is_empty() {
                     // write!(f, " SET ")?;
                     display_comma_separated(assignments).analyse(ctx)?;
@@ -570,6 +580,12 @@ impl SQLAnalyse for Statement {
                     // write!(f, " LIMIT ")?;
                     limit.analyse(ctx)?;
                 }
+                if let Some(common_ctx) = ctx.common_ctx_mut() {
+                    if let Some(selection) = selection {
+                        where_conditions(selection, &mut common_ctx.conditions);
+                    }
+                    common_ctx.limit = limit.as_ref().and_then(number);
+                }
             }
             Statement::Delete {
                 table_name,
@@ -577,9 +593,13 @@ impl SQLAnalyse for Statement {
             } => {
                 // write!(f, "DELETE FROM ")?;
                 table_name.analyse(ctx)?;
+                ctx.add_table(table_name.to_string(), "".to_string());
                 if let Some(selection) = selection {
                     // write!(f, " WHERE ")?;
                     selection.analyse(ctx)?;
+                    if let Some(common_ctx) = ctx.common_ctx_mut() {
+                        where_conditions(selection, &mut common_ctx.conditions);
+                    }
                 }
             }
             Statement::CreateDatabase {
@@ -656,6 +676,9 @@ impl SQLAnalyse for Statement {
                 //     external = if *external { "EXTERNAL " } else { "" },
                 //     if_not_exists = if *if_not_exists { "IF NOT EXISTS " } else { "" },
                 // )?;
+                if *temporary {
+                    ctx.set_temporary();
+                }
                 name.analyse(ctx)?;
                 if !columns.is_empty() || !constraints.is_empty() {
                     // write!(f, " (")?;
@@ -1433,6 +1456,106 @@ fn matching_end_quote(ch: char) -> char {
     }
 }
 
+/// The literal `expr` is, strings without their quotes.
+fn literal(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Value(Value::SingleQuotedString(value)) | Expr::Value(Value::NationalStringLiteral(value)) => Some(value.clone()),
+        Expr::Value(Value::Null) => None,
+        Expr::Value(value) => Some(value.to_string()),
+        Expr::UnaryOp { op: UnaryOperator::Minus, expr } => literal(expr).map(|value| format!("-{}", value)),
+        Expr::Nested(expr) => literal(expr),
+        _ => None,
+    }
+}
+
+fn number(expr: &Expr) -> Option<u64> {
+    match expr {
+        Expr::Value(Value::Number(number, _)) => number.parse().ok(),
+        _ => None,
+    }
+}
+
+/// The qualifier, empty when unqualified, and the name of the column `expr` is.
+fn column(expr: &Expr) -> Option<(String, String)> {
+    match expr {
+        Expr::Identifier(column) => Some(("".to_string(), column.value.clone())),
+        Expr::CompoundIdentifier(idents) => {
+            let (column, qualifier) = idents.split_last()?;
+            Some((qualifier.last().map_or("".to_string(), |ident| ident.value.clone()), column.value.clone()))
+        }
+        Expr::Nested(expr) => column(expr),
+        _ => None,
+    }
+}
+
+/// Collects the conditions of `selection` joined by AND, the ones holding for every row.
+fn where_conditions(selection: &Expr, conditions: &mut Vec<Condition>) {
+    match selection {
+        Expr::BinaryOp { left, op: BinaryOperator::And, right } => {
+            where_conditions(left, conditions);
+            where_conditions(right, conditions);
+        }
+        Expr::Nested(expr) => where_conditions(expr, conditions),
+        Expr::BinaryOp { left, op, right } => {
+            let operator = match op {
+                BinaryOperator::Eq => ConditionOperator::Eq,
+                BinaryOperator::Lt => ConditionOperator::Lt,
+                BinaryOperator::LtEq => ConditionOperator::LtEq,
+                BinaryOperator::Gt => ConditionOperator::Gt,
+                BinaryOperator::GtEq => ConditionOperator::GtEq,
+                _ => return,
+            };
+            if let (Some((qualifier, column)), Some(value)) = (column(left), literal(right)) {
+                conditions.push(Condition::new(qualifier, column, operator, vec![value]));
+            } else if let (Some(value), Some((qualifier, column))) = (literal(left), column(right)) {
+                conditions.push(Condition::new(qualifier, column, operator.reversed(), vec![value]));
+            }
+        }
+        Expr::Between { expr, negated: false, low, high } => {
+            if let (Some((qualifier, column)), Some(low), Some(high)) = (column(expr), literal(low), literal(high)) {
+                conditions.push(Condition::new(qualifier, column, ConditionOperator::Between, vec![low, high]));
+            }
+        }
+        Expr::InList { expr, list, negated: false } => {
+            let values: Option<Vec<String>> = list.iter().map(literal).collect();
+            if let (Some((qualifier, column)), Some(values)) = (column(expr), values) {
+                conditions.push(Condition::new(qualifier, column, ConditionOperator::In, values));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Records the WHERE conditions, the ORDER BY and the LIMIT of the outermost query.
+fn query_clauses(query: &Query, ctx: &mut SQLStatementContext) {
+    let common_ctx = match ctx.common_ctx_mut() {
+        Some(common_ctx) => common_ctx,
+        None => return,
+    };
+    if let SetExpr::Select(select) = &query.body {
+        if let Some(selection) = &select.selection {
+            where_conditions(selection, &mut common_ctx.conditions);
+        }
+    }
+    common_ctx.order_by = query.order_by.iter()
+        .map(|order_by| OrderByItem::new(order_by.expr.to_string(), order_by.asc.unwrap_or(true)))
+        .collect();
+    common_ctx.limit = query.limit.as_ref().and_then(number);
+    common_ctx.offset = query.offset.as_ref().and_then(|offset| number(&offset.value));
+}
+
+/// Records the columns and the literal rows of an INSERT ... VALUES.
+fn insert_rows(columns: &[Ident], source: &Query, ctx: &mut SQLStatementContext) {
+    let common_ctx = match ctx.common_ctx_mut() {
+        Some(common_ctx) => common_ctx,
+        None => return,
+    };
+    common_ctx.insert_columns = columns.iter().map(|column| column.value.clone()).collect();
+    if let SetExpr::Values(values) = &source.body {
+        common_ctx.insert_rows = values.0.iter().map(|row| row.iter().map(literal).collect()).collect();
+    }
+}
+
 impl SQLAnalyse for Whitespace {
     fn analyse(&self, ctx: &mut SQLStatementContext) -> SAResult {
         // match self {
@@ -1448,10 +1571,110 @@ impl SQLAnalyse for Whitespace {
 
 #[cfg(test)]
 mod tests {
-    use crate::handler::database::parser::sql::{SelectStatementContext, SQLStatementContext};
+    use crate::handler::database::parser::sql::{analyse_statement_context, ConditionOperator, OrderByItem, SelectStatementContext, SQLStatementContext, TransactionOperation};
     use crate::handler::database::parser::sql::analyse::SQLAnalyse;
     use crate::handler::database::parser::sql::mysql::parser;
 
+    fn analyse(sql: &str) -> SQLStatementContext {
+        analyse_statement_context(&parser(sql.to_string()).unwrap().pop().unwrap()).unwrap()
+    }
+
+    fn conditions(ctx: &SQLStatementContext) -> Vec<(String, ConditionOperator, Vec<String>)> {
+        ctx.get_common_ctx().unwrap().get_conditions().iter()
+            .map(|condition| (condition.get_column().to_string(), condition.get_operator(), condition.get_values().clone()))
+            .collect()
+    }
+
+    fn values(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn test_analyse_select_context() {
+        let ctx = analyse("SELECT o.id, o.status FROM t_order o JOIN t_user u ON o.user_id = u.id \
+            WHERE o.user_id = 10 AND o.created > '2021-01-01' AND (o.status = 'PAID' OR o.id = 1) \
+            ORDER BY o.id DESC, u.id LIMIT 20 OFFSET 40");
+        assert!(matches!(ctx, SQLStatementContext::Select(_)));
+        let common_ctx = ctx.get_common_ctx().unwrap();
+        assert_eq!(common_ctx.get_tables().get("t_order"), Some(&"o".to_string()));
+        assert_eq!(common_ctx.get_tables().get("t_user"), Some(&"u".to_string()));
+        assert!(common_ctx.get_columns().contains(&"status".to_string()));
+        assert_eq!(conditions(&ctx), vec![
+            ("user_id".to_string(), ConditionOperator::Eq, values(&["10"])),
+            ("created".to_string(), ConditionOperator::Gt, values(&["2021-01-01"])),
+        ]);
+        assert_eq!(common_ctx.get_column_conditions("USER_ID")[0].get_qualifier(), "o");
+        assert_eq!(common_ctx.get_order_by(), &vec![OrderByItem::new("o.id".to_string(), false), OrderByItem::new("u.id".to_string(), true)]);
+        assert_eq!((common_ctx.get_limit(), common_ctx.get_offset()), (Some(20), Some(40)));
+
+        let ctx = analyse("SELECT * FROM t_order WHERE 100 > user_id AND id BETWEEN 1 AND 5");
+        assert_eq!(conditions(&ctx), vec![
+            ("user_id".to_string(), ConditionOperator::Lt, values(&["100"])),
+            ("id".to_string(), ConditionOperator::Between, values(&["1", "5"])),
+        ]);
+        assert_eq!(ctx.get_common_ctx().unwrap().get_wildcards(), &vec!["".to_string()]);
+    }
+
+    #[test]
+    fn test_analyse_update_context() {
+        let ctx = analyse("UPDATE t_order SET status = 'PAID' WHERE user_id IN (1, 2) AND id = 5");
+        assert!(matches!(ctx, SQLStatementContext::Update(_)));
+        assert!(ctx.get_common_ctx().unwrap().get_tables().contains_key("t_order"));
+        assert_eq!(conditions(&ctx), vec![
+            ("user_id".to_string(), ConditionOperator::In, values(&["1", "2"])),
+            ("id".to_string(), ConditionOperator::Eq, values(&["5"])),
+        ]);
+    }
+
+    #[test]
+    fn test_analyse_delete_context() {
+        let ctx = analyse("DELETE FROM t_order WHERE user_id = 10 OR id = 1");
+        assert!(matches!(ctx, SQLStatementContext::Delete(_)));
+        assert!(ctx.get_common_ctx().unwrap().get_tables().contains_key("t_order"));
+        assert!(conditions(&ctx).is_empty());
+    }
+
+    #[test]
+    fn test_analyse_insert_context() {
+        let ctx = analyse("INSERT INTO t_order (id, user_id, status) VALUES (NULL, 10, 'PAID'), (NULL, 11, NOW())");
+        match &ctx {
+            SQLStatementContext::Insert(insert_ctx) => {
+                assert_eq!(insert_ctx.get_table(), "t_order");
+                assert_eq!(insert_ctx.get_generated_key_column(), Some(&"id".to_string()));
+                assert_eq!(insert_ctx.get_row_count(), Some(2));
+            }
+            _ => panic!("expected an insert context"),
+        }
+        let common_ctx = ctx.get_common_ctx().unwrap();
+        assert!(common_ctx.get_tables().contains_key("t_order"));
+        assert_eq!(common_ctx.get_insert_columns(), &values(&["id", "user_id", "status"]));
+        assert_eq!(common_ctx.get_inserted_values("user_id"), vec![Some("10".to_string()), Some("11".to_string())]);
+        assert_eq!(common_ctx.get_inserted_values("status"), vec![Some("PAID".to_string()), None]);
+    }
+
+    #[test]
+    fn test_analyse_ddl_and_transaction_context() {
+        match analyse("DROP TABLE t_order, t_order_item") {
+            SQLStatementContext::Ddl(ddl_ctx) => {
+                assert_eq!((ddl_ctx.get_operation(), ddl_ctx.get_object_type(), ddl_ctx.get_name()), ("DROP", "TABLE", "t_order"));
+                assert_eq!(ddl_ctx.get_names().len(), 2);
+            }
+            _ => panic!("expected a DDL context"),
+        }
+        match analyse("ALTER TABLE t_order ADD COLUMN note TEXT") {
+            SQLStatementContext::Ddl(ddl_ctx) => assert_eq!((ddl_ctx.get_operation(), ddl_ctx.get_name()), ("ALTER", "t_order")),
+            _ => panic!("expected a DDL context"),
+        }
+        match analyse("ROLLBACK TO SAVEPOINT before_items") {
+            SQLStatementContext::Transaction(transaction_ctx) => {
+                assert_eq!(transaction_ctx.get_operation(), TransactionOperation::RollbackToSavepoint);
+                assert_eq!(transaction_ctx.get_savepoint(), Some(&"before_items".to_string()));
+            }
+            _ => panic!("expected a transaction context"),
+        }
+        assert!(matches!(analyse("COMMIT"), SQLStatementContext::Transaction(_)));
+    }
+
     #[test]
     fn test_analyse() {
         let sql = "SELECT a, b, 123, myfunc(b) \
@@ -1459,7 +1682,7 @@ mod tests {
            WHERE a > b AND b < 100 and c = ? and d = 'are' \
            ORDER BY a DESC, b";
         // let sql = "insert into test (a, b, c) values (1, 1, ?)";
-        let mut ast = parser(sql.to_string());
+        let mut ast = parser(sql.to_string()).unwrap();
         let stmt = ast.pop().unwrap();
         let mut resql = String::new();
         let mut ctx = SQLStatementContext::Select(SelectStatementContext::new());
@@ -1470,9 +1693,7 @@ mod tests {
             SQLStatementContext::Select(mut s) => {
                 println!("{:?}", s.common_ctx.tables);
             }
-            SQLStatementContext::Update(_) => {}
-            SQLStatementContext::Delete(_) => {}
-            SQLStatementContext::Default => {}
+            _ => {}
         }
     }
 }
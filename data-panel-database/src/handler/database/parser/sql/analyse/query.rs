@@ -167,9 +167,11 @@ impl SQLAnalyse for SelectItem {
             SelectItem::QualifiedWildcard(prefix) => {
                 prefix.analyse(ctx)?;
                 // write!(f, ".*")?;
+                ctx.add_wildcard(prefix.0.last().map_or("".to_string(), |ident| ident.value.clone()));
             }
             SelectItem::Wildcard => {
                 // write!(f, "*")?;
+                ctx.add_wildcard("".to_string());
             }
         }
         Ok(())
@@ -0,0 +1,82 @@
+//! Column level read authorization, see `ColumnAclConfig`.
+//!
+//! Columns are matched by name against the deny lists of every table the statement reads, so
+//! a denied column is denied whichever table of a join it is qualified with. A wildcard over a
+//! table with denied columns is always rejected, it cannot be masked without the table schema.
+
+use std::collections::HashMap;
+
+use sqlparser::ast::Statement;
+
+use data_panel_common::config::config::ColumnAclConfig;
+
+use crate::handler::database::parser::sql::{analyse_statement, unquoted_table};
+use crate::handler::database::parser::sql::rewrite::masked_column_key;
+
+/// The rewrite context masking the denied columns `statement` reads, or why it is rejected.
+/// `renames` maps legacy table names to their current name, as the rules use current names.
+pub fn column_acl_context(statement: &Statement, user: &str, config: &ColumnAclConfig,
+                          renames: &HashMap<String, String>) -> Result<HashMap<String, String>, String> {
+    let mut ctx = HashMap::new();
+    if config.is_empty() || !matches!(statement, Statement::Query(_)) {
+        return Ok(ctx);
+    }
+    let select_ctx = match analyse_statement(statement) {
+        Some(select_ctx) => select_ctx,
+        None => return Err("SELECT command denied, unable to analyse the statement".to_string()),
+    };
+
+    for (name, alias) in select_ctx.get_tables() {
+        let table = unquoted_table(name);
+        let table = renames.get(&table).cloned().unwrap_or(table);
+        let denied = config.denied_columns(user, &table);
+        if denied.is_empty() {
+            continue;
+        }
+        let expanded = select_ctx.get_wildcards().iter()
+            .any(|qualifier| qualifier.is_empty() || qualifier == alias || *qualifier == unquoted_table(name));
+        if expanded {
+            return Err(format!("SELECT command denied to user '{}' for * in table '{}', name the columns instead", user, table));
+        }
+        for column in select_ctx.get_columns() {
+            if !denied.contains(&column.to_lowercase()) {
+                continue;
+            }
+            if !config.is_mask() {
+                return Err(format!("SELECT command denied to user '{}' for column '{}' in table '{}'", user, column, table));
+            }
+            ctx.insert(masked_column_key(column), "NULL".to_string());
+        }
+    }
+    Ok(ctx)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use data_panel_common::config::config::{ColumnAclConfig, ColumnDenyRule};
+
+    use crate::handler::database::parser::sql::column_acl::column_acl_context;
+    use crate::handler::database::parser::sql::mysql::parser;
+    use crate::handler::database::parser::sql::rewrite_statement;
+
+    #[test]
+    fn test_column_acl() {
+        let config = ColumnAclConfig::new(vec![ColumnDenyRule::new("analyst", "t_user", vec!["phone"])]);
+        assert!(config.validate().is_ok());
+        let renames = HashMap::new();
+
+        let statement = parser("SELECT u.name, u.phone FROM t_user u".to_string()).unwrap().pop().unwrap();
+        assert!(column_acl_context(&statement, "analyst", &config, &renames).is_err());
+        assert!(column_acl_context(&statement, "admin", &config, &renames).unwrap().is_empty());
+
+        let statement = parser("SELECT * FROM t_user".to_string()).unwrap().pop().unwrap();
+        assert!(column_acl_context(&statement, "analyst", &config.clone().mask(), &renames).is_err());
+
+        let statement = parser("SELECT name, phone FROM t_user WHERE phone LIKE '139%'".to_string()).unwrap().pop().unwrap();
+        let ctx = column_acl_context(&statement, "analyst", &config.mask(), &renames).unwrap();
+        assert_eq!(rewrite_statement(&statement, &ctx).unwrap(),
+                   "SELECT name, NULL AS phone FROM t_user WHERE NULL LIKE '139%'");
+    }
+}
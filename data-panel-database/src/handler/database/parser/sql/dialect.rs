@@ -0,0 +1,103 @@
+//! The SQL dialects statements are parsed in, see `ParserConfig`.
+//!
+//! Text statements are parsed in the dialect the config names, MySQL unless it names one; the
+//! protocol the client speaks does not choose it, the PostgreSQL bridge translating its
+//! statements without parsing them. The backends speak MySQL whatever the dialect: rewriting a
+//! statement of another dialect writes its double quoted identifiers with backticks. Prepared
+//! statements stay in MySQL, the dialect of their `?` placeholders.
+
+use sqlparser::ast::Statement;
+use sqlparser::dialect::{Dialect, PostgreSqlDialect, SQLiteDialect};
+use sqlparser::parser::ParserError;
+use sqlparser::tokenizer::{Token, Tokenizer, TokenizerError};
+
+use data_panel_common::config::config::MeshConfig;
+
+use crate::handler::database::parser::sql::{mysql, postgresql, sqlite};
+use crate::handler::database::parser::sql::mysql::MySQLDialect;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SQLDialect {
+    MySQL,
+    PostgreSQL,
+    SQLite,
+}
+
+impl Default for SQLDialect {
+    fn default() -> Self {
+        SQLDialect::MySQL
+    }
+}
+
+impl SQLDialect {
+    /// The dialect named `name` in the config, case insensitive.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "mysql" => Some(SQLDialect::MySQL),
+            "postgresql" | "postgres" => Some(SQLDialect::PostgreSQL),
+            "sqlite" => Some(SQLDialect::SQLite),
+            _ => None,
+        }
+    }
+
+    /// The dialect the config names for every client, MySQL unless it names one.
+    pub fn configured() -> Self {
+        SQLDialect::from_name(&MeshConfig::get_parser_config().get_dialect()).unwrap_or_default()
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            SQLDialect::MySQL => "mysql",
+            SQLDialect::PostgreSQL => "postgresql",
+            SQLDialect::SQLite => "sqlite",
+        }
+    }
+
+    /// The statements of `sql`, or why it does not parse, see `syntax`.
+    pub fn parser(&self, sql: String) -> Result<Vec<Statement>, ParserError> {
+        match self {
+            SQLDialect::MySQL => mysql::parser(sql),
+            SQLDialect::PostgreSQL => postgresql::parser(sql),
+            SQLDialect::SQLite => sqlite::parser(sql),
+        }
+    }
+
+    /// The tokens of `sql`, whitespace and comments included.
+    pub fn tokenize(&self, sql: &str) -> Result<Vec<Token>, TokenizerError> {
+        let dialect: &dyn Dialect = match self {
+            SQLDialect::MySQL => &MySQLDialect {},
+            SQLDialect::PostgreSQL => &PostgreSqlDialect {},
+            SQLDialect::SQLite => &SQLiteDialect {},
+        };
+        Tokenizer::new(dialect, sql).tokenize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::handler::database::parser::sql::{analyse_dialect_statement_context, rewrite_statement};
+    use crate::handler::database::parser::sql::rewrite::DIALECT_KEY;
+
+    use super::SQLDialect;
+
+    #[test]
+    fn test_dialect() {
+        assert_eq!(SQLDialect::from_name("PostgreSQL"), Some(SQLDialect::PostgreSQL));
+        assert_eq!(SQLDialect::from_name("oracle"), None);
+        // An empty dialect in the config leaves the default.
+        assert_eq!(SQLDialect::from_name(""), None);
+
+        let dialect = SQLDialect::PostgreSQL;
+        let statement = dialect.parser("SELECT \"id\" FROM \"t_order\" WHERE status = 'PAID'".to_string()).unwrap().pop().unwrap();
+        let analyse_ctx = analyse_dialect_statement_context(&statement, dialect).unwrap();
+        assert_eq!(analyse_ctx.get_common_ctx().unwrap().get_dialect(), SQLDialect::PostgreSQL);
+
+        let mut ctx = HashMap::new();
+        ctx.insert(DIALECT_KEY.to_string(), dialect.get_name().to_string());
+        assert_eq!(rewrite_statement(&statement, &ctx).unwrap(), "SELECT `id` FROM `t_order` WHERE status = 'PAID'");
+        assert!(SQLDialect::MySQL.parser("SELECT `id` FROM t_order".to_string()).is_ok());
+        assert!(SQLDialect::SQLite.parser("SELECT FROM".to_string()).is_err());
+    }
+}
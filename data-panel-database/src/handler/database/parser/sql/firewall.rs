@@ -0,0 +1,126 @@
+//! Statement firewall, see `FirewallConfig`.
+//!
+//! The tables a statement touches come from its targets and from the analyse pass, so a
+//! denied table is caught in a join or a subquery as well. Names without a schema are on the
+//! session's database. Denied statements are written to the audit log.
+
+use sqlparser::ast::{Expr, ObjectType, Statement, Value};
+
+use data_panel_common::config::config::{FirewallConfig, FirewallRule};
+
+use crate::handler::database::parser::sql::{analyse_statement, unquoted_table};
+
+/// The type of `statement`, e.g. `SELECT` or `DROP`.
+fn statement_type(statement: &Statement) -> String {
+    match statement {
+        Statement::Query(_) => "SELECT".to_string(),
+        statement => statement.to_string().split_whitespace().next().unwrap_or("").to_uppercase(),
+    }
+}
+
+/// The schema, `database` when unqualified, and the table `name` refers to.
+fn schema_table(name: &str, database: &str) -> (String, String) {
+    let table = unquoted_table(name);
+    let schema = match name.rsplitn(2, '.').nth(1) {
+        Some(schema) => unquoted_table(schema),
+        None => database.to_string(),
+    };
+    (schema, table)
+}
+
+/// The schemas and the tables `statement` touches, tables are empty for a whole schema.
+fn statement_objects(statement: &Statement, database: &str) -> Vec<(String, String)> {
+    let mut objects = vec![];
+    match statement {
+        Statement::Insert { table_name, .. }
+        | Statement::Update { table_name, .. }
+        | Statement::Delete { table_name, .. }
+        | Statement::Truncate { table_name, .. }
+        | Statement::CreateIndex { table_name, .. } => objects.push(schema_table(&table_name.to_string(), database)),
+        Statement::AlterTable { name, .. } => objects.push(schema_table(&name.to_string(), database)),
+        Statement::Drop { object_type: ObjectType::Schema, names, .. } => {
+            objects.extend(names.iter().map(|name| (unquoted_table(&name.to_string()), "".to_string())));
+        }
+        Statement::Drop { names, .. } => objects.extend(names.iter().map(|name| schema_table(&name.to_string(), database))),
+        _ => {}
+    }
+    if let Some(select_ctx) = analyse_statement(statement) {
+        for name in select_ctx.get_tables().keys() {
+            objects.push(schema_table(name, database));
+        }
+    }
+    objects
+}
+
+fn is_without_where(statement: &Statement) -> bool {
+    matches!(statement, Statement::Delete { selection: None, .. } | Statement::Update { selection: None, .. })
+}
+
+fn rule_matches(rule: &FirewallRule, statement: &Statement, statement_type: &str, user: &str, objects: &[(String, String)]) -> bool {
+    (rule.get_statement().is_empty() || rule.get_statement().eq_ignore_ascii_case(statement_type))
+        && (rule.get_user().is_empty() || rule.get_user() == user)
+        && (!rule.is_without_where() || is_without_where(statement))
+        && (rule.get_schema().is_empty() || objects.iter().any(|(schema, _)| schema.eq_ignore_ascii_case(&rule.get_schema())))
+        && (rule.get_table().is_empty() || objects.iter().any(|(_, table)| table.eq_ignore_ascii_case(&rule.get_table())))
+}
+
+/// `statement` with `LIMIT limit` when it is a SELECT without a LIMIT clause.
+fn limited(statement: &Statement, limit: u64) -> Option<Statement> {
+    match statement {
+        Statement::Query(query) if query.limit.is_none() => {
+            let mut query = query.clone();
+            query.limit = Some(Expr::Value(Value::Number(limit.to_string(), false)));
+            Some(Statement::Query(query))
+        }
+        _ => None,
+    }
+}
+
+/// Checks `statement` of `user` on `database` against the firewall rules. Returns the
+/// statement to run in its place, if the rules rewrite it, or why it is denied.
+pub fn check(statement: &Statement, user: &str, database: &str, config: &FirewallConfig) -> Result<Option<Statement>, String> {
+    if !config.is_enabled() || config.get_rules().is_empty() {
+        return Ok(None);
+    }
+    let statement_type = statement_type(statement);
+    let objects = statement_objects(statement, database);
+    for rule in config.get_rules().iter().filter(|rule| rule_matches(rule, statement, &statement_type, user, &objects)) {
+        if rule.is_limit() {
+            match limited(statement, rule.get_limit()) {
+                Some(rewritten) => return Ok(Some(rewritten)),
+                None => continue,
+            }
+        }
+        println!("audit: firewall rule {} denied {} of user '{}' on database '{}'; sql = {}", rule.get_name(), statement_type, user, database, statement);
+        return Err(format!("{} command denied to user '{}' by firewall rule '{}'", statement_type, user, rule.get_name()));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use data_panel_common::config::config::{FirewallConfig, FirewallRule};
+
+    use crate::handler::database::parser;
+
+    use super::check;
+
+    #[test]
+    fn test_firewall_check() {
+        let config = FirewallConfig::new(vec![
+            FirewallRule::deny("no-full-delete").statement("DELETE").without_where(true),
+            FirewallRule::deny("no-mysql-schema").schema("mysql"),
+            FirewallRule::limit("report-limit", 100).user("report"),
+        ]);
+        let statement = |sql: &str| parser::sql::mysql::parser(sql.to_string()).unwrap().pop().unwrap();
+
+        assert!(check(&statement("DELETE FROM t_order"), "web", "martlet", &config).is_err());
+        assert_eq!(check(&statement("DELETE FROM t_order WHERE id = 1"), "web", "martlet", &config), Ok(None));
+        assert!(check(&statement("SELECT u.id FROM t_order o JOIN mysql.user u ON o.id = u.id"), "web", "martlet", &config).is_err());
+        assert!(check(&statement("SELECT id FROM user"), "web", "mysql", &config).is_err());
+
+        let rewritten = check(&statement("SELECT id FROM t_order"), "report", "martlet", &config).unwrap().unwrap();
+        assert_eq!(rewritten.to_string(), "SELECT id FROM t_order LIMIT 100");
+        assert_eq!(check(&statement("SELECT id FROM t_order LIMIT 5"), "report", "martlet", &config), Ok(None));
+    }
+}
@@ -0,0 +1,163 @@
+//! Hints in `/*+ ... */` comments, read from the statement text before it is parsed.
+//!
+//! A hint is a bare `NAME`, `NAME=value` or `NAME(arguments)`, hints being separated by
+//! whitespace or commas, e.g. `SELECT /*+ MASTER, TIMEOUT(5000) */ * FROM t_order`. Names are
+//! read in lower case. Comments not starting with `+` carry no hints. The mesh reads:
+//!
+//! - `route=<hint>`, matched by the rules of `QueryRoutingConfig`;
+//! - `MASTER`, running a read on the primary of its segment rather than a mirror;
+//! - `TIMEOUT(<ms>)`, the statement timeout of this statement;
+//! - `SHARD(<key>=<value>, ...)`, shard key values routing the statement to one data segment
+//!   when its WHERE clause does not bind them.
+
+use sqlparser::tokenizer::{Token, Tokenizer, Whitespace};
+
+use crate::handler::database::parser::sql::mysql::MySQLDialect;
+
+pub const ROUTE_HINT: &str = "route";
+pub const MASTER_HINT: &str = "master";
+pub const TIMEOUT_HINT: &str = "timeout";
+pub const SHARD_HINT: &str = "shard";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SQLHints {
+    hints: Vec<(String, String)>,
+}
+
+impl SQLHints {
+    /// The hints of `sql`, none if it does not tokenize.
+    pub fn parse(sql: &str) -> Self {
+        SQLHints {
+            hints: parse_hints(sql),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hints.is_empty()
+    }
+
+    /// The hints in the order written, with their value or arguments, empty for a bare name.
+    pub fn get_hints(&self) -> &Vec<(String, String)> {
+        &self.hints
+    }
+
+    /// The value of the first hint `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.hints.iter().find(|(hint, _)| hint == name).map(|(_, value)| value.as_str())
+    }
+
+    pub fn get_route(&self) -> Option<&str> {
+        self.get(ROUTE_HINT)
+    }
+
+    pub fn is_master(&self) -> bool {
+        self.get(MASTER_HINT).is_some()
+    }
+
+    /// The milliseconds of `TIMEOUT(<ms>)`, None without one or when it is not a number.
+    pub fn get_timeout(&self) -> Option<u64> {
+        self.get(TIMEOUT_HINT).and_then(|timeout| timeout.parse().ok())
+    }
+
+    /// The `(shard key, value)` pairs of `SHARD(...)`, keys in lower case and values unquoted.
+    pub fn get_shard_bindings(&self) -> Vec<(String, String)> {
+        let arguments = match self.get(SHARD_HINT) {
+            Some(arguments) => arguments,
+            None => return vec![],
+        };
+        arguments.split(',')
+            .filter_map(|binding| {
+                let mut parts = binding.splitn(2, '=');
+                let key = parts.next()?.trim().to_lowercase();
+                let value = parts.next()?.trim().trim_matches(|c| c == '\'' || c == '"').to_string();
+                if key.is_empty() { None } else { Some((key, value)) }
+            })
+            .collect()
+    }
+}
+
+fn is_separator(c: char) -> bool {
+    c.is_whitespace() || c == ','
+}
+
+/// The hints of the text of a `/*+ ... */` comment, after the `+`.
+fn comment_hints(comment: &str) -> Vec<(String, String)> {
+    let chars: Vec<char> = comment.chars().collect();
+    let mut hints = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+            i += 1;
+        }
+        if i == start {
+            i += 1;
+            continue;
+        }
+        let name = chars[start..i].iter().collect::<String>().to_lowercase();
+        let value: String = match chars.get(i) {
+            Some('=') => {
+                let value_start = i + 1;
+                i = value_start;
+                while i < chars.len() && !is_separator(chars[i]) {
+                    i += 1;
+                }
+                chars[value_start..i].iter().collect()
+            }
+            Some('(') => {
+                let arguments_start = i + 1;
+                let mut depth = 1;
+                i = arguments_start;
+                while i < chars.len() {
+                    match chars[i] {
+                        '(' => depth += 1,
+                        ')' if depth == 1 => break,
+                        ')' => depth -= 1,
+                        _ => {}
+                    }
+                    i += 1;
+                }
+                let arguments = chars[arguments_start..i].iter().collect();
+                i += 1;
+                arguments
+            }
+            _ => String::new(),
+        };
+        hints.push((name, value.trim().to_string()));
+    }
+    hints
+}
+
+/// The hints of `sql` in the order written, none if it does not tokenize.
+pub fn parse_hints(sql: &str) -> Vec<(String, String)> {
+    let tokens = match Tokenizer::new(&MySQLDialect {}, sql).tokenize() {
+        Ok(tokens) => tokens,
+        Err(_) => return vec![],
+    };
+    tokens.iter()
+        .filter_map(|token| match token {
+            Token::Whitespace(Whitespace::MultiLineComment(comment)) => comment.strip_prefix('+'),
+            _ => None,
+        })
+        .flat_map(comment_hints)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_hints, SQLHints};
+
+    #[test]
+    fn test_parse_hints() {
+        let hints = parse_hints("SELECT /*+ route=canary, NO_CACHE */ id FROM t_order /* route=ignored */ WHERE note = '/*+ route=quoted */'");
+        assert_eq!(hints, vec![("route".to_string(), "canary".to_string()), ("no_cache".to_string(), "".to_string())]);
+        assert!(parse_hints("SELECT 1").is_empty());
+
+        let hints = SQLHints::parse("/*+ MASTER TIMEOUT(5000) SHARD(user_id=42, region='eu') */ SELECT * FROM t_order");
+        assert!(hints.is_master());
+        assert_eq!(hints.get_timeout(), Some(5000));
+        assert_eq!(hints.get_shard_bindings(), vec![("user_id".to_string(), "42".to_string()), ("region".to_string(), "eu".to_string())]);
+        assert_eq!(hints.get_route(), None);
+        assert_eq!(SQLHints::parse("SELECT /*+ TIMEOUT(soon) */ 1").get_timeout(), None);
+    }
+}
@@ -1,46 +1,336 @@
 use std::collections::HashMap;
 
+use sqlparser::ast::{Expr, Ident, Query, SetExpr, Statement, Value};
+use sqlparser::parser::Parser;
+
+use crate::handler::database::parser::sql::analyse::SQLAnalyse;
+use crate::handler::database::parser::sql::dialect::SQLDialect;
+use crate::handler::database::parser::sql::hint::SQLHints;
+use crate::handler::database::parser::sql::mysql::MySQLDialect;
+use crate::handler::database::parser::sql::rewrite::{FINGERPRINT_KEY, SQLReWrite};
+
 pub mod mysql;
 pub mod postgresql;
+pub mod sqlite;
+pub mod dialect;
+pub mod syntax;
 
 pub mod rewrite;
 pub mod analyse;
 pub mod route;
+pub mod alias;
+pub mod column_acl;
+pub mod firewall;
+pub mod rewriter;
+pub mod scope;
+pub mod tenant;
+pub mod row_filter;
+pub mod hint;
 
 pub enum SQLStatementContext {
     Select(SelectStatementContext),
+    Insert(InsertStatementContext),
     Update(UpdateStatementContext),
     Delete(DeleteStatementContext),
+    Ddl(DdlStatementContext),
+    Transaction(TransactionStatementContext),
     Default,
 }
 
 impl SQLStatementContext {
+    /// The context `statement` is analysed into.
+    pub fn new(statement: &Statement) -> Self {
+        match statement {
+            Statement::Update { .. } => SQLStatementContext::Update(UpdateStatementContext::new()),
+            Statement::Delete { .. } => SQLStatementContext::Delete(DeleteStatementContext::new()),
+            Statement::Insert { table_name, columns, source, .. } => {
+                SQLStatementContext::Insert(InsertStatementContext::new(table_name.to_string(), columns, source))
+            }
+            statement => {
+                if let Some(ddl_ctx) = DdlStatementContext::from_statement(statement) {
+                    SQLStatementContext::Ddl(ddl_ctx)
+                } else if let Some(transaction_ctx) = TransactionStatementContext::from_statement(statement) {
+                    SQLStatementContext::Transaction(transaction_ctx)
+                } else {
+                    SQLStatementContext::Select(SelectStatementContext::new())
+                }
+            }
+        }
+    }
+
+    pub fn get_common_ctx(&self) -> Option<&CommonStatementContext> {
+        match self {
+            SQLStatementContext::Select(s) => Some(&s.common_ctx),
+            SQLStatementContext::Insert(s) => Some(&s.common_ctx),
+            SQLStatementContext::Update(s) => Some(&s.common_ctx),
+            SQLStatementContext::Delete(s) => Some(&s.common_ctx),
+            _ => None,
+        }
+    }
+
+    pub fn common_ctx_mut(&mut self) -> Option<&mut CommonStatementContext> {
+        match self {
+            SQLStatementContext::Select(s) => Some(&mut s.common_ctx),
+            SQLStatementContext::Insert(s) => Some(&mut s.common_ctx),
+            SQLStatementContext::Update(s) => Some(&mut s.common_ctx),
+            SQLStatementContext::Delete(s) => Some(&mut s.common_ctx),
+            _ => None,
+        }
+    }
+
     pub fn add_table(&mut self, table: String, alias: String) {
+        if let Some(common_ctx) = self.common_ctx_mut() {
+            common_ctx.add_table(table, alias);
+        }
+    }
+
+    pub fn add_column(&mut self, column: String) {
+        if let Some(common_ctx) = self.common_ctx_mut() {
+            common_ctx.columns.push(column);
+        }
+    }
+
+    /// `qualifier` is the table or alias of `t.*`, empty for a bare `*`.
+    pub fn add_wildcard(&mut self, qualifier: String) {
+        if let Some(common_ctx) = self.common_ctx_mut() {
+            common_ctx.wildcards.push(qualifier);
+        }
+    }
+
+    pub fn set_hints(&mut self, hints: SQLHints) {
+        if let Some(common_ctx) = self.common_ctx_mut() {
+            common_ctx.hints = hints;
+        }
+    }
+
+    pub fn set_dialect(&mut self, dialect: SQLDialect) {
+        if let Some(common_ctx) = self.common_ctx_mut() {
+            common_ctx.dialect = dialect;
+        }
+    }
+
+    /// Marks the table the DDL statement creates as temporary.
+    pub fn set_temporary(&mut self) {
+        if let SQLStatementContext::Ddl(ddl_ctx) = self {
+            ddl_ctx.temporary = true;
+        }
+    }
+
+    pub fn set_session(&mut self, user: &str, database: &str, variables: &[(String, String)]) {
+        if let Some(common_ctx) = self.common_ctx_mut() {
+            common_ctx.user = user.to_string();
+            common_ctx.database = database.to_string();
+            common_ctx.variables = variables.to_vec();
+        }
+    }
+}
+
+/// How a condition compares its column with its values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConditionOperator {
+    Eq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    Between,
+    In,
+}
+
+impl ConditionOperator {
+    /// The operator with its operands swapped, `1 < id` being `id > 1`.
+    pub fn reversed(self) -> Self {
         match self {
-            SQLStatementContext::Select(s) => {
-                s.common_ctx.add_table(table, alias);
-            }
-            SQLStatementContext::Update(_) => {}
-            SQLStatementContext::Delete(_) => {}
-            SQLStatementContext::Default => {}
+            ConditionOperator::Lt => ConditionOperator::Gt,
+            ConditionOperator::LtEq => ConditionOperator::GtEq,
+            ConditionOperator::Gt => ConditionOperator::Lt,
+            ConditionOperator::GtEq => ConditionOperator::LtEq,
+            operator => operator,
         }
     }
 }
 
+/// A column of the WHERE clause compared with literals, holding for every row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    qualifier: String,
+    column: String,
+    operator: ConditionOperator,
+    values: Vec<String>,
+}
+
+impl Condition {
+    pub fn new(qualifier: String, column: String, operator: ConditionOperator, values: Vec<String>) -> Self {
+        Condition {
+            qualifier,
+            column,
+            operator,
+            values,
+        }
+    }
+
+    /// The table or alias the column is qualified with, empty when unqualified.
+    pub fn get_qualifier(&self) -> &str {
+        &self.qualifier
+    }
+
+    pub fn get_column(&self) -> &str {
+        &self.column
+    }
+
+    pub fn get_operator(&self) -> ConditionOperator {
+        self.operator
+    }
+
+    /// The literals, strings unquoted: one, two for BETWEEN, any number for IN.
+    pub fn get_values(&self) -> &Vec<String> {
+        &self.values
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderByItem {
+    expr: String,
+    asc: bool,
+}
+
+impl OrderByItem {
+    pub fn new(expr: String, asc: bool) -> Self {
+        OrderByItem {
+            expr,
+            asc,
+        }
+    }
+
+    pub fn get_expr(&self) -> &str {
+        &self.expr
+    }
+
+    pub fn is_asc(&self) -> bool {
+        self.asc
+    }
+}
+
 pub struct CommonStatementContext {
     tables: HashMap<String, String>,
+    columns: Vec<String>,
+    wildcards: Vec<String>,
+    conditions: Vec<Condition>,
+    order_by: Vec<OrderByItem>,
+    limit: Option<u64>,
+    offset: Option<u64>,
+    insert_columns: Vec<String>,
+    insert_rows: Vec<Vec<Option<String>>>,
+    hints: SQLHints,
+    dialect: SQLDialect,
+    user: String,
+    database: String,
+    variables: Vec<(String, String)>,
 }
 
 impl CommonStatementContext {
     pub fn new() -> Self {
         CommonStatementContext {
             tables: Default::default(),
+            columns: vec![],
+            wildcards: vec![],
+            conditions: vec![],
+            order_by: vec![],
+            limit: None,
+            offset: None,
+            insert_columns: vec![],
+            insert_rows: vec![],
+            hints: SQLHints::default(),
+            dialect: SQLDialect::default(),
+            user: String::new(),
+            database: String::new(),
+            variables: vec![],
         }
     }
 
     pub fn add_table(&mut self, table: String, alias: String) {
         self.tables.insert(table, alias);
     }
+
+    /// Referenced tables and their alias, empty when unaliased.
+    pub fn get_tables(&self) -> &HashMap<String, String> {
+        &self.tables
+    }
+
+    /// Every column referenced, without its qualifier.
+    pub fn get_columns(&self) -> &Vec<String> {
+        &self.columns
+    }
+
+    pub fn get_wildcards(&self) -> &Vec<String> {
+        &self.wildcards
+    }
+
+    /// The conditions of the outermost WHERE clause joined by AND.
+    pub fn get_conditions(&self) -> &Vec<Condition> {
+        &self.conditions
+    }
+
+    /// The conditions on `column`, e.g. a sharding key, whatever its qualifier.
+    pub fn get_column_conditions(&self, column: &str) -> Vec<&Condition> {
+        self.conditions.iter().filter(|condition| condition.column.eq_ignore_ascii_case(column)).collect()
+    }
+
+    pub fn get_order_by(&self) -> &Vec<OrderByItem> {
+        &self.order_by
+    }
+
+    /// The row count of the LIMIT clause, None without one or when it is not a number.
+    pub fn get_limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    pub fn get_offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    /// The columns an INSERT lists, empty when it lists none.
+    pub fn get_insert_columns(&self) -> &Vec<String> {
+        &self.insert_columns
+    }
+
+    /// The rows of an INSERT ... VALUES, None for a value that is not a literal.
+    pub fn get_insert_rows(&self) -> &Vec<Vec<Option<String>>> {
+        &self.insert_rows
+    }
+
+    /// The values inserted into `column`, one per row.
+    pub fn get_inserted_values(&self, column: &str) -> Vec<Option<String>> {
+        match self.insert_columns.iter().position(|insert_column| insert_column.eq_ignore_ascii_case(column)) {
+            Some(index) => self.insert_rows.iter().map(|row| row.get(index).cloned().flatten()).collect(),
+            None => vec![],
+        }
+    }
+
+    /// The `/*+ ... */` hints of the statement, see `hint`.
+    pub fn get_hints(&self) -> &SQLHints {
+        &self.hints
+    }
+
+    /// The dialect the statement was parsed in, see `dialect`.
+    pub fn get_dialect(&self) -> SQLDialect {
+        self.dialect
+    }
+
+    /// The user of the session running the statement, empty unless set.
+    pub fn get_user(&self) -> &str {
+        &self.user
+    }
+
+    /// The current database of the session running the statement, empty unless set.
+    pub fn get_database(&self) -> &str {
+        &self.database
+    }
+
+    /// The value, as SQL, of a session variable or a `@user` variable the session set.
+    pub fn get_variable(&self, name: &str) -> Option<&str> {
+        self.variables.iter().find(|(variable, _)| variable == name).map(|(_, value)| value.as_str())
+    }
 }
 
 pub struct SelectStatementContext {
@@ -54,9 +344,74 @@ impl SelectStatementContext {
         }
     }
 
+    pub fn get_common_ctx(&self) -> &CommonStatementContext {
+        &self.common_ctx
+    }
+
     pub fn add_table(&mut self, table: String, alias: String) {
         self.common_ctx.tables.insert(table, alias);
     }
+
+    /// Referenced tables and their alias, empty when unaliased.
+    pub fn get_tables(&self) -> &HashMap<String, String> {
+        &self.common_ctx.tables
+    }
+
+    /// Every column referenced, without its qualifier.
+    pub fn get_columns(&self) -> &Vec<String> {
+        &self.common_ctx.columns
+    }
+
+    pub fn get_wildcards(&self) -> &Vec<String> {
+        &self.common_ctx.wildcards
+    }
+}
+
+pub struct InsertStatementContext {
+    common_ctx: CommonStatementContext,
+    table: String,
+    generated_key_column: Option<String>,
+    row_count: Option<usize>,
+}
+
+impl InsertStatementContext {
+    pub fn new(table: String, columns: &[Ident], source: &Query) -> Self {
+        let rows = match &source.body {
+            SetExpr::Values(values) => Some(&values.0),
+            _ => None,
+        };
+        // MySQL generates the auto increment value of a column inserted as NULL.
+        let generated_key_column = rows.filter(|rows| !rows.is_empty()).and_then(|rows| {
+            columns.iter().enumerate()
+                .find(|(index, _)| rows.iter().all(|row| matches!(row.get(*index), Some(Expr::Value(Value::Null)))))
+                .map(|(_, column)| column.value.clone())
+        });
+        InsertStatementContext {
+            common_ctx: CommonStatementContext::new(),
+            table,
+            generated_key_column,
+            row_count: rows.map(|rows| rows.len()),
+        }
+    }
+
+    pub fn get_common_ctx(&self) -> &CommonStatementContext {
+        &self.common_ctx
+    }
+
+    /// The table inserted into, as written.
+    pub fn get_table(&self) -> &str {
+        &self.table
+    }
+
+    /// The listed column inserted as NULL in every row, whose value the database generates.
+    pub fn get_generated_key_column(&self) -> Option<&String> {
+        self.generated_key_column.as_ref()
+    }
+
+    /// The rows of an INSERT ... VALUES, None for an INSERT ... SELECT.
+    pub fn get_row_count(&self) -> Option<usize> {
+        self.row_count
+    }
 }
 
 pub struct UpdateStatementContext {
@@ -70,6 +425,10 @@ impl UpdateStatementContext {
         }
     }
 
+    pub fn get_common_ctx(&self) -> &CommonStatementContext {
+        &self.common_ctx
+    }
+
     pub fn add_table(&mut self, table: String, alias: String) {
         self.common_ctx.tables.insert(table, alias);
     }
@@ -86,9 +445,223 @@ impl DeleteStatementContext {
         }
     }
 
+    pub fn get_common_ctx(&self) -> &CommonStatementContext {
+        &self.common_ctx
+    }
+
     pub fn add_table(&mut self, table: String, alias: String) {
         self.common_ctx.tables.insert(table, alias);
     }
 }
 
-pub struct SQLRewriteContext {}
\ No newline at end of file
+pub struct DdlStatementContext {
+    operation: String,
+    object_type: String,
+    names: Vec<String>,
+    temporary: bool,
+}
+
+impl DdlStatementContext {
+    pub fn new(operation: &str, object_type: &str, names: Vec<String>) -> Self {
+        DdlStatementContext {
+            operation: operation.to_string(),
+            object_type: object_type.to_string(),
+            names,
+            temporary: false,
+        }
+    }
+
+    /// The context of `statement`, None unless it is DDL.
+    pub fn from_statement(statement: &Statement) -> Option<Self> {
+        let ddl_ctx = match statement {
+            Statement::CreateDatabase { db_name, .. } => DdlStatementContext::new("CREATE", "DATABASE", vec![db_name.to_string()]),
+            Statement::CreateSchema { schema_name, .. } => DdlStatementContext::new("CREATE", "SCHEMA", vec![schema_name.to_string()]),
+            Statement::CreateView { name, .. } => DdlStatementContext::new("CREATE", "VIEW", vec![name.to_string()]),
+            Statement::CreateTable { name, .. }
+            | Statement::CreateVirtualTable { name, .. } => DdlStatementContext::new("CREATE", "TABLE", vec![name.to_string()]),
+            Statement::CreateIndex { name, .. } => DdlStatementContext::new("CREATE", "INDEX", vec![name.to_string()]),
+            Statement::AlterTable { name, .. } => DdlStatementContext::new("ALTER", "TABLE", vec![name.to_string()]),
+            Statement::Drop { object_type, names, .. } => {
+                DdlStatementContext::new("DROP", &object_type.to_string(), names.iter().map(|name| name.to_string()).collect())
+            }
+            Statement::Truncate { table_name, .. } => DdlStatementContext::new("TRUNCATE", "TABLE", vec![table_name.to_string()]),
+            _ => return None,
+        };
+        Some(ddl_ctx)
+    }
+
+    /// `CREATE`, `ALTER`, `DROP` or `TRUNCATE`.
+    pub fn get_operation(&self) -> &str {
+        &self.operation
+    }
+
+    /// `TABLE`, `VIEW`, `INDEX`, `SCHEMA` or `DATABASE`.
+    pub fn get_object_type(&self) -> &str {
+        &self.object_type
+    }
+
+    /// The first object named, as written.
+    pub fn get_name(&self) -> &str {
+        self.names.first().map_or("", |name| name.as_str())
+    }
+
+    pub fn get_names(&self) -> &Vec<String> {
+        &self.names
+    }
+
+    /// Whether the table created is a temporary table of the session.
+    pub fn is_temporary(&self) -> bool {
+        self.temporary
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionOperation {
+    Begin,
+    Commit,
+    Rollback,
+    Savepoint,
+    RollbackToSavepoint,
+    ReleaseSavepoint,
+    SetTransaction,
+}
+
+pub struct TransactionStatementContext {
+    operation: TransactionOperation,
+    savepoint: Option<String>,
+}
+
+impl TransactionStatementContext {
+    pub fn new(operation: TransactionOperation, savepoint: Option<String>) -> Self {
+        TransactionStatementContext {
+            operation,
+            savepoint,
+        }
+    }
+
+    /// The context of `statement`, None unless it controls a transaction.
+    pub fn from_statement(statement: &Statement) -> Option<Self> {
+        let (operation, savepoint) = match statement {
+            Statement::StartTransaction { .. } => (TransactionOperation::Begin, None),
+            Statement::Commit { .. } => (TransactionOperation::Commit, None),
+            Statement::Rollback { savepoint: None, .. } => (TransactionOperation::Rollback, None),
+            Statement::Rollback { savepoint: Some(savepoint), .. } => (TransactionOperation::RollbackToSavepoint, Some(savepoint.to_string())),
+            Statement::Savepoint { variable } => (TransactionOperation::Savepoint, Some(variable.to_string())),
+            Statement::Release { variable } => (TransactionOperation::ReleaseSavepoint, Some(variable.to_string())),
+            Statement::SetTransaction { .. } => (TransactionOperation::SetTransaction, None),
+            _ => return None,
+        };
+        Some(TransactionStatementContext::new(operation, savepoint))
+    }
+
+    pub fn get_operation(&self) -> TransactionOperation {
+        self.operation
+    }
+
+    pub fn get_savepoint(&self) -> Option<&String> {
+        self.savepoint.as_ref()
+    }
+}
+
+pub struct SQLRewriteContext {}
+
+pub fn unquoted_table(name: &str) -> String {
+    let table = name.rsplit('.').next().unwrap_or(name);
+    table.trim_matches(|c| c == '`' || c == '"' || c == '[' || c == ']').to_string()
+}
+
+/// Unquoted names of the tables `statement` reads or writes, without their schema.
+pub fn statement_tables(statement: &Statement) -> Vec<String> {
+    let mut tables = vec![];
+    match statement {
+        Statement::Insert { table_name, .. }
+        | Statement::Update { table_name, .. }
+        | Statement::Delete { table_name, .. } => tables.push(unquoted_table(&table_name.to_string())),
+        _ => {}
+    }
+    if let Some(select_ctx) = analyse_statement(statement) {
+        tables.extend(select_ctx.common_ctx.tables.keys().map(|table| unquoted_table(table)));
+    }
+    tables.sort();
+    tables.dedup();
+    tables
+}
+
+/// `statement` rewritten with `ctx`, None when the rewrite fails.
+pub fn rewrite_statement(statement: &Statement, ctx: &HashMap<String, String>) -> Option<String> {
+    let mut rewrite_sql = String::new();
+    match statement.rewrite(&mut rewrite_sql, ctx) {
+        Ok(()) => Some(rewrite_sql),
+        Err(e) => {
+            println!("error on rewriting statement; error = {:?}", e);
+            None
+        }
+    }
+}
+
+/// `statement` with every literal written as `?`, and lists of literals as a single `?`.
+pub fn fingerprint_statement(statement: &Statement) -> Option<String> {
+    let mut ctx = HashMap::new();
+    ctx.insert(FINGERPRINT_KEY.to_string(), "".to_string());
+    rewrite_statement(statement, &ctx)
+}
+
+/// The fingerprint of `sql`: its literals replaced by `?`, keywords in upper case and
+/// whitespace collapsed, so statements differing only in their values share it. None if
+/// `sql` does not parse.
+pub fn fingerprint(sql: &str) -> Option<String> {
+    let statements = Parser::parse_sql(&MySQLDialect {}, sql).ok()?;
+    let fingerprints: Option<Vec<String>> = statements.iter().map(fingerprint_statement).collect();
+    Some(fingerprints?.join("; "))
+}
+
+/// The context of `statement`, None if it cannot be analysed.
+pub fn analyse_statement_context(statement: &Statement) -> Option<SQLStatementContext> {
+    let mut analyse_ctx = SQLStatementContext::new(statement);
+    if statement.analyse(&mut analyse_ctx).is_err() {
+        return None;
+    }
+    Some(analyse_ctx)
+}
+
+/// The context of `statement` with `hints`, read from the text it was parsed from.
+pub fn analyse_hinted_statement_context(statement: &Statement, hints: &SQLHints) -> Option<SQLStatementContext> {
+    let mut analyse_ctx = analyse_statement_context(statement)?;
+    analyse_ctx.set_hints(hints.clone());
+    Some(analyse_ctx)
+}
+
+/// The context of `statement`, parsed in `dialect`.
+pub fn analyse_dialect_statement_context(statement: &Statement, dialect: SQLDialect) -> Option<SQLStatementContext> {
+    let mut analyse_ctx = analyse_statement_context(statement)?;
+    analyse_ctx.set_dialect(dialect);
+    Some(analyse_ctx)
+}
+
+/// The tables, columns and wildcards `statement` references, None if it cannot be analysed.
+pub fn analyse_statement(statement: &Statement) -> Option<SelectStatementContext> {
+    let mut analyse_ctx = SQLStatementContext::Select(SelectStatementContext::new());
+    if statement.analyse(&mut analyse_ctx).is_err() {
+        return None;
+    }
+    match analyse_ctx {
+        SQLStatementContext::Select(select_ctx) => Some(select_ctx),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fingerprint;
+
+    #[test]
+    fn test_fingerprint() {
+        assert_eq!(fingerprint("select *  from t_order\nwhere user_id = 10 and status = 'PAID' limit 5").unwrap(),
+                   "SELECT * FROM t_order WHERE user_id = ? AND status = ? LIMIT ?");
+        assert_eq!(fingerprint("SELECT id FROM t_order WHERE id IN (1, 2, 3) AND note IS NULL").unwrap(),
+                   fingerprint("SELECT id FROM t_order WHERE id IN (4) AND note IS NULL").unwrap());
+        assert_eq!(fingerprint("INSERT INTO t_order (id, name) VALUES (1, 'a')").unwrap(),
+                   "INSERT INTO t_order (id, name) VALUES (?, ?)");
+        assert_eq!(fingerprint("SELEC 1"), None);
+    }
+}
@@ -1,6 +1,7 @@
 use sqlparser::ast::{Ident, Statement};
 use sqlparser::dialect::Dialect;
-use sqlparser::parser::Parser;
+use sqlparser::parser::{Parser, ParserError};
+use sqlparser::tokenizer::{Token, Tokenizer};
 
 #[derive(Debug)]
 pub struct MySQLDialect {}
@@ -28,19 +29,93 @@ impl Dialect for MySQLDialect {
     }
 }
 
-pub fn parser(sql: String) -> Vec<Statement> {
+pub fn parser(sql: String) -> Result<Vec<Statement>, ParserError> {
     let dialect = MySQLDialect {}; // or AnsiDialect, or your own dialect ...
 
-    let ast = if sql.to_uppercase().starts_with("XSET NAMES") {
-        vec![Statement::SetVariable {
+    if sql.to_uppercase().starts_with("XSET NAMES") {
+        Ok(vec![Statement::SetVariable {
             local: false,
             hivevar: false,
             variable: Ident { value: "".to_string(), quote_style: None },
             value: vec![],
-        }]
+        }])
     } else {
-        Parser::parse_sql(&dialect, &sql).unwrap()
+        Parser::parse_sql(&dialect, &sql)
+    }
+}
+
+/// Number of `?` placeholders in `sql`, which the dialect reads as identifiers.
+pub fn placeholder_count(sql: &str) -> usize {
+    let dialect = MySQLDialect {};
+    let tokens = match Tokenizer::new(&dialect, sql).tokenize() {
+        Ok(tokens) => tokens,
+        Err(_) => return 0,
     };
+    tokens.iter()
+        .filter(|token| matches!(token, Token::Word(word) if word.quote_style.is_none() && word.value == "?"))
+        .count()
+}
 
-    ast
-}
\ No newline at end of file
+fn is_identifier_part(ch: Option<char>) -> bool {
+    ch.map_or(false, |ch| MySQLDialect {}.is_identifier_part(ch))
+}
+
+/// `sql` with its `?` placeholders replaced by `literals` in order, None unless there is a
+/// literal for every placeholder. For SQL the mesh writes out of a statement, which has no
+/// comments: quotes are skipped, comments are not.
+pub fn bind_placeholders(sql: &str, literals: &[String]) -> Option<String> {
+    let mut literals = literals.iter();
+    let mut bound = String::with_capacity(sql.len());
+    let mut quote: Option<char> = None;
+    let mut previous: Option<char> = None;
+    let mut chars = sql.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match quote {
+            // A doubled quote closes the quoted text and opens it again.
+            Some(open) if ch == open => quote = None,
+            Some(open) if ch == '\\' && open != '`' => {
+                bound.push(ch);
+                previous = chars.next();
+                bound.extend(previous);
+                continue;
+            }
+            Some(_) => {}
+            None if ch == '\'' || ch == '"' || ch == '`' => quote = Some(ch),
+            None if ch == '?' && !is_identifier_part(previous) && !is_identifier_part(chars.peek().cloned()) => {
+                bound.push_str(literals.next()?);
+                previous = Some(ch);
+                continue;
+            }
+            None => {}
+        }
+        bound.push(ch);
+        previous = Some(ch);
+    }
+    match literals.next() {
+        Some(_) => None,
+        None => Some(bound),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handler::database::parser::sql::mysql::{bind_placeholders, placeholder_count};
+
+    #[test]
+    fn test_placeholder_count() {
+        assert_eq!(placeholder_count("SELECT name FROM t_dept"), 0);
+        assert_eq!(placeholder_count("INSERT INTO t_order (id, user_id, status) VALUES (?,?, ?)"), 3);
+        assert_eq!(placeholder_count("SELECT '?', `?` FROM t_order WHERE id = ? AND status IN (?, 'x?')"), 2);
+    }
+
+    #[test]
+    fn test_bind_placeholders() {
+        let literals = |literals: &[&str]| literals.iter().map(|literal| literal.to_string()).collect::<Vec<String>>();
+        assert_eq!(bind_placeholders("INSERT INTO t_order (id, user_id) VALUES (?,?)", &literals(&["1", "'it''s'"])),
+                   Some("INSERT INTO t_order (id, user_id) VALUES (1,'it''s')".to_string()));
+        assert_eq!(bind_placeholders("SELECT '?', `a?`, 'it''s ?', 'x\\'?', col? FROM t WHERE id = ?", &literals(&["7"])),
+                   Some("SELECT '?', `a?`, 'it''s ?', 'x\\'?', col? FROM t WHERE id = 7".to_string()));
+        assert_eq!(bind_placeholders("UPDATE t SET a = ? WHERE id = ?", &literals(&["1"])), None);
+        assert_eq!(bind_placeholders("UPDATE t SET a = 1", &literals(&["1"])), None);
+    }
+}
@@ -70,6 +70,7 @@ fn display_comma_separated<T>(slice: &[T]) -> DisplaySeparated<'_, T>
 impl SQLReWrite for Ident {
     fn rewrite(&self, f: &mut String, ctx: &HashMap<String, String>) -> SRWResult {
         match self.quote_style {
+            Some(q) if q == '"' && ctx.contains_key(DIALECT_KEY) => write!(f, "`{}`", self.value)?,
             Some(q) if q == '"' || q == '\'' || q == '`' => write!(f, "{}{}{}", q, self.value, q)?,
             Some(q) if q == '[' => write!(f, "[{}]", self.value)?,
             None => f.write_str(&self.value)?,
@@ -86,6 +87,61 @@ impl SQLReWrite for ObjectName {
     }
 }
 
+/// The rewrite context key replacing `column` by NULL, see `column_acl`.
+pub fn masked_column_key(column: &str) -> String {
+    format!("column:{}", column.to_lowercase())
+}
+
+/// The rewrite context key writing every literal as `?`, see `fingerprint_statement`.
+pub const FINGERPRINT_KEY: &str = "fingerprint:";
+
+/// The rewrite context key of the dialect a statement was parsed in, when not MySQL: its
+/// double quoted identifiers are written with backticks for the backends, see `dialect`.
+pub const DIALECT_KEY: &str = "dialect:";
+
+fn is_fingerprint(ctx: &HashMap<String, String>) -> bool {
+    ctx.contains_key(FINGERPRINT_KEY)
+}
+
+/// The column `expr` names if the rewrite context masks it.
+fn masked_column<'a>(expr: &'a Expr, ctx: &HashMap<String, String>) -> Option<&'a Ident> {
+    let column = match expr {
+        Expr::Identifier(column) => column,
+        Expr::CompoundIdentifier(idents) => idents.last()?,
+        _ => return None,
+    };
+    if ctx.contains_key(&masked_column_key(&column.value)) {
+        Some(column)
+    } else {
+        None
+    }
+}
+
+/// The name `ctx` gives `table`, matched regardless of case as MySQL table names often are.
+fn renamed_table<'a>(table: &str, ctx: &'a HashMap<String, String>) -> Option<&'a String> {
+    ctx.get(table).or_else(|| ctx.iter().find(|(key, _)| key.eq_ignore_ascii_case(table)).map(|(_, renamed)| renamed))
+}
+
+/// Writes a table name, replacing the table part with its current name when `ctx` aliases it.
+fn rewrite_table_name(name: &ObjectName, f: &mut String, ctx: &HashMap<String, String>) -> SRWResult {
+    match name.0.split_last() {
+        Some((table, schema)) => match renamed_table(&table.value, ctx) {
+            Some(renamed) => {
+                for ident in schema {
+                    ident.rewrite(f, ctx)?;
+                    f.write_str(".")?;
+                }
+                Ident {
+                    value: renamed.clone(),
+                    quote_style: table.quote_style,
+                }.rewrite(f, ctx)
+            }
+            None => name.rewrite(f, ctx),
+        },
+        None => name.rewrite(f, ctx),
+    }
+}
+
 impl SQLReWrite for String {
     fn rewrite(&self, f: &mut String, ctx: &HashMap<String, String>) -> SRWResult {
         f.write_str(&self)?;
@@ -100,6 +156,10 @@ impl SQLReWrite for String {
 /// inappropriate type, like `WHERE 1` or `SELECT 1=1`, as necessary.
 impl SQLReWrite for Expr {
     fn rewrite(&self, f: &mut String, ctx: &HashMap<String, String>) -> SRWResult {
+        if masked_column(self, ctx).is_some() {
+            write!(f, "NULL")?;
+            return Ok(());
+        }
         match self {
             Expr::Identifier(s) => {
                 s.rewrite(f, ctx)?;
@@ -137,7 +197,12 @@ impl SQLReWrite for Expr {
                     " {}IN (",
                     if *negated { "NOT " } else { "" }
                 )?;
-                display_comma_separated(list).rewrite(f, ctx)?;
+                // Lists of literals differing in length share their fingerprint.
+                if is_fingerprint(ctx) && list.iter().all(|item| matches!(item, Expr::Value(_))) {
+                    f.write_str("?")?;
+                } else {
+                    display_comma_separated(list).rewrite(f, ctx)?;
+                }
                 write!(
                     f,
                     ")"
@@ -491,7 +556,7 @@ impl SQLReWrite for Statement {
                     write!(f, "INSERT OR ")?;
                     action.rewrite(f, ctx)?; // TODO
                     write!(f, " INTO ")?;
-                    table_name.rewrite(f, ctx)?;
+                    rewrite_table_name(table_name, f, ctx)?;
                     write!(f, " ")?;
                 } else {
                     write!(
@@ -500,7 +565,7 @@ impl SQLReWrite for Statement {
                         act = if *overwrite { "OVERWRITE" } else { "INTO" },
                         tbl = if *table { " TABLE" } else { "" }
                     )?;
-                    table_name.rewrite(f, ctx)?;
+                    rewrite_table_name(table_name, f, ctx)?;
                     write!(f, " ")?;
                 }
                 if !columns.is_empty() {
@@ -557,7 +622,7 @@ impl SQLReWrite for Statement {
                 limit,
             } => {
                 write!(f, "UPDATE ")?;
-                table_name.rewrite(f, ctx)?;
+                rewrite_table_name(table_name, f, ctx)?;
                 if !assignments.is_empty() {
                     write!(f, " SET ")?;
                     display_comma_separated(assignments).rewrite(f, ctx)?;
@@ -576,7 +641,7 @@ impl SQLReWrite for Statement {
                 selection,
             } => {
                 write!(f, "DELETE FROM ")?;
-                table_name.rewrite(f, ctx)?;
+                rewrite_table_name(table_name, f, ctx)?;
                 if let Some(selection) = selection {
                     write!(f, " WHERE ")?;
                     selection.rewrite(f, ctx)?;
@@ -656,7 +721,7 @@ impl SQLReWrite for Statement {
                     external = if *external { "EXTERNAL " } else { "" },
                     if_not_exists = if *if_not_exists { "IF NOT EXISTS " } else { "" },
                 )?;
-                name.rewrite(f, ctx)?;
+                rewrite_table_name(name, f, ctx)?;
                 if !columns.is_empty() || !constraints.is_empty() {
                     write!(f, " (")?;
                     display_comma_separated(columns).rewrite(f, ctx)?;
@@ -677,7 +742,7 @@ impl SQLReWrite for Statement {
                 // Only for Hive
                 if let Some(l) = like {
                     write!(f, " LIKE ")?;
-                    l.rewrite(f, ctx)?;
+                    rewrite_table_name(l, f, ctx)?;
                 }
                 match hive_distribution {
                     HiveDistributionStyle::PARTITIONED { columns } => {
@@ -860,7 +925,7 @@ impl SQLReWrite for Statement {
             }
             Statement::AlterTable { name, operation } => {
                 write!(f, "ALTER TABLE ")?;
-                name.rewrite(f, ctx)?;
+                rewrite_table_name(name, f, ctx)?;
                 write!(f, " ")?;
                 operation.rewrite(f, ctx)?;
             }
@@ -880,7 +945,12 @@ impl SQLReWrite for Statement {
                     "{} ",
                     if *if_exists { " IF EXISTS" } else { "" }
                 )?;
-                display_comma_separated(names).rewrite(f, ctx)?;
+                for (index, name) in names.iter().enumerate() {
+                    if index > 0 {
+                        f.write_str(", ")?;
+                    }
+                    rewrite_table_name(name, f, ctx)?;
+                }
                 write!(
                     f,
                     "{}{}",
@@ -1368,7 +1438,7 @@ mod tests {
         //let sql = "insert into test (a, b, c) values (1, 1, ?)";
         let sql = "SET NAMES utf8mb4";
         let sql = "show variables like 'aurora_version'";
-        let mut ast = parser(sql.to_string());
+        let mut ast = parser(sql.to_string()).unwrap();
         let stmt = ast.pop().unwrap();
         let mut resql = String::new();
         let mut ctx: HashMap<String, String> = HashMap::new();
@@ -15,7 +15,7 @@ use std::fmt::Write;
 
 use sqlparser::ast::{Cte, Fetch, Join, JoinConstraint, JoinOperator, Offset, OffsetRows, OrderByExpr, Query, Select, SelectItem, SetExpr, SetOperator, TableAlias, TableFactor, TableWithJoins, Top, Values, With};
 
-use crate::handler::database::parser::sql::rewrite::{display_comma_separated, SQLReWrite};
+use crate::handler::database::parser::sql::rewrite::{display_comma_separated, masked_column, rewrite_table_name, SQLReWrite};
 
 pub type SRWResult = data_panel_common::common::Result<()>;
 
@@ -158,6 +158,11 @@ impl SQLReWrite for SelectItem {
         match &self {
             SelectItem::UnnamedExpr(expr) => {
                 expr.rewrite(f, ctx)?;
+                // A masked column keeps its name in the result set.
+                if let Some(column) = masked_column(expr, ctx) {
+                    write!(f, " AS ")?;
+                    column.rewrite(f, ctx)?;
+                }
             }
             SelectItem::ExprWithAlias { expr, alias } => {
                 expr.rewrite(f, ctx)?;
@@ -196,7 +201,7 @@ impl SQLReWrite for TableFactor {
                 args,
                 with_hints,
             } => {
-                name.rewrite(f, ctx)?;
+                rewrite_table_name(name, f, ctx)?;
                 if !args.is_empty() {
                     write!(f, "(")?;
                     display_comma_separated(args).rewrite(f, ctx)?;
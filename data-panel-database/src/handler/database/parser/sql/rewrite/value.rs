@@ -18,13 +18,17 @@ use sqlparser::ast::{DateTimeField, Value};
 #[cfg(feature = "bigdecimal")]
 use bigdecimal::BigDecimal;
 
-use crate::handler::database::parser::sql::rewrite::SQLReWrite;
+use crate::handler::database::parser::sql::rewrite::{FINGERPRINT_KEY, SQLReWrite};
 
 pub type SRWResult = data_panel_common::common::Result<()>;
 
 /// Primitive SQL values such as number and string
 impl SQLReWrite for Value {
     fn rewrite(&self, f: &mut String, ctx: &HashMap<String, String>) -> SRWResult {
+        if ctx.contains_key(FINGERPRINT_KEY) && !matches!(self, Value::Null) {
+            write!(f, "?")?;
+            return Ok(());
+        }
         match self {
             Value::Number(v, l) => {
                 write!(f, "{}{long}", v, long = if *l { "L" } else { "" })?;
@@ -0,0 +1,140 @@
+//! Custom statement rewrites, registered by the application embedding the mesh.
+//!
+//! A `QueryRewriter` changes statements in place, e.g. to add a tenant id condition or a
+//! soft-delete filter, without forking the crate. The registered rewriters run in the order
+//! they were registered on every statement the firewall lets through, before the column ACL,
+//! the routing and the rewrites of the mesh, each one seeing the statement as the previous one
+//! left it. A rewritten statement runs as the parser prints it.
+
+use std::sync::{Arc, RwLock};
+
+use sqlparser::ast::Statement;
+
+use data_panel_common::common::{Error, Result};
+
+use crate::handler::database::parser::sql::{analyse_statement_context, SQLStatementContext};
+use crate::handler::database::parser::sql::dialect::SQLDialect;
+use crate::handler::database::parser::sql::hint::SQLHints;
+
+/// What a rewriter did with a statement.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RewriteAction {
+    Unchanged,
+    /// The statement was changed in place.
+    Rewritten,
+    /// The client gets this message instead of running the statement.
+    Reject(String),
+}
+
+pub type RewriteResult = Result<RewriteAction>;
+
+pub trait QueryRewriter: Send + Sync {
+    /// Rewrites `statement`, `ctx` being its analysis with the user, database and variables of
+    /// the session.
+    fn rewrite(&self, statement: &mut Statement, ctx: &SQLStatementContext) -> RewriteResult;
+}
+
+lazy_static! {
+    static ref QUERY_REWRITERS: RwLock<Vec<Arc<dyn QueryRewriter>>> = RwLock::new(vec![]);
+}
+
+pub fn register_query_rewriter(rewriter: Arc<dyn QueryRewriter>) {
+    QUERY_REWRITERS.write().unwrap().push(rewriter);
+}
+
+/// The user, the current database and the variables of the session running a statement.
+pub type Session<'a> = (&'a str, &'a str, &'a [(String, String)]);
+
+fn statement_context(statement: &Statement, hints: &SQLHints, dialect: SQLDialect, (user, database, variables): Session) -> SQLStatementContext {
+    let mut ctx = analyse_statement_context(statement).unwrap_or_else(|| SQLStatementContext::new(statement));
+    ctx.set_hints(hints.clone());
+    ctx.set_dialect(dialect);
+    ctx.set_session(user, database, variables);
+    ctx
+}
+
+/// Runs the registered rewriters on `statement` of `session`: the statement once rewritten,
+/// None when none of them changed it, an `Auth` error when one rejected it, its error when one failed.
+pub fn rewrite(statement: &Statement, hints: &SQLHints, dialect: SQLDialect, session: Session) -> Result<Option<Statement>> {
+    let rewriters: Vec<Arc<dyn QueryRewriter>> = QUERY_REWRITERS.read().unwrap().clone();
+    if rewriters.is_empty() {
+        return Ok(None);
+    }
+    let mut rewritten: Option<Statement> = None;
+    let mut ctx = statement_context(statement, hints, dialect, session);
+    for rewriter in rewriters {
+        let mut current = rewritten.clone().unwrap_or_else(|| statement.clone());
+        match rewriter.rewrite(&mut current, &ctx) {
+            Ok(RewriteAction::Unchanged) => {}
+            Ok(RewriteAction::Rewritten) => {
+                ctx = statement_context(&current, hints, dialect, session);
+                rewritten = Some(current);
+            }
+            Ok(RewriteAction::Reject(message)) => return Err(Error::Auth(message)),
+            Err(e) => {
+                println!("error on rewriting {}; error = {}", statement, e);
+                return Err(e);
+            }
+        }
+    }
+    Ok(rewritten)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use sqlparser::ast::{BinaryOperator, Expr, Ident, SetExpr, Statement, Value};
+
+    use crate::handler::database::parser::sql::{mysql, SQLStatementContext};
+    use crate::handler::database::parser::sql::dialect::SQLDialect;
+    use crate::handler::database::parser::sql::hint::SQLHints;
+
+    use super::{QueryRewriter, register_query_rewriter, rewrite, RewriteAction, RewriteResult};
+
+    /// Adds `tenant_id = '<user>'` to the queries of t_tenant_order.
+    struct TenantRewriter;
+
+    impl QueryRewriter for TenantRewriter {
+        fn rewrite(&self, statement: &mut Statement, ctx: &SQLStatementContext) -> RewriteResult {
+            let common_ctx = match ctx.get_common_ctx() {
+                Some(common_ctx) if common_ctx.get_tables().contains_key("t_tenant_order") => common_ctx,
+                _ => return Ok(RewriteAction::Unchanged),
+            };
+            if common_ctx.get_user() == "guest" {
+                return Ok(RewriteAction::Reject("guests cannot read orders".to_string()));
+            }
+            let select = match statement {
+                Statement::Query(query) => match &mut query.body {
+                    SetExpr::Select(select) => select,
+                    _ => return Ok(RewriteAction::Unchanged),
+                },
+                _ => return Ok(RewriteAction::Unchanged),
+            };
+            let tenant = Expr::BinaryOp {
+                left: Box::new(Expr::Identifier(Ident::new("tenant_id"))),
+                op: BinaryOperator::Eq,
+                right: Box::new(Expr::Value(Value::SingleQuotedString(common_ctx.get_user().to_string()))),
+            };
+            select.selection = Some(match select.selection.take() {
+                Some(selection) => Expr::BinaryOp { left: Box::new(Expr::Nested(Box::new(selection))), op: BinaryOperator::And, right: Box::new(tenant) },
+                None => tenant,
+            });
+            Ok(RewriteAction::Rewritten)
+        }
+    }
+
+    #[test]
+    fn test_rewrite() {
+        register_query_rewriter(Arc::new(TenantRewriter));
+        let rewritten = |sql: &str, user: &str| {
+            let statement = mysql::parser(sql.to_string()).unwrap().pop().unwrap();
+            rewrite(&statement, &SQLHints::parse(sql), SQLDialect::MySQL, (user, "martlet", &[]))
+                .map(|rewritten| rewritten.map(|statement| statement.to_string()))
+                .map_err(|e| e.to_string())
+        };
+        assert_eq!(rewritten("SELECT * FROM t_tenant_order WHERE id = 1", "acme"), Ok(Some("SELECT * FROM t_tenant_order WHERE (id = 1) AND tenant_id = 'acme'".to_string())));
+        assert_eq!(rewritten("SELECT * FROM t_user", "acme"), Ok(None));
+        assert_eq!(rewritten("SELECT * FROM t_tenant_order", "guest"), Err("guests cannot read orders".to_string()));
+    }
+}
@@ -0,0 +1,153 @@
+//! Row level security, see `RowFilterConfig`.
+//!
+//! `RowFilterRewriter` filters the tables with the predicates of the rules of the user, their
+//! bare column names qualified with the alias or name the statement gives the table so they
+//! keep meaning that table in a join. See `scope` for where the predicates go and which
+//! statements are refused. CALLs and statements run as sent are never read, so a user any rule
+//! applies to may not run them, see `passthrough::check_unscoped`.
+
+use sqlparser::ast::{BinaryOperator, Expr, FunctionArg, Ident, ObjectName, SetExpr, Statement};
+
+use data_panel_common::config::config::{MeshConfig, RowFilterConfig};
+
+use crate::handler::database::parser::sql::{mysql, SQLStatementContext, unquoted_table};
+use crate::handler::database::parser::sql::rewriter::{QueryRewriter, RewriteAction, RewriteResult};
+use crate::handler::database::parser::sql::scope::{filter_statement, table_schema, TableFilter};
+
+/// Filters the statements with the rules of the current `RowFilterConfig`.
+pub struct RowFilterRewriter;
+
+impl QueryRewriter for RowFilterRewriter {
+    fn rewrite(&self, statement: &mut Statement, ctx: &SQLStatementContext) -> RewriteResult {
+        let config = MeshConfig::get_row_filter_config();
+        if config.is_empty() {
+            return Ok(RewriteAction::Unchanged);
+        }
+        let common_ctx = match ctx.get_common_ctx() {
+            Some(common_ctx) => common_ctx,
+            None => return Ok(RewriteAction::Unchanged),
+        };
+        let mut filter = RowFilter {
+            config,
+            user: common_ctx.get_user().to_string(),
+            database: common_ctx.get_database().to_string(),
+        };
+        match filter_statement(statement, &mut filter) {
+            Ok(0) => Ok(RewriteAction::Unchanged),
+            Ok(_) => Ok(RewriteAction::Rewritten),
+            Err(message) => Ok(RewriteAction::Reject(message)),
+        }
+    }
+}
+
+/// `predicate` parsed as the condition of a query.
+fn parse_predicate(predicate: &str) -> Option<Expr> {
+    let mut statements = mysql::parser(format!("SELECT 1 FROM t WHERE {}", predicate)).ok()?;
+    match statements.pop() {
+        Some(Statement::Query(query)) if statements.is_empty() => match query.body {
+            SetExpr::Select(select) => select.selection,
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Qualifies the bare column names of `expr` with `qualifier`, leaving its subqueries alone.
+fn qualify(expr: &mut Expr, qualifier: &[Ident]) {
+    match expr {
+        Expr::Identifier(column) => {
+            let mut name = qualifier.to_vec();
+            name.push(column.clone());
+            *expr = Expr::CompoundIdentifier(name);
+        }
+        Expr::BinaryOp { left, right, .. } => {
+            qualify(left, qualifier);
+            qualify(right, qualifier);
+        }
+        Expr::UnaryOp { expr, .. } | Expr::Nested(expr) | Expr::IsNull(expr) | Expr::IsNotNull(expr)
+        | Expr::Cast { expr, .. } | Expr::Collate { expr, .. } | Expr::InSubquery { expr, .. } => qualify(expr, qualifier),
+        Expr::InList { expr, list, .. } => {
+            qualify(expr, qualifier);
+            list.iter_mut().for_each(|item| qualify(item, qualifier));
+        }
+        Expr::Between { expr, low, high, .. } => {
+            qualify(expr, qualifier);
+            qualify(low, qualifier);
+            qualify(high, qualifier);
+        }
+        Expr::Case { operand, conditions, results, else_result } => {
+            if let Some(operand) = operand {
+                qualify(operand, qualifier);
+            }
+            conditions.iter_mut().chain(results.iter_mut()).for_each(|expr| qualify(expr, qualifier));
+            if let Some(else_result) = else_result {
+                qualify(else_result, qualifier);
+            }
+        }
+        Expr::Function(function) => function.args.iter_mut().for_each(|arg| match arg {
+            FunctionArg::Named { arg, .. } | FunctionArg::Unnamed(arg) => qualify(arg, qualifier),
+        }),
+        _ => {}
+    }
+}
+
+struct RowFilter {
+    config: RowFilterConfig,
+    user: String,
+    database: String,
+}
+
+impl TableFilter for RowFilter {
+    /// The predicates of the rules of the user on `table`, joined with AND.
+    fn filter(&mut self, table: &ObjectName, qualifier: &[Ident]) -> Result<Option<Expr>, String> {
+        let predicates = self.config.predicates(&self.user, &table_schema(table, &self.database), &unquoted_table(&table.to_string()));
+        let mut filter: Option<Expr> = None;
+        for predicate in predicates {
+            let mut expr = parse_predicate(&predicate)
+                .ok_or_else(|| format!("Row filter {} of table {} for user '{}' is not a valid condition", predicate, table, self.user))?;
+            qualify(&mut expr, qualifier);
+            let expr = Expr::Nested(Box::new(expr));
+            filter = Some(match filter {
+                Some(filter) => Expr::BinaryOp { left: Box::new(filter), op: BinaryOperator::And, right: Box::new(expr) },
+                None => expr,
+            });
+        }
+        Ok(filter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data_panel_common::config::config::{RowFilterConfig, RowFilterRule};
+
+    use crate::handler::database::parser::sql::mysql;
+    use crate::handler::database::parser::sql::scope::filter_statement;
+
+    use super::RowFilter;
+
+    fn filtered(config: &RowFilterConfig, user: &str, sql: &str) -> Result<String, String> {
+        let mut statement = mysql::parser(sql.to_string()).unwrap().pop().unwrap();
+        let mut filter = RowFilter { config: config.clone(), user: user.to_string(), database: "martlet".to_string() };
+        filter_statement(&mut statement, &mut filter).map(|_| statement.to_string())
+    }
+
+    #[test]
+    fn test_row_filter() {
+        let config = RowFilterConfig::new(vec![
+            RowFilterRule::new("analyst", "t_order", "region = 'EU' OR region IS NULL"),
+            RowFilterRule::new("*", "martlet.t_order", "deleted = 0"),
+            RowFilterRule::new("analyst", "t_user", "status IN (SELECT status FROM t_status WHERE visible = 1)"),
+        ]);
+        assert!(config.validate().is_ok());
+        assert_eq!(filtered(&config, "analyst", "SELECT o.id FROM t_order o WHERE o.amount > 10"),
+                   Ok("SELECT o.id FROM t_order AS o WHERE (o.amount > 10) AND (o.region = 'EU' OR o.region IS NULL) AND (o.deleted = 0)".to_string()));
+        assert_eq!(filtered(&config, "admin", "DELETE FROM t_order WHERE id = 1"),
+                   Ok("DELETE FROM t_order WHERE (id = 1) AND (t_order.deleted = 0)".to_string()));
+        assert_eq!(filtered(&config, "analyst", "SELECT * FROM t_user"),
+                   Ok("SELECT * FROM t_user WHERE (t_user.status IN (SELECT status FROM t_status WHERE visible = 1))".to_string()));
+        assert_eq!(filtered(&config, "admin", "SELECT * FROM other.t_order"), Ok("SELECT * FROM other.t_order".to_string()));
+        assert!(filtered(&config, "admin", "SELECT * FROM t_user u RIGHT JOIN t_order o ON o.user_id = u.id").is_ok());
+        assert!(filtered(&config, "admin", "SELECT * FROM t_order o RIGHT JOIN t_user u ON o.user_id = u.id").is_err());
+        assert!(filtered(&RowFilterConfig::new(vec![RowFilterRule::new("*", "t_order", "region =")]), "admin", "SELECT * FROM t_order").is_err());
+    }
+}
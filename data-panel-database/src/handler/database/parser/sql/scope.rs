@@ -0,0 +1,268 @@
+//! Filtering the rows a statement reads or writes table by table, for `tenant` and `row_filter`.
+//!
+//! The predicate of a filtered table goes to the WHERE clause of every query block reading it,
+//! or to the ON condition of the LEFT JOIN the table is the nullable side of, so unmatched outer
+//! rows are kept. Subqueries, derived tables, CTEs and set operations are filtered block by
+//! block, so are the conditions of UPDATE and DELETE and the source of INSERT ... SELECT.
+//!
+//! A statement is refused rather than run unfiltered when it reads a filtered table on the
+//! nullable side of a RIGHT or FULL JOIN, in a join without an ON condition to add to, or in an
+//! expression the mesh does not look into.
+
+use sqlparser::ast::{BinaryOperator, Expr, FunctionArg, Ident, JoinConstraint, JoinOperator, ObjectName, Query, Select, SelectItem, SetExpr, Statement, TableFactor, TableWithJoins};
+
+use crate::handler::database::parser::sql::unquoted_table;
+
+pub trait TableFilter {
+    /// The predicate over the rows of `table`, its columns qualified with `qualifier`, the alias
+    /// or name the statement gives the table, None when its rows are not filtered.
+    fn filter(&mut self, table: &ObjectName, qualifier: &[Ident]) -> Result<Option<Expr>, String>;
+}
+
+/// The unquoted schema of `table`, `database` when it is not qualified.
+pub fn table_schema(table: &ObjectName, database: &str) -> String {
+    match table.0.len() {
+        len if len > 1 => unquoted_table(&table.0[len - 2].value),
+        _ => database.to_string(),
+    }
+}
+
+/// Adds the predicates of `filter` to `statement`: how many tables it filtered, or why it is
+/// refused.
+pub fn filter_statement(statement: &mut Statement, filter: &mut dyn TableFilter) -> Result<usize, String> {
+    let mut scope = FilterScope { filter, filtered: 0 };
+    scope.statement(statement)?;
+    Ok(scope.filtered)
+}
+
+fn and(left: Expr, right: Expr) -> Expr {
+    Expr::BinaryOp {
+        left: Box::new(left),
+        op: BinaryOperator::And,
+        right: Box::new(right),
+    }
+}
+
+/// `condition` AND `predicates`, the condition nested so an OR in it keeps binding first.
+fn filtered_condition(condition: Option<Expr>, predicates: Vec<Expr>) -> Option<Expr> {
+    let mut predicates = predicates.into_iter();
+    let first = predicates.next()?;
+    let predicates = predicates.fold(first, and);
+    Some(match condition {
+        Some(condition) => and(Expr::Nested(Box::new(condition)), predicates),
+        None => predicates,
+    })
+}
+
+struct FilterScope<'a> {
+    filter: &'a mut dyn TableFilter,
+    /// The tables filtered so far.
+    filtered: usize,
+}
+
+impl FilterScope<'_> {
+    fn predicate(&mut self, table: &ObjectName, qualifier: &[Ident]) -> Result<Option<Expr>, String> {
+        let predicate = self.filter.filter(table, qualifier)?;
+        if predicate.is_some() {
+            self.filtered += 1;
+        }
+        Ok(predicate)
+    }
+
+    fn statement(&mut self, statement: &mut Statement) -> Result<(), String> {
+        match statement {
+            Statement::Query(query) => self.query(query),
+            Statement::Insert { source, .. } => self.query(source),
+            Statement::Update { table_name, assignments, selection, .. } => {
+                for assignment in assignments.iter_mut() {
+                    self.expr(&mut assignment.value)?;
+                }
+                if let Some(selection) = selection {
+                    self.expr(selection)?;
+                }
+                if let Some(predicate) = self.predicate(table_name, &table_name.0)? {
+                    *selection = filtered_condition(selection.take(), vec![predicate]);
+                }
+                Ok(())
+            }
+            Statement::Delete { table_name, selection } => {
+                if let Some(selection) = selection {
+                    self.expr(selection)?;
+                }
+                if let Some(predicate) = self.predicate(table_name, &table_name.0)? {
+                    *selection = filtered_condition(selection.take(), vec![predicate]);
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn query(&mut self, query: &mut Query) -> Result<(), String> {
+        if let Some(with) = &mut query.with {
+            for cte in with.cte_tables.iter_mut() {
+                self.query(&mut cte.query)?;
+            }
+        }
+        self.set_expr(&mut query.body)?;
+        for order_by in query.order_by.iter_mut() {
+            self.expr(&mut order_by.expr)?;
+        }
+        Ok(())
+    }
+
+    fn set_expr(&mut self, set_expr: &mut SetExpr) -> Result<(), String> {
+        match set_expr {
+            SetExpr::Select(select) => self.select(select),
+            SetExpr::Query(query) => self.query(query),
+            SetExpr::SetOperation { left, right, .. } => {
+                self.set_expr(left)?;
+                self.set_expr(right)
+            }
+            SetExpr::Values(values) => values.0.iter_mut().flatten().try_for_each(|expr| self.expr(expr)),
+            SetExpr::Insert(statement) => self.statement(statement),
+        }
+    }
+
+    fn select(&mut self, select: &mut Select) -> Result<(), String> {
+        for item in select.projection.iter_mut() {
+            if let SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } = item {
+                self.expr(expr)?;
+            }
+        }
+        let mut predicates = vec![];
+        for table in select.from.iter_mut() {
+            self.table_with_joins(table, &mut predicates)?;
+        }
+        if let Some(selection) = &mut select.selection {
+            self.expr(selection)?;
+        }
+        for expr in select.group_by.iter_mut() {
+            self.expr(expr)?;
+        }
+        if let Some(having) = &mut select.having {
+            self.expr(having)?;
+        }
+        if !predicates.is_empty() {
+            select.selection = filtered_condition(select.selection.take(), predicates);
+        }
+        Ok(())
+    }
+
+    /// Filters the tables of `table`, the predicates of the tables whose rows the query block
+    /// filters going to `predicates`.
+    fn table_with_joins(&mut self, table: &mut TableWithJoins, predicates: &mut Vec<Expr>) -> Result<(), String> {
+        // A table is on the nullable side of the RIGHT and FULL JOINs after it.
+        let outer: Vec<bool> = table.joins.iter().map(|join| matches!(join.join_operator, JoinOperator::RightOuter(_) | JoinOperator::FullOuter(_))).collect();
+        self.table_factor(&mut table.relation, predicates, !outer.contains(&true))?;
+        for (i, join) in table.joins.iter_mut().enumerate() {
+            let preserved = !outer[i + 1..].contains(&true);
+            match &mut join.join_operator {
+                JoinOperator::LeftOuter(constraint) => {
+                    let mut on_predicates = vec![];
+                    self.table_factor(&mut join.relation, &mut on_predicates, true)?;
+                    match constraint {
+                        JoinConstraint::On(condition) => {
+                            self.expr(condition)?;
+                            if let Some(filtered) = filtered_condition(Some(condition.clone()), on_predicates) {
+                                *condition = filtered;
+                            }
+                        }
+                        _ if on_predicates.is_empty() => {}
+                        _ => return Err(format!("LEFT JOIN of filtered table {} needs an ON condition", join.relation)),
+                    }
+                }
+                JoinOperator::OuterApply => self.table_factor(&mut join.relation, &mut vec![], false)?,
+                JoinOperator::FullOuter(constraint) => {
+                    if let JoinConstraint::On(condition) = constraint {
+                        self.expr(condition)?;
+                    }
+                    self.table_factor(&mut join.relation, predicates, false)?;
+                }
+                JoinOperator::Inner(constraint) | JoinOperator::RightOuter(constraint) => {
+                    if let JoinConstraint::On(condition) = constraint {
+                        self.expr(condition)?;
+                    }
+                    self.table_factor(&mut join.relation, predicates, preserved)?;
+                }
+                JoinOperator::CrossJoin | JoinOperator::CrossApply => self.table_factor(&mut join.relation, predicates, preserved)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Filters `factor`, refused for a filtered table on the nullable side of an outer join
+    /// unless `preserved`.
+    fn table_factor(&mut self, factor: &mut TableFactor, predicates: &mut Vec<Expr>, preserved: bool) -> Result<(), String> {
+        match factor {
+            TableFactor::Table { name, alias, .. } => {
+                let qualifier = match alias {
+                    Some(alias) => vec![alias.name.clone()],
+                    None => name.0.clone(),
+                };
+                let predicate = match self.predicate(name, &qualifier)? {
+                    Some(predicate) => predicate,
+                    None => return Ok(()),
+                };
+                if !preserved {
+                    return Err(format!("Filtered table {} cannot be read on the nullable side of an outer join", name));
+                }
+                predicates.push(predicate);
+                Ok(())
+            }
+            TableFactor::Derived { subquery, .. } => self.query(subquery),
+            TableFactor::TableFunction { expr, .. } => self.expr(expr),
+            TableFactor::NestedJoin(table) => {
+                let mut nested = vec![];
+                self.table_with_joins(table, &mut nested)?;
+                if !nested.is_empty() && !preserved {
+                    return Err(format!("Filtered tables of {} cannot be read on the nullable side of an outer join", table));
+                }
+                predicates.extend(nested);
+                Ok(())
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &mut Expr) -> Result<(), String> {
+        match expr {
+            Expr::Identifier(_) | Expr::CompoundIdentifier(_) | Expr::Value(_) | Expr::Wildcard | Expr::QualifiedWildcard(_) => Ok(()),
+            Expr::BinaryOp { left, right, .. } => {
+                self.expr(left)?;
+                self.expr(right)
+            }
+            Expr::UnaryOp { expr, .. } | Expr::Nested(expr) | Expr::IsNull(expr) | Expr::IsNotNull(expr)
+            | Expr::Cast { expr, .. } | Expr::Collate { expr, .. } => self.expr(expr),
+            Expr::InList { expr, list, .. } => {
+                self.expr(expr)?;
+                list.iter_mut().try_for_each(|item| self.expr(item))
+            }
+            Expr::InSubquery { expr, subquery, .. } => {
+                self.expr(expr)?;
+                self.query(subquery)
+            }
+            Expr::Between { expr, low, high, .. } => {
+                self.expr(expr)?;
+                self.expr(low)?;
+                self.expr(high)
+            }
+            Expr::Case { operand, conditions, results, else_result } => {
+                if let Some(operand) = operand {
+                    self.expr(operand)?;
+                }
+                conditions.iter_mut().chain(results.iter_mut()).try_for_each(|expr| self.expr(expr))?;
+                match else_result {
+                    Some(else_result) => self.expr(else_result),
+                    None => Ok(()),
+                }
+            }
+            Expr::Exists(query) | Expr::Subquery(query) => self.query(query),
+            Expr::Function(function) => function.args.iter_mut().try_for_each(|arg| match arg {
+                FunctionArg::Named { arg, .. } | FunctionArg::Unnamed(arg) => self.expr(arg),
+            }),
+            // A subquery the mesh does not look into could read a filtered table unfiltered.
+            expr if expr.to_string().to_uppercase().contains("SELECT ") => Err(format!("Subquery of {} cannot be filtered", expr)),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,7 @@
+use sqlparser::ast::Statement;
+use sqlparser::dialect::SQLiteDialect;
+use sqlparser::parser::{Parser, ParserError};
+
+pub fn parser(sql: String) -> Result<Vec<Statement>, ParserError> {
+    Parser::parse_sql(&SQLiteDialect {}, &sql)
+}
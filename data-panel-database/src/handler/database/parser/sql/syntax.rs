@@ -0,0 +1,129 @@
+//! Where a statement stops parsing, for the syntax error its client gets.
+//!
+//! The tokenizer tells the line and column of the text it cannot read. The parser only tells
+//! the token it did not expect, taken to be the first token of the statement written the same.
+
+use sqlparser::parser::ParserError;
+use sqlparser::tokenizer::{Token, Whitespace};
+
+use crate::handler::database::parser::sql::dialect::SQLDialect;
+
+const FOUND: &str = "found: ";
+const TOKENIZER_POSITION: &str = " at Line: ";
+/// The characters of the statement MySQL quotes from the position of a syntax error.
+const NEAR_CHARS: usize = 80;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError {
+    message: String,
+    line: u64,
+    column: u64,
+    near: String,
+}
+
+impl SyntaxError {
+    /// The syntax error of `sql`, parsed in `dialect`, failing with `error`.
+    pub fn new(sql: &str, dialect: SQLDialect, error: &ParserError) -> Self {
+        let message = match error {
+            ParserError::TokenizerError(message) | ParserError::ParserError(message) => message.clone(),
+        };
+        let (line, column) = tokenizer_position(&message)
+            .or_else(|| unexpected_token_position(sql, dialect, &message))
+            .unwrap_or((1, 1));
+        let (line, column) = (line.max(1), column.max(1));
+        let offset = sql.split('\n').take(line as usize - 1).map(|text| text.chars().count() + 1).sum::<usize>() + column as usize - 1;
+        let near = sql.chars().skip(offset).take(NEAR_CHARS).collect();
+        SyntaxError {
+            message,
+            line,
+            column,
+            near,
+        }
+    }
+
+    pub fn get_message(&self) -> String {
+        self.message.clone()
+    }
+
+    pub fn get_line(&self) -> u64 {
+        self.line
+    }
+
+    pub fn get_column(&self) -> u64 {
+        self.column
+    }
+
+    /// The statement from the position of the error on, as MySQL quotes it.
+    pub fn get_near(&self) -> String {
+        self.near.clone()
+    }
+
+    /// The message of the ERR packet, worded as MySQL words its own.
+    pub fn to_mysql_message(&self) -> String {
+        format!("You have an error in your SQL syntax; {} near '{}' at line {} column {}", self.message, self.near, self.line, self.column)
+    }
+}
+
+/// The position in a tokenizer error, `... at Line: 2, Column 5`.
+fn tokenizer_position(message: &str) -> Option<(u64, u64)> {
+    let position = &message[message.rfind(TOKENIZER_POSITION)? + TOKENIZER_POSITION.len()..];
+    let mut parts = position.splitn(2, ", Column ");
+    let line = parts.next()?.trim().parse().ok()?;
+    let column = parts.next()?.trim().parse().ok()?;
+    Some((line, column))
+}
+
+/// The position of the token a parser error, `Expected ..., found: <token>`, did not expect.
+fn unexpected_token_position(sql: &str, dialect: SQLDialect, message: &str) -> Option<(u64, u64)> {
+    let found = &message[message.rfind(FOUND)? + FOUND.len()..];
+    let tokens = dialect.tokenize(sql).ok()?;
+    let (mut line, mut column) = (1, 1);
+    for token in tokens.iter() {
+        let text = token.to_string();
+        if !matches!(token, Token::Whitespace(_)) && text.eq_ignore_ascii_case(found) {
+            return Some((line, column));
+        }
+        match token {
+            Token::Whitespace(Whitespace::Newline) => {
+                line += 1;
+                column = 1;
+            }
+            _ => {
+                let lines: Vec<&str> = text.split('\n').collect();
+                if lines.len() > 1 {
+                    line += lines.len() as u64 - 1;
+                    column = 1;
+                }
+                column += lines.last().map_or(0, |text| text.chars().count() as u64);
+            }
+        }
+    }
+    // Past the end of the statement, `found: EOF`.
+    Some((line, column))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handler::database::parser::sql::dialect::SQLDialect;
+
+    use super::SyntaxError;
+
+    fn syntax_error(sql: &str) -> SyntaxError {
+        let dialect = SQLDialect::MySQL;
+        SyntaxError::new(sql, dialect, &dialect.parser(sql.to_string()).unwrap_err())
+    }
+
+    #[test]
+    fn test_syntax_error() {
+        let error = syntax_error("SELECT id FROM t_order WHERE id = 1 LIMT 5");
+        assert_eq!((error.get_line(), error.get_column()), (1, 37));
+        assert_eq!(error.get_near(), "LIMT 5");
+
+        let error = syntax_error("SELECT id\nFROM t_order\nWHERE id = )");
+        assert_eq!((error.get_line(), error.get_column()), (3, 12));
+        assert!(error.to_mysql_message().ends_with("near ')' at line 3 column 12"));
+
+        let error = syntax_error("SELECT 'unterminated");
+        assert_eq!(error.get_line(), 1);
+    }
+}
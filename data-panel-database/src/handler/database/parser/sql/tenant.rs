@@ -0,0 +1,139 @@
+//! Multi-tenancy, see `TenantConfig`.
+//!
+//! `TenantRewriter` filters the tenant tables with `<table>.<column> = <tenant>`, see `scope`
+//! for where the predicates go and which statements are refused. An UPDATE setting the tenant
+//! column, and a statement on a tenant table of a session without tenant, are refused as well.
+//! CALLs and statements run as sent are never read, so they are refused outright, see
+//! `passthrough::check_unscoped`.
+
+use sqlparser::ast::{BinaryOperator, Expr, Ident, ObjectName, Statement, Value};
+
+use data_panel_common::config::config::{MeshConfig, TenantConfig};
+
+use crate::handler::database::parser::sql::{CommonStatementContext, SQLStatementContext, unquoted_table};
+use crate::handler::database::parser::sql::rewriter::{QueryRewriter, RewriteAction, RewriteResult};
+use crate::handler::database::parser::sql::scope::{filter_statement, table_schema, TableFilter};
+
+/// Scopes the statements on the tenant tables of the current `TenantConfig`.
+pub struct TenantRewriter;
+
+impl QueryRewriter for TenantRewriter {
+    fn rewrite(&self, statement: &mut Statement, ctx: &SQLStatementContext) -> RewriteResult {
+        let config = MeshConfig::get_tenant_config();
+        if !config.is_enabled() {
+            return Ok(RewriteAction::Unchanged);
+        }
+        let common_ctx = match ctx.get_common_ctx() {
+            Some(common_ctx) => common_ctx,
+            None => return Ok(RewriteAction::Unchanged),
+        };
+        let mut filter = TenantFilter {
+            tenant: tenant(&config, common_ctx),
+            database: common_ctx.get_database().to_string(),
+            config,
+        };
+        match filter.scope(statement) {
+            Ok(0) => Ok(RewriteAction::Unchanged),
+            Ok(_) => Ok(RewriteAction::Rewritten),
+            Err(message) => Ok(RewriteAction::Reject(message)),
+        }
+    }
+}
+
+/// The tenant of the session as a literal, None when it has none.
+fn tenant(config: &TenantConfig, common_ctx: &CommonStatementContext) -> Option<Value> {
+    if config.get_source() == "user" {
+        return Some(Value::SingleQuotedString(common_ctx.get_user().to_string()));
+    }
+    let value = common_ctx.get_variable(&config.get_variable())?;
+    let quoted = value.len() >= 2 && (value.starts_with('\'') && value.ends_with('\'') || value.starts_with('"') && value.ends_with('"'));
+    if quoted {
+        Some(Value::SingleQuotedString(value[1..value.len() - 1].replace("''", "'")))
+    } else if !value.is_empty() && value.chars().all(|c| c.is_ascii_digit()) {
+        Some(Value::Number(value.to_string()))
+    } else {
+        // Anything else, like another variable or a function, is not a tenant the mesh can tell.
+        None
+    }
+}
+
+struct TenantFilter {
+    config: TenantConfig,
+    tenant: Option<Value>,
+    database: String,
+}
+
+impl TenantFilter {
+    fn is_tenant_table(&self, table: &ObjectName) -> bool {
+        self.config.is_tenant_table(&table_schema(table, &self.database), &unquoted_table(&table.to_string()))
+    }
+
+    /// Scopes `statement`: how many tenant tables it filtered, or why it is refused.
+    fn scope(&mut self, statement: &mut Statement) -> Result<usize, String> {
+        if let Statement::Update { table_name, assignments, .. } = statement {
+            let column = self.config.get_column();
+            if self.is_tenant_table(table_name) && assignments.iter().any(|assignment| assignment.id.value.eq_ignore_ascii_case(&column)) {
+                return Err(format!("UPDATE of tenant table {} cannot set {}", table_name, column));
+            }
+        }
+        filter_statement(statement, self)
+    }
+}
+
+impl TableFilter for TenantFilter {
+    /// `<qualifier>.<column> = <tenant>`.
+    fn filter(&mut self, table: &ObjectName, qualifier: &[Ident]) -> Result<Option<Expr>, String> {
+        if !self.is_tenant_table(table) {
+            return Ok(None);
+        }
+        let tenant = self.tenant.clone().ok_or_else(|| format!("Statement on tenant table {} needs a tenant, none is set for the session", table))?;
+        let mut column = qualifier.to_vec();
+        column.push(Ident::new(self.config.get_column()));
+        Ok(Some(Expr::BinaryOp {
+            left: Box::new(Expr::CompoundIdentifier(column)),
+            op: BinaryOperator::Eq,
+            right: Box::new(Expr::Value(tenant)),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data_panel_common::config::config::TenantConfig;
+
+    use crate::handler::database::parser::sql::{mysql, SQLStatementContext};
+
+    use super::TenantFilter;
+
+    fn scoped(config: &TenantConfig, sql: &str, variables: &[(String, String)]) -> Result<String, String> {
+        let mut statement = mysql::parser(sql.to_string()).unwrap().pop().unwrap();
+        let mut ctx = SQLStatementContext::new(&statement);
+        ctx.set_session("acme", "martlet", variables);
+        let mut filter = TenantFilter {
+            tenant: super::tenant(config, ctx.get_common_ctx().unwrap()),
+            database: "martlet".to_string(),
+            config: config.clone(),
+        };
+        filter.scope(&mut statement).map(|_| statement.to_string())
+    }
+
+    #[test]
+    fn test_tenant_scope() {
+        let config = TenantConfig::new(vec!["t_order".to_string(), "martlet.t_item".to_string()]);
+        assert_eq!(scoped(&config, "SELECT * FROM t_order WHERE id = 1 OR id = 2", &[]),
+                   Ok("SELECT * FROM t_order WHERE (id = 1 OR id = 2) AND t_order.tenant_id = 'acme'".to_string()));
+        assert_eq!(scoped(&config, "SELECT * FROM t_user u LEFT JOIN t_item i ON u.id = i.user_id", &[]),
+                   Ok("SELECT * FROM t_user AS u LEFT JOIN t_item AS i ON (u.id = i.user_id) AND i.tenant_id = 'acme'".to_string()));
+        assert_eq!(scoped(&config, "SELECT * FROM t_user WHERE id IN (SELECT user_id FROM t_order)", &[]),
+                   Ok("SELECT * FROM t_user WHERE id IN (SELECT user_id FROM t_order WHERE t_order.tenant_id = 'acme')".to_string()));
+        assert_eq!(scoped(&config, "DELETE FROM t_order", &[]), Ok("DELETE FROM t_order WHERE t_order.tenant_id = 'acme'".to_string()));
+        assert_eq!(scoped(&config, "SELECT * FROM other.t_item", &[]), Ok("SELECT * FROM other.t_item".to_string()));
+        assert!(scoped(&config, "UPDATE t_order SET tenant_id = 'other' WHERE id = 1", &[]).is_err());
+        assert!(scoped(&config, "SELECT * FROM t_order o RIGHT JOIN t_user u ON o.user_id = u.id", &[]).is_err());
+
+        let config = config.variable("tenant");
+        assert!(scoped(&config, "SELECT * FROM t_order", &[]).is_err());
+        assert_eq!(scoped(&config, "UPDATE t_order SET status = 'PAID'", &[("@tenant".to_string(), "42".to_string())]),
+                   Ok("UPDATE t_order SET status = 'PAID' WHERE t_order.tenant_id = 42".to_string()));
+    }
+}
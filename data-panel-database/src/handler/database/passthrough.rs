@@ -0,0 +1,127 @@
+//! Statements the parser does not read, run as sent, see `PassthroughConfig`.
+//!
+//! Stored procedures, system commands and other valid MySQL the parser does not support fail
+//! with a syntax error unless passthrough is enabled. They then run verbatim on the primary of
+//! the session's segment, or on the connection of its open transaction, without the firewall,
+//! approval, column ACL and routing rules, which need a parsed statement. Every passthrough is
+//! counted, see `ServiceCounters`. Nothing could keep such a statement to the session's
+//! tenant or to the rows the row filters leave the user, so while tenant tables are configured,
+//! or a row filter applies to the user, they are refused instead.
+
+use bytes::Bytes;
+
+use data_panel_common::common::Error;
+use data_panel_common::config::config::{MeshConfig, PassthroughConfig, RowFilterConfig, TenantConfig};
+
+use crate::discovery;
+use crate::handler::database::{procedure, traffic, transaction, variables};
+use crate::handler::database::mysql::PayloadSink;
+use crate::handler::database::mysql::rdbc::{err_payload, passthrough_query};
+use crate::service::shutdown::service_counters;
+use crate::session::mysql::SessionContext;
+
+/// The backend statements of the session run on outside of a transaction: the primary of its
+/// segment, the session's backend without rules.
+pub fn primary_url(session_ctx: &SessionContext) -> String {
+    let segment = traffic::primary_of(&MeshConfig::get_backend_config().get_segment());
+    discovery::database::segment_url(&segment).unwrap_or_else(|| session_ctx.get_backend_url())
+}
+
+/// Refuses `sql`, which would run as sent, when the tenant rules of `tenant_config` or the row
+/// filters of `row_filter_config` scope the statements of `user`: the mesh can't add their
+/// predicates to what it does not read.
+pub fn check_unscoped(sql: &str, user: &str, tenant_config: &TenantConfig, row_filter_config: &RowFilterConfig) -> Result<(), Error> {
+    let statement = if procedure::is_call(sql) { "CALL" } else { "Statement the mesh does not parse" };
+    if tenant_config.is_enabled() && !tenant_config.get_tables().is_empty() {
+        return Err(Error::Auth(format!("{} can't be scoped to the tenant of the session and is refused while tenant tables are configured", statement)));
+    }
+    if row_filter_config.applies_to(user) {
+        return Err(Error::Auth(format!("{} can't be filtered by the row filters of user '{}' and is refused", statement, user)));
+    }
+    Ok(())
+}
+
+/// `check_unscoped` with the current config and the user of the session.
+pub fn check_session_unscoped(sql: &str, session_ctx: &SessionContext) -> Result<(), Error> {
+    check_unscoped(sql, &session_ctx.get_user_name(), &MeshConfig::get_tenant_config(), &MeshConfig::get_row_filter_config())
+}
+
+/// Whether `sql`, which does not parse, runs as sent for `user` rather than failing with its
+/// syntax error: only with passthrough enabled, and never when the mesh would have to scope it.
+pub fn passes_through(sql: &str, user: &str, config: &PassthroughConfig, tenant_config: &TenantConfig, row_filter_config: &RowFilterConfig) -> Result<bool, Error> {
+    if !config.is_enabled() {
+        return Ok(false);
+    }
+    check_unscoped(sql, user, tenant_config, row_filter_config)?;
+    Ok(true)
+}
+
+/// Runs `sql`, which does not parse, as sent.
+pub fn execute(sql: &str, session_ctx: &mut SessionContext, sink: &mut dyn PayloadSink) -> Option<Vec<Bytes>> {
+    service_counters().passed_through();
+    println!("Passing through {}", sql);
+    run_as_sent(sql, session_ctx, sink)
+}
+
+/// Runs `sql` as sent on the connection of the session's open transaction, or else on the
+/// primary of its segment, relaying every result it has. The session keeps the connection when
+/// it is to hold it, see `transaction::holds_connection`.
+pub fn run_as_sent(sql: &str, session_ctx: &mut SessionContext, sink: &mut dyn PayloadSink) -> Option<Vec<Bytes>> {
+    if let Err(e) = check_session_unscoped(sql, session_ctx) {
+        return Some(vec![err_payload(e)]);
+    }
+    let status_flags = session_ctx.get_status_flags();
+    if let Some(mut conn) = session_ctx.take_pinned_conn() {
+        let payloads = passthrough_query(&mut conn, sql, status_flags, sink);
+        session_ctx.pin_conn(conn);
+        return Some(payloads);
+    }
+    let mut conn = match variables::connect_to(session_ctx, &primary_url(session_ctx)) {
+        Ok(conn) => conn,
+        Err(e) => return Some(vec![err_payload(e)]),
+    };
+    let payloads = passthrough_query(&mut conn, sql, status_flags, sink);
+    if transaction::holds_connection(sql) {
+        conn.reset_on_release();
+        session_ctx.pin_conn(conn);
+        session_ctx.set_holding_conn(true);
+    }
+    Some(payloads)
+}
+
+#[cfg(test)]
+mod tests {
+    use data_panel_common::config::config::{PassthroughConfig, RowFilterConfig, RowFilterRule, TenantConfig};
+
+    use super::{check_unscoped, passes_through};
+
+    #[test]
+    fn test_check_unscoped() {
+        let tenants = TenantConfig::new(vec!["t_order".to_string()]);
+        let no_filters = RowFilterConfig::default();
+        let filters = RowFilterConfig::new(vec![RowFilterRule::new("analyst", "t_order", "region = 'EU'")]);
+        // A CALL sent as text and prepared, and a statement passed through.
+        for sql in &["CALL add_order(1, @id)", "CALL add_order(?, ?)", "HANDLER t_order OPEN"] {
+            assert!(check_unscoped(sql, "acme", &tenants, &no_filters).is_err(), "{}", sql);
+            assert!(check_unscoped(sql, "acme", &TenantConfig::default(), &no_filters).is_ok(), "{}", sql);
+            assert!(check_unscoped(sql, "analyst", &TenantConfig::default(), &filters).is_err(), "{}", sql);
+            assert!(check_unscoped(sql, "admin", &TenantConfig::default(), &filters).is_ok(), "{}", sql);
+        }
+        assert!(check_unscoped("CALL add_order(1, @id)", "acme", &TenantConfig::new(vec![]), &no_filters).is_ok());
+        let everyone = RowFilterConfig::new(vec![RowFilterRule::new("*", "t_order", "deleted = 0")]);
+        assert!(check_unscoped("CALL add_order(1, @id)", "admin", &TenantConfig::default(), &everyone).is_err());
+    }
+
+    #[test]
+    fn test_passes_through() {
+        let sql = "HANDLER t_order READ FIRST";
+        let enabled = PassthroughConfig::default().enabled(true);
+        let no_filters = RowFilterConfig::default();
+        assert_eq!(passes_through(sql, "acme", &PassthroughConfig::default(), &TenantConfig::default(), &no_filters).ok(), Some(false));
+        assert_eq!(passes_through(sql, "acme", &enabled, &TenantConfig::default(), &no_filters).ok(), Some(true));
+        // Run as sent, the statement would read the rows of every tenant.
+        assert!(passes_through(sql, "acme", &enabled, &TenantConfig::new(vec!["t_order".to_string()]), &no_filters).is_err());
+        let filters = RowFilterConfig::new(vec![RowFilterRule::new("acme", "t_order", "region = 'EU'")]);
+        assert!(passes_through(sql, "acme", &enabled, &TenantConfig::default(), &filters).is_err());
+    }
+}
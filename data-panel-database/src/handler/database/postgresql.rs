@@ -0,0 +1,362 @@
+//! Experimental PostgreSQL to MySQL protocol bridge.
+//!
+//! Statements of PostgreSQL clients are translated to the MySQL dialect (`$n` placeholders,
+//! quoted identifiers, casts) and run on the session's backend, the results are sent back in
+//! text format with the closest PostgreSQL types. Backend connections are per statement, as
+//! on the MySQL side, so SET statements are acknowledged without being forwarded.
+
+use bytes::Bytes;
+use mysql::{Params, QueryResult, Value};
+use mysql::consts::ColumnType;
+use mysql::prelude::{Protocol, Queryable};
+
+use crate::handler::database::{fault, lifecycle, scheduler};
+use crate::protocol::database::postgresql::{self, OID_BYTEA, OID_DATE, OID_FLOAT4, OID_FLOAT8, OID_INT2, OID_INT4, OID_INT8, OID_NUMERIC, OID_TEXT, OID_TIME, OID_TIMESTAMP};
+use crate::session::postgresql::{BridgePortal, BridgeResult, BridgeStatement, PostgreSQLSessionContext};
+
+/// SQLSTATE of statements the bridge cannot translate or run.
+const FEATURE_NOT_SUPPORTED: &str = "0A000";
+const INVALID_SQL_STATEMENT_NAME: &str = "26000";
+const INVALID_CURSOR_NAME: &str = "34000";
+const CONNECTION_FAILURE: &str = "08006";
+
+/// `sql` in the MySQL dialect, with the PostgreSQL parameter index of every `?` in order.
+pub fn translate(sql: &str) -> (String, Vec<usize>) {
+    let chars: Vec<char> = sql.chars().collect();
+    let mut translated = String::with_capacity(sql.len());
+    let mut param_order = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '\'' => {
+                let start = i;
+                i += 1;
+                while i < chars.len() {
+                    if chars[i] == '\'' && chars.get(i + 1) == Some(&'\'') {
+                        i += 2;
+                    } else if chars[i] == '\'' {
+                        break;
+                    } else {
+                        i += 1;
+                    }
+                }
+                translated.extend(&chars[start..(i + 1).min(chars.len())]);
+                i += 1;
+            }
+            '"' => {
+                let end = chars[i + 1..].iter().position(|c| *c == '"').map_or(chars.len(), |end| i + 1 + end);
+                translated.push('`');
+                translated.extend(&chars[i + 1..end]);
+                translated.push('`');
+                i = end + 1;
+            }
+            '$' if chars.get(i + 1).map_or(false, |c| c.is_ascii_digit()) => {
+                let start = i + 1;
+                i = start;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+                let index: String = chars[start..i].iter().collect();
+                param_order.push(index.parse::<usize>().unwrap_or(1).max(1) - 1);
+                translated.push('?');
+            }
+            ':' if chars.get(i + 1) == Some(&':') => {
+                // A cast, MySQL converts implicitly: skip the type name and its modifiers.
+                i += 2;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '[' || chars[i] == ']') {
+                    i += 1;
+                }
+                if chars.get(i) == Some(&'(') {
+                    i = chars[i..].iter().position(|c| *c == ')').map_or(chars.len(), |end| i + end + 1);
+                }
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '$') {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+                if word.eq_ignore_ascii_case("ILIKE") {
+                    translated.push_str("LIKE");
+                } else {
+                    translated.push_str(&word);
+                }
+            }
+            c => {
+                translated.push(c);
+                i += 1;
+            }
+        }
+    }
+    (translated, param_order)
+}
+
+fn column_oid(column_type: ColumnType) -> u32 {
+    match column_type {
+        ColumnType::MYSQL_TYPE_TINY | ColumnType::MYSQL_TYPE_SHORT | ColumnType::MYSQL_TYPE_YEAR => OID_INT2,
+        ColumnType::MYSQL_TYPE_LONG | ColumnType::MYSQL_TYPE_INT24 => OID_INT4,
+        ColumnType::MYSQL_TYPE_LONGLONG => OID_INT8,
+        ColumnType::MYSQL_TYPE_FLOAT => OID_FLOAT4,
+        ColumnType::MYSQL_TYPE_DOUBLE => OID_FLOAT8,
+        ColumnType::MYSQL_TYPE_DECIMAL | ColumnType::MYSQL_TYPE_NEWDECIMAL => OID_NUMERIC,
+        ColumnType::MYSQL_TYPE_DATE | ColumnType::MYSQL_TYPE_NEWDATE => OID_DATE,
+        ColumnType::MYSQL_TYPE_TIME | ColumnType::MYSQL_TYPE_TIME2 => OID_TIME,
+        ColumnType::MYSQL_TYPE_DATETIME | ColumnType::MYSQL_TYPE_DATETIME2
+        | ColumnType::MYSQL_TYPE_TIMESTAMP | ColumnType::MYSQL_TYPE_TIMESTAMP2 => OID_TIMESTAMP,
+        ColumnType::MYSQL_TYPE_BIT => OID_BYTEA,
+        _ => OID_TEXT,
+    }
+}
+
+/// The text format of `value`, as PostgreSQL would print a column of type `oid`.
+fn value_text(value: &Value, oid: u32) -> Option<String> {
+    match value {
+        Value::NULL => None,
+        Value::Bytes(bytes) if oid == OID_BYTEA => {
+            Some(format!("\\x{}", bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>()))
+        }
+        Value::Bytes(bytes) => Some(String::from_utf8_lossy(bytes).to_string()),
+        Value::Int(int) => Some(int.to_string()),
+        Value::UInt(uint) => Some(uint.to_string()),
+        Value::Float(float) => Some(float.to_string()),
+        Value::Double(double) => Some(double.to_string()),
+        Value::Date(year, month, day, ..) if oid == OID_DATE => Some(format!("{:04}-{:02}-{:02}", year, month, day)),
+        Value::Date(year, month, day, hour, minutes, seconds, micro_seconds) => {
+            let mut text = format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, hour, minutes, seconds);
+            if *micro_seconds > 0 {
+                text.push_str(&format!(".{:06}", micro_seconds));
+            }
+            Some(text)
+        }
+        Value::Time(is_negative, days, hours, minutes, seconds, micro_seconds) => {
+            let sign = if *is_negative { "-" } else { "" };
+            let mut text = format!("{}{:02}:{:02}:{:02}", sign, *days * 24 + *hours as u32, minutes, seconds);
+            if *micro_seconds > 0 {
+                text.push_str(&format!(".{:06}", micro_seconds));
+            }
+            Some(text)
+        }
+    }
+}
+
+fn verb(sql: &str) -> String {
+    sql.trim_start().split(|c: char| c.is_whitespace() || c == ';' || c == '(').next().unwrap_or("").to_uppercase()
+}
+
+fn command_tag(verb: &str, rows: usize, affected_rows: u64) -> String {
+    match verb {
+        "SELECT" | "SHOW" | "VALUES" | "WITH" => format!("SELECT {}", rows),
+        "INSERT" => format!("INSERT 0 {}", affected_rows),
+        "UPDATE" | "DELETE" => format!("{} {}", verb, affected_rows),
+        "" => "".to_string(),
+        verb => verb.to_string(),
+    }
+}
+
+fn collect_results<P: Protocol>(mut result: QueryResult<'_, '_, '_, P>, verb: &str) -> mysql::Result<Vec<BridgeResult>> {
+    let mut results = vec![];
+    while let Some(result_set) = result.next_set() {
+        let result_set = result_set?;
+        let columns: Vec<(String, u32)> = result_set.columns().as_ref().iter()
+            .map(|column| (column.name_str().to_string(), column_oid(column.column_type())))
+            .collect();
+        let affected_rows = result_set.affected_rows();
+        let mut rows = vec![];
+        for row in result_set {
+            let row = row?;
+            rows.push(columns.iter().enumerate()
+                .map(|(index, (_, oid))| row.as_ref(index).and_then(|value| value_text(value, *oid)))
+                .collect());
+        }
+        let verb = if columns.is_empty() { verb } else { "SELECT" };
+        let tag = command_tag(verb, rows.len(), affected_rows);
+        results.push(BridgeResult::new(columns, rows, tag));
+    }
+    Ok(results)
+}
+
+/// Runs `sql` on the backend, through the prepared statement protocol when there are params.
+fn run(session_ctx: &PostgreSQLSessionContext, sql: &str, params: Option<Vec<Value>>) -> mysql::Result<Vec<BridgeResult>> {
+    let _share = scheduler::fair_share(&session_ctx.get_user_name())?;
+    let mut conn = lifecycle::connect(&session_ctx.get_backend_url())?;
+    let verb = verb(sql);
+    fault::with_injected_latency(|| match params {
+        Some(params) => {
+            let statement = conn.prep(sql)?;
+            let result = conn.exec_iter(&statement, Params::Positional(params))?;
+            collect_results(result, &verb)
+        }
+        None => {
+            let result = conn.query_iter(sql)?;
+            collect_results(result, &verb)
+        }
+    })
+}
+
+fn backend_error(e: mysql::Error) -> Bytes {
+    match e {
+        mysql::Error::MySqlError(e) => postgresql::error_response(&e.state, &e.message),
+        e => postgresql::error_response(CONNECTION_FAILURE, &e.to_string()),
+    }
+}
+
+/// Follows the transaction state of the client, the backend connection does not keep it.
+fn track_transaction(session_ctx: &mut PostgreSQLSessionContext, verb: &str) {
+    match verb {
+        "BEGIN" | "START" => session_ctx.set_in_transaction(true),
+        "COMMIT" | "ROLLBACK" | "END" | "ABORT" => session_ctx.set_in_transaction(false),
+        _ => {}
+    }
+}
+
+fn result_payloads(payloads: &mut Vec<Bytes>, result: &BridgeResult, describe: bool) {
+    if describe && !result.get_columns().is_empty() {
+        payloads.push(postgresql::row_description(result.get_columns()));
+    }
+    for row in result.get_rows() {
+        payloads.push(postgresql::data_row(row));
+    }
+    payloads.push(postgresql::command_complete(result.get_command_tag()));
+}
+
+/// A simple query, possibly several statements, up to and including ReadyForQuery.
+pub fn simple_query(session_ctx: &mut PostgreSQLSessionContext, sql: &str) -> Vec<Bytes> {
+    let mut payloads = vec![];
+    let verb = verb(sql);
+    if verb.is_empty() {
+        payloads.push(postgresql::empty_query_response());
+    } else if verb == "SET" {
+        payloads.push(postgresql::command_complete("SET"));
+    } else {
+        let (translated, _) = translate(sql);
+        match run(session_ctx, &translated, None) {
+            Ok(results) => {
+                track_transaction(session_ctx, &verb);
+                for result in results.iter() {
+                    result_payloads(&mut payloads, result, true);
+                }
+            }
+            Err(e) => payloads.push(backend_error(e)),
+        }
+    }
+    payloads.push(postgresql::ready_for_query(session_ctx.get_transaction_status()));
+    payloads
+}
+
+pub fn parse(session_ctx: &mut PostgreSQLSessionContext, name: String, sql: &str, param_types: Vec<u32>) -> Vec<Bytes> {
+    let (translated, param_order) = translate(sql);
+    session_ctx.put_statement(name, BridgeStatement::new(translated, param_order, param_types));
+    vec![postgresql::parse_complete()]
+}
+
+pub fn bind(session_ctx: &mut PostgreSQLSessionContext, portal: String, statement: &str, formats: Vec<i16>, params: Vec<Option<Vec<u8>>>) -> Vec<Bytes> {
+    if formats.iter().any(|format| *format != 0) {
+        return vec![postgresql::error_response(FEATURE_NOT_SUPPORTED, "binary parameters are not supported by the protocol bridge")];
+    }
+    let statement = match session_ctx.get_statement(statement) {
+        Some(statement) => statement.clone(),
+        None => return vec![postgresql::error_response(INVALID_SQL_STATEMENT_NAME, &format!("prepared statement \"{}\" does not exist", statement))],
+    };
+    session_ctx.put_portal(portal, BridgePortal::new(statement, params));
+    vec![postgresql::bind_complete()]
+}
+
+/// Runs the statement of `portal` with its parameters, in placeholder order.
+fn run_portal(session_ctx: &PostgreSQLSessionContext, portal: &BridgePortal) -> Result<BridgeResult, Bytes> {
+    let statement = portal.get_statement();
+    let params: Vec<Value> = statement.get_param_order().iter()
+        .map(|index| match portal.get_params().get(*index) {
+            Some(Some(param)) => Value::Bytes(param.clone()),
+            _ => Value::NULL,
+        })
+        .collect();
+    let verb = verb(statement.get_sql());
+    if verb == "SET" {
+        return Ok(BridgeResult::new(vec![], vec![], "SET".to_string()));
+    }
+    let params = if params.is_empty() { None } else { Some(params) };
+    match run(session_ctx, statement.get_sql(), params) {
+        Ok(mut results) if !results.is_empty() => Ok(results.remove(0)),
+        Ok(_) => Ok(BridgeResult::new(vec![], vec![], command_tag(&verb, 0, 0))),
+        Err(e) => Err(backend_error(e)),
+    }
+}
+
+pub fn describe(session_ctx: &mut PostgreSQLSessionContext, kind: u8, name: &str) -> Vec<Bytes> {
+    if kind == b'S' {
+        return match session_ctx.get_statement(name) {
+            Some(statement) => {
+                let mut param_types = statement.get_param_types().to_vec();
+                param_types.resize(statement.get_params_count(), OID_TEXT);
+                // Columns are only known once the statement ran, clients describe the portal.
+                vec![postgresql::parameter_description(&param_types), postgresql::no_data()]
+            }
+            None => vec![postgresql::error_response(INVALID_SQL_STATEMENT_NAME, &format!("prepared statement \"{}\" does not exist", name))],
+        };
+    }
+    let mut portal = match session_ctx.remove_portal(name) {
+        Some(portal) => portal,
+        None => return vec![postgresql::error_response(INVALID_CURSOR_NAME, &format!("portal \"{}\" does not exist", name))],
+    };
+    let payloads = match run_portal(session_ctx, &portal) {
+        Ok(result) => {
+            let description = if result.get_columns().is_empty() {
+                postgresql::no_data()
+            } else {
+                postgresql::row_description(result.get_columns())
+            };
+            portal.set_result(result);
+            vec![description]
+        }
+        Err(error) => vec![error],
+    };
+    session_ctx.put_portal(name.to_string(), portal);
+    payloads
+}
+
+pub fn execute(session_ctx: &mut PostgreSQLSessionContext, name: &str) -> Vec<Bytes> {
+    let mut portal = match session_ctx.remove_portal(name) {
+        Some(portal) => portal,
+        None => return vec![postgresql::error_response(INVALID_CURSOR_NAME, &format!("portal \"{}\" does not exist", name))],
+    };
+    let result = match portal.take_result() {
+        Some(result) => Ok(result),
+        None => run_portal(session_ctx, &portal),
+    };
+    let mut payloads = vec![];
+    match result {
+        Ok(result) => {
+            track_transaction(session_ctx, &verb(portal.get_statement().get_sql()));
+            result_payloads(&mut payloads, &result, false);
+        }
+        Err(error) => payloads.push(error),
+    }
+    session_ctx.put_portal(name.to_string(), portal);
+    payloads
+}
+
+pub fn close(session_ctx: &mut PostgreSQLSessionContext, kind: u8, name: &str) -> Vec<Bytes> {
+    if kind == b'S' {
+        session_ctx.remove_statement(name);
+    } else {
+        session_ctx.remove_portal(name);
+    }
+    vec![postgresql::close_complete()]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handler::database::postgresql::translate;
+
+    #[test]
+    fn test_translate() {
+        let (sql, param_order) = translate("SELECT \"name\", 'it''s $1'::text FROM t_user WHERE id = $2::int8 AND name ILIKE $1");
+        assert_eq!(sql, "SELECT `name`, 'it''s $1' FROM t_user WHERE id = ? AND name LIKE ?");
+        assert_eq!(param_order, vec![1, 0]);
+
+        let (sql, param_order) = translate("INSERT INTO t_order (price) VALUES ($1::numeric(10,2))");
+        assert_eq!(sql, "INSERT INTO t_order (price) VALUES (?)");
+        assert_eq!(param_order, vec![0]);
+    }
+}
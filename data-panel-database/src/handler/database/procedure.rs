@@ -0,0 +1,71 @@
+//! Stored procedure CALLs, which the parser does not read.
+//!
+//! A CALL runs as sent on the connection of the session's open transaction, or else on the
+//! primary of its segment, see `passthrough::run_as_sent`. Every result set of the procedure is
+//! relayed flagged SERVER_MORE_RESULTS_EXISTS up to the closing OK. Prepared, the OUT and INOUT
+//! parameters come back as the last result set, flagged SERVER_PS_OUT_PARAMS. Like passthrough
+//! statements, CALLs escape the firewall, approval and column ACL rules: what a procedure may
+//! do is up to the grants of the backend. The tenant and row filter rules could not scope what
+//! a procedure does either, so while tenant tables are configured, or a row filter applies to
+//! the user, CALLs are refused, see `passthrough::check_unscoped`.
+
+use mysql::prelude::Queryable;
+
+use crate::handler::database::lifecycle::BackendConn;
+
+const CALL: &str = "CALL";
+
+const OUT_PARAMETERS_SQL: &str = "SELECT COUNT(*) FROM information_schema.PARAMETERS \
+    WHERE SPECIFIC_SCHEMA = COALESCE(?, DATABASE()) AND SPECIFIC_NAME = ? \
+    AND ROUTINE_TYPE = 'PROCEDURE' AND PARAMETER_MODE IN ('OUT', 'INOUT')";
+
+/// Whether `sql` calls a stored procedure.
+pub fn is_call(sql: &str) -> bool {
+    let sql = sql.trim_start();
+    match (sql.get(..CALL.len()), sql.get(CALL.len()..)) {
+        (Some(keyword), Some(rest)) => keyword.eq_ignore_ascii_case(CALL) && rest.starts_with(|c: char| c.is_whitespace() || c == '`'),
+        _ => false,
+    }
+}
+
+/// The schema, if named, and the name of the procedure `sql` calls.
+fn procedure_name(sql: &str) -> Option<(Option<String>, String)> {
+    let called = sql.trim_start().get(CALL.len()..)?.trim_start();
+    let end = called.find(|c: char| c == '(' || c == ';' || c.is_whitespace()).unwrap_or_else(|| called.len());
+    let mut parts: Vec<String> = called[..end].split('.').map(|part| part.trim_matches('`').to_string()).collect();
+    let name = parts.pop().filter(|name| !name.is_empty())?;
+    Some((parts.pop(), name))
+}
+
+/// How many OUT and INOUT parameters the procedure `sql` calls has, so many columns as the
+/// result set carrying them back to a prepared CALL has. 0 when the backend does not tell.
+pub fn out_parameters(conn: &mut BackendConn, sql: &str) -> usize {
+    let (schema, name) = match procedure_name(sql) {
+        Some(procedure) => procedure,
+        None => return 0,
+    };
+    match conn.exec_first::<u64, _, _>(OUT_PARAMETERS_SQL, (schema, name)) {
+        Ok(count) => count.unwrap_or(0) as usize,
+        Err(e) => {
+            println!("error on reading the parameters of {}; error = {:?}", sql, e);
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_call, procedure_name};
+
+    #[test]
+    fn test_call() {
+        assert!(is_call(" call add_order(?, @id)"));
+        assert!(is_call("CALL `shop`.`add_order`()"));
+        assert!(!is_call("CALLED"));
+        assert!(!is_call("SELECT 1"));
+
+        assert_eq!(procedure_name("CALL add_order(1, @id)"), Some((None, "add_order".to_string())));
+        assert_eq!(procedure_name("CALL `shop`.`add_order`"), Some((Some("shop".to_string()), "add_order".to_string())));
+        assert_eq!(procedure_name("CALL ()"), None);
+    }
+}
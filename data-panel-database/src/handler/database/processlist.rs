@@ -0,0 +1,105 @@
+//! `SHOW PROCESSLIST`, `COM_PROCESS_INFO` and `COM_STATISTICS`, answered by the mesh.
+//!
+//! The process list is the mesh's own session table, with the mesh session ids `KILL` takes.
+//! Like a server to a user without the PROCESS privilege, it only lists the sessions of the
+//! user asking.
+
+use bytes::Bytes;
+
+use crate::handler::database::mysql::text_result_payloads;
+use crate::protocol::database::PacketPayload;
+use crate::protocol::database::mysql::packet::MySQLPacketPayload;
+use crate::service::shutdown::service_counters;
+use crate::session::activity::{session_activities, SessionActivitySnapshot, SessionPhase};
+
+/// The most characters of a statement `SHOW PROCESSLIST` shows without `FULL`.
+const INFO_LENGTH: usize = 100;
+
+const PROCESSLIST_COLUMNS: [&str; 8] = ["Id", "User", "Host", "db", "Command", "Time", "State", "Info"];
+
+/// Whether `sql` is `SHOW [FULL] PROCESSLIST`, and whether it is full.
+pub fn processlist_statement(sql: &str) -> Option<bool> {
+    let words: Vec<&str> = sql.trim().trim_end_matches(';').split_whitespace().collect();
+    match words.as_slice() {
+        [show, processlist] if show.eq_ignore_ascii_case("SHOW") && processlist.eq_ignore_ascii_case("PROCESSLIST") => Some(false),
+        [show, full, processlist] if show.eq_ignore_ascii_case("SHOW") && full.eq_ignore_ascii_case("FULL") && processlist.eq_ignore_ascii_case("PROCESSLIST") => Some(true),
+        _ => None,
+    }
+}
+
+fn processlist_row(activity: &SessionActivitySnapshot, full: bool) -> Vec<String> {
+    let (command, state) = match activity.get_phase() {
+        SessionPhase::Idle => ("Sleep", ""),
+        SessionPhase::Backend => ("Query", "executing"),
+        SessionPhase::Client => ("Query", "Sending to client"),
+    };
+    let info = activity.get_query()
+        .map(|query| if full { query.clone() } else { query.chars().take(INFO_LENGTH).collect() })
+        .unwrap_or_default();
+    vec![
+        activity.get_session_id().to_string(),
+        activity.get_details().get_user(),
+        activity.get_client_addr().to_string(),
+        activity.get_details().get_database(),
+        command.to_string(),
+        (activity.get_elapsed() / 1000).to_string(),
+        state.to_string(),
+        info,
+    ]
+}
+
+fn processlist_rows(user: &str, full: bool) -> Vec<Vec<String>> {
+    let mut activities: Vec<SessionActivitySnapshot> = session_activities().into_iter()
+        .filter(|activity| activity.get_details().get_user() == user)
+        .collect();
+    activities.sort_by_key(|activity| activity.get_session_id());
+    activities.iter().map(|activity| processlist_row(activity, full)).collect()
+}
+
+/// The sessions of `user`, for `SHOW PROCESSLIST` and `COM_PROCESS_INFO`.
+pub fn processlist_payloads(user: &str, full: bool) -> Option<Vec<Bytes>> {
+    text_result_payloads(PROCESSLIST_COLUMNS.to_vec(), processlist_rows(user, full))
+}
+
+/// The status line answering `COM_STATISTICS`, a bare string rather than an OK packet.
+pub fn statistics_payloads() -> Option<Vec<Bytes>> {
+    let counters = service_counters();
+    let uptime = counters.get_uptime().as_secs();
+    let questions = counters.get_queries();
+    let statistics = format!(
+        "Uptime: {}  Threads: {}  Questions: {}  Slow queries: 0  Opens: 0  Flush tables: 0  Open tables: 0  Queries per second avg: {:.3}",
+        uptime,
+        session_activities().len(),
+        questions,
+        questions as f64 / uptime.max(1) as f64,
+    );
+    let mut payload = MySQLPacketPayload::new();
+    payload.put_u8(1);
+    payload.put_slice(statistics.as_bytes());
+    Some(vec![payload.get_payload()])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::session::activity::{register_session_activity, SessionDetails, SessionPhase};
+
+    use super::{processlist_rows, processlist_statement};
+
+    #[test]
+    fn test_processlist() {
+        assert_eq!(processlist_statement("show processlist;"), Some(false));
+        assert_eq!(processlist_statement("SHOW FULL PROCESSLIST"), Some(true));
+        assert_eq!(processlist_statement("SHOW TABLES"), None);
+
+        let guard = register_session_activity(77, "10.0.0.7:40000".parse().unwrap());
+        guard.describe(SessionDetails::new("reporter".to_string(), "sales".to_string(), "127.0.0.1:3306/sales".to_string(), vec![]));
+        guard.run(Some(format!("SELECT '{}'", "x".repeat(200))));
+        guard.enter(SessionPhase::Backend);
+        let rows = processlist_rows("reporter", false);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0][..7], ["77", "reporter", "10.0.0.7:40000", "sales", "Query", "0", "executing"]);
+        assert_eq!(rows[0][7].len(), 100);
+        assert_eq!(processlist_rows("reporter", true)[0][7].len(), 211);
+        assert!(processlist_rows("admin", true).is_empty());
+    }
+}
@@ -0,0 +1,168 @@
+//! Rate limiting of the statements clients send.
+//!
+//! Token buckets refill at the configured rate: one for all statements, one per user and one
+//! per client address. A statement takes a token from each bucket it falls in, and fails when
+//! any of them is empty rather than waiting for the bucket to refill.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use data_panel_common::config::config::RateLimitConfig;
+
+/// Buckets kept per user or address before the full ones, of idle clients, are dropped.
+const MAX_BUCKETS: usize = 4096;
+
+struct TokenBucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64) -> Self {
+        TokenBucket {
+            tokens: capacity,
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, rate: u32, capacity: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(capacity);
+        self.refilled = now;
+    }
+}
+
+struct LimiterState {
+    global: TokenBucket,
+    users: HashMap<String, TokenBucket>,
+    ips: HashMap<IpAddr, TokenBucket>,
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    state: Mutex<LimiterState>,
+    rejected: AtomicU64,
+}
+
+fn prune<K>(buckets: &mut HashMap<K, TokenBucket>, rate: u32, capacity: f64) {
+    if buckets.len() > MAX_BUCKETS {
+        buckets.retain(|_, bucket| {
+            bucket.refill(rate, capacity);
+            bucket.tokens < capacity
+        });
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        let global = TokenBucket::new((config.get_global() * config.get_burst()) as f64);
+        RateLimiter {
+            config,
+            state: Mutex::new(LimiterState {
+                global,
+                users: HashMap::new(),
+                ips: HashMap::new(),
+            }),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    fn capacity(&self, rate: u32) -> f64 {
+        (rate * self.config.get_burst()) as f64
+    }
+
+    /// Takes a token for a statement of `user` from `ip`, or tells which limit it exceeds.
+    pub fn admit(&self, user: &str, ip: IpAddr) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let (global, per_user, per_ip) = (self.config.get_global(), self.config.get_per_user(), self.config.get_per_ip());
+        let mut buckets = vec![];
+        if global > 0 {
+            buckets.push((&mut state.global, global, "all clients".to_string()));
+        }
+        if per_user > 0 {
+            prune(&mut state.users, per_user, self.capacity(per_user));
+            let bucket = state.users.entry(user.to_string()).or_insert_with(|| TokenBucket::new(self.capacity(per_user)));
+            buckets.push((bucket, per_user, format!("user {}", user)));
+        }
+        if per_ip > 0 {
+            prune(&mut state.ips, per_ip, self.capacity(per_ip));
+            let bucket = state.ips.entry(ip).or_insert_with(|| TokenBucket::new(self.capacity(per_ip)));
+            buckets.push((bucket, per_ip, format!("client {}", ip)));
+        }
+        for (bucket, rate, scope) in buckets.iter_mut() {
+            bucket.refill(*rate, self.capacity(*rate));
+            if bucket.tokens < 1.0 {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(format!("rate limit of {} statements per second exceeded for {}", rate, scope));
+            }
+        }
+        for (bucket, _, _) in buckets.iter_mut() {
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+
+    /// Statements refused so far.
+    pub fn get_rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+lazy_static! {
+    static ref RATE_LIMITER: RwLock<Option<Arc<RateLimiter>>> = RwLock::new(None);
+}
+
+pub fn configure_rate_limiter(config: &RateLimitConfig) {
+    let limiter = if config.is_enabled() {
+        Some(Arc::new(RateLimiter::new(config.clone())))
+    } else {
+        None
+    };
+    *RATE_LIMITER.write().unwrap() = limiter;
+}
+
+pub fn rate_limiter() -> Option<Arc<RateLimiter>> {
+    RATE_LIMITER.read().unwrap().clone()
+}
+
+/// Admits a statement of `user` from `ip`, always when no limiter is configured.
+pub fn admit(user: &str, ip: IpAddr) -> Result<(), String> {
+    match rate_limiter() {
+        Some(limiter) => limiter.admit(user, ip),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use data_panel_common::config::config::RateLimitConfig;
+
+    use super::RateLimiter;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(RateLimitConfig::new(0, 2, 0));
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        assert!(limiter.admit("web", ip).is_ok());
+        assert!(limiter.admit("web", ip).is_ok());
+        assert!(limiter.admit("web", ip).is_err());
+        assert!(limiter.admit("batch", ip).is_ok());
+        assert_eq!(limiter.get_rejected(), 1);
+
+        let limiter = RateLimiter::new(RateLimitConfig::new(3, 0, 1));
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        assert!(limiter.admit("web", ip).is_ok());
+        assert!(limiter.admit("web", ip).is_err());
+        assert!(limiter.admit("web", other).is_ok());
+        // The refused statement did not take a token from the global bucket.
+        assert!(limiter.admit("web", "10.0.0.3".parse().unwrap()).is_ok());
+        assert!(limiter.admit("web", "10.0.0.4".parse().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,58 @@
+//! `EXPLAIN ROUTE <statement>`: how the statement's tables are routed under every kept rules
+//! version, so a routing decision can be compared across rule changes.
+
+use bytes::Bytes;
+use sqlparser::parser::Parser;
+
+use crate::discovery::database::rules::{current_rules_version, rules_history, UNVERSIONED};
+use data_panel_common::common::Error;
+
+use crate::handler::database::mysql::text_result_payloads;
+use crate::handler::database::mysql::rdbc::err_payload;
+use crate::handler::database::parser::sql::mysql::MySQLDialect;
+use crate::handler::database::parser::sql::statement_tables;
+
+const EXPLAIN_ROUTE: &str = "EXPLAIN ROUTE ";
+
+/// The explained statement if `sql` is an EXPLAIN ROUTE command.
+pub fn explained_statement(sql: &str) -> Option<&str> {
+    let sql = sql.trim_start();
+    if sql.len() > EXPLAIN_ROUTE.len() && sql[..EXPLAIN_ROUTE.len()].eq_ignore_ascii_case(EXPLAIN_ROUTE) {
+        Some(&sql[EXPLAIN_ROUTE.len()..])
+    } else {
+        None
+    }
+}
+
+pub fn explain_route(sql: &str) -> Option<Vec<Bytes>> {
+    let dialect = MySQLDialect {};
+    let mut statements = match Parser::parse_sql(&dialect, sql) {
+        Ok(statements) => statements,
+        Err(e) => return Some(vec![err_payload(Error::Parse(e.to_string()))]),
+    };
+    let statement = match statements.pop() {
+        Some(statement) => statement,
+        None => return Some(vec![err_payload(Error::Parse("empty statement".to_string()))]),
+    };
+    let tables = statement_tables(&statement);
+
+    let current = current_rules_version();
+    let mut rows = vec![];
+    for rules in rules_history() {
+        for table in tables.iter() {
+            rows.push(vec![
+                rules.get_version(),
+                rules.get_loaded_at().to_string(),
+                (rules.get_version() == current).to_string(),
+                table.clone(),
+                rules.route_of(table),
+            ]);
+        }
+    }
+    if rows.is_empty() {
+        for table in tables.iter() {
+            rows.push(vec![UNVERSIONED.to_string(), "".to_string(), "true".to_string(), table.clone(), "meta segment".to_string()]);
+        }
+    }
+    text_result_payloads(vec!["rules_version", "loaded_at", "current", "table", "route"], rows)
+}
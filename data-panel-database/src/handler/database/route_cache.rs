@@ -0,0 +1,794 @@
+//! Cache of route plans.
+//!
+//! A plan is keyed by the statement fingerprint (see `parser::sql::fingerprint`) plus the literal
+//! values the statement binds to shard keys, so hot statements reuse their routing instead of
+//! resolving every table again. The cache holds a bounded number of plans, evicting the least
+//! recently used one, and starts over whenever another rules version becomes active. Shard keys
+//! bound by a `SHARD(...)` hint count as bound by the WHERE clause.
+//!
+//! A statement binds a shard key of one of its tables with a `column = literal` equality its
+//! WHERE clause ANDs at the top level, the column qualified by the table or its alias, or
+//! unqualified in a statement over that table alone. An equality under an OR, a NOT, a
+//! comparison, a function or a CASE binds nothing, and the table scatters.
+//!
+//! A statement over several tables runs on a data segment as a whole only when its tables meet
+//! there: replicated tables are on every data segment, and binding tables, which list each
+//! other in `dis_relatives`, keep the rows of a shard key value on the same one, so a bound
+//! table narrows the segments of its relatives. Binding tables meet only when the statement
+//! equates their shard keys. Other distributed tables spanning more than one data segment
+//! cannot be joined and are refused, see `RoutePlan::check_join`.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use sqlparser::ast::{BinaryOperator, Expr, JoinConstraint, JoinOperator, Select, SetExpr, Statement, TableFactor, Value};
+use sqlparser::tokenizer::Tokenizer;
+
+use data_panel_common::config::config::RouteCacheConfig;
+
+use crate::discovery::database::rules::{current_rules, RulesVersion, TableRoute};
+use crate::handler::database::corpus::normalize_tokens;
+use crate::handler::database::parser::sql::hint::SQLHints;
+use crate::handler::database::parser::sql::mysql::MySQLDialect;
+use crate::handler::database::parser::sql::{fingerprint_statement, rewrite_statement, statement_tables, unquoted_table};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RoutePlanKey {
+    fingerprint: String,
+    /// `(table, shard key, value)` triples, sorted. The table is empty for the shard keys a
+    /// `SHARD(...)` hint binds, which bind every table.
+    bindings: Vec<(String, String, String)>,
+}
+
+impl RoutePlanKey {
+    pub fn get_fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    pub fn get_bindings(&self) -> &[(String, String, String)] {
+        &self.bindings
+    }
+
+    /// The key with the shard keys of `hint_bindings` added to its bindings.
+    fn hinted(mut self, hint_bindings: &[(String, String)]) -> Self {
+        self.bindings.extend(hint_bindings.iter().map(|(key, value)| (String::new(), key.clone(), value.clone())));
+        self.bindings.sort();
+        self.bindings.dedup();
+        self
+    }
+}
+
+/// The cache key of `sql` without bindings, or None if it does not tokenize.
+pub fn plan_key(sql: &str) -> Option<RoutePlanKey> {
+    let dialect = MySQLDialect {};
+    let tokens = Tokenizer::new(&dialect, sql).tokenize().ok()?;
+    Some(RoutePlanKey {
+        fingerprint: normalize_tokens(&tokens),
+        bindings: vec![],
+    })
+}
+
+/// The cache key of `sql` parsed into `statement`, with the parser fingerprint of the
+/// statement when it can be written, and the shard keys the statement binds under `rules`.
+pub fn statement_plan_key(sql: &str, statement: &Statement, rules: &RulesVersion) -> Option<RoutePlanKey> {
+    let key = plan_key(sql)?;
+    Some(RoutePlanKey {
+        fingerprint: fingerprint_statement(statement).unwrap_or(key.fingerprint),
+        bindings: statement_bindings(rules, statement),
+    })
+}
+
+/// The shard keys of the SHARD hint of a statement, of the ones in `shard_keys`.
+fn hint_bindings(hints: &SQLHints, shard_keys: &HashSet<String>) -> Vec<(String, String)> {
+    hints.get_shard_bindings().into_iter().filter(|(key, _)| shard_keys.contains(key)).collect()
+}
+
+/// How the distributed tables of a statement meet on the data segments.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoinRoute {
+    /// At most one distributed table, with any replicated tables.
+    Broadcast,
+    /// Distributed tables that are all relatives of one another, joined on their shard keys.
+    Binding,
+    /// Distributed tables that are not relatives, or not joined on their shard keys.
+    CrossSegment,
+}
+
+/// A column of a table, as `(table, lowercased column)`.
+type TableColumn = (String, String);
+
+/// The table and column `expr` names through the aliases of the FROM clause, None for
+/// unqualified columns, which the mesh can't tell the table of.
+fn qualified_column(expr: &Expr, aliases: &HashMap<String, String>) -> Option<TableColumn> {
+    match expr {
+        Expr::CompoundIdentifier(idents) if idents.len() >= 2 => {
+            let table = aliases.get(&idents[idents.len() - 2].value.to_lowercase())?;
+            Some((table.clone(), idents[idents.len() - 1].value.to_lowercase()))
+        }
+        Expr::Nested(expr) => qualified_column(expr, aliases),
+        _ => None,
+    }
+}
+
+/// The table and column `expr` names, an unqualified column naming `only_table`, the table of
+/// a statement over a single one.
+fn bound_column(expr: &Expr, aliases: &HashMap<String, String>, only_table: Option<&String>) -> Option<TableColumn> {
+    match expr {
+        Expr::Identifier(ident) => only_table.map(|table| (table.clone(), ident.value.to_lowercase())),
+        Expr::Nested(expr) => bound_column(expr, aliases, only_table),
+        _ => qualified_column(expr, aliases),
+    }
+}
+
+fn literal(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Value(Value::Number(number, _)) => Some(number.clone()),
+        Expr::Value(Value::SingleQuotedString(value)) => Some(value.clone()),
+        Expr::Nested(expr) => literal(expr),
+        _ => None,
+    }
+}
+
+/// The `column = literal` equalities `condition` ANDs at its top level, the only ones every
+/// row it keeps satisfies: nested in a comparison, a function, a CASE, a negation or a
+/// disjunction, an equality restricts nothing.
+fn conjunct_bindings(condition: &Expr, aliases: &HashMap<String, String>, only_table: Option<&String>, bindings: &mut Vec<(TableColumn, String)>) {
+    match condition {
+        Expr::BinaryOp { left, op: BinaryOperator::And, right } => {
+            conjunct_bindings(left, aliases, only_table, bindings);
+            conjunct_bindings(right, aliases, only_table, bindings);
+        }
+        Expr::BinaryOp { left, op: BinaryOperator::Eq, right } => {
+            let binding = |column: &Expr, value: &Expr| bound_column(column, aliases, only_table).and_then(|column| literal(value).map(|value| (column, value)));
+            if let Some(binding) = binding(left, right).or_else(|| binding(right, left)) {
+                bindings.push(binding);
+            }
+        }
+        Expr::Nested(condition) => conjunct_bindings(condition, aliases, only_table, bindings),
+        _ => {}
+    }
+}
+
+/// The tables of the FROM clause of `select` by the qualifier their columns are named with,
+/// their alias or their name, and the table of the clause when it has only that one.
+fn from_tables(select: &Select) -> (HashMap<String, String>, Option<String>) {
+    let mut aliases = HashMap::new();
+    let mut relations = 0;
+    for table in select.from.iter() {
+        for relation in std::iter::once(&table.relation).chain(table.joins.iter().map(|join| &join.relation)) {
+            relations += 1;
+            if let TableFactor::Table { name, alias, .. } = relation {
+                let table = unquoted_table(&name.to_string());
+                let qualifier = alias.as_ref().map_or_else(|| table.clone(), |alias| alias.name.value.clone());
+                aliases.insert(qualifier.to_lowercase(), table);
+            }
+        }
+    }
+    let only_table = match (relations, aliases.values().next()) {
+        (1, Some(table)) => Some(table.clone()),
+        _ => None,
+    };
+    (aliases, only_table)
+}
+
+/// The `(table, shard key, value)` triples the WHERE clause of `statement` binds under
+/// `rules`, sorted: its top-level ANDed equalities of a shard key of one of its tables with a
+/// literal.
+pub fn statement_bindings(rules: &RulesVersion, statement: &Statement) -> Vec<(String, String, String)> {
+    let (aliases, only_table, selection) = match statement {
+        Statement::Query(query) => match &query.body {
+            SetExpr::Select(select) => {
+                let (aliases, only_table) = from_tables(select);
+                (aliases, only_table, select.selection.as_ref())
+            }
+            _ => return vec![],
+        },
+        Statement::Update { table_name, selection, .. } | Statement::Delete { table_name, selection, .. } => {
+            let table = unquoted_table(&table_name.to_string());
+            let aliases = std::iter::once((table.to_lowercase(), table.clone())).collect();
+            (aliases, Some(table), selection.as_ref())
+        }
+        _ => return vec![],
+    };
+    let mut bindings = vec![];
+    if let Some(selection) = selection {
+        conjunct_bindings(selection, &aliases, only_table.as_ref(), &mut bindings);
+    }
+    let mut bindings: Vec<(String, String, String)> = bindings.into_iter()
+        .filter_map(|((table, column), value)| {
+            let key = rules.dis_keys(&table).into_iter().find(|key| key.eq_ignore_ascii_case(&column))?;
+            Some((table, key.to_lowercase(), value))
+        })
+        .collect();
+    bindings.sort();
+    bindings.dedup();
+    bindings
+}
+
+/// The column equalities of `condition` every row it keeps satisfies: the ones it ANDs.
+fn conjunct_equalities(condition: &Expr, aliases: &HashMap<String, String>, equalities: &mut Vec<(TableColumn, TableColumn)>) {
+    match condition {
+        Expr::BinaryOp { left, op: BinaryOperator::And, right } => {
+            conjunct_equalities(left, aliases, equalities);
+            conjunct_equalities(right, aliases, equalities);
+        }
+        Expr::BinaryOp { left, op: BinaryOperator::Eq, right } => {
+            if let (Some(left), Some(right)) = (qualified_column(left, aliases), qualified_column(right, aliases)) {
+                equalities.push((left, right));
+            }
+        }
+        Expr::Nested(condition) => conjunct_equalities(condition, aliases, equalities),
+        _ => {}
+    }
+}
+
+/// The columns of two tables the ON conditions and the WHERE clause of `statement` equate.
+fn column_equalities(statement: &Statement) -> Vec<(TableColumn, TableColumn)> {
+    let select = match statement {
+        Statement::Query(query) => match &query.body {
+            SetExpr::Select(select) => select,
+            _ => return vec![],
+        },
+        _ => return vec![],
+    };
+    let (aliases, _) = from_tables(select);
+    let mut conditions: Vec<&Expr> = select.selection.iter().collect();
+    for table in select.from.iter() {
+        for join in table.joins.iter() {
+            match &join.join_operator {
+                JoinOperator::Inner(JoinConstraint::On(on)) | JoinOperator::LeftOuter(JoinConstraint::On(on))
+                | JoinOperator::RightOuter(JoinConstraint::On(on)) | JoinOperator::FullOuter(JoinConstraint::On(on)) => conditions.push(on),
+                _ => {}
+            }
+        }
+    }
+    let mut equalities = vec![];
+    for condition in conditions {
+        conjunct_equalities(condition, &aliases, &mut equalities);
+    }
+    equalities
+}
+
+/// Whether `equalities` equate every shard key of `a` with the shard key of `b` in its place.
+fn joined_on_keys(rules: &RulesVersion, a: &str, b: &str, equalities: &[(TableColumn, TableColumn)]) -> bool {
+    let (keys_a, keys_b) = (rules.dis_keys(a), rules.dis_keys(b));
+    let equated = |key_a: &String, key_b: &String| equalities.iter().any(|(left, right)| {
+        let is = |(table, column): &TableColumn, name: &str, key: &String| table == name && column.eq_ignore_ascii_case(key);
+        is(left, a, key_a) && is(right, b, key_b) || is(left, b, key_b) && is(right, a, key_a)
+    });
+    !keys_a.is_empty() && keys_a.len() == keys_b.len() && keys_a.iter().zip(keys_b.iter()).all(|(key_a, key_b)| equated(key_a, key_b))
+}
+
+fn join_route(rules: &RulesVersion, tables: &[(String, TableRoute)], equalities: &[(TableColumn, TableColumn)]) -> JoinRoute {
+    let distributed: Vec<&String> = tables.iter()
+        .filter(|(_, route)| matches!(route, TableRoute::Distributed(_)))
+        .map(|(table, _)| table)
+        .collect();
+    if distributed.len() < 2 {
+        return JoinRoute::Broadcast;
+    }
+    let bound = distributed.iter().enumerate()
+        .all(|(i, a)| distributed[i + 1..].iter().all(|b| a == b || rules.are_relatives(a, b) && joined_on_keys(rules, a, b, equalities)));
+    if bound { JoinRoute::Binding } else { JoinRoute::CrossSegment }
+}
+
+/// Routes every binding table to the segments all of them may be on, the ones of the first
+/// if they have none in common: no row of theirs joins then, and one segment tells so.
+fn bind_relatives(tables: &mut [(String, TableRoute)]) {
+    let mut common: Option<Vec<u32>> = None;
+    for (_, route) in tables.iter() {
+        if let TableRoute::Distributed(segments) = route {
+            common = Some(match common {
+                Some(common) => common.into_iter().filter(|segment| segments.contains(segment)).collect(),
+                None => segments.clone(),
+            });
+        }
+    }
+    let common = match common {
+        Some(common) if !common.is_empty() => common,
+        _ => match tables.iter().find_map(|(_, route)| match route {
+            TableRoute::Distributed(segments) => segments.first().cloned(),
+            _ => None,
+        }) {
+            Some(segment) => vec![segment],
+            None => return,
+        },
+    };
+    for (_, route) in tables.iter_mut() {
+        if let TableRoute::Distributed(segments) = route {
+            *segments = common.clone();
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RoutePlan {
+    rules_version: String,
+    tables: Vec<(String, TableRoute)>,
+    join: JoinRoute,
+}
+
+impl RoutePlan {
+    /// The plan of `statement`, its tables routed by the shard keys its WHERE clause binds, see
+    /// `statement_bindings`, and by `bindings`, which bind every table.
+    pub fn build(rules: &RulesVersion, statement: &Statement, bindings: &[(String, String)]) -> Self {
+        let statement_bindings = statement_bindings(rules, statement);
+        let mut tables: Vec<(String, TableRoute)> = statement_tables(statement).into_iter().map(|table| {
+            let mut table_bindings: Vec<(String, String)> = statement_bindings.iter()
+                .filter(|(bound_table, _, _)| *bound_table == table)
+                .map(|(_, key, value)| (key.clone(), value.clone()))
+                .chain(bindings.iter().cloned())
+                .collect();
+            table_bindings.sort();
+            table_bindings.dedup();
+            let route = rules.route(&table, &table_bindings);
+            (table, route)
+        }).collect();
+        let join = join_route(rules, &tables, &column_equalities(statement));
+        if join == JoinRoute::Binding {
+            bind_relatives(&mut tables);
+        }
+        RoutePlan {
+            rules_version: rules.get_version(),
+            tables,
+            join,
+        }
+    }
+
+    pub fn get_rules_version(&self) -> &str {
+        &self.rules_version
+    }
+
+    pub fn get_tables(&self) -> &[(String, TableRoute)] {
+        &self.tables
+    }
+
+    pub fn get_join(&self) -> JoinRoute {
+        self.join
+    }
+
+    /// Refuses a join of unrelated distributed tables over more than one data segment, no
+    /// segment holds the rows to join.
+    pub fn check_join(&self) -> Result<(), String> {
+        if self.join != JoinRoute::CrossSegment || self.data_segments().len() < 2 {
+            return Ok(());
+        }
+        let distributed: Vec<&str> = self.tables.iter()
+            .filter(|(_, route)| matches!(route, TableRoute::Distributed(_)))
+            .map(|(table, _)| table.as_str())
+            .collect();
+        Err(format!("{} are distributed without being relatives joined on their shard keys, they can't be joined across data segments; \
+                     bind their shard keys to one segment, or list them in dis_relatives and join them on their shard keys", distributed.join(", ")))
+    }
+
+    /// The data segments the statement touches, none when it stays on the meta segment.
+    pub fn data_segments(&self) -> Vec<u32> {
+        let mut segments: Vec<u32> = self.tables.iter()
+            .flat_map(|(_, route)| match route {
+                TableRoute::Distributed(segments) => segments.clone(),
+                _ => vec![],
+            })
+            .collect();
+        segments.sort_unstable();
+        segments.dedup();
+        segments
+    }
+
+    /// `statement` as sent to data segment `segment`: rewritten per `rewrite_ctx`, and its
+    /// distributed tables renamed to their actual tables there. None when it can't be written.
+    pub fn segment_sql(&self, statement: &Statement, segment: u32, rules: &RulesVersion, rewrite_ctx: &HashMap<String, String>) -> Option<String> {
+        let mut ctx = rewrite_ctx.clone();
+        for (table, route) in self.tables.iter() {
+            let actual = rules.actual_table(table, segment);
+            if matches!(route, TableRoute::Distributed(_)) && actual != *table {
+                ctx.insert(table.clone(), actual);
+            }
+        }
+        if ctx.is_empty() {
+            return Some(statement.to_string());
+        }
+        rewrite_statement(statement, &ctx)
+    }
+}
+
+struct RouteCacheState {
+    rules_version: String,
+    shard_keys: Arc<HashSet<String>>,
+    tick: u64,
+    plans: HashMap<RoutePlanKey, (Arc<RoutePlan>, u64)>,
+    /// Keys by last use, the first one is evicted next.
+    recency: BTreeMap<u64, RoutePlanKey>,
+}
+
+impl RouteCacheState {
+    fn get(&mut self, key: &RoutePlanKey) -> Option<Arc<RoutePlan>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (plan, used) = self.plans.get_mut(key)?;
+        self.recency.remove(used);
+        *used = tick;
+        self.recency.insert(tick, key.clone());
+        Some(plan.clone())
+    }
+
+    /// Inserts `plan`, returns whether a plan was evicted for it.
+    fn insert(&mut self, key: RoutePlanKey, plan: Arc<RoutePlan>, capacity: usize) -> bool {
+        self.tick += 1;
+        if let Some((_, used)) = self.plans.insert(key.clone(), (plan, self.tick)) {
+            self.recency.remove(&used);
+        }
+        self.recency.insert(self.tick, key);
+        if self.plans.len() <= capacity {
+            return false;
+        }
+        let oldest = self.recency.keys().next().cloned();
+        if let Some(key) = oldest.and_then(|oldest| self.recency.remove(&oldest)) {
+            self.plans.remove(&key);
+        }
+        true
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RouteCacheStats {
+    rules_version: String,
+    plans: usize,
+    capacity: usize,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+    invalidations: u64,
+}
+
+impl RouteCacheStats {
+    pub fn get_rules_version(&self) -> &str {
+        &self.rules_version
+    }
+
+    pub fn get_plans(&self) -> usize {
+        self.plans
+    }
+
+    pub fn get_capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn get_hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn get_misses(&self) -> u64 {
+        self.misses
+    }
+
+    pub fn get_evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    /// Times the cache was emptied because the rules changed.
+    pub fn get_invalidations(&self) -> u64 {
+        self.invalidations
+    }
+}
+
+pub struct RouteCache {
+    capacity: usize,
+    state: Mutex<RouteCacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+impl RouteCache {
+    pub fn new(config: &RouteCacheConfig) -> Self {
+        RouteCache {
+            capacity: config.get_capacity(),
+            state: Mutex::new(RouteCacheState {
+                rules_version: "".to_string(),
+                shard_keys: Arc::new(HashSet::new()),
+                tick: 0,
+                plans: HashMap::new(),
+                recency: BTreeMap::new(),
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            invalidations: AtomicU64::new(0),
+        }
+    }
+
+    /// The shard keys of `rules`, dropping every plan first if they belong to older rules.
+    fn shard_keys(&self, rules: &RulesVersion) -> Arc<HashSet<String>> {
+        let mut state = self.state.lock().unwrap();
+        if state.rules_version != rules.get_version() {
+            if !state.rules_version.is_empty() {
+                self.invalidations.fetch_add(1, Ordering::Relaxed);
+            }
+            state.rules_version = rules.get_version();
+            state.shard_keys = Arc::new(rules.shard_keys());
+            state.plans.clear();
+            state.recency.clear();
+        }
+        state.shard_keys.clone()
+    }
+
+    /// The plan of `statement` under `rules`, built on a miss.
+    pub fn plan(&self, rules: &RulesVersion, sql: &str, statement: &Statement, hints: &SQLHints) -> Option<Arc<RoutePlan>> {
+        let hint_bindings = hint_bindings(hints, &self.shard_keys(rules));
+        let key = statement_plan_key(sql, statement, rules)?.hinted(&hint_bindings);
+        if let Some(plan) = self.state.lock().unwrap().get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Some(plan);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let plan = Arc::new(RoutePlan::build(rules, statement, &hint_bindings));
+        let mut state = self.state.lock().unwrap();
+        // The rules may have changed while the plan was built, it is not kept then.
+        if state.rules_version == plan.get_rules_version() && state.insert(key, plan.clone(), self.capacity) {
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        Some(plan)
+    }
+
+    pub fn stats(&self) -> RouteCacheStats {
+        let state = self.state.lock().unwrap();
+        RouteCacheStats {
+            rules_version: state.rules_version.clone(),
+            plans: state.plans.len(),
+            capacity: self.capacity,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            invalidations: self.invalidations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+lazy_static! {
+    static ref ROUTE_CACHE: RwLock<Option<Arc<RouteCache>>> = RwLock::new(None);
+}
+
+/// Installs the cache described by `config`, or removes it when disabled.
+pub fn configure_route_cache(config: &RouteCacheConfig) {
+    let cache = if config.is_enabled() {
+        Some(Arc::new(RouteCache::new(config)))
+    } else {
+        None
+    };
+    *ROUTE_CACHE.write().unwrap() = cache;
+}
+
+pub fn route_cache() -> Option<Arc<RouteCache>> {
+    ROUTE_CACHE.read().unwrap().clone()
+}
+
+/// The route plan of `statement`, none while no rules are loaded. Without a cache the plan is
+/// built every time.
+pub fn route_plan(sql: &str, statement: &Statement, hints: &SQLHints) -> Option<Arc<RoutePlan>> {
+    let rules = current_rules()?;
+    match route_cache() {
+        Some(cache) => cache.plan(&rules, sql, statement, hints),
+        None => {
+            let hint_bindings = hint_bindings(hints, &rules.shard_keys());
+            Some(Arc::new(RoutePlan::build(&rules, statement, &hint_bindings)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::sync::Arc;
+
+    use data_panel_common::config::config::RouteCacheConfig;
+
+    use crate::discovery::database::{Cluster, DisAlgorithm, DisRules, DisTable, DisType, Segment};
+    use crate::discovery::database::rules::{RulesVersion, TableRoute};
+    use crate::handler::database::parser::sql::hint::SQLHints;
+    use crate::handler::database::parser::sql::mysql::parser;
+    use crate::handler::database::route_cache::{JoinRoute, plan_key, RouteCache, RouteCacheState, RoutePlan, statement_bindings, statement_plan_key};
+
+    fn order_rules() -> RulesVersion {
+        let url = "jdbc:mysql://localhost:3306/martlet";
+        let cluster = Cluster::builder("martlet")
+            .meta_segment(Segment::new(0, url, "root", "root"), vec![])
+            .data_segment(100, Segment::new(0, url, "root", "root"), vec![])
+            .data_segment(200, Segment::new(0, url, "root", "root"), vec![])
+            .dis_rules(DisRules::builder()
+                .distributed_table("t_order", DisTable::new(vec!["user_id"], DisAlgorithm::new(DisType::HASH, ""), vec![]))
+                .distributed_table("t_user", DisTable::new(vec!["id"], DisAlgorithm::new(DisType::HASH, ""), vec![]))
+                .build())
+            .build()
+            .unwrap();
+        RulesVersion::new("v1".to_string(), cluster)
+    }
+
+    #[test]
+    fn test_plan_key() {
+        let rules = order_rules();
+        let key = |sql: &str| statement_plan_key(sql, &parser(sql.to_string()).unwrap().pop().unwrap(), &rules).unwrap();
+        let bound = key("SELECT * FROM t_order o WHERE o.user_id = 10 AND status = 'PAID' ORDER BY id");
+        assert_eq!(plan_key("SELECT * FROM t_order o WHERE o.user_id = 10 AND status = 'PAID' ORDER BY id").unwrap().get_fingerprint(),
+                   "SELECT * FROM t_order o WHERE o.user_id = ? AND status = ? ORDER BY id");
+        assert_eq!(bound.get_bindings(), &[("t_order".to_string(), "user_id".to_string(), "10".to_string())]);
+
+        let other = key("SELECT * FROM t_order o WHERE o.user_id = 11 AND status = 'PAID' ORDER BY id");
+        assert_eq!(bound.get_fingerprint(), other.get_fingerprint());
+        assert_ne!(bound, other);
+
+        assert!(key("SELECT * FROM t_order WHERE user_id = 10 OR status = 'PAID'").get_bindings().is_empty());
+        assert!(key("UPDATE t_order SET user_id = 10 WHERE id = 1").get_bindings().is_empty());
+        assert_eq!(key("DELETE FROM t_order WHERE (user_id = 10) AND id = 1").get_bindings().len(), 1);
+    }
+
+    #[test]
+    fn test_statement_bindings() {
+        let rules = order_rules();
+        let segments = |sql: &str| RoutePlan::build(&rules, &parser(sql.to_string()).unwrap().pop().unwrap(), &[]).data_segments();
+        let segment_of_user = match rules.route("t_order", &[("user_id".to_string(), "1".to_string())]) {
+            TableRoute::Distributed(segments) => segments,
+            _ => unreachable!(),
+        };
+        assert_eq!(segments("SELECT * FROM t_order WHERE user_id = 1 AND status = 'PAID'"), segment_of_user);
+        assert_eq!(segments("SELECT * FROM t_order o WHERE 1 = o.user_id"), segment_of_user);
+        assert_eq!(segments("UPDATE t_order SET status = 'PAID' WHERE t_order.user_id = 1"), segment_of_user);
+
+        // Equalities that don't restrict every row the statement touches fan out to every segment.
+        for sql in [
+            "SELECT * FROM t_order WHERE (user_id = 1) = 0",
+            "SELECT * FROM t_order WHERE IF(user_id = 1, 0, 1) = 1",
+            "SELECT * FROM t_order WHERE CASE WHEN user_id = 1 THEN 0 ELSE 1 END = 1",
+            "SELECT * FROM t_order WHERE NOT (user_id = 1)",
+            "SELECT * FROM t_order WHERE user_id = 1 OR status = 'PAID'",
+            "UPDATE t_order SET status = 'PAID' WHERE (user_id = 1) = 0",
+            "DELETE FROM t_order WHERE IF(user_id = 1, 0, 1) = 1",
+        ].iter() {
+            assert_eq!(segments(sql), vec![100, 200], "{}", sql);
+        }
+
+        // The shard key of another table, or an unqualified column of a join, binds nothing.
+        let other_table = "SELECT * FROM t_user o JOIN t_order x ON o.id = x.buyer_id WHERE o.user_id = 1";
+        let statement = parser(other_table.to_string()).unwrap().pop().unwrap();
+        assert!(statement_bindings(&rules, &statement).is_empty());
+        let plan = RoutePlan::build(&rules, &statement, &[]);
+        assert_eq!(plan.get_tables().iter().find(|(table, _)| table == "t_order").unwrap().1, TableRoute::Distributed(vec![100, 200]));
+        let unqualified = "SELECT * FROM t_user u JOIN t_order o ON u.id = o.buyer_id WHERE user_id = 1";
+        assert!(statement_bindings(&rules, &parser(unqualified.to_string()).unwrap().pop().unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let mut state = RouteCacheState {
+            rules_version: "v1".to_string(),
+            shard_keys: Arc::new(HashSet::new()),
+            tick: 0,
+            plans: HashMap::new(),
+            recency: BTreeMap::new(),
+        };
+        let keys: Vec<_> = ["SELECT 1", "SELECT a", "SELECT b"].iter().map(|sql| plan_key(sql).unwrap()).collect();
+        let plan = Arc::new(RoutePlan { rules_version: "v1".to_string(), tables: vec![], join: JoinRoute::Broadcast });
+        assert!(!state.insert(keys[0].clone(), plan.clone(), 2));
+        assert!(!state.insert(keys[1].clone(), plan.clone(), 2));
+        assert!(state.get(&keys[0]).is_some());
+        assert!(state.insert(keys[2].clone(), plan, 2));
+        assert!(state.get(&keys[0]).is_some());
+        assert!(state.get(&keys[1]).is_none());
+        assert!(state.get(&keys[2]).is_some());
+    }
+
+    #[test]
+    fn test_shard_hint() {
+        let url = "jdbc:mysql://localhost:3306/martlet";
+        let cluster = Cluster::builder("martlet")
+            .meta_segment(Segment::new(0, url, "root", "root"), vec![])
+            .data_segment(100, Segment::new(0, url, "root", "root"), vec![])
+            .data_segment(200, Segment::new(0, url, "root", "root"), vec![])
+            .dis_rules(DisRules::builder()
+                .distributed_table("t_order", DisTable::new(vec!["user_id"], DisAlgorithm::new(DisType::HASH, ""), vec![]))
+                .build())
+            .build()
+            .unwrap();
+        let rules = RulesVersion::new("v1".to_string(), cluster);
+        let cache = RouteCache::new(&RouteCacheConfig::new(16));
+        let plan = |sql: &str| {
+            let statement = parser(sql.to_string()).unwrap().pop().unwrap();
+            cache.plan(&rules, sql, &statement, &SQLHints::parse(sql)).unwrap()
+        };
+        let segment_of_user = match rules.route("t_order", &[("user_id".to_string(), "10".to_string())]) {
+            TableRoute::Distributed(segments) => segments,
+            _ => unreachable!(),
+        };
+
+        // A shard key bound by the hint routes like one bound by the WHERE clause.
+        assert_eq!(plan("SELECT /*+ SHARD(user_id=10) */ * FROM t_order WHERE status = 1").data_segments(), segment_of_user);
+        assert_eq!(plan("SELECT * FROM t_order WHERE user_id = 10 AND status = 1").data_segments(), segment_of_user);
+        // Other hints and other keys leave the statement scattered, and are planned apart.
+        assert_eq!(plan("SELECT /*+ SHARD(region=eu) */ * FROM t_order WHERE status = 1").data_segments(), vec![100, 200]);
+        assert_eq!(plan("SELECT * FROM t_order WHERE status = 1").data_segments(), vec![100, 200]);
+        let stats = cache.stats();
+        assert_eq!((stats.get_hits(), stats.get_misses()), (1, 3));
+    }
+
+    #[test]
+    fn test_join_routes() {
+        let url = "jdbc:mysql://localhost:3306/martlet";
+        let cluster = Cluster::builder("martlet")
+            .meta_segment(Segment::new(0, url, "root", "root"), vec![])
+            .data_segment(100, Segment::new(0, url, "root", "root"), vec![])
+            .data_segment(200, Segment::new(0, url, "root", "root"), vec![])
+            .dis_rules(DisRules::builder()
+                .distributed_table("t_order", DisTable::new(vec!["user_id"], DisAlgorithm::new(DisType::HASH, ""), vec!["t_order_item"]))
+                .distributed_table("t_order_item", DisTable::new(vec!["buyer_id"], DisAlgorithm::new(DisType::HASH, ""), vec![]))
+                .distributed_table("t_user", DisTable::new(vec!["id"], DisAlgorithm::new(DisType::HASH, ""), vec![]))
+                .replicated_table("t_dept")
+                .replicated_table("t_area")
+                .build())
+            .build()
+            .unwrap();
+        let rules = RulesVersion::new("v1".to_string(), cluster);
+        let plan = |sql: &str| {
+            let statement = parser(sql.to_string()).unwrap().pop().unwrap();
+            RoutePlan::build(&rules, &statement, &[])
+        };
+        let segment_of_user = match rules.route("t_order", &[("user_id".to_string(), "10".to_string())]) {
+            TableRoute::Distributed(segments) => segments,
+            _ => unreachable!(),
+        };
+
+        // A distributed table with a replicated one: on the segment of the distributed table.
+        let broadcast = plan("SELECT * FROM t_order o JOIN t_dept d ON o.dept_id = d.id WHERE o.user_id = 10");
+        assert_eq!(broadcast.get_join(), JoinRoute::Broadcast);
+        assert_eq!(broadcast.data_segments(), segment_of_user);
+        assert!(broadcast.check_join().is_ok());
+
+        // Binding tables: the bound one narrows its relative.
+        let binding = plan("SELECT * FROM t_order o JOIN t_order_item i ON o.user_id = i.buyer_id WHERE o.user_id = 10");
+        assert_eq!(binding.get_join(), JoinRoute::Binding);
+        assert_eq!(binding.data_segments(), segment_of_user);
+        assert!(binding.check_join().is_ok());
+        let unbound = plan("SELECT * FROM t_order o JOIN t_order_item i ON o.user_id = i.buyer_id");
+        assert_eq!(unbound.data_segments(), vec![100, 200]);
+        assert!(unbound.check_join().is_ok());
+        let in_where = plan("SELECT * FROM t_order, t_order_item WHERE t_order.user_id = t_order_item.buyer_id AND t_order.status = 1");
+        assert_eq!(in_where.get_join(), JoinRoute::Binding);
+
+        // Binding tables joined on other columns, or on their shard keys only in one branch of
+        // an OR: the rows to join may be on different segments.
+        let mismatched = plan("SELECT * FROM t_order o JOIN t_order_item i ON o.id = i.order_id");
+        assert_eq!(mismatched.get_join(), JoinRoute::CrossSegment);
+        assert!(mismatched.check_join().is_err());
+        let either = plan("SELECT * FROM t_order o JOIN t_order_item i ON o.user_id = i.buyer_id OR o.id = i.order_id");
+        assert!(either.check_join().is_err());
+        let bound = plan("SELECT * FROM t_order o JOIN t_order_item i ON o.id = i.order_id WHERE o.user_id = 10 AND i.buyer_id = 10");
+        assert_eq!(bound.get_join(), JoinRoute::CrossSegment);
+        assert!(bound.check_join().is_ok());
+
+        // Unrelated distributed tables: refused over several segments.
+        let cross = plan("SELECT * FROM t_order o JOIN t_user u ON o.user_id = u.id WHERE o.user_id = 10");
+        assert_eq!(cross.get_join(), JoinRoute::CrossSegment);
+        assert!(cross.check_join().is_err());
+        let segment_of_id = |id: u32| match rules.route("t_user", &[("id".to_string(), id.to_string())]) {
+            TableRoute::Distributed(segments) => segments,
+            _ => unreachable!(),
+        };
+        let id = (1..100).find(|id| segment_of_id(*id) == segment_of_user).unwrap();
+        let colocated = plan(&format!("SELECT * FROM t_order o JOIN t_user u ON o.user_id = u.id WHERE o.user_id = 10 AND u.id = {}", id));
+        assert_eq!(colocated.get_join(), JoinRoute::CrossSegment);
+        assert!(colocated.check_join().is_ok());
+
+        // A distributed table scattered with a replicated one.
+        let scattered = plan("SELECT * FROM t_order o JOIN t_dept d ON o.dept_id = d.id");
+        assert_eq!(scattered.get_join(), JoinRoute::Broadcast);
+        assert_eq!(scattered.data_segments(), vec![100, 200]);
+        assert!(scattered.check_join().is_ok());
+
+        // A single table, or replicated ones only.
+        assert_eq!(plan("SELECT * FROM t_order").get_join(), JoinRoute::Broadcast);
+        let replicated = plan("SELECT * FROM t_dept d JOIN t_area a ON d.area_id = a.id");
+        assert_eq!(replicated.get_join(), JoinRoute::Broadcast);
+        assert!(replicated.data_segments().is_empty());
+        assert!(replicated.check_join().is_ok());
+    }
+}
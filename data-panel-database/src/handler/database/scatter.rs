@@ -0,0 +1,469 @@
+//! Reads over more than one data segment.
+//!
+//! A query whose distributed tables span several data segments, see
+//! `RoutePlan::data_segments`, is sent to every one of them, its tables renamed to their actual
+//! tables on the segment and its parameters bound, and the sub-queries run concurrently, see
+//! `fanout`. The results of the segments are merged into the one result set answered, per the
+//! ORDER BY, grouping and LIMIT of the query, see `merge`. LIMIT n OFFSET m is pushed down to
+//! the segments as LIMIT n+m, see `MergePlan::segment_query`, the merge skips the first m rows.
+//! Reads opted into partial results, see `fanout::partial_results_allowed`, are answered
+//! without a failed minority of the segments, with a warning telling the missing ones.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use mysql::{Column, Value};
+use sqlparser::ast::{Query, Statement};
+use tokio::time::Instant;
+
+use data_panel_common::common::Error;
+use data_panel_common::config::config::{FanoutConfig, MergeConfig, MeshConfig};
+
+use crate::discovery;
+use crate::discovery::database::rules::{current_rules, RulesVersion};
+use crate::handler::database::fanout::{CancelToken, FanoutBackend, MySQLFanoutBackend, partial_results_allowed, scatter_gather, scatter_gather_partial, SegmentResult, SubQuery};
+use crate::handler::database::merge::{self, MergeError, MergePlan, MergeRow};
+use crate::handler::database::mysql::{binary, PayloadSink, rdbc};
+use crate::handler::database::mysql::rdbc::err_payload;
+use crate::handler::database::parser::sql::hint::SQLHints;
+use crate::handler::database::parser::sql::mysql::bind_placeholders;
+use crate::handler::database::route_cache::RoutePlan;
+use crate::handler::database::sharded_insert::sql_literals;
+use crate::handler::database::xa;
+use crate::session::mysql::SessionContext;
+
+/// How long a fan-out may run without a statement timeout.
+const UNBOUNDED_DEADLINE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The answer of a fanned out read: the columns of the segments and the merged rows, with the
+/// warning of a partial result.
+pub struct MergedRead {
+    columns: Vec<Column>,
+    rows: Box<dyn Iterator<Item = MergeRow>>,
+    warning: Option<String>,
+}
+
+fn data_segment(segment: u32) -> String {
+    format!("data-{}/primary", segment)
+}
+
+/// `query` as sent to every segment, see `MergePlan::segment_query`. A plan that needs the
+/// result columns, as DISTINCT * does, is only known once the segments answered: they are
+/// then sent the query without LIMIT and OFFSET, which the merge applies. A HAVING the merge
+/// can't apply is refused before any segment runs the query.
+fn segment_query(query: &Query, rules: &RulesVersion) -> Result<Query, MergeError> {
+    match MergePlan::from_query(query, &[]) {
+        Ok(plan) => {
+            let plan = plan.group_on_segments(query, rules);
+            plan.check_having()?;
+            Ok(plan.segment_query(query))
+        }
+        Err(_) => Ok(MergePlan::new().segment_query(query)),
+    }
+}
+
+/// The sub-queries of `query` on every data segment of `route_plan`, `params` bound.
+fn sub_queries(query: &Query, params: &[Value], route_plan: &RoutePlan, rules: &RulesVersion,
+               rewrite_ctx: &HashMap<String, String>) -> Result<Vec<SubQuery>, Error> {
+    let literals = sql_literals(params);
+    let statement = Statement::Query(Box::new(segment_query(query, rules)?));
+    route_plan.data_segments().into_iter()
+        .map(|segment| {
+            let sql = route_plan.segment_sql(&statement, segment, rules, rewrite_ctx)
+                .ok_or_else(|| Error::Parse(format!("the query can't be rewritten for data segment {}", segment)))?;
+            let sql = bind_placeholders(&sql, &literals)
+                .ok_or_else(|| Error::Protocol(format!("the parameters of the query to data segment {} can't be bound", segment)))?;
+            Ok(SubQuery::new(&data_segment(segment), &sql))
+        })
+        .collect()
+}
+
+/// Runs `query` on every data segment of `route_plan` through `backend`, and merges their
+/// results. Without the segments that failed when `partial` and they are a minority.
+pub async fn read_with<B>(backend: Arc<B>, query: &Query, params: &[Value], route_plan: &RoutePlan, rules: &RulesVersion,
+                          rewrite_ctx: &HashMap<String, String>, partial: bool, deadline: Instant, cancel: CancelToken,
+                          fanout_config: &FanoutConfig, merge_config: &MergeConfig) -> Result<MergedRead, Error>
+    where B: FanoutBackend<Output = SegmentResult> {
+    let queries = sub_queries(query, params, route_plan, rules, rewrite_ctx)?;
+    let (outputs, warning) = if partial {
+        let result = scatter_gather_partial(backend, queries, deadline, cancel, fanout_config).await?;
+        let warning = result.warning();
+        (result.into_outputs(), warning)
+    } else {
+        (scatter_gather(backend, queries, deadline, cancel, fanout_config).await?, None)
+    };
+    let columns = outputs.first().map(|output| output.get_columns().to_vec()).unwrap_or_default();
+    let names: Vec<String> = columns.iter().map(|column| column.name_str().to_string()).collect();
+    let plan = MergePlan::from_query(query, &names)?.group_on_segments(query, rules);
+    let sources = outputs.into_iter().map(|output| output.into_rows().into_iter()).collect();
+    Ok(MergedRead {
+        columns,
+        rows: merge::merge(&plan, sources, merge_config)?,
+        warning,
+    })
+}
+
+/// The backend of the data segments of `route_plan`.
+fn segment_backend(route_plan: &RoutePlan, binary: bool) -> Result<MySQLFanoutBackend, Error> {
+    let mut urls = HashMap::new();
+    for segment in route_plan.data_segments() {
+        let segment = data_segment(segment);
+        let database_url = discovery::database::segment_url(&segment).ok_or_else(|| xa::no_such_segment(&segment))?;
+        urls.insert(segment, database_url);
+    }
+    Ok(MySQLFanoutBackend::new(urls).binary(binary))
+}
+
+/// When the fan-out is aborted: past the TIMEOUT hint of the statement, or else the statement
+/// timeout when enabled.
+fn deadline(hints: &SQLHints) -> Instant {
+    let config = MeshConfig::get_statement_timeout_config();
+    let timeout = match hints.get_timeout() {
+        Some(timeout) => Duration::from_millis(timeout),
+        None if config.is_enabled() => Duration::from_millis(config.get_timeout() as u64),
+        None => UNBOUNDED_DEADLINE,
+    };
+    Instant::now() + timeout
+}
+
+/// Answers a query over more than one data segment, in the binary protocol when `binary`.
+/// None for any other statement. The warning of a partial result is kept for SHOW WARNINGS.
+pub fn intercept(statement: &Statement, sql: &str, params: &[Value], hints: &SQLHints, route_plan: Option<&RoutePlan>,
+                 rewrite_ctx: &HashMap<String, String>, binary: bool, session_ctx: &mut SessionContext,
+                 sink: &mut dyn PayloadSink) -> Option<Vec<Bytes>> {
+    let query = match statement {
+        Statement::Query(query) => query,
+        _ => return None,
+    };
+    let route_plan = route_plan.filter(|route_plan| route_plan.data_segments().len() > 1)?;
+    let rules = current_rules()?;
+    let backend = match segment_backend(route_plan, binary) {
+        Ok(backend) => Arc::new(backend),
+        Err(e) => return Some(vec![err_payload(e)]),
+    };
+    let handle = match tokio::runtime::Handle::try_current() {
+        Ok(handle) => handle,
+        Err(e) => return Some(vec![err_payload(Error::Protocol(e.to_string()))]),
+    };
+    let partial = partial_results_allowed(sql, statement, &MeshConfig::get_partial_results_config());
+    let read = read_with(backend, query, params, route_plan, &rules, rewrite_ctx, partial, deadline(hints), CancelToken::new(),
+                         &MeshConfig::get_fanout_config(), &MeshConfig::get_merge_config());
+    let read = match tokio::task::block_in_place(|| handle.block_on(read)) {
+        Ok(read) => read,
+        Err(e) => return Some(vec![err_payload(e)]),
+    };
+    if let Some(warning) = read.warning {
+        session_ctx.push_warning(warning);
+    }
+    let warnings = session_ctx.get_warnings().len() as u16;
+    let status_flags = session_ctx.get_status_flags();
+    Some(if binary {
+        binary::merged_result(&read.columns, read.rows, warnings, status_flags, sink)
+    } else {
+        rdbc::merged_result(&read.columns, read.rows, warnings, status_flags, sink)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use mysql::{Column, Value};
+    use mysql::consts::ColumnType;
+    use sqlparser::ast::{Query, Statement};
+    use tokio::time::Instant;
+
+    use data_panel_common::common::Error;
+    use data_panel_common::config::config::{FanoutConfig, MergeConfig};
+
+    use crate::discovery::database::{Cluster, DisAlgorithm, DisRules, DisTable, DisType, Segment};
+    use crate::discovery::database::rules::RulesVersion;
+    use crate::handler::database::fanout::{CancelToken, FanoutBackend, SegmentResult};
+    use crate::handler::database::merge::{MergeRow, pagination_stats};
+    use crate::handler::database::parser::sql::mysql::parser;
+    use crate::handler::database::route_cache::RoutePlan;
+
+    use super::{MergedRead, read_with};
+
+    /// Answers every segment with its rows of `columns`, and keeps the sub-queries it was sent.
+    /// `down` segments refuse connections, sub-queries on `slow` ones block until killed.
+    #[derive(Default)]
+    struct SegmentsBackend {
+        columns: Vec<String>,
+        rows: HashMap<String, Vec<MergeRow>>,
+        down: HashSet<String>,
+        slow: HashSet<String>,
+        sent: Mutex<Vec<(String, String)>>,
+        next_id: AtomicU64,
+        running: AtomicUsize,
+        max_running: AtomicUsize,
+        killed: Mutex<HashSet<u64>>,
+        kill_signal: Condvar,
+    }
+
+    impl SegmentsBackend {
+        fn new(columns: &[&str], rows: Vec<(&str, Vec<MergeRow>)>) -> Self {
+            SegmentsBackend {
+                columns: columns.iter().map(|column| column.to_string()).collect(),
+                rows: rows.into_iter().map(|(segment, rows)| (segment.to_string(), rows)).collect(),
+                ..Default::default()
+            }
+        }
+
+        fn down(mut self, segment: &str) -> Self {
+            self.down.insert(segment.to_string());
+            self
+        }
+
+        fn slow(mut self, segment: &str) -> Self {
+            self.slow.insert(segment.to_string());
+            self
+        }
+
+        fn sent(&self) -> Vec<(String, String)> {
+            let mut sent = self.sent.lock().unwrap().clone();
+            sent.sort();
+            sent
+        }
+    }
+
+    impl FanoutBackend for SegmentsBackend {
+        type Conn = (String, u64);
+        type Output = SegmentResult;
+
+        fn connect(&self, segment: &str) -> Result<Self::Conn, String> {
+            if self.down.contains(segment) {
+                return Err("Connection refused".to_string());
+            }
+            Ok((segment.to_string(), self.next_id.fetch_add(1, Ordering::SeqCst)))
+        }
+
+        fn connection_id(&self, conn: &Self::Conn) -> u64 {
+            conn.1
+        }
+
+        fn query(&self, conn: &mut Self::Conn, sql: &str) -> Result<SegmentResult, String> {
+            self.sent.lock().unwrap().push((conn.0.clone(), sql.to_string()));
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(10));
+            if self.slow.contains(&conn.0) {
+                let mut killed = self.killed.lock().unwrap();
+                while !killed.contains(&conn.1) {
+                    killed = self.kill_signal.wait(killed).unwrap();
+                }
+                self.running.fetch_sub(1, Ordering::SeqCst);
+                return Err("Query execution was interrupted".to_string());
+            }
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            let columns = self.columns.iter()
+                .map(|name| Column::new(ColumnType::MYSQL_TYPE_VAR_STRING).with_name(name.as_bytes()))
+                .collect();
+            Ok(SegmentResult::new(columns, self.rows.get(&conn.0).cloned().unwrap_or_default()))
+        }
+
+        fn kill(&self, _segment: &str, connection_id: u64) {
+            self.killed.lock().unwrap().insert(connection_id);
+            self.kill_signal.notify_all();
+        }
+    }
+
+    fn row(values: &[&str]) -> MergeRow {
+        values.iter().map(|value| Value::Bytes(value.as_bytes().to_vec())).collect()
+    }
+
+    fn rules() -> RulesVersion {
+        rules_of(&[100, 200])
+    }
+
+    fn rules_of(data_segments: &[u32]) -> RulesVersion {
+        let url = "jdbc:mysql://localhost:3306/martlet";
+        let mut cluster = Cluster::builder("martlet")
+            .meta_segment(Segment::new(0, url, "root", "root"), vec![]);
+        for segment in data_segments {
+            cluster = cluster.data_segment(*segment, Segment::new(0, url, "root", "root"), vec![]);
+        }
+        let cluster = cluster
+            .dis_rules(DisRules::builder()
+                .distributed_table("t_order", DisTable::new(vec!["user_id"], DisAlgorithm::new(DisType::HASH, ""), vec![])
+                    .actual_table("t_order_{segment}"))
+                .build())
+            .build()
+            .unwrap();
+        RulesVersion::new("v1".to_string(), cluster)
+    }
+
+    fn query(rules: &RulesVersion, sql: &str) -> (Query, RoutePlan) {
+        let statement = parser(sql.to_string()).unwrap().pop().unwrap();
+        let route_plan = RoutePlan::build(rules, &statement, &[]);
+        match statement {
+            Statement::Query(query) => (*query, route_plan),
+            _ => unreachable!(),
+        }
+    }
+
+    async fn read_over(backend: &Arc<SegmentsBackend>, rules: &RulesVersion, sql: &str, partial: bool,
+                       fanout_config: &FanoutConfig) -> Result<MergedRead, Error> {
+        let (query, route_plan) = query(rules, sql);
+        let deadline = Instant::now() + Duration::from_secs(60);
+        read_with(backend.clone(), &query, &[], &route_plan, rules, &HashMap::new(), partial, deadline, CancelToken::new(),
+                  fanout_config, &MergeConfig::new(16, 0, "")).await
+    }
+
+    async fn read(backend: &Arc<SegmentsBackend>, sql: &str, params: &[Value]) -> MergedRead {
+        let rules = rules();
+        let (query, route_plan) = query(&rules, sql);
+        let deadline = Instant::now() + Duration::from_secs(60);
+        read_with(backend.clone(), &query, params, &route_plan, &rules, &HashMap::new(), false, deadline, CancelToken::new(),
+                  &FanoutConfig::default(), &MergeConfig::new(16, 0, "")).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_read_over_segments() {
+        let backend = Arc::new(SegmentsBackend::new(&["id", "status"], vec![
+            ("data-100/primary", vec![row(&["1", "PAID"])]),
+            ("data-200/primary", vec![row(&["2", "SENT"]), row(&["4", "PAID"])]),
+        ]));
+        let read = read(&backend, "SELECT id, status FROM t_order WHERE status = ?", &[Value::Bytes(b"PAID".to_vec())]).await;
+        assert_eq!(backend.sent(), vec![
+            ("data-100/primary".to_string(), "SELECT id, status FROM t_order_100 WHERE status = 'PAID'".to_string()),
+            ("data-200/primary".to_string(), "SELECT id, status FROM t_order_200 WHERE status = 'PAID'".to_string()),
+        ]);
+        assert_eq!(read.columns.iter().map(|c| c.name_str().to_string()).collect::<Vec<String>>(), vec!["id", "status"]);
+        assert_eq!(read.rows.count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_merged_read() {
+        let backend = Arc::new(SegmentsBackend::new(&["id", "status"], vec![
+            ("data-100/primary", vec![row(&["9", "PAID"]), row(&["5", "SENT"]), row(&["1", "PAID"])]),
+            ("data-200/primary", vec![row(&["8", "SENT"]), row(&["2", "PAID"])]),
+        ]));
+        let read = read(&backend, "SELECT id, status FROM t_order ORDER BY id DESC LIMIT 2 OFFSET 1", &[]).await;
+        assert_eq!(backend.sent()[0].1, "SELECT id, status FROM t_order_100 ORDER BY id DESC LIMIT 3");
+        assert_eq!(read.rows.collect::<Vec<MergeRow>>(), vec![row(&["8", "SENT"]), row(&["5", "SENT"])]);
+
+        let backend = Arc::new(SegmentsBackend::new(&["status", "orders"], vec![
+            ("data-100/primary", vec![row(&["PAID", "2"]), row(&["SENT", "1"])]),
+            ("data-200/primary", vec![row(&["PAID", "3"])]),
+        ]));
+        let read = read(&backend, "SELECT status, COUNT(*) AS orders FROM t_order GROUP BY status ORDER BY orders DESC", &[]).await;
+        assert_eq!(read.rows.collect::<Vec<MergeRow>>(), vec![
+            vec![Value::Bytes(b"PAID".to_vec()), Value::Int(5)],
+            row(&["SENT", "1"]),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_paginated_read() {
+        let backend = Arc::new(SegmentsBackend::new(&["id"], vec![
+            ("data-100/primary", vec![row(&["1"]), row(&["3"]), row(&["5"])]),
+            ("data-200/primary", vec![row(&["2"]), row(&["4"]), row(&["6"])]),
+        ]));
+        let before = pagination_stats();
+        let read = read(&backend, "SELECT id FROM t_order ORDER BY id LIMIT 2 OFFSET 2", &[]).await;
+        assert_eq!(read.rows.collect::<Vec<MergeRow>>(), vec![row(&["3"]), row(&["4"])]);
+        assert_eq!(backend.sent(), vec![
+            ("data-100/primary".to_string(), "SELECT id FROM t_order_100 ORDER BY id LIMIT 4".to_string()),
+            ("data-200/primary".to_string(), "SELECT id FROM t_order_200 ORDER BY id LIMIT 4".to_string()),
+        ]);
+        let after = pagination_stats();
+        assert!(after.get_merges() > before.get_merges());
+        assert!(after.get_segment_rows() >= before.get_segment_rows() + 4);
+        assert!(after.get_returned_rows() >= before.get_returned_rows() + 2);
+
+        // Grouped across segments: every group is folded before the page is known.
+        let backend = Arc::new(SegmentsBackend::new(&["status"], vec![]));
+        read(&backend, "SELECT status, COUNT(*) FROM t_order GROUP BY status LIMIT 10 OFFSET 20", &[]).await;
+        assert_eq!(backend.sent()[0].1, "SELECT status, COUNT(*) FROM t_order_100 GROUP BY status");
+    }
+
+    #[tokio::test]
+    async fn test_distinct_read() {
+        // DISTINCT on other columns than the shard key: every segment returns its distinct rows.
+        let backend = Arc::new(SegmentsBackend::new(&["status"], vec![
+            ("data-100/primary", vec![row(&["PAID"]), row(&["SENT"])]),
+            ("data-200/primary", vec![row(&["PAID"])]),
+        ]));
+        let read = read(&backend, "SELECT DISTINCT status FROM t_order LIMIT 10", &[]).await;
+        assert_eq!(backend.sent()[0].1, "SELECT DISTINCT status FROM t_order_100");
+        assert_eq!(read.rows.collect::<Vec<MergeRow>>(), vec![row(&["PAID"]), row(&["SENT"])]);
+
+        // Grouped on the shard key: the groups of the segments are whole, and paginated as rows.
+        let backend = Arc::new(SegmentsBackend::new(&["user_id", "orders"], vec![
+            ("data-100/primary", vec![row(&["1", "9"]), row(&["3", "2"])]),
+            ("data-200/primary", vec![row(&["2", "5"]), row(&["4", "1"])]),
+        ]));
+        let read = read(&backend, "SELECT user_id, COUNT(*) AS orders FROM t_order GROUP BY user_id ORDER BY orders DESC LIMIT 2", &[]).await;
+        assert_eq!(backend.sent()[1].1, "SELECT user_id, COUNT(*) AS orders FROM t_order_200 GROUP BY user_id ORDER BY orders DESC LIMIT 2");
+        assert_eq!(read.rows.collect::<Vec<MergeRow>>(), vec![row(&["1", "9"]), row(&["2", "5"])]);
+    }
+
+    #[tokio::test]
+    async fn test_spilled_read() {
+        let backend = Arc::new(SegmentsBackend::new(&["status", "orders"], vec![
+            ("data-100/primary", vec![row(&["a", "1"]), row(&["b", "4"]), row(&["c", "2"])]),
+            ("data-200/primary", vec![row(&["c", "5"]), row(&["a", "2"])]),
+        ]));
+        let rules = rules();
+        let (query, route_plan) = query(&rules, "SELECT status, COUNT(*) AS orders FROM t_order GROUP BY status ORDER BY orders DESC");
+        let deadline = Instant::now() + Duration::from_secs(60);
+        // A single group held in memory: the others are spilled and folded again from disk.
+        let read = read_with(backend.clone(), &query, &[], &route_plan, &rules, &HashMap::new(), false, deadline, CancelToken::new(),
+                             &FanoutConfig::default(), &MergeConfig::new(1, 0, "")).await.unwrap();
+        assert_eq!(read.rows.collect::<Vec<MergeRow>>(), vec![
+            vec![Value::Bytes(b"c".to_vec()), Value::Int(7)],
+            row(&["b", "4"]),
+            vec![Value::Bytes(b"a".to_vec()), Value::Int(3)],
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_partial_read() {
+        let rules = rules_of(&[100, 200, 300]);
+        let backend = Arc::new(SegmentsBackend::new(&["id"], vec![
+            ("data-100/primary", vec![row(&["1"])]),
+            ("data-300/primary", vec![row(&["3"])]),
+        ]).down("data-200/primary"));
+        let read = read_over(&backend, &rules, "SELECT id FROM t_order ORDER BY id", true, &FanoutConfig::default()).await.unwrap();
+        assert_eq!(read.warning.unwrap(), "Partial result, 1 of 3 segments missing: data-200/primary");
+        assert_eq!(read.rows.collect::<Vec<MergeRow>>(), vec![row(&["1"]), row(&["3"])]);
+
+        // Not opted in, or no majority left: the read fails.
+        assert!(read_over(&backend, &rules, "SELECT id FROM t_order ORDER BY id", false, &FanoutConfig::default()).await.is_err());
+        let backend = Arc::new(SegmentsBackend::new(&["id"], vec![]).down("data-200/primary"));
+        assert!(read_over(&backend, &rules(), "SELECT id FROM t_order", true, &FanoutConfig::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bounded_read() {
+        let rules = rules_of(&[100, 200, 300]);
+        let backend = Arc::new(SegmentsBackend::new(&["id"], vec![
+            ("data-100/primary", vec![row(&["1"])]),
+            ("data-200/primary", vec![row(&["2"])]),
+            ("data-300/primary", vec![row(&["3"])]),
+        ]));
+        let read = read_over(&backend, &rules, "SELECT id FROM t_order ORDER BY id", false, &FanoutConfig::new(1, 0)).await.unwrap();
+        assert_eq!(read.rows.count(), 3);
+        assert_eq!(backend.max_running.load(Ordering::SeqCst), 1);
+
+        // The slow segment is killed past the branch timeout, and every failure is told.
+        let backend = Arc::new(SegmentsBackend::new(&["id"], vec![("data-100/primary", vec![row(&["1"])])])
+            .slow("data-200/primary")
+            .down("data-300/primary"));
+        let e = read_over(&backend, &rules, "SELECT id FROM t_order", false, &FanoutConfig::new(4, 50)).await.err().unwrap();
+        match e {
+            Error::Backend { code, message, .. } => {
+                assert_eq!(code, 1105);
+                assert_eq!(message, "2 of 3 segments failed: data-200/primary: no answer within 50 ms; data-300/primary: Connection refused");
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert_eq!(backend.killed.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,229 @@
+//! Weighted fair sharing of backend capacity across client applications.
+//!
+//! A statement needs a share before it runs against a backend, at most `capacity` shares are
+//! out at a time. Waiting statements are granted in start-time fair queueing order: each one is
+//! tagged with the virtual time it may start at, which advances by `1 / weight` for every
+//! statement of its application, so a busy application cannot crowd out the others however
+//! many statements it queues.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use mysql::MySqlError;
+
+use data_panel_common::config::config::SchedulerConfig;
+
+/// Virtual time a statement of an application with weight 1 takes.
+const STATEMENT_COST: u64 = 1_000_000;
+
+/// Orders the waiting statements: start tag, then arrival.
+type Ticket = (u64, u64);
+
+struct SchedulerState {
+    virtual_time: u64,
+    /// Start tag of the next statement of each application.
+    next_start: HashMap<String, u64>,
+    waiting: BTreeMap<Ticket, String>,
+    running: HashMap<String, usize>,
+    in_flight: usize,
+    arrivals: u64,
+}
+
+impl SchedulerState {
+    fn enqueue(&mut self, app: &str, weight: u32) -> Ticket {
+        let start = self.next_start.get(app).cloned().unwrap_or(0).max(self.virtual_time);
+        self.next_start.insert(app.to_string(), start + STATEMENT_COST / weight.max(1) as u64);
+        let ticket = (start, self.arrivals);
+        self.arrivals += 1;
+        self.waiting.insert(ticket, app.to_string());
+        ticket
+    }
+
+    fn grant(&mut self, ticket: &Ticket) {
+        if let Some(app) = self.waiting.remove(ticket) {
+            *self.running.entry(app).or_insert(0) += 1;
+            self.in_flight += 1;
+            self.virtual_time = self.virtual_time.max(ticket.0);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AppShareStats {
+    app: String,
+    weight: u32,
+    running: usize,
+    waiting: usize,
+}
+
+impl AppShareStats {
+    pub fn get_app(&self) -> String {
+        self.app.clone()
+    }
+
+    pub fn get_weight(&self) -> u32 {
+        self.weight
+    }
+
+    pub fn get_running(&self) -> usize {
+        self.running
+    }
+
+    pub fn get_waiting(&self) -> usize {
+        self.waiting
+    }
+}
+
+pub struct FairScheduler {
+    config: SchedulerConfig,
+    state: Mutex<SchedulerState>,
+    released: Condvar,
+}
+
+impl FairScheduler {
+    pub fn new(config: SchedulerConfig) -> Self {
+        FairScheduler {
+            config,
+            state: Mutex::new(SchedulerState {
+                virtual_time: 0,
+                next_start: HashMap::new(),
+                waiting: BTreeMap::new(),
+                running: HashMap::new(),
+                in_flight: 0,
+                arrivals: 0,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    fn timed_out(&self, app: &str) -> mysql::Error {
+        mysql::Error::MySqlError(MySqlError {
+            state: "08004".to_string(),
+            message: format!("no backend capacity for application {} within {} ms", app, self.config.get_queue_timeout()),
+            code: 1040,
+        })
+    }
+
+    /// Waits up to the queue timeout for a share of the backend capacity for `app`.
+    pub fn acquire(scheduler: &Arc<FairScheduler>, app: &str) -> mysql::Result<FairShare> {
+        let deadline = Instant::now() + Duration::from_millis(scheduler.config.get_queue_timeout() as u64);
+        let mut state = scheduler.state.lock().unwrap();
+        let ticket = state.enqueue(app, scheduler.config.weight(app));
+        loop {
+            let next = state.waiting.keys().next() == Some(&ticket);
+            if next && state.in_flight < scheduler.config.get_capacity() {
+                state.grant(&ticket);
+                // The statement queued behind may fit in as well.
+                scheduler.released.notify_all();
+                return Ok(FairShare {
+                    scheduler: scheduler.clone(),
+                    app: app.to_string(),
+                });
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                state.waiting.remove(&ticket);
+                scheduler.released.notify_all();
+                return Err(scheduler.timed_out(app));
+            }
+            state = scheduler.released.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    fn release(&self, app: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+        if let Some(running) = state.running.get_mut(app) {
+            *running -= 1;
+        }
+        self.released.notify_all();
+    }
+
+    pub fn stats(&self) -> Vec<AppShareStats> {
+        let state = self.state.lock().unwrap();
+        let mut stats: Vec<AppShareStats> = state.next_start.keys()
+            .map(|app| AppShareStats {
+                app: app.clone(),
+                weight: self.config.weight(app),
+                running: state.running.get(app).cloned().unwrap_or(0),
+                waiting: state.waiting.values().filter(|waiting| *waiting == app).count(),
+            })
+            .collect();
+        stats.sort_by(|a, b| a.app.cmp(&b.app));
+        stats
+    }
+}
+
+/// A share of the backend capacity, given back on drop.
+pub struct FairShare {
+    scheduler: Arc<FairScheduler>,
+    app: String,
+}
+
+impl Drop for FairShare {
+    fn drop(&mut self) {
+        self.scheduler.release(&self.app);
+    }
+}
+
+lazy_static! {
+    static ref FAIR_SCHEDULER: RwLock<Option<Arc<FairScheduler>>> = RwLock::new(None);
+}
+
+/// Installs the scheduler described by `config`, or removes it when disabled.
+pub fn configure_scheduler(config: &SchedulerConfig) {
+    let scheduler = if config.is_enabled() {
+        Some(Arc::new(FairScheduler::new(config.clone())))
+    } else {
+        None
+    };
+    *FAIR_SCHEDULER.write().unwrap() = scheduler;
+}
+
+pub fn fair_scheduler() -> Option<Arc<FairScheduler>> {
+    FAIR_SCHEDULER.read().unwrap().clone()
+}
+
+/// A share for a statement of `app`, none when no scheduler is installed.
+pub fn fair_share(app: &str) -> mysql::Result<Option<FairShare>> {
+    match fair_scheduler() {
+        Some(scheduler) => FairScheduler::acquire(&scheduler, app).map(Some),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use data_panel_common::config::config::{AppWeight, SchedulerConfig};
+
+    use crate::handler::database::scheduler::FairScheduler;
+
+    #[test]
+    fn test_weighted_fair_order() {
+        let config = SchedulerConfig::new(1, vec![AppWeight::new("web", 3)]);
+        let scheduler = Arc::new(FairScheduler::new(config));
+        let mut state = scheduler.state.lock().unwrap();
+        for _ in 0..8 {
+            state.enqueue("batch", 1);
+        }
+        for _ in 0..8 {
+            state.enqueue("web", 3);
+        }
+        let first: Vec<String> = state.waiting.values().take(8).cloned().collect();
+        assert_eq!(first.iter().filter(|app| *app == "web").count(), 6);
+        assert_eq!(first[0], "batch");
+    }
+
+    #[test]
+    fn test_capacity() {
+        let scheduler = Arc::new(FairScheduler::new(SchedulerConfig::new(1, vec![]).queue_timeout(10)));
+        let share = FairScheduler::acquire(&scheduler, "batch").unwrap();
+        assert!(FairScheduler::acquire(&scheduler, "web").is_err());
+        drop(share);
+        assert!(FairScheduler::acquire(&scheduler, "web").is_ok());
+        assert_eq!(scheduler.stats().iter().map(|stats| stats.get_running()).sum::<usize>(), 0);
+    }
+}
@@ -0,0 +1,549 @@
+//! Splitting of INSERTs into distributed tables.
+//!
+//! The VALUES rows are grouped by the data segment their shard keys hash to, and every segment
+//! gets an INSERT of its own rows into its actual table. A `?` in a row takes its value from
+//! the parameters bound to the statement; the sub-INSERT keeps the placeholder together with
+//! the parameters it refers to. The outcomes of the sub-INSERTs make up one OK packet, the
+//! affected rows summed and the last insert id the one generated for the earliest row, the
+//! one a single multi-row INSERT reports.
+//!
+//! When the table has a generated key the INSERT leaves out, or gives as NULL, the key
+//! generator fills it in for those rows before the rows are routed, and the first key is the
+//! last insert id, as for an AUTO_INCREMENT column. A key placeholder bound to NULL counts as
+//! NULL: the key replaces the placeholder, and its parameter is left out when binding.
+//!
+//! A write over more than one data segment runs in a distributed transaction when those are
+//! enabled, see `xa`. Otherwise every sub-INSERT commits on its own on the primary of its
+//! segment: when one fails, the rows of the segments before it stay. Inside a client
+//! transaction, which the sub-INSERTs could not be part of, the INSERT is refused.
+
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+use mysql::prelude::Queryable;
+use sqlparser::ast::{Expr, Ident, ObjectName, SetExpr, Statement, UnaryOperator, Value, Values};
+
+use data_panel_common::common::Error;
+
+use crate::discovery;
+use crate::discovery::database::rules::{current_rules, RulesVersion, TableRoute};
+use crate::handler::database::{variables, xa};
+use crate::handler::database::keygen::{key_generator, KeyGenerator};
+use crate::handler::database::mysql::rdbc::err_payload;
+use crate::handler::database::parser::sql::mysql::{bind_placeholders, placeholder_count};
+use crate::handler::database::parser::sql::unquoted_table;
+use crate::protocol::database::{DatabasePacket, PacketPayload};
+use crate::protocol::database::mysql::packet::{MySQLOKPacket, MySQLPacketPayload};
+use crate::session::mysql::SessionContext;
+
+/// The INSERT of the rows of one data segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentInsert {
+    segment: u32,
+    sql: String,
+    params: Vec<usize>,
+    rows: Vec<usize>,
+}
+
+impl SegmentInsert {
+    pub fn get_segment(&self) -> u32 {
+        self.segment
+    }
+
+    pub fn get_sql(&self) -> &str {
+        &self.sql
+    }
+
+    /// The indexes of the statement parameters the placeholders of `sql` take, in order.
+    pub fn get_params(&self) -> &Vec<usize> {
+        &self.params
+    }
+
+    /// The indexes of the rows of the statement this insert holds, in order.
+    pub fn get_rows(&self) -> &Vec<usize> {
+        &self.rows
+    }
+
+    /// `sql` with its placeholders bound, `literals` being the SQL literals of every parameter
+    /// of the statement.
+    pub fn bound_sql(&self, literals: &[String]) -> Result<String, String> {
+        let literals = self.params.iter()
+            .map(|param| literals.get(*param).cloned())
+            .collect::<Option<Vec<String>>>()
+            .ok_or_else(|| format!("the INSERT into data segment {} has more placeholders than bound parameters", self.segment))?;
+        bind_placeholders(&self.sql, &literals)
+            .ok_or_else(|| format!("the parameters of the INSERT into data segment {} can't be bound", self.segment))
+    }
+}
+
+/// The sub-INSERTs of a statement and the keys generated for its rows, if any.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InsertPlan {
+    inserts: Vec<SegmentInsert>,
+    generated_keys: Vec<u64>,
+    filled_params: Vec<usize>,
+}
+
+impl InsertPlan {
+    pub fn get_inserts(&self) -> &Vec<SegmentInsert> {
+        &self.inserts
+    }
+
+    /// One per row filled in, in the order of the rows.
+    pub fn get_generated_keys(&self) -> &Vec<u64> {
+        &self.generated_keys
+    }
+
+    /// The data segments and the INSERT each of them runs, its parameters bound, see
+    /// `SegmentInsert::bound_sql`.
+    pub fn bound_writes(&self, literals: &[String]) -> Result<Vec<(u32, String)>, String> {
+        let literals = unfilled(literals, &self.filled_params);
+        self.inserts.iter().map(|insert| Ok((insert.segment, insert.bound_sql(&literals)?))).collect()
+    }
+
+    /// The outcome of the whole INSERT from the outcomes of the sub-INSERTs, in order.
+    pub fn merge(&self, outcomes: &[InsertOutcome]) -> InsertOutcome {
+        let outcome = merge_outcomes(&self.inserts, outcomes);
+        match self.generated_keys.first() {
+            Some(key) => InsertOutcome::new(outcome.affected_rows, *key),
+            None => outcome,
+        }
+    }
+}
+
+/// What the backend answered a sub-INSERT.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct InsertOutcome {
+    affected_rows: u64,
+    last_insert_id: u64,
+}
+
+impl InsertOutcome {
+    pub fn new(affected_rows: u64, last_insert_id: u64) -> Self {
+        InsertOutcome {
+            affected_rows,
+            last_insert_id,
+        }
+    }
+
+    pub fn get_affected_rows(&self) -> u64 {
+        self.affected_rows
+    }
+
+    pub fn get_last_insert_id(&self) -> u64 {
+        self.last_insert_id
+    }
+}
+
+/// The shard key values of the parameters bound to a statement, None for NULL.
+pub fn key_values(params: &[mysql::Value]) -> Vec<Option<String>> {
+    params.iter().map(|param| match param {
+        mysql::Value::NULL => None,
+        mysql::Value::Bytes(bytes) => Some(String::from_utf8_lossy(bytes).to_string()),
+        mysql::Value::Int(x) => Some(x.to_string()),
+        mysql::Value::UInt(x) => Some(x.to_string()),
+        param => Some(param.as_sql(false).trim_matches('\'').to_string()),
+    }).collect()
+}
+
+/// The SQL literals of the parameters bound to a statement.
+pub fn sql_literals(params: &[mysql::Value]) -> Vec<String> {
+    params.iter().map(|param| param.as_sql(false)).collect()
+}
+
+/// `params` without the ones at the indexes of `filled`.
+fn unfilled<T: Clone>(params: &[T], filled: &[usize]) -> Vec<T> {
+    params.iter().enumerate()
+        .filter(|(param, _)| !filled.contains(param))
+        .map(|(_, value)| value.clone())
+        .collect()
+}
+
+fn is_placeholder(expr: &Expr) -> bool {
+    matches!(expr, Expr::Identifier(ident) if ident.quote_style.is_none() && ident.value == "?")
+}
+
+/// The shard key value `expr` gives, `param` being the index of its first placeholder.
+fn key_value(expr: &Expr, param: usize, params: &[Option<String>]) -> Option<String> {
+    match expr {
+        expr if is_placeholder(expr) => params.get(param).cloned().flatten(),
+        Expr::Value(Value::SingleQuotedString(value)) => Some(value.clone()),
+        Expr::Value(Value::Null) => None,
+        Expr::Value(value) => Some(value.to_string()),
+        Expr::UnaryOp { op: UnaryOperator::Minus, expr } => key_value(expr, param, params).map(|value| format!("-{}", value)),
+        Expr::Nested(expr) => key_value(expr, param, params),
+        _ => None,
+    }
+}
+
+/// `name` with its table part renamed to `table`, the schema and quoting kept.
+fn renamed(name: &ObjectName, table: &str) -> ObjectName {
+    let mut name = name.clone();
+    if let Some(last) = name.0.last_mut() {
+        *last = Ident {
+            value: table.to_string(),
+            quote_style: last.quote_style,
+        };
+    }
+    name
+}
+
+/// `statement` with the generated key of its table filled in, None unless the table has one
+/// the INSERT leaves out or gives as NULL. A key left out is added to the columns and to every
+/// row, one given as NULL, or as a placeholder `params` binds to NULL, is filled in for the
+/// rows giving NULL. Returns the keys too, one per row filled in, and the indexes of the
+/// parameters whose placeholders the keys replace.
+pub fn fill_generated_keys(statement: &Statement, params: &[Option<String>], rules: &RulesVersion, generator: &KeyGenerator) -> Option<(Statement, Vec<u64>, Vec<usize>)> {
+    let mut statement = statement.clone();
+    let mut filled_params = vec![];
+    let keys = match &mut statement {
+        Statement::Insert { table_name, columns, source, .. } if !columns.is_empty() => {
+            let generated_key = rules.generated_key(&unquoted_table(&table_name.to_string()))?;
+            let rows = match &mut source.body {
+                SetExpr::Values(values) => &mut values.0,
+                _ => return None,
+            };
+            let key_value = |key: &u64| Expr::Value(Value::Number(key.to_string(), false));
+            match columns.iter().position(|column| column.value.eq_ignore_ascii_case(&generated_key)) {
+                Some(column) => {
+                    let mut next_param = 0;
+                    let mut nulls: Vec<&mut Expr> = vec![];
+                    for row in rows.iter_mut() {
+                        let row_params: Vec<usize> = row.iter().map(|expr| placeholder_count(&expr.to_string())).collect();
+                        let param = next_param + row_params[..column.min(row.len())].iter().sum::<usize>();
+                        next_param += row_params.iter().sum::<usize>();
+                        match row.get_mut(column) {
+                            Some(expr) if matches!(expr, Expr::Value(Value::Null)) => nulls.push(expr),
+                            Some(expr) if is_placeholder(expr) && matches!(params.get(param), Some(None)) => {
+                                filled_params.push(param);
+                                nulls.push(expr);
+                            }
+                            _ => {}
+                        }
+                    }
+                    if nulls.is_empty() {
+                        return None;
+                    }
+                    let keys = generator.next_keys(nulls.len());
+                    for (expr, key) in nulls.into_iter().zip(keys.iter()) {
+                        *expr = key_value(key);
+                    }
+                    keys
+                }
+                None => {
+                    let keys = generator.next_keys(rows.len());
+                    for (row, key) in rows.iter_mut().zip(keys.iter()) {
+                        row.push(key_value(key));
+                    }
+                    columns.push(Ident::new(generated_key));
+                    keys
+                }
+            }
+        }
+        _ => return None,
+    };
+    Some((statement, keys, filled_params))
+}
+
+/// Plans `statement`, see `split_insert`, filling in its generated key when the key
+/// generator is on.
+pub fn plan_insert(statement: &Statement, params: &[Option<String>], rules: &RulesVersion) -> Result<Option<InsertPlan>, String> {
+    plan_insert_with(statement, params, rules, key_generator().as_deref())
+}
+
+/// Like `plan_insert`, with the key generator given.
+fn plan_insert_with(statement: &Statement, params: &[Option<String>], rules: &RulesVersion, generator: Option<&KeyGenerator>) -> Result<Option<InsertPlan>, String> {
+    let filled = generator.and_then(|generator| fill_generated_keys(statement, params, rules, generator));
+    let (statement, generated_keys, filled_params) = match &filled {
+        Some((filled_statement, keys, filled_params)) => (filled_statement, keys.clone(), filled_params.clone()),
+        None => (statement, vec![], vec![]),
+    };
+    Ok(split_insert(statement, &unfilled(params, &filled_params), rules)?.map(|inserts| InsertPlan {
+        inserts,
+        generated_keys,
+        filled_params,
+    }))
+}
+
+/// The per segment INSERTs of `statement`, None unless it inserts VALUES into a distributed
+/// table. `params` are the values bound to its placeholders, None for NULL. Fails when a row
+/// does not give every shard key a value.
+pub fn split_insert(statement: &Statement, params: &[Option<String>], rules: &RulesVersion) -> Result<Option<Vec<SegmentInsert>>, String> {
+    let (table_name, columns, source) = match statement {
+        Statement::Insert { table_name, columns, source, .. } => (table_name, columns, source),
+        _ => return Ok(None),
+    };
+    let values = match &source.body {
+        SetExpr::Values(values) => values,
+        _ => return Ok(None),
+    };
+    let table = unquoted_table(&table_name.to_string());
+    if !matches!(rules.route(&table, &[]), TableRoute::Distributed(_)) {
+        return Ok(None);
+    }
+    let dis_keys = rules.dis_keys(&table);
+    let key_columns: Vec<(String, Option<usize>)> = dis_keys.iter()
+        .map(|key| (key.clone(), columns.iter().position(|column| column.value.eq_ignore_ascii_case(key))))
+        .collect();
+
+    // Rows and the parameters they take, by segment.
+    let mut segments: BTreeMap<u32, (Vec<usize>, Vec<usize>)> = BTreeMap::new();
+    let mut next_param = 0;
+    for (row_index, row) in values.0.iter().enumerate() {
+        let row_params: Vec<usize> = row.iter().map(|expr| placeholder_count(&expr.to_string())).collect();
+        let mut bindings = vec![];
+        for (key, column) in key_columns.iter() {
+            let value = column.and_then(|column| {
+                let param = next_param + row_params[..column.min(row.len())].iter().sum::<usize>();
+                row.get(column).and_then(|expr| key_value(expr, param, params))
+            });
+            match value {
+                Some(value) => bindings.push((key.clone(), value)),
+                None => return Err(format!("row {} of the INSERT into {} has no value for the shard key {}", row_index + 1, table, key)),
+            }
+        }
+        let segment = match rules.route(&table, &bindings) {
+            TableRoute::Distributed(segments) if segments.len() == 1 => segments[0],
+            _ => return Err(format!("row {} of the INSERT into {} does not route to a single segment", row_index + 1, table)),
+        };
+        let row_param_count: usize = row_params.iter().sum();
+        let (rows, segment_params) = segments.entry(segment).or_insert_with(|| (vec![], vec![]));
+        rows.push(row_index);
+        segment_params.extend(next_param..next_param + row_param_count);
+        next_param += row_param_count;
+    }
+
+    let inserts = segments.into_iter().map(|(segment, (rows, segment_params))| {
+        let mut sub_statement = statement.clone();
+        if let Statement::Insert { table_name, source, .. } = &mut sub_statement {
+            *table_name = renamed(table_name, &rules.actual_table(&table, segment));
+            source.body = SetExpr::Values(Values(rows.iter().map(|row| values.0[*row].clone()).collect()));
+        }
+        SegmentInsert {
+            segment,
+            sql: sub_statement.to_string(),
+            params: segment_params,
+            rows,
+        }
+    }).collect();
+    Ok(Some(inserts))
+}
+
+/// The outcome of the whole INSERT from the outcomes of `inserts`, in the same order.
+pub fn merge_outcomes(inserts: &[SegmentInsert], outcomes: &[InsertOutcome]) -> InsertOutcome {
+    let affected_rows = outcomes.iter().map(|outcome| outcome.affected_rows).sum();
+    let last_insert_id = inserts.iter().zip(outcomes.iter())
+        .filter(|(_, outcome)| outcome.last_insert_id != 0)
+        .min_by_key(|(insert, _)| insert.rows.first().cloned().unwrap_or(usize::MAX))
+        .map_or(0, |(_, outcome)| outcome.last_insert_id);
+    InsertOutcome::new(affected_rows, last_insert_id)
+}
+
+/// The OK packet answering the whole INSERT.
+pub fn ok_payloads(outcome: InsertOutcome, status_flags: u16) -> Option<Vec<Bytes>> {
+    let mut ok_packet = MySQLOKPacket::new(1, outcome.affected_rows, outcome.last_insert_id);
+    ok_packet.set_status_flags(status_flags);
+    let mut ok_payload = MySQLPacketPayload::new();
+    let ok_payload = DatabasePacket::encode(&mut ok_packet, &mut ok_payload);
+    Some(vec![ok_payload.get_payload()])
+}
+
+fn execute_on_segment(segment: u32, sql: &str, session_ctx: &SessionContext) -> mysql::Result<InsertOutcome> {
+    let segment = format!("data-{}/primary", segment);
+    let database_url = discovery::database::segment_url(&segment).ok_or_else(|| xa::no_such_segment(&segment))?;
+    let mut conn = variables::connect_to(session_ctx, &database_url)?;
+    let result = conn.query_iter(sql)?;
+    Ok(InsertOutcome::new(result.affected_rows(), result.last_insert_id().unwrap_or(0)))
+}
+
+/// Like `intercept`, with the rules given.
+fn intercept_with(statement: &Statement, params: &[mysql::Value], rules: &RulesVersion, session_ctx: &mut SessionContext) -> Option<Vec<Bytes>> {
+    let plan = match plan_insert(statement, &key_values(params), rules) {
+        Ok(Some(plan)) => plan,
+        Ok(None) => return None,
+        Err(message) => return Some(vec![err_payload(Error::Routing(message))]),
+    };
+    if session_ctx.is_in_transaction() {
+        let message = "an INSERT into a distributed table inside a transaction needs distributed transactions, enable distributed_transaction";
+        return Some(vec![err_payload(Error::Routing(message.to_string()))]);
+    }
+    let writes = match plan.bound_writes(&sql_literals(params)) {
+        Ok(writes) => writes,
+        Err(message) => return Some(vec![err_payload(Error::Protocol(message))]),
+    };
+    let mut outcomes = vec![];
+    for (segment, sql) in writes.iter() {
+        match execute_on_segment(*segment, sql, session_ctx) {
+            Ok(outcome) => outcomes.push(outcome),
+            Err(e) => return Some(vec![err_payload(e)]),
+        }
+    }
+    ok_payloads(plan.merge(&outcomes), session_ctx.get_status_flags())
+}
+
+/// Answers an INSERT of VALUES into a distributed table that no distributed transaction runs,
+/// see `xa::intercept`, its rows on the primaries of their data segments. `params` are the
+/// values bound to its placeholders. None for other statements.
+pub fn intercept(statement: &Statement, params: &[mysql::Value], session_ctx: &mut SessionContext) -> Option<Vec<Bytes>> {
+    if !matches!(statement, Statement::Insert { .. }) {
+        return None;
+    }
+    intercept_with(statement, params, &current_rules()?, session_ctx)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discovery::database::{Cluster, DisAlgorithm, DisRules, DisTable, DisType, Segment};
+    use crate::discovery::database::rules::{RulesVersion, TableRoute};
+    use crate::handler::database::keygen::KeyGenerator;
+    use crate::handler::database::mysql::{err_code_message, ok_affected_rows, ok_last_insert_id};
+    use crate::handler::database::parser::sql::mysql::parser;
+    use crate::session::mysql::SessionContext;
+
+    use super::{fill_generated_keys, InsertOutcome, InsertPlan, intercept_with, key_values, merge_outcomes, ok_payloads, plan_insert_with, split_insert, sql_literals};
+
+    fn rules() -> RulesVersion {
+        let url = "jdbc:mysql://localhost:3306/martlet";
+        let cluster = Cluster::builder("martlet")
+            .meta_segment(Segment::new(0, url, "root", "root"), vec![])
+            .data_segment(100, Segment::new(0, url, "root", "root"), vec![])
+            .data_segment(200, Segment::new(0, url, "root", "root"), vec![])
+            .dis_rules(DisRules::builder()
+                .distributed_table("t_order", DisTable::new(vec!["user_id"], DisAlgorithm::new(DisType::HASH, ""), vec![])
+                    .actual_table("t_order_{segment}"))
+                .distributed_table("t_order_item", DisTable::new(vec!["order_id"], DisAlgorithm::new(DisType::HASH, ""), vec![])
+                    .generated_key("order_id"))
+                .build())
+            .build()
+            .unwrap();
+        RulesVersion::new("v1".to_string(), cluster)
+    }
+
+    #[test]
+    fn test_split_insert() {
+        let rules = rules();
+        let segment_of = |user_id: &str| match rules.route("t_order", &[("user_id".to_string(), user_id.to_string())]) {
+            TableRoute::Distributed(segments) => segments[0],
+            _ => unreachable!(),
+        };
+        let (first_id, first_segment) = ("1".to_string(), segment_of("1"));
+        // A user id hashing to another segment.
+        let second_id = (2..100).map(|id| id.to_string()).find(|id| segment_of(id) != first_segment).unwrap();
+        let second_segment = segment_of(&second_id);
+
+        let sql = format!("INSERT INTO t_order (id, user_id, status) VALUES (?, {}, 'PAID'), (?, ?, 'SENT'), (3, {}, ?)", first_id, first_id);
+        let statement = parser(sql).unwrap().pop().unwrap();
+        let params = vec![Some("1".to_string()), Some("2".to_string()), Some(second_id.clone()), Some("NEW".to_string())];
+        let inserts = split_insert(&statement, &params, &rules).unwrap().unwrap();
+        assert_eq!(inserts.len(), 2);
+        let first_insert = inserts.iter().find(|insert| insert.get_segment() == first_segment).unwrap();
+        assert_eq!(first_insert.get_sql(), format!("INSERT INTO t_order_{} (id, user_id, status) VALUES (?, {}, 'PAID'), (3, {}, ?)", first_segment, first_id, first_id));
+        assert_eq!(first_insert.get_params(), &vec![0, 3]);
+        let second_insert = inserts.iter().find(|insert| insert.get_segment() == second_segment).unwrap();
+        assert_eq!(second_insert.get_sql(), format!("INSERT INTO t_order_{} (id, user_id, status) VALUES (?, ?, 'SENT')", second_segment));
+        assert_eq!(second_insert.get_params(), &vec![1, 2]);
+
+        let outcomes: Vec<InsertOutcome> = inserts.iter()
+            .map(|insert| if insert.get_segment() == first_segment { InsertOutcome::new(2, 41) } else { InsertOutcome::new(1, 7) })
+            .collect();
+        assert_eq!(merge_outcomes(&inserts, &outcomes), InsertOutcome::new(3, 41));
+
+        let statement = parser("INSERT INTO t_order (id, status) VALUES (1, 'PAID')".to_string()).unwrap().pop().unwrap();
+        assert!(split_insert(&statement, &[], &rules).is_err());
+        let statement = parser("INSERT INTO t_user (id) VALUES (1)".to_string()).unwrap().pop().unwrap();
+        assert_eq!(split_insert(&statement, &[], &rules), Ok(None));
+    }
+
+    #[test]
+    fn test_bound_insert() {
+        let rules = rules();
+        let segment_of = |user_id: &str| match rules.route("t_order", &[("user_id".to_string(), user_id.to_string())]) {
+            TableRoute::Distributed(segments) => segments[0],
+            _ => unreachable!(),
+        };
+        let first_segment = segment_of("1");
+        let second_id = (2..100u64).find(|id| segment_of(&id.to_string()) != first_segment).unwrap();
+
+        // The values a client binds in COM_STMT_EXECUTE, the shard keys among them.
+        let params = vec![
+            mysql::Value::Int(1),
+            mysql::Value::Bytes(b"1".to_vec()),
+            mysql::Value::Bytes(b"it's ?".to_vec()),
+            mysql::Value::Int(2),
+            mysql::Value::UInt(second_id),
+            mysql::Value::NULL,
+        ];
+        assert_eq!(key_values(&params)[1], Some("1".to_string()));
+        assert_eq!(key_values(&params)[5], None);
+        let statement = parser("INSERT INTO t_order (id, user_id, status) VALUES (?, ?, ?), (?, ?, ?)".to_string()).unwrap().pop().unwrap();
+        let inserts = split_insert(&statement, &key_values(&params), &rules).unwrap().unwrap();
+        assert_eq!(inserts.len(), 2);
+        let literals = sql_literals(&params);
+        let first_insert = inserts.iter().find(|insert| insert.get_segment() == first_segment).unwrap();
+        assert_eq!(first_insert.bound_sql(&literals), Ok(format!("INSERT INTO t_order_{} (id, user_id, status) VALUES (1, '1', 'it\\'s ?')", first_segment)));
+        let second_insert = inserts.iter().find(|insert| insert.get_segment() != first_segment).unwrap();
+        assert_eq!(second_insert.bound_sql(&literals), Ok(format!("INSERT INTO t_order_{} (id, user_id, status) VALUES (2, {}, NULL)", second_insert.get_segment(), second_id)));
+        assert!(first_insert.bound_sql(&literals[..2]).is_err());
+
+        // Refused before any segment is written to: a row without its shard key, and inside a
+        // transaction, which the sub-INSERTs can't be part of.
+        let mut session_ctx = SessionContext::new(1);
+        let unkeyed = parser("INSERT INTO t_order (id, user_id) VALUES (1, ?)".to_string()).unwrap().pop().unwrap();
+        let payloads = intercept_with(&unkeyed, &[mysql::Value::NULL], &rules, &mut session_ctx);
+        assert!(err_code_message(&payloads).unwrap().1.contains("no value for the shard key user_id"));
+        session_ctx.set_in_transaction(true);
+        let payloads = intercept_with(&statement, &params, &rules, &mut session_ctx);
+        assert!(err_code_message(&payloads).unwrap().1.contains("distributed_transaction"));
+        let unsharded = parser("INSERT INTO t_user (id) VALUES (?)".to_string()).unwrap().pop().unwrap();
+        assert!(intercept_with(&unsharded, &[mysql::Value::Int(1)], &rules, &mut session_ctx).is_none());
+    }
+
+    #[test]
+    fn test_fill_generated_keys() {
+        let rules = rules();
+        let generator = KeyGenerator::new(1, 0);
+        let statement = parser("INSERT INTO t_order_item (sku, amount) VALUES ('a', ?), ('b', 2)".to_string()).unwrap().pop().unwrap();
+        let (filled, keys, _) = fill_generated_keys(&statement, &[], &rules, &generator).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(filled.to_string(), format!("INSERT INTO t_order_item (sku, amount, order_id) VALUES ('a', ?, {}), ('b', 2, {})", keys[0], keys[1]));
+        // The generated key is the shard key, every row routes.
+        let inserts = split_insert(&filled, &[Some("1".to_string())], &rules).unwrap().unwrap();
+        assert_eq!(inserts.iter().map(|insert| insert.get_rows().len()).sum::<usize>(), 2);
+
+        let statement = parser("INSERT INTO t_order_item (order_id, sku) VALUES (7, 'a')".to_string()).unwrap().pop().unwrap();
+        assert!(fill_generated_keys(&statement, &[], &rules, &generator).is_none());
+
+        // A key given as NULL is generated, as AUTO_INCREMENT would, the others are kept.
+        let statement = parser("INSERT INTO t_order_item (order_id, sku) VALUES (NULL, 'a'), (7, 'b'), (NULL, 'c')".to_string()).unwrap().pop().unwrap();
+        let (filled, keys, _) = fill_generated_keys(&statement, &[], &rules, &generator).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(filled.to_string(), format!("INSERT INTO t_order_item (order_id, sku) VALUES ({}, 'a'), (7, 'b'), ({}, 'c')", keys[0], keys[1]));
+
+        // The OK packet answering the INSERT has the first generated key as last insert id,
+        // not the ids the backends report.
+        let inserts = split_insert(&filled, &[], &rules).unwrap().unwrap();
+        let outcomes: Vec<InsertOutcome> = inserts.iter().map(|insert| InsertOutcome::new(insert.get_rows().len() as u64, 0)).collect();
+        let plan = InsertPlan {
+            inserts,
+            generated_keys: keys.clone(),
+            filled_params: vec![],
+        };
+        let payloads = ok_payloads(plan.merge(&outcomes), 0);
+        assert_eq!(ok_affected_rows(&payloads), Some(3));
+        assert_eq!(ok_last_insert_id(&payloads), Some(keys[0]));
+
+        // The same INSERT prepared, the key bound to NULL in COM_STMT_EXECUTE: the generated key
+        // replaces the placeholder, the other parameters keep their places.
+        let statement = parser("INSERT INTO t_order_item (order_id, sku) VALUES (?, ?), (?, ?)".to_string()).unwrap().pop().unwrap();
+        let params = vec![mysql::Value::NULL, mysql::Value::Bytes(b"a".to_vec()), mysql::Value::Int(7), mysql::Value::Bytes(b"b".to_vec())];
+        let plan = plan_insert_with(&statement, &key_values(&params), &rules, Some(&generator)).unwrap().unwrap();
+        let key = plan.get_generated_keys()[0];
+        assert_eq!(plan.get_generated_keys().len(), 1);
+        let writes = plan.bound_writes(&sql_literals(&params)).unwrap();
+        let sqls: Vec<&str> = writes.iter().map(|(_, sql)| sql.as_str()).collect();
+        assert!(sqls.iter().any(|sql| sql.contains(&format!("({}, 'a')", key))), "{:?}", sqls);
+        assert!(sqls.iter().any(|sql| sql.contains("(7, 'b')")), "{:?}", sqls);
+        let outcomes: Vec<InsertOutcome> = plan.get_inserts().iter().map(|insert| InsertOutcome::new(insert.get_rows().len() as u64, 0)).collect();
+        assert_eq!(plan.merge(&outcomes), InsertOutcome::new(2, key));
+    }
+}
@@ -0,0 +1,189 @@
+//! Runs of merge rows spilled to temporary files, see `MergeConfig`.
+//!
+//! A run is written once and read back once, in the order it was written, and its file is
+//! removed when it is dropped. NULL, bytes and numbers are written as they are, other values
+//! as their text, which is how they compare in a merge anyway.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use mysql::Value;
+
+use crate::handler::database::merge::MergeRow;
+
+const NULL: u8 = 0;
+const BYTES: u8 = 1;
+const INT: u8 = 2;
+const UINT: u8 = 3;
+const DOUBLE: u8 = 4;
+
+lazy_static! {
+    static ref NEXT_RUN: AtomicU64 = AtomicU64::new(0);
+}
+
+/// The bytes `row` takes in memory, roughly.
+pub fn row_size(row: &MergeRow) -> usize {
+    row.iter()
+        .map(|value| std::mem::size_of::<Value>() + match value {
+            Value::Bytes(bytes) => bytes.len(),
+            _ => 0,
+        })
+        .sum::<usize>() + std::mem::size_of::<MergeRow>()
+}
+
+fn write_row(writer: &mut impl Write, row: &MergeRow) -> io::Result<()> {
+    writer.write_all(&(row.len() as u32).to_le_bytes())?;
+    for value in row {
+        match value {
+            Value::NULL => writer.write_all(&[NULL])?,
+            Value::Int(i) => {
+                writer.write_all(&[INT])?;
+                writer.write_all(&i.to_le_bytes())?;
+            }
+            Value::UInt(u) => {
+                writer.write_all(&[UINT])?;
+                writer.write_all(&u.to_le_bytes())?;
+            }
+            Value::Double(d) => {
+                writer.write_all(&[DOUBLE])?;
+                writer.write_all(&d.to_bits().to_le_bytes())?;
+            }
+            Value::Bytes(bytes) => {
+                writer.write_all(&[BYTES])?;
+                writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+                writer.write_all(bytes)?;
+            }
+            value => {
+                let text = value.as_sql(false);
+                let text = text.trim_matches('\'').as_bytes();
+                writer.write_all(&[BYTES])?;
+                writer.write_all(&(text.len() as u32).to_le_bytes())?;
+                writer.write_all(text)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_row(reader: &mut impl Read) -> io::Result<MergeRow> {
+    let columns = read_u32(reader)? as usize;
+    let mut row = Vec::with_capacity(columns);
+    for _ in 0..columns {
+        let mut tag = [0u8; 1];
+        reader.read_exact(&mut tag)?;
+        row.push(match tag[0] {
+            NULL => Value::NULL,
+            INT => Value::Int(read_u64(reader)? as i64),
+            UINT => Value::UInt(read_u64(reader)?),
+            DOUBLE => Value::Double(f64::from_bits(read_u64(reader)?)),
+            BYTES => {
+                let mut bytes = vec![0u8; read_u32(reader)? as usize];
+                reader.read_exact(&mut bytes)?;
+                Value::Bytes(bytes)
+            }
+            tag => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown value tag {}", tag))),
+        });
+    }
+    Ok(row)
+}
+
+/// Rows spilled to a file of their own.
+pub struct SpillRun {
+    path: PathBuf,
+    rows: usize,
+}
+
+impl SpillRun {
+    /// Writes `rows` to a new file of `dir`.
+    pub fn write<'a>(dir: &Path, rows: impl Iterator<Item = &'a MergeRow>) -> io::Result<Self> {
+        let path = dir.join(format!("martlet-merge-{}-{}.run", std::process::id(), NEXT_RUN.fetch_add(1, Ordering::Relaxed)));
+        let file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+        // Removed with the run from here on, even when the write fails.
+        let mut run = SpillRun { path, rows: 0 };
+        let mut writer = BufWriter::new(file);
+        for row in rows {
+            write_row(&mut writer, row)?;
+            run.rows += 1;
+        }
+        writer.flush()?;
+        Ok(run)
+    }
+
+    pub fn get_rows(&self) -> usize {
+        self.rows
+    }
+
+    /// The rows of the run, in the order they were written.
+    pub fn into_rows(self) -> io::Result<SpillRows> {
+        let reader = BufReader::new(File::open(&self.path)?);
+        Ok(SpillRows { reader, remaining: self.rows, run: self })
+    }
+}
+
+impl Drop for SpillRun {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+pub struct SpillRows {
+    reader: BufReader<File>,
+    remaining: usize,
+    /// Removes the file once the rows are read or dropped.
+    run: SpillRun,
+}
+
+impl Iterator for SpillRows {
+    type Item = MergeRow;
+
+    fn next(&mut self) -> Option<MergeRow> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        match read_row(&mut self.reader) {
+            Ok(row) => Some(row),
+            Err(e) => {
+                println!("error on reading spilled rows from {}; error = {}", self.run.path.display(), e);
+                self.remaining = 0;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mysql::Value;
+
+    use super::SpillRun;
+
+    #[test]
+    fn test_spill_run() {
+        let rows = vec![
+            vec![Value::NULL, Value::Int(-7), Value::UInt(7), Value::Double(0.5), Value::Bytes(b"a".to_vec())],
+            vec![Value::Bytes(vec![]), Value::Date(2021, 4, 1, 0, 0, 0, 0), Value::NULL, Value::NULL, Value::NULL],
+        ];
+        let run = SpillRun::write(&std::env::temp_dir(), rows.iter()).unwrap();
+        assert_eq!(run.get_rows(), 2);
+        let path = run.path.clone();
+        let read: Vec<Vec<Value>> = run.into_rows().unwrap().collect();
+        assert_eq!(read[0], rows[0]);
+        assert_eq!(read[1][1], Value::Bytes(b"2021-04-01".to_vec()));
+        assert!(!path.exists());
+    }
+}
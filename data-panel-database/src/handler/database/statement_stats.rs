@@ -0,0 +1,297 @@
+//! Statistics of the statements clients run by fingerprint, see `StatementStatsConfig`.
+//!
+//! Every statement is counted under its fingerprint with its latency, from its command read to
+//! its response sent, whether it failed, and the rows it returned. Latencies go to a histogram
+//! of log-linear buckets, so the percentiles are within 1/16 of the real ones however many
+//! statements ran. `SHOW MESH STATS` answers them to any user, as fingerprints hold no values,
+//! and `GET /stats` of the admin API too, `DELETE /stats` starting them over.
+
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use bytes::Bytes;
+use dashmap::DashMap;
+
+use data_panel_common::config::config::StatementStatsConfig;
+
+use crate::handler::database::{corpus, parser};
+use crate::handler::database::mysql::text_result_payloads;
+
+/// The fingerprint the statements beyond `max_fingerprints` are counted under.
+pub const OTHER_FINGERPRINT: &str = "(other)";
+
+/// Buckets per power of two of the latency histogram.
+const SUB_BUCKETS: u64 = 16;
+/// Latencies are counted in microseconds, up to 2^36, about 19 hours.
+const MAX_LATENCY_EXPONENT: u64 = 36;
+const BUCKETS: usize = ((MAX_LATENCY_EXPONENT - 3) * SUB_BUCKETS + SUB_BUCKETS) as usize;
+
+pub const STATS_COLUMNS: [&str; 8] = ["Fingerprint", "Count", "Errors", "Rows", "P50_ms", "P95_ms", "P99_ms", "Total_ms"];
+
+/// Whether `sql` is `SHOW MESH STATS`.
+pub fn stats_statement(sql: &str) -> bool {
+    let words: Vec<&str> = sql.trim().trim_end_matches(';').split_whitespace().collect();
+    match words.as_slice() {
+        [show, mesh, stats] => show.eq_ignore_ascii_case("SHOW") && mesh.eq_ignore_ascii_case("MESH") && stats.eq_ignore_ascii_case("STATS"),
+        _ => false,
+    }
+}
+
+/// The bucket of a latency of `micros`: exact below 16, then 16 buckets per power of two.
+fn bucket(micros: u64) -> usize {
+    let micros = micros.min((1 << (MAX_LATENCY_EXPONENT + 1)) - 1);
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let exponent = 63 - micros.leading_zeros() as u64;
+    let sub_bucket = (micros >> (exponent - 4)) & (SUB_BUCKETS - 1);
+    ((exponent - 3) * SUB_BUCKETS + sub_bucket) as usize
+}
+
+/// The middle of the latencies of `bucket`, in microseconds.
+fn bucket_micros(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < SUB_BUCKETS {
+        return bucket;
+    }
+    let exponent = bucket / SUB_BUCKETS + 3;
+    let width = 1 << (exponent - 4);
+    (SUB_BUCKETS + bucket % SUB_BUCKETS) * width + width / 2
+}
+
+pub struct FingerprintStats {
+    count: AtomicU64,
+    errors: AtomicU64,
+    rows: AtomicU64,
+    total_micros: AtomicU64,
+    buckets: Vec<AtomicU64>,
+}
+
+impl FingerprintStats {
+    fn new() -> Self {
+        FingerprintStats {
+            count: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            rows: AtomicU64::new(0),
+            total_micros: AtomicU64::new(0),
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    fn record(&self, latency: Duration, failed: bool, rows: u64) {
+        let micros = latency.as_micros() as u64;
+        self.count.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.rows.fetch_add(rows, Ordering::Relaxed);
+        self.total_micros.fetch_add(micros, Ordering::Relaxed);
+        self.buckets[bucket(micros)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self, fingerprint: &str) -> FingerprintSnapshot {
+        let buckets: Vec<u64> = self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect();
+        let recorded: u64 = buckets.iter().sum();
+        let percentile = |percentile: u64| {
+            let rank = (recorded * percentile).div_ceil(100).max(1);
+            let mut seen = 0;
+            for (i, count) in buckets.iter().enumerate() {
+                seen += count;
+                if seen >= rank {
+                    return bucket_micros(i);
+                }
+            }
+            0
+        };
+        FingerprintSnapshot {
+            fingerprint: fingerprint.to_string(),
+            count: self.count.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            rows: self.rows.load(Ordering::Relaxed),
+            total_micros: self.total_micros.load(Ordering::Relaxed),
+            percentiles: if recorded == 0 { [0; 3] } else { [percentile(50), percentile(95), percentile(99)] },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FingerprintSnapshot {
+    fingerprint: String,
+    count: u64,
+    errors: u64,
+    rows: u64,
+    total_micros: u64,
+    /// p50, p95 and p99 in microseconds.
+    percentiles: [u64; 3],
+}
+
+/// The fingerprint `sql` is counted under, `sql` itself when it neither parses nor normalizes.
+pub fn statement_fingerprint(sql: &str) -> String {
+    parser::sql::fingerprint(sql).or_else(|| corpus::normalize(sql)).unwrap_or_else(|| sql.to_string())
+}
+
+fn millis(micros: u64) -> String {
+    format!("{:.3}", micros as f64 / 1000.0)
+}
+
+impl FingerprintSnapshot {
+    pub fn get_fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    pub fn get_count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn get_errors(&self) -> u64 {
+        self.errors
+    }
+
+    pub fn get_rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// p50, p95 and p99 in microseconds.
+    pub fn get_percentiles(&self) -> [u64; 3] {
+        self.percentiles
+    }
+
+    pub fn to_row(&self) -> Vec<String> {
+        vec![
+            self.fingerprint.clone(),
+            self.count.to_string(),
+            self.errors.to_string(),
+            self.rows.to_string(),
+            millis(self.percentiles[0]),
+            millis(self.percentiles[1]),
+            millis(self.percentiles[2]),
+            millis(self.total_micros),
+        ]
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "fingerprint": self.fingerprint,
+            "count": self.count,
+            "errors": self.errors,
+            "rows": self.rows,
+            "p50_ms": self.percentiles[0] as f64 / 1000.0,
+            "p95_ms": self.percentiles[1] as f64 / 1000.0,
+            "p99_ms": self.percentiles[2] as f64 / 1000.0,
+            "total_ms": self.total_micros as f64 / 1000.0,
+        })
+    }
+}
+
+pub struct StatementStats {
+    fingerprints: DashMap<String, Arc<FingerprintStats>>,
+    max_fingerprints: AtomicUsize,
+}
+
+impl StatementStats {
+    pub fn new(max_fingerprints: usize) -> Self {
+        StatementStats {
+            fingerprints: DashMap::new(),
+            max_fingerprints: AtomicUsize::new(max_fingerprints),
+        }
+    }
+
+    fn fingerprint_stats(&self, fingerprint: String) -> Arc<FingerprintStats> {
+        if let Some(stats) = self.fingerprints.get(&fingerprint) {
+            return stats.value().clone();
+        }
+        let fingerprint = if self.fingerprints.len() < self.max_fingerprints.load(Ordering::Relaxed) {
+            fingerprint
+        } else {
+            OTHER_FINGERPRINT.to_string()
+        };
+        self.fingerprints.entry(fingerprint).or_insert_with(|| Arc::new(FingerprintStats::new())).value().clone()
+    }
+
+    /// Counts a statement of `sql` that ran for `latency` and returned `rows`.
+    pub fn record(&self, sql: &str, latency: Duration, failed: bool, rows: u64) {
+        self.fingerprint_stats(statement_fingerprint(sql)).record(latency, failed, rows);
+    }
+
+    /// The statistics of every fingerprint, the ones the longest in total first.
+    pub fn snapshots(&self) -> Vec<FingerprintSnapshot> {
+        let mut snapshots: Vec<FingerprintSnapshot> = self.fingerprints.iter()
+            .map(|stats| stats.value().snapshot(stats.key()))
+            .collect();
+        snapshots.sort_by(|a, b| b.total_micros.cmp(&a.total_micros).then_with(|| a.fingerprint.cmp(&b.fingerprint)));
+        snapshots
+    }
+
+    pub fn reset(&self) {
+        self.fingerprints.clear();
+    }
+}
+
+lazy_static! {
+    static ref STATEMENT_STATS: RwLock<Option<Arc<StatementStats>>> = RwLock::new(None);
+}
+
+/// Keeps the statistics gathered so far across reloads while they stay enabled.
+pub fn configure_statement_stats(config: &StatementStatsConfig) {
+    let mut statement_stats = STATEMENT_STATS.write().unwrap();
+    if !config.is_enabled() {
+        *statement_stats = None;
+        return;
+    }
+    match statement_stats.as_ref() {
+        Some(stats) => stats.max_fingerprints.store(config.get_max_fingerprints(), Ordering::Relaxed),
+        None => *statement_stats = Some(Arc::new(StatementStats::new(config.get_max_fingerprints()))),
+    }
+}
+
+pub fn statement_stats() -> Option<Arc<StatementStats>> {
+    STATEMENT_STATS.read().unwrap().clone()
+}
+
+/// Answers `SHOW MESH STATS`, no rows while the statistics are disabled.
+pub fn stats_payloads() -> Option<Vec<Bytes>> {
+    let rows = statement_stats()
+        .map(|stats| stats.snapshots().iter().map(|snapshot| snapshot.to_row()).collect())
+        .unwrap_or_default();
+    text_result_payloads(STATS_COLUMNS.to_vec(), rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{bucket, bucket_micros, OTHER_FINGERPRINT, StatementStats, stats_statement};
+
+    #[test]
+    fn test_statement_stats() {
+        assert!(stats_statement("show mesh stats;"));
+        assert!(!stats_statement("SHOW STATUS"));
+        for micros in [0u64, 15, 16, 31, 1000, 123_456, 10_000_000].iter() {
+            let estimate = bucket_micros(bucket(*micros));
+            assert!((estimate as f64 - *micros as f64).abs() <= *micros as f64 / 16.0, "{} estimated as {}", micros, estimate);
+        }
+
+        let stats = StatementStats::new(2);
+        for millis in 1..=100 {
+            stats.record("SELECT c FROM sbtest1 WHERE id = 1", Duration::from_millis(millis), false, 1);
+        }
+        stats.record("SELECT c FROM sbtest1 WHERE id = 2", Duration::from_millis(1000), true, 0);
+        stats.record("UPDATE sbtest1 SET k = k + 1 WHERE id = 3", Duration::from_millis(5), false, 0);
+        stats.record("DELETE FROM sbtest1 WHERE id = 4", Duration::from_millis(5), false, 0);
+
+        let snapshots = stats.snapshots();
+        assert_eq!(snapshots.len(), 3);
+        let select = snapshots.iter().find(|snapshot| snapshot.get_fingerprint().starts_with("SELECT")).unwrap();
+        assert_eq!((select.get_count(), select.get_errors(), select.get_rows()), (101, 1, 100));
+        let [p50, p95, p99] = select.get_percentiles();
+        assert!((47_000..=53_000).contains(&p50), "p50 {}", p50);
+        assert!((92_000..=98_000).contains(&p95), "p95 {}", p95);
+        assert!((96_000..=102_000).contains(&p99), "p99 {}", p99);
+        let other = snapshots.iter().find(|snapshot| snapshot.get_fingerprint() == OTHER_FINGERPRINT).unwrap();
+        assert_eq!(other.get_count(), 1);
+
+        stats.reset();
+        assert!(stats.snapshots().is_empty());
+    }
+}
@@ -0,0 +1,459 @@
+//! OpenTelemetry traces of sessions and statements, see `TracingConfig`.
+//!
+//! A session has a span from its login to its close, and every statement it runs a span with a
+//! child span per phase: parse, its checks and rewrites included, route, backend execute, the
+//! rows a streamed result sends while the backend answers included, and serialize, the rest of
+//! the response sent to the client. A statement joins the trace of its caller when a comment of
+//! its SQL has a W3C `traceparent`, as sqlcommenter writes it (`/*traceparent='00-...-01'*/`),
+//! and otherwise the trace of its session, which joins the one of a `traceparent` connection
+//! attribute. Finished spans are queued and exported in batches as OTLP/HTTP JSON by a
+//! background thread, a full queue drops spans instead of holding statements up.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::{Body, Client, Method, Request};
+use rand::Rng;
+
+use data_panel_common::config::config::TracingConfig;
+
+use crate::handler::database::{corpus, parser};
+use crate::session::mysql::SessionContext;
+
+/// Spans exported at once.
+const BATCH_SIZE: usize = 512;
+const TRACEPARENT: &str = "traceparent";
+
+const SPAN_KIND_INTERNAL: u8 = 1;
+const SPAN_KIND_SERVER: u8 = 2;
+const SPAN_KIND_CLIENT: u8 = 3;
+const STATUS_CODE_ERROR: u8 = 2;
+
+/// The trace a span is in and the span its children go under, a W3C trace context.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceContext {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    sampled: bool,
+}
+
+impl TraceContext {
+    /// The context of a `traceparent` value, None unless it is a valid one.
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let fields: Vec<&str> = traceparent.trim().split('-').collect();
+        if fields.len() < 4 || fields[0].len() != 2 || fields[0] == "ff" || (fields[0] == "00" && fields.len() != 4) {
+            return None;
+        }
+        let mut trace_id = [0u8; 16];
+        let mut span_id = [0u8; 8];
+        let mut flags = [0u8; 1];
+        decode_hex(fields[1], &mut trace_id)?;
+        decode_hex(fields[2], &mut span_id)?;
+        decode_hex(fields[3], &mut flags)?;
+        if trace_id == [0u8; 16] || span_id == [0u8; 8] {
+            return None;
+        }
+        Some(TraceContext {
+            trace_id,
+            span_id,
+            sampled: flags[0] & 0x01 == 0x01,
+        })
+    }
+
+    /// The `traceparent` of the first comment of `sql` having one, in the `key='value'` pairs
+    /// of sqlcommenter.
+    pub fn from_sql(sql: &str) -> Option<Self> {
+        let mut rest = sql;
+        while let Some(start) = rest.find("/*") {
+            let comment = &rest[start + 2..];
+            let end = comment.find("*/")?;
+            let context = comment[..end].split(',').find_map(|pair| {
+                let mut key_value = pair.splitn(2, '=');
+                match (key_value.next(), key_value.next()) {
+                    (Some(key), Some(value)) if key.trim() == TRACEPARENT => Self::parse(value.trim().trim_matches('\'')),
+                    _ => None,
+                }
+            });
+            if context.is_some() {
+                return context;
+            }
+            rest = &comment[end + 2..];
+        }
+        None
+    }
+
+    /// The `traceparent` connection attribute.
+    pub fn from_connect_attrs(connect_attrs: &[(String, String)]) -> Option<Self> {
+        connect_attrs.iter()
+            .find(|(key, _)| key == TRACEPARENT)
+            .and_then(|(_, value)| Self::parse(value))
+    }
+
+    pub fn is_sampled(&self) -> bool {
+        self.sampled
+    }
+
+    /// As a `traceparent` value.
+    pub fn to_traceparent(&self) -> String {
+        format!("00-{}-{}-{}", encode_hex(&self.trace_id), encode_hex(&self.span_id), if self.sampled { "01" } else { "00" })
+    }
+}
+
+fn decode_hex(hex: &str, bytes: &mut [u8]) -> Option<()> {
+    if hex.len() != bytes.len() * 2 {
+        return None;
+    }
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(())
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_nanos() as u64).unwrap_or(0)
+}
+
+#[derive(Debug, Clone)]
+pub struct Span {
+    context: TraceContext,
+    parent_span_id: Option<[u8; 8]>,
+    name: String,
+    kind: u8,
+    start: u64,
+    end: u64,
+    attributes: Vec<(&'static str, String)>,
+    error: Option<String>,
+}
+
+impl Span {
+    /// A span started now under `parent`, or the first of a new trace.
+    fn new(name: &str, kind: u8, parent: Option<TraceContext>) -> Self {
+        let mut rng = rand::thread_rng();
+        let (trace_id, parent_span_id) = match parent {
+            Some(parent) => (parent.trace_id, Some(parent.span_id)),
+            None => (rng.gen(), None),
+        };
+        Span {
+            context: TraceContext {
+                trace_id,
+                span_id: rng.gen(),
+                sampled: true,
+            },
+            parent_span_id,
+            name: name.to_string(),
+            kind,
+            start: now_nanos(),
+            end: 0,
+            attributes: vec![],
+            error: None,
+        }
+    }
+
+    /// The context the children of the span go under.
+    pub fn context(&self) -> TraceContext {
+        self.context
+    }
+
+    pub fn attribute(&mut self, key: &'static str, value: String) {
+        self.attributes.push((key, value));
+    }
+
+    /// Ends the span now and exports it, failed with `error` when given.
+    pub fn end(mut self, error: Option<String>) {
+        self.end = now_nanos();
+        self.error = error;
+        if let Some(tracer) = tracer() {
+            tracer.record(self);
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let status = match &self.error {
+            Some(message) => serde_json::json!({ "code": STATUS_CODE_ERROR, "message": message }),
+            None => serde_json::json!({}),
+        };
+        serde_json::json!({
+            "traceId": encode_hex(&self.context.trace_id),
+            "spanId": encode_hex(&self.context.span_id),
+            "parentSpanId": self.parent_span_id.map_or("".to_string(), |parent_span_id| encode_hex(&parent_span_id)),
+            "name": self.name,
+            "kind": self.kind,
+            "startTimeUnixNano": self.start.to_string(),
+            "endTimeUnixNano": self.end.to_string(),
+            "attributes": self.attributes.iter()
+                .map(|(key, value)| serde_json::json!({ "key": key, "value": { "stringValue": value } }))
+                .collect::<Vec<_>>(),
+            "status": status,
+        })
+    }
+}
+
+/// A span under `parent`, or the first of a new trace when sampled. None while tracing is
+/// disabled and for the traces not sampled.
+fn start_span(name: &str, kind: u8, parent: Option<TraceContext>) -> Option<Span> {
+    let tracer = tracer()?;
+    match parent {
+        Some(parent) if parent.sampled => Some(Span::new(name, kind, Some(parent))),
+        Some(_) => None,
+        None if tracer.sample() => Some(Span::new(name, kind, None)),
+        None => None,
+    }
+}
+
+/// Starts the span of a session once it logged in, None unless it is traced.
+pub fn start_session(session_ctx: &SessionContext, client_addr: SocketAddr) -> Option<Span> {
+    let parent = TraceContext::from_connect_attrs(&session_ctx.get_connect_attrs());
+    let mut span = start_span("session", SPAN_KIND_SERVER, parent)?;
+    span.attribute("db.system", "mysql".to_string());
+    span.attribute("db.user", session_ctx.get_user_name());
+    span.attribute("db.name", session_ctx.get_database());
+    span.attribute("session.id", session_ctx.get_thread_id().to_string());
+    span.attribute("net.peer.name", client_addr.to_string());
+    Some(span)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Parse,
+    Route,
+    Execute,
+    Serialize,
+}
+
+impl Phase {
+    fn name(&self) -> &'static str {
+        match self {
+            Phase::Parse => "parse",
+            Phase::Route => "route",
+            Phase::Execute => "backend execute",
+            Phase::Serialize => "serialize",
+        }
+    }
+}
+
+/// The span of a statement and of the phase it is in.
+#[derive(Debug)]
+pub struct StatementTrace {
+    span: Span,
+    phase: Option<Span>,
+}
+
+impl StatementTrace {
+    /// Starts the trace of the statement `sql` of the session traced in `session`, None unless
+    /// the statement is traced.
+    pub fn start(sql: &str, session: Option<&Span>) -> Option<Self> {
+        let parent = match TraceContext::from_sql(sql) {
+            Some(parent) => parent,
+            None => session?.context(),
+        };
+        let mut span = start_span("statement", SPAN_KIND_SERVER, Some(parent))?;
+        span.attribute("db.system", "mysql".to_string());
+        span.attribute("db.statement", parser::sql::fingerprint(sql).or_else(|| corpus::normalize(sql)).unwrap_or_else(|| sql.to_string()));
+        Some(StatementTrace {
+            span,
+            phase: None,
+        })
+    }
+
+    /// Ends the phase the statement is in and starts `phase`.
+    pub fn enter(&mut self, phase: Phase) {
+        if let Some(ended) = self.phase.take() {
+            ended.end(None);
+        }
+        let kind = if phase == Phase::Execute { SPAN_KIND_CLIENT } else { SPAN_KIND_INTERNAL };
+        self.phase = Some(Span::new(phase.name(), kind, Some(self.span.context())));
+    }
+
+    /// Ends the phase and the statement, failed with `error` when given.
+    pub fn finish(mut self, error: Option<String>) {
+        if let Some(ended) = self.phase.take() {
+            ended.end(None);
+        }
+        self.span.end(error);
+    }
+}
+
+/// Marks the statement of the session entering `phase`, when it is traced.
+pub fn enter(session_ctx: &mut SessionContext, phase: Phase) {
+    if let Some(trace) = session_ctx.get_statement_trace_mut() {
+        trace.enter(phase);
+    }
+}
+
+/// Posts batches of spans to an OTLP/HTTP endpoint as JSON.
+pub struct OtlpExporter {
+    endpoint: String,
+    service_name: String,
+    runtime: tokio::runtime::Runtime,
+}
+
+impl OtlpExporter {
+    pub fn new(endpoint: &str, service_name: &str) -> Result<Self, String> {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().map_err(|e| e.to_string())?;
+        Ok(OtlpExporter {
+            endpoint: endpoint.to_string(),
+            service_name: service_name.to_string(),
+            runtime,
+        })
+    }
+
+    pub fn to_json(&self, spans: &[Span]) -> serde_json::Value {
+        serde_json::json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{ "key": "service.name", "value": { "stringValue": self.service_name } }],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "martlet-mesh" },
+                    "spans": spans.iter().map(|span| span.to_json()).collect::<Vec<_>>(),
+                }],
+            }],
+        })
+    }
+
+    pub fn export(&mut self, spans: &[Span]) -> Result<(), String> {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.endpoint.as_str())
+            .header("content-type", "application/json")
+            .body(Body::from(self.to_json(spans).to_string()))
+            .map_err(|e| e.to_string())?;
+        let response = self.runtime.block_on(async {
+            tokio::time::timeout(Duration::from_secs(5), Client::new().request(request)).await
+        });
+        match response {
+            Ok(Ok(response)) if response.status().is_success() => Ok(()),
+            Ok(Ok(response)) => Err(format!("the OTLP endpoint answered {}", response.status())),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err("no answer from the OTLP endpoint within 5s".to_string()),
+        }
+    }
+}
+
+fn export_spans(mut exporter: OtlpExporter, spans: Receiver<Span>) {
+    // Ends once the tracer is replaced and its last span exported.
+    while let Ok(span) = spans.recv() {
+        let mut batch = vec![span];
+        while batch.len() < BATCH_SIZE {
+            match spans.try_recv() {
+                Ok(span) => batch.push(span),
+                Err(_) => break,
+            }
+        }
+        if let Err(e) = exporter.export(&batch) {
+            println!("error on exporting {} spans; error = {}", batch.len(), e);
+        }
+    }
+}
+
+pub struct Tracer {
+    spans: SyncSender<Span>,
+    sample_percent: u32,
+    dropped: AtomicU64,
+}
+
+impl Tracer {
+    pub fn new(exporter: OtlpExporter, queue_size: usize, sample_percent: u32) -> Self {
+        let (spans, receiver) = mpsc::sync_channel(queue_size);
+        thread::spawn(move || export_spans(exporter, receiver));
+        Tracer {
+            spans,
+            sample_percent,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether a trace the mesh starts is sampled.
+    fn sample(&self) -> bool {
+        self.sample_percent >= 100 || rand::thread_rng().gen_range(0..100) < self.sample_percent
+    }
+
+    pub fn record(&self, span: Span) {
+        match self.spans.try_send(span) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                if self.dropped.fetch_add(1, Ordering::Relaxed) % 10000 == 0 {
+                    println!("Tracing queue is full, spans are dropped");
+                }
+            }
+        }
+    }
+
+    /// Spans dropped on a full queue.
+    pub fn get_dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+lazy_static! {
+    static ref TRACER: RwLock<Option<Arc<Tracer>>> = RwLock::new(None);
+}
+
+pub fn configure_tracing(config: &TracingConfig) -> Result<(), String> {
+    let tracer = if config.is_enabled() {
+        let exporter = OtlpExporter::new(&config.get_endpoint(), &config.get_service_name())?;
+        Some(Arc::new(Tracer::new(exporter, config.get_queue_size(), config.get_sample_percent())))
+    } else {
+        None
+    };
+    *TRACER.write().unwrap() = tracer;
+    Ok(())
+}
+
+pub fn tracer() -> Option<Arc<Tracer>> {
+    TRACER.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OtlpExporter, Span, TraceContext, SPAN_KIND_SERVER};
+
+    const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    #[test]
+    fn test_trace_context() {
+        let context = TraceContext::parse(TRACEPARENT).unwrap();
+        assert!(context.is_sampled());
+        assert_eq!(context.to_traceparent(), TRACEPARENT);
+        assert!(!TraceContext::parse("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00").unwrap().is_sampled());
+        assert!(TraceContext::parse("00-00000000000000000000000000000000-b7ad6b7169203331-01").is_none());
+        assert!(TraceContext::parse("00-0af7651916cd43dd8448eb211c80319c-b7ad6b71692033-01").is_none());
+        assert!(TraceContext::parse("ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01").is_none());
+
+        let sql = format!("SELECT * FROM t_order /*controller='order',traceparent='{}'*/", TRACEPARENT);
+        assert_eq!(TraceContext::from_sql(&sql), Some(context));
+        let sql = format!("/* TIMEOUT(100) */ SELECT 1 /*traceparent='{}'*/", TRACEPARENT);
+        assert_eq!(TraceContext::from_sql(&sql), Some(context));
+        assert!(TraceContext::from_sql("SELECT '/*traceparent' FROM t_order").is_none());
+        let connect_attrs = vec![("_client_name".to_string(), "libmysql".to_string()), ("traceparent".to_string(), TRACEPARENT.to_string())];
+        assert_eq!(TraceContext::from_connect_attrs(&connect_attrs), Some(context));
+    }
+
+    #[test]
+    fn test_otlp_json() {
+        let parent = TraceContext::parse(TRACEPARENT).unwrap();
+        let mut span = Span::new("statement", SPAN_KIND_SERVER, Some(parent));
+        span.attribute("db.statement", "SELECT * FROM t_order WHERE id = ?".to_string());
+        span.error = Some("1146 Table 'martlet.t_order' doesn't exist".to_string());
+        let exporter = OtlpExporter::new("http://localhost:4318/v1/traces", "martlet-mesh").unwrap();
+        let json = exporter.to_json(&[span]);
+
+        let resource_spans = &json["resourceSpans"][0];
+        assert_eq!(resource_spans["resource"]["attributes"][0]["value"]["stringValue"], "martlet-mesh");
+        let span = &resource_spans["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"], "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(span["parentSpanId"], "b7ad6b7169203331");
+        assert_eq!(span["spanId"].as_str().unwrap().len(), 16);
+        assert_eq!(span["kind"], 2);
+        assert_eq!(span["attributes"][0]["key"], "db.statement");
+        assert_eq!(span["status"]["code"], 2);
+    }
+}
@@ -0,0 +1,125 @@
+//! Routing statements to segments by rule and by hint, see `QueryRoutingConfig` and `hint`.
+//!
+//! A statement matching a rule on its tables, its user or its `/*+ route=<hint> */` hint runs
+//! on the segment the rule names instead of the session's backend, e.g. to move the traffic of
+//! a table to a new segment step by step during a migration. A rule with `percent` only routes
+//! that share of the sessions, picked by session id so a session stays on one side.
+//!
+//! Without a matching rule, a `SHARD(<key>=<value>)` hint runs the statement on the data segment
+//! the rules place those values on. `MASTER` runs it on the primary of the segment it goes to.
+//! Statements of a transaction stay on the connection the transaction holds.
+
+use sqlparser::ast::Statement;
+
+use data_panel_common::config::config::{MeshConfig, QueryRoutingConfig, QueryRoutingRule};
+
+use crate::discovery;
+use crate::discovery::database::balance;
+use crate::discovery::database::rules::current_rules;
+use crate::handler::database::parser::sql::hint::SQLHints;
+use crate::handler::database::parser::sql::statement_tables;
+use crate::handler::database::route_cache::RoutePlan;
+use crate::session::mysql::SessionContext;
+
+fn rule_matches(rule: &QueryRoutingRule, tables: &[String], user: &str, hints: &SQLHints, session_id: u64) -> bool {
+    (rule.get_table().is_empty() || tables.iter().any(|table| table.eq_ignore_ascii_case(&rule.get_table())))
+        && (rule.get_user().is_empty() || rule.get_user() == user)
+        && (rule.get_hint().is_empty() || hints.get_route() == Some(rule.get_hint().as_str()))
+        && session_id % 100 < rule.get_percent() as u64
+}
+
+/// The first rule matching `statement`.
+pub fn matching_rule<'a>(config: &'a QueryRoutingConfig, statement: &Statement, hints: &SQLHints, user: &str, session_id: u64) -> Option<&'a QueryRoutingRule> {
+    if !config.is_enabled() || config.get_rules().is_empty() {
+        return None;
+    }
+    let tables = statement_tables(statement);
+    config.get_rules().iter().find(|rule| rule_matches(rule, &tables, user, hints, session_id))
+}
+
+/// The segment the first rule matching `statement` routes it to.
+pub fn routed_segment(config: &QueryRoutingConfig, statement: &Statement, hints: &SQLHints, user: &str, session_id: u64) -> Option<String> {
+    matching_rule(config, statement, hints, user, session_id).map(|rule| rule.get_segment())
+}
+
+/// The segment group, like `data-200`, the `SHARD` hint places every distributed table of
+/// `statement` on, None unless that is a single one.
+fn shard_group(statement: &Statement, hints: &SQLHints) -> Option<String> {
+    let bindings = hints.get_shard_bindings();
+    if bindings.is_empty() {
+        return None;
+    }
+    let rules = current_rules()?;
+    match RoutePlan::build(&rules, statement, &bindings).data_segments().as_slice() {
+        [segment] => Some(format!("data-{}", segment)),
+        _ => None,
+    }
+}
+
+/// The primary of the group of `segment`, `data-100/primary` for `data-100/mirrors`.
+pub fn primary_of(segment: &str) -> String {
+    format!("{}/primary", segment.split('/').next().unwrap_or(segment))
+}
+
+/// The segment the rules or the hints route `statement` to, with what routed it there: `rule
+/// <name>`, `shard hint` or `master hint`. None to run it on the session's backend.
+pub fn route_decision(statement: &Statement, hints: &SQLHints, session_ctx: &SessionContext) -> Option<(String, String)> {
+    if session_ctx.has_pinned_conn() {
+        return None;
+    }
+    let backend_segment = MeshConfig::get_backend_config().get_segment();
+    let routing_config = MeshConfig::get_query_routing_config();
+    let (segment, reason) = matching_rule(&routing_config, statement, hints, &session_ctx.get_user_name(), session_ctx.get_thread_id())
+        .map(|rule| (rule.get_segment(), format!("rule {}", rule.get_name())))
+        .or_else(|| shard_group(statement, hints).map(|group| {
+            // Reads spread over mirrors keep doing so on the data segment of the hint.
+            let role = if balance::mirror_group(&backend_segment).is_some() { "mirrors" } else { "primary" };
+            (format!("{}/{}", group, role), "shard hint".to_string())
+        }))
+        .or_else(|| if hints.is_master() { Some((backend_segment, "master hint".to_string())) } else { None })
+        .filter(|(segment, _)| !segment.is_empty())?;
+    let segment = if hints.is_master() { primary_of(&segment) } else { segment };
+    Some((segment, reason))
+}
+
+/// The backend url the rules or the hints route `statement` to, None to run it on the
+/// session's backend.
+pub fn route(statement: &Statement, hints: &SQLHints, session_ctx: &SessionContext) -> Option<String> {
+    let (segment, _) = route_decision(statement, hints, session_ctx)?;
+    let database_url = discovery::database::segment_url(&segment);
+    if database_url.is_none() {
+        println!("error on routing to segment {}; error = \"no such segment\"", segment);
+    }
+    database_url
+}
+
+#[cfg(test)]
+mod tests {
+    use data_panel_common::config::config::{QueryRoutingConfig, QueryRoutingRule};
+
+    use crate::handler::database::parser::sql::hint::SQLHints;
+    use crate::handler::database::parser::sql::mysql::parser;
+
+    use super::{primary_of, routed_segment};
+
+    #[test]
+    fn test_routed_segment() {
+        let config = QueryRoutingConfig::new(vec![
+            QueryRoutingRule::new("canary", "data-300/primary").hint("canary"),
+            QueryRoutingRule::new("orders", "data-200/primary").table("t_order").percent(10),
+            QueryRoutingRule::new("reports", "data-100/mirrors").user("report"),
+        ]);
+        let routed = |sql: &str, user: &str, session_id: u64| {
+            let statement = parser(sql.to_string()).unwrap().pop().unwrap();
+            routed_segment(&config, &statement, &SQLHints::parse(sql), user, session_id)
+        };
+        assert_eq!(routed("SELECT /*+ route=canary */ * FROM t_user", "app", 55), Some("data-300/primary".to_string()));
+        assert_eq!(routed("SELECT * FROM t_order WHERE id = 1", "app", 105), Some("data-200/primary".to_string()));
+        assert_eq!(routed("UPDATE t_order SET status = 'PAID' WHERE id = 1", "app", 55), None);
+        assert_eq!(routed("SELECT * FROM t_user", "report", 55), Some("data-100/mirrors".to_string()));
+        assert_eq!(routed("SELECT * FROM t_user", "app", 55), None);
+
+        assert_eq!(primary_of("data-100/mirrors"), "data-100/primary");
+        assert_eq!(primary_of("meta/mirror-1"), "meta/primary");
+    }
+}
@@ -0,0 +1,357 @@
+//! Session transactions.
+//!
+//! A transaction runs on one backend connection, pinned to the session from its first
+//! statement until COMMIT or ROLLBACK. `SET autocommit` and `SET TRANSACTION ISOLATION LEVEL`
+//! are answered by the mesh: while autocommit is off it starts the backend transactions itself,
+//! so pooled connections always go back with autocommit on.
+//!
+//! Writes of the transaction to several data segments run in XA branches next to it, see `xa`,
+//! committed and rolled back together with it by the mesh.
+//!
+//! The temporary tables a session creates only exist on the connection it created them on,
+//! which stays pinned to the session, transaction or not, until it dropped them all.
+//!
+//! Outside of transactions and temporary tables, sessions share the pooled connections
+//! statement by statement, see `PoolConfig`. A statement leaving state on its connection that
+//! the mesh cannot replay, like a user lock or a prepared statement, pins it to the session
+//! until it closes.
+
+use bytes::Bytes;
+use mysql::prelude::Queryable;
+use sqlparser::ast::{Statement, TransactionMode};
+
+use data_panel_common::common::Error;
+
+use crate::handler::database::lifecycle::BackendConn;
+use crate::handler::database::parser::sql::{analyse_statement_context, unquoted_table, SQLStatementContext};
+use crate::handler::database::variables;
+use crate::handler::database::mysql::err_payloads;
+use crate::handler::database::mysql::rdbc::err_payload;
+use crate::protocol::database::{DatabasePacket, PacketPayload};
+use crate::protocol::database::mysql::constant::MySQLServerErrorCode;
+use crate::protocol::database::mysql::packet::{MySQLOKPacket, MySQLPacketPayload};
+use crate::session::mysql::SessionContext;
+
+/// The value of a `SET autocommit` statement.
+fn autocommit_value(statement: &Statement) -> Option<bool> {
+    match statement {
+        Statement::SetVariable { variable, value, .. } if value.len() == 1 => {
+            let name = variable.value.trim_start_matches('@');
+            if !name.rsplit('.').next().unwrap_or(name).eq_ignore_ascii_case("autocommit") {
+                return None;
+            }
+            match value[0].to_string().to_uppercase().as_str() {
+                "1" | "ON" | "TRUE" => Some(true),
+                "0" | "OFF" | "FALSE" => Some(false),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn ok_payloads(session_ctx: &SessionContext) -> Option<Vec<Bytes>> {
+    let mut ok_packet = MySQLOKPacket::new(1, 0, 0);
+    ok_packet.set_status_flags(session_ctx.get_status_flags());
+    let mut ok_payload = MySQLPacketPayload::new();
+    let ok_payload = DatabasePacket::encode(&mut ok_packet, &mut ok_payload);
+    Some(vec![ok_payload.get_payload()])
+}
+
+/// Commits the open transaction, if any, and releases its connection. Its XA branches are
+/// prepared first and commit once the connection did.
+fn commit(session_ctx: &mut SessionContext) -> mysql::Result<()> {
+    session_ctx.set_in_transaction(false);
+    let xa = match session_ctx.take_xa_transaction() {
+        Some(mut xa) => match xa.prepare() {
+            Ok(()) => Some(xa),
+            Err(e) => {
+                xa.rollback();
+                abort(session_ctx);
+                return Err(e);
+            }
+        },
+        None => None,
+    };
+    if let Some(mut conn) = session_ctx.take_pinned_conn() {
+        if let Err(e) = conn.query_drop("COMMIT") {
+            conn.discard();
+            session_ctx.clear_temporary_tables();
+            if let Some(xa) = xa {
+                xa.rollback();
+            }
+            return Err(e);
+        }
+        if session_ctx.has_temporary_tables() || session_ctx.is_holding_conn() {
+            session_ctx.pin_conn(conn);
+        }
+    }
+    match xa {
+        Some(xa) => xa.commit_prepared(),
+        None => Ok(()),
+    }
+}
+
+/// Answers COMMIT and ROLLBACK of a transaction with XA branches, which the mesh finishes
+/// together with the connection it holds.
+fn finish_xa(statement: &Statement, session_ctx: &mut SessionContext) -> Option<Vec<Bytes>> {
+    if !session_ctx.has_xa_transaction() {
+        return None;
+    }
+    match statement {
+        Statement::Commit { chain: false } => match commit(session_ctx) {
+            Ok(()) => ok_payloads(session_ctx),
+            Err(e) => Some(vec![err_payload(e)]),
+        },
+        Statement::Rollback { chain: false, savepoint: None } => {
+            if let Some(xa) = session_ctx.take_xa_transaction() {
+                xa.rollback();
+            }
+            // The ROLLBACK itself goes to the connection of the transaction.
+            None
+        }
+        Statement::Commit { .. } | Statement::Rollback { .. } => {
+            let message = "AND CHAIN and savepoints of a transaction writing to several segments".to_string();
+            Some(vec![err_payload(Error::Protocol(message))])
+        }
+        _ => None,
+    }
+}
+
+/// Answers the statements that only change the session's transaction state.
+pub fn intercept(statement: &Statement, session_ctx: &mut SessionContext) -> Option<Vec<Bytes>> {
+    if let Some(payloads) = finish_xa(statement, session_ctx) {
+        return Some(payloads);
+    }
+    if let Some(autocommit) = autocommit_value(statement) {
+        // Turning autocommit on commits the open transaction, as MySQL does.
+        if autocommit && session_ctx.is_in_transaction() {
+            if let Err(e) = commit(session_ctx) {
+                return Some(vec![err_payload(e)]);
+            }
+        }
+        session_ctx.set_autocommit(autocommit);
+        return ok_payloads(session_ctx);
+    }
+    if let Statement::SetTransaction { session, modes } = statement {
+        let mut levels: Vec<String> = modes.iter()
+            .filter_map(|mode| match mode {
+                TransactionMode::IsolationLevel(level) => Some(level.to_string()),
+                _ => None,
+            })
+            .collect();
+        // Access modes go to the backend as before.
+        if levels.is_empty() || levels.len() != modes.len() {
+            return None;
+        }
+        let level = levels.pop();
+        if *session {
+            session_ctx.set_isolation_level(level);
+        } else if session_ctx.is_in_transaction() {
+            let message = "Transaction characteristics can't be changed while a transaction is in progress".to_string();
+            return err_payloads(1, MySQLServerErrorCode::ErCantChangeTxCharacteristics, message);
+        } else {
+            session_ctx.set_next_isolation_level(level);
+        }
+        return ok_payloads(session_ctx);
+    }
+    None
+}
+
+/// A connection for a new transaction, with the session's isolation level applied, and the
+/// transaction started when `start` is set.
+fn begin(session_ctx: &mut SessionContext, start: bool) -> mysql::Result<BackendConn> {
+    let mut conn = variables::connect(session_ctx)?;
+    let level = session_ctx.take_transaction_isolation_level();
+    let prepared = level.map_or(Ok(()), |level| conn.query_drop(format!("SET TRANSACTION ISOLATION LEVEL {}", level)))
+        .and_then(|_| if start { conn.query_drop("START TRANSACTION") } else { Ok(()) });
+    match prepared {
+        Ok(()) => Ok(conn),
+        Err(e) => {
+            conn.discard();
+            Err(e)
+        }
+    }
+}
+
+/// The table `statement` creates as a temporary table, as the analyse pass finds it.
+fn created_temporary_table(statement: &Statement) -> Option<String> {
+    if !matches!(statement, Statement::CreateTable { .. }) {
+        return None;
+    }
+    match analyse_statement_context(statement)? {
+        SQLStatementContext::Ddl(ddl_ctx) if ddl_ctx.is_temporary() => Some(unquoted_table(ddl_ctx.get_name())),
+        _ => None,
+    }
+}
+
+/// Records the temporary tables `statement`, which ran, created or dropped.
+fn track_temporary_tables(statement: &Statement, session_ctx: &mut SessionContext) {
+    if let Some(table) = created_temporary_table(statement) {
+        session_ctx.add_temporary_table(table);
+    } else if let Statement::Drop { names, .. } = statement {
+        for name in names.iter() {
+            session_ctx.remove_temporary_table(&unquoted_table(&name.to_string()));
+        }
+    }
+}
+
+/// Whether `sql` leaves state on its connection the mesh cannot replay on another one: user
+/// locks, locked tables, user variables a query assigns, prepared statements or a row count
+/// kept for `FOUND_ROWS()`.
+fn binds_connection(sql: &str) -> bool {
+    let sql: String = sql.to_uppercase().split_whitespace().collect();
+    ["GET_LOCK(", "LOCKTABLE", ":=", "INTO@", "SQL_CALC_FOUND_ROWS"].iter().any(|bound| sql.contains(bound))
+        || sql.starts_with("PREPARE")
+}
+
+/// Whether the session keeps the connection `sql` runs on until it closes.
+pub fn holds_connection(sql: &str) -> bool {
+    binds_connection(sql)
+}
+
+/// Updates the transaction state before `statement` runs, pinning a connection when it starts
+/// a transaction, when autocommit is off and none is open yet, when it creates a temporary
+/// table, or when the session is to hold it.
+pub fn pin(statement: &Statement, session_ctx: &mut SessionContext) -> mysql::Result<()> {
+    match statement {
+        Statement::StartTransaction { .. } => {
+            if !session_ctx.has_pinned_conn() {
+                let conn = begin(session_ctx, false)?;
+                session_ctx.pin_conn(conn);
+            }
+            session_ctx.set_in_transaction(true);
+        }
+        Statement::Commit { chain } | Statement::Rollback { chain, savepoint: None } => {
+            session_ctx.set_in_transaction(*chain && session_ctx.has_pinned_conn());
+        }
+        Statement::Rollback { .. } => {}
+        _ if !session_ctx.is_autocommit() && !session_ctx.is_in_transaction() => {
+            let conn = begin(session_ctx, true)?;
+            session_ctx.pin_conn(conn);
+            session_ctx.set_in_transaction(true);
+        }
+        _ => {}
+    }
+    let holding = holds_connection(&statement.to_string());
+    if !session_ctx.has_pinned_conn() && (holding || created_temporary_table(statement).is_some()) {
+        let conn = variables::connect(session_ctx)?;
+        session_ctx.pin_conn(conn);
+    }
+    if holding {
+        session_ctx.set_holding_conn(true);
+    }
+    Ok(())
+}
+
+/// Takes back the connection `statement` ran on, pinned again while the transaction is open, the
+/// session has temporary tables on it or holds it, and released otherwise.
+pub fn settle(statement: &Statement, conn: Option<BackendConn>, failed: bool, session_ctx: &mut SessionContext) {
+    let conn = match conn {
+        Some(conn) => conn,
+        None => return,
+    };
+    if !failed {
+        track_temporary_tables(statement, session_ctx);
+    }
+    match statement {
+        Statement::StartTransaction { .. } | Statement::Commit { .. } | Statement::Rollback { savepoint: None, .. } if failed => {
+            // Where the backend transaction stands is unknown, the connection is not reused.
+            session_ctx.set_in_transaction(false);
+            session_ctx.clear_temporary_tables();
+            session_ctx.set_holding_conn(false);
+            conn.discard();
+        }
+        _ if session_ctx.is_in_transaction() || session_ctx.has_temporary_tables() || session_ctx.is_holding_conn() => session_ctx.pin_conn(conn),
+        _ => {}
+    }
+}
+
+/// Rolls back the transaction a closing session left open. Its temporary tables and the state of
+/// a held connection go with the reset of the connection before it is reused.
+pub fn abort(session_ctx: &mut SessionContext) {
+    if let Some(xa) = session_ctx.take_xa_transaction() {
+        xa.rollback();
+    }
+    if let Some(mut conn) = session_ctx.take_pinned_conn() {
+        if session_ctx.has_temporary_tables() || session_ctx.is_holding_conn() {
+            conn.reset_on_release();
+        }
+        if let Err(e) = conn.query_drop("ROLLBACK") {
+            println!("error on rolling back an abandoned transaction; error = {:?}", e);
+            conn.discard();
+        }
+    }
+    session_ctx.set_in_transaction(false);
+    session_ctx.clear_temporary_tables();
+    session_ctx.set_holding_conn(false);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handler::database::parser;
+    use crate::session::mysql::SessionContext;
+
+    use super::{binds_connection, created_temporary_table, holds_connection, intercept, pin, track_temporary_tables};
+
+    #[test]
+    fn test_session_transaction_state() {
+        let mut session_ctx = SessionContext::new(1);
+        assert_eq!(session_ctx.get_status_flags(), 0x0002);
+
+        let statement = parser::sql::mysql::parser("SET autocommit = 0".to_string()).unwrap().pop().unwrap();
+        assert!(intercept(&statement, &mut session_ctx).is_some());
+        assert!(!session_ctx.is_autocommit());
+        assert_eq!(session_ctx.get_status_flags(), 0);
+
+        let statement = parser::sql::mysql::parser("SET TRANSACTION ISOLATION LEVEL READ COMMITTED".to_string()).unwrap().pop().unwrap();
+        assert!(intercept(&statement, &mut session_ctx).is_some());
+        assert_eq!(session_ctx.take_transaction_isolation_level(), Some("READ COMMITTED".to_string()));
+        assert_eq!(session_ctx.take_transaction_isolation_level(), None);
+
+        let statement = parser::sql::mysql::parser("SELECT 1".to_string()).unwrap().pop().unwrap();
+        assert!(intercept(&statement, &mut session_ctx).is_none());
+    }
+
+    #[test]
+    fn test_temporary_tables() {
+        let mut session_ctx = SessionContext::new(1);
+        let create = parser::sql::mysql::parser("CREATE TEMPORARY TABLE `tmp_ids` (id INT)".to_string()).unwrap().pop().unwrap();
+        assert_eq!(created_temporary_table(&create), Some("tmp_ids".to_string()));
+        let statement = parser::sql::mysql::parser("CREATE TABLE t_ids (id INT)".to_string()).unwrap().pop().unwrap();
+        assert_eq!(created_temporary_table(&statement), None);
+
+        track_temporary_tables(&create, &mut session_ctx);
+        assert!(session_ctx.has_temporary_tables());
+        let statement = parser::sql::mysql::parser("DROP TABLE t_ids".to_string()).unwrap().pop().unwrap();
+        track_temporary_tables(&statement, &mut session_ctx);
+        assert!(session_ctx.has_temporary_tables());
+        let statement = parser::sql::mysql::parser("DROP TABLE tmp_ids".to_string()).unwrap().pop().unwrap();
+        track_temporary_tables(&statement, &mut session_ctx);
+        assert!(!session_ctx.has_temporary_tables());
+    }
+
+    #[test]
+    fn test_bound_connections() {
+        assert!(binds_connection("SELECT GET_LOCK('job', 10)"));
+        assert!(binds_connection("select sql_calc_found_rows * from t_order limit 10"));
+        assert!(binds_connection("SELECT @total := COUNT(*) FROM t_order"));
+        assert!(binds_connection("SELECT id INTO @id FROM t_order LIMIT 1"));
+        assert!(binds_connection("LOCK TABLES t_order READ"));
+        assert!(binds_connection("PREPARE stmt FROM 'SELECT 1'"));
+        assert!(!binds_connection("SELECT * FROM t_order WHERE id = 1"));
+        assert!(!binds_connection("INSERT INTO t_order (id) VALUES (1)"));
+    }
+
+    #[test]
+    fn test_plain_statements_do_not_pin() {
+        // The default pool, without multiplexing: a plain SELECT takes no connection of its own.
+        let mut session_ctx = SessionContext::new(1);
+        let statement = parser::sql::mysql::parser("SELECT * FROM t_order WHERE id = 1".to_string()).unwrap().pop().unwrap();
+        assert!(!holds_connection(&statement.to_string()));
+        pin(&statement, &mut session_ctx).unwrap();
+        assert!(!session_ctx.has_pinned_conn());
+        assert!(!session_ctx.is_holding_conn());
+        assert!(holds_connection("SELECT GET_LOCK('job', 10)"));
+    }
+}
@@ -0,0 +1,242 @@
+//! Session variables.
+//!
+//! `SET` of session and user variables and `SET NAMES` are answered by the mesh, which records
+//! them in the session and replays them on every backend connection the session acquires. A
+//! connection they were replayed on is reset before it goes back to its pool, so they never
+//! leak into another session. `SELECT @@variable` of recorded variables is answered from the
+//! session, global variables go to the backend as before.
+
+use std::fmt;
+
+use bytes::Bytes;
+use mysql::prelude::Queryable;
+use sqlparser::ast::{Expr, SelectItem, SetExpr, SetVariableValue, Statement};
+
+use crate::common::arena::QueryArena;
+use crate::handler::database::lifecycle::{self, BackendConn};
+use crate::handler::database::mysql::rdbc::err_payload;
+use crate::handler::database::mysql::text_result_payloads;
+use crate::protocol::database::{DatabasePacket, PacketPayload};
+use crate::protocol::database::mysql::packet::{MySQLOKPacket, MySQLPacketPayload};
+use crate::session::mysql::SessionContext;
+
+/// The variables `SET NAMES` sets.
+const NAMES_VARIABLES: [&str; 3] = ["character_set_client", "character_set_connection", "character_set_results"];
+
+/// The recorded name of a variable as written in a statement: user variables keep their `@`,
+/// session variables lose `@@` and the `session.` or `local.` scope. None for globals.
+fn variable_name(name: &str) -> Option<String> {
+    let name = name.to_lowercase();
+    if !name.starts_with("@@") {
+        return Some(name);
+    }
+    let name = name.trim_start_matches("@@");
+    if name.starts_with("global.") || name.starts_with("persist.") || name.starts_with("persist_only.") {
+        return None;
+    }
+    Some(name.trim_start_matches("session.").trim_start_matches("local.").to_string())
+}
+
+/// The value as the client reads it back.
+fn displayed_value(value: &str) -> String {
+    let quoted = value.len() >= 2 && (value.starts_with('\'') && value.ends_with('\'') || value.starts_with('"') && value.ends_with('"'));
+    if quoted {
+        value[1..value.len() - 1].replace("''", "'")
+    } else {
+        value.to_string()
+    }
+}
+
+/// The statement setting a recorded variable on a backend connection.
+fn set_statement(name: &str, value: &str) -> String {
+    let mut statement = String::new();
+    write_set_statement(&mut statement, name, value);
+    statement
+}
+
+fn write_set_statement(out: &mut impl fmt::Write, name: &str, value: &str) {
+    let written = if name.starts_with('@') {
+        write!(out, "SET {} = {}", name, value)
+    } else {
+        write!(out, "SET SESSION {} = {}", name, value)
+    };
+    written.expect("writing to a string can't fail")
+}
+
+/// The variables `statement` sets and their values as SQL.
+fn assigned_variables(statement: &Statement) -> Option<Vec<(String, String)>> {
+    match statement {
+        Statement::SetVariable { hivevar: false, variable, value, .. } if !value.is_empty() => {
+            let name = variable_name(&variable.value)?;
+            let value = value.iter()
+                .map(|value| match value {
+                    SetVariableValue::Ident(ident) => ident.value.clone(),
+                    SetVariableValue::Literal(literal) => literal.to_string(),
+                })
+                .collect::<Vec<String>>()
+                .join(", ");
+            Some(vec![(name, value)])
+        }
+        Statement::SetNames { variable } => {
+            Some(NAMES_VARIABLES.iter().map(|name| (name.to_string(), variable.to_string())).collect())
+        }
+        _ => None,
+    }
+}
+
+fn ok_payloads(session_ctx: &SessionContext) -> Option<Vec<Bytes>> {
+    let mut ok_packet = MySQLOKPacket::new(1, 0, 0);
+    ok_packet.set_status_flags(session_ctx.get_status_flags());
+    let mut ok_payload = MySQLPacketPayload::new();
+    let ok_payload = DatabasePacket::encode(&mut ok_packet, &mut ok_payload);
+    Some(vec![ok_payload.get_payload()])
+}
+
+/// Answers `SET` of session variables and `SELECT @@variable` of recorded ones. The backend
+/// checks a `SET` first, on the connection of the open transaction if there is one.
+pub fn intercept(statement: &Statement, session_ctx: &mut SessionContext) -> Option<Vec<Bytes>> {
+    if let Some(assigned) = assigned_variables(statement) {
+        let pinned = session_ctx.has_pinned_conn();
+        let mut conn = match session_ctx.take_pinned_conn() {
+            Some(conn) => conn,
+            None => match lifecycle::connect(&session_ctx.get_backend_url()) {
+                Ok(conn) => conn,
+                Err(e) => return Some(vec![err_payload(e)]),
+            },
+        };
+        conn.reset_on_release();
+        let applied = assigned.iter().try_for_each(|(name, value)| conn.query_drop(set_statement(name, value)));
+        if pinned {
+            session_ctx.pin_conn(conn);
+        }
+        if let Err(e) = applied {
+            return Some(vec![err_payload(e)]);
+        }
+        for (name, value) in assigned {
+            session_ctx.set_variable(name, value);
+        }
+        return ok_payloads(session_ctx);
+    }
+    selected_variables(statement, session_ctx)
+}
+
+/// The result of a `SELECT` of nothing but session variables the session knows.
+fn selected_variables(statement: &Statement, session_ctx: &SessionContext) -> Option<Vec<Bytes>> {
+    let select = match statement {
+        Statement::Query(query) => match &query.body {
+            SetExpr::Select(select) if select.from.is_empty() && select.selection.is_none() => select,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let mut columns = Vec::with_capacity(select.projection.len());
+    let mut row = Vec::with_capacity(select.projection.len());
+    for item in select.projection.iter() {
+        let (expr, column) = match item {
+            SelectItem::UnnamedExpr(expr) => (expr, expr.to_string()),
+            SelectItem::ExprWithAlias { expr, alias } => (expr, alias.value.clone()),
+            _ => return None,
+        };
+        let written = match expr {
+            Expr::Identifier(ident) if ident.value.starts_with('@') => ident.value.clone(),
+            Expr::CompoundIdentifier(idents) if idents.first().map_or(false, |ident| ident.value.starts_with("@@")) => {
+                idents.iter().map(|ident| ident.value.as_str()).collect::<Vec<&str>>().join(".")
+            }
+            _ => return None,
+        };
+        let name = variable_name(&written)?;
+        let value = if name == "autocommit" {
+            if session_ctx.is_autocommit() { "1".to_string() } else { "0".to_string() }
+        } else {
+            displayed_value(&session_ctx.get_variable(&name)?)
+        };
+        columns.push(column);
+        row.push(value);
+    }
+    text_result_payloads(columns.iter().map(|column| column.as_str()).collect(), vec![row])
+}
+
+/// The statements that bring a backend connection to the session's variables.
+pub fn replay_statements(session_ctx: &SessionContext) -> Vec<String> {
+    session_ctx.get_variables().iter()
+        .map(|(name, value)| set_statement(name, value))
+        .collect()
+}
+
+/// Like `replay_statements`, written in the query arena.
+pub fn replay_statements_in<'a>(session_ctx: &SessionContext, arena: &QueryArena<'a>) -> &'a [&'a str] {
+    let mut statements = arena.new_vec();
+    for (name, value) in session_ctx.get_variables().iter() {
+        let mut statement = arena.new_string();
+        write_set_statement(&mut statement, name, value);
+        statements.push(statement.into_bump_str());
+    }
+    statements.into_bump_slice()
+}
+
+/// Runs `statements` on `conn`, which is then reset before it goes back to its pool.
+pub fn replay<S: AsRef<str>>(conn: &mut BackendConn, statements: &[S]) -> mysql::Result<()> {
+    if statements.is_empty() {
+        return Ok(());
+    }
+    conn.reset_on_release();
+    statements.iter().try_for_each(|statement| conn.query_drop(statement.as_ref()))
+}
+
+/// A backend connection for the session, with its variables replayed.
+pub fn connect(session_ctx: &SessionContext) -> mysql::Result<BackendConn> {
+    connect_to(session_ctx, &session_ctx.get_backend_url())
+}
+
+/// A connection to `database_url` for the session, with its variables replayed.
+pub fn connect_to(session_ctx: &SessionContext, database_url: &str) -> mysql::Result<BackendConn> {
+    let mut conn = lifecycle::connect(database_url)?;
+    match replay(&mut conn, &replay_statements(session_ctx)) {
+        Ok(()) => Ok(conn),
+        Err(e) => {
+            conn.discard();
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::arena::with_query_arena;
+    use crate::handler::database::parser;
+    use crate::session::mysql::SessionContext;
+
+    use super::{assigned_variables, intercept, replay_statements, replay_statements_in};
+
+    #[test]
+    fn test_session_variables() {
+        let mut session_ctx = SessionContext::new(1);
+        for sql in vec!["SET sql_mode = 'STRICT_TRANS_TABLES'", "SET NAMES utf8mb4", "SET @tenant = 7", "SET sql_mode = 'ANSI_QUOTES'"] {
+            let statement = parser::sql::mysql::parser(sql.to_string()).unwrap().pop().unwrap();
+            for (name, value) in assigned_variables(&statement).unwrap() {
+                session_ctx.set_variable(name, value);
+            }
+        }
+        assert_eq!(session_ctx.get_variable("sql_mode"), Some("'ANSI_QUOTES'".to_string()));
+        assert_eq!(session_ctx.get_variable("character_set_results"), Some("utf8mb4".to_string()));
+        assert_eq!(replay_statements(&session_ctx), vec![
+            "SET SESSION sql_mode = 'ANSI_QUOTES'",
+            "SET SESSION character_set_client = utf8mb4",
+            "SET SESSION character_set_connection = utf8mb4",
+            "SET SESSION character_set_results = utf8mb4",
+            "SET @tenant = 7",
+        ]);
+        let in_arena = with_query_arena(|arena| replay_statements_in(&session_ctx, arena).iter().map(|statement| statement.to_string()).collect::<Vec<String>>());
+        assert_eq!(in_arena, replay_statements(&session_ctx));
+
+        let statement = parser::sql::mysql::parser("SELECT @@sql_mode, @@autocommit AS ac, @tenant".to_string()).unwrap().pop().unwrap();
+        let payloads = intercept(&statement, &mut session_ctx).unwrap();
+        // The column count, 3 definitions, EOF, the row and EOF.
+        assert_eq!(payloads.len(), 7);
+        assert_eq!(payloads[5].as_ref(), &b"\x06\x0bANSI_QUOTES\x011\x017"[..]);
+
+        // Variables the session does not know go to the backend.
+        let statement = parser::sql::mysql::parser("SELECT @@version".to_string()).unwrap().pop().unwrap();
+        assert!(intercept(&statement, &mut session_ctx).is_none());
+    }
+}
@@ -0,0 +1,641 @@
+//! Two-phase commit of writes over several data segments, see `DistributedTransactionConfig`.
+//!
+//! A write whose rows are on more than one data segment runs in an XA transaction with a branch
+//! on the primary of every segment it touches. To commit, every branch is ended and, once the
+//! log holds the branches, prepared. The commit decision is appended to the log before any
+//! branch commits, so a branch failing to commit, or a mesh stopping half way, leaves it to the
+//! recovery: at startup the transactions of the log are finished, committed when the decision
+//! was logged and rolled back otherwise.
+//!
+//! Inside a client transaction the branches stay open until COMMIT. The connection the session
+//! holds commits after every branch is prepared and before the decision is logged, so its
+//! outcome is the one of the branches. A write failing on a branch rolls back the whole
+//! transaction.
+//!
+//! The log is compacted whenever no logged transaction is running any more, keeping only the
+//! transactions left to the recovery, so it does not grow with the transactions a mesh runs.
+//!
+//! In the best-effort mode the branches are local transactions instead, committed one after the
+//! other and retried from a queue when they fail, see `best_effort`.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::process;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use mysql::MySqlError;
+use mysql::prelude::Queryable;
+use sqlparser::ast::Statement;
+
+use data_panel_common::common::Error;
+use data_panel_common::config::config::{DistributedTransactionConfig, MeshConfig};
+
+use crate::common::store::{self, MetadataStore};
+use crate::discovery;
+use crate::discovery::database::rules::{current_rules, RulesVersion};
+use crate::handler::database::{best_effort, intent, lifecycle, transaction, variables};
+use crate::handler::database::lifecycle::BackendConn;
+use crate::handler::database::mysql::rdbc::err_payload;
+use crate::handler::database::parser::sql::mysql::bind_placeholders;
+use crate::handler::database::route_cache::RoutePlan;
+use crate::handler::database::sharded_insert::{self, InsertOutcome, InsertPlan, key_values, plan_insert, sql_literals};
+use crate::session::mysql::SessionContext;
+
+/// The branches of a transaction, logged before they are prepared.
+const PREPARE_RECORD: &str = "prepare";
+/// The decision to commit a prepared transaction.
+const COMMIT_RECORD: &str = "commit";
+/// Every branch of the transaction committed or rolled back.
+const END_RECORD: &str = "end";
+/// The namespace of the log in the metadata store, the records of a transaction keyed by xid.
+const STORE_NAMESPACE: &str = "xa";
+
+lazy_static! {
+    static ref XID_GENERATOR: AtomicU64 = AtomicU64::new(1);
+    static ref XID_PREFIX: String = {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+        format!("martlet-{}-{}", process::id(), started)
+    };
+    /// Keeps the records of concurrent transactions on lines of their own.
+    static ref XA_LOG: Mutex<()> = Mutex::new(());
+    /// The transactions with records in the log that are not finished yet.
+    static ref LOGGED_RUNNING: AtomicU64 = AtomicU64::new(0);
+}
+
+/// Unique across mesh restarts and instances sharing the backends.
+fn xid() -> String {
+    format!("{}-{}", *XID_PREFIX, XID_GENERATOR.fetch_add(1, Ordering::SeqCst))
+}
+
+/// Keeps `record` with the records of its transaction, dropping them all at its end record.
+fn store_record(store: &dyn MetadataStore, record: &str) -> Result<(), String> {
+    let mut fields = record.split_whitespace();
+    match (fields.next(), fields.next()) {
+        (Some(END_RECORD), Some(xid)) => store.delete(STORE_NAMESPACE, xid),
+        (_, Some(xid)) => {
+            let mut records = store.get(STORE_NAMESPACE, xid)?.unwrap_or_default();
+            records.extend_from_slice(format!("{}\n", record).as_bytes());
+            store.put(STORE_NAMESPACE, xid, &records)
+        }
+        _ => Err(format!("malformed XA record {}", record)),
+    }
+}
+
+/// Appends `record` to the log, or to the metadata store, and waits until it is on disk.
+fn append(record: &str) -> io::Result<()> {
+    let _guard = XA_LOG.lock().unwrap();
+    if let Some(store) = store::metadata_store() {
+        return store_record(&*store, record).map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(MeshConfig::get_distributed_transaction_config().get_log())?;
+    file.write_all(format!("{}\n", record).as_bytes())?;
+    file.sync_data()
+}
+
+/// Replaces the log at `path` with `log` at once, a crash leaves either of them.
+fn write_log(path: &str, log: &str) -> io::Result<()> {
+    let compacted = format!("{}.compact", path);
+    let mut file = File::create(&compacted)?;
+    file.write_all(log.as_bytes())?;
+    file.sync_data()?;
+    fs::rename(&compacted, path)
+}
+
+/// Rewrites the log at `path` with only the transactions it leaves unfinished.
+fn compact_log(path: &str) -> io::Result<()> {
+    let log = match fs::read_to_string(path) {
+        Ok(log) => log,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let left: String = unfinished(&log).iter().map(|transaction| transaction.records()).collect();
+    if left.len() == log.len() {
+        return Ok(());
+    }
+    write_log(path, &left)
+}
+
+/// Compacts the file log once no logged transaction is running, the metadata store drops the
+/// records of a transaction at its end record.
+fn compact_idle_log() {
+    let _guard = XA_LOG.lock().unwrap();
+    if store::metadata_store().is_some() || LOGGED_RUNNING.load(Ordering::SeqCst) > 0 {
+        return;
+    }
+    let path = MeshConfig::get_distributed_transaction_config().get_log();
+    if let Err(e) = compact_log(&path) {
+        println!("error on compacting XA log {}; error = {:?}", path, e);
+    }
+}
+
+pub fn no_such_segment(segment: &str) -> mysql::Error {
+    mysql::Error::MySqlError(MySqlError {
+        state: "HY000".to_string(),
+        message: format!("no such segment {}", segment),
+        code: 1105,
+    })
+}
+
+#[derive(Debug)]
+struct XaBranch {
+    segment: String,
+    conn: BackendConn,
+    /// What the branch ran, kept to run it again in the best-effort mode.
+    statements: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct XaTransaction {
+    xid: String,
+    branches: Vec<XaBranch>,
+    /// The log holds the branches, it gets an end record once they are finished.
+    logged: bool,
+    best_effort: bool,
+}
+
+impl Default for XaTransaction {
+    fn default() -> Self {
+        XaTransaction::new()
+    }
+}
+
+impl XaTransaction {
+    pub fn new() -> Self {
+        XaTransaction {
+            xid: xid(),
+            branches: vec![],
+            logged: false,
+            best_effort: MeshConfig::get_distributed_transaction_config().is_best_effort(),
+        }
+    }
+
+    pub fn get_xid(&self) -> &str {
+        &self.xid
+    }
+
+    /// The segments of the branches, in the order they started.
+    pub fn get_segments(&self) -> Vec<String> {
+        self.branches.iter().map(|branch| branch.segment.clone()).collect()
+    }
+
+    /// Runs `sql` in the branch on `segment`, e.g. `data-100/primary`, starting it on a
+    /// connection of the session when the transaction has none there yet.
+    pub fn execute(&mut self, segment: &str, sql: &str, session_ctx: &SessionContext) -> mysql::Result<InsertOutcome> {
+        let index = match self.branches.iter().position(|branch| branch.segment == segment) {
+            Some(index) => index,
+            None => {
+                let database_url = discovery::database::segment_url(segment).ok_or_else(|| no_such_segment(segment))?;
+                let mut conn = variables::connect_to(session_ctx, &database_url)?;
+                let start = if self.best_effort { "START TRANSACTION".to_string() } else { format!("XA START '{}'", self.xid) };
+                if let Err(e) = conn.query_drop(start) {
+                    conn.discard();
+                    return Err(e);
+                }
+                self.branches.push(XaBranch {
+                    segment: segment.to_string(),
+                    conn,
+                    statements: vec![],
+                });
+                self.branches.len() - 1
+            }
+        };
+        let branch = &mut self.branches[index];
+        let outcome = {
+            let result = branch.conn.query_iter(sql)?;
+            InsertOutcome::new(result.affected_rows(), result.last_insert_id().unwrap_or(0))
+        };
+        if self.best_effort {
+            branch.statements.push(sql.to_string());
+        }
+        Ok(outcome)
+    }
+
+    /// Ends every branch and prepares them once the log holds them, unless there is only one,
+    /// which commits in one phase.
+    pub fn prepare(&mut self) -> mysql::Result<()> {
+        if self.best_effort {
+            return Ok(());
+        }
+        for branch in self.branches.iter_mut() {
+            branch.conn.query_drop(format!("XA END '{}'", self.xid))?;
+        }
+        if self.branches.len() < 2 {
+            return Ok(());
+        }
+        append(&format!("{} {} {}", PREPARE_RECORD, self.xid, self.get_segments().join(" "))).map_err(mysql::Error::IoError)?;
+        self.logged = true;
+        LOGGED_RUNNING.fetch_add(1, Ordering::SeqCst);
+        for branch in self.branches.iter_mut() {
+            branch.conn.query_drop(format!("XA PREPARE '{}'", self.xid))?;
+        }
+        Ok(())
+    }
+
+    /// Commits the prepared branches once the session's connection committed. A branch failing
+    /// to commit once the decision is logged is left to the recovery, the transaction is
+    /// committed nonetheless.
+    pub fn commit_prepared(self) -> mysql::Result<()> {
+        self.finish(true)
+    }
+
+    fn finish(mut self, decided: bool) -> mysql::Result<()> {
+        let branches = std::mem::take(&mut self.branches);
+        if self.best_effort {
+            let branches = branches.into_iter().map(|branch| (branch.segment, branch.conn, branch.statements)).collect();
+            return best_effort::commit(&self.xid, branches, decided);
+        }
+        if branches.len() == 1 {
+            let mut branch = branches.into_iter().next().unwrap();
+            if let Err(e) = branch.conn.query_drop(format!("XA COMMIT '{}' ONE PHASE", self.xid)) {
+                branch.conn.discard();
+                return Err(e);
+            }
+            return Ok(());
+        }
+        if let Err(e) = append(&format!("{} {}", COMMIT_RECORD, self.xid)) {
+            self.branches = branches;
+            self.abandon();
+            return Err(mysql::Error::IoError(e));
+        }
+        let mut committed = true;
+        for mut branch in branches {
+            if let Err(e) = branch.conn.query_drop(format!("XA COMMIT '{}'", self.xid)) {
+                println!("error on committing XA transaction {} on {}, left to the recovery; error = {:?}", self.xid, branch.segment, e);
+                branch.conn.discard();
+                committed = false;
+            }
+        }
+        if committed {
+            if let Err(e) = append(&format!("{} {}", END_RECORD, self.xid)) {
+                println!("error on logging the end of XA transaction {}; error = {:?}", self.xid, e);
+            }
+        }
+        self.unlog();
+        Ok(())
+    }
+
+    /// The transaction is no longer running, its records are done with unless left to the
+    /// recovery.
+    fn unlog(&mut self) {
+        if !self.logged {
+            return;
+        }
+        self.logged = false;
+        if LOGGED_RUNNING.fetch_sub(1, Ordering::SeqCst) == 1 {
+            compact_idle_log();
+        }
+    }
+
+    /// Prepares and commits every branch, rolling them back when one fails to prepare.
+    pub fn commit(mut self) -> mysql::Result<()> {
+        match self.prepare() {
+            Ok(()) => self.finish(false),
+            Err(e) => {
+                self.abandon();
+                Err(e)
+            }
+        }
+    }
+
+    pub fn rollback(mut self) {
+        self.abandon();
+    }
+
+    fn abandon(&mut self) {
+        let mut rolled_back = true;
+        for mut branch in std::mem::take(&mut self.branches) {
+            let rollback = if self.best_effort {
+                "ROLLBACK".to_string()
+            } else {
+                // Fails for the branches already ended, which can roll back right away.
+                let _ = branch.conn.query_drop(format!("XA END '{}'", self.xid));
+                format!("XA ROLLBACK '{}'", self.xid)
+            };
+            if let Err(e) = branch.conn.query_drop(rollback) {
+                println!("error on rolling back XA transaction {} on {}; error = {:?}", self.xid, branch.segment, e);
+                branch.conn.discard();
+                rolled_back = false;
+            }
+        }
+        if self.logged && rolled_back {
+            if let Err(e) = append(&format!("{} {}", END_RECORD, self.xid)) {
+                println!("error on logging the end of XA transaction {}; error = {:?}", self.xid, e);
+            }
+        }
+        self.unlog();
+    }
+}
+
+impl Drop for XaTransaction {
+    fn drop(&mut self) {
+        if !self.branches.is_empty() {
+            self.abandon();
+        }
+    }
+}
+
+/// A transaction of the log that did not end.
+#[derive(Debug, Clone, PartialEq)]
+struct LoggedTransaction {
+    xid: String,
+    segments: Vec<String>,
+    committed: bool,
+}
+
+impl LoggedTransaction {
+    fn records(&self) -> String {
+        let mut records = format!("{} {} {}\n", PREPARE_RECORD, self.xid, self.segments.join(" "));
+        if self.committed {
+            records.push_str(&format!("{} {}\n", COMMIT_RECORD, self.xid));
+        }
+        records
+    }
+}
+
+/// The transactions `log` has no end record of, in the order they were prepared. A torn last
+/// record is skipped.
+fn unfinished(log: &str) -> Vec<LoggedTransaction> {
+    let mut transactions: Vec<LoggedTransaction> = vec![];
+    for line in log.lines() {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some(PREPARE_RECORD), Some(xid)) => transactions.push(LoggedTransaction {
+                xid: xid.to_string(),
+                segments: fields.map(|segment| segment.to_string()).collect(),
+                committed: false,
+            }),
+            (Some(COMMIT_RECORD), Some(xid)) => {
+                if let Some(transaction) = transactions.iter_mut().find(|transaction| transaction.xid == xid) {
+                    transaction.committed = true;
+                }
+            }
+            (Some(END_RECORD), Some(xid)) => transactions.retain(|transaction| transaction.xid != xid),
+            _ => {}
+        }
+    }
+    transactions
+}
+
+/// Commits or rolls back the branches `transaction` still has prepared on its segments.
+fn recover_transaction(transaction: &LoggedTransaction) -> mysql::Result<()> {
+    let action = if transaction.committed { "COMMIT" } else { "ROLLBACK" };
+    for segment in transaction.segments.iter() {
+        let database_url = discovery::database::segment_url(segment).ok_or_else(|| no_such_segment(segment))?;
+        let mut conn = lifecycle::connect(&database_url)?;
+        let prepared: Vec<String> = conn.query_map("XA RECOVER", |(_, _, _, data): (i64, i64, i64, Vec<u8>)| {
+            String::from_utf8_lossy(&data).to_string()
+        })?;
+        if prepared.contains(&transaction.xid) {
+            conn.query_drop(format!("XA {} '{}'", action, transaction.xid))?;
+        }
+    }
+    Ok(())
+}
+
+/// Finishes the transactions `log` leaves unfinished, returns those that could not be.
+fn recover_log(log: &str) -> Vec<LoggedTransaction> {
+    unfinished(log).into_iter()
+        .filter(|transaction| match recover_transaction(transaction) {
+            Ok(()) => {
+                println!("Recovered XA transaction {}, {}", transaction.xid, if transaction.committed { "committed" } else { "rolled back" });
+                false
+            }
+            Err(e) => {
+                println!("error on recovering XA transaction {}; error = {:?}", transaction.xid, e);
+                true
+            }
+        })
+        .collect()
+}
+
+/// Finishes the transactions of the metadata store, keeping only those that could not be.
+fn recover_stored(store: &dyn MetadataStore) {
+    let stored = match store.scan(STORE_NAMESPACE) {
+        Ok(stored) => stored,
+        Err(e) => return println!("error on reading the XA log of the metadata store; error = {}", e),
+    };
+    let log: String = stored.iter().map(|(_, records)| String::from_utf8_lossy(records)).collect();
+    let left = recover_log(&log);
+    let _guard = XA_LOG.lock().unwrap();
+    for (xid, _) in stored.iter().filter(|(xid, _)| !left.iter().any(|transaction| &transaction.xid == xid)) {
+        if let Err(e) = store.delete(STORE_NAMESPACE, xid) {
+            println!("error on compacting the XA log of the metadata store; error = {}", e);
+        }
+    }
+}
+
+/// Finishes the transactions the log leaves unfinished, keeping only those that could not be.
+pub fn recover(config: &DistributedTransactionConfig) {
+    if let Some(store) = store::metadata_store() {
+        return recover_stored(&*store);
+    }
+    let path = config.get_log();
+    let log = match fs::read_to_string(&path) {
+        Ok(log) => log,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => return println!("error on reading XA log {}; error = {:?}", path, e),
+    };
+    let left: String = recover_log(&log).iter().map(|transaction| transaction.records()).collect();
+    let _guard = XA_LOG.lock().unwrap();
+    if let Err(e) = write_log(&path, &left) {
+        println!("error on compacting XA log {}; error = {:?}", path, e);
+    }
+}
+
+/// The statements a write runs on the data segments it touches.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentWrites {
+    writes: Vec<(u32, String)>,
+    insert_plan: Option<InsertPlan>,
+}
+
+impl SegmentWrites {
+    /// The data segments and the statement each of them runs, in segment order.
+    pub fn get_writes(&self) -> &Vec<(u32, String)> {
+        &self.writes
+    }
+
+    fn execute(&self, xa: &mut XaTransaction, session_ctx: &SessionContext) -> mysql::Result<InsertOutcome> {
+        let mut outcomes = vec![];
+        for (segment, sql) in self.writes.iter() {
+            outcomes.push(xa.execute(&format!("data-{}/primary", segment), sql, session_ctx)?);
+        }
+        Ok(match &self.insert_plan {
+            Some(plan) => plan.merge(&outcomes),
+            None => InsertOutcome::new(outcomes.iter().map(|outcome| outcome.get_affected_rows()).sum(), 0),
+        })
+    }
+}
+
+/// The writes of `statement` over the data segments of `route_plan`, None unless it writes to
+/// more than one, or is an INSERT into a distributed table. Distributed tables take their
+/// actual names on every segment, on top of the renames of `rewrite_ctx`. `params` are the
+/// values bound to the placeholders of `statement`, which every write has bound.
+pub fn segment_writes(statement: &Statement, params: &[mysql::Value], route_plan: &RoutePlan, rules: &RulesVersion, rewrite_ctx: &HashMap<String, String>) -> Result<Option<SegmentWrites>, String> {
+    let literals = sql_literals(params);
+    if let Statement::Insert { .. } = statement {
+        return match plan_insert(statement, &key_values(params), rules)? {
+            Some(plan) => Ok(Some(SegmentWrites {
+                writes: plan.bound_writes(&literals)?,
+                insert_plan: Some(plan),
+            })),
+            None => Ok(None),
+        };
+    }
+    let segments = route_plan.data_segments();
+    if segments.len() < 2 {
+        return Ok(None);
+    }
+    let mut writes = vec![];
+    for segment in segments {
+        let sql = route_plan.segment_sql(statement, segment, rules, rewrite_ctx)
+            .ok_or_else(|| format!("the write can't be rewritten for data segment {}", segment))?;
+        let sql = bind_placeholders(&sql, &literals).ok_or_else(|| format!("the parameters of the write to data segment {} can't be bound", segment))?;
+        writes.push((segment, sql));
+    }
+    Ok(Some(SegmentWrites {
+        writes,
+        insert_plan: None,
+    }))
+}
+
+/// Answers a write to more than one data segment, run in an XA transaction: the one of the
+/// client transaction open on the session, or else one of its own. Inside a client
+/// transaction an INSERT into a distributed table joins it even when its rows are on one data
+/// segment. `params` are the values bound to the placeholders of `statement`. None for other
+/// statements.
+pub fn intercept(statement: &Statement, params: &[mysql::Value], route_plan: Option<&RoutePlan>, rewrite_ctx: &HashMap<String, String>, session_ctx: &mut SessionContext) -> Option<Vec<Bytes>> {
+    if !MeshConfig::get_distributed_transaction_config().is_enabled() || !intent::is_write(statement) {
+        return None;
+    }
+    let rules = current_rules()?;
+    let writes = match segment_writes(statement, params, route_plan?, &rules, rewrite_ctx) {
+        Ok(Some(writes)) if writes.writes.len() > 1 || session_ctx.is_in_transaction() => writes,
+        Ok(_) => return None,
+        Err(message) => return Some(vec![err_payload(Error::Protocol(message))]),
+    };
+    if session_ctx.is_in_transaction() {
+        let mut xa = session_ctx.take_xa_transaction().unwrap_or_default();
+        return match writes.execute(&mut xa, session_ctx) {
+            Ok(outcome) => {
+                session_ctx.set_xa_transaction(xa);
+                sharded_insert::ok_payloads(outcome, session_ctx.get_status_flags())
+            }
+            Err(e) => {
+                xa.rollback();
+                transaction::abort(session_ctx);
+                Some(vec![err_payload(e)])
+            }
+        };
+    }
+    let mut xa = XaTransaction::new();
+    match writes.execute(&mut xa, session_ctx) {
+        Ok(outcome) => match xa.commit() {
+            Ok(()) => sharded_insert::ok_payloads(outcome, session_ctx.get_status_flags()),
+            Err(e) => Some(vec![err_payload(e)]),
+        },
+        Err(e) => {
+            xa.rollback();
+            Some(vec![err_payload(e)])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use crate::discovery::database::{Cluster, DisAlgorithm, DisRules, DisTable, DisType, Segment};
+    use crate::discovery::database::rules::RulesVersion;
+    use crate::handler::database::parser::sql::mysql::parser;
+    use crate::handler::database::route_cache::RoutePlan;
+
+    use super::{compact_log, LoggedTransaction, segment_writes, unfinished};
+
+    #[test]
+    fn test_segment_writes_and_log() {
+        let url = "jdbc:mysql://localhost:3306/martlet";
+        let cluster = Cluster::builder("martlet")
+            .meta_segment(Segment::new(0, url, "root", "root"), vec![])
+            .data_segment(100, Segment::new(0, url, "root", "root"), vec![])
+            .data_segment(200, Segment::new(0, url, "root", "root"), vec![])
+            .dis_rules(DisRules::builder()
+                .distributed_table("t_order", DisTable::new(vec!["user_id"], DisAlgorithm::new(DisType::HASH, ""), vec![])
+                    .actual_table("t_order_{segment}"))
+                .build())
+            .build()
+            .unwrap();
+        let rules = RulesVersion::new("v1".to_string(), cluster);
+        let writes = |sql: &str, bindings: &[(String, String)]| {
+            let statement = parser(sql.to_string()).unwrap().pop().unwrap();
+            let route_plan = RoutePlan::build(&rules, &statement, bindings);
+            segment_writes(&statement, &[], &route_plan, &rules, &HashMap::new()).unwrap()
+        };
+        let scattered = writes("UPDATE t_order SET status = 'PAID' WHERE id = 1", &[]).unwrap();
+        assert_eq!(scattered.get_writes(), &vec![
+            (100, "UPDATE t_order_100 SET status = 'PAID' WHERE id = 1".to_string()),
+            (200, "UPDATE t_order_200 SET status = 'PAID' WHERE id = 1".to_string()),
+        ]);
+        assert!(writes("DELETE FROM t_order WHERE user_id = 7", &[("user_id".to_string(), "7".to_string())]).is_none());
+        assert!(writes("UPDATE t_user SET name = 'a' WHERE id = 1", &[]).is_none());
+
+        // The parameters of a prepared write are bound in every branch.
+        let statement = parser("UPDATE t_order SET status = ? WHERE id = ?".to_string()).unwrap().pop().unwrap();
+        let route_plan = RoutePlan::build(&rules, &statement, &[]);
+        let params = vec![mysql::Value::Bytes(b"PAID".to_vec()), mysql::Value::Int(1)];
+        let bound = segment_writes(&statement, &params, &route_plan, &rules, &HashMap::new()).unwrap().unwrap();
+        assert_eq!(bound.get_writes()[1], (200, "UPDATE t_order_200 SET status = 'PAID' WHERE id = 1".to_string()));
+        let statement = parser("INSERT INTO t_order (id, user_id) VALUES (?, ?), (?, ?)".to_string()).unwrap().pop().unwrap();
+        let route_plan = RoutePlan::build(&rules, &statement, &[]);
+        let params = vec![mysql::Value::Int(1), mysql::Value::Int(7), mysql::Value::Int(2), mysql::Value::Int(7)];
+        let bound = segment_writes(&statement, &params, &route_plan, &rules, &HashMap::new()).unwrap().unwrap();
+        assert_eq!(bound.get_writes().len(), 1);
+        assert!(bound.get_writes()[0].1.ends_with("(id, user_id) VALUES (1, 7), (2, 7)"));
+        assert!(segment_writes(&statement, &params[..3], &route_plan, &rules, &HashMap::new()).is_err());
+
+        let log = "prepare x-1 data-100/primary data-200/primary\n\
+                   commit x-1\n\
+                   prepare x-2 data-100/primary data-200/primary\n\
+                   prepare x-3 data-100/primary data-200/primary\n\
+                   commit x-3\n\
+                   end x-3\n\
+                   comm";
+        let transactions = unfinished(log);
+        assert_eq!(transactions, vec![
+            LoggedTransaction { xid: "x-1".to_string(), segments: vec!["data-100/primary".to_string(), "data-200/primary".to_string()], committed: true },
+            LoggedTransaction { xid: "x-2".to_string(), segments: vec!["data-100/primary".to_string(), "data-200/primary".to_string()], committed: false },
+        ]);
+        assert_eq!(unfinished(&transactions[0].records()), vec![transactions[0].clone()]);
+    }
+
+    #[test]
+    fn test_compact_log() {
+        let path = std::env::temp_dir().join(format!("martlet_test_xa_{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        let log = "prepare x-1 data-100/primary data-200/primary\n\
+                   commit x-1\n\
+                   prepare x-2 data-100/primary data-200/primary\n\
+                   commit x-2\n\
+                   end x-2\n";
+        fs::write(path, log).unwrap();
+        // The transaction left to the recovery stays, the ended one goes.
+        compact_log(path).unwrap();
+        let compacted = fs::read_to_string(path).unwrap();
+        assert_eq!(compacted, "prepare x-1 data-100/primary data-200/primary\ncommit x-1\n");
+        assert!(compacted.len() < log.len());
+
+        fs::write(path, "prepare x-3 data-100/primary data-200/primary\nend x-3\n").unwrap();
+        compact_log(path).unwrap();
+        assert_eq!(fs::metadata(path).unwrap().len(), 0);
+        fs::remove_file(path).unwrap();
+        assert!(compact_log(path).is_ok());
+    }
+}
@@ -0,0 +1,85 @@
+//! Character sets and collations for MySQL, by the collation id of the handshake.
+//!
+//! @see <a href="https://dev.mysql.com/doc/internals/en/character-set.html">Character Set</a>
+
+#[derive(Debug, PartialEq)]
+pub struct MySQLCollation {
+    id: u8,
+    name: &'static str,
+    charset: &'static str,
+    /// Whether it is the collation a charset gets when none is named.
+    is_default: bool,
+}
+
+impl MySQLCollation {
+    pub fn get_id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn get_charset(&self) -> &'static str {
+        self.charset
+    }
+}
+
+const fn collation(id: u8, name: &'static str, charset: &'static str, is_default: bool) -> MySQLCollation {
+    MySQLCollation { id, name, charset, is_default }
+}
+
+static COLLATIONS: [MySQLCollation; 24] = [
+    collation(1, "big5_chinese_ci", "big5", true),
+    collation(8, "latin1_swedish_ci", "latin1", true),
+    collation(11, "ascii_general_ci", "ascii", true),
+    collation(19, "euckr_korean_ci", "euckr", true),
+    collation(24, "gb2312_chinese_ci", "gb2312", true),
+    collation(28, "gbk_chinese_ci", "gbk", true),
+    collation(33, "utf8_general_ci", "utf8", true),
+    collation(45, "utf8mb4_general_ci", "utf8mb4", true),
+    collation(46, "utf8mb4_bin", "utf8mb4", false),
+    collation(47, "latin1_bin", "latin1", false),
+    collation(48, "latin1_general_ci", "latin1", false),
+    collation(63, "binary", "binary", true),
+    collation(65, "ascii_bin", "ascii", false),
+    collation(83, "utf8_bin", "utf8", false),
+    collation(84, "big5_bin", "big5", false),
+    collation(86, "gb2312_bin", "gb2312", false),
+    collation(87, "gbk_bin", "gbk", false),
+    collation(95, "cp932_japanese_ci", "cp932", true),
+    collation(192, "utf8_unicode_ci", "utf8", false),
+    collation(224, "utf8mb4_unicode_ci", "utf8mb4", false),
+    collation(248, "gb18030_chinese_ci", "gb18030", true),
+    collation(249, "gb18030_bin", "gb18030", false),
+    collation(254, "utf8mb4_0900_as_cs", "utf8mb4", false),
+    collation(255, "utf8mb4_0900_ai_ci", "utf8mb4", false),
+];
+
+pub fn collation_by_id(id: u8) -> Option<&'static MySQLCollation> {
+    COLLATIONS.iter().find(|collation| collation.id == id)
+}
+
+pub fn collation_by_name(name: &str) -> Option<&'static MySQLCollation> {
+    COLLATIONS.iter().find(|collation| collation.name.eq_ignore_ascii_case(name))
+}
+
+/// The collation of `charset` when none is named, `utf8mb3` read as `utf8`.
+pub fn default_collation(charset: &str) -> Option<&'static MySQLCollation> {
+    let charset = if charset.eq_ignore_ascii_case("utf8mb3") { "utf8" } else { charset };
+    COLLATIONS.iter().find(|collation| collation.is_default && collation.charset.eq_ignore_ascii_case(charset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{collation_by_id, collation_by_name, default_collation};
+
+    #[test]
+    fn test_collations() {
+        assert_eq!(collation_by_id(0x21).unwrap().get_name(), "utf8_general_ci");
+        assert_eq!(collation_by_name("UTF8MB4_UNICODE_CI").unwrap().get_id(), 224);
+        assert_eq!(default_collation("utf8mb4").unwrap().get_id(), 45);
+        assert_eq!(default_collation("utf8mb3").unwrap().get_charset(), "utf8");
+        assert!(collation_by_id(2).is_none());
+    }
+}
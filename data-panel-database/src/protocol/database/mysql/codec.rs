@@ -26,4 +26,58 @@ impl ServiceCodec for MySQLCodec {
             .num_skip(0)
             .new_read(io)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+
+    use bytes::Bytes;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    use data_panel_common::service::io::Channel;
+
+    use super::MySQLCodec;
+
+    /// A client socket recording the writes made to it.
+    #[derive(Clone, Default)]
+    struct RecordingSocket {
+        writes: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl AsyncRead for RecordingSocket {
+        fn poll_read(self: Pin<&mut Self>, _cx: &mut Context<'_>, _buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for RecordingSocket {
+        fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.writes.lock().unwrap().push(buf.to_vec());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batched_writes() {
+        let socket = RecordingSocket::default();
+        let mut channel = Channel::new(socket.clone(), MySQLCodec {});
+        let rows = (1..=100u8).map(|sequence_id| Bytes::from(vec![sequence_id, 3, b'a', b'b', b'c'])).collect();
+        channel.send(Some(rows)).await.unwrap();
+
+        let writes = socket.writes.lock().unwrap();
+        assert_eq!(writes.len(), 1);
+        assert_eq!(writes[0].len(), 100 * 8);
+        assert_eq!(&writes[0][..8], &[4, 0, 0, 1, 3, b'a', b'b', b'c']);
+    }
+}
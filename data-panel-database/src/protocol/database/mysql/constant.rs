@@ -283,6 +283,7 @@ pub enum MySQLAuthenticationMethod {
     ClearTextAuthentication,
     WindowsNativeAuthentication,
     SHA256,
+    CachingSha2Password,
 }
 
 impl MySQLAuthenticationMethod {
@@ -293,6 +294,7 @@ impl MySQLAuthenticationMethod {
             MySQLAuthenticationMethod::ClearTextAuthentication => "mysql_clear_password",
             MySQLAuthenticationMethod::WindowsNativeAuthentication => "authentication_windows_client",
             MySQLAuthenticationMethod::SHA256 => "sha256_password",
+            MySQLAuthenticationMethod::CachingSha2Password => "caching_sha2_password",
         }
     }
 }
@@ -690,4 +692,82 @@ bitflags! {
         /// Field is num (for clients).
         const NUM_FLAG              = 32768u16;
     }
-}
\ No newline at end of file
+}
+
+///
+/// Server error codes for MySQL, with the SQLSTATE sent alongside them in ERR packets.
+///
+/// @see <a href="https://dev.mysql.com/doc/refman/5.7/en/server-error-reference.html">Server Error Message Reference</a>
+///
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum MySQLServerErrorCode {
+    ErSpecificAccessDeniedError,
+    ErParseError,
+    ErColumnaccessDeniedError,
+    ErAccessDeniedError,
+    ErDbaccessDeniedError,
+    ErHostNotPrivileged,
+    ErCantChangeTxCharacteristics,
+    ErUserLimitReached,
+    ErNoSuchThread,
+    ErKillDeniedError,
+    ErLockDeadlock,
+    ErNotSupportedYet,
+    ErEmptyQuery,
+    ErConCountError,
+    ErTooManyUserConnections,
+    ErClientInteractionTimeout,
+    ErNoSuchTable,
+    ErBadFieldError,
+    ErUnknownError,
+}
+
+impl MySQLServerErrorCode {
+    pub fn code(&self) -> u32 {
+        match *self {
+            MySQLServerErrorCode::ErSpecificAccessDeniedError => 1227,
+            MySQLServerErrorCode::ErParseError => 1064,
+            MySQLServerErrorCode::ErColumnaccessDeniedError => 1143,
+            MySQLServerErrorCode::ErAccessDeniedError => 1045,
+            MySQLServerErrorCode::ErDbaccessDeniedError => 1044,
+            MySQLServerErrorCode::ErHostNotPrivileged => 1130,
+            MySQLServerErrorCode::ErCantChangeTxCharacteristics => 1568,
+            MySQLServerErrorCode::ErUserLimitReached => 1226,
+            MySQLServerErrorCode::ErNoSuchThread => 1094,
+            MySQLServerErrorCode::ErKillDeniedError => 1095,
+            MySQLServerErrorCode::ErLockDeadlock => 1213,
+            MySQLServerErrorCode::ErNotSupportedYet => 1235,
+            MySQLServerErrorCode::ErEmptyQuery => 1065,
+            MySQLServerErrorCode::ErConCountError => 1040,
+            MySQLServerErrorCode::ErTooManyUserConnections => 1203,
+            MySQLServerErrorCode::ErClientInteractionTimeout => 4031,
+            MySQLServerErrorCode::ErNoSuchTable => 1146,
+            MySQLServerErrorCode::ErBadFieldError => 1054,
+            MySQLServerErrorCode::ErUnknownError => 1105,
+        }
+    }
+
+    pub fn sql_state(&self) -> &str {
+        match *self {
+            MySQLServerErrorCode::ErSpecificAccessDeniedError => "42000",
+            MySQLServerErrorCode::ErParseError => "42000",
+            MySQLServerErrorCode::ErColumnaccessDeniedError => "42000",
+            MySQLServerErrorCode::ErAccessDeniedError => "28000",
+            MySQLServerErrorCode::ErDbaccessDeniedError => "42000",
+            MySQLServerErrorCode::ErHostNotPrivileged => "HY000",
+            MySQLServerErrorCode::ErCantChangeTxCharacteristics => "25001",
+            MySQLServerErrorCode::ErUserLimitReached => "42000",
+            MySQLServerErrorCode::ErNoSuchThread => "HY000",
+            MySQLServerErrorCode::ErKillDeniedError => "HY000",
+            MySQLServerErrorCode::ErLockDeadlock => "40001",
+            MySQLServerErrorCode::ErNotSupportedYet => "42000",
+            MySQLServerErrorCode::ErEmptyQuery => "42000",
+            MySQLServerErrorCode::ErConCountError => "08004",
+            MySQLServerErrorCode::ErTooManyUserConnections => "42000",
+            MySQLServerErrorCode::ErClientInteractionTimeout => "HY000",
+            MySQLServerErrorCode::ErNoSuchTable => "42S02",
+            MySQLServerErrorCode::ErBadFieldError => "42S22",
+            MySQLServerErrorCode::ErUnknownError => "HY000",
+        }
+    }
+}
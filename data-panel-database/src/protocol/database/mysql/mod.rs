@@ -1,3 +1,4 @@
+pub mod charset;
 pub mod codec;
 pub mod constant;
 pub mod packet;
\ No newline at end of file
@@ -1,5 +1,7 @@
+use bytes::Bytes;
+
 use crate::protocol::database::DatabasePacket;
-use crate::protocol::database::mysql::constant::{MySQLColumnFlags, MySQLColumnType, MySQLNewParametersBoundFlag};
+use crate::protocol::database::mysql::constant::{MySQLColumnType, MySQLNewParametersBoundFlag};
 use crate::protocol::database::mysql::packet::{MySQLPacket, MySQLPacketHeader, MySQLPacketPayload};
 use crate::session::mysql::SessionContext;
 
@@ -12,7 +14,8 @@ pub struct MySQLComStmtPreparePacket {
     sequence_id: u32,
     /// MySQLCommandPacketType,
     command_type: u8,
-    sql: Vec<u8>,
+    /// A slice of the received packet.
+    sql: Bytes,
 }
 
 impl MySQLComStmtPreparePacket {
@@ -20,11 +23,11 @@ impl MySQLComStmtPreparePacket {
         MySQLComStmtPreparePacket {
             sequence_id: 0,
             command_type: command_type, // MySQLCommandPacketType::value_of(command_type & 0xff),
-            sql: vec![],
+            sql: Bytes::new(),
         }
     }
 
-    pub fn get_sql(&self) -> Vec<u8> {
+    pub fn get_sql(&self) -> Bytes {
         self.sql.clone()
     }
 
@@ -35,8 +38,7 @@ impl MySQLComStmtPreparePacket {
 
 impl DatabasePacket<MySQLPacketHeader, MySQLPacketPayload, SessionContext> for MySQLComStmtPreparePacket {
     fn decode<'p, 'd>(this: &'d mut Self, header: &'p MySQLPacketHeader, payload: &'p mut MySQLPacketPayload, session_ctx: &mut SessionContext) -> &'d mut Self {
-        let bytes = payload.get_remaining_bytes();
-        this.sql = Vec::from(bytes.as_slice());
+        this.sql = payload.get_remaining_bytes();
         this
     }
 }
@@ -116,7 +118,7 @@ pub struct MySQLComStmtExecutePacket {
     null_bit_map: Vec<u8>,
     new_parameters_bound_flag: u8,
     iteration_count: u32,
-    sql: Vec<u8>,
+    sql: Bytes,
     parameters: Vec<PrepareParamValue>,
 }
 
@@ -130,16 +132,16 @@ impl MySQLComStmtExecutePacket {
             null_bit_map: vec![],
             new_parameters_bound_flag: 0,
             iteration_count: 0,
-            sql: vec![],
+            sql: Bytes::new(),
             parameters: vec![],
         }
     }
 
-    pub fn get_sql(&self) -> Vec<u8> {
+    pub fn get_sql(&self) -> Bytes {
         self.sql.clone()
     }
 
-    pub fn set_sql(&mut self, sql: Vec<u8>) {
+    pub fn set_sql(&mut self, sql: Bytes) {
         self.sql = sql;
     }
 
@@ -156,6 +158,10 @@ impl MySQLComStmtExecutePacket {
     }
 }
 
+/// The flag byte after the type of a parameter has its high bit set for unsigned ones, it is
+/// not a column flag.
+const PARAMETER_UNSIGNED_FLAG: u8 = 0x80;
+
 impl DatabasePacket<MySQLPacketHeader, MySQLPacketPayload, SessionContext> for MySQLComStmtExecutePacket {
     fn decode<'p, 'd>(this: &'d mut Self, header: &'p MySQLPacketHeader, payload: &'p mut MySQLPacketPayload, session_ctx: &mut SessionContext) -> &'d mut Self {
         this.sequence_id = header.sequence_id;
@@ -200,8 +206,8 @@ impl DatabasePacket<MySQLPacketHeader, MySQLPacketPayload, SessionContext> for M
                     this.parameters.push(PrepareParamValue::NULL);
                 } else {
                     let (column_type, unsigned_flag) = parameter_types.get(i).unwrap();
-                    let column_flags = MySQLColumnFlags::from_bits_truncate(*unsigned_flag as u16);
-                    let param_value = read_bin(payload, MySQLColumnType::from(*column_type), column_flags.contains(MySQLColumnFlags::UNSIGNED_FLAG)).unwrap();
+                    let unsigned = *unsigned_flag & PARAMETER_UNSIGNED_FLAG != 0;
+                    let param_value = read_bin(payload, MySQLColumnType::from(*column_type), unsigned).unwrap();
                     this.parameters.push(param_value);
                 }
             }
@@ -219,22 +225,34 @@ impl MySQLPacket for MySQLComStmtExecutePacket {
 /**
  * Binary result set row packet for MySQL.
  *
+ * The values are encoded by the types of their columns, NULLs only in the NULL bitmap.
+ *
  * @see <a href="https://dev.mysql.com/doc/internals/en/binary-protocol-resultset-row.html">Binary Protocol ResultSet Row</a>
  */
 pub struct MySQLBinaryResultSetRowPacket {
     sequence_id: u32,
+    /// The type of every column and whether it is unsigned.
+    column_types: Vec<(MySQLColumnType, bool)>,
     data: Vec<PrepareParamValue>,
 }
 
 impl MySQLBinaryResultSetRowPacket {
-    pub fn new(sequence_id: u32, data: Vec<PrepareParamValue>) -> Self {
+    pub fn new(sequence_id: u32, column_types: Vec<(MySQLColumnType, bool)>, data: Vec<PrepareParamValue>) -> Self {
         MySQLBinaryResultSetRowPacket {
             sequence_id: sequence_id,
+            column_types: column_types,
             data: data,
         }
     }
+
+    pub fn get_data(&self) -> Vec<PrepareParamValue> {
+        self.data.clone()
+    }
 }
 
+/// The NULL bitmap of a binary row starts at bit 2.
+const ROW_NULL_BITMAP_OFFSET: usize = 2;
+
 impl MySQLPacket for MySQLBinaryResultSetRowPacket {
     fn get_sequence_id(&self) -> u32 {
         self.sequence_id
@@ -246,18 +264,13 @@ impl DatabasePacket<MySQLPacketHeader, MySQLPacketPayload, SessionContext> for M
         payload.put_u8(this.get_sequence_id() as u8); // seq
         payload.put_u8(0x00); // PACKET_HEADER
 
-        let null_bitmap_offset = 2;
-        let columns_numbers = this.data.len();
-        let len = (columns_numbers + null_bitmap_offset + 7) / 8;
-        let mut null_bit_map = vec![0u8; len];//Vec::with_capacity(len);
-        for i in 0..len {
-            null_bit_map[i] = 0u8;
-            if let Some(v) = this.data.get(i) {
-                if *v == PrepareParamValue::NULL {
-                    let null_byte_position = (i + null_bitmap_offset) / 8;
-                    let null_bit_position = (i + null_bitmap_offset) % 8;
-                    null_bit_map[null_byte_position] = (1 << null_bit_position) as u8;
-                }
+        let len = (this.data.len() + ROW_NULL_BITMAP_OFFSET + 7) / 8;
+        let mut null_bit_map = vec![0u8; len];
+        for (i, v) in this.data.iter().enumerate() {
+            if *v == PrepareParamValue::NULL {
+                let null_byte_position = (i + ROW_NULL_BITMAP_OFFSET) / 8;
+                let null_bit_position = (i + ROW_NULL_BITMAP_OFFSET) % 8;
+                null_bit_map[null_byte_position] |= (1 << null_bit_position) as u8;
             }
         }
 
@@ -265,12 +278,43 @@ impl DatabasePacket<MySQLPacketHeader, MySQLPacketPayload, SessionContext> for M
             payload.put_u8(*v);
         }
 
-        for v in this.data.iter() {
-            write_bin(v, payload);
+        for (i, v) in this.data.iter().enumerate() {
+            if *v == PrepareParamValue::NULL {
+                continue;
+            }
+            match this.column_types.get(i) {
+                Some((column_type, _)) => write_bin_column(v, *column_type, payload),
+                None => write_bin(v, payload),
+            }
         }
 
         payload
     }
+
+    fn decode<'p, 'd>(this: &'d mut Self, header: &'p MySQLPacketHeader, payload: &'p mut MySQLPacketPayload, session_ctx: &mut SessionContext) -> &'d mut Self {
+        this.sequence_id = header.sequence_id;
+        payload.get_uint(1); // PACKET_HEADER
+
+        let columns_count = this.column_types.len();
+        let len = (columns_count + ROW_NULL_BITMAP_OFFSET + 7) / 8;
+        let mut null_bit_map = vec![0u8; len];
+        for i in 0..len {
+            null_bit_map[i] = (payload.get_uint(1) & 0xff) as u8;
+        }
+
+        this.data = Vec::with_capacity(columns_count);
+        for i in 0..columns_count {
+            let null_byte_position = (i + ROW_NULL_BITMAP_OFFSET) / 8;
+            let null_bit_position = (i + ROW_NULL_BITMAP_OFFSET) % 8;
+            if (null_bit_map[null_byte_position] & (1 << null_bit_position) as u8) != 0 {
+                this.data.push(PrepareParamValue::NULL);
+            } else {
+                let (column_type, unsigned) = this.column_types[i];
+                this.data.push(read_bin(payload, column_type, unsigned).unwrap());
+            }
+        }
+        this
+    }
 }
 
 /**
@@ -385,7 +429,7 @@ pub fn read_bin(payload: &mut MySQLPacketPayload, column_type: MySQLColumnType,
         | MySQLColumnType::MysqlTypeBit
         | MySQLColumnType::MysqlTypeNewDecimal
         | MySQLColumnType::MysqlTypeGeometry
-        | MySQLColumnType::MysqlTypeJson => Ok(PrepareParamValue::Bytes(payload.get_string_lenenc())),
+        | MySQLColumnType::MysqlTypeJson => Ok(PrepareParamValue::Bytes(payload.get_string_lenenc().to_vec())),
         MySQLColumnType::MysqlTypeTiny => {
             if unsigned {
                 Ok(PrepareParamValue::UInt(payload.get_uint(1) & 0xff))
@@ -418,7 +462,10 @@ pub fn read_bin(payload: &mut MySQLPacketPayload, column_type: MySQLColumnType,
         MySQLColumnType::MysqlTypeDouble => Ok(PrepareParamValue::Double(payload.get_f64_le())),
         MySQLColumnType::MysqlTypeTimestamp
         | MySQLColumnType::MysqlTypeDate
-        | MySQLColumnType::MysqlTypeDatetime => {
+        | MySQLColumnType::MysqlTypeNewDate
+        | MySQLColumnType::MysqlTypeDatetime
+        | MySQLColumnType::MysqlTypeTimestamp2
+        | MySQLColumnType::MysqlTypeDatetime2 => {
             let len = (payload.get_uint(1) & 0xff) as u8;
             let mut year = 0u16;
             let mut month = 0u8;
@@ -431,16 +478,18 @@ pub fn read_bin(payload: &mut MySQLPacketPayload, column_type: MySQLColumnType,
                 year = payload.get_uint_le(2) as u16;
                 month = (payload.get_uint(1) & 0xff) as u8;
                 day = (payload.get_uint(1) & 0xff) as u8;
-            } else if len >= 7u8 {
+            }
+            if len >= 7u8 {
                 hour = (payload.get_uint(1) & 0xff) as u8;
                 minute = (payload.get_uint(1) & 0xff) as u8;
                 second = (payload.get_uint(1) & 0xff) as u8;
-            } else if len == 11u8 {
+            }
+            if len == 11u8 {
                 micro_second = payload.get_uint_le(4) as u32;
             }
             Ok(PrepareParamValue::Date(year, month, day, hour, minute, second, micro_second))
         }
-        MySQLColumnType::MysqlTypeTime => {
+        MySQLColumnType::MysqlTypeTime | MySQLColumnType::MysqlTypeTime2 => {
             let len = (payload.get_uint(1) & 0xff) as u8;
             let mut is_negative = false;
             let mut days = 0u32;
@@ -454,7 +503,8 @@ pub fn read_bin(payload: &mut MySQLPacketPayload, column_type: MySQLColumnType,
                 hours = (payload.get_uint(1) & 0xff) as u8;
                 minutes = (payload.get_uint(1) & 0xff) as u8;
                 seconds = (payload.get_uint(1) & 0xff) as u8;
-            } else if len == 12u8 {
+            }
+            if len == 12u8 {
                 micro_seconds = payload.get_uint_le(4) as u32;
             }
             Ok(PrepareParamValue::Time(
@@ -537,4 +587,371 @@ pub fn write_bin(value: &PrepareParamValue, payload: &mut MySQLPacketPayload) {
             payload.put_u32_le(u);
         }
     }
-}
\ No newline at end of file
+}
+
+/// Writes a non NULL `value` of a column of `column_type` in binary value format. A value of
+/// another type, as the text protocol hands out, is converted to the one of the column.
+///
+/// @see <a href="https://dev.mysql.com/doc/internals/en/binary-protocol-value.html">Binary Protocol Value</a>
+pub fn write_bin_column(value: &PrepareParamValue, column_type: MySQLColumnType, payload: &mut MySQLPacketPayload) {
+    match column_type {
+        MySQLColumnType::MysqlTypeTiny => payload.put_u8(int_bits(value) as u8),
+        MySQLColumnType::MysqlTypeShort | MySQLColumnType::MysqlTypeYear => payload.put_u16_le(int_bits(value) as u16),
+        MySQLColumnType::MysqlTypeLong | MySQLColumnType::MysqlTypeInt24 => payload.put_u32_le(int_bits(value) as u32),
+        MySQLColumnType::MysqlTypeLonglong => payload.put_u64_le(int_bits(value)),
+        MySQLColumnType::MysqlTypeFloat => payload.put_f32_le(float_value(value) as f32),
+        MySQLColumnType::MysqlTypeDouble => payload.put_f64_le(float_value(value)),
+        MySQLColumnType::MysqlTypeTimestamp
+        | MySQLColumnType::MysqlTypeDate
+        | MySQLColumnType::MysqlTypeNewDate
+        | MySQLColumnType::MysqlTypeDatetime
+        | MySQLColumnType::MysqlTypeTimestamp2
+        | MySQLColumnType::MysqlTypeDatetime2 => write_bin(&date_value(value), payload),
+        MySQLColumnType::MysqlTypeTime | MySQLColumnType::MysqlTypeTime2 => write_bin(&time_value(value), payload),
+        MySQLColumnType::MysqlTypeNull => {}
+        // DECIMAL, JSON, BIT, ENUM, SET, GEOMETRY and the strings and blobs.
+        _ => payload.put_string_lenenc(&text_value(value)),
+    }
+}
+
+/// The two's complement bits of an integer value.
+fn int_bits(value: &PrepareParamValue) -> u64 {
+    match value {
+        PrepareParamValue::Int(x) => *x as u64,
+        PrepareParamValue::UInt(x) => *x,
+        PrepareParamValue::Float(x) => *x as i64 as u64,
+        PrepareParamValue::Double(x) => *x as i64 as u64,
+        PrepareParamValue::Bytes(x) => {
+            let text = String::from_utf8_lossy(x);
+            let text = text.trim();
+            text.parse::<i64>().map(|x| x as u64)
+                .or_else(|_| text.parse::<u64>())
+                .unwrap_or_else(|_| text.parse::<f64>().map(|x| x as i64 as u64).unwrap_or(0))
+        }
+        _ => 0,
+    }
+}
+
+fn float_value(value: &PrepareParamValue) -> f64 {
+    match value {
+        PrepareParamValue::Int(x) => *x as f64,
+        PrepareParamValue::UInt(x) => *x as f64,
+        PrepareParamValue::Float(x) => *x as f64,
+        PrepareParamValue::Double(x) => *x,
+        PrepareParamValue::Bytes(x) => String::from_utf8_lossy(x).trim().parse().unwrap_or(0.0),
+        _ => 0.0,
+    }
+}
+
+fn date_value(value: &PrepareParamValue) -> PrepareParamValue {
+    match value {
+        PrepareParamValue::Date(..) => value.clone(),
+        PrepareParamValue::Bytes(x) => parse_date(&String::from_utf8_lossy(x))
+            .unwrap_or(PrepareParamValue::Date(0, 0, 0, 0, 0, 0, 0)),
+        _ => PrepareParamValue::Date(0, 0, 0, 0, 0, 0, 0),
+    }
+}
+
+fn time_value(value: &PrepareParamValue) -> PrepareParamValue {
+    match value {
+        PrepareParamValue::Time(..) => value.clone(),
+        PrepareParamValue::Bytes(x) => parse_time(&String::from_utf8_lossy(x))
+            .unwrap_or(PrepareParamValue::Time(false, 0, 0, 0, 0, 0)),
+        _ => PrepareParamValue::Time(false, 0, 0, 0, 0, 0),
+    }
+}
+
+/// The text form of a value, as MySQL writes it.
+fn text_value(value: &PrepareParamValue) -> Vec<u8> {
+    let text = match value {
+        PrepareParamValue::NULL => String::new(),
+        PrepareParamValue::Bytes(x) => return x.clone(),
+        PrepareParamValue::Int(x) => x.to_string(),
+        PrepareParamValue::UInt(x) => x.to_string(),
+        PrepareParamValue::Float(x) => x.to_string(),
+        PrepareParamValue::Double(x) => x.to_string(),
+        PrepareParamValue::Date(y, m, d, 0, 0, 0, 0) => format!("{:04}-{:02}-{:02}", y, m, d),
+        PrepareParamValue::Date(y, m, d, h, i, s, 0) => format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", y, m, d, h, i, s),
+        PrepareParamValue::Date(y, m, d, h, i, s, u) => format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:06}", y, m, d, h, i, s, u),
+        PrepareParamValue::Time(neg, d, h, m, s, u) => {
+            let sign = if *neg { "-" } else { "" };
+            let hours = *d * 24 + *h as u32;
+            if *u == 0 {
+                format!("{}{:02}:{:02}:{:02}", sign, hours, m, s)
+            } else {
+                format!("{}{:02}:{:02}:{:02}.{:06}", sign, hours, m, s, u)
+            }
+        }
+    };
+    text.into_bytes()
+}
+
+/// Parses `YYYY-MM-DD[ HH:MM:SS[.ffffff]]`.
+fn parse_date(text: &str) -> Option<PrepareParamValue> {
+    let text = text.trim();
+    let (date, clock) = match text.find(|c| c == ' ' || c == 'T') {
+        Some(i) => (&text[..i], Some(&text[i + 1..])),
+        None => (text, None),
+    };
+    let mut parts = date.split('-');
+    let year = parts.next()?.parse().ok()?;
+    let month = parts.next()?.parse().ok()?;
+    let day = parts.next()?.parse().ok()?;
+    let (hour, minute, second, micro_second) = match clock {
+        Some(clock) => parse_clock(clock)?,
+        None => (0, 0, 0, 0),
+    };
+    if hour > 23 {
+        return None;
+    }
+    Some(PrepareParamValue::Date(year, month, day, hour as u8, minute, second, micro_second))
+}
+
+/// Parses `[-]HHH:MM:SS[.ffffff]`, the hours may go beyond a day.
+fn parse_time(text: &str) -> Option<PrepareParamValue> {
+    let text = text.trim();
+    let (is_negative, clock) = match text.strip_prefix('-') {
+        Some(clock) => (true, clock),
+        None => (false, text),
+    };
+    let (hours, minutes, seconds, micro_seconds) = parse_clock(clock)?;
+    Some(PrepareParamValue::Time(is_negative, hours / 24, (hours % 24) as u8, minutes, seconds, micro_seconds))
+}
+
+fn parse_clock(text: &str) -> Option<(u32, u8, u8, u32)> {
+    let (clock, fraction) = match text.find('.') {
+        Some(i) => (&text[..i], &text[i + 1..]),
+        None => (text, ""),
+    };
+    let mut parts = clock.split(':');
+    let hours = parts.next()?.parse().ok()?;
+    let minutes = parts.next()?.parse().ok()?;
+    let seconds = parts.next()?.parse().ok()?;
+    // Fractional seconds are padded to micro seconds.
+    let micro_seconds = if fraction.is_empty() {
+        0
+    } else {
+        fraction.chars().chain(std::iter::repeat('0')).take(6).collect::<String>().parse().ok()?
+    };
+    Some((hours, minutes, seconds, micro_seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+
+    use crate::protocol::database::{DatabasePacket, PacketPayload};
+    use crate::protocol::database::mysql::constant::MySQLColumnType;
+    use crate::protocol::database::mysql::packet::{MySQLPacketHeader, MySQLPacketPayload};
+    use crate::session::mysql::{PrepareStatementContext, SessionContext};
+
+    use super::{MySQLBinaryResultSetRowPacket, MySQLComStmtExecutePacket, PrepareParamValue};
+
+    /// A row as MySQL sends it, the 3 bytes of the length left out, with the values of the
+    /// Binary Protocol Value examples.
+    const CAPTURED_ROW: [u8; 58] = [
+        0x04, // seq
+        0x00, // PACKET_HEADER
+        0x08, 0x08, // NULL bitmap, columns 1 and 9
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // BIGINT 1
+        0x05, 0x31, 0x30, 0x2e, 0x32, 0x30, // DECIMAL(10,2) 10.20
+        0xff, // TINYINT -1
+        0x0b, 0xda, 0x07, 0x0a, 0x11, 0x13, 0x1b, 0x1e, 0x01, 0x00, 0x00, 0x00, // DATETIME(6) 2010-10-17 19:27:30.000001
+        0x08, 0x01, 0x78, 0x00, 0x00, 0x00, 0x13, 0x1b, 0x1e, // TIME -2899:27:30
+        0x07, 0x7b, 0x22, 0x61, 0x22, 0x3a, 0x31, 0x7d, // JSON {"a":1}
+        0x01, 0x05, // BIT(3) b'101'
+        0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x24, 0x40, // DOUBLE 10.2
+    ];
+
+    fn column_types() -> Vec<(MySQLColumnType, bool)> {
+        vec![
+            (MySQLColumnType::MysqlTypeLonglong, false),
+            (MySQLColumnType::MysqlTypeVarString, false),
+            (MySQLColumnType::MysqlTypeNewDecimal, false),
+            (MySQLColumnType::MysqlTypeTiny, false),
+            (MySQLColumnType::MysqlTypeDatetime, false),
+            (MySQLColumnType::MysqlTypeTime, false),
+            (MySQLColumnType::MysqlTypeJson, false),
+            (MySQLColumnType::MysqlTypeBit, true),
+            (MySQLColumnType::MysqlTypeDouble, false),
+            (MySQLColumnType::MysqlTypeShort, false),
+        ]
+    }
+
+    fn values() -> Vec<PrepareParamValue> {
+        vec![
+            PrepareParamValue::Int(1),
+            PrepareParamValue::NULL,
+            PrepareParamValue::Bytes(b"10.20".to_vec()),
+            PrepareParamValue::Int(-1),
+            PrepareParamValue::Date(2010, 10, 17, 19, 27, 30, 1),
+            PrepareParamValue::Time(true, 120, 19, 27, 30, 0),
+            PrepareParamValue::Bytes(b"{\"a\":1}".to_vec()),
+            PrepareParamValue::Bytes(vec![0x05]),
+            PrepareParamValue::Double(10.2),
+            PrepareParamValue::NULL,
+        ]
+    }
+
+    #[test]
+    fn test_binary_row_conformance() {
+        let mut row_packet = MySQLBinaryResultSetRowPacket::new(4, column_types(), values());
+        let mut row_payload = MySQLPacketPayload::new();
+        let row_payload = DatabasePacket::encode(&mut row_packet, &mut row_payload);
+        assert_eq!(row_payload.get_payload().as_ref(), &CAPTURED_ROW[..]);
+
+        // Values in text form, as a text protocol backend returns them, encode the same.
+        let text_values = vec![
+            PrepareParamValue::Bytes(b"1".to_vec()),
+            PrepareParamValue::NULL,
+            PrepareParamValue::Bytes(b"10.20".to_vec()),
+            PrepareParamValue::Bytes(b"-1".to_vec()),
+            PrepareParamValue::Bytes(b"2010-10-17 19:27:30.000001".to_vec()),
+            PrepareParamValue::Bytes(b"-2899:27:30".to_vec()),
+            PrepareParamValue::Bytes(b"{\"a\":1}".to_vec()),
+            PrepareParamValue::Bytes(vec![0x05]),
+            PrepareParamValue::Bytes(b"10.2".to_vec()),
+            PrepareParamValue::NULL,
+        ];
+        let mut row_packet = MySQLBinaryResultSetRowPacket::new(4, column_types(), text_values);
+        let mut row_payload = MySQLPacketPayload::new();
+        let row_payload = DatabasePacket::encode(&mut row_packet, &mut row_payload);
+        assert_eq!(row_payload.get_payload().as_ref(), &CAPTURED_ROW[..]);
+
+        let header = MySQLPacketHeader::new((CAPTURED_ROW.len() - 1) as u64, 4, 0, 1);
+        let mut payload = MySQLPacketPayload::new_with_payload(BytesMut::from(&CAPTURED_ROW[1..]));
+        let mut session_ctx = SessionContext::new(1);
+        let mut row_packet = MySQLBinaryResultSetRowPacket::new(0, column_types(), vec![]);
+        let row_packet = DatabasePacket::decode(&mut row_packet, &header, &mut payload, &mut session_ctx);
+        assert!(row_packet.get_data() == values());
+    }
+
+    /// Encodes a row of `values`, checks the bytes after the sequence id, and decodes them back.
+    fn assert_row(column_types: Vec<(MySQLColumnType, bool)>, values: Vec<PrepareParamValue>, expected: &[u8], decoded: Vec<PrepareParamValue>) {
+        let mut row_packet = MySQLBinaryResultSetRowPacket::new(1, column_types.clone(), values);
+        let mut row_payload = MySQLPacketPayload::new();
+        let row_payload = DatabasePacket::encode(&mut row_packet, &mut row_payload);
+        assert_eq!(&row_payload.get_payload()[1..], expected);
+
+        let header = MySQLPacketHeader::new(expected.len() as u64, 1, 0, 1);
+        let mut payload = MySQLPacketPayload::new_with_payload(BytesMut::from(expected));
+        let mut session_ctx = SessionContext::new(1);
+        let mut row_packet = MySQLBinaryResultSetRowPacket::new(0, column_types, vec![]);
+        let row_packet = DatabasePacket::decode(&mut row_packet, &header, &mut payload, &mut session_ctx);
+        assert!(row_packet.get_data() == decoded);
+    }
+
+    #[test]
+    fn test_binary_row_null_bitmap() {
+        let ints = |count: usize| (1..=count as i64).map(PrepareParamValue::Int).collect::<Vec<_>>();
+        let longs = |count: usize| vec![(MySQLColumnType::MysqlTypeLong, false); count];
+        let longs_bytes = |count: usize| (1..=count as u8).flat_map(|i| vec![i, 0x00, 0x00, 0x00]).collect::<Vec<_>>();
+
+        // The 6th column is the last bit of the first byte.
+        let mut values = ints(5);
+        values.push(PrepareParamValue::NULL);
+        let expected = [&[0x00, 0x80][..], &longs_bytes(5)].concat();
+        assert_row(longs(6), values.clone(), &expected, values);
+
+        // The 7th column takes a second byte.
+        let mut values = ints(6);
+        values.push(PrepareParamValue::NULL);
+        let expected = [&[0x00, 0x00, 0x01][..], &longs_bytes(6)].concat();
+        assert_row(longs(7), values.clone(), &expected, values);
+
+        // A row of NULLs has no values after the bitmap.
+        let values = vec![PrepareParamValue::NULL; 3];
+        assert_row(longs(3), values.clone(), &[0x00, 0x1c], values);
+    }
+
+    #[test]
+    fn test_binary_row_temporal_lengths() {
+        let column_types = vec![
+            (MySQLColumnType::MysqlTypeDatetime, false),
+            (MySQLColumnType::MysqlTypeDate, false),
+            (MySQLColumnType::MysqlTypeTimestamp, false),
+            (MySQLColumnType::MysqlTypeDatetime, false),
+            (MySQLColumnType::MysqlTypeTime, false),
+            (MySQLColumnType::MysqlTypeTime, false),
+        ];
+        // Fractional seconds in text form are padded to micro seconds.
+        let values = vec![
+            PrepareParamValue::Bytes(b"0000-00-00 00:00:00".to_vec()),
+            PrepareParamValue::Bytes(b"2010-10-17".to_vec()),
+            PrepareParamValue::Bytes(b"2010-10-17 19:27:30".to_vec()),
+            PrepareParamValue::Bytes(b"2010-10-17 19:27:30.5".to_vec()),
+            PrepareParamValue::Bytes(b"00:00:00".to_vec()),
+            PrepareParamValue::Bytes(b"26:03:04.000005".to_vec()),
+        ];
+        let expected = [
+            0x00, // PACKET_HEADER
+            0x00, // NULL bitmap
+            0x00, // 0000-00-00 00:00:00
+            0x04, 0xda, 0x07, 0x0a, 0x11, // 2010-10-17
+            0x07, 0xda, 0x07, 0x0a, 0x11, 0x13, 0x1b, 0x1e, // 2010-10-17 19:27:30
+            0x0b, 0xda, 0x07, 0x0a, 0x11, 0x13, 0x1b, 0x1e, 0x20, 0xa1, 0x07, 0x00, // 2010-10-17 19:27:30.500000
+            0x00, // 00:00:00
+            0x0c, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x03, 0x04, 0x05, 0x00, 0x00, 0x00, // 26:03:04.000005
+        ];
+        let decoded = vec![
+            PrepareParamValue::Date(0, 0, 0, 0, 0, 0, 0),
+            PrepareParamValue::Date(2010, 10, 17, 0, 0, 0, 0),
+            PrepareParamValue::Date(2010, 10, 17, 19, 27, 30, 0),
+            PrepareParamValue::Date(2010, 10, 17, 19, 27, 30, 500000),
+            PrepareParamValue::Time(false, 0, 0, 0, 0, 0),
+            PrepareParamValue::Time(false, 1, 2, 3, 4, 5),
+        ];
+        assert_row(column_types, values, &expected, decoded);
+    }
+
+    /// A COM_STMT_EXECUTE as a client sends it, after the command byte, for 9 parameters of
+    /// which the 2nd and the 9th are NULL.
+    const CAPTURED_EXECUTE: [u8; 79] = [
+        0x01, 0x00, 0x00, 0x00, // statement id
+        0x00, // flags
+        0x01, 0x00, 0x00, 0x00, // iteration count
+        0x02, 0x01, // NULL bitmap, parameters 1 and 8
+        0x01, // new params bound flag
+        0x08, 0x80, 0x08, 0x00, 0xf6, 0x00, 0x0c, 0x00, 0x0a, 0x00, 0x0b, 0x00, 0xf5, 0x00, 0x01, 0x80, 0xfd, 0x00, // types
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, // BIGINT UNSIGNED 18446744073709551615
+        0x05, 0x31, 0x30, 0x2e, 0x32, 0x30, // DECIMAL 10.20
+        0x07, 0xda, 0x07, 0x0a, 0x11, 0x13, 0x1b, 0x1e, // DATETIME 2010-10-17 19:27:30
+        0x04, 0xda, 0x07, 0x0a, 0x11, // DATE 2010-10-17
+        0x0c, 0x01, 0x01, 0x00, 0x00, 0x00, 0x02, 0x03, 0x04, 0x05, 0x00, 0x00, 0x00, // TIME -26:03:04.000005
+        0x07, 0x7b, 0x22, 0x61, 0x22, 0x3a, 0x31, 0x7d, // JSON {"a":1}
+        0xff, // TINYINT UNSIGNED 255
+    ];
+
+    #[test]
+    fn test_execute_parameters_conformance() {
+        let sql = "INSERT INTO t_order VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)";
+        let mut session_ctx = SessionContext::new(1);
+        session_ctx.cache_prepare_stmt_ctx(sql.to_string(), PrepareStatementContext::new(1, 9, 0, Bytes::from(sql)));
+        let decode = |bytes: &[u8], session_ctx: &mut SessionContext| {
+            let header = MySQLPacketHeader::new(bytes.len() as u64 + 1, 0, 0x17, 1);
+            let mut payload = MySQLPacketPayload::new_with_payload(BytesMut::from(bytes));
+            let mut execute_packet = MySQLComStmtExecutePacket::new(0x17);
+            DatabasePacket::decode(&mut execute_packet, &header, &mut payload, session_ctx).get_parameters()
+        };
+
+        let parameters = decode(&CAPTURED_EXECUTE, &mut session_ctx);
+        assert!(parameters == vec![
+            PrepareParamValue::UInt(u64::MAX),
+            PrepareParamValue::NULL,
+            PrepareParamValue::Bytes(b"10.20".to_vec()),
+            PrepareParamValue::Date(2010, 10, 17, 19, 27, 30, 0),
+            PrepareParamValue::Date(2010, 10, 17, 0, 0, 0, 0),
+            PrepareParamValue::Time(true, 1, 2, 3, 4, 5),
+            PrepareParamValue::Bytes(b"{\"a\":1}".to_vec()),
+            PrepareParamValue::UInt(255),
+            PrepareParamValue::NULL,
+        ]);
+
+        // A later execute without types reuses the ones of the first, unsigned flag included.
+        let mut again = vec![0x01, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0xfe, 0x01, 0x00];
+        again.extend_from_slice(&[0xff; 8]);
+        let parameters = decode(&again, &mut session_ctx);
+        assert!(parameters[0] == PrepareParamValue::UInt(u64::MAX));
+        assert!(parameters[1..].iter().all(|parameter| *parameter == PrepareParamValue::NULL));
+    }
+}
@@ -61,6 +61,9 @@ impl MySQLPacketHeader {
 /**
  * MySQL payload operation for MySQL packet data types.
  *
+ * Strings are read as `Bytes` slices of the payload, which share the receive buffer the
+ * payload was split from rather than copying out of it.
+ *
  * @see <a href="https://dev.mysql.com/doc/internals/en/describing-packets.html">describing packets</a>
  */
 
@@ -81,6 +84,11 @@ impl MySQLPacketPayload {
         }
     }
 
+    /// Makes room for `additional` bytes, so a packet of known size is written without growing.
+    pub fn reserve(&mut self, additional: usize) {
+        self.bytes_mut.reserve(additional);
+    }
+
     pub fn put_u8(&mut self, val: u8) {
         self.bytes_mut.put_u8(val);
     }
@@ -152,7 +160,7 @@ impl MySQLPacketPayload {
 
     // string with nul
     pub fn get_string_nul(&mut self) -> String {
-        let pos = match self.bytes_mut.to_vec().iter().position(|&x| x == 0) {
+        let pos = match self.bytes_mut.iter().position(|&x| x == 0) {
             Some(pos) => pos,
             None => 0 // TODO
         };
@@ -160,7 +168,7 @@ impl MySQLPacketPayload {
             "".to_string()
         } else {
             let bytes = self.bytes_mut.split_to(pos);
-            let result = String::from_utf8_lossy(bytes.to_vec().as_slice()).to_string();
+            let result = String::from_utf8_lossy(&bytes).to_string();
             self.bytes_mut.advance(1);
             result
         }
@@ -234,9 +242,9 @@ impl MySQLPacketPayload {
      *
      * @return lenenc bytes
      */
-    pub fn get_string_lenenc(&mut self) -> Vec<u8> {
+    pub fn get_string_lenenc(&mut self) -> Bytes {
         let length = self.get_int_lenenc() as u32;
-        self.bytes_mut.split_to(length as usize).to_vec()
+        self.bytes_mut.split_to(length as usize).freeze()
     }
 
     /**
@@ -248,9 +256,9 @@ impl MySQLPacketPayload {
     *
     * @return fixed length bytes
     */
-    pub fn get_string_fix(&mut self) -> Vec<u8> {
+    pub fn get_string_fix(&mut self) -> Bytes {
         let length = self.bytes_mut.get_uint(1) as u32 & 0xff;
-        self.bytes_mut.split_to(length as usize).to_vec()
+        self.bytes_mut.split_to(length as usize).freeze()
     }
 
     /**
@@ -262,8 +270,8 @@ impl MySQLPacketPayload {
     *
     * @return fixed length bytes
     */
-    pub fn get_string_fix_length(&mut self, length: u32) -> Vec<u8> {
-        self.bytes_mut.split_to(length as usize).to_vec()
+    pub fn get_string_fix_length(&mut self, length: u32) -> Bytes {
+        self.bytes_mut.split_to(length as usize).freeze()
     }
 
     /**
@@ -273,8 +281,8 @@ impl MySQLPacketPayload {
      *
      * @return rest of packet string bytes
      */
-    pub fn get_remaining_bytes(&mut self) -> Vec<u8> {
-        self.bytes_mut.to_vec()
+    pub fn get_remaining_bytes(&mut self) -> Bytes {
+        self.bytes_mut.split().freeze()
     }
 }
 
@@ -326,6 +334,8 @@ impl MySQLHandshakePacket {
         capability_flags |= MySQLCapabilityFlag::CLIENT_SECURE_CONNECTION;
 
         capability_flags |= MySQLCapabilityFlag::CLIENT_PLUGIN_AUTH;
+        capability_flags |= MySQLCapabilityFlag::CLIENT_CONNECT_ATTRS;
+        capability_flags |= MySQLCapabilityFlag::CLIENT_DEPRECATE_EOF;
 
         MySQLHandshakePacket {
             protocol_version: PROTOCOL_VERSION,
@@ -339,6 +349,16 @@ impl MySQLHandshakePacket {
             auth_plugin_name: MySQLAuthenticationMethod::SecurePasswordAuthentication.value().to_string(),
         }
     }
+
+    /// Advertises that the client may upgrade the connection to TLS.
+    /// The collation id the server offers, see `charset`.
+    pub fn set_character_set(&mut self, character_set: u8) {
+        self.character_set = character_set;
+    }
+
+    pub fn enable_ssl(&mut self) {
+        self.capability_flags |= MySQLCapabilityFlag::CLIENT_SSL;
+    }
 }
 
 impl MySQLPacket for MySQLHandshakePacket {
@@ -404,6 +424,16 @@ impl MySQLAuthSwitchRequestPacket {
             auth_plugin_name: MySQLAuthenticationMethod::SecurePasswordAuthentication.value().to_string(),
         }
     }
+
+    /// Asks the client for the password as typed.
+    pub fn clear_text(sequence_id: u32) -> Self {
+        MySQLAuthSwitchRequestPacket {
+            sequence_id,
+            seed1: vec![],
+            seed2: vec![],
+            auth_plugin_name: MySQLAuthenticationMethod::ClearTextAuthentication.value().to_string(),
+        }
+    }
 }
 
 impl MySQLPacket for MySQLAuthSwitchRequestPacket {
@@ -437,6 +467,7 @@ pub struct MySQLHandshakeResponse41Packet {
     capability_flags: MySQLCapabilityFlag,
     database: String,
     auth_plugin_name: String,
+    connect_attrs: Vec<(String, String)>,
 }
 
 impl MySQLHandshakeResponse41Packet {
@@ -450,6 +481,7 @@ impl MySQLHandshakeResponse41Packet {
             capability_flags: MySQLCapabilityFlag::empty(),
             database: "".to_string(),
             auth_plugin_name: "".to_string(),
+            connect_attrs: vec![],
         }
     }
 
@@ -469,9 +501,55 @@ impl MySQLHandshakeResponse41Packet {
         self.capability_flags
     }
 
+    /// The collation id the client asks for.
+    pub fn get_character_set(&self) -> u8 {
+        self.character_set
+    }
+
     pub fn get_auth_plugin_name(&self) -> String {
         self.auth_plugin_name.clone()
     }
+
+    /// The connection attributes the client sent, e.g. `_client_name`, in the order sent.
+    pub fn get_connect_attrs(&self) -> Vec<(String, String)> {
+        self.connect_attrs.clone()
+    }
+}
+
+/// A lenenc string, or None when `payload` is too short for it.
+fn get_string_lenenc_checked(payload: &mut MySQLPacketPayload) -> Option<BytesMut> {
+    let width = match *payload.bytes_mut.first()? {
+        0xfc => 3,
+        0xfd => 4,
+        0xfe => 9,
+        _ => 1,
+    };
+    if payload.bytes_mut.len() < width {
+        return None;
+    }
+    let length = payload.get_int_lenenc() as usize;
+    if payload.bytes_mut.len() < length {
+        return None;
+    }
+    Some(payload.bytes_mut.split_to(length))
+}
+
+/**
+ * Read the key/value pairs of the connection attributes, malformed ones end the list rather
+ * than the login.
+ *
+ * @see <a href="https://dev.mysql.com/doc/internals/en/connection-phase-packets.html#packet-Protocol::HandshakeResponse41">HandshakeResponse41</a>
+ */
+fn get_connect_attrs(payload: &mut MySQLPacketPayload) -> Vec<(String, String)> {
+    let mut connect_attrs = vec![];
+    let mut attrs = match get_string_lenenc_checked(payload) {
+        Some(attrs) => MySQLPacketPayload::new_with_payload(attrs),
+        None => return connect_attrs,
+    };
+    while let (Some(key), Some(value)) = (get_string_lenenc_checked(&mut attrs), get_string_lenenc_checked(&mut attrs)) {
+        connect_attrs.push((String::from_utf8_lossy(&key).to_string(), String::from_utf8_lossy(&value).to_string()));
+    }
+    connect_attrs
 }
 
 impl DatabasePacket<MySQLPacketHeader, MySQLPacketPayload, SessionContext> for MySQLHandshakeResponse41Packet {
@@ -486,9 +564,9 @@ impl DatabasePacket<MySQLPacketHeader, MySQLPacketPayload, SessionContext> for M
         this.user_name = payload.get_string_nul();
 
         this.auth_response = if this.capability_flags.contains(MySQLCapabilityFlag::CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA) {
-            payload.get_string_lenenc()
+            payload.get_string_lenenc().to_vec()
         } else if this.capability_flags.contains(MySQLCapabilityFlag::CLIENT_SECURE_CONNECTION) {
-            payload.get_string_fix()
+            payload.get_string_fix().to_vec()
         } else {
             let auth = payload.get_string_nul();
             auth.into_bytes()
@@ -505,6 +583,10 @@ impl DatabasePacket<MySQLPacketHeader, MySQLPacketPayload, SessionContext> for M
         } else {
             String::from("")
         };
+
+        if this.capability_flags.contains(MySQLCapabilityFlag::CLIENT_CONNECT_ATTRS) {
+            this.connect_attrs = get_connect_attrs(payload);
+        }
         this
     }
 }
@@ -541,7 +623,7 @@ impl MySQLAuthSwitchResponsePacket {
 impl DatabasePacket<MySQLPacketHeader, MySQLPacketPayload, SessionContext> for MySQLAuthSwitchResponsePacket {
     fn decode<'p, 'd>(this: &'d mut Self, header: &'p MySQLPacketHeader, payload: &'p mut MySQLPacketPayload, session_ctx: &mut SessionContext) -> &'d mut Self {
         this.sequence_id = header.sequence_id;
-        this.auth_response = payload.get_remaining_bytes();
+        this.auth_response = payload.get_remaining_bytes().to_vec();
         this
     }
 }
@@ -578,6 +660,14 @@ impl MySQLEOFPacket {
             status_flags: MySQLStatusFlag::ServerStatusAutocommit as u16,
         }
     }
+
+    pub fn set_warnings(&mut self, warnings: u16) {
+        self.warnings = warnings;
+    }
+
+    pub fn set_status_flags(&mut self, status_flags: u16) {
+        self.status_flags = status_flags;
+    }
 }
 
 impl DatabasePacket<MySQLPacketHeader, MySQLPacketPayload, SessionContext> for MySQLEOFPacket {
@@ -627,6 +717,24 @@ impl MySQLOKPacket {
             info: "".to_string(),
         }
     }
+
+    /// The OK packet that takes the place of an EOF packet when the client negotiated
+    /// CLIENT_DEPRECATE_EOF, told apart from an OK packet by its 0xfe header.
+    pub fn new_eof(sequence_id: u32, warnings: u16, status_flags: u16) -> Self {
+        let mut ok_packet = MySQLOKPacket::new(sequence_id, 0, 0);
+        ok_packet.header = 0xfe;
+        ok_packet.warnings = warnings as u32;
+        ok_packet.status_flag = status_flags as u32;
+        ok_packet
+    }
+
+    pub fn set_status_flags(&mut self, status_flags: u16) {
+        self.status_flag = status_flags as u32;
+    }
+
+    pub fn set_warnings(&mut self, warnings: u16) {
+        self.warnings = warnings as u32;
+    }
 }
 
 impl DatabasePacket<MySQLPacketHeader, MySQLPacketPayload, SessionContext> for MySQLOKPacket {
@@ -677,7 +785,7 @@ impl MySQLErrPacket {
             header: 0xff,
             sql_state_marker: "#".to_string(),
             sequence_id,
-            error_code: 0,
+            error_code,
             sql_state: sql_state,
             error_message: error_message,
         }
@@ -831,7 +939,7 @@ pub struct MySQLComInitDbPacket {
     sequence_id: u32,
     /// MySQLCommandPacketType,
     command_type: u8,
-    schema: Vec<u8>,
+    schema: Bytes,
 }
 
 impl MySQLComInitDbPacket {
@@ -839,11 +947,11 @@ impl MySQLComInitDbPacket {
         MySQLComInitDbPacket {
             sequence_id: 0,
             command_type: command_type, // MySQLCommandPacketType::value_of(command_type & 0xff),
-            schema: vec![],
+            schema: Bytes::new(),
         }
     }
 
-    pub fn get_schema(&self) -> Vec<u8> {
+    pub fn get_schema(&self) -> Bytes {
         self.schema.clone()
     }
 
@@ -854,8 +962,7 @@ impl MySQLComInitDbPacket {
 
 impl DatabasePacket<MySQLPacketHeader, MySQLPacketPayload, SessionContext> for MySQLComInitDbPacket {
     fn decode<'p, 'd>(this: &'d mut Self, header: &'p MySQLPacketHeader, payload: &'p mut MySQLPacketPayload, session_ctx: &mut SessionContext) -> &'d mut Self {
-        let bytes = payload.get_remaining_bytes();
-        this.schema = Vec::from(bytes.as_slice());
+        this.schema = payload.get_remaining_bytes();
         this
     }
 }
@@ -876,7 +983,7 @@ pub struct MySQLComFieldListPacket {
     /// MySQLCommandPacketType,
     command_type: u8,
     table: Vec<u8>,
-    field_wildcard: Vec<u8>,
+    field_wildcard: Bytes,
 }
 
 impl MySQLComFieldListPacket {
@@ -885,7 +992,7 @@ impl MySQLComFieldListPacket {
             sequence_id: 0,
             command_type: command_type, // MySQLCommandPacketType::value_of(command_type & 0xff),
             table: vec![],
-            field_wildcard: vec![],
+            field_wildcard: Bytes::new(),
         }
     }
 
@@ -893,7 +1000,7 @@ impl MySQLComFieldListPacket {
         self.table.clone()
     }
 
-    pub fn get_field_wildcard(&self) -> Vec<u8> {
+    pub fn get_field_wildcard(&self) -> Bytes {
         self.field_wildcard.clone()
     }
 
@@ -905,8 +1012,7 @@ impl MySQLComFieldListPacket {
 impl DatabasePacket<MySQLPacketHeader, MySQLPacketPayload, SessionContext> for MySQLComFieldListPacket {
     fn decode<'p, 'd>(this: &'d mut Self, header: &'p MySQLPacketHeader, payload: &'p mut MySQLPacketPayload, session_ctx: &mut SessionContext) -> &'d mut Self {
         this.table = payload.get_string_nul().into_bytes();
-        let bytes = payload.get_remaining_bytes();
-        this.field_wildcard = Vec::from(bytes.as_slice());
+        this.field_wildcard = payload.get_remaining_bytes();
         this
     }
 }
@@ -915,4 +1021,53 @@ impl MySQLPacket for MySQLComFieldListPacket {
     fn get_sequence_id(&self) -> u32 {
         self.sequence_id
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::{get_connect_attrs, MySQLPacketPayload};
+
+    #[test]
+    fn test_strings_slice_the_payload() {
+        let mut payload = MySQLPacketPayload::new();
+        payload.put_string_with_nul(b"app");
+        payload.put_string_lenenc(b"SELECT 1");
+        payload.put_slice(b"rest");
+        let received = BytesMut::from(&payload.bytes_mut[..]);
+        let start = received.as_ptr() as usize;
+
+        let mut payload = MySQLPacketPayload::new_with_payload(received);
+        assert_eq!(payload.get_string_nul(), "app");
+        let sql = payload.get_string_lenenc();
+        assert_eq!(&sql[..], b"SELECT 1");
+        assert_eq!(sql.as_ptr() as usize, start + 5);
+        let rest = payload.get_remaining_bytes();
+        assert_eq!(&rest[..], b"rest");
+        assert_eq!(rest.as_ptr() as usize, start + 13);
+        assert!(payload.get_remaining_bytes().is_empty());
+    }
+
+    #[test]
+    fn test_connect_attrs() {
+        let mut attrs = MySQLPacketPayload::new();
+        attrs.put_string_lenenc(b"_client_name");
+        attrs.put_string_lenenc(b"libmysql");
+        attrs.put_string_lenenc(b"traceparent");
+        attrs.put_string_lenenc(b"00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01");
+        let mut payload = MySQLPacketPayload::new();
+        payload.put_string_lenenc(&attrs.bytes_mut);
+        let mut received = MySQLPacketPayload::new_with_payload(BytesMut::from(&payload.bytes_mut[..]));
+        assert_eq!(get_connect_attrs(&mut received), vec![
+            ("_client_name".to_string(), "libmysql".to_string()),
+            ("traceparent".to_string(), "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".to_string()),
+        ]);
+
+        // Cut short, the attributes are left out rather than failing the login.
+        let truncated = payload.bytes_mut.len() - 10;
+        let mut received = MySQLPacketPayload::new_with_payload(BytesMut::from(&payload.bytes_mut[..truncated]));
+        assert!(get_connect_attrs(&mut received).is_empty());
+        let mut received = MySQLPacketPayload::new_with_payload(BytesMut::new());
+        assert!(get_connect_attrs(&mut received).is_empty());
+    }
+}
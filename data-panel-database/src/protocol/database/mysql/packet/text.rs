@@ -1,3 +1,5 @@
+use bytes::Bytes;
+
 use crate::protocol::database::DatabasePacket;
 use crate::protocol::database::mysql::packet::{MySQLPacket, MySQLPacketHeader, MySQLPacketPayload};
 use crate::session::mysql::SessionContext;
@@ -10,7 +12,8 @@ use crate::session::mysql::SessionContext;
 pub struct MySQLComQueryPacket {
     sequence_id: u32,
     command_type: u8,
-    sql: Vec<u8>,
+    /// A slice of the received packet.
+    sql: Bytes,
 }
 
 impl MySQLComQueryPacket {
@@ -18,11 +21,11 @@ impl MySQLComQueryPacket {
         MySQLComQueryPacket {
             sequence_id: 0,
             command_type: command_type, // MySQLCommandPacketType::value_of(command_type & 0xff),
-            sql: vec![],
+            sql: Bytes::new(),
         }
     }
 
-    pub fn get_sql(&self) -> Vec<u8> {
+    pub fn get_sql(&self) -> Bytes {
         self.sql.clone()
     }
 
@@ -33,8 +36,7 @@ impl MySQLComQueryPacket {
 
 impl DatabasePacket<MySQLPacketHeader, MySQLPacketPayload, SessionContext> for MySQLComQueryPacket {
     fn decode<'p, 'd>(this: &'d mut Self, header: &'p MySQLPacketHeader, payload: &'p mut MySQLPacketPayload, session_ctx: &mut SessionContext) -> &'d mut Self {
-        let bytes = payload.get_remaining_bytes();
-        this.sql = Vec::from(bytes.as_slice());
+        this.sql = payload.get_remaining_bytes();
         this
     }
 }
@@ -72,6 +74,8 @@ impl MySQLPacket for MySQLTextResultSetRowPacket {
 
 impl DatabasePacket<MySQLPacketHeader, MySQLPacketPayload, SessionContext> for MySQLTextResultSetRowPacket {
     fn encode<'p, 'd>(this: &'d mut Self, payload: &'p mut MySQLPacketPayload) -> &'p mut MySQLPacketPayload {
+        let len: usize = this.data.iter().map(|(_, col_v)| col_v.len() + 9).sum();
+        payload.reserve(len + 1);
         payload.put_u8(this.get_sequence_id() as u8); // seq
 
         for (null, col_v) in this.data.iter() {
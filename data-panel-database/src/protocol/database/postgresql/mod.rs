@@ -0,0 +1,381 @@
+//! PostgreSQL frontend/backend protocol 3.0, as far as the protocol bridge needs it.
+//!
+//! @see <a href="https://www.postgresql.org/docs/current/protocol-message-formats.html">Message Formats</a>
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::Decoder;
+
+pub const PROTOCOL_VERSION_3: u32 = 196608;
+pub const CANCEL_REQUEST_CODE: u32 = 80877102;
+pub const SSL_REQUEST_CODE: u32 = 80877103;
+pub const GSSENC_REQUEST_CODE: u32 = 80877104;
+
+/// Messages above this length are refused instead of buffered.
+const MAX_MESSAGE_LEN: usize = 64 * 1024 * 1024;
+
+/// Type oids the bridge describes result columns and parameters with.
+pub const OID_BOOL: u32 = 16;
+pub const OID_BYTEA: u32 = 17;
+pub const OID_INT8: u32 = 20;
+pub const OID_INT2: u32 = 21;
+pub const OID_INT4: u32 = 23;
+pub const OID_TEXT: u32 = 25;
+pub const OID_FLOAT4: u32 = 700;
+pub const OID_FLOAT8: u32 = 701;
+pub const OID_DATE: u32 = 1082;
+pub const OID_TIME: u32 = 1083;
+pub const OID_TIMESTAMP: u32 = 1114;
+pub const OID_NUMERIC: u32 = 1700;
+
+/// Transaction status sent with ReadyForQuery.
+pub const TRANSACTION_IDLE: u8 = b'I';
+pub const TRANSACTION_BLOCK: u8 = b'T';
+pub const TRANSACTION_FAILED: u8 = b'E';
+
+/// One message as read off the wire. Messages of the startup phase carry no tag, they are
+/// told apart by the code at the start of their body.
+#[derive(Debug)]
+pub struct PostgreSQLFrame {
+    tag: Option<u8>,
+    body: BytesMut,
+}
+
+impl PostgreSQLFrame {
+    pub fn get_tag(&self) -> Option<u8> {
+        self.tag
+    }
+}
+
+/// Splits the byte stream into frames, untagged until the startup message was read.
+pub struct PostgreSQLCodec {
+    startup: bool,
+}
+
+impl PostgreSQLCodec {
+    pub fn new() -> Self {
+        PostgreSQLCodec {
+            startup: true,
+        }
+    }
+}
+
+impl Decoder for PostgreSQLCodec {
+    type Item = PostgreSQLFrame;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<PostgreSQLFrame>, Error> {
+        let header_len = if self.startup { 4 } else { 5 };
+        if src.len() < header_len {
+            return Ok(None);
+        }
+        let offset = header_len - 4;
+        let len = u32::from_be_bytes([src[offset], src[offset + 1], src[offset + 2], src[offset + 3]]) as usize;
+        if len < 4 || len > MAX_MESSAGE_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, format!("invalid message length {}", len)));
+        }
+        if src.len() < offset + len {
+            src.reserve(offset + len - src.len());
+            return Ok(None);
+        }
+        let mut frame = src.split_to(offset + len);
+        let tag = if self.startup { None } else { Some(frame[0]) };
+        frame.advance(header_len);
+        if self.startup && frame.len() >= 4 {
+            let code = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]);
+            // The client starts over after the answer to an SSL or GSS encryption request.
+            self.startup = code == SSL_REQUEST_CODE || code == GSSENC_REQUEST_CODE;
+        }
+        Ok(Some(PostgreSQLFrame { tag, body: frame }))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FrontendMessage {
+    /// SSL or GSS encryption request, answered with `N`.
+    EncryptionRequest,
+    CancelRequest,
+    Startup { params: HashMap<String, String> },
+    Password(String),
+    Query(String),
+    Parse { name: String, sql: String, param_types: Vec<u32> },
+    Bind { portal: String, statement: String, formats: Vec<i16>, params: Vec<Option<Vec<u8>>> },
+    Describe { kind: u8, name: String },
+    Execute { portal: String, max_rows: i32 },
+    Close { kind: u8, name: String },
+    Sync,
+    Flush,
+    Terminate,
+    Unknown(u8),
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn get_cstring(buf: &mut BytesMut) -> Result<String, Error> {
+    let end = buf.iter().position(|b| *b == 0).ok_or_else(|| invalid("unterminated string"))?;
+    let value = String::from_utf8_lossy(&buf[..end]).to_string();
+    buf.advance(end + 1);
+    Ok(value)
+}
+
+fn get_i16(buf: &mut BytesMut) -> Result<i16, Error> {
+    if buf.remaining() < 2 {
+        return Err(invalid("truncated message"));
+    }
+    Ok(buf.get_i16())
+}
+
+fn get_i32(buf: &mut BytesMut) -> Result<i32, Error> {
+    if buf.remaining() < 4 {
+        return Err(invalid("truncated message"));
+    }
+    Ok(buf.get_i32())
+}
+
+impl FrontendMessage {
+    pub fn decode(frame: PostgreSQLFrame) -> Result<Self, Error> {
+        let mut body = frame.body;
+        let tag = match frame.tag {
+            Some(tag) => tag,
+            None => {
+                let code = get_i32(&mut body)? as u32;
+                return match code {
+                    SSL_REQUEST_CODE | GSSENC_REQUEST_CODE => Ok(FrontendMessage::EncryptionRequest),
+                    CANCEL_REQUEST_CODE => Ok(FrontendMessage::CancelRequest),
+                    PROTOCOL_VERSION_3 => {
+                        let mut params = HashMap::new();
+                        loop {
+                            let name = get_cstring(&mut body)?;
+                            if name.is_empty() {
+                                break;
+                            }
+                            params.insert(name, get_cstring(&mut body)?);
+                        }
+                        Ok(FrontendMessage::Startup { params })
+                    }
+                    _ => Err(invalid(&format!("unsupported protocol version {}", code))),
+                };
+            }
+        };
+        let message = match tag {
+            b'p' => FrontendMessage::Password(get_cstring(&mut body)?),
+            b'Q' => FrontendMessage::Query(get_cstring(&mut body)?),
+            b'P' => {
+                let name = get_cstring(&mut body)?;
+                let sql = get_cstring(&mut body)?;
+                let count = get_i16(&mut body)?;
+                let mut param_types = Vec::with_capacity(count.max(0) as usize);
+                for _ in 0..count {
+                    param_types.push(get_i32(&mut body)? as u32);
+                }
+                FrontendMessage::Parse { name, sql, param_types }
+            }
+            b'B' => {
+                let portal = get_cstring(&mut body)?;
+                let statement = get_cstring(&mut body)?;
+                let count = get_i16(&mut body)?;
+                let mut formats = Vec::with_capacity(count.max(0) as usize);
+                for _ in 0..count {
+                    formats.push(get_i16(&mut body)?);
+                }
+                let count = get_i16(&mut body)?;
+                let mut params = Vec::with_capacity(count.max(0) as usize);
+                for _ in 0..count {
+                    let len = get_i32(&mut body)?;
+                    if len < 0 {
+                        params.push(None);
+                    } else if body.remaining() < len as usize {
+                        return Err(invalid("truncated parameter"));
+                    } else {
+                        params.push(Some(body.split_to(len as usize).to_vec()));
+                    }
+                }
+                FrontendMessage::Bind { portal, statement, formats, params }
+            }
+            b'D' | b'C' => {
+                if body.remaining() < 1 {
+                    return Err(invalid("truncated message"));
+                }
+                let kind = body.get_u8();
+                let name = get_cstring(&mut body)?;
+                if tag == b'D' {
+                    FrontendMessage::Describe { kind, name }
+                } else {
+                    FrontendMessage::Close { kind, name }
+                }
+            }
+            b'E' => {
+                let portal = get_cstring(&mut body)?;
+                FrontendMessage::Execute { portal, max_rows: get_i32(&mut body)? }
+            }
+            b'S' => FrontendMessage::Sync,
+            b'H' => FrontendMessage::Flush,
+            b'X' => FrontendMessage::Terminate,
+            tag => FrontendMessage::Unknown(tag),
+        };
+        Ok(message)
+    }
+}
+
+fn message(tag: u8, body: BytesMut) -> Bytes {
+    let mut message = BytesMut::with_capacity(body.len() + 5);
+    message.put_u8(tag);
+    message.put_u32(body.len() as u32 + 4);
+    message.put_slice(&body);
+    message.freeze()
+}
+
+fn put_cstring(buf: &mut BytesMut, value: &str) {
+    buf.put_slice(value.as_bytes());
+    buf.put_u8(0);
+}
+
+pub fn authentication_ok() -> Bytes {
+    let mut body = BytesMut::new();
+    body.put_u32(0);
+    message(b'R', body)
+}
+
+pub fn authentication_cleartext_password() -> Bytes {
+    let mut body = BytesMut::new();
+    body.put_u32(3);
+    message(b'R', body)
+}
+
+pub fn parameter_status(name: &str, value: &str) -> Bytes {
+    let mut body = BytesMut::new();
+    put_cstring(&mut body, name);
+    put_cstring(&mut body, value);
+    message(b'S', body)
+}
+
+pub fn backend_key_data(process_id: u32, secret_key: u32) -> Bytes {
+    let mut body = BytesMut::new();
+    body.put_u32(process_id);
+    body.put_u32(secret_key);
+    message(b'K', body)
+}
+
+pub fn ready_for_query(transaction_status: u8) -> Bytes {
+    let mut body = BytesMut::new();
+    body.put_u8(transaction_status);
+    message(b'Z', body)
+}
+
+/// Describes result columns by name and type oid, all sent in text format.
+pub fn row_description(columns: &[(String, u32)]) -> Bytes {
+    let mut body = BytesMut::new();
+    body.put_i16(columns.len() as i16);
+    for (name, oid) in columns {
+        put_cstring(&mut body, name);
+        body.put_u32(0); // table oid
+        body.put_i16(0); // column number
+        body.put_u32(*oid);
+        body.put_i16(-1); // type size
+        body.put_i32(-1); // type modifier
+        body.put_i16(0); // text format
+    }
+    message(b'T', body)
+}
+
+pub fn data_row(values: &[Option<String>]) -> Bytes {
+    let mut body = BytesMut::new();
+    body.put_i16(values.len() as i16);
+    for value in values {
+        match value {
+            Some(value) => {
+                body.put_i32(value.len() as i32);
+                body.put_slice(value.as_bytes());
+            }
+            None => body.put_i32(-1),
+        }
+    }
+    message(b'D', body)
+}
+
+pub fn command_complete(command_tag: &str) -> Bytes {
+    let mut body = BytesMut::new();
+    put_cstring(&mut body, command_tag);
+    message(b'C', body)
+}
+
+pub fn parameter_description(param_types: &[u32]) -> Bytes {
+    let mut body = BytesMut::new();
+    body.put_i16(param_types.len() as i16);
+    for oid in param_types {
+        body.put_u32(*oid);
+    }
+    message(b't', body)
+}
+
+pub fn parse_complete() -> Bytes {
+    message(b'1', BytesMut::new())
+}
+
+pub fn bind_complete() -> Bytes {
+    message(b'2', BytesMut::new())
+}
+
+pub fn close_complete() -> Bytes {
+    message(b'3', BytesMut::new())
+}
+
+pub fn no_data() -> Bytes {
+    message(b'n', BytesMut::new())
+}
+
+pub fn empty_query_response() -> Bytes {
+    message(b'I', BytesMut::new())
+}
+
+/// An ERROR with the SQLSTATE `code`.
+pub fn error_response(code: &str, text: &str) -> Bytes {
+    let mut body = BytesMut::new();
+    body.put_u8(b'S');
+    put_cstring(&mut body, "ERROR");
+    body.put_u8(b'V');
+    put_cstring(&mut body, "ERROR");
+    body.put_u8(b'C');
+    put_cstring(&mut body, code);
+    body.put_u8(b'M');
+    put_cstring(&mut body, text);
+    body.put_u8(0);
+    message(b'E', body)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, BytesMut};
+    use tokio_util::codec::Decoder;
+
+    use crate::protocol::database::postgresql::{FrontendMessage, PostgreSQLCodec, PROTOCOL_VERSION_3, SSL_REQUEST_CODE};
+
+    #[test]
+    fn test_decode_startup_then_query() {
+        let mut codec = PostgreSQLCodec::new();
+        let mut src = BytesMut::new();
+        src.put_u32(8);
+        src.put_u32(SSL_REQUEST_CODE);
+        let startup = b"user\0martlet\0\0";
+        src.put_u32(8 + startup.len() as u32);
+        src.put_u32(PROTOCOL_VERSION_3);
+        src.put_slice(startup);
+        src.put_u8(b'Q');
+        src.put_u32(4 + 9);
+        src.put_slice(b"SELECT 1\0");
+
+        let ssl = FrontendMessage::decode(codec.decode(&mut src).unwrap().unwrap()).unwrap();
+        assert_eq!(ssl, FrontendMessage::EncryptionRequest);
+        match FrontendMessage::decode(codec.decode(&mut src).unwrap().unwrap()).unwrap() {
+            FrontendMessage::Startup { params } => assert_eq!(params.get("user").unwrap(), "martlet"),
+            message => panic!("unexpected {:?}", message),
+        }
+        let query = FrontendMessage::decode(codec.decode(&mut src).unwrap().unwrap()).unwrap();
+        assert_eq!(query, FrontendMessage::Query("SELECT 1".to_string()));
+        assert!(codec.decode(&mut src).unwrap().is_none());
+    }
+}
@@ -0,0 +1,98 @@
+//! HTTP/2 proxying: requests are forwarded stream by stream with their bodies and trailers,
+//! gRPC errors of the proxy itself are answered the way gRPC clients expect them.
+
+use hyper::{Body, HeaderMap, Request, Response, StatusCode, Uri};
+use hyper::header::{CONTENT_TYPE, HeaderName, HeaderValue};
+use hyper::http::uri::PathAndQuery;
+
+/// Headers not forwarded: the connection specific ones HTTP/2 forbids, and the host, which
+/// comes from the uri.
+const HOP_BY_HOP_HEADERS: [&str; 6] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+    "host",
+];
+
+/// gRPC status codes answered by the proxy.
+pub const GRPC_UNIMPLEMENTED: u32 = 12;
+pub const GRPC_INTERNAL: u32 = 13;
+pub const GRPC_UNAVAILABLE: u32 = 14;
+
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers.get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map_or(false, |content_type| content_type.starts_with("application/grpc"))
+}
+
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    for name in HOP_BY_HOP_HEADERS.iter() {
+        headers.remove(*name);
+    }
+}
+
+/// `request` addressed to `upstream`, its path and query kept.
+pub fn upstream_request(request: Request<Body>, upstream: &Uri) -> Result<Request<Body>, hyper::http::Error> {
+    let (mut parts, body) = request.into_parts();
+    let path_and_query = parts.uri.path_and_query().cloned().unwrap_or_else(|| PathAndQuery::from_static("/"));
+    let mut uri = Uri::builder().path_and_query(path_and_query);
+    if let Some(scheme) = upstream.scheme() {
+        uri = uri.scheme(scheme.clone());
+    }
+    if let Some(authority) = upstream.authority() {
+        uri = uri.authority(authority.clone());
+    }
+    parts.uri = uri.build()?;
+    strip_hop_by_hop(&mut parts.headers);
+    Ok(Request::from_parts(parts, body))
+}
+
+/// The upstream's response on its way back to the client.
+pub fn downstream_response(response: Response<Body>) -> Response<Body> {
+    let (mut parts, body) = response.into_parts();
+    strip_hop_by_hop(&mut parts.headers);
+    Response::from_parts(parts, body)
+}
+
+/// An error of the proxy itself. gRPC clients get a trailers-only response carrying
+/// `grpc_status`, the others `status` with the message as body.
+pub fn error_response(grpc: bool, status: StatusCode, grpc_status: u32, message: &str) -> Response<Body> {
+    if !grpc {
+        let mut response = Response::new(Body::from(message.to_string()));
+        *response.status_mut() = status;
+        return response;
+    }
+    let mut response = Response::new(Body::empty());
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+    headers.insert(HeaderName::from_static("grpc-status"), HeaderValue::from(grpc_status));
+    if let Ok(message) = HeaderValue::from_str(message) {
+        headers.insert(HeaderName::from_static("grpc-message"), message);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Body, Request, Uri};
+
+    use super::upstream_request;
+
+    #[test]
+    fn test_upstream_request() {
+        let request = Request::builder()
+            .uri("http://mesh:15001/helloworld.Greeter/SayHello?debug=1")
+            .header("content-type", "application/grpc")
+            .header("connection", "keep-alive")
+            .header("te", "trailers")
+            .body(Body::empty())
+            .unwrap();
+        let upstream = "http://greeter:50051".parse::<Uri>().unwrap();
+        let request = upstream_request(request, &upstream).unwrap();
+        assert_eq!(request.uri().to_string(), "http://greeter:50051/helloworld.Greeter/SayHello?debug=1");
+        assert!(request.headers().get("connection").is_none());
+        assert_eq!(request.headers().get("te").unwrap(), "trailers");
+    }
+}
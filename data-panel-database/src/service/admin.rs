@@ -0,0 +1,278 @@
+//! Admin HTTP API of the mesh.
+//!
+//! `GET /sessions` lists the open sessions, `GET /sessions/{id}/statements` the statements a
+//! session prepared and `GET /rules` the current routing rules, passwords redacted.
+//! `POST /drain` refuses new sessions while the open ones go on, `DELETE /drain` accepts them
+//! again. `GET /features` tells which runtime features are on, and
+//! `POST /features/{circuit_breaker|fault_injection}/{enable|disable}` switches them until the
+//! next reload. `GET /failovers` lists the recent primary failovers, and
+//! `DELETE /failovers/{group}` gives the writes of a segment group back to its primary.
+//! `GET /branches` lists the best-effort branches waiting to commit again, with the counts of
+//! those queued, delivered and abandoned, `POST /branches/{id}/retry` retries one right away and
+//! `DELETE /branches/{id}` abandons it to the compensation hooks. `GET /connections` counts the
+//! open client connections of every user, with the logins refused over the connection limits
+//! and the idle sessions closed. `GET /stats` gives the statistics of the statements by
+//! fingerprint, the ones the longest in total first, and `DELETE /stats` starts them over.
+//! `GET /contention` lists the recent deadlocks and lock wait timeouts with the fingerprints
+//! of the sessions likely taking part in them, and `GET /pagination` counts the rows the
+//! paginated cross-segment merges read and returned. `GET /schema` gives the columns of the
+//! distributed tables as of the last DDL the mesh ran on them.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use serde_json::{json, Value};
+
+use data_panel_common::config::config::MeshConfig;
+
+use crate::discovery::database::failover;
+use crate::discovery::database::rules::current_rules;
+use crate::handler::database::{best_effort, breaker, contention, ddl, fault, merge, statement_stats};
+use crate::service::shutdown::{self, service_counters};
+use crate::session::activity::{session_activities, session_activity};
+use crate::session::limits::connection_counter;
+
+fn json_response(status: StatusCode, body: Value) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response.headers_mut().insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    response
+}
+
+fn not_found(path: &str) -> Response<Body> {
+    json_response(StatusCode::NOT_FOUND, json!({ "error": format!("no such resource {}", path) }))
+}
+
+fn redact_passwords(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                if key == "password" {
+                    *value = Value::String("******".to_string());
+                } else {
+                    redact_passwords(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_passwords),
+        _ => {}
+    }
+}
+
+fn rules() -> Response<Body> {
+    let rules = match current_rules() {
+        Some(rules) => rules,
+        None => return json_response(StatusCode::NOT_FOUND, json!({ "error": "no rules loaded" })),
+    };
+    let mut cluster = serde_json::to_value(&*rules.get_cluster()).unwrap_or(Value::Null);
+    redact_passwords(&mut cluster);
+    json_response(StatusCode::OK, json!({
+        "version": rules.get_version(),
+        "loaded_at": rules.get_loaded_at(),
+        "cluster": cluster,
+    }))
+}
+
+fn prepared_statements(id: &str) -> Option<Response<Body>> {
+    let activity = session_activity(id.parse().ok()?)?;
+    let statements: Vec<Value> = activity.get_details().get_prepared_statements().iter()
+        .map(|(statement_id, sql)| json!({ "statement_id": statement_id, "sql": sql }))
+        .collect();
+    Some(json_response(StatusCode::OK, Value::Array(statements)))
+}
+
+fn failovers() -> Response<Body> {
+    let events: Vec<Value> = failover::failover_events().iter().map(|event| event.to_json()).collect();
+    let promotions: Vec<Value> = failover::promotions().iter().map(|event| event.to_json()).collect();
+    json_response(StatusCode::OK, json!({ "epoch": failover::fencing_epoch(), "events": events, "promotions": promotions }))
+}
+
+fn demote(group: &str) -> Response<Body> {
+    let rules = match current_rules() {
+        Some(rules) => rules,
+        None => return json_response(StatusCode::NOT_FOUND, json!({ "error": "no rules loaded" })),
+    };
+    match failover::demote(&rules.get_cluster(), group) {
+        Some(event) => {
+            println!("Admin API gave the writes of {} back to its primary", group);
+            json_response(StatusCode::OK, event.to_json())
+        }
+        None => json_response(StatusCode::NOT_FOUND, json!({ "error": format!("{} is not failed over", group) })),
+    }
+}
+
+fn branches() -> Response<Body> {
+    let stats = best_effort::best_effort_stats();
+    let branches: Vec<Value> = best_effort::stuck_branches().iter().map(|branch| branch.to_json()).collect();
+    json_response(StatusCode::OK, json!({
+        "queued": stats.get_queued(),
+        "delivered": stats.get_delivered(),
+        "abandoned": stats.get_abandoned(),
+        "pending": stats.get_pending(),
+        "branches": branches,
+    }))
+}
+
+fn retry_branch(id: &str) -> Option<Response<Body>> {
+    match best_effort::retry(id)? {
+        Ok(()) => Some(json_response(StatusCode::OK, json!({ "id": id, "delivered": true }))),
+        Err(e) => Some(json_response(StatusCode::BAD_GATEWAY, json!({ "id": id, "delivered": false, "error": e }))),
+    }
+}
+
+fn abandon_branch(id: &str) -> Option<Response<Body>> {
+    let branch = best_effort::abandon(id)?;
+    println!("Admin API abandoned branch {}", id);
+    Some(json_response(StatusCode::OK, branch.to_json()))
+}
+
+fn connections() -> Response<Body> {
+    let config = MeshConfig::get_connection_limits_config();
+    let users = connection_counter().user_connections();
+    let counters = service_counters();
+    json_response(StatusCode::OK, json!({
+        "open": users.iter().map(|(_, open)| open).sum::<usize>(),
+        "users": users.into_iter().map(|(user, open)| (user, json!(open))).collect::<serde_json::Map<String, Value>>(),
+        "max_connections": config.get_max_connections(),
+        "max_user_connections": config.get_max_user_connections(),
+        "refused": counters.get_refused_connections(),
+        "idle_closed": counters.get_idle_sessions_closed(),
+    }))
+}
+
+fn stats() -> Response<Body> {
+    let snapshots = statement_stats::statement_stats()
+        .map(|stats| stats.snapshots().iter().map(|snapshot| snapshot.to_json()).collect())
+        .unwrap_or_default();
+    json_response(StatusCode::OK, Value::Array(snapshots))
+}
+
+fn reset_stats() -> Response<Body> {
+    if let Some(stats) = statement_stats::statement_stats() {
+        stats.reset();
+        println!("Admin API reset the statement statistics");
+    }
+    stats()
+}
+
+fn contention_events() -> Response<Body> {
+    let events: Vec<Value> = contention::contention_events().iter().map(|event| event.to_json()).collect();
+    json_response(StatusCode::OK, Value::Array(events))
+}
+
+fn pagination() -> Response<Body> {
+    let stats = merge::pagination_stats();
+    json_response(StatusCode::OK, json!({
+        "merges": stats.get_merges(),
+        "segment_rows": stats.get_segment_rows(),
+        "returned_rows": stats.get_returned_rows(),
+    }))
+}
+
+fn schema() -> Response<Body> {
+    let tables: serde_json::Map<String, Value> = ddl::cached_tables().into_iter()
+        .map(|(table, columns)| {
+            let columns: Vec<Value> = columns.into_iter().map(|(name, column_type)| json!({"name": name, "type": column_type})).collect();
+            (table, Value::Array(columns))
+        })
+        .collect();
+    json_response(StatusCode::OK, Value::Object(tables))
+}
+
+fn features() -> Response<Body> {
+    json_response(StatusCode::OK, json!({
+        "circuit_breaker": breaker::circuit_breakers().is_some(),
+        "fault_injection": fault::is_injecting(),
+        "draining": shutdown::is_shutting_down(),
+    }))
+}
+
+fn toggle(feature: &str, action: &str) -> Option<Response<Body>> {
+    let enabled = match action {
+        "enable" => true,
+        "disable" => false,
+        _ => return None,
+    };
+    match feature {
+        "circuit_breaker" => breaker::configure_circuit_breakers(&MeshConfig::get_circuit_breaker_config().enabled(enabled)),
+        "fault_injection" => fault::configure_fault_injection(&MeshConfig::get_fault_injection_config().enabled(enabled)),
+        _ => return None,
+    }
+    println!("Admin API {}d {}", action, feature);
+    Some(features())
+}
+
+fn route(request: &Request<Body>) -> Response<Body> {
+    let path = request.uri().path();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let response = match (request.method(), segments.as_slice()) {
+        (&Method::GET, ["sessions"]) => {
+            let sessions: Vec<Value> = session_activities().iter().map(|activity| activity.to_json()).collect();
+            Some(json_response(StatusCode::OK, Value::Array(sessions)))
+        }
+        (&Method::GET, ["sessions", id, "statements"]) => prepared_statements(id),
+        (&Method::GET, ["rules"]) => Some(rules()),
+        (&Method::GET, ["connections"]) => Some(connections()),
+        (&Method::GET, ["stats"]) => Some(stats()),
+        (&Method::DELETE, ["stats"]) => Some(reset_stats()),
+        (&Method::GET, ["contention"]) => Some(contention_events()),
+        (&Method::GET, ["pagination"]) => Some(pagination()),
+        (&Method::GET, ["schema"]) => Some(schema()),
+        (&Method::POST, ["drain"]) => {
+            println!("Admin API started draining, new sessions are refused");
+            shutdown::set_draining(true);
+            Some(features())
+        }
+        (&Method::DELETE, ["drain"]) => {
+            println!("Admin API stopped draining, new sessions are accepted");
+            shutdown::set_draining(false);
+            Some(features())
+        }
+        (&Method::GET, ["features"]) => Some(features()),
+        (&Method::POST, ["features", feature, action]) => toggle(feature, action),
+        (&Method::GET, ["failovers"]) => Some(failovers()),
+        (&Method::DELETE, ["failovers", group]) => Some(demote(group)),
+        (&Method::GET, ["branches"]) => Some(branches()),
+        (&Method::POST, ["branches", id, "retry"]) => retry_branch(id),
+        (&Method::DELETE, ["branches", id]) => abandon_branch(id),
+        _ => None,
+    };
+    response.unwrap_or_else(|| not_found(path))
+}
+
+pub async fn serve_admin(addr: SocketAddr) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move { Ok::<_, Infallible>(route(&request)) }))
+    });
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    println!("Admin API listening on: {}", addr);
+    server.await
+}
+
+pub fn spawn_admin(addr: SocketAddr) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        if let Err(e) = serve_admin(addr).await {
+            println!("error on serving the admin API on {}; error = {:?}", addr, e);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::redact_passwords;
+
+    #[test]
+    fn test_redact_passwords() {
+        let mut cluster = json!({
+            "segments": {"meta_segment": {"primary": {"url": "jdbc:mysql://localhost:3306/martlet", "password": "root"}, "mirrors": []}}
+        });
+        redact_passwords(&mut cluster);
+        assert_eq!(cluster["segments"]["meta_segment"]["primary"]["password"], "******");
+        assert_eq!(cluster["segments"]["meta_segment"]["primary"]["url"], "jdbc:mysql://localhost:3306/martlet");
+    }
+}
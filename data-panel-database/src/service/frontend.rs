@@ -0,0 +1,65 @@
+//! Client protocols the listeners speak, by the `protocol` of their config.
+//!
+//! A frontend serves the sessions of one protocol: it decodes the frames of the client,
+//! dispatches them to the handlers and encodes their responses until the client leaves. The
+//! shards accept on every TCP listener and hand the sockets to the frontend of the listener,
+//! so sessions of every protocol get shard local ids, counters and the drain on shutdown.
+//! `mysql`, `postgres` and `http2` are registered when the service starts, another protocol
+//! plugs in with `register_frontend` before it does.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use tokio::net::TcpStream;
+
+#[async_trait]
+pub trait FrontendProtocol: Send + Sync {
+    /// The `protocol` of the listeners served, e.g. `postgres`.
+    fn get_protocol(&self) -> &'static str;
+
+    /// Serves the client of `socket` as session `session_id` until it leaves, `tls` when the
+    /// listener offers it TLS.
+    async fn serve_session(&self, socket: TcpStream, session_id: u64, tls: bool);
+}
+
+lazy_static! {
+    static ref FRONTENDS: RwLock<HashMap<&'static str, Arc<dyn FrontendProtocol>>> = RwLock::new(HashMap::new());
+}
+
+/// Serves the listeners of the protocol of `frontend`, in place of the one registered before.
+pub fn register_frontend(frontend: Arc<dyn FrontendProtocol>) {
+    FRONTENDS.write().unwrap().insert(frontend.get_protocol(), frontend);
+}
+
+pub fn frontend(protocol: &str) -> Option<Arc<dyn FrontendProtocol>> {
+    FRONTENDS.read().unwrap().get(protocol).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use tokio::net::TcpStream;
+
+    use super::{frontend, FrontendProtocol, register_frontend};
+
+    struct EchoFrontend;
+
+    #[async_trait]
+    impl FrontendProtocol for EchoFrontend {
+        fn get_protocol(&self) -> &'static str {
+            "echo"
+        }
+
+        async fn serve_session(&self, _socket: TcpStream, _session_id: u64, _tls: bool) {}
+    }
+
+    #[test]
+    fn test_register_frontend() {
+        assert!(frontend("echo").is_none());
+        register_frontend(Arc::new(EchoFrontend));
+        assert_eq!(frontend("echo").map(|frontend| frontend.get_protocol()), Some("echo"));
+    }
+}
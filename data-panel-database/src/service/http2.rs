@@ -0,0 +1,114 @@
+//! Listener of the HTTP/2 proxy, see `protocol::http2`. It runs next to the database listener
+//! on the `http2` listeners, or alone with `mode = "http2"`, the binary then being a plain
+//! sidecar.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use hyper::{Body, Client, Request, Response, Server, StatusCode};
+use hyper::client::HttpConnector;
+use hyper::server::conn::Http;
+use hyper::service::{make_service_fn, service_fn};
+use tokio::net::{lookup_host, TcpStream};
+
+use data_panel_common::config::config::MeshConfig;
+use data_panel_common::service::Service;
+
+use crate::discovery::http2::Http2Routes;
+use crate::protocol::http2::{self, GRPC_INTERNAL, GRPC_UNAVAILABLE, GRPC_UNIMPLEMENTED};
+use crate::service::frontend::FrontendProtocol;
+use crate::service::shutdown;
+
+async fn proxy(client: Client<HttpConnector>, routes: Arc<Http2Routes>, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let grpc = http2::is_grpc(request.headers());
+    let upstream = match routes.upstream(request.uri().path()) {
+        Some(upstream) => upstream,
+        None => {
+            let message = format!("no route for {}", request.uri().path());
+            return Ok(http2::error_response(grpc, StatusCode::NOT_FOUND, GRPC_UNIMPLEMENTED, &message));
+        }
+    };
+    let request = match http2::upstream_request(request, &upstream) {
+        Ok(request) => request,
+        Err(e) => return Ok(http2::error_response(grpc, StatusCode::BAD_REQUEST, GRPC_INTERNAL, &e.to_string())),
+    };
+    match client.request(request).await {
+        Ok(response) => Ok(http2::downstream_response(response)),
+        Err(e) => {
+            println!("error on proxying to {}; error = {:?}", upstream, e);
+            let message = format!("upstream {} unavailable", upstream);
+            Ok(http2::error_response(grpc, StatusCode::BAD_GATEWAY, GRPC_UNAVAILABLE, &message))
+        }
+    }
+}
+
+/// Proxies the HTTP/2 streams accepted on `addr` to the upstreams of `routes`.
+pub async fn serve_http2_proxy(addr: SocketAddr, routes: Http2Routes) -> Result<(), hyper::Error> {
+    let routes = Arc::new(routes);
+    let client = Client::builder().http2_only(true).build_http::<Body>();
+    let make_service = make_service_fn(move |_| {
+        let client = client.clone();
+        let routes = routes.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| proxy(client.clone(), routes.clone(), request)))
+        }
+    });
+    let server = Server::try_bind(&addr)?.http2_only(true).serve(make_service);
+    println!("HTTP/2 proxy listening on: {}", addr);
+    server.await
+}
+
+/// Serves the `http2` listeners of the database service, proxying to the upstreams of `routes`.
+pub struct Http2Frontend {
+    client: Client<HttpConnector>,
+    routes: Arc<Http2Routes>,
+}
+
+impl Http2Frontend {
+    pub fn new(routes: Http2Routes) -> Self {
+        Http2Frontend {
+            client: Client::builder().http2_only(true).build_http::<Body>(),
+            routes: Arc::new(routes),
+        }
+    }
+}
+
+#[async_trait]
+impl FrontendProtocol for Http2Frontend {
+    fn get_protocol(&self) -> &'static str {
+        "http2"
+    }
+
+    async fn serve_session(&self, socket: TcpStream, session_id: u64, _tls: bool) {
+        let (client, routes) = (self.client.clone(), self.routes.clone());
+        let service = service_fn(move |request| proxy(client.clone(), routes.clone(), request));
+        if let Err(e) = Http::new().http2_only(true).serve_connection(socket, service).await {
+            println!("error on serving http2 session {}; error = {:?}", session_id, e);
+        }
+    }
+}
+
+pub struct Http2ProxyService {}
+
+#[async_trait]
+impl Service for Http2ProxyService {
+    async fn serve(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let config = MeshConfig::get_http2_proxy_config();
+        let routes = match Http2Routes::from_config(&config) {
+            Ok(routes) => routes,
+            Err(e) => return Err(format!("invalid http2_proxy config; error = {}", e).into()),
+        };
+        let addr = format!("{}:{}", MeshConfig::get_host(), config.get_port());
+        let addr = match lookup_host(&addr).await?.next() {
+            Some(addr) => addr,
+            None => return Err(format!("unable to resolve {}", addr).into()),
+        };
+        tokio::select! {
+            result = serve_http2_proxy(addr, routes) => result?,
+            _ = shutdown::shutdown_signal() => println!("Shutting down the HTTP/2 proxy"),
+        }
+        Ok(())
+    }
+}
@@ -1 +1,9 @@
-pub mod mysql;
\ No newline at end of file
+pub mod admin;
+pub mod frontend;
+pub mod http2;
+pub mod mysql;
+pub mod postgresql;
+pub mod reload;
+pub mod shard;
+pub mod shutdown;
+pub mod tls;
\ No newline at end of file
@@ -1,20 +1,42 @@
+use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
-use bytes::{Buf, BytesMut};
-use tokio::net::{TcpListener, TcpStream};
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::net::{lookup_host, TcpStream};
+use tokio_rustls::rustls::Session;
 use tokio_stream::StreamExt;
 
+use data_panel_common::common;
 use data_panel_common::config::config::MeshConfig;
 use data_panel_common::service::{Service, ServiceHandler};
 use data_panel_common::service::io::Channel;
 
-use crate::handler::database::mysql::{AuthMethodMismatchHandler, AuthPhaseFastPathHandler, CommandHandler, CommandRootHandler, HandshakeHandler};
-use crate::protocol::database::{DatabasePacket, PacketPayload};
+use crate::discovery;
+use crate::discovery::database::{dns, failover, health, kubernetes, pilot, registry, tls};
+use crate::discovery::http2::Http2Routes;
+use crate::handler::database::{access, audit, authenticator, best_effort, cancel, cdc, contention, corpus, lifecycle, parser, pool, ratelimit, statement_stats, telemetry, transaction, xa};
+use crate::handler::database::audit::AuditRecord;
+use crate::handler::database::cancel::KillSwitch;
+use crate::handler::database::lifecycle::redact_url;
+use crate::handler::database::mysql::{auth, AuthMethodMismatchHandler, AuthPhaseFastPathHandler, CommandHandler, CommandRootHandler, EofDeprecator, err_code_message, err_payloads, HandshakeHandler, is_err_payloads, ok_affected_rows, PayloadSink, RowCounter};
+use crate::handler::database::telemetry::{Phase, Span, StatementTrace};
 use crate::protocol::database::mysql::codec::MySQLCodec;
-use crate::protocol::database::mysql::constant::MySQLConnectionPhase;
-use crate::protocol::database::mysql::packet::{MySQLOKPacket, MySQLPacketHeader, MySQLPacketPayload};
+use crate::protocol::database::mysql::constant::{MySQLCapabilityFlag, MySQLCommandPacketType, MySQLConnectionPhase, MySQLServerErrorCode};
+use crate::protocol::database::mysql::packet::{MySQLPacketHeader, MySQLPacketPayload};
+use crate::service::admin::spawn_admin;
+use crate::service::frontend::{self, FrontendProtocol};
+use crate::service::http2::Http2Frontend;
+use crate::service::postgresql::PostgreSQLFrontend;
+use crate::service::reload;
+use crate::service::shard::{ShardedServer, ShardListener};
+use crate::service::shutdown::{self, service_counters};
+use crate::service::tls::{self, ClientStream};
+use crate::session::activity::{register_session_activity, SessionActivityGuard, SessionDetails, SessionPhase};
+use crate::session::limits::{self, ConnectionSlot};
 use crate::session::mysql::SessionContext;
 
 lazy_static! {
@@ -25,31 +47,150 @@ pub fn io_context_id() -> u64 {
     IO_CONTEXT_ID_GENERATOR.fetch_add(1, Ordering::SeqCst)
 }
 
-pub struct MySQLIOContext<'a> {
+/// The most payloads of a streamed response held back before they are written to the client.
+const STREAM_BUFFERED_PAYLOADS: usize = 256;
+
+/// Writes a response to the client while the handler still produces it.
+struct ClientSink<'c> {
+    channel: &'c mut Channel<Box<dyn ClientStream>>,
+    deprecator: Option<EofDeprecator>,
+    buffered: Vec<Bytes>,
+    /// The first payload of the response, which tells OK from ERR.
+    first: Option<Bytes>,
+    rows: RowCounter,
+}
+
+impl<'c> ClientSink<'c> {
+    fn new(channel: &'c mut Channel<Box<dyn ClientStream>>, deprecator: Option<EofDeprecator>) -> Self {
+        ClientSink {
+            channel,
+            deprecator,
+            buffered: Vec::with_capacity(STREAM_BUFFERED_PAYLOADS),
+            first: None,
+            rows: RowCounter::default(),
+        }
+    }
+
+    fn buffer(&mut self, payload: Bytes) {
+        if self.first.is_none() {
+            self.first = Some(payload.clone());
+        }
+        self.rows.count(&payload);
+        let payload = match self.deprecator.as_mut() {
+            Some(deprecator) => deprecator.deprecate(payload),
+            None => Some(payload),
+        };
+        self.buffered.extend(payload);
+    }
+
+    /// Sends what the handler returned after the streamed payloads. Gives the start of the
+    /// response back for the counters and the audit log, which only read its first payload,
+    /// with the rows it returned.
+    async fn finish(mut self, payloads: Option<Vec<Bytes>>) -> (Option<Vec<Bytes>>, u64, Result<(), Error>) {
+        let response = match self.first.clone() {
+            Some(first) => Some(vec![first]),
+            None => payloads.clone(),
+        };
+        let streamed = self.first.is_some();
+        let payloads = match payloads {
+            Some(payloads) => {
+                payloads.into_iter().for_each(|payload| self.buffer(payload));
+                Some(std::mem::take(&mut self.buffered))
+            }
+            None if streamed => Some(std::mem::take(&mut self.buffered)),
+            None => None,
+        };
+        let rows = self.rows.get_rows();
+        (response, rows, self.channel.send(payloads).await)
+    }
+}
+
+impl<'c> PayloadSink for ClientSink<'c> {
+    fn write(&mut self, payload: Bytes) -> Result<(), Error> {
+        self.buffer(payload);
+        if self.buffered.len() < STREAM_BUFFERED_PAYLOADS {
+            return Ok(());
+        }
+        let handle = tokio::runtime::Handle::try_current().map_err(|e| Error::new(ErrorKind::Other, e))?;
+        let payloads = std::mem::take(&mut self.buffered);
+        let channel = &mut *self.channel;
+        tokio::task::block_in_place(|| handle.block_on(channel.send(Some(payloads))))
+    }
+}
+
+/// Length of an SSL request frame: the packet header and the truncated handshake response.
+const SSL_REQUEST_LEN: usize = 4 + 32;
+
+/// Whether `payload` asks to upgrade the connection to TLS before authenticating.
+fn is_ssl_request(payload: &BytesMut) -> bool {
+    if payload.len() != SSL_REQUEST_LEN {
+        return false;
+    }
+    let capability_flags = u32::from_le_bytes([payload[4], payload[5], payload[6], payload[7]]);
+    MySQLCapabilityFlag::from_bits_truncate(capability_flags).contains(MySQLCapabilityFlag::CLIENT_SSL)
+}
+
+pub struct MySQLIOContext {
     id: u64,
-    channel: Channel<'a>,
+    /// Only empty while the connection is upgraded to TLS.
+    channel: Option<Channel<Box<dyn ClientStream>>>,
     client_addr: SocketAddr,
     session_ctx: SessionContext,
+    /// The config generation the session's backend was resolved under.
+    config_generation: u64,
+    /// The failover fencing epoch the session's backend was resolved under.
+    fencing_epoch: u64,
+    activity: SessionActivityGuard,
+    kill_switch: KillSwitch,
+    /// Counts the session against the connection limits once its user logged in.
+    connection_slot: Option<ConnectionSlot>,
+    /// The span of the session once its user logged in, when it is traced.
+    session_span: Option<Span>,
 }
 
-impl<'a> MySQLIOContext<'a> {
-    pub fn new(id: u64, socket: &'a mut TcpStream) -> Self {
+impl MySQLIOContext {
+    pub fn new(id: u64, socket: TcpStream) -> Self {
         let client_addr = socket.peer_addr().unwrap();
+        Self::with_stream(id, Box::new(socket), client_addr)
+    }
+
+    /// A session on any stream, e.g. a Unix domain socket, from the client at `client_addr`.
+    pub fn with_stream(id: u64, stream: Box<dyn ClientStream>, client_addr: SocketAddr) -> Self {
+        let kill_switch = cancel::register_kill_switch(id);
+        let mut session_ctx = SessionContext::new(id);
+        session_ctx.set_backend_url(discovery::database::backend_url(&MeshConfig::get_backend_config()));
+        session_ctx.set_running_slot(kill_switch.running_slot());
         MySQLIOContext {
             id,
-            channel: Channel::new::<MySQLCodec>(socket, MySQLCodec {}),
+            channel: Some(Channel::new::<MySQLCodec>(stream, MySQLCodec {})),
             client_addr,
-            session_ctx: SessionContext::new(id),
+            session_ctx,
+            config_generation: reload::config_generation(),
+            fencing_epoch: failover::fencing_epoch(),
+            activity: register_session_activity(id, client_addr),
+            kill_switch,
+            connection_slot: None,
+            session_span: None,
         }
     }
 
+    /// Whether the session is offered TLS, as the listener it was accepted on says.
+    pub fn offer_tls(mut self, tls: bool) -> Self {
+        self.session_ctx.set_tls_offered(tls);
+        self
+    }
+
     pub fn id(&self) -> u64 {
         self.id
     }
 
+    fn channel(&mut self) -> &mut Channel<Box<dyn ClientStream>> {
+        self.channel.as_mut().unwrap()
+    }
+
     pub async fn handshake(&mut self) -> Result<(), futures::io::Error> {
         self.session_ctx.set_connection_phase(MySQLConnectionPhase::AuthPhaseFastPath);
-        self.channel.send(HandshakeHandler::handle(None, None, &mut self.session_ctx)).await
+        self.channel().send(HandshakeHandler::handle(None, None, &mut self.session_ctx)).await
     }
 
     pub async fn auth(&mut self, mut payload: BytesMut) -> Result<(), futures::io::Error> {
@@ -63,7 +204,7 @@ impl<'a> MySQLIOContext<'a> {
             MySQLConnectionPhase::AuthPhaseFastPath => {
                 let handshake_response41_payload = MySQLPacketPayload::new_with_payload(payload);
                 if let Some(payloads) = AuthPhaseFastPathHandler::handle(Some(header), Some(handshake_response41_payload), &mut self.session_ctx) {
-                    self.channel.send(Option::from(payloads)).await;
+                    self.channel().send(Option::from(payloads)).await;
                 }
                 if self.session_ctx.get_connection_phase() == MySQLConnectionPhase::AuthenticationMethodMismatch {
                     Err(())
@@ -79,15 +220,45 @@ impl<'a> MySQLIOContext<'a> {
         };
 
         if let Ok(()) = connection_phase_status {
-            // TODO login
             println!("session = {:?}", self.session_ctx);
 
-            let mut ok_packet = MySQLOKPacket::new(sequence_id + 1, 0, 0);
-            let mut ok_payload = MySQLPacketPayload::new();
-            let ok_payload = DatabasePacket::encode(&mut ok_packet, &mut ok_payload);
-            self.channel.send(Some(vec![ok_payload.get_payload()])).await;
+            let user_name = self.session_ctx.get_user_name();
+            if let Err((code, message)) = access::check(&MeshConfig::get_connection_acl_config(), self.client_addr.ip(), &user_name, &self.session_ctx.get_database()) {
+                self.channel().send(err_payloads(sequence_id + 1, code, message)).await?;
+                return Err(Error::new(ErrorKind::PermissionDenied, format!("connection of user {} refused", user_name)));
+            }
 
-            self.session_ctx.set_authorized(true);
+            // A client certificate mapped to a user stands for the password of that user.
+            let result = match self.session_ctx.get_cert_user() {
+                Some(cert_user) if cert_user == user_name => Ok(()),
+                Some(cert_user) => Err(format!("Access denied for user '{}', the client certificate is for '{}'", user_name, cert_user)),
+                None => {
+                    let authenticator = authenticator::authenticator(&MeshConfig::get_auth_config());
+                    auth::authenticate(&user_name, auth::credential(&self.session_ctx), authenticator.as_ref()).await
+                }
+            };
+            if result.is_ok() {
+                match limits::admit(&user_name) {
+                    Ok(slot) => self.connection_slot = Some(slot),
+                    Err((code, message)) => {
+                        service_counters().connection_refused();
+                        self.channel().send(err_payloads(sequence_id + 1, code, message)).await?;
+                        return Err(Error::new(ErrorKind::PermissionDenied, format!("connection of user {} over the connection limits", user_name)));
+                    }
+                }
+            }
+            match auth::auth_result_payloads(sequence_id + 1, &self.session_ctx, result) {
+                Ok(payloads) => {
+                    self.channel().send(Some(payloads)).await?;
+                    self.session_ctx.set_authorized(true);
+                    self.session_span = telemetry::start_session(&self.session_ctx, self.client_addr);
+                    self.describe();
+                }
+                Err(payloads) => {
+                    self.channel().send(Some(payloads)).await?;
+                    return Err(Error::new(ErrorKind::PermissionDenied, format!("access denied for user {}", self.session_ctx.get_user_name())));
+                }
+            }
         }
         Ok(())
     }
@@ -96,9 +267,184 @@ impl<'a> MySQLIOContext<'a> {
         let len = payload.get_uint_le(3);
         let sequence_id = payload.get_uint(1) as u32 & 0xff;
         let command_packet_type = payload.get_uint(1) as u8;
+        self.revalidate();
+        if let Some(message) = self.fence() {
+            if command_packet_type != MySQLCommandPacketType::ComQuit as u8 {
+                if let Err(e) = self.channel().send(err_payloads(sequence_id + 1, MySQLServerErrorCode::ErLockDeadlock, message)).await {
+                    println!("error on sending response; error = {:?}", e);
+                }
+                return;
+            }
+        }
+        let is_statement = command_packet_type == MySQLCommandPacketType::ComQuery as u8 || command_packet_type == MySQLCommandPacketType::ComStmtExecute as u8;
+        let sql = if is_statement {
+            self.statement_sql(command_packet_type, &payload)
+        } else {
+            None
+        };
+        let audited_sql = if audit::audit_log().is_some() { sql.clone() } else { None };
+        if is_statement {
+            if let Err(message) = ratelimit::admit(&self.session_ctx.get_user_name(), self.client_addr.ip()) {
+                let payloads = err_payloads(sequence_id + 1, MySQLServerErrorCode::ErUserLimitReached, message);
+                self.audit(audited_sql, &payloads);
+                if let Err(e) = self.channel().send(payloads).await {
+                    println!("error on sending response; error = {:?}", e);
+                }
+                return;
+            }
+        }
+        let counted = statement_stats::statement_stats().and_then(|stats| sql.clone().map(|sql| (stats, sql)));
+        let statement_trace = sql.as_deref().and_then(|sql| StatementTrace::start(sql, self.session_span.as_ref()));
+        self.session_ctx.set_statement_trace(statement_trace);
         let header = MySQLPacketHeader::new(len, sequence_id, command_packet_type, self.id);
         let command_payload = MySQLPacketPayload::new_with_payload(payload);
-        if let Err(e) = self.channel.send(CommandRootHandler::handle(Some(header), Some(command_payload), &mut self.session_ctx)).await {
+        self.activity.run(sql);
+        self.activity.enter(SessionPhase::Backend);
+        let in_transaction = self.session_ctx.is_in_transaction();
+        let deprecator = if self.session_ctx.is_deprecate_eof() {
+            Some(EofDeprecator::new(command_packet_type))
+        } else {
+            None
+        };
+        let started = Instant::now();
+        let mut sink = ClientSink::new(self.channel.as_mut().unwrap(), deprecator);
+        let payloads = CommandRootHandler::handle_streaming(Some(header), Some(command_payload), &mut self.session_ctx, &mut sink);
+        self.activity.enter(SessionPhase::Client);
+        telemetry::enter(&mut self.session_ctx, Phase::Serialize);
+        let (response, rows, sent) = sink.finish(payloads).await;
+        if let Err(e) = sent {
+            println!("error on sending response; error = {:?}", e);
+        }
+        if let Some(statement_trace) = self.session_ctx.take_statement_trace() {
+            statement_trace.finish(err_code_message(&response).map(|(code, message)| format!("{} {}", code, message)));
+        }
+        if is_statement {
+            service_counters().record_query(&response);
+            self.audit(audited_sql, &response);
+            if let Some((stats, sql)) = counted {
+                stats.record(&sql, started.elapsed(), is_err_payloads(&response), rows);
+            }
+            if let Some((code, _)) = err_code_message(&response).filter(|(code, _)| contention::is_lock_conflict(*code)) {
+                contention::record(self.id, code);
+            }
+        }
+        match (in_transaction, self.session_ctx.is_in_transaction()) {
+            (false, true) => service_counters().transaction_begun(),
+            (true, false) => service_counters().transaction_ended(),
+            _ => {}
+        }
+        if command_packet_type == MySQLCommandPacketType::ComStmtPrepare as u8
+            || command_packet_type == MySQLCommandPacketType::ComStmtClose as u8
+            || command_packet_type == MySQLCommandPacketType::ComInitDb as u8 {
+            self.describe();
+        }
+        self.activity.finish(self.session_ctx.is_in_transaction());
+        self.activity.enter(SessionPhase::Idle);
+    }
+
+    /// The SQL a COM_QUERY or COM_STMT_EXECUTE runs, `payload` starting after the command.
+    fn statement_sql(&self, command_packet_type: u8, payload: &BytesMut) -> Option<String> {
+        if command_packet_type == MySQLCommandPacketType::ComQuery as u8 {
+            return Some(String::from_utf8_lossy(payload).to_string());
+        }
+        let statement_id = u32::from_le_bytes([*payload.get(0)?, *payload.get(1)?, *payload.get(2)?, *payload.get(3)?]);
+        self.session_ctx.get_prepare_stmt_ctx_by_id(statement_id as u64)
+            .map(|prepare_stmt_ctx| String::from_utf8_lossy(&prepare_stmt_ctx.get_sql()).to_string())
+    }
+
+    fn audit(&self, sql: Option<String>, payloads: &Option<Vec<Bytes>>) {
+        let (audit_log, sql) = match (audit::audit_log(), sql) {
+            (Some(audit_log), Some(sql)) => (audit_log, sql),
+            _ => return,
+        };
+        let fingerprint = parser::sql::fingerprint(&sql).or_else(|| corpus::normalize(&sql)).unwrap_or(sql);
+        let record = AuditRecord::new(self.id, self.session_ctx.get_user_name(), self.client_addr, self.session_ctx.get_database(), fingerprint)
+            .affected_rows(ok_affected_rows(payloads))
+            .error(err_code_message(payloads));
+        audit_log.record(record);
+    }
+
+    /// Resolves the backend again after a reload, once no transaction holds the session on
+    /// the one it has.
+    fn revalidate(&mut self) {
+        let generation = reload::config_generation();
+        if generation == self.config_generation || self.session_ctx.is_in_transaction() || self.session_ctx.has_pinned_conn() {
+            return;
+        }
+        self.config_generation = generation;
+        self.session_ctx.set_backend_url(discovery::database::backend_url(&MeshConfig::get_backend_config()));
+        self.describe();
+    }
+
+    /// Moves the session off a backend a failover took the writes off. A transaction or pinned
+    /// connection held there is rolled back, and the message telling the client is returned.
+    fn fence(&mut self) -> Option<String> {
+        let epoch = failover::fencing_epoch();
+        if epoch == self.fencing_epoch {
+            return None;
+        }
+        let backend_url = self.session_ctx.get_backend_url();
+        let fenced = failover::is_fenced_since(self.fencing_epoch, &backend_url);
+        self.fencing_epoch = epoch;
+        if !fenced {
+            return None;
+        }
+        let held = self.session_ctx.is_in_transaction() || self.session_ctx.has_pinned_conn();
+        if self.session_ctx.is_in_transaction() {
+            service_counters().transaction_aborted();
+        }
+        transaction::abort(&mut self.session_ctx);
+        self.session_ctx.set_backend_url(discovery::database::backend_url(&MeshConfig::get_backend_config()));
+        self.describe();
+        if held {
+            Some(format!("Deadlock found when trying to get lock; try restarting transaction, as {} failed over", redact_url(&backend_url)))
+        } else {
+            None
+        }
+    }
+
+    /// Publishes what the admin API shows of the session.
+    fn describe(&self) {
+        self.activity.describe(SessionDetails::new(
+            self.session_ctx.get_user_name(),
+            self.session_ctx.get_database(),
+            redact_url(&self.session_ctx.get_backend_url()),
+            self.session_ctx.get_prepared_statements(),
+        ));
+    }
+
+    /// Continues the session over TLS, after the client sent an SSL request.
+    pub async fn upgrade(&mut self) -> Result<(), Error> {
+        let acceptor = match tls::tls_acceptor() {
+            Some(acceptor) => acceptor,
+            None => return Err(Error::new(ErrorKind::Other, "TLS is not configured")),
+        };
+        let socket = self.channel.take().unwrap().into_inner()?;
+        let socket = acceptor.accept(socket).await?;
+        let identities = socket.get_ref().1.get_peer_certificates()
+            .and_then(|certs| certs.first().map(tls::certificate_identities))
+            .unwrap_or_default();
+        if let Some(cert_user) = MeshConfig::get_tls_config().cert_user(&identities) {
+            self.session_ctx.set_cert_user(cert_user);
+        }
+        self.channel = Some(Channel::new::<MySQLCodec>(Box::new(socket), MySQLCodec {}));
+        self.session_ctx.set_secure(true);
+        Ok(())
+    }
+
+    /// Refuses a client that authenticates in plain text while TLS is required.
+    pub async fn reject_insecure(&mut self, payload: &BytesMut) -> Result<(), Error> {
+        let sequence_id = payload[3] as u32;
+        let message = "Connections using insecure transport are prohibited, connect with TLS".to_string();
+        self.channel().send(err_payloads(sequence_id + 1, MySQLServerErrorCode::ErAccessDeniedError, message)).await
+    }
+
+    /// Tells the client it is disconnected for staying idle, as MySQL does past `wait_timeout`.
+    async fn close_idle(&mut self) {
+        println!("session {} closed after staying idle", self.id);
+        service_counters().idle_session_closed();
+        let message = "The client was disconnected by the server because of inactivity.".to_string();
+        if let Err(e) = self.channel().send(err_payloads(0, MySQLServerErrorCode::ErClientInteractionTimeout, message)).await {
             println!("error on sending response; error = {:?}", e);
         }
     }
@@ -107,15 +453,46 @@ impl<'a> MySQLIOContext<'a> {
         if let Err(e) = self.handshake().await {
             println!("error on sending Handshake Packet response; error = {:?}", e);
         }
+        let mut idle = false;
         // Here for every line we get back from the `Framed` decoder,
         // we parse the request, and if it's valid we generate a response
         // based on the values in the database.
-        while let Some(result) = self.channel.stream.next().await {
+        loop {
+            let result = tokio::select! {
+                result = self.channel.as_mut().unwrap().stream.next() => match result {
+                    Some(result) => result,
+                    None => break,
+                },
+                _ = self.kill_switch.killed() => {
+                    println!("session {} killed", self.id);
+                    break;
+                }
+                _ = limits::idle_timeout_elapsed() => {
+                    idle = true;
+                    break;
+                }
+            };
             match result {
                 Ok(payload) => {
+                    if !self.session_ctx.get_authorized() && !self.session_ctx.is_secure() && self.session_ctx.is_tls_offered() {
+                        if is_ssl_request(&payload) {
+                            if let Err(e) = self.upgrade().await {
+                                println!("error on upgrading to TLS; error = {:?}", e);
+                                break;
+                            }
+                            continue;
+                        }
+                        if MeshConfig::get_tls_config().is_required() {
+                            if let Err(e) = self.reject_insecure(&payload).await {
+                                println!("error on sending response; error = {:?}", e);
+                            }
+                            break;
+                        }
+                    }
                     if !self.session_ctx.get_authorized() {
                         if let Err(e) = self.auth(payload).await {
-                            println!("error on sending response; error = {:?}", e);
+                            println!("error on authenticating; error = {:?}", e);
+                            break;
                         }
                         // 小鱼在水里活泼乱跳 闫圣哲 王茹玉 毛毛虫 人类 电脑
                     } else {
@@ -128,6 +505,16 @@ impl<'a> MySQLIOContext<'a> {
                 }
             }
         }
+        if idle {
+            self.close_idle().await;
+        }
+        if self.session_ctx.is_in_transaction() {
+            service_counters().transaction_aborted();
+        }
+        transaction::abort(&mut self.session_ctx);
+        if let Some(session_span) = self.session_span.take() {
+            session_span.end(None);
+        }
     }
 }
 
@@ -135,59 +522,154 @@ pub struct MySQLServiceHandler {}
 
 #[async_trait]
 impl ServiceHandler for MySQLServiceHandler {
-    async fn handle(&self, mut socket: TcpStream) {
+    async fn handle(&self, socket: TcpStream) {
         // Since our protocol is line-based we use `tokio_codecs`'s `LineCodec`
         // to convert our stream of bytes, `socket`, into a `Stream` of lines
         // as well as convert our line based responses into a stream of bytes.
 
-        let mut io_ctx = MySQLIOContext::new(io_context_id(), &mut socket);
+        let mut io_ctx = MySQLIOContext::new(io_context_id(), socket);
         io_ctx.receive().await;
     }
 }
 
+/// Serves the `mysql` listeners.
+pub struct MySQLFrontend {}
+
+#[async_trait]
+impl FrontendProtocol for MySQLFrontend {
+    fn get_protocol(&self) -> &'static str {
+        "mysql"
+    }
+
+    async fn serve_session(&self, socket: TcpStream, session_id: u64, tls: bool) {
+        MySQLIOContext::new(session_id, socket).offer_tls(tls).receive().await;
+    }
+}
+
 pub struct MySQLService {}
 
+/// Drains the sessions, publishes the shutdown report and exits.
+async fn shut_down() {
+    let shutdown_config = MeshConfig::get_shutdown_config();
+    let report = shutdown::drain(&shutdown_config).await;
+    shutdown::publish(&report, &shutdown_config).await;
+    // The shard threads never return on their own, waiting for them would hang.
+    std::process::exit(0);
+}
+
 #[async_trait]
 impl Service for MySQLService {
     async fn serve(&self) -> Result<(), Box<dyn std::error::Error>> {
-        // Parse the address we're going to run this server on
-        // and set up our TCP listener to accept connections.
-        let bind_host = MeshConfig::get_host();
-        let bind_port = MeshConfig::get_port();
-        let bind_port = bind_port.to_string();
-        let addr = vec![bind_host.as_str(), ":", bind_port.as_str()];
-        let addr = addr.join("");
-        println!("Listening on: {}", addr);
-
-        let listener = TcpListener::bind(&addr).await?;
-
-        // Create the shared state of this server that will be shared amongst all
-        // clients. We populate the initial database and then create the `Database`
-        // structure. Note the usage of `Arc` here which will be used to ensure that
-        // each independently spawned client will have a reference to the in-memory
-        // database.
+        // Parse the addresses we're going to run this server on,
+        // every shard sets up its own TCP listener on the mysql ones.
+        let mut listeners = vec![];
+        let mut unix_paths = vec![];
+        for listener in MeshConfig::get_listeners() {
+            if !listener.get_path().is_empty() {
+                println!("Listening on: {} (mysql)", listener.get_path());
+                unix_paths.push(listener.get_path());
+                continue;
+            }
+            let addr = format!("{}:{}", listener.get_address(), listener.get_port());
+            println!("Listening on: {} ({})", addr, listener.get_protocol());
+            match lookup_host(&addr).await?.next() {
+                Some(addr) => listeners.push((addr, listener)),
+                None => return Err(Box::new(common::Error::Config(format!("unable to resolve {}", addr)))),
+            }
+        }
 
-        loop {
-            match listener.accept().await {
-                Ok((socket, _)) => {
-                    // After getting a new connection first we see a clone of the database
-                    // being created, which is creating a new reference for this connected
-                    // client to use.
-
-                    // Like with other small servers, we'll `spawn` this client to ensure it
-                    // runs concurrently with all other clients. The `move` keyword is used
-                    // here to move ownership of our db handle into the async closure.
-                    tokio::spawn(async move {
-                        // Since our protocol is line-based we use `tokio_codecs`'s `LineCodec`
-                        // to convert our stream of bytes, `socket`, into a `Stream` of lines
-                        // as well as convert our line based responses into a stream of bytes.
-
-                        let handler = MySQLServiceHandler {};
-                        handler.handle(socket).await;
-                    });
+        // Starts the uptime clock of the shutdown report.
+        service_counters();
+        lifecycle::register_configured_hooks();
+        // Scopes nothing until [tenant] is enabled, the config being read per statement.
+        parser::sql::rewriter::register_query_rewriter(Arc::new(parser::sql::tenant::TenantRewriter));
+        parser::sql::rewriter::register_query_rewriter(Arc::new(parser::sql::row_filter::RowFilterRewriter));
+        reload::configure_subsystems()?;
+        if MeshConfig::get_pool_config().is_enabled() {
+            pool::spawn_pool_reaper(Duration::from_secs(30));
+        }
+        if let Err(e) = reload::load_configured_rules() {
+            println!("{}", e);
+        }
+        // The segments of the logged transactions are known once the rules are.
+        let distributed_transaction_config = MeshConfig::get_distributed_transaction_config();
+        if distributed_transaction_config.is_enabled() {
+            xa::recover(&distributed_transaction_config);
+            best_effort::start(&distributed_transaction_config);
+        }
+        let cdc_config = MeshConfig::get_cdc_config();
+        if cdc_config.is_enabled() {
+            cdc::start(&cdc_config);
+        }
+        let control_config = MeshConfig::get_control_config();
+        if control_config.is_discovery() {
+            pilot::spawn_rules_discovery(control_config);
+        }
+        let kubernetes_config = MeshConfig::get_kubernetes_config();
+        if kubernetes_config.is_enabled() {
+            kubernetes::spawn_kubernetes_discovery(kubernetes_config);
+        }
+        let dns_config = MeshConfig::get_dns_config();
+        if dns_config.is_enabled() {
+            dns::spawn_dns_discovery(dns_config);
+        }
+        let registry_config = MeshConfig::get_registry_config();
+        if registry_config.is_enabled() {
+            registry::spawn_registry(registry_config);
+        }
+        reload::spawn_hangup_listener();
+        tls::spawn_tls_pin_monitor(tls::TLS_PIN_CHECK_INTERVAL);
+        let reload_config = MeshConfig::get_reload_config();
+        if reload_config.is_enabled() {
+            reload::spawn_config_watcher(reload_config);
+        }
+
+        let health_check_config = MeshConfig::get_health_check_config();
+        if health_check_config.is_enabled() {
+            health::spawn_health_checker(health_check_config);
+        }
+
+        let admin_config = MeshConfig::get_admin_config();
+        if admin_config.is_enabled() {
+            let admin_addr = format!("{}:{}", admin_config.get_host(), admin_config.get_port());
+            match lookup_host(&admin_addr).await?.next() {
+                Some(admin_addr) => {
+                    spawn_admin(admin_addr);
                 }
-                Err(e) => println!("error accepting socket; error = {:?}", e),
+                None => return Err(Box::new(common::Error::Config(format!("unable to resolve {}", admin_addr)))),
             }
         }
+        frontend::register_frontend(Arc::new(MySQLFrontend {}));
+        frontend::register_frontend(Arc::new(PostgreSQLFrontend {}));
+        if listeners.iter().any(|(_, listener)| listener.get_protocol() == "http2") {
+            let routes = match Http2Routes::from_config(&MeshConfig::get_http2_proxy_config()) {
+                Ok(routes) => routes,
+                Err(e) => return Err(Box::new(common::Error::Config(format!("invalid http2_proxy config; error = {}", e)))),
+            };
+            frontend::register_frontend(Arc::new(Http2Frontend::new(routes)));
+        }
+        let mut shard_listeners = vec![];
+        for (addr, listener) in listeners {
+            match frontend::frontend(&listener.get_protocol()) {
+                Some(frontend) => shard_listeners.push(ShardListener::new(addr, listener.is_tls(), frontend)),
+                None => return Err(Box::new(common::Error::Config(format!("no frontend serves the {} listener on {}", listener.get_protocol(), addr)))),
+            }
+        }
+        if shard_listeners.is_empty() && unix_paths.is_empty() {
+            shutdown::shutdown_signal().await;
+            shut_down().await;
+            return Ok(());
+        }
+
+        // Sessions are pinned to the shard that accepted them, see `ShardedServer`.
+        let server = ShardedServer::new(shard_listeners, MeshConfig::get_workers())
+            .unix_paths(unix_paths)
+            .thread_per_core(MeshConfig::get_runtime() == "thread_per_core");
+        let running = tokio::task::spawn_blocking(move || server.run());
+        tokio::select! {
+            result = running => result??,
+            _ = shutdown::shutdown_signal() => shut_down().await,
+        }
+        Ok(())
     }
 }
\ No newline at end of file
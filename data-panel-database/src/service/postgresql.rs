@@ -0,0 +1,168 @@
+//! Listener of the experimental PostgreSQL protocol bridge, see `handler::database::postgresql`.
+
+use std::io::{Error, ErrorKind};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::io::{AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio_stream::StreamExt;
+use tokio_util::codec::FramedRead;
+
+use data_panel_common::config::config::MeshConfig;
+
+use crate::discovery;
+use crate::handler::database::authenticator::{self, Credential};
+use crate::handler::database::postgresql as bridge;
+use crate::protocol::database::postgresql::{self, FrontendMessage, PostgreSQLCodec};
+use crate::service::frontend::FrontendProtocol;
+use crate::service::shutdown::service_counters;
+use crate::session::postgresql::PostgreSQLSessionContext;
+
+/// Server parameters reported after the startup, clients refuse to go on without some of them.
+const SERVER_PARAMETERS: [(&str, &str); 6] = [
+    ("server_version", "11.0 (martlet bridge)"),
+    ("server_encoding", "UTF8"),
+    ("client_encoding", "UTF8"),
+    ("DateStyle", "ISO, MDY"),
+    ("integer_datetimes", "on"),
+    ("standard_conforming_strings", "on"),
+];
+
+pub struct PostgreSQLIOContext {
+    stream: FramedRead<ReadHalf<TcpStream>, PostgreSQLCodec>,
+    sink: WriteHalf<TcpStream>,
+    session_ctx: PostgreSQLSessionContext,
+}
+
+impl PostgreSQLIOContext {
+    pub fn new(id: u64, socket: TcpStream) -> Self {
+        let (r, w) = tokio::io::split(socket);
+        let backend_url = discovery::database::backend_url(&MeshConfig::get_backend_config());
+        PostgreSQLIOContext {
+            stream: FramedRead::new(r, PostgreSQLCodec::new()),
+            sink: w,
+            session_ctx: PostgreSQLSessionContext::new(id, backend_url),
+        }
+    }
+
+    async fn send(&mut self, payloads: Vec<Bytes>) -> Result<(), Error> {
+        for payload in payloads {
+            self.sink.write_all(&payload).await?;
+        }
+        self.sink.flush().await
+    }
+
+    fn startup_payloads(&self) -> Vec<Bytes> {
+        let mut payloads = vec![postgresql::authentication_ok()];
+        for (name, value) in SERVER_PARAMETERS.iter() {
+            payloads.push(postgresql::parameter_status(name, value));
+        }
+        payloads.push(postgresql::backend_key_data(self.session_ctx.get_id() as u32, 0));
+        payloads.push(postgresql::ready_for_query(self.session_ctx.get_transaction_status()));
+        payloads
+    }
+
+    /// Answers one message, false once the session is over.
+    async fn process(&mut self, message: FrontendMessage) -> Result<bool, Error> {
+        let session_ctx = &mut self.session_ctx;
+        if session_ctx.is_failed() && message != FrontendMessage::Sync {
+            return Ok(true);
+        }
+        let payloads = match message {
+            FrontendMessage::EncryptionRequest => {
+                self.sink.write_all(b"N").await?;
+                return Ok(true);
+            }
+            FrontendMessage::CancelRequest | FrontendMessage::Terminate => return Ok(false),
+            FrontendMessage::Startup { params } => {
+                session_ctx.set_user_name(params.get("user").cloned().unwrap_or_default());
+                session_ctx.set_database(params.get("database").cloned().unwrap_or_default());
+                if MeshConfig::get_auth_config().is_enabled() {
+                    vec![postgresql::authentication_cleartext_password()]
+                } else {
+                    session_ctx.set_authorized(true);
+                    self.startup_payloads()
+                }
+            }
+            FrontendMessage::Password(password) => {
+                let user = session_ctx.get_user_name();
+                let authenticator = authenticator::authenticator(&MeshConfig::get_auth_config());
+                if let Err(e) = authenticator.authenticate(&user, Credential::Cleartext(password)).await {
+                    println!("login of user {} refused; error = {}", user, e);
+                    let message = format!("password authentication failed for user \"{}\"", session_ctx.get_user_name());
+                    self.send(vec![postgresql::error_response("28P01", &message)]).await?;
+                    return Ok(false);
+                }
+                session_ctx.set_authorized(true);
+                self.startup_payloads()
+            }
+            _ if !session_ctx.get_authorized() => {
+                self.send(vec![postgresql::error_response("28000", "authentication required")]).await?;
+                return Ok(false);
+            }
+            FrontendMessage::Query(sql) => {
+                let payloads = bridge::simple_query(session_ctx, &sql);
+                service_counters().count_query(payloads.iter().any(|payload| payload[0] == b'E'));
+                payloads
+            }
+            FrontendMessage::Parse { name, sql, param_types } => bridge::parse(session_ctx, name, &sql, param_types),
+            FrontendMessage::Bind { portal, statement, formats, params } => bridge::bind(session_ctx, portal, &statement, formats, params),
+            FrontendMessage::Describe { kind, name } => bridge::describe(session_ctx, kind, &name),
+            FrontendMessage::Execute { portal, .. } => {
+                let payloads = bridge::execute(session_ctx, &portal);
+                service_counters().count_query(payloads.iter().any(|payload| payload[0] == b'E'));
+                payloads
+            }
+            FrontendMessage::Close { kind, name } => bridge::close(session_ctx, kind, &name),
+            FrontendMessage::Sync => {
+                session_ctx.set_failed(false);
+                vec![postgresql::ready_for_query(session_ctx.get_transaction_status())]
+            }
+            FrontendMessage::Flush => vec![],
+            FrontendMessage::Unknown(tag) => {
+                return Err(Error::new(ErrorKind::InvalidData, format!("unsupported message {}", tag as char)));
+            }
+        };
+        // An error of the extended protocol skips the following messages until Sync.
+        if payloads.iter().any(|payload| payload[0] == b'E') {
+            self.session_ctx.set_failed(true);
+        }
+        self.send(payloads).await?;
+        Ok(true)
+    }
+
+    pub async fn receive(&mut self) {
+        while let Some(frame) = self.stream.next().await {
+            let message = match frame.and_then(FrontendMessage::decode) {
+                Ok(message) => message,
+                Err(e) => {
+                    println!("error on decoding from postgresql client; error = {:?}", e);
+                    break;
+                }
+            };
+            match self.process(message).await {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    println!("error on serving postgresql client; error = {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Serves the `postgres` listeners, TLS being refused to their clients.
+pub struct PostgreSQLFrontend {}
+
+#[async_trait]
+impl FrontendProtocol for PostgreSQLFrontend {
+    fn get_protocol(&self) -> &'static str {
+        "postgres"
+    }
+
+    async fn serve_session(&self, socket: TcpStream, session_id: u64, _tls: bool) {
+        PostgreSQLIOContext::new(session_id, socket).receive().await;
+    }
+}
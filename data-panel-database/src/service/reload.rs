@@ -0,0 +1,148 @@
+//! Hot reload of the mesh config and the routing rules.
+//!
+//! On SIGHUP, or when the watcher sees the config or the rules file change, both are read
+//! again. A config that fails to parse or validate is rejected whole and the running one kept.
+//! The config and the rules are each swapped in at once, and the subsystems set up from the
+//! config are set up again. Open sessions resolve their backend again before their next
+//! statement outside of a transaction, the transactions in progress finish where they started.
+//! The listen address, the workers and the mode only change on restart.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use data_panel_common::config::config::{MeshConfig, ReloadConfig};
+
+use crate::common::store;
+use crate::discovery::database::{endpoints, rules};
+use crate::handler::database::{audit, breaker, concurrency, fault, keygen, ratelimit, route_cache, scheduler, statement_stats, telemetry};
+use crate::service::tls;
+
+lazy_static! {
+    static ref CONFIG_GENERATION: AtomicU64 = AtomicU64::new(0);
+}
+
+/// Bumped on every reload, sessions compare it to the one they resolved their backend under.
+pub fn config_generation() -> u64 {
+    CONFIG_GENERATION.load(Ordering::Acquire)
+}
+
+/// Sets up the subsystems configured from the current config.
+pub fn configure_subsystems() -> Result<(), String> {
+    store::configure_metadata_store(&MeshConfig::get_metadata_store_config()).map_err(|e| format!("unable to open the metadata store; error = {}", e))?;
+    fault::configure_fault_injection(&MeshConfig::get_fault_injection_config());
+    scheduler::configure_scheduler(&MeshConfig::get_scheduler_config());
+    route_cache::configure_route_cache(&MeshConfig::get_route_cache_config());
+    breaker::configure_circuit_breakers(&MeshConfig::get_circuit_breaker_config());
+    concurrency::configure_segment_limiter(&MeshConfig::get_segment_concurrency_config());
+    ratelimit::configure_rate_limiter(&MeshConfig::get_rate_limit_config());
+    keygen::configure_key_generator(&MeshConfig::get_key_generator_config());
+    statement_stats::configure_statement_stats(&MeshConfig::get_statement_stats_config());
+    audit::configure_audit(&MeshConfig::get_audit_config()).map_err(|e| format!("unable to configure the audit log; error = {}", e))?;
+    telemetry::configure_tracing(&MeshConfig::get_tracing_config()).map_err(|e| format!("unable to configure tracing; error = {}", e))?;
+    tls::configure_tls(&MeshConfig::get_tls_config()).map_err(|e| format!("unable to configure TLS; error = {:?}", e))
+}
+
+/// Loads the rules file of the current config, if it names one.
+pub fn load_configured_rules() -> Result<(), String> {
+    let rules_config = MeshConfig::get_rules_config();
+    if rules_config.get_history() > 0 {
+        rules::set_rules_history(rules_config.get_history());
+    }
+    if rules_config.get_path().is_empty() {
+        return Ok(());
+    }
+    let version = rules::load_rules_file(&rules_config.get_path())
+        .map_err(|e| format!("error on loading rules {}; error = {:?}", rules_config.get_path(), e))?;
+    println!("Loaded rules version: {}", version);
+    // The addresses discovered so far replace the ones of the file.
+    if let Some(version) = endpoints::refresh().map_err(|e| format!("error on applying the discovered endpoints; error = {}", e))? {
+        println!("Loaded rules version {} with the discovered endpoints", version);
+    }
+    Ok(())
+}
+
+/// Reads the config file and the rules file again and makes them current.
+pub fn reload() -> Result<(), String> {
+    let path = MeshConfig::get_path();
+    if path.is_empty() {
+        return Err("the config was not read from a file".to_string());
+    }
+    let config = MeshConfig::try_from_file(&path)?;
+    let (host, port, workers, runtime) = (MeshConfig::get_host(), MeshConfig::get_port(), MeshConfig::get_workers(), MeshConfig::get_runtime());
+    config.make_current();
+    if (host, port, workers, runtime) != (MeshConfig::get_host(), MeshConfig::get_port(), MeshConfig::get_workers(), MeshConfig::get_runtime()) {
+        println!("The listen address, workers and runtime of the reloaded config take effect on restart");
+    }
+    let configured = configure_subsystems();
+    // The rules are reloaded even when a subsystem failed, they are independent of it.
+    let loaded = load_configured_rules();
+    CONFIG_GENERATION.fetch_add(1, Ordering::AcqRel);
+    configured.and(loaded)
+}
+
+fn reload_and_log(reason: &str) {
+    println!("Reloading the config, {}", reason);
+    if let Err(e) = reload() {
+        println!("error on reloading the config; error = {}", e);
+    }
+}
+
+/// Reloads on every SIGHUP.
+pub fn spawn_hangup_listener() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::hangup()) {
+            Ok(mut hangup) => {
+                tokio::spawn(async move {
+                    while hangup.recv().await.is_some() {
+                        reload_and_log("received SIGHUP");
+                    }
+                });
+            }
+            Err(e) => println!("error on listening for SIGHUP; error = {:?}", e),
+        }
+    }
+}
+
+fn modified_times() -> Vec<Option<SystemTime>> {
+    vec![MeshConfig::get_path(), MeshConfig::get_rules_config().get_path()].iter()
+        .filter(|path| !path.is_empty())
+        .map(|path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+        .collect()
+}
+
+/// Reloads when the config or the rules file was modified since the last check.
+pub fn spawn_config_watcher(config: ReloadConfig) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_millis(config.get_interval() as u64));
+        let mut seen = modified_times();
+        loop {
+            ticker.tick().await;
+            if modified_times() != seen {
+                reload_and_log("its files changed");
+                // The reloaded config may name another rules file.
+                seen = modified_times();
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use data_panel_common::config::config::ReloadConfig;
+
+    use super::{config_generation, modified_times, reload};
+
+    #[test]
+    fn test_reload() {
+        // Built in code, there is no file to read the config again from, nor one to watch.
+        let generation = config_generation();
+        assert_eq!(reload(), Err("the config was not read from a file".to_string()));
+        assert_eq!(config_generation(), generation);
+        assert!(modified_times().is_empty());
+
+        assert_eq!(ReloadConfig::default().get_interval(), 2000);
+        assert_eq!(ReloadConfig::new(500).get_interval(), 500);
+    }
+}
@@ -0,0 +1,433 @@
+//! Sharded accept and worker model.
+//!
+//! Every shard owns an OS thread running its own single worker tokio runtime. On unix each
+//! shard binds its own listener with SO_REUSEPORT and the kernel spreads new connections
+//! across shards, elsewhere one acceptor hands connections out round robin. A session never
+//! leaves the shard that accepted it, so session ids and shard counters stay core local.
+//! Every shard accepts on all the TCP listeners, serving their sessions with the frontend of
+//! their protocol, and on the mysql Unix domain sockets.
+//!
+//! With the `thread_per_core` runtime the threads of a shard know it, see `local_shard`, and
+//! check backend connections out of pools of their own.
+
+use std::cell::Cell;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+use crate::handler::database::cancel;
+use crate::service::frontend::FrontendProtocol;
+use crate::service::mysql::MySQLIOContext;
+use crate::service::shutdown::is_shutting_down;
+use crate::session::activity;
+
+/// Session ids carry the shard in the high byte, the mysql thread id is only 32 bits wide.
+const SHARD_ID_SHIFT: u32 = 24;
+const LOCAL_ID_MASK: u64 = (1 << SHARD_ID_SHIFT) - 1;
+
+/// Counters of one shard, only ever written from that shard's runtime.
+#[derive(Debug, Default)]
+pub struct ShardStats {
+    shard: u64,
+    next_session_id: AtomicU64,
+    accepted: AtomicU64,
+    active: AtomicU64,
+}
+
+impl ShardStats {
+    fn new(shard: u64) -> Self {
+        ShardStats {
+            shard,
+            ..Default::default()
+        }
+    }
+
+    /// Allocates a session id without touching any counter shared with other shards. Past the
+    /// last local id the ids start over, skipping the ones of sessions still open, which the
+    /// kill registry and the processlist are keyed by.
+    fn session_id(&self) -> u64 {
+        self.session_id_where(|id| cancel::is_kill_switch_registered(id) || activity::is_session_registered(id))
+    }
+
+    /// The next session id that neither is 0 nor `is_open`.
+    fn session_id_where(&self, is_open: impl Fn(u64) -> bool) -> u64 {
+        let mut id = 0;
+        for _ in 0..LOCAL_ID_MASK {
+            let next = (self.next_session_id.fetch_add(1, Ordering::Relaxed) + 1) & LOCAL_ID_MASK;
+            if next == 0 {
+                continue;
+            }
+            id = (self.shard << SHARD_ID_SHIFT) | next;
+            if !is_open(id) {
+                break;
+            }
+        }
+        id
+    }
+
+    pub fn get_shard(&self) -> u64 {
+        self.shard
+    }
+
+    pub fn get_accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    pub fn get_active(&self) -> u64 {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
+lazy_static! {
+    static ref SHARD_STATS: RwLock<Vec<Arc<ShardStats>>> = RwLock::new(vec![]);
+}
+
+thread_local! {
+    static LOCAL_SHARD: Cell<Option<(usize, usize)>> = Cell::new(None);
+}
+
+pub fn shard_stats() -> Vec<Arc<ShardStats>> {
+    SHARD_STATS.read().unwrap().clone()
+}
+
+/// The shard of the current thread and the number of shards, when it runs a shard of the
+/// `thread_per_core` runtime.
+pub fn local_shard() -> Option<(usize, usize)> {
+    LOCAL_SHARD.with(|shard| shard.get())
+}
+
+pub fn shard_count(workers: usize) -> usize {
+    if workers == 0 {
+        num_cpus::get()
+    } else {
+        workers
+    }.min(1 << (32 - SHARD_ID_SHIFT))
+}
+
+/// An address sessions are accepted on and served by `frontend`, `tls` when they are offered
+/// TLS there.
+#[derive(Clone)]
+pub struct ShardListener {
+    addr: SocketAddr,
+    tls: bool,
+    frontend: Arc<dyn FrontendProtocol>,
+}
+
+impl ShardListener {
+    pub fn new(addr: SocketAddr, tls: bool, frontend: Arc<dyn FrontendProtocol>) -> Self {
+        ShardListener { addr, tls, frontend }
+    }
+}
+
+pub struct ShardedServer {
+    listeners: Vec<ShardListener>,
+    unix_paths: Vec<String>,
+    shards: usize,
+    thread_per_core: bool,
+}
+
+impl ShardedServer {
+    pub fn new(listeners: Vec<ShardListener>, workers: usize) -> Self {
+        ShardedServer {
+            listeners,
+            unix_paths: vec![],
+            shards: shard_count(workers),
+            thread_per_core: false,
+        }
+    }
+
+    /// Also accepts sessions on the Unix domain sockets at `unix_paths`.
+    pub fn unix_paths(mut self, unix_paths: Vec<String>) -> Self {
+        self.unix_paths = unix_paths;
+        self
+    }
+
+    /// Gives every shard backend pools of its own.
+    pub fn thread_per_core(mut self, thread_per_core: bool) -> Self {
+        self.thread_per_core = thread_per_core;
+        self
+    }
+
+    /// Starts the shard threads and blocks until all of them stop.
+    pub fn run(self) -> std::io::Result<()> {
+        let stats: Vec<Arc<ShardStats>> = (0..self.shards).map(|shard| Arc::new(ShardStats::new(shard as u64))).collect();
+        *SHARD_STATS.write().unwrap() = stats.clone();
+        let addrs: Vec<String> = self.listeners.iter().map(|listener| listener.addr.to_string())
+            .chain(self.unix_paths.iter().cloned())
+            .collect();
+        let runtime = if self.thread_per_core { "thread per core" } else { "sharded" };
+        println!("Starting {} shards, {}, on: {}", self.shards, runtime, addrs.join(", "));
+
+        let workers = if cfg!(unix) {
+            self.run_reuse_port(stats)?
+        } else {
+            self.run_dispatch(stats)?
+        };
+
+        for worker in workers {
+            if let Err(e) = worker.join() {
+                println!("error on joining shard worker; error = {:?}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Every shard accepts on its own SO_REUSEPORT listener.
+    fn run_reuse_port(&self, stats: Vec<Arc<ShardStats>>) -> std::io::Result<Vec<thread::JoinHandle<()>>> {
+        let mut workers = vec![];
+        #[cfg(unix)]
+        let unix_listeners = bind_unix_listeners(&self.unix_paths)?;
+        for (shard, stats) in stats.into_iter().enumerate() {
+            let runtime = shard_runtime(shard, self.shards, self.thread_per_core)?;
+            #[cfg(unix)]
+            let local_listeners = runtime.block_on(async {
+                unix_listeners.iter()
+                    .map(|listener| listener.try_clone().and_then(tokio::net::UnixListener::from_std))
+                    .collect::<std::io::Result<Vec<tokio::net::UnixListener>>>()
+            })?;
+            let listeners = runtime.block_on(async {
+                self.listeners.iter()
+                    .map(|listener| reuse_port_listener(listener.addr).map(|socket| (socket, listener.clone())))
+                    .collect::<std::io::Result<Vec<(TcpListener, ShardListener)>>>()
+            })?;
+            workers.push(spawn_shard(shard, runtime, stats, move |stats| async move {
+                let mut accepting: Vec<_> = listeners.into_iter()
+                    .map(|(socket, listener)| tokio::spawn(accept(socket, listener, stats.clone())))
+                    .collect();
+                #[cfg(unix)]
+                accepting.extend(local_listeners.into_iter().map(|listener| tokio::spawn(accept_local(listener, stats.clone()))));
+                for accepting in accepting {
+                    if let Err(e) = accepting.await {
+                        println!("error on accepting on shard {}; error = {:?}", stats.get_shard(), e);
+                    }
+                }
+            })?);
+        }
+        Ok(workers)
+    }
+
+    /// A single acceptor hands connections out round robin, the shards own them from then on.
+    fn run_dispatch(&self, stats: Vec<Arc<ShardStats>>) -> std::io::Result<Vec<thread::JoinHandle<()>>> {
+        if !self.unix_paths.is_empty() {
+            println!("Unix domain sockets are not supported here, not listening on: {}", self.unix_paths.join(", "));
+        }
+        let mut workers = vec![];
+        let mut senders: Vec<UnboundedSender<(std::net::TcpStream, usize)>> = vec![];
+        for (shard, stats) in stats.into_iter().enumerate() {
+            let (sender, mut receiver) = unbounded_channel::<(std::net::TcpStream, usize)>();
+            senders.push(sender);
+            let runtime = shard_runtime(shard, self.shards, self.thread_per_core)?;
+            let listeners = self.listeners.clone();
+            workers.push(spawn_shard(shard, runtime, stats, move |stats| async move {
+                while let Some((socket, index)) = receiver.recv().await {
+                    match TcpStream::from_std(socket) {
+                        Ok(socket) => serve_session(socket, &listeners[index], stats.clone()),
+                        Err(e) => println!("error on registering socket; error = {:?}", e),
+                    }
+                }
+            })?);
+        }
+
+        for (index, listener) in self.listeners.iter().enumerate() {
+            let senders = senders.clone();
+            let listener = std::net::TcpListener::bind(listener.addr)?;
+            workers.push(thread::Builder::new().name(format!("martlet-acceptor-{}", index)).spawn(move || {
+                let mut next = 0;
+                for socket in listener.incoming() {
+                    match socket.and_then(|socket| socket.set_nonblocking(true).map(|_| socket)) {
+                        Ok(socket) => {
+                            if let Err(e) = senders[next].send((socket, index)) {
+                                println!("error on dispatching socket to shard {}; error = {:?}", next, e);
+                            }
+                            next = (next + 1) % senders.len();
+                        }
+                        Err(e) => println!("error accepting socket; error = {:?}", e),
+                    }
+                }
+            })?);
+        }
+        Ok(workers)
+    }
+}
+
+/// A multi thread runtime limited to one worker, so `block_in_place` keeps working for the
+/// synchronous handlers while every session spawned on it stays on this shard. The threads
+/// `block_in_place` hands the worker over to are threads of the runtime too, they know the
+/// shard all the same.
+fn shard_runtime(shard: usize, shards: usize, thread_per_core: bool) -> std::io::Result<Runtime> {
+    let mut builder = Builder::new_multi_thread();
+    if thread_per_core {
+        builder.on_thread_start(move || LOCAL_SHARD.with(|local_shard| local_shard.set(Some((shard, shards)))));
+    }
+    builder.worker_threads(1)
+        .thread_name(format!("martlet-shard-{}", shard))
+        .enable_all()
+        .build()
+}
+
+fn reuse_port_listener(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        tokio::net::TcpSocket::new_v4()?
+    } else {
+        tokio::net::TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+fn spawn_shard<F, Fut>(shard: usize, runtime: Runtime, stats: Arc<ShardStats>, accept: F) -> std::io::Result<thread::JoinHandle<()>>
+    where F: FnOnce(Arc<ShardStats>) -> Fut + Send + 'static,
+          Fut: std::future::Future<Output=()> + Send + 'static {
+    thread::Builder::new().name(format!("martlet-shard-main-{}", shard)).spawn(move || {
+        let accept = accept(stats);
+        runtime.block_on(async move {
+            // Accept on the shard's worker rather than on this driving thread.
+            if let Err(e) = tokio::spawn(accept).await {
+                println!("error on running shard {}; error = {:?}", shard, e);
+            }
+        });
+    })
+}
+
+async fn accept(tcp_listener: TcpListener, listener: ShardListener, stats: Arc<ShardStats>) {
+    loop {
+        match tcp_listener.accept().await {
+            Ok((socket, _)) => serve_session(socket, &listener, stats.clone()),
+            Err(e) => println!("error accepting socket; error = {:?}", e),
+        }
+    }
+}
+
+/// Binds the Unix domain sockets once, every shard accepts on a clone. A socket file left
+/// behind by an earlier run is replaced.
+#[cfg(unix)]
+fn bind_unix_listeners(paths: &[String]) -> std::io::Result<Vec<std::os::unix::net::UnixListener>> {
+    use std::os::unix::fs::FileTypeExt;
+
+    paths.iter()
+        .map(|path| {
+            if std::fs::metadata(path).map_or(false, |metadata| metadata.file_type().is_socket()) {
+                std::fs::remove_file(path)?;
+            }
+            let listener = std::os::unix::net::UnixListener::bind(path)?;
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+async fn accept_local(listener: tokio::net::UnixListener, stats: Arc<ShardStats>) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, _)) => {
+                if is_shutting_down() {
+                    continue;
+                }
+                // Clients on the socket run in the pod, as if on loopback.
+                let client_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+                let mut io_ctx = MySQLIOContext::with_stream(stats.session_id(), Box::new(socket), client_addr).offer_tls(false);
+                spawn_session(async move { io_ctx.receive().await }, stats.clone());
+            }
+            Err(e) => println!("error accepting unix socket; error = {:?}", e),
+        }
+    }
+}
+
+fn serve_session(socket: TcpStream, listener: &ShardListener, stats: Arc<ShardStats>) {
+    if is_shutting_down() {
+        return;
+    }
+    let (frontend, session_id, tls) = (listener.frontend.clone(), stats.session_id(), listener.tls);
+    spawn_session(async move { frontend.serve_session(socket, session_id, tls).await }, stats);
+}
+
+fn spawn_session<F>(session: F, stats: Arc<ShardStats>)
+    where F: std::future::Future<Output=()> + Send + 'static {
+    stats.accepted.fetch_add(1, Ordering::Relaxed);
+    stats.active.fetch_add(1, Ordering::Relaxed);
+    tokio::spawn(async move {
+        session.await;
+        stats.active.fetch_sub(1, Ordering::Relaxed);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use data_panel_common::config::config::{ListenerConfig, PoolConfig};
+
+    #[cfg(unix)]
+    use crate::service::shard::bind_unix_listeners;
+    use crate::service::shard::{local_shard, LOCAL_ID_MASK, SHARD_ID_SHIFT, shard_runtime, ShardStats};
+
+    #[test]
+    fn test_shard_session_id() {
+        let stats = ShardStats::new(3);
+        let first = stats.session_id();
+        let second = stats.session_id();
+        assert_eq!(first >> SHARD_ID_SHIFT, 3);
+        assert_eq!(second & LOCAL_ID_MASK, (first & LOCAL_ID_MASK) + 1);
+        assert!(ShardStats::new(255).session_id() <= u32::MAX as u64);
+    }
+
+    #[test]
+    fn test_session_id_wrap_around() {
+        let stats = ShardStats::new(0);
+        stats.next_session_id.store(LOCAL_ID_MASK - 1, Ordering::Relaxed);
+        assert_eq!(stats.session_id_where(|_| false), LOCAL_ID_MASK);
+        // Past the last local id: 0 is skipped, and so are the ids of sessions still open.
+        assert_eq!(stats.session_id_where(|id| id == 2 || id == 3), 1);
+        assert_eq!(stats.session_id_where(|id| id == 2 || id == 3), 4);
+
+        let stats = ShardStats::new(5);
+        stats.next_session_id.store(LOCAL_ID_MASK, Ordering::Relaxed);
+        assert_eq!(stats.session_id_where(|_| false), (5 << SHARD_ID_SHIFT) | 1);
+    }
+
+    #[test]
+    fn test_thread_per_core_local_shard() {
+        let on_shard = |thread_per_core| shard_runtime(2, 4, thread_per_core).unwrap().block_on(async {
+            // Also on the thread the worker is handed over to.
+            tokio::spawn(async { tokio::task::block_in_place(local_shard) }).await.unwrap()
+        });
+        assert_eq!(on_shard(true), Some((2, 4)));
+        assert_eq!(on_shard(false), None);
+        assert_eq!(local_shard(), None);
+
+        let pool_config = PoolConfig::new(2, 10).shard(4);
+        assert_eq!((pool_config.get_min_size(), pool_config.get_max_size()), (1, 3));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_listeners() {
+        let socket = std::env::temp_dir().join(format!("martlet_test_{}.sock", std::process::id()));
+        let socket = socket.to_str().unwrap().to_string();
+        assert!(ListenerConfig::new_unix(&socket).validate().is_ok());
+        assert!(ListenerConfig::new_unix(&socket).tls(true).validate().is_err());
+
+        // The socket file left behind by an earlier run is replaced.
+        drop(bind_unix_listeners(&[socket.clone()]).unwrap());
+        let listeners = bind_unix_listeners(&[socket.clone()]).unwrap();
+        assert!(std::os::unix::net::UnixStream::connect(&socket).is_ok());
+        drop(listeners);
+        std::fs::remove_file(&socket).unwrap();
+
+        // Any other file is not.
+        let file = std::env::temp_dir().join(format!("martlet_test_{}.txt", std::process::id()));
+        std::fs::write(&file, "kept").unwrap();
+        assert!(bind_unix_listeners(&[file.to_str().unwrap().to_string()]).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "kept");
+        std::fs::remove_file(&file).unwrap();
+    }
+}
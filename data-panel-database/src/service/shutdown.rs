@@ -0,0 +1,378 @@
+//! Graceful shutdown and the final report.
+//!
+//! On SIGTERM or ctrl-c new sessions are refused and the open ones get the drain timeout to
+//! finish. A structured report of what the process served is then logged, and posted to the
+//! metrics url when configured, so short-lived sidecars still leave an operational record.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use hyper::{Body, Client, Method, Request};
+
+use data_panel_common::config::config::ShutdownConfig;
+
+use crate::handler::database::mysql::is_err_payloads;
+use crate::service::shard::shard_stats;
+
+/// Counters of what the process served, across every shard.
+pub struct ServiceCounters {
+    started: Instant,
+    queries: AtomicU64,
+    errors: AtomicU64,
+    open_transactions: AtomicU64,
+    aborted_transactions: AtomicU64,
+    failovers: AtomicU64,
+    passthroughs: AtomicU64,
+    refused_connections: AtomicU64,
+    idle_sessions_closed: AtomicU64,
+}
+
+impl ServiceCounters {
+    fn new() -> Self {
+        ServiceCounters {
+            started: Instant::now(),
+            queries: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            open_transactions: AtomicU64::new(0),
+            aborted_transactions: AtomicU64::new(0),
+            failovers: AtomicU64::new(0),
+            passthroughs: AtomicU64::new(0),
+            refused_connections: AtomicU64::new(0),
+            idle_sessions_closed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn count_query(&self, failed: bool) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a statement, and an error when its response starts with an ERR packet.
+    pub fn record_query(&self, payloads: &Option<Vec<Bytes>>) {
+        self.count_query(is_err_payloads(payloads));
+    }
+
+    pub fn transaction_begun(&self) {
+        self.open_transactions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn transaction_ended(&self) {
+        self.open_transactions.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// A session closed, or was fenced by a failover, in the middle of a transaction.
+    pub fn transaction_aborted(&self) {
+        self.transaction_ended();
+        self.aborted_transactions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A primary failed over to one of its mirrors.
+    pub fn failed_over(&self) {
+        self.failovers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A statement that does not parse ran as sent, see `passthrough`.
+    pub fn passed_through(&self) {
+        self.passthroughs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A login was refused over the connection limits, see `limits`.
+    pub fn connection_refused(&self) {
+        self.refused_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A session was closed after it stayed idle for the idle timeout.
+    pub fn idle_session_closed(&self) {
+        self.idle_sessions_closed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get_queries(&self) -> u64 {
+        self.queries.load(Ordering::Relaxed)
+    }
+
+    pub fn get_errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    pub fn get_failovers(&self) -> u64 {
+        self.failovers.load(Ordering::Relaxed)
+    }
+
+    pub fn get_passthroughs(&self) -> u64 {
+        self.passthroughs.load(Ordering::Relaxed)
+    }
+
+    pub fn get_refused_connections(&self) -> u64 {
+        self.refused_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn get_idle_sessions_closed(&self) -> u64 {
+        self.idle_sessions_closed.load(Ordering::Relaxed)
+    }
+
+    pub fn get_uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+lazy_static! {
+    static ref SERVICE_COUNTERS: ServiceCounters = ServiceCounters::new();
+}
+
+/// Whether new sessions are refused.
+struct DrainState {
+    draining: AtomicBool,
+}
+
+impl DrainState {
+    const fn new() -> Self {
+        DrainState {
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Release);
+    }
+}
+
+static DRAIN_STATE: DrainState = DrainState::new();
+
+pub fn service_counters() -> &'static ServiceCounters {
+    &SERVICE_COUNTERS
+}
+
+/// Whether new sessions should be refused.
+pub fn is_shutting_down() -> bool {
+    DRAIN_STATE.is_draining()
+}
+
+/// Refuses new sessions, or accepts them again, without shutting down.
+pub fn set_draining(draining: bool) {
+    DRAIN_STATE.set_draining(draining);
+}
+
+fn active_sessions() -> u64 {
+    shard_stats().iter().map(|stats| stats.get_active()).sum()
+}
+
+#[derive(Debug, Clone)]
+pub struct ShutdownReport {
+    uptime: Duration,
+    sessions: u64,
+    queries: u64,
+    errors: u64,
+    drained_sessions: u64,
+    abandoned_sessions: u64,
+    aborted_transactions: u64,
+    failovers: u64,
+    passthroughs: u64,
+    refused_connections: u64,
+    idle_sessions_closed: u64,
+}
+
+impl ShutdownReport {
+    pub fn get_queries(&self) -> u64 {
+        self.queries
+    }
+
+    pub fn get_errors(&self) -> u64 {
+        self.errors
+    }
+
+    /// Sessions that finished within the drain timeout.
+    pub fn get_drained_sessions(&self) -> u64 {
+        self.drained_sessions
+    }
+
+    /// Sessions still open when the drain timeout ran out.
+    pub fn get_abandoned_sessions(&self) -> u64 {
+        self.abandoned_sessions
+    }
+
+    /// Transactions the client never committed nor rolled back.
+    pub fn get_aborted_transactions(&self) -> u64 {
+        self.aborted_transactions
+    }
+
+    pub fn get_failovers(&self) -> u64 {
+        self.failovers
+    }
+
+    pub fn get_passthroughs(&self) -> u64 {
+        self.passthroughs
+    }
+
+    pub fn get_refused_connections(&self) -> u64 {
+        self.refused_connections
+    }
+
+    pub fn get_idle_sessions_closed(&self) -> u64 {
+        self.idle_sessions_closed
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "event": "shutdown",
+            "uptime_secs": self.uptime.as_secs(),
+            "sessions": self.sessions,
+            "queries": self.queries,
+            "errors": self.errors,
+            "drained_sessions": self.drained_sessions,
+            "abandoned_sessions": self.abandoned_sessions,
+            "aborted_transactions": self.aborted_transactions,
+            "failovers": self.failovers,
+            "passthroughs": self.passthroughs,
+            "refused_connections": self.refused_connections,
+            "idle_sessions_closed": self.idle_sessions_closed,
+        })
+    }
+}
+
+/// Resolves on SIGTERM or ctrl-c.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => println!("error on listening for SIGTERM; error = {:?}", e),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        println!("error on listening for ctrl-c; error = {:?}", e);
+    }
+}
+
+/// Refuses new sessions, waits up to the drain timeout for the open ones and reports.
+pub async fn drain(config: &ShutdownConfig) -> ShutdownReport {
+    drain_with(&DRAIN_STATE, config).await
+}
+
+/// Like `drain`, with the drain state given.
+async fn drain_with(drain_state: &DrainState, config: &ShutdownConfig) -> ShutdownReport {
+    drain_state.set_draining(true);
+    let draining = active_sessions();
+    println!("Shutting down, draining {} sessions", draining);
+    let deadline = Instant::now() + Duration::from_millis(config.get_drain_timeout() as u64);
+    while active_sessions() > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let counters = service_counters();
+    let abandoned_sessions = active_sessions();
+    ShutdownReport {
+        uptime: counters.started.elapsed(),
+        sessions: shard_stats().iter().map(|stats| stats.get_accepted()).sum(),
+        queries: counters.get_queries(),
+        errors: counters.get_errors(),
+        drained_sessions: draining.saturating_sub(abandoned_sessions),
+        abandoned_sessions,
+        aborted_transactions: counters.aborted_transactions.load(Ordering::Relaxed)
+            + counters.open_transactions.load(Ordering::Relaxed),
+        failovers: counters.get_failovers(),
+        passthroughs: counters.get_passthroughs(),
+        refused_connections: counters.get_refused_connections(),
+        idle_sessions_closed: counters.get_idle_sessions_closed(),
+    }
+}
+
+/// Logs `report` and posts it to the metrics url, if one is configured.
+pub async fn publish(report: &ShutdownReport, config: &ShutdownConfig) {
+    let body = report.to_json().to_string();
+    println!("Shutdown report: {}", body);
+    if config.get_metrics_url().is_empty() {
+        return;
+    }
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(config.get_metrics_url().as_str())
+        .header("content-type", "application/json")
+        .body(Body::from(body));
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => return println!("error on building the metrics request; error = {:?}", e),
+    };
+    match tokio::time::timeout(Duration::from_secs(5), Client::new().request(request)).await {
+        Ok(Ok(response)) if response.status().is_success() => {}
+        Ok(Ok(response)) => println!("error on pushing final metrics; status = {}", response.status()),
+        Ok(Err(e)) => println!("error on pushing final metrics; error = {:?}", e),
+        Err(_) => println!("error on pushing final metrics; no answer within 5s"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::Ordering;
+
+    use hyper::{Body, Request, Response, Server};
+    use hyper::service::{make_service_fn, service_fn};
+
+    use data_panel_common::config::config::ShutdownConfig;
+
+    use super::{drain_with, DrainState, publish, ServiceCounters};
+
+    #[test]
+    fn test_service_counters() {
+        let counters = ServiceCounters::new();
+        counters.count_query(false);
+        counters.count_query(true);
+        counters.transaction_begun();
+        counters.transaction_begun();
+        counters.transaction_ended();
+        counters.transaction_aborted();
+        assert_eq!((counters.get_queries(), counters.get_errors()), (2, 1));
+        assert_eq!(counters.open_transactions.load(Ordering::Relaxed), 0);
+        assert_eq!(counters.aborted_transactions.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_drain_and_publish() {
+        let received = Arc::new(Mutex::new(None));
+        let sink = received.clone();
+        let make_service = make_service_fn(move |_| {
+            let sink = sink.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let sink = sink.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        *sink.lock().unwrap() = Some(serde_json::from_slice::<serde_json::Value>(&body).unwrap());
+                        Ok::<_, Infallible>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let config = ShutdownConfig::new(0, &format!("http://{}/metrics", server.local_addr()));
+        tokio::spawn(server);
+
+        // No session is open, nothing is left to drain or abandon. A drain state of its own
+        // leaves the process accepting sessions for the other tests.
+        let drain_state = DrainState::new();
+        let report = drain_with(&drain_state, &config).await;
+        assert!(drain_state.is_draining());
+        assert_eq!((report.get_drained_sessions(), report.get_abandoned_sessions()), (0, 0));
+
+        publish(&report, &config).await;
+        let posted = received.lock().unwrap().take().unwrap();
+        assert_eq!(posted["event"], "shutdown");
+        assert_eq!(posted["abandoned_sessions"], 0);
+        assert_eq!(posted, report.to_json());
+    }
+}
@@ -0,0 +1,200 @@
+//! TLS termination for client connections.
+//!
+//! The handshake advertises `CLIENT_SSL` while an acceptor is configured, a client that wants
+//! TLS answers with an SSL request and the session continues on the upgraded stream. With a
+//! `client_ca` the client may authenticate with its certificate, its identities then being
+//! mapped to a user, see `TlsConfig`.
+
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind};
+use std::sync::{Arc, RwLock};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use openssl::nid::Nid;
+use openssl::x509::X509;
+use tokio_rustls::rustls::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, Certificate, NoClientAuth, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::TlsAcceptor;
+
+use data_panel_common::config::config::TlsConfig;
+
+/// The stream a client session runs on, plain TCP or TLS over it.
+pub trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> ClientStream for S {}
+
+lazy_static! {
+    static ref TLS_ACCEPTOR: RwLock<Option<TlsAcceptor>> = RwLock::new(None);
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match certs(&mut reader) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(Error::new(ErrorKind::InvalidInput, format!("no certificate in {}", path))),
+    }
+}
+
+fn load_key(path: &str) -> Result<PrivateKey, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut keys = pkcs8_private_keys(&mut reader).unwrap_or_default();
+    if keys.is_empty() {
+        let mut reader = BufReader::new(File::open(path)?);
+        keys = rsa_private_keys(&mut reader).unwrap_or_default();
+    }
+    match keys.pop() {
+        Some(key) => Ok(key),
+        None => Err(Error::new(ErrorKind::InvalidInput, format!("no private key in {}", path))),
+    }
+}
+
+fn load_client_roots(path: &str) -> Result<RootCertStore, Error> {
+    let mut roots = RootCertStore::empty();
+    match roots.add_pem_file(&mut BufReader::new(File::open(path)?)) {
+        Ok((added, _)) if added > 0 => Ok(roots),
+        _ => Err(Error::new(ErrorKind::InvalidInput, format!("no CA certificate in {}", path))),
+    }
+}
+
+pub fn load_tls_acceptor(config: &TlsConfig) -> Result<TlsAcceptor, Error> {
+    let client_auth = if config.get_client_ca().is_empty() {
+        NoClientAuth::new()
+    } else if config.is_client_cert_required() {
+        AllowAnyAuthenticatedClient::new(load_client_roots(&config.get_client_ca())?)
+    } else {
+        AllowAnyAnonymousOrAuthenticatedClient::new(load_client_roots(&config.get_client_ca())?)
+    };
+    let mut server_config = ServerConfig::new(client_auth);
+    server_config.set_single_cert(load_certs(&config.get_cert())?, load_key(&config.get_key())?)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Installs the acceptor described by `config`, or removes it when disabled.
+pub fn configure_tls(config: &TlsConfig) -> Result<(), Error> {
+    let acceptor = if config.is_enabled() {
+        Some(load_tls_acceptor(config)?)
+    } else {
+        None
+    };
+    *TLS_ACCEPTOR.write().unwrap() = acceptor;
+    Ok(())
+}
+
+pub fn tls_acceptor() -> Option<TlsAcceptor> {
+    TLS_ACCEPTOR.read().unwrap().clone()
+}
+
+/// The URI, DNS and email SANs of a verified client certificate, then its CNs.
+pub fn certificate_identities(cert: &Certificate) -> Vec<String> {
+    let cert = match X509::from_der(&cert.0) {
+        Ok(cert) => cert,
+        Err(e) => {
+            println!("error on reading a client certificate; error = {:?}", e);
+            return vec![];
+        }
+    };
+    let mut identities = vec![];
+    for name in cert.subject_alt_names().into_iter().flatten() {
+        if let Some(identity) = name.uri().or_else(|| name.dnsname()).or_else(|| name.email()) {
+            identities.push(identity.to_string());
+        }
+    }
+    for entry in cert.subject_name().entries_by_nid(Nid::COMMONNAME) {
+        if let Ok(common_name) = String::from_utf8(entry.data().as_slice().to_vec()) {
+            identities.push(common_name);
+        }
+    }
+    identities
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::{X509, X509Name};
+    use openssl::x509::extension::SubjectAlternativeName;
+    use tokio_rustls::rustls::Certificate;
+
+    use data_panel_common::config::config::{CertUser, TlsConfig};
+
+    use super::{certificate_identities, load_tls_acceptor};
+
+    /// Writes a self-signed certificate named `common_name`, with the URI and DNS SANs of
+    /// `alt_names`, and its key to the temp dir.
+    fn certificate(name: &str, common_name: &str, alt_names: &[&str]) -> (PathBuf, PathBuf) {
+        let key = PKey::from_ec_key(EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap()).unwrap();
+        let mut subject = X509Name::builder().unwrap();
+        subject.append_entry_by_nid(Nid::COMMONNAME, common_name).unwrap();
+        let subject = subject.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_issuer_name(&subject).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        if !alt_names.is_empty() {
+            let mut san = SubjectAlternativeName::new();
+            for alt_name in alt_names {
+                if alt_name.contains("://") {
+                    san.uri(alt_name);
+                } else {
+                    san.dns(alt_name);
+                }
+            }
+            let san = san.build(&builder.x509v3_context(None, None)).unwrap();
+            builder.append_extension(san).unwrap();
+        }
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let cert_path = std::env::temp_dir().join(format!("martlet_test_{}.crt", name));
+        let key_path = std::env::temp_dir().join(format!("martlet_test_{}.key", name));
+        std::fs::write(&cert_path, builder.build().to_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (cert_path, key_path)
+    }
+
+    #[test]
+    fn test_tls_acceptor() {
+        let (cert, key) = certificate("acceptor", "martlet", &[]);
+        let (cert, key) = (cert.to_str().unwrap(), key.to_str().unwrap());
+        assert!(load_tls_acceptor(&TlsConfig::new(cert, key)).is_ok());
+
+        // A key where the certificate is expected, and the other way around.
+        let e = load_tls_acceptor(&TlsConfig::new(key, key)).err().unwrap();
+        assert_eq!(e.to_string(), format!("no certificate in {}", key));
+        let e = load_tls_acceptor(&TlsConfig::new(cert, cert)).err().unwrap();
+        assert_eq!(e.to_string(), format!("no private key in {}", cert));
+        assert!(load_tls_acceptor(&TlsConfig::new("/nonexistent/martlet.crt", key)).is_err());
+    }
+
+    #[test]
+    fn test_client_certificates() {
+        let (cert, key) = certificate("client", "billing", &["spiffe://martlet/billing", "billing.internal"]);
+        let der = X509::from_pem(&std::fs::read(&cert).unwrap()).unwrap().to_der().unwrap();
+        let identities = certificate_identities(&Certificate(der));
+        assert_eq!(identities, vec!["spiffe://martlet/billing".to_string(), "billing.internal".to_string(), "billing".to_string()]);
+        assert!(certificate_identities(&Certificate(b"not a certificate".to_vec())).is_empty());
+
+        let (cert, key) = (cert.to_str().unwrap(), key.to_str().unwrap());
+        let config = TlsConfig::new(cert, key).client_ca(cert, vec![
+            CertUser::new("spiffe://martlet/reporting", "reporting"),
+            CertUser::new("billing", "billing_ro"),
+            CertUser::new("billing.internal", "billing_rw"),
+        ]);
+        // The first cert user matching any identity of the certificate.
+        assert_eq!(config.cert_user(&identities), Some("billing_ro".to_string()));
+        assert_eq!(config.cert_user(&["payments".to_string()]), None);
+
+        assert!(load_tls_acceptor(&config).is_ok());
+        assert!(load_tls_acceptor(&config.client_cert_required(true)).is_ok());
+        let e = load_tls_acceptor(&TlsConfig::new(cert, key).client_ca(key, vec![])).err().unwrap();
+        assert_eq!(e.to_string(), format!("no CA certificate in {}", key));
+    }
+}
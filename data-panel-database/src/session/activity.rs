@@ -0,0 +1,303 @@
+//! Per-session activity tracking, used to tell which side of the proxy a stalled query waits on.
+//!
+//! A command is in the backend phase while its handler runs and in the client phase while its
+//! response is written out. A phase lasting longer than the system timeout is reported as a stall.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::time::Instant;
+
+use dashmap::DashMap;
+
+use data_panel_common::config::config::MeshConfig;
+
+/// The statements of an open transaction kept, the last ones.
+const TRANSACTION_STATEMENTS_KEPT: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionPhase {
+    /// Waiting for the next command from the client, never a stall.
+    Idle = 0,
+    /// The handler is running, waiting for the backend to produce the response.
+    Backend = 1,
+    /// The response is being written, waiting for the client to read it.
+    Client = 2,
+}
+
+impl SessionPhase {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => SessionPhase::Backend,
+            2 => SessionPhase::Client,
+            _ => SessionPhase::Idle,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StallReason {
+    BackendNotProducing,
+    ClientNotReading,
+}
+
+impl StallReason {
+    pub fn value(&self) -> &'static str {
+        match self {
+            StallReason::BackendNotProducing => "backend not producing",
+            StallReason::ClientNotReading => "client not reading",
+        }
+    }
+}
+
+/// Who a session is and what it holds, as the admin API shows it.
+#[derive(Debug, Clone, Default)]
+pub struct SessionDetails {
+    user: String,
+    database: String,
+    /// `host:port/db` of the backend, without credentials.
+    backend: String,
+    prepared_statements: Vec<(u64, String)>,
+}
+
+impl SessionDetails {
+    pub fn new(user: String, database: String, backend: String, prepared_statements: Vec<(u64, String)>) -> Self {
+        SessionDetails {
+            user,
+            database,
+            backend,
+            prepared_statements,
+        }
+    }
+
+    pub fn get_user(&self) -> String {
+        self.user.clone()
+    }
+
+    pub fn get_database(&self) -> String {
+        self.database.clone()
+    }
+
+    pub fn get_backend(&self) -> String {
+        self.backend.clone()
+    }
+
+    pub fn get_prepared_statements(&self) -> &Vec<(u64, String)> {
+        &self.prepared_statements
+    }
+}
+
+pub struct SessionActivity {
+    id: u64,
+    client_addr: SocketAddr,
+    phase: AtomicU8,
+    /// Milliseconds since `ACTIVITY_EPOCH` when the current phase started.
+    since: AtomicU64,
+    details: Mutex<SessionDetails>,
+    /// The statement the current command runs.
+    query: Mutex<Option<String>>,
+    /// The statements the open transaction ran, whose locks it holds.
+    transaction: Mutex<VecDeque<String>>,
+}
+
+impl SessionActivity {
+    pub fn enter(&self, phase: SessionPhase) {
+        self.since.store(now_millis(), Ordering::Relaxed);
+        self.phase.store(phase as u8, Ordering::Release);
+    }
+
+    pub fn snapshot(&self, threshold: u64) -> SessionActivitySnapshot {
+        let phase = SessionPhase::from_u8(self.phase.load(Ordering::Acquire));
+        let elapsed = now_millis().saturating_sub(self.since.load(Ordering::Relaxed));
+        let stall = match phase {
+            SessionPhase::Backend if elapsed >= threshold => Some(StallReason::BackendNotProducing),
+            SessionPhase::Client if elapsed >= threshold => Some(StallReason::ClientNotReading),
+            _ => None,
+        };
+        SessionActivitySnapshot {
+            session_id: self.id,
+            client_addr: self.client_addr,
+            phase,
+            elapsed,
+            stall,
+            details: self.details.lock().unwrap().clone(),
+            query: self.query.lock().unwrap().clone(),
+            transaction: self.transaction.lock().unwrap().iter().cloned().collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionActivitySnapshot {
+    session_id: u64,
+    client_addr: SocketAddr,
+    phase: SessionPhase,
+    elapsed: u64,
+    stall: Option<StallReason>,
+    details: SessionDetails,
+    query: Option<String>,
+    transaction: Vec<String>,
+}
+
+impl SessionActivitySnapshot {
+    pub fn get_session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    pub fn get_client_addr(&self) -> SocketAddr {
+        self.client_addr
+    }
+
+    pub fn get_phase(&self) -> SessionPhase {
+        self.phase
+    }
+
+    /// Milliseconds spent in the current phase.
+    pub fn get_elapsed(&self) -> u64 {
+        self.elapsed
+    }
+
+    pub fn get_stall(&self) -> Option<StallReason> {
+        self.stall
+    }
+
+    pub fn get_details(&self) -> &SessionDetails {
+        &self.details
+    }
+
+    pub fn get_query(&self) -> Option<&String> {
+        self.query.as_ref()
+    }
+
+    /// The statements the open transaction ran, oldest first, none outside of one.
+    pub fn get_transaction_statements(&self) -> &Vec<String> {
+        &self.transaction
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "session_id": self.session_id,
+            "client_addr": self.client_addr.to_string(),
+            "phase": format!("{:?}", self.phase),
+            "elapsed_ms": self.elapsed,
+            "stall": self.stall.map(|stall| stall.value()),
+            "user": self.details.user,
+            "database": self.details.database,
+            "backend": self.details.backend,
+            "prepared_statements": self.details.prepared_statements.len(),
+            "query": self.query,
+        })
+    }
+}
+
+/// Removes the session from the registry when the connection goes away.
+pub struct SessionActivityGuard {
+    activity: Arc<SessionActivity>,
+}
+
+impl SessionActivityGuard {
+    pub fn enter(&self, phase: SessionPhase) {
+        self.activity.enter(phase);
+    }
+
+    pub fn describe(&self, details: SessionDetails) {
+        *self.activity.details.lock().unwrap() = details;
+    }
+
+    /// Sets the statement the current command runs, None once it is done.
+    pub fn run(&self, query: Option<String>) {
+        *self.activity.query.lock().unwrap() = query;
+    }
+
+    /// Ends the current command, its statement kept with the ones of the transaction while
+    /// `in_transaction`, all of them forgotten once it ends.
+    pub fn finish(&self, in_transaction: bool) {
+        let query = self.activity.query.lock().unwrap().take();
+        let mut transaction = self.activity.transaction.lock().unwrap();
+        if !in_transaction {
+            transaction.clear();
+        } else if let Some(query) = query {
+            if transaction.len() == TRANSACTION_STATEMENTS_KEPT {
+                transaction.pop_front();
+            }
+            transaction.push_back(query);
+        }
+    }
+}
+
+impl Drop for SessionActivityGuard {
+    fn drop(&mut self) {
+        SESSION_ACTIVITIES.remove(&self.activity.id);
+    }
+}
+
+lazy_static! {
+    static ref ACTIVITY_EPOCH: Instant = Instant::now();
+    static ref SESSION_ACTIVITIES: DashMap<u64, Arc<SessionActivity>> = DashMap::new();
+}
+
+fn now_millis() -> u64 {
+    ACTIVITY_EPOCH.elapsed().as_millis() as u64
+}
+
+pub fn register_session_activity(id: u64, client_addr: SocketAddr) -> SessionActivityGuard {
+    let activity = Arc::new(SessionActivity {
+        id,
+        client_addr,
+        phase: AtomicU8::new(SessionPhase::Idle as u8),
+        since: AtomicU64::new(now_millis()),
+        details: Mutex::new(SessionDetails::default()),
+        query: Mutex::new(None),
+        transaction: Mutex::new(VecDeque::new()),
+    });
+    SESSION_ACTIVITIES.insert(id, activity.clone());
+    SessionActivityGuard { activity }
+}
+
+/// Activity of every open session, stalled for longer than the system timeout or not.
+pub fn session_activities() -> Vec<SessionActivitySnapshot> {
+    let threshold = MeshConfig::get_timeout() as u64;
+    SESSION_ACTIVITIES.iter()
+        .map(|activity| activity.value().snapshot(threshold))
+        .collect()
+}
+
+pub fn session_activity(id: u64) -> Option<SessionActivitySnapshot> {
+    let threshold = MeshConfig::get_timeout() as u64;
+    SESSION_ACTIVITIES.get(&id).map(|activity| activity.value().snapshot(threshold))
+}
+
+/// Whether session `id` is open, listed by the processlist.
+pub fn is_session_registered(id: u64) -> bool {
+    SESSION_ACTIVITIES.contains_key(&id)
+}
+
+/// Only the sessions currently stalled on either side.
+pub fn stalled_sessions() -> Vec<SessionActivitySnapshot> {
+    session_activities().into_iter()
+        .filter(|activity| activity.get_stall().is_some())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::session::activity::{register_session_activity, SessionPhase, StallReason, SESSION_ACTIVITIES};
+
+    #[test]
+    fn test_stall_attribution() {
+        let guard = register_session_activity(42, "127.0.0.1:3306".parse().unwrap());
+        let activity = SESSION_ACTIVITIES.get(&42).unwrap().value().clone();
+        assert_eq!(activity.snapshot(0).get_stall(), None);
+
+        guard.enter(SessionPhase::Backend);
+        assert_eq!(activity.snapshot(0).get_stall(), Some(StallReason::BackendNotProducing));
+        assert_eq!(activity.snapshot(60_000).get_stall(), None);
+
+        guard.enter(SessionPhase::Client);
+        assert_eq!(activity.snapshot(0).get_stall(), Some(StallReason::ClientNotReading));
+
+        drop(guard);
+        assert!(SESSION_ACTIVITIES.get(&42).is_none());
+    }
+}
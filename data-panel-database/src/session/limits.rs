@@ -0,0 +1,128 @@
+//! Client connection limits and the closing of idle sessions, see `ConnectionLimitConfig`.
+//!
+//! A session takes a connection slot once its user logged in and gives it back when it closes.
+//! Logins over the limit in all, or over the limit of their user, are refused with the errors
+//! MySQL answers them with. The limits are read on every login, a reload never closes the
+//! sessions already open.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use data_panel_common::config::config::{ConnectionLimitConfig, MeshConfig};
+
+use crate::protocol::database::mysql::constant::MySQLServerErrorCode;
+
+#[derive(Default)]
+pub struct ConnectionCounter {
+    /// The open connections of every user.
+    users: Mutex<HashMap<String, usize>>,
+}
+
+impl ConnectionCounter {
+    /// A slot for a connection of `user`, or the error refusing it when a limit is reached.
+    pub fn admit(counter: &Arc<ConnectionCounter>, config: &ConnectionLimitConfig, user: &str) -> Result<ConnectionSlot, (MySQLServerErrorCode, String)> {
+        let mut users = counter.users.lock().unwrap();
+        if config.is_enabled() {
+            let total: usize = users.values().sum();
+            if config.get_max_connections() > 0 && total >= config.get_max_connections() {
+                return Err((MySQLServerErrorCode::ErConCountError, "Too many connections".to_string()));
+            }
+            let max_connections = config.max_connections(user);
+            if max_connections > 0 && users.get(user).map_or(0, |open| *open) >= max_connections {
+                return Err((MySQLServerErrorCode::ErTooManyUserConnections, format!("User {} already has more than 'max_user_connections' active connections", user)));
+            }
+        }
+        *users.entry(user.to_string()).or_insert(0) += 1;
+        Ok(ConnectionSlot {
+            counter: counter.clone(),
+            user: user.to_string(),
+        })
+    }
+
+    fn release(&self, user: &str) {
+        let mut users = self.users.lock().unwrap();
+        if let Some(open) = users.get_mut(user) {
+            *open -= 1;
+            if *open == 0 {
+                users.remove(user);
+            }
+        }
+    }
+
+    /// The open connections of every user, by user.
+    pub fn user_connections(&self) -> Vec<(String, usize)> {
+        let mut users: Vec<(String, usize)> = self.users.lock().unwrap().iter()
+            .map(|(user, open)| (user.clone(), *open))
+            .collect();
+        users.sort();
+        users
+    }
+}
+
+/// A connection counted against the limits, given back on drop.
+pub struct ConnectionSlot {
+    counter: Arc<ConnectionCounter>,
+    user: String,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.counter.release(&self.user);
+    }
+}
+
+lazy_static! {
+    static ref CONNECTION_COUNTER: Arc<ConnectionCounter> = Arc::new(ConnectionCounter::default());
+}
+
+/// A slot for a connection of `user` under the current limits.
+pub fn admit(user: &str) -> Result<ConnectionSlot, (MySQLServerErrorCode, String)> {
+    ConnectionCounter::admit(&CONNECTION_COUNTER, &MeshConfig::get_connection_limits_config(), user)
+}
+
+pub fn connection_counter() -> Arc<ConnectionCounter> {
+    CONNECTION_COUNTER.clone()
+}
+
+/// Resolves once a session waited the idle timeout for its next command, never without one.
+pub async fn idle_timeout_elapsed() {
+    let config = MeshConfig::get_connection_limits_config();
+    if !config.is_enabled() || config.get_idle_timeout() == 0 {
+        return futures::future::pending().await;
+    }
+    tokio::time::sleep(Duration::from_secs(config.get_idle_timeout() as u64)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use data_panel_common::config::config::{ConnectionLimitConfig, UserConnectionLimit};
+
+    use crate::protocol::database::mysql::constant::MySQLServerErrorCode;
+
+    use super::ConnectionCounter;
+
+    #[test]
+    fn test_connection_limits() {
+        let config = ConnectionLimitConfig::new(3, 2).users(vec![UserConnectionLimit::new("report", 1)]);
+        let counter = Arc::new(ConnectionCounter::default());
+        let report = ConnectionCounter::admit(&counter, &config, "report").ok().unwrap();
+        assert_eq!(ConnectionCounter::admit(&counter, &config, "report").err().unwrap().0, MySQLServerErrorCode::ErTooManyUserConnections);
+        let apps = (ConnectionCounter::admit(&counter, &config, "app").ok().unwrap(), ConnectionCounter::admit(&counter, &config, "app").ok().unwrap());
+        assert_eq!(ConnectionCounter::admit(&counter, &config, "other").err().unwrap().0, MySQLServerErrorCode::ErConCountError);
+        assert_eq!(counter.user_connections(), vec![("app".to_string(), 2), ("report".to_string(), 1)]);
+
+        drop(report);
+        assert!(ConnectionCounter::admit(&counter, &config, "other").is_ok());
+        drop(apps);
+        assert!(counter.user_connections().is_empty());
+
+        // Counted, never refused, while the limits are disabled.
+        let counter = Arc::new(ConnectionCounter::default());
+        let slots: Vec<_> = (0..4).map(|_| ConnectionCounter::admit(&counter, &ConnectionLimitConfig::default(), "app").ok().unwrap()).collect();
+        assert_eq!(counter.user_connections(), vec![("app".to_string(), 4)]);
+        drop(slots);
+    }
+}
@@ -1 +1,4 @@
-pub mod mysql;
\ No newline at end of file
+pub mod mysql;
+pub mod postgresql;
+pub mod activity;
+pub mod limits;
\ No newline at end of file
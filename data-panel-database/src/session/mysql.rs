@@ -1,22 +1,56 @@
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
-use crate::protocol::database::mysql::constant::MySQLConnectionPhase;
+use bytes::Bytes;
+
+use crate::handler::database::cancel::RunningSlot;
+use crate::handler::database::lifecycle::BackendConn;
+use crate::handler::database::telemetry::StatementTrace;
+use crate::handler::database::xa::XaTransaction;
+use crate::protocol::database::mysql::constant::{MySQLConnectionPhase, MySQLStatusFlag};
 use crate::protocol::database::mysql::packet::generate_random_bytes;
 
 #[derive(Debug)]
 pub struct SessionContext {
     id: u64,
     authorized: bool,
+    secure: bool,
+    /// The user the client certificate maps to.
+    cert_user: Option<String>,
+    tls_offered: bool,
+    deprecate_eof: bool,
+    in_transaction: bool,
+    autocommit: bool,
+    isolation_level: Option<String>,
+    next_isolation_level: Option<String>,
+    pinned_conn: Option<BackendConn>,
+    /// The temporary tables the session created on its pinned connection.
+    temporary_tables: Vec<String>,
+    /// Whether the pinned connection has session state only it knows, and stays pinned until
+    /// the session closes.
+    holding_conn: bool,
+    /// The branches the open transaction has on other segments.
+    xa_transaction: Option<XaTransaction>,
     connection_phase: MySQLConnectionPhase,
     auth_plugin_data1: Vec<u8>,
     auth_plugin_data2: Vec<u8>,
     prepare_stmt_ctx_id: HashMap<String, u64>,
     prepare_stmt_ctx_map: HashMap<u64, PrepareStatementContext>,
+    /// The id of the next statement the session prepares, statement ids are per session.
+    next_statement_id: u64,
+    /// Where the statements the session runs on backends are tracked, see `cancel::track`.
+    running_slot: Arc<RunningSlot>,
+    /// The trace of the statement the session runs, when it is traced.
+    statement_trace: Option<StatementTrace>,
+    connect_attrs: Vec<(String, String)>,
     character_set: u8,
     user_name: String,
     auth_response: Vec<u8>,
+    auth_plugin_name: String,
     database: String,
+    warnings: Vec<String>,
+    variables: Vec<(String, String)>,
+    backend_url: String,
 }
 
 impl SessionContext {
@@ -28,15 +62,35 @@ impl SessionContext {
         SessionContext {
             id,
             authorized: false,
+            secure: false,
+            cert_user: None,
+            tls_offered: true,
+            deprecate_eof: false,
+            in_transaction: false,
+            autocommit: true,
+            isolation_level: None,
+            next_isolation_level: None,
+            pinned_conn: None,
+            temporary_tables: vec![],
+            holding_conn: false,
+            xa_transaction: None,
             connection_phase: MySQLConnectionPhase::InitialHandshake,
             auth_plugin_data1,
             auth_plugin_data2,
             prepare_stmt_ctx_id: HashMap::new(),
             prepare_stmt_ctx_map: HashMap::new(),
+            next_statement_id: 1,
+            running_slot: Arc::new(RunningSlot::new(id)),
+            statement_trace: None,
+            connect_attrs: vec![],
             character_set: 0,
             user_name: "".to_string(),
             auth_response: vec![],
+            auth_plugin_name: "".to_string(),
             database: "".to_string(),
+            warnings: vec![],
+            variables: vec![],
+            backend_url: "".to_string(),
         }
     }
 
@@ -52,6 +106,140 @@ impl SessionContext {
         self.authorized = authorized;
     }
 
+    /// Whether the client connection was upgraded to TLS.
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    pub fn set_secure(&mut self, secure: bool) {
+        self.secure = secure;
+    }
+
+    pub fn get_cert_user(&self) -> Option<String> {
+        self.cert_user.clone()
+    }
+
+    pub fn set_cert_user(&mut self, cert_user: String) {
+        self.cert_user = Some(cert_user);
+    }
+
+    /// Whether the listener the client connected to offers TLS, see `ListenerConfig`.
+    pub fn is_tls_offered(&self) -> bool {
+        self.tls_offered
+    }
+
+    pub fn set_tls_offered(&mut self, tls_offered: bool) {
+        self.tls_offered = tls_offered;
+    }
+
+    /// Whether the client negotiated CLIENT_DEPRECATE_EOF, OK packets then end result sets.
+    pub fn is_deprecate_eof(&self) -> bool {
+        self.deprecate_eof
+    }
+
+    pub fn set_deprecate_eof(&mut self, deprecate_eof: bool) {
+        self.deprecate_eof = deprecate_eof;
+    }
+
+    /// Whether the client started a transaction it did not commit or roll back yet.
+    pub fn is_in_transaction(&self) -> bool {
+        self.in_transaction
+    }
+
+    pub fn set_in_transaction(&mut self, in_transaction: bool) {
+        self.in_transaction = in_transaction;
+    }
+
+    pub fn is_autocommit(&self) -> bool {
+        self.autocommit
+    }
+
+    pub fn set_autocommit(&mut self, autocommit: bool) {
+        self.autocommit = autocommit;
+    }
+
+    /// The isolation level set for the session's transactions, the backend default if none.
+    pub fn get_isolation_level(&self) -> Option<String> {
+        self.isolation_level.clone()
+    }
+
+    pub fn set_isolation_level(&mut self, isolation_level: Option<String>) {
+        self.isolation_level = isolation_level;
+    }
+
+    /// An isolation level set for the next transaction only.
+    pub fn set_next_isolation_level(&mut self, next_isolation_level: Option<String>) {
+        self.next_isolation_level = next_isolation_level;
+    }
+
+    /// The isolation level the next transaction starts with, consuming a one-off level.
+    pub fn take_transaction_isolation_level(&mut self) -> Option<String> {
+        self.next_isolation_level.take().or_else(|| self.isolation_level.clone())
+    }
+
+    /// The backend connection the open transaction, or the temporary tables, are on.
+    pub fn has_pinned_conn(&self) -> bool {
+        self.pinned_conn.is_some()
+    }
+
+    pub fn pin_conn(&mut self, conn: BackendConn) {
+        self.pinned_conn = Some(conn);
+    }
+
+    pub fn take_pinned_conn(&mut self) -> Option<BackendConn> {
+        self.pinned_conn.take()
+    }
+
+    pub fn has_temporary_tables(&self) -> bool {
+        !self.temporary_tables.is_empty()
+    }
+
+    pub fn add_temporary_table(&mut self, table: String) {
+        if !self.temporary_tables.contains(&table) {
+            self.temporary_tables.push(table);
+        }
+    }
+
+    pub fn remove_temporary_table(&mut self, table: &str) {
+        self.temporary_tables.retain(|temporary_table| temporary_table != table);
+    }
+
+    pub fn clear_temporary_tables(&mut self) {
+        self.temporary_tables.clear();
+    }
+
+    pub fn is_holding_conn(&self) -> bool {
+        self.holding_conn
+    }
+
+    pub fn set_holding_conn(&mut self, holding_conn: bool) {
+        self.holding_conn = holding_conn;
+    }
+
+    pub fn has_xa_transaction(&self) -> bool {
+        self.xa_transaction.is_some()
+    }
+
+    pub fn set_xa_transaction(&mut self, xa_transaction: XaTransaction) {
+        self.xa_transaction = Some(xa_transaction);
+    }
+
+    pub fn take_xa_transaction(&mut self) -> Option<XaTransaction> {
+        self.xa_transaction.take()
+    }
+
+    /// Server status flags of the OK and EOF packets, following the transaction state.
+    pub fn get_status_flags(&self) -> u16 {
+        let mut status_flags = 0;
+        if self.in_transaction {
+            status_flags |= MySQLStatusFlag::ServerStatusInTrans as u16;
+        }
+        if self.autocommit {
+            status_flags |= MySQLStatusFlag::ServerStatusAutocommit as u16;
+        }
+        status_flags
+    }
+
     pub fn get_auth_plugin_data1(&self) -> Vec<u8> {
         self.auth_plugin_data1.clone()
     }
@@ -76,6 +264,15 @@ impl SessionContext {
         self.auth_response = auth_response;
     }
 
+    /// The authentication method the auth response was computed with.
+    pub fn get_auth_plugin_name(&self) -> String {
+        self.auth_plugin_name.clone()
+    }
+
+    pub fn set_auth_plugin_name(&mut self, auth_plugin_name: String) {
+        self.auth_plugin_name = auth_plugin_name;
+    }
+
     pub fn get_database(&self) -> String {
         self.database.clone()
     }
@@ -84,6 +281,89 @@ impl SessionContext {
         self.database = database;
     }
 
+    /// The mysql url of the segment this session's statements run on.
+    pub fn get_backend_url(&self) -> String {
+        self.backend_url.clone()
+    }
+
+    pub fn set_backend_url(&mut self, backend_url: String) {
+        self.backend_url = backend_url;
+    }
+
+    pub fn next_statement_id(&mut self) -> u64 {
+        let statement_id = self.next_statement_id;
+        self.next_statement_id += 1;
+        statement_id
+    }
+
+    pub fn get_running_slot(&self) -> Arc<RunningSlot> {
+        self.running_slot.clone()
+    }
+
+    pub fn set_running_slot(&mut self, running_slot: Arc<RunningSlot>) {
+        self.running_slot = running_slot;
+    }
+
+    pub fn get_statement_trace_mut(&mut self) -> Option<&mut StatementTrace> {
+        self.statement_trace.as_mut()
+    }
+
+    pub fn set_statement_trace(&mut self, statement_trace: Option<StatementTrace>) {
+        self.statement_trace = statement_trace;
+    }
+
+    pub fn take_statement_trace(&mut self) -> Option<StatementTrace> {
+        self.statement_trace.take()
+    }
+
+    /// The connection attributes the client sent in its handshake response.
+    pub fn get_connect_attrs(&self) -> Vec<(String, String)> {
+        self.connect_attrs.clone()
+    }
+
+    pub fn set_connect_attrs(&mut self, connect_attrs: Vec<(String, String)>) {
+        self.connect_attrs = connect_attrs;
+    }
+
+    /// Warnings raised by the mesh itself for the last statement, see SHOW WARNINGS.
+    pub fn get_warnings(&self) -> Vec<String> {
+        self.warnings.clone()
+    }
+
+    /// Records a session or user variable the client set, `value` as SQL.
+    pub fn set_variable(&mut self, name: String, value: String) {
+        match self.variables.iter_mut().find(|(recorded, _)| *recorded == name) {
+            Some(variable) => variable.1 = value,
+            None => self.variables.push((name, value)),
+        }
+    }
+
+    pub fn get_variable(&self, name: &str) -> Option<String> {
+        self.variables.iter().find(|(recorded, _)| recorded == name).map(|(_, value)| value.clone())
+    }
+
+    /// The recorded variables in the order they were first set.
+    pub fn get_variables(&self) -> Vec<(String, String)> {
+        self.variables.clone()
+    }
+
+    /// The collation id the client negotiated in the handshake.
+    pub fn get_character_set(&self) -> u8 {
+        self.character_set
+    }
+
+    pub fn set_character_set(&mut self, character_set: u8) {
+        self.character_set = character_set;
+    }
+
+    pub fn push_warning(&mut self, warning: String) {
+        self.warnings.push(warning);
+    }
+
+    pub fn clear_warnings(&mut self) {
+        self.warnings.clear();
+    }
+
     pub fn cache_prepare_stmt_ctx(&mut self, sql: String, prepare_stmt_ctx: PrepareStatementContext) {
         self.prepare_stmt_ctx_id.insert(sql, prepare_stmt_ctx.statement_id);
         self.prepare_stmt_ctx_map.insert(prepare_stmt_ctx.statement_id, prepare_stmt_ctx);
@@ -91,7 +371,7 @@ impl SessionContext {
 
     pub fn clear_prepare_stmt_ctx(&mut self, statement_id: u64) {
         if let Some(prepare_stmt_ctx) = self.get_prepare_stmt_ctx_by_id(statement_id) {
-            let sql = String::from_utf8_lossy(prepare_stmt_ctx.get_sql().as_slice()).to_string();
+            let sql = String::from_utf8_lossy(&prepare_stmt_ctx.get_sql()).to_string();
             self.prepare_stmt_ctx_id.remove(&*sql);
             self.prepare_stmt_ctx_map.remove(&statement_id);
         }
@@ -117,6 +397,20 @@ impl SessionContext {
         self.prepare_stmt_ctx_map.get(self.prepare_stmt_ctx_id.get(&sql).unwrap())
     }
 
+    /// The SQL of every statement the session has prepared.
+    pub fn get_prepare_stmt_sqls(&self) -> Vec<Bytes> {
+        self.prepare_stmt_ctx_map.values().map(|prepare_stmt_ctx| prepare_stmt_ctx.get_sql()).collect()
+    }
+
+    /// The id and SQL of every statement the session has prepared, by id.
+    pub fn get_prepared_statements(&self) -> Vec<(u64, String)> {
+        let mut statements: Vec<(u64, String)> = self.prepare_stmt_ctx_map.iter()
+            .map(|(id, prepare_stmt_ctx)| (*id, String::from_utf8_lossy(&prepare_stmt_ctx.get_sql()).to_string()))
+            .collect();
+        statements.sort();
+        statements
+    }
+
     pub fn get_prepare_stmt_ctx_by_id(&self, statement_id: u64) -> Option<&PrepareStatementContext> {
         self.prepare_stmt_ctx_map.get(&statement_id)
     }
@@ -139,7 +433,7 @@ pub struct PrepareStatementContext {
     statement_id: u64,
     parameters_count: u16,
     columns_count: u16,
-    sql: Vec<u8>,
+    sql: Bytes,
     parameter_types: Vec<(u8, u8)>,
 }
 
@@ -147,7 +441,7 @@ impl PrepareStatementContext {
     pub fn new(statement_id: u64,
                parameters_count: u16,
                columns_count: u16,
-               sql: Vec<u8>) -> Self {
+               sql: Bytes) -> Self {
         PrepareStatementContext {
             statement_id,
             parameters_count,
@@ -157,7 +451,7 @@ impl PrepareStatementContext {
         }
     }
 
-    pub fn get_sql(&self) -> Vec<u8> {
+    pub fn get_sql(&self) -> Bytes {
         self.sql.clone()
     }
 
@@ -182,14 +476,6 @@ impl PrepareStatementContext {
     }
 }
 
-lazy_static! {
-    static ref SESSION_PREPARESTMTCONTEXT_STATEMENT_ID_GENERATOR: AtomicU64 = AtomicU64::new(1);
-}
-
-pub fn session_prepare_stmt_context_statement_id() -> u64 {
-    SESSION_PREPARESTMTCONTEXT_STATEMENT_ID_GENERATOR.fetch_add(1, Ordering::SeqCst)
-}
-
 #[cfg(test)]
 mod session_tests {
     use std::collections::HashMap;
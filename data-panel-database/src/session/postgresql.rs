@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use crate::protocol::database::postgresql::{TRANSACTION_BLOCK, TRANSACTION_IDLE};
+
+/// A statement of the extended query protocol, already translated to the MySQL dialect.
+#[derive(Debug, Clone)]
+pub struct BridgeStatement {
+    sql: String,
+    /// For every `?` of `sql`, the index of the PostgreSQL parameter it stands for.
+    param_order: Vec<usize>,
+    param_types: Vec<u32>,
+}
+
+impl BridgeStatement {
+    pub fn new(sql: String, param_order: Vec<usize>, param_types: Vec<u32>) -> Self {
+        BridgeStatement {
+            sql,
+            param_order,
+            param_types,
+        }
+    }
+
+    pub fn get_sql(&self) -> &str {
+        &self.sql
+    }
+
+    pub fn get_param_order(&self) -> &[usize] {
+        &self.param_order
+    }
+
+    /// Number of parameters the client binds, `$n` may be used more than once.
+    pub fn get_params_count(&self) -> usize {
+        self.param_order.iter().map(|index| index + 1).max().unwrap_or(0).max(self.param_types.len())
+    }
+
+    pub fn get_param_types(&self) -> &[u32] {
+        &self.param_types
+    }
+}
+
+/// The buffered outcome of one statement, rows already encoded as text.
+#[derive(Debug, Clone, Default)]
+pub struct BridgeResult {
+    columns: Vec<(String, u32)>,
+    rows: Vec<Vec<Option<String>>>,
+    command_tag: String,
+}
+
+impl BridgeResult {
+    pub fn new(columns: Vec<(String, u32)>, rows: Vec<Vec<Option<String>>>, command_tag: String) -> Self {
+        BridgeResult {
+            columns,
+            rows,
+            command_tag,
+        }
+    }
+
+    pub fn get_columns(&self) -> &[(String, u32)] {
+        &self.columns
+    }
+
+    pub fn get_rows(&self) -> &[Vec<Option<String>>] {
+        &self.rows
+    }
+
+    pub fn get_command_tag(&self) -> &str {
+        &self.command_tag
+    }
+}
+
+/// A statement bound to its parameters. The result is kept once the portal was described,
+/// as the description needs the statement to run.
+#[derive(Debug, Clone)]
+pub struct BridgePortal {
+    statement: BridgeStatement,
+    params: Vec<Option<Vec<u8>>>,
+    result: Option<BridgeResult>,
+}
+
+impl BridgePortal {
+    pub fn new(statement: BridgeStatement, params: Vec<Option<Vec<u8>>>) -> Self {
+        BridgePortal {
+            statement,
+            params,
+            result: None,
+        }
+    }
+
+    pub fn get_statement(&self) -> &BridgeStatement {
+        &self.statement
+    }
+
+    pub fn get_params(&self) -> &[Option<Vec<u8>>] {
+        &self.params
+    }
+
+    pub fn take_result(&mut self) -> Option<BridgeResult> {
+        self.result.take()
+    }
+
+    pub fn set_result(&mut self, result: BridgeResult) {
+        self.result = Some(result);
+    }
+}
+
+#[derive(Debug)]
+pub struct PostgreSQLSessionContext {
+    id: u64,
+    user_name: String,
+    database: String,
+    authorized: bool,
+    backend_url: String,
+    in_transaction: bool,
+    /// Set after an error of the extended protocol, messages are skipped until the next Sync.
+    failed: bool,
+    statements: HashMap<String, BridgeStatement>,
+    portals: HashMap<String, BridgePortal>,
+}
+
+impl PostgreSQLSessionContext {
+    pub fn new(id: u64, backend_url: String) -> Self {
+        PostgreSQLSessionContext {
+            id,
+            user_name: "".to_string(),
+            database: "".to_string(),
+            authorized: false,
+            backend_url,
+            in_transaction: false,
+            failed: false,
+            statements: HashMap::new(),
+            portals: HashMap::new(),
+        }
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn get_user_name(&self) -> String {
+        self.user_name.clone()
+    }
+
+    pub fn set_user_name(&mut self, user_name: String) {
+        self.user_name = user_name;
+    }
+
+    pub fn get_database(&self) -> String {
+        self.database.clone()
+    }
+
+    pub fn set_database(&mut self, database: String) {
+        self.database = database;
+    }
+
+    pub fn get_authorized(&self) -> bool {
+        self.authorized
+    }
+
+    pub fn set_authorized(&mut self, authorized: bool) {
+        self.authorized = authorized;
+    }
+
+    pub fn get_backend_url(&self) -> String {
+        self.backend_url.clone()
+    }
+
+    pub fn set_in_transaction(&mut self, in_transaction: bool) {
+        self.in_transaction = in_transaction;
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.failed
+    }
+
+    pub fn set_failed(&mut self, failed: bool) {
+        self.failed = failed;
+    }
+
+    /// The status ReadyForQuery reports.
+    pub fn get_transaction_status(&self) -> u8 {
+        if self.in_transaction { TRANSACTION_BLOCK } else { TRANSACTION_IDLE }
+    }
+
+    pub fn get_statement(&self, name: &str) -> Option<&BridgeStatement> {
+        self.statements.get(name)
+    }
+
+    pub fn put_statement(&mut self, name: String, statement: BridgeStatement) {
+        self.statements.insert(name, statement);
+    }
+
+    pub fn remove_statement(&mut self, name: &str) {
+        self.statements.remove(name);
+    }
+
+    pub fn get_portal_mut(&mut self, name: &str) -> Option<&mut BridgePortal> {
+        self.portals.get_mut(name)
+    }
+
+    pub fn put_portal(&mut self, name: String, portal: BridgePortal) {
+        self.portals.insert(name, portal);
+    }
+
+    pub fn remove_portal(&mut self, name: &str) -> Option<BridgePortal> {
+        self.portals.remove(name)
+    }
+}
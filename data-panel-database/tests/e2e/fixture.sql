@@ -0,0 +1,15 @@
+-- Loaded into every segment before the suite runs.
+DROP TABLE IF EXISTS t_order;
+DROP TABLE IF EXISTS t_dept;
+CREATE TABLE t_order (
+    id BIGINT PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    status VARCHAR(16) NOT NULL,
+    amount DECIMAL(10, 2) NOT NULL
+);
+CREATE TABLE t_dept (
+    id INT PRIMARY KEY,
+    name VARCHAR(32) NOT NULL
+);
+INSERT INTO t_dept (id, name) VALUES (1, 'sales'), (2, 'support'), (3, 'engineering');
+INSERT INTO t_order (id, user_id, status, amount) VALUES (1, 10, 'PAID', 12.50), (2, 10, 'NEW', 3.00), (3, 20, 'PAID', 99.99);
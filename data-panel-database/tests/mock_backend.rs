@@ -0,0 +1,39 @@
+//! The in-memory backend of the OLTP load answering a real client, so a broken mock shows
+//! here rather than as errors in the load numbers.
+
+#[path = "../benches/oltp/backend.rs"]
+mod backend;
+
+use mysql::{Conn, Error, Opts};
+use mysql::prelude::Queryable;
+
+use crate::backend::{MockBackend, RANGE_SIZE};
+
+#[test]
+fn test_mock_backend() {
+    let backend = MockBackend::start().unwrap();
+    let mut conn = Conn::new(Opts::from_url(&backend.url()).unwrap()).unwrap();
+    let queries = backend.get_queries();
+
+    let rows: Vec<String> = conn.query("SELECT c FROM sbtest1 WHERE id = 7").unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].len(), 120);
+    let rows: Vec<String> = conn.query("SELECT c FROM sbtest1 WHERE id BETWEEN 1 AND 100").unwrap();
+    assert_eq!(rows.len(), RANGE_SIZE);
+    let sum: Option<u64> = conn.query_first("SELECT SUM(k) FROM sbtest1 WHERE id BETWEEN 1 AND 100").unwrap();
+    assert_eq!(sum, Some(RANGE_SIZE as u64 * 7));
+
+    conn.query_drop("UPDATE sbtest1 SET k = k + 1 WHERE id = 7").unwrap();
+    assert_eq!(conn.affected_rows(), 1);
+    conn.query_drop("INSERT INTO sbtest1 (id, k, c, pad) VALUES (7, 1, 'c', 'pad')").unwrap();
+    conn.query_drop("INSERT INTO sbtest1 (id, k, c, pad) VALUES (8, 1, 'c', 'pad')").unwrap();
+    assert_eq!(conn.last_insert_id(), 2);
+    conn.query_drop("COMMIT").unwrap();
+    assert_eq!(backend.get_queries() - queries, 7);
+
+    match conn.exec_drop("SELECT c FROM sbtest1 WHERE id = ?", (7,)) {
+        Err(Error::MySqlError(e)) => assert_eq!(e.code, 1235),
+        result => panic!("expected the prepared statement refused, got {:?}", result),
+    }
+    assert!(conn.ping());
+}
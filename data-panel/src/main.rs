@@ -5,11 +5,9 @@
 #![warn(rust_2018_idioms)]
 
 use std::error::Error;
-use std::fs::File;
-use std::io::Read;
+use std::process;
 
 use clap::{App, Arg, SubCommand};
-use toml::Value;
 
 use data_panel_common::config::config::MeshConfig;
 
@@ -50,35 +48,31 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
-    let mut file = File::open(config_path).expect("Unable to open file");
-    let mut contents = String::new();
-
-    file.read_to_string(&mut contents).expect("Unable to read file");
-
-    let docs = contents.as_str().parse::<Value>().unwrap();
-
-    // Multi document support, doc is a yaml::Yaml
-    let doc = &docs;
-
-    // Debug support
-    println!("{:?}", doc);
-
-    // Index access for map & array
-    assert_eq!(doc["app"]["name"].as_str().unwrap(), "Database Mesh");
-    assert_eq!(doc["system"]["timeout"].as_integer().unwrap(), 5000);
-
-    // Chained key/array access is checked and won't panic,
-    // return BadValue if they are not exist.
-    // assert!(doc["INVALID_KEY"][100].is_badvalue());
-
-    println!("{}", toml::to_string(doc).unwrap());
-
-    let mesh_config = MeshConfig::from_file(config_path);
+    // Every key is checked against the config schema, the first invalid one is reported.
+    let mesh_config = match MeshConfig::try_from_file(config_path) {
+        Ok(mesh_config) => mesh_config,
+        Err(message) => exit(data_panel_common::common::Error::Config(message)),
+    };
     mesh_config.make_current();
 
     println!("{:#?}", MeshConfig::current());
 
     let service = service::new_service();
 
-    service.serve().await
+    if let Err(e) = service.serve().await {
+        match e.downcast::<data_panel_common::common::Error>() {
+            Ok(e) => exit(*e),
+            Err(e) => {
+                println!("error on starting the mesh; error = {}", e);
+                process::exit(1);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Ends the process with the exit code of `e`.
+fn exit(e: data_panel_common::common::Error) -> ! {
+    println!("error on starting the mesh; error = {}", e);
+    process::exit(e.exit_code())
 }
\ No newline at end of file
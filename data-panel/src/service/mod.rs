@@ -1,6 +1,11 @@
+use data_panel_common::config::config::MeshConfig;
 use data_panel_common::service::Service;
+use data_panel_database::service::http2::Http2ProxyService;
 use data_panel_database::service::mysql::MySQLService;
 
 pub fn new_service() -> Box<dyn Service> {
-    Box::new(MySQLService {})
+    match MeshConfig::get_mode().as_str() {
+        "http2" => Box::new(Http2ProxyService {}),
+        _ => Box::new(MySQLService {}),
+    }
 }
\ No newline at end of file